@@ -23,7 +23,7 @@ install_illumos_prerequisites() {
     local BUILD_DIR=""
     local OUTPUT_DIR=""
     local REMOVE_DIRS=()
-    local pkgs="pkg:/system/kvm pkg:/ooce/system/file-system/ntfs-3g pkg:/ooce/driver/fuse pkg:/compress/p7zip pkg:/ooce/system/gptfdisk"
+    local pkgs="pkg:/system/kvm pkg:/ooce/system/file-system/ntfs-3g pkg:/ooce/driver/fuse pkg:/ooce/system/gptfdisk"
     local rc=0;
 
     # shellcheck disable=SC2317
@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Works out how QEMU can accelerate the installation and test VMs.
+//!
+//! A VM whose architecture matches the host's runs on KVM. When KVM isn't
+//! usable, or the guest is of another architecture, QEMU falls back to TCG,
+//! its CPU emulator, which works everywhere but is many times slower, so
+//! wimsy also gives the image tests longer to finish.
+
+use crate::{
+    app::Accelerator, autounattend::Architecture, kvm::KvmProbe, trace,
+};
+
+/// How many times longer than configured the image tests may take when the
+/// VMs run on TCG.
+pub const TCG_TIMEOUT_FACTOR: u32 = 4;
+
+/// What a host offers for accelerating guests of one architecture.
+pub struct HostAccel {
+    arch: Architecture,
+
+    /// The host's architecture, as [`std::env::consts::ARCH`] names it.
+    host_arch: String,
+
+    /// The result of checking whether KVM is usable, if it was worth
+    /// checking.
+    kvm: Option<KvmProbe>,
+}
+
+impl HostAccel {
+    /// Describes a `host_arch` host that runs `arch` guests. `kvm` is the
+    /// result of probing for KVM, if [`HostAccel::wants_kvm`] said to.
+    pub fn new(
+        arch: Architecture,
+        host_arch: &str,
+        kvm: Option<KvmProbe>,
+    ) -> Self {
+        Self { arch, host_arch: host_arch.to_string(), kvm }
+    }
+
+    /// Returns whether it's worth probing for KVM to run `arch` guests on a
+    /// `host_arch` host: it isn't if the user `requested` another
+    /// accelerator, or if KVM couldn't run the guest anyway.
+    pub fn wants_kvm(
+        requested: Accelerator,
+        arch: Architecture,
+        host_arch: &str,
+    ) -> bool {
+        matches!(requested, Accelerator::Auto | Accelerator::Kvm)
+            && host_arch == arch.to_string()
+    }
+
+    /// Returns whether the host can run its guests without emulating their
+    /// CPUs.
+    fn runs_natively(&self) -> bool {
+        self.host_arch == self.arch.to_string()
+    }
+
+    /// Determines which accelerator to use given the one the user requested.
+    /// `Auto` selects KVM if it can run the guest, and TCG otherwise. Guests
+    /// of another architecture always use TCG.
+    pub fn resolve(&self, requested: Accelerator) -> Accelerator {
+        let kvm_available =
+            self.kvm.as_ref().is_some_and(KvmProbe::is_available);
+        let selected = match requested {
+            _ if !self.runs_natively() => Accelerator::Tcg,
+            Accelerator::Auto if kvm_available => Accelerator::Kvm,
+            Accelerator::Auto => Accelerator::Tcg,
+            other => other,
+        };
+
+        trace::debug!(
+            "selected accelerator",
+            requested = requested.to_string(),
+            selected = selected.to_string(),
+            kvm_available = kvm_available
+        );
+        selected
+    }
+
+    /// Checks that `requested` can run guests on this host, returning errors
+    /// for accelerators that can't and warnings if the VMs will fall back to
+    /// TCG.
+    pub fn check(&self, requested: Accelerator) -> (Vec<String>, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        if requested == Accelerator::Kvm && !self.runs_natively() {
+            errors.push(format!(
+                "KVM can't run {} guests on this {} host; pass --accel tcg to \
+                emulate them (this is much slower)",
+                self.arch, self.host_arch
+            ));
+        }
+
+        if let Some(problem) = self.kvm.as_ref().and_then(KvmProbe::problem) {
+            if requested == Accelerator::Kvm {
+                errors.push(format!(
+                    "KVM is not available: {}. To fix this, {}, or pass \
+                    --accel tcg to build without KVM (this is much slower).",
+                    problem.cause, problem.remedy
+                ));
+            } else {
+                warnings.push(format!(
+                    "KVM is not available ({}), so the VMs will use TCG and \
+                    run much more slowly, and the image tests get \
+                    {TCG_TIMEOUT_FACTOR} times as long to finish. To use KVM, \
+                    {}.",
+                    problem.cause, problem.remedy
+                ));
+            }
+        }
+
+        (errors, warnings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kvm::KvmFacts;
+
+    fn kvm(error: Option<std::io::ErrorKind>) -> Option<KvmProbe> {
+        Some(KvmProbe::resolve(KvmFacts {
+            open: error.map_or(Ok(()), |kind| Err(kind.into())),
+            group: Some("kvm".to_string()),
+            cpu_supports_virtualization: Some(true),
+        }))
+    }
+
+    #[test]
+    fn resolves_accelerators() {
+        use std::io::ErrorKind::NotFound;
+        use Accelerator::{Auto, Kvm, Tcg};
+        use Architecture::{Aarch64, X86_64};
+
+        let cases = [
+            // (guest, host, KVM probe, requested, selected)
+            (X86_64, "x86_64", kvm(None), Auto, Kvm),
+            (X86_64, "x86_64", kvm(Some(NotFound)), Auto, Tcg),
+            (X86_64, "x86_64", kvm(Some(NotFound)), Kvm, Kvm),
+            (X86_64, "x86_64", None, Tcg, Tcg),
+            (Aarch64, "aarch64", kvm(None), Auto, Kvm),
+            (Aarch64, "x86_64", None, Auto, Tcg),
+            (Aarch64, "x86_64", None, Kvm, Tcg),
+        ];
+
+        for (arch, host_arch, kvm, requested, selected) in cases {
+            let host = HostAccel::new(arch, host_arch, kvm);
+            assert_eq!(
+                host.resolve(requested),
+                selected,
+                "{requested} for {arch} guests on {host_arch}"
+            );
+        }
+
+        assert!(HostAccel::wants_kvm(Auto, X86_64, "x86_64"));
+        assert!(HostAccel::wants_kvm(Kvm, Aarch64, "aarch64"));
+        assert!(!HostAccel::wants_kvm(Tcg, X86_64, "x86_64"));
+        assert!(!HostAccel::wants_kvm(Kvm, Aarch64, "x86_64"));
+    }
+
+    #[test]
+    fn checks_accelerators() {
+        use std::io::ErrorKind::NotFound;
+        use Accelerator::{Auto, Kvm, Tcg};
+        use Architecture::{Aarch64, X86_64};
+
+        let cases = [
+            // (guest, host, KVM probe, requested, error, warning)
+            (X86_64, "x86_64", kvm(None), Kvm, None, None),
+            (X86_64, "x86_64", None, Tcg, None, None),
+            (
+                Aarch64,
+                "x86_64",
+                None,
+                Kvm,
+                Some("KVM can't run aarch64 guests on this x86_64 host"),
+                None,
+            ),
+            (
+                X86_64,
+                "x86_64",
+                kvm(Some(NotFound)),
+                Kvm,
+                Some("KVM is not available: /dev/kvm does not exist"),
+                None,
+            ),
+            (
+                X86_64,
+                "x86_64",
+                kvm(Some(NotFound)),
+                Auto,
+                None,
+                Some("so the VMs will use TCG"),
+            ),
+        ];
+
+        for (arch, host_arch, kvm, requested, error, warning) in cases {
+            let host = HostAccel::new(arch, host_arch, kvm);
+            let (errors, warnings) = host.check(requested);
+            for (messages, expected) in [(errors, error), (warnings, warning)] {
+                match expected {
+                    Some(expected) => {
+                        assert_eq!(messages.len(), 1, "{messages:?}");
+                        assert!(messages[0].contains(expected), "{messages:?}");
+                    }
+                    None => assert!(messages.is_empty(), "{messages:?}"),
+                }
+            }
+        }
+    }
+}
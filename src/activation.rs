@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Chooses the product key built images activate with.
+//!
+//! Users can supply a product key of their own, or ask for the generic key
+//! Microsoft publishes for activating the edition being installed against a
+//! Key Management Service (KMS) host or through Automatic Virtual Machine
+//! Activation (AVMA). The generic keys depend on the Windows version and
+//! edition, so they're looked up once the Windows ISO's image metadata has
+//! been read. The chosen key ends up in the `product_key` template variable,
+//! which the repo's answer files write into their `ProductKey` settings.
+
+use anyhow::Result;
+
+use crate::{autounattend::WindowsVersion, runner::Context, ui::Ui};
+
+/// The product key to activate built images with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProductKey {
+    /// A specific product key.
+    Key(String),
+
+    /// The KMS client setup key for the edition being installed.
+    Kms,
+
+    /// The AVMA key for the edition being installed.
+    Avma,
+}
+
+impl std::str::FromStr for ProductKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("kms") {
+            return Ok(Self::Kms);
+        }
+
+        if s.eq_ignore_ascii_case("avma") {
+            return Ok(Self::Avma);
+        }
+
+        let key = s.to_ascii_uppercase();
+        let groups: Vec<&str> = key.split('-').collect();
+        if groups.len() != 5
+            || !groups.iter().all(|group| {
+                group.len() == 5
+                    && group.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+        {
+            return Err(format!(
+                "'{s}' isn't \"kms\", \"avma\", or a product key like \
+                XXXXX-XXXXX-XXXXX-XXXXX-XXXXX"
+            ));
+        }
+
+        Ok(Self::Key(key))
+    }
+}
+
+impl std::fmt::Display for ProductKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProductKey::Key(key) => write!(f, "{key}"),
+            ProductKey::Kms => write!(f, "kms"),
+            ProductKey::Avma => write!(f, "avma"),
+        }
+    }
+}
+
+/// A KMS host, with an optional port (the default is 1688).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KmsHost {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl std::str::FromStr for KmsHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("'{port}' isn't a port number"))?;
+                (host, Some(port))
+            }
+            None => (s, None),
+        };
+
+        if host.is_empty()
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'))
+        {
+            return Err(format!("'{host}' isn't a host name or IPv4 address"));
+        }
+
+        Ok(Self { host: host.to_string(), port })
+    }
+}
+
+impl std::fmt::Display for KmsHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{port}", self.host),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// Returns the edition family (e.g. "datacenter") of a Windows image's
+/// edition ID (e.g. "ServerDatacenter" or "ServerDatacenterCor"), or of an
+/// `--edition` name.
+fn edition_family(edition: &str) -> String {
+    let lower = edition.to_ascii_lowercase().replace('-', "");
+    let lower = lower.strip_prefix("server").unwrap_or(&lower);
+    let lower = lower.strip_suffix("core").unwrap_or(lower);
+    let lower = lower.strip_suffix("desktop").unwrap_or(lower);
+    let lower = lower.strip_suffix("cor").unwrap_or(lower);
+    match lower {
+        // Essentials and Datacenter: Azure Edition have internal names.
+        "solution" => "essentials".to_string(),
+        "turbine" => "azure".to_string(),
+        family => crate::wim::expand_edition_alias(family),
+    }
+}
+
+/// Microsoft's KMS client setup keys, by release and edition family.
+const KMS_KEYS: &[(WindowsVersion, &str, &str)] = &[
+    (WindowsVersion::Server2016, "datacenter", "CB7KF-BWN84-R7R2Y-793K2-8XDDG"),
+    (WindowsVersion::Server2016, "standard", "WC2BQ-8NRM3-FDDYY-2BFGV-KHKQY"),
+    (WindowsVersion::Server2016, "essentials", "JCKRF-N37P4-C2D82-9YXRT-4M63B"),
+    (WindowsVersion::Server2019, "datacenter", "WMDGN-G9PQG-XVVXX-R3X43-63DFG"),
+    (WindowsVersion::Server2019, "standard", "N69G4-B89J2-4G8F4-WWYCC-J464C"),
+    (WindowsVersion::Server2019, "essentials", "WVDHN-86M7X-466P6-VHXV7-YY726"),
+    (WindowsVersion::Server2022, "datacenter", "WX4NM-KYWYW-QJJR4-XV3QB-6VM33"),
+    (WindowsVersion::Server2022, "standard", "VDYBN-27WPP-V4HQT-9VMD4-VMK7H"),
+    (WindowsVersion::Server2022, "azure", "NTBV8-9K7Q8-V27C6-M2BTV-KHMXV"),
+    (WindowsVersion::Server2025, "datacenter", "D764K-2NDRG-47T6Q-P8T8W-YP6DF"),
+    (WindowsVersion::Server2025, "standard", "TVRH6-WHNXV-R9WG3-9XRFY-MY832"),
+    (WindowsVersion::Server2025, "azure", "XGN3F-F394H-FD2MY-PP6FD-8MCRC"),
+    (
+        WindowsVersion::Windows10,
+        "professional",
+        "W269N-WFGWX-YVC9B-4J6C9-T83GX",
+    ),
+    (
+        WindowsVersion::Windows10,
+        "professionalworkstation",
+        "NRG8B-VKK3Q-CXVCJ-9G2XF-6Q84J",
+    ),
+    (WindowsVersion::Windows10, "enterprise", "NPPR9-FWDCX-D2C8J-H872K-2YT43"),
+    (WindowsVersion::Windows10, "education", "NW6C2-QMPVW-D7KKK-3GKT6-VCFB2"),
+    (WindowsVersion::Windows10, "enterprises", "M7XTQ-FN8P6-TTKYV-9D4CC-J462D"),
+    (
+        WindowsVersion::Windows10,
+        "iotenterprises",
+        "KBN8V-HFGQ4-MGXVD-347P6-PDQGT",
+    ),
+    (
+        WindowsVersion::Windows11,
+        "professional",
+        "W269N-WFGWX-YVC9B-4J6C9-T83GX",
+    ),
+    (
+        WindowsVersion::Windows11,
+        "professionalworkstation",
+        "NRG8B-VKK3Q-CXVCJ-9G2XF-6Q84J",
+    ),
+    (WindowsVersion::Windows11, "enterprise", "NPPR9-FWDCX-D2C8J-H872K-2YT43"),
+    (WindowsVersion::Windows11, "education", "NW6C2-QMPVW-D7KKK-3GKT6-VCFB2"),
+];
+
+/// Microsoft's AVMA keys, by release and edition family.
+const AVMA_KEYS: &[(WindowsVersion, &str, &str)] = &[
+    (WindowsVersion::Server2016, "datacenter", "TMJ3Y-NTRTM-FJYXT-T22BY-CWG3J"),
+    (WindowsVersion::Server2016, "standard", "C3RCX-M6NRP-6CXC9-TW2F2-4RHYD"),
+    (WindowsVersion::Server2016, "essentials", "B4YNW-62DX9-W8V6M-82649-MHBKQ"),
+    (WindowsVersion::Server2019, "datacenter", "H3RNG-8C32Q-Q8FRX-6TDXV-WMBMW"),
+    (WindowsVersion::Server2019, "standard", "TNK62-RXVTB-4P47B-2D623-4GF74"),
+    (WindowsVersion::Server2019, "essentials", "2CTP7-NHT64-BP62M-FV6GG-HFV28"),
+    (WindowsVersion::Server2022, "datacenter", "W3GNR-8DDXR-2TFRP-H8P33-DV9BG"),
+    (WindowsVersion::Server2022, "standard", "YDFWN-MJ9JR-3DYRK-FXXRW-78VHK"),
+    (WindowsVersion::Server2025, "datacenter", "YQB4H-NKHHJ-Q6K4R-4VMY6-VCH67"),
+    (WindowsVersion::Server2025, "standard", "WWVGQ-PNHV9-B89P4-8GGM9-9HPQ4"),
+];
+
+impl ProductKey {
+    /// Returns the product key to use for `edition` (an edition ID or
+    /// `--edition` name) of `version`, if they're known.
+    pub fn resolve(
+        &self,
+        version: Option<WindowsVersion>,
+        edition: Option<&str>,
+    ) -> Result<String> {
+        let table = match self {
+            ProductKey::Key(key) => return Ok(key.clone()),
+            ProductKey::Kms => KMS_KEYS,
+            ProductKey::Avma => AVMA_KEYS,
+        };
+
+        let (Some(version), Some(edition)) = (version, edition) else {
+            anyhow::bail!(
+                "the {} key depends on the Windows version and edition, and \
+                the {} couldn't be determined; pass --windows-version and \
+                --edition, or supply the key itself",
+                self.to_string().to_uppercase(),
+                if version.is_none() { "version" } else { "edition" }
+            );
+        };
+
+        let family = edition_family(edition);
+        table
+            .iter()
+            .find(|(v, f, _)| *v == version && *f == family)
+            .map(|(_, _, key)| key.to_string())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "there's no {} key for {version} {edition}",
+                    self.to_string().to_uppercase()
+                )
+            })
+    }
+}
+
+/// Returns the edition the build installs: the edition ID from the Windows
+/// ISO's metadata if it could be read, or the name passed to `--edition`.
+fn edition(ctx: &Context) -> Option<String> {
+    if let Some(edition) = ctx.get_var("windows_edition") {
+        return Some(edition.to_string());
+    }
+
+    match ctx.get_var("edition")?.parse().ok()? {
+        crate::wim::Edition::Named { family, .. } => Some(family),
+        crate::wim::Edition::Index(_) => None,
+    }
+}
+
+/// Sets the `product_key` variable to the key that `--product-key` selects
+/// for the Windows version and edition being installed.
+pub fn select_product_key(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(requested) = ctx.get_var("activation") else {
+        return Ok(());
+    };
+
+    let requested: ProductKey =
+        requested.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+    let key = requested
+        .resolve(crate::steps::windows_version(ctx), edition(ctx).as_deref())?;
+
+    if requested != ProductKey::Key(key.clone()) {
+        ui.set_substep(&format!(
+            "using the {} key {key}",
+            requested.to_string().to_uppercase()
+        ));
+    }
+
+    ctx.set_var("product_key", key);
+    Ok(())
+}
+
+pub fn describe_select_product_key(ctx: &mut Context) -> Vec<String> {
+    match ctx.get_var("activation").map(str::parse) {
+        Some(Ok(ProductKey::Key(_))) => {
+            vec!["write the product key into the answer files".to_string()]
+        }
+        Some(Ok(requested)) => vec![format!(
+            "look up the {} key for the Windows version and edition being \
+            installed",
+            requested.to_string().to_uppercase()
+        )],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolves_product_keys() {
+        assert_eq!("KMS".parse::<ProductKey>().unwrap(), ProductKey::Kms);
+        assert_eq!(
+            "abcde-12345-fghij-67890-klmno".parse::<ProductKey>().unwrap(),
+            ProductKey::Key("ABCDE-12345-FGHIJ-67890-KLMNO".to_string())
+        );
+        for bad in
+            ["", "retail", "ABCDE-12345", "ABCDE-12345-FGHIJ-67890-KLMN!"]
+        {
+            assert!(bad.parse::<ProductKey>().is_err(), "{bad}");
+        }
+
+        let version = Some(WindowsVersion::Server2022);
+        for edition in ["ServerDatacenter", "ServerDatacenterCor", "datacenter"]
+        {
+            assert_eq!(
+                ProductKey::Kms.resolve(version, Some(edition)).unwrap(),
+                "WX4NM-KYWYW-QJJR4-XV3QB-6VM33",
+                "{edition}"
+            );
+        }
+
+        assert_eq!(
+            ProductKey::Avma
+                .resolve(
+                    Some(WindowsVersion::Server2019),
+                    Some("standard-core")
+                )
+                .unwrap(),
+            "TNK62-RXVTB-4P47B-2D623-4GF74"
+        );
+        assert_eq!(
+            ProductKey::Kms
+                .resolve(Some(WindowsVersion::Windows11), Some("pro"))
+                .unwrap(),
+            "W269N-WFGWX-YVC9B-4J6C9-T83GX"
+        );
+        assert_eq!(
+            ProductKey::Kms
+                .resolve(Some(WindowsVersion::Windows10), Some("EnterpriseS"))
+                .unwrap(),
+            "M7XTQ-FN8P6-TTKYV-9D4CC-J462D"
+        );
+        assert!(ProductKey::Kms.resolve(None, Some("ServerStandard")).is_err());
+        assert!(ProductKey::Kms.resolve(version, None).is_err());
+        assert!(ProductKey::Avma
+            .resolve(version, Some("ServerTurbine"))
+            .is_err());
+        assert_eq!(
+            ProductKey::Key("ABCDE-12345-FGHIJ-67890-KLMNO".to_string())
+                .resolve(None, None)
+                .unwrap(),
+            "ABCDE-12345-FGHIJ-67890-KLMNO"
+        );
+
+        assert_eq!(
+            "kms.example.com:1689".parse::<KmsHost>().unwrap(),
+            KmsHost { host: "kms.example.com".to_string(), port: Some(1689) }
+        );
+        assert!("kms.example.com:http".parse::<KmsHost>().is_err());
+        assert!(":1688".parse::<KmsHost>().is_err());
+    }
+}
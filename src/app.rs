@@ -2,21 +2,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Args, Parser, Subcommand};
 
-use crate::autounattend::WindowsVersion;
+use crate::{
+    autounattend::{Architecture, WindowsVersion},
+    monitor,
+    secrets::SecretSource,
+    workspace::KeepWorkDir,
+};
 
 #[derive(Parser)]
 pub struct App {
-    /// The directory in which to store temporary files.
+    /// The directory in which to store logs, intermediate files, and
+    /// downloaded installation media. See "The work directory" in README.md.
     #[arg(long)]
     pub work_dir: Utf8PathBuf,
 
+    /// When to keep the work directory's logs, ISOs, and scratch files after
+    /// the build finishes. The build report and downloaded installation media
+    /// are always kept.
+    #[arg(long, value_name = "WHEN", default_value_t = KeepWorkDir::OnFailure)]
+    pub keep_work_dir: KeepWorkDir,
+
     /// The path to the tool's output disk image (i.e. the generated all-in-one
-    /// installation disk or guest disk image).
+    /// installation disk or guest disk image). Overrides the configuration
+    /// file's `output.image`; one of them is required.
     #[arg(long)]
-    pub output_image: Utf8PathBuf,
+    pub output_image: Option<Utf8PathBuf>,
 
     /// Forces the tool to run in an interactive or non-interactive mode. If not
     /// set, the tool infers whether to run interactively from whether it is
@@ -24,10 +37,258 @@ pub struct App {
     #[arg(long, default_value = Option::None)]
     pub interactive: Option<bool>,
 
+    /// The path to a configuration file that modifies the steps the selected
+    /// command will run. See CONFIGURING.md for the file's format.
+    #[arg(long)]
+    pub config: Option<Utf8PathBuf>,
+
+    /// Builds the target of this name in the configuration file's
+    /// `[[targets]]` array, whose settings are laid over the rest of the
+    /// file's. See the build-all command to build every target.
+    #[arg(long, value_name = "NAME", requires = "config")]
+    pub target: Option<String>,
+
+    /// Pauses after each of the named steps (or after every step, if "all" is
+    /// specified) so that the work directory and output image can be
+    /// inspected before continuing. Requires interactive mode.
+    #[arg(long, value_delimiter = ',', value_name = "all|STEP,...")]
+    pub pause_after: Vec<String>,
+
+    /// Skips the steps that completed in an earlier run of the same build
+    /// (i.e. one with the same options, configuration, and input files), as
+    /// recorded in the work directory's checkpoint.json, and continues from
+    /// the first step that didn't. If the build can't be resumed, it starts
+    /// from the beginning.
+    #[arg(long, default_value_t = false)]
+    pub resume: bool,
+
+    /// Doesn't run the named step, which may be given more than once. Builds
+    /// that skip steps, or don't start at the first, borrow the context
+    /// variables the steps they don't run set in the last build in the same
+    /// work directory, as recorded in its checkpoint, and leave the
+    /// checkpoint and work directory as they are.
+    #[arg(long, value_name = "STEP", conflicts_with = "resume")]
+    pub skip_step: Vec<String>,
+
+    /// Runs only the named step, which may be given more than once, e.g. to
+    /// try a change to a post-install script against a work directory kept
+    /// from an earlier build. See --skip-step.
+    #[arg(
+        long,
+        value_name = "STEP",
+        conflicts_with_all = ["resume", "skip_step", "start_at"]
+    )]
+    pub only_step: Vec<String>,
+
+    /// Skips the steps before the named step and runs the rest. See
+    /// --skip-step.
+    #[arg(long, value_name = "STEP", conflicts_with = "resume")]
+    pub start_at: Option<String>,
+
+    /// Breaks the locks another build holds on the work directory and output
+    /// image. Builds normally release their locks however they exit, so only
+    /// pass this if the build holding them is known to have stopped (e.g.
+    /// because its host crashed while they were on a network filesystem).
+    #[arg(long, default_value_t = false)]
+    pub force: bool,
+
+    /// How to report progress. "json" writes one JSON object per line to
+    /// stdout for each step, command, warning, and error, for CI systems and
+    /// other programs to parse; other messages go to stderr. JSON progress
+    /// implies --interactive false.
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Human)]
+    pub progress: ProgressFormat,
+
+    /// Prints the commands each step would run, with their arguments filled
+    /// in from the selected options, without running anything or writing to
+    /// the work directory. Arguments that depend on the results of earlier
+    /// steps are shown as placeholders, e.g. `<last_sector>`.
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Copies the installation VM's serial console to stderr as the guest
+    /// writes it, in addition to serial.log in the work directory, so that
+    /// non-interactive builds show what Windows Setup is doing. (Interactive
+    /// mode always shows the latest serial output below the step list.)
+    #[arg(long, default_value_t = false)]
+    pub follow_serial: bool,
+
+    /// Writes the build's trace events to stderr as they happen: `-v` writes
+    /// steps, commands, and their outcomes, and `-vv` adds the decisions the
+    /// build makes along the way. Only allowed in non-interactive mode. The
+    /// work directory's build.log has the same events whether or not this is
+    /// set.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Runs qemu-img and sgdisk in containers created from this image, with
+    /// podman (or docker, if podman isn't installed), for hosts that don't
+    /// have them or whose versions don't work. The work directory, the
+    /// output image's directory, and the current directory are mounted into
+    /// the containers at the same paths. QEMU itself still runs on the host.
+    #[arg(long, value_name = "IMAGE")]
+    pub tools_container: Option<String>,
+
+    /// Signs the image manifest written next to the output image with this
+    /// minisign secret key or SSH key (for a key held by ssh-agent, the
+    /// public key). The key mustn't need a passphrase, since the build can't
+    /// ask for one. Overrides the configuration file's `output.sign_key`.
+    #[arg(long, value_name = "PATH")]
+    pub sign_key: Option<Utf8PathBuf>,
+
+    /// Pins what the build can of the things that would otherwise differ
+    /// between two builds from the same inputs (the output image's GPT
+    /// GUIDs, and the installation VM's identity and computer name), so that
+    /// their images can be compared. The image manifest lists what still
+    /// differs. Linux hosts only.
+    #[arg(long, default_value_t = false)]
+    pub reproducible: bool,
+
+    /// The directory through which the builds build-all runs at once share
+    /// the host's VMs and image conversions. build-all passes this to the
+    /// builds it runs; it isn't meant to be passed by hand.
+    #[arg(long, value_name = "DIR", hide = true)]
+    pub slots_dir: Option<Utf8PathBuf>,
+
+    #[command(flatten)]
+    pub disk_monitor: DiskMonitorOptions,
+
+    #[command(flatten)]
+    pub remote: RemoteOptions,
+
     #[command(subcommand)]
     pub command: Command,
 }
 
+impl App {
+    /// Returns the path of the output image. `main` fills it in from the
+    /// configuration file if the command line didn't name one.
+    pub fn output_image(&self) -> &Utf8Path {
+        self.output_image.as_deref().expect("main requires an output image")
+    }
+}
+
+// Options that control how wimsy watches host disk space and the
+// installation VM's progress while it runs. (This is deliberately not a doc
+// comment: clap would use it as the top-level command's description.)
+#[derive(Args, Clone)]
+pub struct DiskMonitorOptions {
+    /// How often, in seconds, to check free space on the filesystems holding
+    /// the work directory and output image while Windows is being installed.
+    #[arg(long, default_value_t = monitor::DEFAULT_INTERVAL_SECS)]
+    pub disk_monitor_interval_secs: u64,
+
+    /// Warn when a monitored filesystem has less than this many MiB free.
+    #[arg(long, default_value_t = monitor::DEFAULT_WARN_FREE_MIB)]
+    pub disk_warn_free_mib: u64,
+
+    /// Stop the installation VM and fail the build when a monitored
+    /// filesystem has less than this many MiB free.
+    #[arg(long, default_value_t = monitor::DEFAULT_MIN_FREE_MIB)]
+    pub disk_min_free_mib: u64,
+
+    /// Stop the installation VM and fail the build if Windows hasn't
+    /// finished installing after this many minutes.
+    #[arg(long = "install-timeout", value_name = "MINUTES")]
+    pub install_timeout_mins: Option<u64>,
+
+    /// Stop the installation VM and fail the build if it goes this many
+    /// minutes without writing to its serial port or disk image, since it's
+    /// probably hung. 0 disables hang detection.
+    #[arg(
+        long = "hang-timeout",
+        value_name = "MINUTES",
+        default_value_t = monitor::DEFAULT_HANG_TIMEOUT_MINS
+    )]
+    pub hang_timeout_mins: u64,
+}
+
+impl DiskMonitorOptions {
+    /// Returns the command-line options that set these options as they're
+    /// set now.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!(
+                "--disk-monitor-interval-secs={}",
+                self.disk_monitor_interval_secs
+            ),
+            format!("--disk-warn-free-mib={}", self.disk_warn_free_mib),
+            format!("--disk-min-free-mib={}", self.disk_min_free_mib),
+            format!("--hang-timeout={}", self.hang_timeout_mins),
+        ];
+        if let Some(mins) = self.install_timeout_mins {
+            args.push(format!("--install-timeout={mins}"));
+        }
+        args
+    }
+
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "disk_monitor_interval_secs".to_string(),
+                self.disk_monitor_interval_secs.to_string(),
+            ),
+            (
+                "disk_warn_free_mib".to_string(),
+                self.disk_warn_free_mib.to_string(),
+            ),
+            (
+                "disk_min_free_mib".to_string(),
+                self.disk_min_free_mib.to_string(),
+            ),
+            (
+                "hang_timeout_mins".to_string(),
+                self.hang_timeout_mins.to_string(),
+            ),
+        ]
+        .into_iter()
+        .chain(
+            self.install_timeout_mins.map(|mins| {
+                ("install_timeout_mins".to_string(), mins.to_string())
+            }),
+        )
+        .collect()
+    }
+}
+
+// Options that run the whole build on another host over SSH. (This is
+// deliberately not a doc comment, for the same reason as above.)
+#[derive(Args, Clone)]
+pub struct RemoteOptions {
+    /// Runs the command on this host (an ssh destination, e.g.
+    /// builder@lab-1) instead of this one. wimsy uploads the input files
+    /// named on the command line with rsync, runs the remote host's wimsy
+    /// with the same options, and downloads the output images, build report,
+    /// and logs. See "Building on a remote host" in README.md.
+    #[arg(long, value_name = "DESTINATION")]
+    pub remote_host: Option<String>,
+
+    /// The directory on the remote host in which to keep uploaded inputs,
+    /// the remote work directory, and output images. Relative paths are
+    /// relative to the remote user's home directory. Inputs are kept between
+    /// builds, so later builds only upload the files that changed.
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = crate::remote::DEFAULT_REMOTE_DIR,
+        requires = "remote_host"
+    )]
+    pub remote_dir: String,
+
+    /// The wimsy executable to run on the remote host.
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "wimsy",
+        requires = "remote_host"
+    )]
+    pub remote_wimsy: String,
+}
+
+// The command is parsed once, so the size of its largest variant doesn't
+// matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Command {
     /// Builds from a set of source files an installation disk suitable for use
@@ -42,10 +303,11 @@ pub enum Command {
     /// suitable for use as an image in an Oxide rack.
     CreateGuestDiskImage {
         /// The name of the physical link on the host machine to which the
-        /// installation VM's VNIC should be bound.
+        /// installation VM's VNIC should be bound. Required with --hypervisor
+        /// propolis or bhyve.
         #[cfg(target_os = "illumos")]
         #[cfg_attr(target_os = "illumos", arg(long))]
-        vnic_link: String,
+        vnic_link: Option<String>,
 
         /// The path to the repacked installation disk (created with the
         /// build-installation-disk subcommand) to use to install Windows.
@@ -53,55 +315,995 @@ pub enum Command {
         #[cfg_attr(target_os = "illumos", arg(long))]
         installer_image: Utf8PathBuf,
 
+        /// The hypervisor to run the installation VM in. With oxide, the
+        /// installation runs in an instance in the --oxide-project project,
+        /// and the image is created there instead of in --output-image.
+        #[cfg(target_os = "illumos")]
+        #[cfg_attr(
+            target_os = "illumos",
+            arg(long, value_enum, default_value_t = Hypervisor::Propolis)
+        )]
+        hypervisor: Hypervisor,
+
         /// The path to the Propolis bootrom (guest firmware image) to supply to
-        /// the installation VM.
+        /// the installation VM. Required with --hypervisor propolis.
         #[cfg(target_os = "illumos")]
         #[cfg_attr(target_os = "illumos", arg(long))]
-        propolis_bootrom: Utf8PathBuf,
+        propolis_bootrom: Option<Utf8PathBuf>,
+
+        /// The path to the bhyve UEFI firmware image to supply to the
+        /// installation VM with --hypervisor bhyve. If not set, wimsy looks
+        /// for one where illumos distributions usually install it.
+        #[cfg(target_os = "illumos")]
+        #[cfg_attr(
+            target_os = "illumos",
+            arg(long, conflicts_with = "propolis_bootrom")
+        )]
+        bhyve_bootrom: Option<Utf8PathBuf>,
 
         #[cfg(target_os = "linux")]
         #[cfg_attr(target_os = "linux", command(flatten))]
         sources: ImageSources,
 
+        /// The processor architecture of the Windows to install. aarch64
+        /// builds run the setup VM with qemu-system-aarch64 on the virt
+        /// machine type, so --ovmf-path must name an AAVMF (Arm UEFI) image,
+        /// and the ISO and drivers must be ARM64 builds. They use KVM on
+        /// aarch64 hosts and TCG (which is very slow) elsewhere.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(long, value_enum, default_value_t = Architecture::X86_64)
+        )]
+        arch: Architecture,
+
         /// The path to the OVMF bootrom to supply to QEMU for use as a guest
-        /// firmware image.
+        /// firmware image. If not set, wimsy looks for one for --arch (and
+        /// --secure-boot) in the locations where distributions usually
+        /// install it, and next to QEMU's own files.
         #[cfg(target_os = "linux")]
         #[cfg_attr(target_os = "linux", arg(long))]
-        ovmf_path: Utf8PathBuf,
+        ovmf_path: Option<Utf8PathBuf>,
+
+        /// Boots the setup VM with Secure Boot enforced. --ovmf-path must
+        /// then name the code image of a Secure Boot OVMF build (e.g.
+        /// OVMF_CODE.secboot.fd), and --ovmf-vars-template a varstore with
+        /// Microsoft's keys enrolled (e.g. OVMF_VARS.secboot.fd or
+        /// OVMF_VARS_4M.ms.fd). x86_64 builds need the q35 machine type. The
+        /// varstore is saved next to the output image, with ".vars.fd"
+        /// appended to its name.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(
+                long,
+                default_value_t = false,
+                requires = "ovmf_vars_template"
+            )
+        )]
+        secure_boot: bool,
+
+        /// The OVMF varstore template to copy for a --secure-boot setup VM.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(long, value_name = "PATH", requires = "secure_boot")
+        )]
+        ovmf_vars_template: Option<Utf8PathBuf>,
+
+        /// Gives the setup VM (and the image test VM) an emulated TPM 2.0,
+        /// which Windows 11 requires. Requires swtpm.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, default_value_t = false))]
+        tpm: bool,
 
         /// Displays a graphical console for the setup VM.
         #[cfg(target_os = "linux")]
         #[cfg_attr(target_os = "linux", arg(long, default_value_t = false))]
         vga_console: bool,
+
+        /// Saves a PNG screenshot of the setup VM's display to the work
+        /// directory's screenshots directory every SECS seconds while Windows
+        /// is being installed, and another if the installation fails, to
+        /// show which dialog (or crash screen) Setup stopped at. Gives the
+        /// VM a display adapter if --vga-console doesn't.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(
+                long,
+                value_name = "SECS",
+                value_parser = clap::value_parser!(u64).range(1..)
+            )
+        )]
+        screenshot_interval_secs: Option<u64>,
+
+        /// Boots the finished image in a throwaway VM and fails the build if
+        /// it doesn't boot within the configuration file's
+        /// `tests.boot_timeout_secs`. CHECK is how to tell that it has:
+        /// "serial" waits for Windows to start its serial console, "agent"
+        /// for the QEMU guest agent to report that Windows has finished
+        /// setting up (the guest setup script then installs the agent), and
+        /// "tcp:PORT" for something in the guest to accept connections on
+        /// PORT. Overrides the configuration file's `tests.boot_check`.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, value_name = "CHECK"))]
+        boot_test: Option<crate::config::BootCheck>,
+
+        /// The accelerator QEMU should use to run the setup VM. "auto" uses
+        /// KVM if it is available and falls back to TCG (software emulation,
+        /// which is much slower) if it is not; on TCG, the image tests get
+        /// longer to finish.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(long, value_enum, default_value_t = Accelerator::Auto)
+        )]
+        accel: Accelerator,
+
+        /// How to run the setup VM: "qemu" launches QEMU directly, and
+        /// "libvirt" has libvirt run it as a transient domain, for hosts
+        /// where QEMU may only run under libvirt (e.g. because of SELinux
+        /// policy or cgroup limits). Requires virsh.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(
+            target_os = "linux",
+            arg(long, value_enum, default_value_t = Hypervisor::Qemu)
+        )]
+        hypervisor: Hypervisor,
+
+        /// The libvirt connection URI for --hypervisor libvirt, e.g.
+        /// "qemu:///system". If not set, virsh connects to its default URI.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, value_name = "URI"))]
+        libvirt_uri: Option<String>,
+
+        /// The format of the output image. qcow2 images only take up as much
+        /// space on the host as the guest has written to them, but the steps
+        /// that edit the image's partition table need qemu-nbd, the nbd
+        /// kernel module, and root privileges to work on them. Overrides the
+        /// configuration file's `output.format`; the default is raw.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, value_enum))]
+        output_format: Option<OutputFormat>,
+
+        /// The size of the disk to install Windows to, e.g. "40G". Suffixes K,
+        /// M, G, and T are powers of 1024, and a number without a suffix is a
+        /// count of bytes. Overrides the configuration file's `disk.size`;
+        /// the default is 30G.
+        #[arg(long, value_name = "SIZE")]
+        disk_size: Option<DiskSize>,
+
+        #[command(flatten)]
+        vm: VmOptions,
+
+        /// Launches the installation VM even if the host doesn't appear to
+        /// have enough free memory for it (e.g. because the host has enough
+        /// swap to make up the difference).
+        #[arg(long, default_value_t = false)]
+        force_memory: bool,
+
+        /// Starts the build even if the filesystems holding the work
+        /// directory and output images don't appear to have room for
+        /// everything the build may write to them. The estimate assumes every
+        /// image grows to the full disk size, which sparse images rarely do.
+        #[arg(long, default_value_t = false)]
+        force_disk_space: bool,
+
+        #[command(flatten)]
+        qcow2: Qcow2Options,
+
+        #[command(flatten)]
+        vhdx: VhdxOptions,
+
+        #[command(flatten)]
+        vmdk: VmdkOptions,
+
+        #[command(flatten)]
+        output_device: OutputDeviceOptions,
+
+        #[command(flatten)]
+        oxide: OxidePublishOptions,
+
+        #[command(flatten)]
+        s3: S3PublishOptions,
+
+        #[command(flatten)]
+        compress: CompressOptions,
+    },
+
+    /// Checks that this host has the tools, virtualization support, and free
+    /// disk space needed to build images, and explains how to fix any
+    /// problems it finds, without building anything. Free space is checked on
+    /// the filesystems that would hold the work directory and output image.
+    Doctor {
+        /// The path to the OVMF bootrom that builds will use. If not set,
+        /// wimsy looks in the locations where distributions usually install
+        /// it.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long))]
+        ovmf_path: Option<Utf8PathBuf>,
+
+        /// The output image format builds will use. qcow2 images need extra
+        /// tools and privileges. Overrides the configuration file's
+        /// `output.format`; the default is raw.
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, value_enum))]
+        output_format: Option<OutputFormat>,
+
+        /// The size of the disk builds will install Windows to. Overrides the
+        /// configuration file's `disk.size`; the default is 30G.
+        #[arg(long, value_name = "SIZE")]
+        disk_size: Option<DiskSize>,
+    },
+
+    /// Lists the editions (the images in install.wim or install.esd) on a
+    /// Windows installation ISO, with the indices to pass to
+    /// --unattend-image-index to select them.
+    ListEditions {
+        /// The path to the Windows installation ISO.
+        #[arg(long)]
+        windows_iso: Utf8PathBuf,
+    },
+
+    /// Describes a raw or qcow2 disk image without changing it: its
+    /// partitions, whether its secondary GPT is intact, and the Windows
+    /// version installed on it, as recorded in its registry. Reading qcow2
+    /// images needs what qcow2 output images do (see --output-format).
+    Inspect {
+        /// The path to the image.
+        #[arg(long)]
+        image: Utf8PathBuf,
     },
+
+    /// Shrinks a raw or qcow2 disk image in place, as builds shrink their
+    /// output images: trims the space after its last partition and rewrites
+    /// its secondary GPT at the new end of the disk.
+    Shrink {
+        /// The path to the image.
+        image: Utf8PathBuf,
+    },
+
+    /// Converts a disk image to another format with the options builds use
+    /// for that format, then checks the result with qemu-img and that it
+    /// presents a disk of the same size. The input's format is detected from
+    /// its contents.
+    Convert {
+        /// The path to the image to convert.
+        #[arg(long)]
+        input: Utf8PathBuf,
+
+        /// The path at which to write the converted image.
+        #[arg(long)]
+        output: Utf8PathBuf,
+
+        /// The format to convert the image to.
+        #[arg(long, value_enum)]
+        format: ImageFormat,
+
+        /// The codec with which to compress qcow2 images.
+        #[arg(long, value_enum, default_value_t = Qcow2Compression::Zlib)]
+        qcow2_compression: Qcow2Compression,
+
+        /// Whether VHDX images allocate their space as they're written to
+        /// (dynamic) or all at once (fixed).
+        #[arg(long, value_enum, default_value_t = VhdxSubformat::Dynamic)]
+        vhdx_subformat: VhdxSubformat,
+    },
+
+    /// Runs create-guest-disk-image for each of the targets in the
+    /// configuration file's `[[targets]]` array, each in its own
+    /// subdirectory of the work directory, and prints how each build went.
+    BuildAll {
+        /// How many targets to build at once. Fewer installation VMs than
+        /// this may run at a time, as many as the host's CPUs and available
+        /// memory can hold, and only one build converts its image with
+        /// qemu-img at a time. Builds that run in parallel aren't interactive:
+        /// they report their progress as lines prefixed with the target's
+        /// name, and write the rest of their output to NAME.log in the work
+        /// directory.
+        #[arg(
+            long,
+            short = 'j',
+            visible_alias = "parallel",
+            value_name = "N",
+            default_value_t = 1
+        )]
+        jobs: usize,
+
+        /// Options to pass to create-guest-disk-image for every target, after
+        /// a `--`, e.g. `-- --unattend-dir unattend`.
+        #[arg(last = true, value_name = "OPTIONS")]
+        build_args: Vec<String>,
+    },
+}
+
+// Options for writing the finished output image to a block device.
+#[derive(Args, Clone, Debug)]
+pub struct OutputDeviceOptions {
+    /// After building the output image, also write it to the start of this
+    /// block device (e.g. a zvol or a logical volume). The device must not be
+    /// in use, and its existing contents are overwritten.
+    #[arg(long, value_name = "PATH")]
+    pub output_device: Option<Utf8PathBuf>,
+
+    /// Discards the entire --output-device before writing the image to it,
+    /// and zeroes the image's empty regions on the device instead of writing
+    /// them, so that thinly-provisioned devices only allocate space for the
+    /// image's contents. This destroys everything on the device. Linux only.
+    #[arg(long, default_value_t = false, requires = "output_device")]
+    pub discard_output_device: bool,
+}
+
+impl OutputDeviceOptions {
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(device) = &self.output_device {
+            vars.push(("output_device".to_string(), device.to_string()));
+            if self.discard_output_device {
+                vars.push(("discard_output_device".to_string(), String::new()));
+            }
+        }
+        vars
+    }
+}
+
+// Options for publishing the finished output image to an Oxide rack.
+#[derive(Args, Clone, Debug)]
+pub struct OxidePublishOptions {
+    /// After building the output image, upload it to this project in an
+    /// Oxide rack and create an image from it, using the oxide CLI (which
+    /// must be installed and logged in to the rack). Requires
+    /// --oxide-image-name.
+    #[arg(long, value_name = "PROJECT", requires = "oxide_image_name")]
+    pub oxide_project: Option<String>,
+
+    /// The name of the Oxide image to create. The image is imported through
+    /// a disk and snapshot with this name plus "-import" and "-snapshot",
+    /// which are left in the project.
+    #[arg(
+        long,
+        value_name = "NAME",
+        requires = "oxide_project",
+        value_parser = crate::oxide::parse_image_name
+    )]
+    pub oxide_image_name: Option<String>,
+
+    /// The Oxide image's description. The default is "Windows image built by
+    /// wimsy".
+    #[arg(long, value_name = "TEXT", requires = "oxide_project")]
+    pub oxide_image_description: Option<String>,
+
+    /// The Oxide image's OS version. The default is the Windows release being
+    /// installed (e.g. "Server 2022"), if it's known.
+    #[arg(long, value_name = "VERSION", requires = "oxide_project")]
+    pub oxide_image_version: Option<String>,
+
+    /// The oxide CLI profile to publish with, if not its default.
+    #[arg(long, value_name = "PROFILE", requires = "oxide_project")]
+    pub oxide_profile: Option<String>,
+}
+
+impl OxidePublishOptions {
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        [
+            ("oxide_project", &self.oxide_project),
+            ("oxide_image", &self.oxide_image_name),
+            ("oxide_image_description", &self.oxide_image_description),
+            ("oxide_image_version", &self.oxide_image_version),
+            ("oxide_profile", &self.oxide_profile),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.clone()?)))
+        .collect()
+    }
+}
+
+// Options for compressing the finished output image.
+#[derive(Args, Clone, Debug)]
+pub struct CompressOptions {
+    /// After building the output image (and any converted images), also
+    /// compress it to a file next to it with the compressor's extension (e.g.
+    /// image.raw.zst). The build report records the sizes and SHA-256 digests
+    /// of the compressed and uncompressed images. Overrides the configuration
+    /// file's `output.compress`; the default is none.
+    #[arg(long, value_enum, value_name = "COMPRESSION")]
+    pub compress: Option<crate::compress::Compression>,
+}
+
+impl CompressOptions {
+    /// Returns these options with the compressor from the configuration file
+    /// if the command line didn't choose one.
+    pub fn with_config_defaults(
+        mut self,
+        config: &crate::config::Config,
+    ) -> Self {
+        self.compress = self.compress.or(config.output.compress);
+        self
+    }
+
+    /// Returns the compressor to use.
+    pub fn compression(&self) -> crate::compress::Compression {
+        self.compress.unwrap_or(crate::compress::Compression::None)
+    }
+
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        match self.compression() {
+            crate::compress::Compression::None => Vec::new(),
+            compression => {
+                vec![("compress".to_string(), compression.to_string())]
+            }
+        }
+    }
+}
+
+// Options for uploading the finished output image to object storage.
+#[derive(Args, Clone, Debug)]
+pub struct S3PublishOptions {
+    /// After building the output image, upload it to this S3 object (e.g.
+    /// s3://bucket/images/windows.raw) using the AWS CLI, which must be
+    /// installed and configured with credentials. If the key ends in a
+    /// slash, the image is uploaded under its own file name (plus the
+    /// compression's extension). The image's SHA-256 digest is uploaded
+    /// alongside it, to the same key plus ".sha256".
+    #[arg(long, value_name = "S3_URL")]
+    pub publish: Option<crate::s3::S3Url>,
+
+    /// Compresses the image as it's uploaded to --publish.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = crate::compress::Compression::None,
+        requires = "publish"
+    )]
+    pub publish_compression: crate::compress::Compression,
+
+    /// The endpoint of the S3-compatible service to upload to, if it isn't
+    /// AWS (e.g. https://minio.example.com:9000).
+    #[arg(long, value_name = "URL", requires = "publish")]
+    pub publish_endpoint_url: Option<String>,
+}
+
+impl S3PublishOptions {
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        let Some(url) = &self.publish else {
+            return Vec::new();
+        };
+
+        let mut vars = vec![
+            ("publish_url".to_string(), url.to_string()),
+            (
+                "publish_compression".to_string(),
+                self.publish_compression.to_string(),
+            ),
+        ];
+        if let Some(endpoint) = &self.publish_endpoint_url {
+            vars.push(("publish_endpoint_url".to_string(), endpoint.clone()));
+        }
+        vars
+    }
+}
+
+/// A compression codec for qcow2 images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Qcow2Compression {
+    Zlib,
+    Zstd,
+}
+
+impl std::fmt::Display for Qcow2Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Qcow2Compression::Zlib => write!(f, "zlib"),
+            Qcow2Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// Options for converting the finished output image to a compressed qcow2
+// image.
+#[derive(Args, Clone, Debug)]
+pub struct Qcow2Options {
+    /// After building the output image, also convert it to a compressed qcow2
+    /// image at this path. A JSON file describing the image (including the
+    /// compression codec used) is written next to it with a ".json" suffix.
+    /// Overrides the configuration file's `output.qcow2_image`.
+    #[arg(long, value_name = "PATH")]
+    pub qcow2_image: Option<Utf8PathBuf>,
+
+    /// The codec with which to compress --qcow2-image. zstd produces smaller
+    /// images, but both qemu-img and the hypervisors that read the image must
+    /// be QEMU 5.1 or later built with zstd support.
+    #[arg(long, value_enum, default_value_t = Qcow2Compression::Zlib)]
+    pub qcow2_compression: Qcow2Compression,
+
+    /// Fails the build if qemu-img (run on the host or in --tools-container)
+    /// doesn't support the requested --qcow2-compression codec instead of
+    /// falling back to zlib. Has no effect unless a qcow2 image is being
+    /// built.
+    #[arg(long, default_value_t = false)]
+    pub strict_qcow2_compression: bool,
+}
+
+impl Qcow2Options {
+    /// Returns these options with the qcow2 image path from the configuration
+    /// file if the command line didn't give one.
+    pub fn with_config_defaults(
+        mut self,
+        config: &crate::config::Config,
+    ) -> Self {
+        if self.qcow2_image.is_none() {
+            self.qcow2_image = config.output.qcow2_image.clone();
+        }
+        self
+    }
+}
+
+// Options that size the installation VM. Each overrides the corresponding
+// setting in the configuration file's [vm] table.
+#[derive(Args, Clone, Debug)]
+pub struct VmOptions {
+    /// The number of vCPUs to give the installation VM. On Linux, the default
+    /// is one fewer than the host's physical cores; on illumos, it's 2.
+    #[arg(long, value_name = "COUNT")]
+    pub vm_cpus: Option<u32>,
+
+    /// The amount of memory, in MiB, to give the installation VM. On Linux,
+    /// the default is the host's memory less 4 GiB, clamped to between 4 and
+    /// 16 GiB; on illumos, it's 2048.
+    #[arg(long, value_name = "MIB")]
+    pub vm_memory_mib: Option<u64>,
+
+    /// The QEMU machine type to give the installation VM (Linux only). The
+    /// image's functional tests run on the same machine type. The default is
+    /// pc for x86_64 builds and virt for aarch64 builds.
+    #[arg(long, value_enum)]
+    pub vm_machine: Option<MachineType>,
+
+    /// The storage controller the installation VM's disk is attached to
+    /// (Linux only). The default, NVMe, is what Oxide instances boot from;
+    /// choose the controller the image's target hypervisor presents, since
+    /// Windows only loads the drivers for its boot disk's controller at boot.
+    /// The image's functional tests use the same controller.
+    #[arg(long, value_enum)]
+    pub disk_controller: Option<DiskController>,
+
+    /// The network adapter to give the installation VM (Linux only). The
+    /// default, virtio-net, needs the NetKVM driver; choose none for a fully
+    /// offline build, which then can't download anything from inside the
+    /// guest. The image's functional tests use the same adapter.
+    #[arg(long, value_enum)]
+    pub nic_model: Option<NicModel>,
+}
+
+/// A QEMU machine type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MachineType {
+    /// The i440FX chipset, with an IDE controller.
+    Pc,
+
+    /// The Q35 chipset, with an AHCI (SATA) controller.
+    Q35,
+
+    /// QEMU's generic Arm board, for aarch64 builds. It has no IDE or SATA
+    /// controller, so CD-ROMs are attached over USB.
+    Virt,
+}
+
+impl std::fmt::Display for MachineType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MachineType::Pc => write!(f, "pc"),
+            MachineType::Q35 => write!(f, "q35"),
+            MachineType::Virt => write!(f, "virt"),
+        }
+    }
+}
+
+/// A storage controller for a QEMU VM's disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiskController {
+    /// An NVMe controller, for which Windows has an inbox driver.
+    #[default]
+    Nvme,
+
+    /// A virtio-blk device, which needs the viostor driver.
+    VirtioBlk,
+
+    /// A virtio-scsi controller, which needs the vioscsi driver.
+    VirtioScsi,
+
+    /// An AHCI (SATA) controller, for which Windows has an inbox driver.
+    Ahci,
+}
+
+impl std::fmt::Display for DiskController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskController::Nvme => write!(f, "nvme"),
+            DiskController::VirtioBlk => write!(f, "virtio-blk"),
+            DiskController::VirtioScsi => write!(f, "virtio-scsi"),
+            DiskController::Ahci => write!(f, "ahci"),
+        }
+    }
+}
+
+/// A network adapter for a QEMU VM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NicModel {
+    /// An emulated Intel 82574, for which Windows has an inbox driver.
+    E1000e,
+
+    /// A virtio-net device, which needs the NetKVM driver.
+    #[default]
+    VirtioNet,
+
+    /// No network adapter at all.
+    None,
+}
+
+impl std::fmt::Display for NicModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NicModel::E1000e => write!(f, "e1000e"),
+            NicModel::VirtioNet => write!(f, "virtio-net"),
+            NicModel::None => write!(f, "none"),
+        }
+    }
+}
+
+/// The size of a disk, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiskSize(pub u64);
+
+impl DiskSize {
+    /// The size of the output image if neither the command line nor the
+    /// configuration file chooses one.
+    pub const DEFAULT: DiskSize = DiskSize(30 * 1024 * 1024 * 1024);
+
+    /// The free space setup leaves on the OS partition of minimal images if
+    /// the user doesn't choose an amount.
+    pub const MINIMAL_SHRINK_HEADROOM: DiskSize = DiskSize(1024 * 1024 * 1024);
+
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl std::str::FromStr for DiskSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 30),
+            Some((i, 'T' | 't')) => (&s[..i], 40),
+            _ => (s, 0),
+        };
+
+        let count: u64 = digits
+            .parse()
+            .map_err(|_| format!("'{s}' isn't a size like \"40G\""))?;
+        let bytes = count
+            .checked_mul(1 << shift)
+            .ok_or_else(|| format!("'{s}' is too large"))?;
+        if bytes == 0 {
+            return Err("the disk size can't be 0".to_string());
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for DiskSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (shift, suffix) in [(40, 'T'), (30, 'G'), (20, 'M'), (10, 'K')] {
+            if self.0.is_multiple_of(1 << shift) {
+                return write!(f, "{}{suffix}", self.0 >> shift);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A VHDX image's allocation policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum VhdxSubformat {
+    /// Space is allocated as the guest writes to the disk.
+    Dynamic,
+
+    /// All of the disk's space is allocated when the image is created.
+    Fixed,
+}
+
+impl std::fmt::Display for VhdxSubformat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VhdxSubformat::Dynamic => write!(f, "dynamic"),
+            VhdxSubformat::Fixed => write!(f, "fixed"),
+        }
+    }
+}
+
+// Options for converting the finished output image to a VHDX image for
+// Hyper-V.
+#[derive(Args, Clone, Debug)]
+pub struct VhdxOptions {
+    /// After building the output image, also convert it to a VHDX image at
+    /// this path. Overrides the configuration file's `output.vhdx_image`.
+    #[arg(long, value_name = "PATH")]
+    pub vhdx_image: Option<Utf8PathBuf>,
+
+    /// Whether --vhdx-image allocates its space as it's written to (dynamic)
+    /// or all at once (fixed).
+    #[arg(long, value_enum, default_value_t = VhdxSubformat::Dynamic)]
+    pub vhdx_subformat: VhdxSubformat,
+}
+
+impl VhdxOptions {
+    /// Returns these options with the VHDX image path from the configuration
+    /// file if the command line didn't give one.
+    pub fn with_config_defaults(
+        mut self,
+        config: &crate::config::Config,
+    ) -> Self {
+        if self.vhdx_image.is_none() {
+            self.vhdx_image = config.output.vhdx_image.clone();
+        }
+        self
+    }
+
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(image) = &self.vhdx_image {
+            vars.push(("vhdx_image".to_string(), image.to_string()));
+            vars.push((
+                "vhdx_subformat".to_string(),
+                self.vhdx_subformat.to_string(),
+            ));
+        }
+        vars
+    }
+}
+
+// Options for converting the finished output image to a VMDK image for
+// vSphere and ESXi.
+#[derive(Args, Clone, Debug)]
+pub struct VmdkOptions {
+    /// After building the output image, also convert it to a
+    /// stream-optimized VMDK image at this path, which vCenter can import
+    /// directly. Overrides the configuration file's `output.vmdk_image`.
+    #[arg(long, value_name = "PATH")]
+    pub vmdk_image: Option<Utf8PathBuf>,
+}
+
+impl VmdkOptions {
+    /// Returns these options with the VMDK image path from the configuration
+    /// file if the command line didn't give one.
+    pub fn with_config_defaults(
+        mut self,
+        config: &crate::config::Config,
+    ) -> Self {
+        if self.vmdk_image.is_none() {
+            self.vmdk_image = config.output.vmdk_image.clone();
+        }
+        self
+    }
+
+    /// Yields the context variables that carry these options to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(image) = &self.vmdk_image {
+            vars.push(("vmdk_image".to_string(), image.to_string()));
+        }
+        vars
+    }
+}
+
+/// How the build reports its progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    Human,
+    Json,
+}
+
+/// A disk image format for the output image.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Raw,
+    Qcow2,
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Raw => write!(f, "raw"),
+            OutputFormat::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
+/// A disk image format the convert command can write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    Vhdx,
+    Vmdk,
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageFormat::Raw => write!(f, "raw"),
+            ImageFormat::Qcow2 => write!(f, "qcow2"),
+            ImageFormat::Vhdx => write!(f, "vhdx"),
+            ImageFormat::Vmdk => write!(f, "vmdk"),
+        }
+    }
+}
+
+/// A hypervisor that can run the installation VM on illumos.
+#[cfg(target_os = "illumos")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Hypervisor {
+    /// propolis-standalone, the VMM Oxide instances run in.
+    Propolis,
+
+    /// bhyve(8), which ships with illumos distributions.
+    Bhyve,
+
+    /// A temporary instance on an Oxide rack, created with the oxide CLI.
+    Oxide,
+}
+
+#[cfg(target_os = "illumos")]
+impl std::fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hypervisor::Propolis => write!(f, "propolis"),
+            Hypervisor::Bhyve => write!(f, "bhyve"),
+            Hypervisor::Oxide => write!(f, "oxide"),
+        }
+    }
+}
+
+/// How to run the setup VM on Linux.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Hypervisor {
+    /// Launch QEMU directly.
+    Qemu,
+
+    /// Have libvirt run QEMU in a transient domain.
+    Libvirt,
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hypervisor::Qemu => write!(f, "qemu"),
+            Hypervisor::Libvirt => write!(f, "libvirt"),
+        }
+    }
+}
+
+/// A QEMU accelerator.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Accelerator {
+    Auto,
+    Kvm,
+    Tcg,
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for Accelerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Accelerator::Auto => write!(f, "auto"),
+            Accelerator::Kvm => write!(f, "kvm"),
+            Accelerator::Tcg => write!(f, "tcg"),
+        }
+    }
 }
 
 #[derive(Args, Clone)]
 pub struct ImageSources {
-    /// The path to the Windows setup ISO to use for this operation.
+    /// The path to the Windows setup ISO to use for this operation. This,
+    /// --iso-url, or the configuration file's `media.windows_iso` or
+    /// `media.iso_url` is required; any of them on the command line overrides
+    /// the configuration file's.
     #[arg(long)]
-    pub windows_iso: Utf8PathBuf,
+    pub windows_iso: Option<Utf8PathBuf>,
+
+    /// A URL from which to download the Windows setup ISO instead of naming a
+    /// local ISO with --windows-iso. Requires --iso-sha256. The ISO is cached
+    /// in the work directory and reused by later builds; an interrupted
+    /// download resumes where it left off.
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with = "windows_iso",
+        requires = "iso_sha256"
+    )]
+    pub iso_url: Option<String>,
+
+    /// The SHA-256 digest, in hex, that the ISO downloaded from --iso-url
+    /// must have. Builds stop if the downloaded ISO's digest doesn't match.
+    #[arg(long, value_name = "DIGEST", requires = "iso_url")]
+    pub iso_sha256: Option<String>,
 
     /// A path to an ISO containing signed virtio-net and virtio-block drivers
     /// to install. The drivers on this disk must have the directory structure
     /// the Fedora project uses in its virtio driver disks:
     ///
-    /// - Top-level directories named `viostor` and `NetKVM`
+    /// - Top-level directories named `viostor` and `NetKVM` (and, to install
+    ///   to virtio-scsi disks, `vioscsi`)
     ///
     /// - Within each of these directories, subdirectories named `2k16`, `2k19`,
     ///   `2k22`, and `2k25`
     ///
-    /// - Within each of these directories, an `amd64` subdirectory, which
-    ///   contains `.cat`, `.inf`, and `.sys` files (i.e. the driver collateral
-    ///   itself)
+    /// - Within each of these directories, an `amd64` subdirectory (`ARM64`
+    ///   for aarch64 builds), which contains `.cat`, `.inf`, and `.sys` files
+    ///   (i.e. the driver collateral itself)
+    ///
+    /// This, --virtio-driver-dir, --virtio-iso-manifest, or the corresponding
+    /// setting in the configuration file's `[media]` table is required; any
+    /// of them on the command line overrides the configuration file's.
     #[arg(long)]
-    pub virtio_iso: Utf8PathBuf,
+    pub virtio_iso: Option<Utf8PathBuf>,
+
+    /// A directory containing virtio drivers, laid out like the driver ISO
+    /// described under --virtio-iso, to use instead of a driver ISO.
+    #[arg(long, value_name = "DIR", conflicts_with = "virtio_iso")]
+    pub virtio_driver_dir: Option<Utf8PathBuf>,
+
+    /// A manifest pinning a virtio-win driver ISO release to download instead
+    /// of naming a driver ISO with --virtio-iso. The manifest gives the
+    /// release's version, URL, and SHA-256 digest (see the README). The ISO
+    /// is cached in the work directory and reused by later builds.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["virtio_iso", "virtio_driver_dir"]
+    )]
+    pub virtio_iso_manifest: Option<Utf8PathBuf>,
+
+    /// Leaves the Windows PE driver settings in the template Autounattend.xml
+    /// as they are. By default, the windowsPE pass is given a
+    /// Microsoft-Windows-PnpCustomizationsWinPE component that loads the
+    /// virtio storage and network drivers from the driver disc, so that
+    /// Windows can be installed to a virtio disk.
+    #[arg(long, default_value_t = false)]
+    pub skip_winpe_drivers: bool,
 
     /// The path to a directory containing the unattend files to inject into the
     /// image.
     #[arg(long)]
     pub unattend_dir: Utf8PathBuf,
 
+    /// The path to a directory of unattend files that override those in
+    /// --unattend-dir. A file (or template) here with the same name as one of
+    /// the unattend files is used instead of that file. A file named after an
+    /// answer file and one of its configuration passes, such as
+    /// `Autounattend.oobeSystem.xml` or `specialize-unattend.specialize.xml`,
+    /// is an answer file containing settings for just that pass, which
+    /// replace the pass's settings in the answer file (or are added to it if
+    /// it has none). Overrides the configuration file's `unattend.overrides`.
+    #[arg(long, value_name = "DIR")]
+    pub unattend_overrides: Option<Utf8PathBuf>,
+
     /// An optional image index to write into the Microsoft-Windows-Setup
     /// component's ImageInstall/OSImage/InstallFrom elements in
     /// Autounattend.xml. This index determines the edition of Windows that will
@@ -112,13 +1314,1119 @@ pub struct ImageSources {
     #[arg(long)]
     pub unattend_image_index: Option<u32>,
 
-    /// An optional Windows Server version that specifies the driver
-    /// installation paths to specify in Autounattend.xml. If set, this
-    /// substitutes the appropriate versioned directory name ("2k16", "2k19",
-    /// "2k22", or "2k25") into the DriverPaths specified in the template
-    /// Autounattend.xml
-    /// specified by --unattend-dir. If not specified, the existing driver paths
-    /// in that Autounattend.xml are used.
+    /// The edition of Windows to install: an image index, or an edition name
+    /// such as "standard" or "datacenter" with an optional "-core" (Server
+    /// Core) or "-desktop" (Desktop Experience) suffix. Names are looked up
+    /// in the Windows ISO's image list (see the list-editions command) and
+    /// the matching image's index is written into Autounattend.xml as for
+    /// --unattend-image-index. A name without a suffix selects the Desktop
+    /// Experience. Overrides the configuration file's `media.edition`.
+    #[arg(long, conflicts_with = "unattend_image_index")]
+    pub edition: Option<crate::wim::Edition>,
+
+    /// An optional Windows version that specifies the driver installation paths
+    /// to specify in Autounattend.xml. If set, this substitutes the appropriate
+    /// versioned directory name ("2k16", "2k19", "2k22", "2k25", "w10", or
+    /// "w11") into the DriverPaths specified in the template Autounattend.xml
+    /// specified by --unattend-dir. Client releases also turn on the answer
+    /// files' client settings (see CONFIGURING.md). If not specified, the
+    /// version is detected from the Windows ISO's image metadata; if it can't
+    /// be, the existing driver paths in that Autounattend.xml are used.
+    /// Overrides the configuration file's `media.windows_version`.
     #[arg(long, value_enum)]
     pub windows_version: Option<WindowsVersion>,
+
+    /// The path to a Windows update package (.msu or .cab) to slipstream into
+    /// the installation media's install image before Windows is installed.
+    /// May be specified multiple times; packages are added in the order
+    /// given, after any listed in the configuration file's
+    /// `updates.packages`. Requires --edition or --unattend-image-index and
+    /// xorriso.
+    #[arg(long = "update", value_name = "PATH")]
+    pub updates: Vec<Utf8PathBuf>,
+
+    /// The path to a PEM or DER file containing X.509 certificates to add to
+    /// the image's LocalMachine\Root (trusted root) certificate store. May be
+    /// specified multiple times, in addition to the configuration file's
+    /// `certificates.trusted`.
+    #[arg(long = "trusted-cert", value_name = "PATH")]
+    pub trusted_certs: Vec<Utf8PathBuf>,
+
+    /// The path to a PEM or DER file containing X.509 certificates to add to
+    /// the image's LocalMachine\CA (intermediate certification authorities)
+    /// certificate store, for CAs whose certificates servers don't send
+    /// along. May be specified multiple times, in addition to the
+    /// configuration file's `certificates.intermediate`.
+    #[arg(long = "intermediate-cert", value_name = "PATH")]
+    pub intermediate_certs: Vec<Utf8PathBuf>,
+
+    /// The path to a PowerShell script to run in the guest once Windows and
+    /// the Oxide guest tools are installed, before the image is generalized.
+    /// May be specified multiple times; scripts run in the order given, after
+    /// any listed in the configuration file's `provision.scripts`. A script
+    /// that exits with a non-zero status fails the build.
+    #[arg(long = "provision-script", value_name = "PATH")]
+    pub provision_scripts: Vec<Utf8PathBuf>,
+
+    /// A Windows feature to add to the image before any provisioning scripts
+    /// run: a server role or feature such as "Web-Server", or an optional
+    /// feature such as "NetFx3". May be specified multiple times, in addition
+    /// to the configuration file's `provision.windows_features`.
+    #[arg(long = "windows-feature", value_name = "NAME")]
+    pub windows_features: Vec<crate::features::WindowsFeature>,
+
+    /// A Windows feature to remove from the image before any provisioning
+    /// scripts run. May be specified multiple times, in addition to the
+    /// configuration file's `provision.remove_features`.
+    #[arg(long = "remove-feature", value_name = "NAME")]
+    pub remove_features: Vec<crate::features::WindowsFeature>,
+
+    /// A host file to copy into the image, as SOURCE=DEST. DEST is an absolute
+    /// Windows path; if it ends in a backslash, the file is copied into that
+    /// directory under its own name. May be specified multiple times, in
+    /// addition to the configuration file's `[[files]]` tables. Files are
+    /// copied before any provisioning scripts run.
+    #[arg(long = "copy-file", value_name = "SOURCE=DEST")]
+    pub files: Vec<crate::provision::FileCopy>,
+
+    /// Leaves the installed image specialized instead of generalizing it with
+    /// sysprep at the end of setup. Generalized images get a new identity
+    /// (e.g. a new SID and computer name) when they first boot, so this is
+    /// only useful for images that will only ever be deployed once, such as
+    /// domain-joined test images.
+    #[arg(long, default_value_t = false)]
+    pub skip_generalize: bool,
+
+    /// Overwrites the free space on the OS partition with zeroes at the end
+    /// of setup, after the partition is shrunk, so that the data of files
+    /// setup deleted doesn't take up space in compressed or sparse copies of
+    /// the image. This adds a minute or two to the build. Also enabled by the
+    /// configuration file's `disk.zero_free_space`.
+    #[arg(long, default_value_t = false)]
+    pub zero_free_space: bool,
+
+    /// Makes the output image as small as possible: setup consolidates the
+    /// files on the OS partition before shrinking it, and leaves only 1G of
+    /// free space on it (unless --shrink-headroom says otherwise). Also
+    /// enabled by the configuration file's `disk.minimal_image`.
+    #[arg(long, default_value_t = false)]
+    pub minimal_image: bool,
+
+    /// How much free space setup leaves on the OS partition when it shrinks
+    /// the partition to fit its contents. The default is 3G, or 1G with
+    /// --minimal-image. Overrides the configuration file's
+    /// `disk.shrink_headroom`.
+    #[arg(long, value_name = "SIZE")]
+    pub shrink_headroom: Option<DiskSize>,
+
+    /// Has deployed images grow their OS partition to fill their disk when
+    /// they first boot, even if cloudbase-init (which normally does this) is
+    /// skipped. Also enabled by the configuration file's
+    /// `disk.expand_on_first_boot`.
+    #[arg(long, default_value_t = false)]
+    pub expand_on_first_boot: bool,
+
+    /// The product key built images activate with: a key of the form
+    /// XXXXX-XXXXX-XXXXX-XXXXX-XXXXX, "kms" for Microsoft's KMS client setup
+    /// key for the edition being installed, or "avma" for its Automatic
+    /// Virtual Machine Activation key. The key is written into the answer
+    /// files. Overrides the configuration file's `activation.product_key`.
+    #[arg(long, value_name = "KEY")]
+    pub product_key: Option<crate::activation::ProductKey>,
+
+    /// The KMS host (HOST or HOST:PORT) that built images activate against.
+    /// If not specified, Windows finds a KMS host through DNS. Overrides the
+    /// configuration file's `activation.kms_host`.
+    #[arg(long, value_name = "HOST")]
+    pub kms_host: Option<crate::activation::KmsHost>,
+
+    /// Where to read the password for the image's built-in Administrator
+    /// account from: "env:NAME" reads an environment variable, "file:PATH"
+    /// reads a file, and "prompt" asks for it when the unattend files are
+    /// prepared. The password is written, encoded, into the templates'
+    /// `admin_password` variable; the repo's specialize-unattend.xml then
+    /// sets it and leaves the account enabled instead of disabling it.
+    #[arg(long, value_name = "SOURCE")]
+    pub admin_password: Option<SecretSource>,
+
+    /// The computer name built images take (at most 15 letters, digits, and
+    /// hyphens). A `*` in the name, as in "web-*", is replaced with five
+    /// random characters when the unattend files are prepared, so each build
+    /// gets a different name; every VM deployed from one image still shares
+    /// its name. "*" on its own has Windows make up a new name each time a
+    /// generalized image is deployed. Sets the unattend templates'
+    /// `computer_name` variable, taking precedence over `--template-var`.
+    #[arg(long, value_name = "NAME")]
+    pub hostname: Option<crate::hostname::Hostname>,
+
+    /// Leaves Remote Desktop off in built images. By default, the repo's
+    /// specialize-unattend.xml turns on Remote Desktop and opens its firewall
+    /// rules when a deployed image is specialized. Also set by the
+    /// configuration file's `rdp.enable = false`.
+    #[arg(long, default_value_t = false)]
+    pub skip_rdp: bool,
+
+    /// Installs and enables the OpenSSH server in images built with
+    /// --skip-cloudbase-init. Images with cloudbase-init get it regardless,
+    /// so that deployed instances can be reached with the keys in their
+    /// metadata. Also enabled by the configuration file's `ssh.enable` or by
+    /// --ssh-authorized-keys.
+    #[arg(long, default_value_t = false)]
+    pub enable_ssh: bool,
+
+    /// The path to an OpenSSH authorized keys file whose keys may log on to
+    /// built images as any administrator. The keys are installed as the
+    /// image's `C:\ProgramData\ssh\administrators_authorized_keys`.
+    /// Implies --enable-ssh. Overrides the configuration file's
+    /// `ssh.authorized_keys`.
+    #[arg(long, value_name = "PATH")]
+    pub ssh_authorized_keys: Option<Utf8PathBuf>,
+
+    #[command(flatten)]
+    pub domain_join: DomainJoinOptions,
+
+    #[command(flatten)]
+    pub regional: RegionalOptions,
+
+    #[command(flatten)]
+    pub network: NetworkOptions,
+
+    #[command(flatten)]
+    pub cloudbase_init: CloudbaseInitOptions,
+
+    /// Defines a variable for the unattend file templates (see
+    /// CONFIGURING.md), e.g. `--template-var timezone=UTC`. May be specified
+    /// multiple times. Overrides the same variable in the configuration
+    /// file's `[template-vars]` table and any built-in variable with the same
+    /// name.
+    #[arg(long = "template-var", value_name = "NAME=VALUE")]
+    pub template_vars: Vec<crate::template::UserVar>,
+
+    /// Local user accounts to create in built images, from the configuration
+    /// file's `[[users]]` tables.
+    #[arg(skip)]
+    pub local_users: Vec<crate::users::LocalUser>,
+
+    /// Registry values to set in built images, from the configuration file's
+    /// `[[registry]]` tables.
+    #[arg(skip)]
+    pub registry_settings: Vec<crate::registry_settings::RegistrySetting>,
+}
+
+impl ImageSources {
+    /// Returns these sources with the settings from the configuration file
+    /// `config` filled in where the command line didn't set them. Template
+    /// variables from the file are added ahead of those passed on the command
+    /// line, so that the command line's take precedence. Fails if neither
+    /// names a Windows ISO or a source of virtio drivers.
+    pub fn with_config_defaults(
+        mut self,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<Self> {
+        let media = &config.media;
+        if self.windows_iso.is_none() && self.iso_url.is_none() {
+            self.windows_iso = media.windows_iso.clone();
+            self.iso_url = media.iso_url.clone();
+            self.iso_sha256 = media.iso_sha256.clone();
+        }
+        if self.windows_iso.is_none() && self.iso_url.is_none() {
+            anyhow::bail!(
+                "no Windows ISO was given: pass --windows-iso or --iso-url, \
+                or set media.windows_iso or media.iso_url in the \
+                configuration file"
+            );
+        }
+
+        if self.virtio_iso.is_none()
+            && self.virtio_driver_dir.is_none()
+            && self.virtio_iso_manifest.is_none()
+        {
+            self.virtio_iso = media.virtio_iso.clone();
+            self.virtio_driver_dir = media.virtio_driver_dir.clone();
+            self.virtio_iso_manifest = media.virtio_iso_manifest.clone();
+        }
+        if self.virtio_iso.is_none()
+            && self.virtio_driver_dir.is_none()
+            && self.virtio_iso_manifest.is_none()
+        {
+            anyhow::bail!(
+                "no virtio drivers were given: pass --virtio-iso, \
+                --virtio-driver-dir, or --virtio-iso-manifest, or set one of \
+                them in the configuration file's [media] table"
+            );
+        }
+
+        self.windows_version = self.windows_version.or(media.windows_version);
+        if self.edition.is_none() && self.unattend_image_index.is_none() {
+            self.edition = media.edition.clone();
+        }
+
+        let cli = std::mem::take(&mut self.template_vars);
+        self.template_vars =
+            config.template_vars.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.provision_scripts);
+        self.provision_scripts =
+            config.provision.scripts.iter().cloned().chain(cli).collect();
+        let provision = &config.provision;
+        let cli = std::mem::take(&mut self.windows_features);
+        self.windows_features =
+            provision.windows_features.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.remove_features);
+        self.remove_features =
+            provision.remove_features.iter().cloned().chain(cli).collect();
+        let cloudbase_init = &mut self.cloudbase_init;
+        let config_cloudbase_init = &config.cloudbase_init;
+        cloudbase_init.skip_cloudbase_init |= config_cloudbase_init.skip;
+        if cloudbase_init.cloudbase_init_msi.is_none()
+            && cloudbase_init.cloudbase_init_url.is_none()
+        {
+            cloudbase_init.cloudbase_init_msi =
+                config_cloudbase_init.msi.clone();
+            cloudbase_init.cloudbase_init_url =
+                config_cloudbase_init.url.clone();
+        }
+        let cli = std::mem::take(&mut cloudbase_init.cloudbase_init_settings);
+        cloudbase_init.cloudbase_init_settings =
+            config_cloudbase_init.settings.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.files);
+        self.files = config.files.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.updates);
+        self.updates =
+            config.updates.packages.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.trusted_certs);
+        self.trusted_certs =
+            config.certificates.trusted.iter().cloned().chain(cli).collect();
+        let cli = std::mem::take(&mut self.intermediate_certs);
+        self.intermediate_certs = config
+            .certificates
+            .intermediate
+            .iter()
+            .cloned()
+            .chain(cli)
+            .collect();
+        self.local_users = config.users.clone();
+        self.registry_settings = config.registry.clone();
+        self.zero_free_space |= config.disk.zero_free_space;
+        self.minimal_image |= config.disk.minimal_image;
+        self.expand_on_first_boot |= config.disk.expand_on_first_boot;
+        if self.shrink_headroom.is_none() {
+            self.shrink_headroom = config.disk.shrink_headroom;
+        }
+        if self.unattend_overrides.is_none() {
+            self.unattend_overrides = config.unattend.overrides.clone();
+        }
+        if self.product_key.is_none() {
+            self.product_key = config.activation.product_key.clone();
+        }
+        if self.kms_host.is_none() {
+            self.kms_host = config.activation.kms_host.clone();
+        }
+        let network = &mut self.network;
+        if network.static_ip.is_none() {
+            network.static_ip = config.network.address;
+            network.gateway = config.network.gateway;
+        }
+        if network.dns_servers.is_empty() {
+            network.dns_servers = config.network.dns_servers.clone();
+        }
+        if network.network_interface.is_none() {
+            network.network_interface = config.network.interface.clone();
+        }
+        self.skip_rdp |= !config.rdp.enable;
+        self.enable_ssh |= config.ssh.enable;
+        if self.ssh_authorized_keys.is_none() {
+            self.ssh_authorized_keys = config.ssh.authorized_keys.clone();
+        }
+        if !self.domain_join.is_requested() {
+            let join = &config.domain_join;
+            self.domain_join = DomainJoinOptions {
+                join_domain: join.domain.clone(),
+                join_ou: join.ou.clone(),
+                join_user: join.user.clone(),
+                join_password: join.password.clone(),
+                join_unsecure: join.unsecure,
+                join_machine_password: join.machine_password.clone(),
+                djoin_blob: join.djoin_blob.clone(),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Returns the paths from which the unattend files will be read: for
+    /// each of the unattend files, its override or template if there is one,
+    /// followed by any per-pass answer file overrides.
+    pub fn unattend_source_paths(&self) -> Vec<Utf8PathBuf> {
+        let overrides = self.unattend_overrides.as_deref();
+        let mut paths: Vec<_> = crate::UNATTEND_FILES
+            .iter()
+            .map(|file| {
+                overrides
+                    .map(|dir| crate::util::unattend_source_path(dir, file))
+                    .filter(|path| path.exists())
+                    .unwrap_or_else(|| {
+                        crate::util::unattend_source_path(
+                            &self.unattend_dir,
+                            file,
+                        )
+                    })
+            })
+            .collect();
+
+        if let Some(dir) = overrides {
+            paths.extend(
+                crate::steps::pass_overrides(dir)
+                    .into_iter()
+                    .map(|(_, _, path)| path),
+            );
+        }
+
+        paths
+    }
+
+    /// Checks that the Administrator password, if one was requested, can be
+    /// read.
+    pub fn check_admin_password(&self) -> Vec<String> {
+        self.admin_password
+            .iter()
+            .filter_map(|source| source.check("Administrator password").err())
+            .map(|e| format!("{e:#}"))
+            .collect()
+    }
+
+    /// Checks that --unattend-overrides, if set, names a directory.
+    pub fn check_unattend_overrides(&self) -> Vec<String> {
+        match &self.unattend_overrides {
+            Some(dir) if !dir.is_dir() => {
+                vec![format!("unattend overrides directory '{dir}' not found")]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Yields the files these sources name: the installation media, the
+    /// unattend files, and any update packages, trusted certificates,
+    /// provisioning scripts, files to copy into the image, and authorized SSH
+    /// keys.
+    pub fn input_files(&self) -> Vec<Utf8PathBuf> {
+        let mut files: Vec<_> = self.windows_iso.iter().cloned().collect();
+        files.extend(self.virtio_iso.iter().cloned());
+        files.extend(self.virtio_driver_dir.iter().cloned());
+        files.extend(self.virtio_iso_manifest.iter().cloned());
+        files.extend(self.unattend_source_paths());
+        files.extend(self.updates.iter().cloned());
+        files.extend(self.trusted_certs.iter().cloned());
+        files.extend(self.intermediate_certs.iter().cloned());
+        files.extend(self.provision_scripts.iter().cloned());
+        files.extend(self.files.iter().map(|file| file.source.clone()));
+        files.extend(self.cloudbase_init.cloudbase_init_msi.iter().cloned());
+        files.extend(self.ssh_authorized_keys.iter().cloned());
+        files
+    }
+
+    /// Returns a description of where the Windows setup ISO comes from: its
+    /// path, or the URL it will be downloaded from.
+    pub fn windows_iso_source(&self) -> &str {
+        match (&self.windows_iso, &self.iso_url) {
+            (Some(iso), _) => iso.as_str(),
+            (None, Some(url)) => url,
+            (None, None) => {
+                unreachable!("with_config_defaults requires a Windows ISO")
+            }
+        }
+    }
+
+    /// Returns the download that --iso-url and --iso-sha256 describe, if
+    /// they were passed.
+    pub fn windows_iso_download(
+        &self,
+    ) -> anyhow::Result<Option<crate::download::Download>> {
+        let (Some(url), Some(sha256)) = (&self.iso_url, &self.iso_sha256)
+        else {
+            return Ok(None);
+        };
+
+        crate::download::Download::new(url, sha256).map(Some)
+    }
+
+    /// Checks that the Windows setup ISO exists and looks like installation
+    /// media, or that it can be downloaded, returning errors and warnings.
+    pub fn check_windows_iso_prerequisites(
+        &self,
+    ) -> (Vec<String>, Vec<String>) {
+        if let Some(iso) = &self.windows_iso {
+            let errors = crate::util::check_file_prerequisites(
+                std::slice::from_ref(iso),
+            );
+            if !errors.is_empty() {
+                return (errors, Vec::new());
+            }
+
+            let warnings = crate::media::check_installation_media(iso);
+            let mut errors = Vec::new();
+            if warnings.is_empty() {
+                errors.extend(self.check_edition(iso));
+            }
+
+            return (errors, warnings);
+        }
+
+        let mut errors = Vec::new();
+        if let Err(e) = self.windows_iso_download() {
+            errors.push(format!("invalid Windows ISO download: {e:#}"));
+        }
+
+        if which::which("curl").is_err() {
+            errors
+                .push("curl is needed to download the Windows ISO".to_string());
+        }
+
+        (errors, Vec::new())
+    }
+
+    /// Checks that --edition, if it names an edition, selects one of the
+    /// images in `iso`.
+    fn check_edition(&self, iso: &Utf8Path) -> Option<String> {
+        let edition = self.edition.as_ref()?;
+        let images = crate::wim::read_install_images(iso).ok()?;
+        edition.select(&images).err().map(|e| format!("{e:#}"))
+    }
+
+    /// Returns a label for the source of the virtio drivers (an ISO, a
+    /// directory, or a manifest naming an ISO to download) and its path.
+    pub fn driver_source(&self) -> (&'static str, &Utf8Path) {
+        if let Some(iso) = &self.virtio_iso {
+            ("Virtio driver ISO", iso)
+        } else if let Some(dir) = &self.virtio_driver_dir {
+            ("Virtio driver directory", dir)
+        } else if let Some(manifest) = &self.virtio_iso_manifest {
+            ("Virtio driver manifest", manifest)
+        } else {
+            unreachable!("with_config_defaults requires a driver source")
+        }
+    }
+
+    /// Checks that the virtio driver source exists and, if it's a directory,
+    /// has the drivers for `arch` that installing to a disk on `controller`,
+    /// with the network adapter `nic`, needs, returning errors and warnings.
+    pub fn check_driver_prerequisites(
+        &self,
+        arch: Architecture,
+        controller: DiskController,
+        nic: NicModel,
+    ) -> (Vec<String>, Vec<String>) {
+        match (&self.virtio_iso, &self.virtio_driver_dir) {
+            (Some(iso), _) => (
+                crate::util::check_file_prerequisites(std::slice::from_ref(
+                    iso,
+                )),
+                Vec::new(),
+            ),
+            (None, Some(dir)) => crate::drivers::check_driver_dir(
+                dir,
+                self.windows_version,
+                arch,
+                controller,
+                nic,
+            ),
+            (None, None) => {
+                let Some(manifest) = &self.virtio_iso_manifest else {
+                    return (Vec::new(), Vec::new());
+                };
+
+                let mut errors = Vec::new();
+                if let Err(e) = crate::drivers::VirtioManifest::load(manifest) {
+                    errors.push(format!("{e:#}"));
+                }
+
+                if which::which("curl").is_err() {
+                    errors.push(
+                        "curl is needed to download the virtio driver ISO"
+                            .to_string(),
+                    );
+                }
+
+                (errors, Vec::new())
+            }
+        }
+    }
+
+    /// Yields the context variables that carry the installation media, driver,
+    /// and guest customization options (media and driver sources, trusted
+    /// certificates, generalization, and domain join) to script steps.
+    pub fn context_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(iso) = &self.windows_iso {
+            vars.push(("windows_iso".to_string(), iso.to_string()));
+        }
+
+        if let Some(url) = &self.iso_url {
+            vars.push(("windows_iso_url".to_string(), url.clone()));
+        }
+
+        if let Some(sha256) = &self.iso_sha256 {
+            vars.push(("windows_iso_sha256".to_string(), sha256.clone()));
+        }
+
+        match &self.edition {
+            Some(crate::wim::Edition::Index(index)) => vars
+                .push(("unattend_image_index".to_string(), index.to_string())),
+            Some(edition) => {
+                vars.push(("edition".to_string(), edition.to_string()))
+            }
+            None => {}
+        }
+
+        if let Some(dir) = &self.unattend_overrides {
+            vars.push(("unattend_overrides".to_string(), dir.to_string()));
+        }
+
+        if let Some(iso) = &self.virtio_iso {
+            vars.push(("virtio_iso".to_string(), iso.to_string()));
+        }
+
+        if let Some(dir) = &self.virtio_driver_dir {
+            vars.push(("virtio_driver_dir".to_string(), dir.to_string()));
+        }
+
+        if let Some(manifest) = &self.virtio_iso_manifest {
+            vars.push((
+                "virtio_iso_manifest".to_string(),
+                manifest.to_string(),
+            ));
+        }
+
+        if self.skip_winpe_drivers {
+            vars.push(("skip_winpe_drivers".to_string(), String::new()));
+        }
+
+        if !self.updates.is_empty() {
+            let paths = self.updates.iter().map(|path| path.as_str());
+            vars.push((
+                crate::updates::UPDATES_VAR.to_string(),
+                itertools::join(paths, "\n"),
+            ));
+        }
+
+        for (var, certs) in [
+            (crate::certs::TRUSTED_CERTS_VAR, &self.trusted_certs),
+            (crate::certs::INTERMEDIATE_CERTS_VAR, &self.intermediate_certs),
+        ] {
+            if !certs.is_empty() {
+                let paths = certs.iter().map(|path| path.as_str());
+                vars.push((var.to_string(), itertools::join(paths, "\n")));
+            }
+        }
+
+        if !self.provision_scripts.is_empty() {
+            let paths = self.provision_scripts.iter().map(|path| path.as_str());
+            vars.push((
+                crate::provision::PROVISION_SCRIPTS_VAR.to_string(),
+                itertools::join(paths, "\n"),
+            ));
+        }
+
+        for (var, features) in [
+            (crate::features::WINDOWS_FEATURES_VAR, &self.windows_features),
+            (crate::features::REMOVE_FEATURES_VAR, &self.remove_features),
+        ] {
+            if !features.is_empty() {
+                vars.push((
+                    var.to_string(),
+                    crate::features::features_var(features),
+                ));
+            }
+        }
+
+        if !self.files.is_empty() {
+            vars.push((
+                crate::provision::PROVISION_FILES_VAR.to_string(),
+                crate::provision::files_var(&self.files),
+            ));
+        }
+
+        if !self.registry_settings.is_empty() {
+            vars.push((
+                crate::registry_settings::REGISTRY_VAR.to_string(),
+                crate::registry_settings::compile(&self.registry_settings),
+            ));
+        }
+
+        if !self.local_users.is_empty() {
+            vars.push((
+                crate::users::USERS_VAR.to_string(),
+                crate::users::users_var(&self.local_users),
+            ));
+        }
+
+        if self.skip_generalize {
+            vars.push(("skip_generalize".to_string(), String::new()));
+        }
+
+        if self.zero_free_space {
+            vars.push(("zero_free_space".to_string(), String::new()));
+        }
+
+        if self.minimal_image {
+            vars.push(("minimal_image".to_string(), String::new()));
+        }
+
+        if self.expand_on_first_boot {
+            vars.push(("expand_on_first_boot".to_string(), String::new()));
+        }
+
+        let headroom = self.shrink_headroom.or(self
+            .minimal_image
+            .then_some(DiskSize::MINIMAL_SHRINK_HEADROOM));
+        if let Some(headroom) = headroom {
+            vars.push((
+                "shrink_headroom".to_string(),
+                headroom.bytes().to_string(),
+            ));
+        }
+
+        if let Some(key) = &self.product_key {
+            vars.push(("activation".to_string(), key.to_string()));
+        }
+
+        if let Some(kms) = &self.kms_host {
+            vars.push(("kms_host".to_string(), kms.host.clone()));
+            if let Some(port) = kms.port {
+                vars.push(("kms_port".to_string(), port.to_string()));
+            }
+        }
+
+        if let Some(source) = &self.admin_password {
+            vars.push(("admin_password".to_string(), source.to_string()));
+        }
+
+        if self.skip_rdp {
+            vars.push(("skip_rdp".to_string(), String::new()));
+        }
+
+        if self.enable_ssh || self.ssh_authorized_keys.is_some() {
+            vars.push(("enable_ssh".to_string(), String::new()));
+        }
+
+        if let Some(path) = &self.ssh_authorized_keys {
+            vars.push((
+                crate::ssh::AUTHORIZED_KEYS_VAR.to_string(),
+                path.to_string(),
+            ));
+        }
+
+        vars.extend(self.domain_join.context_vars());
+        vars.extend(self.network.context_vars());
+        vars.extend(self.cloudbase_init.context_vars());
+        for var in &self.template_vars {
+            vars.push((
+                format!("{}{}", crate::template::USER_VAR_PREFIX, var.name),
+                var.value.clone(),
+            ));
+        }
+
+        // These come after --template-var so that they take precedence.
+        vars.extend(self.regional.context_vars());
+        if let Some(hostname) = &self.hostname {
+            vars.push((
+                format!("{}computer_name", crate::template::USER_VAR_PREFIX),
+                hostname.to_string(),
+            ));
+        }
+
+        vars
+    }
+}
+
+/// Options that set the language, keyboard, and time zone of setup and the
+/// installed image. Each sets the unattend template variable of the same
+/// name (see CONFIGURING.md), taking precedence over `--template-var` and the
+/// configuration file's `[template-vars]`.
+#[derive(Args, Clone, Debug)]
+pub struct RegionalOptions {
+    /// The locale (e.g. "de-DE") to use for setup and the installed image's
+    /// system, user, and UI language. The default is "en-US". The Windows
+    /// ISO must include the locale's language for setup and Windows to be
+    /// displayed in it.
+    #[arg(long, value_name = "LOCALE", value_parser = parse_locale)]
+    pub locale: Option<String>,
+
+    /// The keyboard layout to use: a locale (e.g. "de-DE") or an input locale
+    /// (e.g. "0407:00000407"), or several separated by semicolons. The
+    /// default is the --locale's keyboard layout.
+    #[arg(long, value_name = "LAYOUT", value_parser = parse_keyboard)]
+    pub keyboard: Option<String>,
+
+    /// The installed image's time zone, as a Windows time zone name (e.g.
+    /// "W. Europe Standard Time"; `tzutil /l` lists them). The default is
+    /// Windows's default, Pacific Standard Time.
+    #[arg(long, value_name = "ZONE", value_parser = parse_timezone)]
+    pub timezone: Option<String>,
+}
+
+/// Checks that `s` looks like a locale name such as "de-DE" or "zh-Hant-TW".
+fn parse_locale(s: &str) -> Result<String, String> {
+    let mut parts = s.split('-');
+    let language = parts.next().unwrap_or_default();
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.clone().count() > 0
+        && parts.all(|part| {
+            (2..=8).contains(&part.len())
+                && part.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !valid {
+        return Err(format!("'{s}' isn't a locale like \"de-DE\""));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Checks that `s` is a list of keyboard layouts separated by semicolons,
+/// each either a locale or an input locale such as "0407:00000407".
+fn parse_keyboard(s: &str) -> Result<String, String> {
+    for layout in s.split(';') {
+        let is_input_locale = layout.split_once(':').is_some_and(|(a, b)| {
+            a.len() == 4
+                && b.len() == 8
+                && a.chars().chain(b.chars()).all(|c| c.is_ascii_hexdigit())
+        });
+        if !is_input_locale && parse_locale(layout).is_err() {
+            return Err(format!(
+                "'{layout}' isn't a locale like \"de-DE\" or an input locale \
+                like \"0407:00000407\""
+            ));
+        }
+    }
+
+    Ok(s.to_string())
+}
+
+/// Rejects time zone names that are obviously not Windows time zone names.
+fn parse_timezone(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("the time zone can't be empty".to_string());
+    }
+
+    if s.contains('/') {
+        return Err(format!(
+            "'{s}' looks like an IANA time zone; Windows needs a Windows time \
+            zone name such as \"W. Europe Standard Time\" (see `tzutil /l`)"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+impl RegionalOptions {
+    fn context_vars(&self) -> Vec<(String, String)> {
+        [
+            ("locale", &self.locale),
+            ("keyboard", &self.keyboard),
+            ("timezone", &self.timezone),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((
+                format!("{}{name}", crate::template::USER_VAR_PREFIX),
+                value.clone()?,
+            ))
+        })
+        .collect()
+    }
+}
+
+/// Options that change how cloudbase-init is installed in the image and
+/// configured.
+#[derive(Args, Clone, Debug)]
+pub struct CloudbaseInitOptions {
+    /// Leaves cloudbase-init out of the image. Images without it don't pick
+    /// up their host name, users, SSH keys, or user data from the Oxide
+    /// rack's instance metadata.
+    #[arg(long, default_value_t = false)]
+    pub skip_cloudbase_init: bool,
+
+    /// The path to a cloudbase-init installer (MSI) to install instead of
+    /// downloading the Oxide build of cloudbase-init in the guest. Overrides
+    /// the configuration file's `cloudbase-init.msi`.
+    #[arg(long, value_name = "PATH", conflicts_with = "skip_cloudbase_init")]
+    pub cloudbase_init_msi: Option<Utf8PathBuf>,
+
+    /// The URL from which the guest downloads the cloudbase-init installer,
+    /// instead of the Oxide build's. Overrides the configuration file's
+    /// `cloudbase-init.url`.
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["skip_cloudbase_init", "cloudbase_init_msi"]
+    )]
+    pub cloudbase_init_url: Option<String>,
+
+    /// Sets an option in the cloudbase-init configuration files, as KEY=VALUE
+    /// for an option in their DEFAULT section (e.g. `username=admin`) or
+    /// SECTION.KEY=VALUE for one in another section. May be specified
+    /// multiple times; applied after the configuration file's
+    /// `[cloudbase-init.settings]`.
+    #[arg(
+        long = "cloudbase-init-setting",
+        value_name = "KEY=VALUE",
+        conflicts_with = "skip_cloudbase_init"
+    )]
+    pub cloudbase_init_settings: Vec<crate::cloudbase_init::Setting>,
+}
+
+impl CloudbaseInitOptions {
+    fn context_vars(&self) -> Vec<(String, String)> {
+        if self.skip_cloudbase_init {
+            return vec![("skip_cloudbase_init".to_string(), String::new())];
+        }
+
+        let mut vars = Vec::new();
+        if let Some(msi) = &self.cloudbase_init_msi {
+            vars.push(("cloudbase_init_msi".to_string(), msi.to_string()));
+        }
+
+        if let Some(url) = &self.cloudbase_init_url {
+            vars.push(("cloudbase_init_url".to_string(), url.clone()));
+        }
+
+        if !self.cloudbase_init_settings.is_empty() {
+            vars.push((
+                crate::cloudbase_init::SETTINGS_VAR.to_string(),
+                itertools::join(&self.cloudbase_init_settings, "\n"),
+            ));
+        }
+
+        vars
+    }
+}
+
+/// Options that join the image to an Active Directory domain during setup.
+#[derive(Args, Clone, Debug)]
+pub struct DomainJoinOptions {
+    /// The Active Directory domain to join: while Windows is being set up
+    /// with --skip-generalize, or otherwise when a deployed image first
+    /// boots. Requires --join-user and --join-password, --join-unsecure, or
+    /// --djoin-blob. Overrides the configuration file's `[domain_join]`
+    /// table.
+    #[arg(long, value_name = "DOMAIN")]
+    pub join_domain: Option<String>,
+
+    /// The organizational unit in which to create the computer account (e.g.
+    /// "OU=Servers,DC=corp,DC=example,DC=com"). If not specified, the
+    /// domain's default computer container is used.
+    #[arg(long, value_name = "OU", requires = "join_domain")]
+    pub join_ou: Option<String>,
+
+    /// The account to use to join the domain, either as "user" (in the domain
+    /// being joined) or as "DOMAIN\user".
+    #[arg(long, value_name = "USER", requires_all = ["join_domain", "join_password"])]
+    pub join_user: Option<String>,
+
+    /// Where to read the join account's password from: "env:NAME" reads an
+    /// environment variable, "file:PATH" reads a file, and "prompt" asks for
+    /// it when it's needed.
+    #[arg(long, value_name = "SOURCE", requires = "join_user")]
+    pub join_password: Option<SecretSource>,
+
+    /// Joins the domain with a computer account created ahead of time (e.g.
+    /// with `New-ADComputer`), proving the right to it with the account's
+    /// one-time password (--join-machine-password) instead of another
+    /// account's credentials. The account's name must be the image's
+    /// --hostname.
+    #[arg(
+        long,
+        requires_all = ["join_domain", "join_machine_password"],
+        conflicts_with_all = ["join_user", "join_ou"]
+    )]
+    pub join_unsecure: bool,
+
+    /// Where to read --join-unsecure's machine password from, as for
+    /// --join-password.
+    #[arg(long, value_name = "SOURCE", requires = "join_unsecure")]
+    pub join_machine_password: Option<SecretSource>,
+
+    /// The path to an offline domain join blob created with `djoin
+    /// /provision`, to join the domain without using credentials during
+    /// setup. Requires --skip-generalize.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "join_user",
+            "join_password",
+            "join_ou",
+            "join_unsecure"
+        ]
+    )]
+    pub djoin_blob: Option<Utf8PathBuf>,
+}
+
+/// Options that give built images a static network configuration when
+/// they're deployed, for networks without DHCP. The installation VM still
+/// gets its address from DHCP.
+#[derive(Args, Clone, Debug)]
+pub struct NetworkOptions {
+    /// The IPv4 address and prefix length (e.g. "10.0.0.5/24") deployed
+    /// images give their network adapter instead of getting one from DHCP.
+    /// Overrides the configuration file's `network.address`.
+    #[arg(long, value_name = "ADDRESS/PREFIX")]
+    pub static_ip: Option<crate::network::Ipv4Interface>,
+
+    /// The default gateway for --static-ip's network. Overrides the
+    /// configuration file's `network.gateway`.
+    #[arg(long, value_name = "ADDRESS", requires = "static_ip")]
+    pub gateway: Option<std::net::Ipv4Addr>,
+
+    /// A DNS server for deployed images to use, whether their address is
+    /// static or comes from DHCP. May be specified multiple times, in order
+    /// of preference. Overrides the configuration file's
+    /// `network.dns_servers`.
+    #[arg(long = "dns-server", value_name = "ADDRESS")]
+    pub dns_servers: Vec<std::net::IpAddr>,
+
+    /// The name of the network adapter the settings apply to. The default is
+    /// "Ethernet", Windows's name for the first wired adapter. Overrides the
+    /// configuration file's `network.interface`.
+    #[arg(long, value_name = "NAME")]
+    pub network_interface: Option<String>,
+}
+
+impl NetworkOptions {
+    /// Returns true if any network settings were given.
+    pub fn is_requested(&self) -> bool {
+        self.static_ip.is_some() || !self.dns_servers.is_empty()
+    }
+
+    fn context_vars(&self) -> Vec<(String, String)> {
+        if !self.is_requested() {
+            return Vec::new();
+        }
+
+        let interface = self
+            .network_interface
+            .as_deref()
+            .unwrap_or(crate::network::DEFAULT_INTERFACE);
+        [
+            ("network_interface", Some(interface.to_string())),
+            ("network_address", self.static_ip.map(|ip| ip.to_string())),
+            ("network_gateway", self.gateway.map(|ip| ip.to_string())),
+            (
+                "network_dns_servers",
+                (!self.dns_servers.is_empty()).then(|| {
+                    crate::network::dns_servers_var(&self.dns_servers)
+                }),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect()
+    }
+}
+
+impl DomainJoinOptions {
+    /// Returns true if any domain join was requested.
+    pub fn is_requested(&self) -> bool {
+        self.join_domain.is_some() || self.djoin_blob.is_some()
+    }
+
+    fn context_vars(&self) -> Vec<(String, String)> {
+        [
+            ("join_domain", self.join_domain.clone()),
+            ("join_ou", self.join_ou.clone()),
+            ("join_user", self.join_user.clone()),
+            (
+                "join_password",
+                self.join_password.as_ref().map(|s| s.to_string()),
+            ),
+            ("join_unsecure", self.join_unsecure.then(String::new)),
+            (
+                "join_machine_password",
+                self.join_machine_password.as_ref().map(|s| s.to_string()),
+            ),
+            ("djoin_blob", self.djoin_blob.as_ref().map(|p| p.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value?)))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_regional_options() {
+        for locale in ["de-DE", "ja-JP", "zh-Hant-TW", "fil-PH"] {
+            assert!(parse_locale(locale).is_ok(), "{locale}");
+        }
+        for bad in ["", "de", "german", "de_DE", "d-DE", "de-"] {
+            assert!(parse_locale(bad).is_err(), "{bad}");
+        }
+
+        assert!(parse_keyboard("0407:00000407").is_ok());
+        assert!(parse_keyboard("en-US;0407:00000407").is_ok());
+        assert!(parse_keyboard("0407:407").is_err());
+        assert!(parse_keyboard("en-US;").is_err());
+
+        assert!(parse_timezone("W. Europe Standard Time").is_ok());
+        assert!(parse_timezone("Europe/Berlin").is_err());
+        assert!(parse_timezone(" ").is_err());
+    }
+
+    #[test]
+    fn parses_disk_sizes() {
+        for (input, bytes) in [
+            ("512", 512),
+            ("64K", 64 << 10),
+            ("40G", 40 << 30),
+            (" 2t ", 2 << 40),
+            ("1536M", 1536 << 20),
+        ] {
+            assert_eq!(input.parse::<DiskSize>(), Ok(DiskSize(bytes)));
+        }
+
+        for input in ["", "G", "40GB", "-1G", "0", "99999999999T"] {
+            assert!(input.parse::<DiskSize>().is_err(), "{input:?}");
+        }
+
+        assert_eq!(DiskSize(1536 << 20).to_string(), "1536M");
+        assert_eq!(DiskSize::DEFAULT.to_string(), "30G");
+        assert_eq!(DiskSize(1000).to_string(), "1000");
+    }
+
+    #[test]
+    fn fills_media_from_config() {
+        let app = App::try_parse_from([
+            "wimsy",
+            "--work-dir=w",
+            "--output-image=out.img",
+            "create-guest-disk-image",
+            "--windows-iso=cli.iso",
+            "--unattend-dir=unattend",
+        ])
+        .unwrap();
+        let Command::CreateGuestDiskImage { sources, .. } = app.command else {
+            panic!("parsed the wrong command");
+        };
+
+        let err = sources
+            .clone()
+            .with_config_defaults(&crate::config::Config::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("no virtio drivers"), "{err}");
+
+        let mut config = crate::config::Config::default();
+        config.media.windows_iso = Some("config.iso".into());
+        config.media.virtio_driver_dir = Some("drivers".into());
+        config.media.windows_version = Some(WindowsVersion::Server2019);
+        let sources = sources.with_config_defaults(&config).unwrap();
+        assert_eq!(sources.windows_iso.as_deref(), Some("cli.iso".into()));
+        assert_eq!(
+            sources.virtio_driver_dir.as_deref(),
+            Some("drivers".into())
+        );
+        assert_eq!(sources.windows_version, Some(WindowsVersion::Server2019));
+    }
 }
@@ -13,12 +13,16 @@ use std::{
 
 use anyhow::{Context, Result};
 
-#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+use crate::app::DiskSize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
 pub enum WindowsVersion {
     Server2016,
     Server2019,
     Server2022,
     Server2025,
+    Windows10,
+    Windows11,
 }
 
 impl std::fmt::Display for WindowsVersion {
@@ -28,17 +32,111 @@ impl std::fmt::Display for WindowsVersion {
             WindowsVersion::Server2019 => write!(f, "Windows Server 2019"),
             WindowsVersion::Server2022 => write!(f, "Windows Server 2022"),
             WindowsVersion::Server2025 => write!(f, "Windows Server 2025"),
+            WindowsVersion::Windows10 => write!(f, "Windows 10"),
+            WindowsVersion::Windows11 => write!(f, "Windows 11"),
+        }
+    }
+}
+
+/// The processor architecture of the Windows being installed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Architecture {
+    #[default]
+    #[value(name = "x86_64")]
+    X86_64,
+    Aarch64,
+}
+
+impl std::fmt::Display for Architecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Architecture::X86_64 => write!(f, "x86_64"),
+            Architecture::Aarch64 => write!(f, "aarch64"),
         }
     }
 }
 
+impl Architecture {
+    /// The name answer files use for this architecture in their components'
+    /// `processorArchitecture` attributes. Setup ignores components for
+    /// other architectures.
+    pub fn windows_name(&self) -> &'static str {
+        match self {
+            Architecture::X86_64 => "amd64",
+            Architecture::Aarch64 => "arm64",
+        }
+    }
+
+    /// The name of the directories that hold this architecture's drivers on
+    /// a virtio driver disc.
+    pub fn driver_dir(&self) -> &'static str {
+        match self {
+            Architecture::X86_64 => "amd64",
+            Architecture::Aarch64 => "ARM64",
+        }
+    }
+}
+
+/// The configuration passes Setup recognizes.
+pub const PASSES: [&str; 7] = [
+    "windowsPE",
+    "offlineServicing",
+    "generalize",
+    "specialize",
+    "auditSystem",
+    "auditUser",
+    "oobeSystem",
+];
+
 impl WindowsVersion {
+    /// The smallest disk Microsoft supports installing this version on.
+    pub fn minimum_disk_size(&self) -> DiskSize {
+        // Every supported Server release and Windows 10 document a 32 GB
+        // (not GiB) minimum; Windows 11's Setup refuses disks smaller than
+        // 64 GB.
+        match self {
+            WindowsVersion::Server2016
+            | WindowsVersion::Server2019
+            | WindowsVersion::Server2022
+            | WindowsVersion::Server2025
+            | WindowsVersion::Windows10 => DiskSize(32_000_000_000),
+            WindowsVersion::Windows11 => DiskSize(64_000_000_000),
+        }
+    }
+
+    /// Whether this is a client (desktop) release rather than a Server one.
+    pub fn is_client(&self) -> bool {
+        matches!(self, WindowsVersion::Windows10 | WindowsVersion::Windows11)
+    }
+
     pub fn as_driver_path_component(&self) -> &'static str {
         match self {
             WindowsVersion::Server2016 => "2k16",
             WindowsVersion::Server2019 => "2k19",
             WindowsVersion::Server2022 => "2k22",
             WindowsVersion::Server2025 => "2k25",
+            WindowsVersion::Windows10 => "w10",
+            WindowsVersion::Windows11 => "w11",
+        }
+    }
+
+    /// Returns the release whose Windows build number is `build`. Client
+    /// and Server releases sometimes share a build (Windows 11 24H2 and
+    /// Server 2025 are both 26100), so `client` says which kind of release
+    /// the build belongs to. Windows 10 covers the LTSC releases still in
+    /// support (LTSC 2019 is build 17763 and LTSC 2021 is 19044) as well as
+    /// the last general availability releases.
+    pub fn from_build(build: u32, client: bool) -> Option<Self> {
+        match (build, client) {
+            (14393, false) => Some(WindowsVersion::Server2016),
+            (17763, false) => Some(WindowsVersion::Server2019),
+            (20348, false) => Some(WindowsVersion::Server2022),
+            (26100, false) => Some(WindowsVersion::Server2025),
+            (17763 | 19044 | 19045, true) => Some(WindowsVersion::Windows10),
+            (22000 | 22621 | 22631 | 26100 | 26200, true) => {
+                Some(WindowsVersion::Windows11)
+            }
+            _ => None,
         }
     }
 }
@@ -119,8 +217,54 @@ fn replace_version_in_driver_path(
     )
 }
 
+/// A component to add to one of the answer file's configuration passes. If
+/// the pass already contains a component with the same name, the new
+/// component replaces it.
+struct ComponentInjection {
+    /// The configuration pass (e.g. "specialize") to add the component to.
+    pass: &'static str,
+
+    /// The component's name (e.g. "Microsoft-Windows-UnattendedJoin").
+    name: &'static str,
+
+    /// The XML events that make up the component element.
+    events: Vec<xml::reader::XmlEvent>,
+}
+
+impl ComponentInjection {
+    fn write<W: std::io::Write>(
+        &self,
+        output: &mut xml::EventWriter<W>,
+    ) -> Result<()> {
+        write_events(&self.events, output)
+    }
+}
+
+fn write_events<W: std::io::Write>(
+    events: &[xml::reader::XmlEvent],
+    output: &mut xml::EventWriter<W>,
+) -> Result<()> {
+    for e in events {
+        if let Some(writer_event) = e.as_writer_event() {
+            output.write(writer_event)?;
+        }
+    }
+    Ok(())
+}
+
+/// Settings for a configuration pass that replace the answer file's settings
+/// for the pass, or are added to the end of the file if it has none.
+struct PassInjection {
+    pass: String,
+
+    /// The XML events that make up the settings element.
+    events: Vec<xml::reader::XmlEvent>,
+}
+
 pub struct AutounattendUpdater {
     rules: Vec<ReplacementRule>,
+    components: Vec<ComponentInjection>,
+    passes: Vec<PassInjection>,
 }
 
 impl AutounattendUpdater {
@@ -204,7 +348,101 @@ impl AutounattendUpdater {
             });
         }
 
-        Self { rules }
+        Self { rules, components: Vec::new(), passes: Vec::new() }
+    }
+
+    /// Adds the component element in `component_xml` to the settings for the
+    /// configuration pass named `pass`, replacing any existing component
+    /// named `name` in that pass. If the answer file has no settings for the
+    /// pass, they are added at the end of the file.
+    ///
+    /// The component element is parsed on its own, so it must declare the
+    /// answer file's namespace and any other namespaces it uses.
+    pub fn with_component(
+        mut self,
+        pass: &'static str,
+        name: &'static str,
+        component_xml: &str,
+    ) -> Result<Self> {
+        let mut events = Vec::new();
+        for e in xml::EventReader::new(component_xml.as_bytes()) {
+            match e.with_context(|| format!("parsing {name} component"))? {
+                xml::reader::XmlEvent::StartDocument { .. }
+                | xml::reader::XmlEvent::EndDocument => {}
+                e => events.push(e),
+            }
+        }
+
+        self.components.push(ComponentInjection { pass, name, events });
+        Ok(self)
+    }
+
+    /// Replaces the answer file's settings for the configuration pass named
+    /// `pass` with the settings for that pass in `answer_file_xml`, which is
+    /// itself an answer file containing only those settings. If the answer
+    /// file has no settings for the pass, they are added at the end of the
+    /// file. Components added with [`AutounattendUpdater::with_component`]
+    /// aren't added to replaced passes, so replace passes and add components
+    /// in separate updates.
+    pub fn with_pass(
+        mut self,
+        pass: &str,
+        answer_file_xml: &str,
+    ) -> Result<Self> {
+        let mut events = Vec::new();
+        let mut depth = 0;
+        let mut passes = Vec::new();
+        let mut in_pass = false;
+        for e in xml::EventReader::new(answer_file_xml.as_bytes()) {
+            let e = e.with_context(|| format!("parsing {pass} settings"))?;
+            match &e {
+                xml::reader::XmlEvent::StartElement {
+                    name,
+                    attributes,
+                    ..
+                } => {
+                    depth += 1;
+                    if depth == 2 {
+                        if name.local_name != "settings" {
+                            anyhow::bail!(
+                                "expected only <settings> elements in \
+                                <unattend>, found <{}>",
+                                name.local_name
+                            );
+                        }
+
+                        let found = attributes
+                            .iter()
+                            .find(|a| a.name.local_name == "pass")
+                            .map(|a| a.value.clone())
+                            .unwrap_or_default();
+                        in_pass = found == pass;
+                        passes.push(found);
+                    }
+                }
+                xml::reader::XmlEvent::EndElement { .. } => depth -= 1,
+                _ => {}
+            }
+
+            if in_pass {
+                events.push(e);
+            }
+
+            if depth < 2 {
+                in_pass = false;
+            }
+        }
+
+        if passes != [pass] {
+            anyhow::bail!(
+                "expected settings for the {pass} pass only, found settings \
+                for [{}]",
+                passes.join(", ")
+            );
+        }
+
+        self.passes.push(PassInjection { pass: pass.to_string(), events });
+        Ok(self)
     }
 
     pub fn run(
@@ -227,14 +465,90 @@ impl AutounattendUpdater {
     ) -> Result<usize> {
         let mut matches = 0;
         let mut next_match_depths = vec![0; self.rules.len()];
+
+        // State for component injection: the depth of the current element
+        // (1 for <unattend>), the pass whose settings are open, if any, which
+        // components have been written, and the depth at which a replaced
+        // component's events stop being skipped.
+        let mut depth = 0;
+        let mut current_pass: Option<String> = None;
+        let mut injected = vec![false; self.components.len()];
+        let mut passes_injected = vec![false; self.passes.len()];
+        let mut skip_until_depth: Option<usize> = None;
         for e in input {
             if let Ok(e) = &e {
+                if let Some(skip_depth) = skip_until_depth {
+                    match e {
+                        xml::reader::XmlEvent::StartElement { .. } => {
+                            depth += 1
+                        }
+                        xml::reader::XmlEvent::EndElement { .. } => {
+                            depth -= 1;
+                            if depth < skip_depth {
+                                skip_until_depth = None;
+                            }
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+
                 match e {
                     xml::reader::XmlEvent::StartElement {
                         name,
                         attributes,
                         ..
                     } => {
+                        depth += 1;
+                        let attribute = |key: &str| {
+                            attributes
+                                .iter()
+                                .find(|a| a.name.local_name == key)
+                                .map(|a| a.value.as_str())
+                        };
+
+                        if depth == 2 && name.local_name == "settings" {
+                            current_pass = attribute("pass").map(str::to_owned);
+                            let replacement =
+                                self.passes.iter().position(|p| {
+                                    current_pass.as_deref()
+                                        == Some(p.pass.as_str())
+                                });
+
+                            if let Some(index) = replacement {
+                                if !passes_injected[index] {
+                                    write_events(
+                                        &self.passes[index].events,
+                                        &mut output,
+                                    )?;
+                                    passes_injected[index] = true;
+                                    matches += 1;
+                                }
+                                current_pass = None;
+                                skip_until_depth = Some(depth);
+                                continue;
+                            }
+                        }
+
+                        if depth == 3 && name.local_name == "component" {
+                            let replacement =
+                                self.components.iter().position(|c| {
+                                    current_pass.as_deref() == Some(c.pass)
+                                        && attribute("name") == Some(c.name)
+                                });
+
+                            if let Some(index) = replacement {
+                                if !injected[index] {
+                                    self.components[index]
+                                        .write(&mut output)?;
+                                    injected[index] = true;
+                                    matches += 1;
+                                }
+                                skip_until_depth = Some(depth);
+                                continue;
+                            }
+                        }
+
                         for (rule_index, rule) in self.rules.iter().enumerate()
                         {
                             let depth = &mut next_match_depths[rule_index];
@@ -262,6 +576,59 @@ impl AutounattendUpdater {
                         }
                     }
                     xml::reader::XmlEvent::EndElement { name } => {
+                        // Add components that didn't replace an existing
+                        // component to the end of their pass's settings, or
+                        // add settings for their pass at the end of the file.
+                        if depth == 2 && name.local_name == "settings" {
+                            for (component, done) in
+                                self.components.iter().zip(&mut injected)
+                            {
+                                if !*done
+                                    && current_pass.as_deref()
+                                        == Some(component.pass)
+                                {
+                                    component.write(&mut output)?;
+                                    *done = true;
+                                    matches += 1;
+                                }
+                            }
+                            current_pass = None;
+                        }
+
+                        if depth == 1 {
+                            for (component, done) in
+                                self.components.iter().zip(&mut injected)
+                            {
+                                if *done {
+                                    continue;
+                                }
+
+                                output.write(
+                                    xml::writer::XmlEvent::start_element(
+                                        "settings",
+                                    )
+                                    .attr("pass", component.pass),
+                                )?;
+                                component.write(&mut output)?;
+                                output.write(
+                                    xml::writer::XmlEvent::end_element(),
+                                )?;
+                                *done = true;
+                                matches += 1;
+                            }
+
+                            for (pass, done) in
+                                self.passes.iter().zip(&mut passes_injected)
+                            {
+                                if !*done {
+                                    write_events(&pass.events, &mut output)?;
+                                    *done = true;
+                                    matches += 1;
+                                }
+                            }
+                        }
+
+                        depth -= 1;
                         for (rule_index, rule) in self.rules.iter().enumerate()
                         {
                             let depth = &mut next_match_depths[rule_index];
@@ -316,10 +683,17 @@ impl AutounattendUpdater {
 mod test {
     use super::*;
 
-    const ILLUMOS_UNATTEND: &'static str =
-        include_str!("../illumos/Autounattend.xml");
-    const LINUX_UNATTEND: &'static str =
-        include_str!("../unattend/Autounattend.xml");
+    const ILLUMOS_UNATTEND: &str = include_str!("../illumos/Autounattend.xml");
+
+    /// The repo's Autounattend.xml template, rendered with no variables set.
+    static LINUX_UNATTEND: std::sync::LazyLock<String> =
+        std::sync::LazyLock::new(|| {
+            crate::template::render(
+                include_str!("../unattend/Autounattend.xml.j2"),
+                &Default::default(),
+            )
+            .unwrap()
+        });
 
     #[test]
     fn replace_illumos_unattend() {
@@ -370,14 +744,135 @@ mod test {
         assert!(as_str.contains("D:\\viostor\\2k25\\amd64"));
     }
 
+    #[test]
+    fn replace_linux_unattend_virtio_scsi() {
+        let unattend = crate::template::render(
+            include_str!("../unattend/Autounattend.xml.j2"),
+            &[("disk_controller".to_string(), "virtio-scsi".to_string())]
+                .into(),
+        )
+        .unwrap();
+        let updater =
+            AutounattendUpdater::new(None, Some(WindowsVersion::Server2019));
+
+        let reader = xml::EventReader::new(unattend.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = xml::EventWriter::new(&mut new);
+
+        // 6 offlineServicing paths + 3 for vioscsi
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 9);
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(as_str.contains("F:\\vioscsi\\2k19\\amd64"));
+    }
+
     #[test]
     fn replace_with_no_rules_is_noop() {
         let updater = AutounattendUpdater::new(None, None);
 
-        for input in &[ILLUMOS_UNATTEND, LINUX_UNATTEND] {
+        for input in [ILLUMOS_UNATTEND, LINUX_UNATTEND.as_str()] {
             let reader = xml::EventReader::new(input.as_bytes());
             let writer = xml::EventWriter::new(std::io::empty());
             assert_eq!(updater.run_internal(reader, writer).unwrap(), 0);
         }
     }
+
+    #[test]
+    fn inject_components() {
+        let component = |name: &str, body: &str| {
+            format!(
+                "<component name=\"{name}\" \
+                xmlns=\"urn:schemas-microsoft-com:unattend\">{body}</component>"
+            )
+        };
+
+        // Adding a component to a pass the file doesn't have adds settings
+        // for that pass.
+        let updater = AutounattendUpdater::new(None, None)
+            .with_component(
+                "specialize",
+                "Microsoft-Windows-UnattendedJoin",
+                &component("Microsoft-Windows-UnattendedJoin", "<A>1</A>"),
+            )
+            .unwrap();
+
+        let reader = xml::EventReader::new(LINUX_UNATTEND.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = xml::EventWriter::new(&mut new);
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 1);
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(as_str.contains(
+            "<settings pass=\"specialize\"><component \
+            name=\"Microsoft-Windows-UnattendedJoin\"><A>1</A></component>\
+            </settings></unattend>"
+        ));
+
+        // Adding a component that already exists replaces it, leaving the
+        // pass's other components alone.
+        let updater = AutounattendUpdater::new(None, None)
+            .with_component(
+                "oobeSystem",
+                "Microsoft-Windows-Deployment",
+                &component("Microsoft-Windows-Deployment", "<B>2</B>"),
+            )
+            .unwrap();
+
+        let reader = xml::EventReader::new(LINUX_UNATTEND.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = xml::EventWriter::new(&mut new);
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 1);
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(as_str.contains("<B>2</B>"));
+        assert!(!as_str.contains("<Mode>Audit</Mode>"));
+        assert!(as_str.contains("Prepare Oxide base image"));
+        assert_eq!(as_str.matches("Microsoft-Windows-Deployment").count(), 2);
+    }
+
+    #[test]
+    fn replaces_passes() {
+        const OVERRIDE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<unattend xmlns="urn:schemas-microsoft-com:unattend">
+    <settings pass="oobeSystem">
+        <component name="Microsoft-Windows-Deployment"><C>3</C></component>
+    </settings>
+</unattend>"#;
+
+        let updater = AutounattendUpdater::new(None, None)
+            .with_pass("oobeSystem", OVERRIDE)
+            .unwrap();
+        let reader = xml::EventReader::new(LINUX_UNATTEND.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = xml::EventWriter::new(&mut new);
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 1);
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(as_str.contains("<C>3</C>"));
+        assert!(!as_str.contains("<Mode>Audit</Mode>"));
+        assert!(as_str.contains("Prepare Oxide base image"));
+        assert_eq!(as_str.matches("pass=\"oobeSystem\"").count(), 1);
+        assert_eq!(as_str.matches("pass=\"windowsPE\"").count(), 1);
+
+        // Settings for a pass the answer file doesn't configure are added at
+        // the end.
+        let updater = AutounattendUpdater::new(None, None)
+            .with_pass(
+                "specialize",
+                &OVERRIDE.replace("oobeSystem", "specialize"),
+            )
+            .unwrap();
+        let reader = xml::EventReader::new(LINUX_UNATTEND.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = xml::EventWriter::new(&mut new);
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 1);
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(as_str.contains("<Mode>Audit</Mode>"));
+        assert!(as_str.trim_end().ends_with("</settings></unattend>"));
+        assert!(as_str.contains("<C>3</C>"));
+
+        // The override has to contain exactly the pass it's for.
+        assert!(AutounattendUpdater::new(None, None)
+            .with_pass("specialize", OVERRIDE)
+            .is_err());
+        assert!(AutounattendUpdater::new(None, None)
+            .with_pass("oobeSystem", &LINUX_UNATTEND)
+            .is_err());
+    }
 }
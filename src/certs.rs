@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Loads the X.509 certificates passed with `--trusted-cert` and
+//! `--intermediate-cert` and stages them so that the guest's setup script can
+//! add them to its trusted root and intermediate CA stores.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    hash, json::Json, runner::Context, trace, ui::Ui, util::decode_base64,
+};
+
+/// The name of the directory, relative to the unattend directory, into which
+/// trusted certificates are staged. `OxidePrepBaseImage.ps1` imports every
+/// `.cer` file in this directory into `Cert:\LocalMachine\Root`.
+pub const TRUSTED_CERTS_DIR: &str = "TrustedCerts";
+
+/// The context variable holding the newline-separated paths of the
+/// certificate files to trust.
+pub const TRUSTED_CERTS_VAR: &str = "trusted_certs";
+
+/// Like [`TRUSTED_CERTS_DIR`], for certificates that
+/// `OxidePrepBaseImage.ps1` imports into `Cert:\LocalMachine\CA`.
+pub const INTERMEDIATE_CERTS_DIR: &str = "IntermediateCerts";
+
+/// The context variable holding the newline-separated paths of the
+/// intermediate CA certificate files.
+pub const INTERMEDIATE_CERTS_VAR: &str = "intermediate_certs";
+
+/// For each certificate store: the context variable listing the files to add
+/// to it, the directory they're staged in, and the metric that records their
+/// fingerprints.
+const STORES: [(&str, &str, &str); 2] = [
+    (TRUSTED_CERTS_VAR, TRUSTED_CERTS_DIR, "trusted_cert_sha256_fingerprints"),
+    (
+        INTERMEDIATE_CERTS_VAR,
+        INTERMEDIATE_CERTS_DIR,
+        "intermediate_cert_sha256_fingerprints",
+    ),
+];
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// A DER-encoded X.509 certificate.
+pub struct Certificate {
+    der: Vec<u8>,
+}
+
+impl Certificate {
+    /// Returns the certificate's SHA-256 fingerprint as lowercase hex.
+    pub fn fingerprint(&self) -> String {
+        hash::to_hex(&hash::sha256(&self.der))
+    }
+}
+
+/// Loads the certificates in the file at `path`, which may be a single
+/// DER-encoded certificate or a PEM file containing one or more certificates.
+pub fn load(path: &Utf8Path) -> Result<Vec<Certificate>> {
+    let contents = std::fs::read(path)
+        .with_context(|| format!("reading certificate file '{path}'"))?;
+    parse(&contents)
+        .with_context(|| format!("parsing certificates in '{path}'"))
+}
+
+fn parse(contents: &[u8]) -> Result<Vec<Certificate>> {
+    let ders = match std::str::from_utf8(contents) {
+        Ok(text) if text.contains(PEM_BEGIN) => parse_pem(text)?,
+        _ => vec![contents.to_vec()],
+    };
+
+    ders.into_iter()
+        .enumerate()
+        .map(|(i, der)| {
+            check_der_certificate(&der).with_context(|| {
+                format!("certificate {} is malformed", i + 1)
+            })?;
+            Ok(Certificate { der })
+        })
+        .collect()
+}
+
+/// Extracts the base64-decoded contents of each `CERTIFICATE` block in `text`.
+fn parse_pem(text: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let body = &rest[start + PEM_BEGIN.len()..];
+        let end = body
+            .find(PEM_END)
+            .ok_or_else(|| anyhow::anyhow!("missing '{PEM_END}' line"))?;
+        certs.push(decode_base64(&body[..end])?);
+        rest = &body[end + PEM_END.len()..];
+    }
+
+    Ok(certs)
+}
+
+/// Reads a DER tag-length header from the front of `data`, returning the tag,
+/// the contents, and the remaining data.
+fn read_der_element(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let (&tag, data) =
+        data.split_first().ok_or_else(|| anyhow::anyhow!("truncated data"))?;
+    let (&first, mut data) = data
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("truncated length"))?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            anyhow::bail!("unsupported or truncated length");
+        }
+        let len = data[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        data = &data[count..];
+        len
+    };
+
+    if data.len() < len {
+        anyhow::bail!("element is longer than the remaining data");
+    }
+
+    Ok((tag, &data[..len], &data[len..]))
+}
+
+/// Checks that `der` has the outer structure of an X.509 certificate: a
+/// SEQUENCE containing the to-be-signed certificate (a SEQUENCE), the
+/// signature algorithm (a SEQUENCE), and the signature (a BIT STRING).
+fn check_der_certificate(der: &[u8]) -> Result<()> {
+    const SEQUENCE: u8 = 0x30;
+    const BIT_STRING: u8 = 0x03;
+
+    let (tag, contents, trailing) = read_der_element(der)?;
+    if tag != SEQUENCE || !trailing.is_empty() {
+        anyhow::bail!("not a DER-encoded certificate");
+    }
+
+    let mut rest = contents;
+    for (expected, what) in [
+        (SEQUENCE, "to-be-signed certificate"),
+        (SEQUENCE, "signature algorithm"),
+        (BIT_STRING, "signature"),
+    ] {
+        let (tag, _, next) = read_der_element(rest)
+            .with_context(|| format!("reading the {what}"))?;
+        if tag != expected {
+            anyhow::bail!("unexpected tag {tag:#04x} for the {what}");
+        }
+        rest = next;
+    }
+
+    if !rest.is_empty() {
+        anyhow::bail!("unexpected data after the signature");
+    }
+
+    Ok(())
+}
+
+/// Checks that each of `paths` contains at least one well-formed certificate,
+/// returning a message for each file that doesn't.
+pub fn check_prerequisites(paths: &[Utf8PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .filter_map(|path| match load(path) {
+            Ok(certs) if certs.is_empty() => {
+                Some(format!("no certificates found in '{path}'"))
+            }
+            Ok(_) => None,
+            Err(e) => Some(format!("{e:#}")),
+        })
+        .collect()
+}
+
+/// Writes each certificate listed in the context's trusted and intermediate
+/// certificate variables to the matching directory in `unattend_dir` and
+/// records their fingerprints as step metrics.
+pub fn stage_trusted_certs(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    for (var, dir, metric) in STORES {
+        if let Some(paths) = ctx.get_var(var) {
+            stage(paths, &unattend_dir.join(dir), metric, ui)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stages the certificates in the newline-separated `paths` in `dst_dir`,
+/// recording their fingerprints as the metric `metric`.
+fn stage(
+    paths: &str,
+    dst_dir: &Utf8Path,
+    metric: &str,
+    ui: &dyn Ui,
+) -> Result<()> {
+    std::fs::create_dir_all(dst_dir)
+        .with_context(|| format!("creating '{dst_dir}'"))?;
+
+    let mut fingerprints = Vec::new();
+    for path in paths.lines().map(Utf8Path::new) {
+        for cert in load(path)? {
+            let fingerprint = cert.fingerprint();
+            ui.set_substep(&format!("staging certificate {fingerprint}"));
+            trace::debug!(
+                "staging certificate",
+                source = path.as_str(),
+                fingerprint = fingerprint.as_str()
+            );
+
+            // Name each file after its certificate's fingerprint so that
+            // certificates that appear in more than one input are only
+            // imported once.
+            let dst = dst_dir.join(format!("{fingerprint}.cer"));
+            std::fs::write(&dst, &cert.der)
+                .with_context(|| format!("writing '{dst}'"))?;
+            if !fingerprints.contains(&fingerprint) {
+                fingerprints.push(fingerprint);
+            }
+        }
+    }
+
+    ui.record_metric(metric, Json::from(fingerprints));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A minimal structure with the shape of a certificate: SEQUENCE {
+    /// SEQUENCE {}, SEQUENCE {}, BIT STRING { 0 } }.
+    const FAKE_DER: &[u8] =
+        &[0x30, 0x07, 0x30, 0x00, 0x30, 0x00, 0x03, 0x01, 0x00];
+
+    #[test]
+    fn parses_der_and_pem() {
+        assert_eq!(parse(FAKE_DER).unwrap().len(), 1);
+
+        let pem = format!(
+            "junk\n{PEM_BEGIN}\nMAcwADAAAwEA\n{PEM_END}\n\
+            {PEM_BEGIN}\nMAcwADAAAwEA\n{PEM_END}\n"
+        );
+        let certs = parse(pem.as_bytes()).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].der, FAKE_DER);
+        assert_eq!(certs[0].fingerprint(), certs[1].fingerprint());
+
+        assert!(parse(b"not a certificate").is_err());
+        assert!(parse(&FAKE_DER[..8]).is_err());
+        assert!(parse(&[0x30, 0x02, 0x30, 0x00]).is_err());
+        assert!(parse(format!("{PEM_BEGIN}\nMAcw").as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Records which steps of a build have completed so that a build that dies
+//! partway through can be resumed with `--resume`.
+//!
+//! The checkpoint file lists each completed step along with the context
+//! variables it set. It also holds a fingerprint of the build's inputs: the
+//! script's initial context, the names of the planned steps, and the size and
+//! modification time of each of the script's input files. A checkpoint is only
+//! used if the fingerprint of the build being resumed matches it.
+
+use std::{collections::HashMap, time::SystemTime};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    hash::{self, Sha256},
+    json::Json,
+    runner::Step,
+};
+
+/// The name of the checkpoint file written to the work directory.
+pub const CHECKPOINT_FILE_NAME: &str = "checkpoint.json";
+
+/// A step that completed in an earlier run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompletedStep {
+    pub name: String,
+
+    /// The context variables the step set or changed.
+    pub vars: Vec<(String, String)>,
+}
+
+/// The steps that have completed in a build with a particular fingerprint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    fingerprint: String,
+    completed: Vec<CompletedStep>,
+}
+
+/// Computes the fingerprint of a build that starts with context `vars`, runs
+/// `steps`, and reads `input_files`.
+pub fn fingerprint(
+    vars: &HashMap<String, String>,
+    steps: &[&dyn Step],
+    input_files: &[Utf8PathBuf],
+) -> String {
+    let mut hasher = Sha256::new();
+    let mut sorted: Vec<_> = vars.iter().collect();
+    sorted.sort();
+    for (var, value) in sorted {
+        hasher.update(format!("var {var}={value}\n").as_bytes());
+    }
+
+    for step in steps {
+        hasher.update(format!("step {}\n", step.name()).as_bytes());
+    }
+
+    for path in input_files {
+        let stamp = std::fs::metadata(path).ok().map(|metadata| {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            (metadata.len(), modified.as_nanos())
+        });
+        hasher.update(format!("file {path} {stamp:?}\n").as_bytes());
+    }
+
+    hash::to_hex(&hasher.finish())
+}
+
+impl Checkpoint {
+    /// Creates an empty checkpoint for a build with `fingerprint`.
+    pub fn new(fingerprint: String) -> Self {
+        Self { fingerprint, completed: Vec::new() }
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn completed(&self) -> &[CompletedStep] {
+        &self.completed
+    }
+
+    /// Records that the step `name` completed and set `vars`.
+    pub fn record(&mut self, name: &str, vars: Vec<(String, String)>) {
+        self.completed.push(CompletedStep { name: name.to_string(), vars });
+    }
+
+    /// Returns how many of the leading `steps` completed in the run that
+    /// wrote this checkpoint, and forgets any other steps it recorded. Steps
+    /// after the first one that didn't complete need to run again, since
+    /// they may depend on its outputs.
+    pub fn retain_completed_prefix(&mut self, steps: &[&dyn Step]) -> usize {
+        let count = steps
+            .iter()
+            .zip(&self.completed)
+            .take_while(|(step, completed)| step.name() == completed.name)
+            .count();
+        self.completed.truncate(count);
+        count
+    }
+
+    pub fn to_json(&self) -> Json {
+        let completed: Vec<Json> = self
+            .completed
+            .iter()
+            .map(|step| {
+                let mut vars = Json::object();
+                for (var, value) in &step.vars {
+                    vars.insert(var, value.as_str());
+                }
+                Json::object()
+                    .with("step", step.name.as_str())
+                    .with("vars", vars)
+            })
+            .collect();
+
+        Json::object()
+            .with("fingerprint", self.fingerprint.as_str())
+            .with("completed", completed)
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self> {
+        let fingerprint = json
+            .get("fingerprint")
+            .and_then(Json::as_str)
+            .context("checkpoint has no fingerprint")?
+            .to_string();
+
+        let Some(Json::Array(steps)) = json.get("completed") else {
+            anyhow::bail!("checkpoint has no list of completed steps");
+        };
+
+        let mut completed = Vec::new();
+        for step in steps {
+            let name = step
+                .get("step")
+                .and_then(Json::as_str)
+                .context("checkpoint entry has no step name")?;
+            let mut vars = Vec::new();
+            if let Some(Json::Object(fields)) = step.get("vars") {
+                for (var, value) in fields {
+                    let value = value.as_str().with_context(|| {
+                        format!("checkpoint variable '{var}' isn't a string")
+                    })?;
+                    vars.push((var.clone(), value.to_string()));
+                }
+            }
+
+            completed.push(CompletedStep { name: name.to_string(), vars });
+        }
+
+        Ok(Self { fingerprint, completed })
+    }
+
+    /// Reads the checkpoint in `work_dir`, returning `Ok(None)` if there
+    /// isn't one.
+    pub fn load(work_dir: &Utf8Path) -> Result<Option<Self>> {
+        let path = work_dir.join(CHECKPOINT_FILE_NAME);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading '{path}'"))
+            }
+        };
+
+        let json = Json::parse(&text)
+            .with_context(|| format!("parsing checkpoint '{path}'"))?;
+        Self::from_json(&json)
+            .with_context(|| format!("reading checkpoint '{path}'"))
+            .map(Some)
+    }
+
+    /// Writes this checkpoint to `work_dir`. The file is replaced atomically
+    /// so that a build that dies while writing it leaves the previous
+    /// checkpoint intact.
+    pub fn write(&self, work_dir: &Utf8Path) -> Result<()> {
+        let path = work_dir.join(CHECKPOINT_FILE_NAME);
+        let temp = work_dir.join(format!("{CHECKPOINT_FILE_NAME}.tmp"));
+        std::fs::write(&temp, format!("{:#}\n", self.to_json()))
+            .with_context(|| format!("writing '{temp}'"))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("renaming '{temp}' to '{path}'"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runner::ScriptStep;
+
+    fn steps(names: &[&'static str]) -> Vec<ScriptStep> {
+        names
+            .iter()
+            .map(|&name| ScriptStep::new(name, name, |_, _| Ok(())))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut checkpoint = Checkpoint::new("abc".to_string());
+        checkpoint.record("one", vec![("image".into(), "a \"b\"".into())]);
+        checkpoint.record("two", Vec::new());
+        let text = checkpoint.to_json().to_string();
+        let parsed = Checkpoint::from_json(&Json::parse(&text).unwrap());
+        assert_eq!(parsed.unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn keeps_only_the_completed_prefix() {
+        let mut checkpoint = Checkpoint::new(String::new());
+        for name in ["one", "two", "four"] {
+            checkpoint.record(name, Vec::new());
+        }
+
+        let planned = steps(&["one", "two", "three", "four"]);
+        let planned: Vec<&dyn Step> =
+            planned.iter().map(|step| step as &dyn Step).collect();
+        assert_eq!(checkpoint.retain_completed_prefix(&planned), 2);
+        let names: Vec<&str> =
+            checkpoint.completed().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["one", "two"]);
+    }
+
+    #[test]
+    fn fingerprints_inputs() {
+        let planned = steps(&["one", "two"]);
+        let planned: Vec<&dyn Step> =
+            planned.iter().map(|step| step as &dyn Step).collect();
+        let mut vars = HashMap::new();
+        vars.insert("iso".to_string(), "a.iso".to_string());
+        let base = fingerprint(&vars, &planned, &[]);
+        assert_eq!(base, fingerprint(&vars, &planned, &[]));
+
+        vars.insert("iso".to_string(), "b.iso".to_string());
+        assert_ne!(base, fingerprint(&vars, &planned, &[]));
+        vars.insert("iso".to_string(), "a.iso".to_string());
+        assert_ne!(base, fingerprint(&vars, &planned[..1], &[]));
+        assert_ne!(
+            base,
+            fingerprint(&vars, &planned, &[Utf8PathBuf::from("/nonexistent")])
+        );
+    }
+}
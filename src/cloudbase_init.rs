@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Customizes how images get cloudbase-init.
+//!
+//! The guest setup script installs cloudbase-init and copies the unattend
+//! directory's cloudbase-init configuration files into place, and the
+//! specialize answer file runs it when a generalized image first boots. This
+//! module lets users supply the installer themselves (instead of having the
+//! guest download it), change individual settings in the configuration files
+//! without replacing them, or leave cloudbase-init out of the image.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{runner::Context, trace, ui::Ui};
+
+/// The name under which a user-supplied cloudbase-init installer is staged
+/// in the unattend directory.
+pub const MSI_FILE: &str = "CloudbaseInitSetup.msi";
+
+/// The cloudbase-init configuration files in the unattend directory.
+pub const CONF_FILES: [&str; 2] =
+    ["cloudbase-init.conf", "cloudbase-init-unattend.conf"];
+
+/// The context variable that lists the settings to apply to the
+/// configuration files, one per line.
+pub const SETTINGS_VAR: &str = "cloudbase_init_settings";
+
+/// The section of a configuration file that holds cloudbase-init's general
+/// settings.
+const DEFAULT_SECTION: &str = "DEFAULT";
+
+/// A setting to write into the cloudbase-init configuration files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+}
+
+impl Setting {
+    /// Parses a setting named `name`, either `KEY` (a key in the `DEFAULT`
+    /// section) or `SECTION.KEY`.
+    pub fn new(name: &str, value: &str) -> Result<Self, String> {
+        let (section, key) =
+            name.split_once('.').unwrap_or((DEFAULT_SECTION, name));
+        let is_name = |s: &str| {
+            !s.is_empty()
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !is_name(section) || !is_name(key) {
+            return Err(format!(
+                "'{name}' isn't a cloudbase-init setting name like \
+                \"username\" or \"config_drive.types\""
+            ));
+        }
+
+        if value.contains(['\r', '\n']) {
+            return Err(format!(
+                "the value of '{name}' can't contain newlines"
+            ));
+        }
+
+        Ok(Self {
+            section: section.to_string(),
+            key: key.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+impl std::str::FromStr for Setting {
+    type Err = String;
+
+    /// Parses `[SECTION.]KEY=VALUE`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| {
+            format!("'{s}' should be KEY=VALUE, e.g. username=admin")
+        })?;
+        Self::new(name.trim(), value)
+    }
+}
+
+impl std::fmt::Display for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.section != DEFAULT_SECTION {
+            write!(f, "{}.", self.section)?;
+        }
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// Returns the key that `line` sets, if it starts a setting.
+fn line_key(line: &str) -> Option<&str> {
+    if line.starts_with([' ', '\t', '#', ';', '[']) {
+        return None;
+    }
+
+    Some(line.split_once('=')?.0.trim())
+}
+
+/// Returns whether `line` continues the value of the setting before it.
+fn is_continuation(line: &str) -> bool {
+    line.starts_with([' ', '\t']) && !line.trim().is_empty()
+}
+
+/// Returns `conf`, the contents of a cloudbase-init configuration file, with
+/// each of `settings` set: replacing the key's existing value (including any
+/// continuation lines) if the section has one and adding it to the end of
+/// the section otherwise.
+pub fn apply_settings(conf: &str, settings: &[Setting]) -> String {
+    let newline = if conf.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut lines: Vec<String> = conf.lines().map(str::to_string).collect();
+    for setting in settings {
+        let header = format!("[{}]", setting.section);
+        let entry = format!("{}={}", setting.key, setting.value);
+        let Some(start) = lines.iter().position(|l| l.trim() == header) else {
+            if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(header);
+            lines.push(entry);
+            continue;
+        };
+
+        let end = lines[start + 1..]
+            .iter()
+            .position(|l| l.trim_start().starts_with('['))
+            .map_or(lines.len(), |i| start + 1 + i);
+        if let Some(i) = (start + 1..end)
+            .find(|&i| line_key(&lines[i]) == Some(setting.key.as_str()))
+        {
+            let continued = lines[i + 1..end]
+                .iter()
+                .take_while(|l| is_continuation(l))
+                .count();
+            lines.splice(i..i + 1 + continued, [entry]);
+        } else {
+            let last = (start..end)
+                .rev()
+                .find(|&i| !lines[i].trim().is_empty())
+                .unwrap_or(start);
+            lines.insert(last + 1, entry);
+        }
+    }
+
+    let mut out = lines.join(newline);
+    out.push_str(newline);
+    out
+}
+
+/// Checks that `msi`, if set, names a Windows Installer package that exists.
+pub fn check_prerequisites(msi: Option<&Utf8Path>) -> Vec<String> {
+    let Some(msi) = msi else {
+        return Vec::new();
+    };
+
+    if !msi.is_file() {
+        vec![format!("cloudbase-init installer '{msi}' not found")]
+    } else if !msi
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("msi"))
+    {
+        vec![format!(
+            "cloudbase-init installer '{msi}' should be a Windows Installer \
+            package with a .msi extension"
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+fn settings_from_var(var: &str) -> Vec<Setting> {
+    var.lines().filter_map(|line| line.parse().ok()).collect()
+}
+
+/// Stages the user's cloudbase-init installer, if there is one, in
+/// `unattend_dir` and applies the user's settings to the cloudbase-init
+/// configuration files there.
+pub fn configure_cloudbase_init(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    if ctx.get_var("skip_cloudbase_init").is_some() {
+        return Ok(());
+    }
+
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    if let Some(msi) = ctx.get_var("cloudbase_init_msi") {
+        ui.set_substep(&format!("staging {msi}"));
+        let dst = unattend_dir.join(MSI_FILE);
+        std::fs::copy(msi, &dst)
+            .with_context(|| format!("copying '{msi}' to '{dst}'"))?;
+    }
+
+    let Some(settings) = ctx.get_var(SETTINGS_VAR) else {
+        return Ok(());
+    };
+
+    let settings = settings_from_var(settings);
+    for filename in CONF_FILES {
+        let path = unattend_dir.join(filename);
+        if !path.exists() {
+            continue;
+        }
+
+        ui.set_substep(&format!("updating {path}"));
+        for setting in &settings {
+            trace::debug!(
+                "setting cloudbase-init option",
+                file = filename,
+                setting = setting.to_string().as_str()
+            );
+        }
+        let conf = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading '{path}'"))?;
+        std::fs::write(&path, apply_settings(&conf, &settings))
+            .with_context(|| format!("writing '{path}'"))?;
+    }
+
+    Ok(())
+}
+
+pub fn describe_configure_cloudbase_init(ctx: &mut Context) -> Vec<String> {
+    if ctx.get_var("skip_cloudbase_init").is_some() {
+        return Vec::new();
+    }
+
+    let unattend_dir = Utf8Path::new(ctx.get_var("unattend_dir").unwrap());
+    let mut descriptions = Vec::new();
+    if let Some(msi) = ctx.get_var("cloudbase_init_msi") {
+        descriptions.push(format!("copy {msi} to {unattend_dir}/{MSI_FILE}"));
+    }
+
+    if let Some(settings) = ctx.get_var(SETTINGS_VAR) {
+        descriptions.extend(settings.lines().map(|setting| {
+            format!("set {setting} in the cloudbase-init configuration files")
+        }));
+    }
+
+    descriptions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn applies_settings() {
+        let conf = "[DEFAULT]\r\n\
+            # Name default admin user 'oxide'\r\n\
+            username=oxide\r\n\
+            plugins=a.Plugin,\r\n\
+            \x20       b.Plugin\r\n\
+            \r\n\
+            [config_drive]\r\n\
+            types=vfat\r\n";
+        let settings: Vec<Setting> = [
+            "username=admin",
+            "plugins = c.Plugin",
+            "first_logon_behaviour=no",
+            "config_drive.types=iso",
+            "config_drive.cdrom=true",
+            "metadata.hosts=10.0.0.1",
+        ]
+        .into_iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+
+        assert_eq!(
+            apply_settings(conf, &settings),
+            "[DEFAULT]\r\n\
+            # Name default admin user 'oxide'\r\n\
+            username=admin\r\n\
+            plugins=c.Plugin\r\n\
+            first_logon_behaviour=no\r\n\
+            \r\n\
+            [config_drive]\r\n\
+            types=iso\r\n\
+            cdrom=true\r\n\
+            \r\n\
+            [metadata]\r\n\
+            hosts=10.0.0.1\r\n"
+        );
+
+        assert_eq!(settings[3].to_string(), "config_drive.types=iso");
+        for bad in ["username", "=admin", "a.b.c=d", "user name=admin"] {
+            assert!(bad.parse::<Setting>().is_err(), "{bad}");
+        }
+    }
+}
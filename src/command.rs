@@ -0,0 +1,352 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the external commands steps run to completion (those run with
+//! [`crate::util::run_command_check_status`]).
+//!
+//! Steps reach the build's [`CommandRunner`] through their [`Ui`]. Builds
+//! normally run commands on this host with [`HostRunner`], but a library user
+//! can supply another runner to run them somewhere else (in a container, say),
+//! and tests can use a [`RecordingRunner`] to check which commands a step runs
+//! and how it handles their output without the tools being installed.
+//! Processes that steps start and supervise, like VMs, aren't run through the
+//! runner.
+//!
+//! `--tools-container` selects a [`ContainerRunner`], which runs the disk
+//! image tools in a podman or docker container, for hosts that don't have
+//! them (or have versions that don't work).
+//!
+//! [`Ui`]: crate::ui::Ui
+
+use std::{
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus, Output},
+    sync::Mutex,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::util::format_command;
+
+/// Runs commands to completion.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `cmd`, waiting for it to exit, and returns its exit status and
+    /// the output it wrote to stdout and stderr.
+    fn output(&self, cmd: &mut Command) -> std::io::Result<Output>;
+
+    /// Returns whether this runner runs `tool` somewhere other than this
+    /// host, so that it needn't be installed here.
+    fn runs_elsewhere(&self, tool: &str) -> bool {
+        let _ = tool;
+        false
+    }
+
+    /// Returns the commands this runner itself needs this host to have.
+    fn host_tools(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+/// Runs commands on this host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostRunner;
+
+impl CommandRunner for HostRunner {
+    fn output(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        cmd.output()
+    }
+}
+
+/// The tools a [`ContainerRunner`] runs in its container. wimsy writes ISOs
+/// itself, so only the tools that work on disk images need to be here.
+pub const CONTAINER_TOOLS: &[&str] = &["qemu-img", "sgdisk"];
+
+/// Runs the [`CONTAINER_TOOLS`] in a container, giving it the directories
+/// they work in at the same paths they have on this host, and runs other
+/// commands on this host.
+pub struct ContainerRunner {
+    /// The container engine to run, `podman` or `docker`.
+    engine: String,
+    image: String,
+
+    /// The directory commands run in unless they say otherwise.
+    current_dir: Utf8PathBuf,
+
+    /// The directories to bind-mount into the container.
+    mounts: Vec<Utf8PathBuf>,
+}
+
+impl ContainerRunner {
+    /// Returns a runner that runs tools in containers created from `image`,
+    /// into which `dirs` (and the current directory) are bind-mounted. Uses
+    /// podman if it's installed, and docker otherwise.
+    pub fn new(image: &str, dirs: &[&Utf8Path]) -> std::io::Result<Self> {
+        let engine = ["podman", "docker"]
+            .into_iter()
+            .find(|engine| which::which(engine).is_ok())
+            .unwrap_or("podman");
+        let current_dir = Utf8PathBuf::try_from(std::env::current_dir()?)
+            .map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+
+        // Engines refuse to mount directories that don't exist, and a build
+        // whose output directory doesn't exist will fail and say so anyway.
+        let dirs: Vec<Utf8PathBuf> = dirs
+            .iter()
+            .map(|dir| current_dir.join(dir))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        Ok(Self::with_engine(engine, image, current_dir, dirs))
+    }
+
+    fn with_engine(
+        engine: &str,
+        image: &str,
+        current_dir: Utf8PathBuf,
+        dirs: Vec<Utf8PathBuf>,
+    ) -> Self {
+        // Mounting a directory inside another that's already mounted is
+        // redundant, and mounting one twice is an error.
+        let mut dirs = dirs;
+        dirs.push(current_dir.clone());
+        dirs.sort();
+        let mut mounts: Vec<Utf8PathBuf> = Vec::new();
+        for dir in dirs {
+            if !mounts.iter().any(|mount| dir.starts_with(mount)) {
+                mounts.push(dir);
+            }
+        }
+
+        Self {
+            engine: engine.to_string(),
+            image: image.to_string(),
+            current_dir,
+            mounts,
+        }
+    }
+
+    /// Returns the command that runs `cmd` in a container.
+    fn command(&self, cmd: &Command) -> Command {
+        let mut container = Command::new(&self.engine);
+        container
+            .args(["run", "--rm"])
+            .args(["--security-opt", "label=disable"]);
+
+        // Rootless podman maps the container's root user to the user running
+        // it, but docker needs to be told to run as that user for the files
+        // the tools write to belong to them.
+        if self.engine == "docker" {
+            // SAFETY: getuid and getgid can't fail.
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            container.arg(format!("--user={uid}:{gid}"));
+        }
+
+        for mount in &self.mounts {
+            container.arg("-v").arg(format!("{mount}:{mount}"));
+        }
+
+        // Devices, like the network block devices non-raw images are
+        // attached to, have to be passed through rather than mounted.
+        for arg in cmd.get_args() {
+            if arg.to_string_lossy().starts_with("/dev/") {
+                container.arg("--device").arg(arg);
+            }
+        }
+
+        let current_dir =
+            cmd.get_current_dir().unwrap_or(self.current_dir.as_std_path());
+        container.arg("-w").arg(current_dir);
+        for (key, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                let mut var = key.to_os_string();
+                var.push("=");
+                var.push(value);
+                container.arg("-e").arg(var);
+            }
+        }
+
+        container.arg(&self.image).arg(cmd.get_program()).args(cmd.get_args());
+        container
+    }
+}
+
+impl CommandRunner for ContainerRunner {
+    fn output(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        if self.runs_elsewhere(&cmd.get_program().to_string_lossy()) {
+            self.command(cmd).output()
+        } else {
+            cmd.output()
+        }
+    }
+
+    fn runs_elsewhere(&self, tool: &str) -> bool {
+        CONTAINER_TOOLS.contains(&tool)
+    }
+
+    fn host_tools(&self) -> Vec<&str> {
+        vec![&self.engine]
+    }
+}
+
+/// A canned result for the commands a [`RecordingRunner`] runs.
+struct Response {
+    /// The start of the command lines this response is for.
+    prefix: String,
+    status: i32,
+    stdout: String,
+}
+
+/// Pretends to run commands, recording their command lines and returning
+/// canned output instead.
+#[derive(Default)]
+pub struct RecordingRunner {
+    responses: Vec<Response>,
+    commands: Mutex<Vec<String>>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes commands whose command lines (as [`format_command`] writes them)
+    /// start with `prefix` exit with `status` after writing `stdout`. The
+    /// first matching response applies; commands that don't match any exit
+    /// successfully without writing anything.
+    pub fn respond(mut self, prefix: &str, status: i32, stdout: &str) -> Self {
+        self.responses.push(Response {
+            prefix: prefix.to_string(),
+            status,
+            stdout: stdout.to_string(),
+        });
+        self
+    }
+
+    /// Returns the command lines of the commands run so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn output(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        let line = format_command(cmd);
+        let (status, stdout) = self
+            .responses
+            .iter()
+            .find(|response| line.starts_with(&response.prefix))
+            .map_or((0, ""), |response| {
+                (response.status, response.stdout.as_str())
+            });
+        self.commands.lock().unwrap().push(line);
+
+        // Wait statuses hold the exit code in their second byte.
+        Ok(Output {
+            status: ExitStatus::from_raw(status << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::{json::Json, ui::Ui};
+
+    /// A UI for tests of steps, which runs commands with a
+    /// [`RecordingRunner`] and throws their logs away.
+    pub struct TestUi<'a> {
+        pub runner: &'a RecordingRunner,
+    }
+
+    impl Ui for TestUi<'_> {
+        fn set_substep(&self, _: &str) {}
+
+        fn child_stdout(&self, _: &str) -> anyhow::Result<std::fs::File> {
+            Ok(std::fs::File::create("/dev/null")?)
+        }
+
+        fn child_stderr(&self, _: &str) -> anyhow::Result<std::fs::File> {
+            Ok(std::fs::File::create("/dev/null")?)
+        }
+
+        fn serial_log(
+            &self,
+            _: &str,
+        ) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
+            Ok(Box::new(std::io::sink()))
+        }
+
+        fn command_started(&self, _: &Command) {}
+
+        fn warn(&self, _: &str) {}
+
+        fn record_metric(&self, _: &str, _: Json) {}
+
+        fn read_secret(&self, _: &str) -> anyhow::Result<String> {
+            anyhow::bail!("tests can't read secrets")
+        }
+
+        fn command_runner(&self) -> &dyn CommandRunner {
+            self.runner
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_commands_and_returns_responses() {
+        let runner = RecordingRunner::new()
+            .respond("sgdisk -p", 0, "table")
+            .respond("sgdisk", 2, "");
+
+        let output = runner
+            .output(Command::new("sgdisk").args(["-p", "a b.img"]))
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"table");
+
+        let output = runner.output(Command::new("sgdisk").arg("-e")).unwrap();
+        assert_eq!(output.status.code(), Some(2));
+
+        assert!(runner
+            .output(&mut Command::new("true"))
+            .unwrap()
+            .status
+            .success());
+        assert_eq!(
+            runner.commands(),
+            ["sgdisk -p 'a b.img'", "sgdisk -e", "true"]
+        );
+    }
+
+    #[test]
+    fn runs_tools_in_containers() {
+        let runner = ContainerRunner::with_engine(
+            "podman",
+            "example.com/tools",
+            "/src".into(),
+            vec!["/work/a".into(), "/work".into(), "/src/out".into()],
+        );
+        assert_eq!(runner.mounts, ["/src", "/work"]);
+
+        let mut cmd = Command::new("sgdisk");
+        cmd.args(["-p", "/dev/nbd0"]).env("LC_ALL", "C");
+        assert_eq!(
+            format_command(&runner.command(&cmd)),
+            "podman run --rm --security-opt label=disable -v /src:/src \
+            -v /work:/work --device /dev/nbd0 -w /src -e LC_ALL=C \
+            example.com/tools sgdisk -p /dev/nbd0"
+        );
+
+        assert!(runner.runs_elsewhere("qemu-img"));
+        assert!(!runner.runs_elsewhere("qemu-system-x86_64"));
+        assert_eq!(runner.host_tools(), ["podman"]);
+    }
+}
@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compresses finished output images for distribution.
+//!
+//! A freshly installed raw image is mostly zeroes, so it compresses very
+//! well, but the standard compressors don't know to skip a sparse file's
+//! holes and read them as zeroes like everything else. wimsy streams the
+//! image into the compressor itself, so that it can hash the uncompressed
+//! image on the way and report how far along the compressor is.
+
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{
+    hash::{self, Sha256},
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::format_command,
+};
+
+/// The amount of the image to read and compress at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How often, in percent of the image, to report compression progress.
+const PROGRESS_INTERVAL: u64 = 5;
+
+/// A compressor for finished images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+impl Compression {
+    /// The file name extension of images compressed this way.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+            Compression::Xz => ".xz",
+        }
+    }
+
+    /// The program that does this compression.
+    fn program(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::Xz => Some("xz"),
+        }
+    }
+
+    /// The command that compresses its standard input to its standard
+    /// output, using all of the host's CPUs if the compressor can.
+    pub fn command(&self) -> Option<Command> {
+        let mut cmd = Command::new(self.program()?);
+        if matches!(self, Compression::Zstd | Compression::Xz) {
+            cmd.args(["-q", "-T0"]);
+        }
+        // Leave the time the image was compressed out of the gzip header, so
+        // that compressing the same image twice gives the same file.
+        if *self == Compression::Gzip {
+            cmd.arg("-n");
+        }
+        cmd.arg("-c");
+        Some(cmd)
+    }
+}
+
+/// Checks that the program that does `compression` is installed.
+pub fn check_prerequisites(compression: Compression) -> Vec<String> {
+    match compression.program() {
+        Some(program) if which::which(program).is_err() => vec![format!(
+            "{compression} compression requires {program}, which wasn't \
+            found (is it on your PATH?)"
+        )],
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the requested compression and the path of the compressed image,
+/// if the user asked for one.
+fn target(ctx: &Context) -> Option<(Compression, Utf8PathBuf)> {
+    let compression: Compression =
+        clap::ValueEnum::from_str(ctx.get_var("compress")?, true).ok()?;
+    if compression == Compression::None {
+        return None;
+    }
+
+    let image = ctx.get_var("output_image").unwrap();
+    Some((compression, format!("{image}{}", compression.extension()).into()))
+}
+
+/// Describes the command [`compress_output_image`] would run.
+pub fn describe_compression(ctx: &mut Context) -> Vec<String> {
+    let Some((compression, compressed)) = target(ctx) else {
+        return Vec::new();
+    };
+
+    vec![format!(
+        "{} < {} > {compressed}",
+        format_command(&compression.command().unwrap()),
+        ctx.get_var("output_image").unwrap()
+    )]
+}
+
+/// Compresses the output image to a file next to it named after the
+/// compression (e.g. `image.raw.zst`), and records the sizes and digests of
+/// both images. Does nothing if no compression was requested.
+pub fn compress_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some((compression, compressed)) = target(ctx) else {
+        return Ok(());
+    };
+
+    let image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let size = std::fs::metadata(&image)
+        .with_context(|| format!("reading metadata for '{image}'"))?
+        .len();
+    let mut source = std::fs::File::open(&image)
+        .with_context(|| format!("opening '{image}'"))?;
+    let _cancel = crate::interrupt::remove_on_cancel(&compressed);
+    let output = std::fs::File::create(&compressed)
+        .with_context(|| format!("creating '{compressed}'"))?;
+
+    let mut cmd = compression.command().unwrap();
+    let program = compression.program().unwrap();
+    ui.command_started(&cmd);
+    cmd.stdin(Stdio::piped()).stdout(output).stderr(ui.child_stderr(program)?);
+    let mut child =
+        cmd.spawn().with_context(|| format!("starting {program}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+
+    let mut hasher = Sha256::new();
+    let mut read_total = 0u64;
+    let mut reported = None;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let copied: Result<()> = (|| loop {
+        let percent = (read_total * 100).checked_div(size).unwrap_or(100);
+        if reported.is_none_or(|r| percent >= r + PROGRESS_INTERVAL) {
+            ui.set_substep(&format!("compressing {image} ({percent}%)"));
+            reported = Some(percent);
+        }
+
+        let read = source
+            .read(&mut buf)
+            .with_context(|| format!("reading '{image}'"))?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        hasher.update(&buf[..read]);
+        stdin
+            .write_all(&buf[..read])
+            .with_context(|| format!("writing to {program}"))?;
+        read_total += read as u64;
+    })();
+    drop(stdin);
+
+    let status =
+        child.wait().with_context(|| format!("waiting for {program}"))?;
+    if !status.success() {
+        anyhow::bail!("{program} failed ({status}); see its log for details");
+    }
+    copied?;
+
+    ui.set_substep(&format!("hashing {compressed}"));
+    let compressed_size = std::fs::metadata(&compressed)
+        .with_context(|| format!("reading metadata for '{compressed}'"))?
+        .len();
+    let sha256 = hash::to_hex(&hasher.finish());
+    let compressed_sha256 = crate::download::file_sha256(&compressed)?;
+    trace::debug!(
+        "compressed output image",
+        compression = compression.to_string(),
+        size = size,
+        compressed_size = compressed_size
+    );
+
+    crate::manifest::record_image_digest(ctx, &image, &sha256);
+    let metadata = Json::object()
+        .with("path", compressed.as_str())
+        .with("compression", compression.to_string())
+        .with("uncompressed_size_bytes", size)
+        .with("uncompressed_sha256", sha256)
+        .with("file_size_bytes", compressed_size)
+        .with("sha256", compressed_sha256);
+    ui.record_metric("compressed_image", metadata);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_compression_commands() {
+        let args = |compression: Compression| {
+            compression.command().map(|cmd| format_command(&cmd))
+        };
+
+        assert_eq!(args(Compression::None), None);
+        assert_eq!(args(Compression::Gzip).unwrap(), "gzip -n -c");
+        assert_eq!(args(Compression::Zstd).unwrap(), "zstd -q -T0 -c");
+        assert_eq!(args(Compression::Xz).unwrap(), "xz -q -T0 -c");
+        assert_eq!(Compression::Xz.extension(), ".xz");
+    }
+}
@@ -0,0 +1,3216 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading wimsy configuration files.
+//!
+//! Configuration files are written in a subset of TOML: tables, arrays of
+//! tables, dotted keys, strings (basic, literal, and their multi-line forms),
+//! integers, booleans, arrays, and inline tables are supported. Dates, times,
+//! and floating-point numbers are not.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+#[cfg(target_os = "linux")]
+use crate::app::OutputFormat;
+use crate::{
+    activation::{KmsHost, ProductKey},
+    app::{DiskController, DiskSize, MachineType, NicModel},
+    autounattend::WindowsVersion,
+    cloudbase_init::Setting,
+    compress::Compression,
+    features::WindowsFeature,
+    network::Ipv4Interface,
+    provision::FileCopy,
+    registry_settings::{Data, RegistrySetting},
+    secrets::SecretSource,
+    template::UserVar,
+    users::LocalUser,
+    wim::Edition,
+};
+
+/// A value in a configuration file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+/// Parses `source` as a configuration file, returning its top-level table.
+pub fn parse(source: &str) -> Result<Table> {
+    Parser::new(source).parse()
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+/// Tracks whether a table was created by a header or only implicitly (as the
+/// parent of a dotted key or nested header), so that duplicate headers can be
+/// rejected.
+#[derive(Default)]
+struct DefinedTables {
+    explicit: BTreeSet<Vec<String>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { chars: source.chars().peekable(), line: 1 }
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> anyhow::Error {
+        anyhow::anyhow!("line {}: {}", self.line, msg)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            let found = describe(self.peek());
+            Err(self.error(format!("expected '{c}', found {found}")))
+        }
+    }
+
+    /// Skips spaces and tabs on the current line.
+    fn skip_inline_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    /// Skips whitespace, newlines, and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.next();
+                }
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.next();
+        }
+    }
+
+    /// Consumes the remainder of a line after a key/value pair or header,
+    /// which may contain only whitespace and a comment.
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_inline_whitespace();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.next();
+                Ok(())
+            }
+            other => Err(self.error(format!(
+                "expected end of line, found {}",
+                describe(other)
+            ))),
+        }
+    }
+
+    fn parse(mut self) -> Result<Table> {
+        let mut root = Table::new();
+        let mut defined = DefinedTables::default();
+        let mut current: Vec<String> = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    self.next();
+                    let array = self.eat('[');
+                    self.skip_inline_whitespace();
+                    let path = self.key_path()?;
+                    self.skip_inline_whitespace();
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                        self.push_array_table(&mut root, &path)?;
+                    } else {
+                        if !defined.explicit.insert(path.clone()) {
+                            return Err(self.error(format!(
+                                "table [{}] is defined more than once",
+                                path.join(".")
+                            )));
+                        }
+                        self.table_at(&mut root, &path)?;
+                    }
+                    self.end_of_line()?;
+                    current = path;
+                }
+                Some(_) => {
+                    let (path, value) = self.key_value()?;
+                    self.end_of_line()?;
+                    let table = self.table_at(&mut root, &current)?;
+                    self.insert(table, &path, value)?;
+                }
+            }
+        }
+
+        Ok(root)
+    }
+
+    /// Returns the table at `path`, creating intermediate tables as needed.
+    /// If a path component names an array of tables, the last table in the
+    /// array is used.
+    fn table_at<'t>(
+        &self,
+        mut table: &'t mut Table,
+        path: &[String],
+    ) -> Result<&'t mut Table> {
+        for key in path {
+            let entry = table
+                .entry(key.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            table = match entry {
+                Value::Table(t) => t,
+                Value::Array(items) => match items.last_mut() {
+                    Some(Value::Table(t)) => t,
+                    _ => {
+                        return Err(
+                            self.error(format!("key '{key}' is not a table"))
+                        )
+                    }
+                },
+                _ => {
+                    return Err(
+                        self.error(format!("key '{key}' is not a table"))
+                    )
+                }
+            };
+        }
+
+        Ok(table)
+    }
+
+    fn push_array_table(
+        &self,
+        root: &mut Table,
+        path: &[String],
+    ) -> Result<()> {
+        let (last, parents) = path.split_last().unwrap();
+        let parent = self.table_at(root, parents)?;
+        let entry =
+            parent.entry(last.clone()).or_insert_with(|| Value::Array(vec![]));
+        match entry {
+            Value::Array(items)
+                if items.iter().all(|i| matches!(i, Value::Table(_))) =>
+            {
+                items.push(Value::Table(Table::new()));
+                Ok(())
+            }
+            _ => Err(self.error(format!(
+                "key '{}' is not an array of tables",
+                path.join(".")
+            ))),
+        }
+    }
+
+    fn insert(
+        &self,
+        table: &mut Table,
+        path: &[String],
+        value: Value,
+    ) -> Result<()> {
+        let (last, parents) = path.split_last().unwrap();
+        let table = self.table_at(table, parents)?;
+        if table.contains_key(last) {
+            return Err(self.error(format!(
+                "key '{}' is defined more than once",
+                path.join(".")
+            )));
+        }
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn key_value(&mut self) -> Result<(Vec<String>, Value)> {
+        let path = self.key_path()?;
+        self.skip_inline_whitespace();
+        self.expect('=')?;
+        self.skip_inline_whitespace();
+        let value = self.value()?;
+        Ok((path, value))
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_inline_whitespace();
+            if !self.eat('.') {
+                break;
+            }
+            self.skip_inline_whitespace();
+            path.push(self.key()?);
+        }
+        Ok(path)
+    }
+
+    fn key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => {
+                self.next();
+                self.basic_string()
+            }
+            Some('\'') => {
+                self.next();
+                self.literal_string()
+            }
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    let found = describe(self.peek());
+                    Err(self.error(format!("expected a key, found {found}")))
+                } else {
+                    Ok(key)
+                }
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => {
+                self.next();
+                if self.eat('"') {
+                    if self.eat('"') {
+                        return self.multiline_string('"').map(Value::String);
+                    }
+                    return Ok(Value::String(String::new()));
+                }
+                self.basic_string().map(Value::String)
+            }
+            Some('\'') => {
+                self.next();
+                if self.eat('\'') {
+                    if self.eat('\'') {
+                        return self.multiline_string('\'').map(Value::String);
+                    }
+                    return Ok(Value::String(String::new()));
+                }
+                self.literal_string().map(Value::String)
+            }
+            Some('[') => {
+                self.next();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.eat(']') {
+                        break;
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank();
+                    if !self.eat(',') {
+                        self.skip_blank();
+                        self.expect(']')?;
+                        break;
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some('{') => {
+                self.next();
+                let mut table = Table::new();
+                self.skip_inline_whitespace();
+                if self.eat('}') {
+                    return Ok(Value::Table(table));
+                }
+                loop {
+                    self.skip_inline_whitespace();
+                    let (path, value) = self.key_value()?;
+                    self.insert(&mut table, &path, value)?;
+                    self.skip_inline_whitespace();
+                    if self.eat('}') {
+                        break;
+                    }
+                    self.expect(',')?;
+                }
+                Ok(Value::Table(table))
+            }
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() => {
+                let mut text = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '_')
+                    {
+                        text.push(c);
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                let digits = text.replace('_', "");
+                digits.parse::<i64>().map(Value::Integer).map_err(|_| {
+                    self.error(format!("'{text}' is not a supported integer"))
+                })
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let word = self.key()?;
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => Err(self.error(format!(
+                        "'{word}' is not a valid value (strings must be quoted)"
+                    ))),
+                }
+            }
+            other => Err(self
+                .error(format!("expected a value, found {}", describe(other)))),
+        }
+    }
+
+    /// Parses the remainder of a basic string after its opening quote.
+    fn basic_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => {
+                    return Err(self.error("unterminated string"));
+                }
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// Parses the remainder of a literal string after its opening quote.
+    fn literal_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => {
+                    return Err(self.error("unterminated string"));
+                }
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// Parses the remainder of a multi-line string after its opening
+    /// delimiter. A newline immediately following the delimiter is trimmed.
+    fn multiline_string(&mut self, quote: char) -> Result<String> {
+        let mut s = String::new();
+        self.eat('\r');
+        self.eat('\n');
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated string")),
+                Some(c) if c == quote => {
+                    if self.eat(quote) {
+                        if self.eat(quote) {
+                            return Ok(s);
+                        }
+                        s.push(quote);
+                    }
+                    s.push(quote);
+                }
+                Some('\\') if quote == '"' => {
+                    // A backslash at the end of a line trims the newline and
+                    // any leading whitespace on the next line.
+                    if matches!(self.peek(), Some('\n' | '\r' | ' ' | '\t')) {
+                        self.skip_blank_no_comments();
+                    } else {
+                        s.push(self.escape()?);
+                    }
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn skip_blank_no_comments(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.next();
+        }
+    }
+
+    fn escape(&mut self) -> Result<char> {
+        match self.next() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some(c @ ('u' | 'U')) => {
+                let len = if c == 'u' { 4 } else { 8 };
+                let mut hex = String::new();
+                for _ in 0..len {
+                    hex.extend(self.next());
+                }
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| {
+                        self.error(format!(
+                            "invalid unicode escape '\\{c}{hex}'"
+                        ))
+                    })
+            }
+            other => Err(self.error(format!(
+                "invalid escape sequence '\\{}'",
+                other.map(String::from).unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+fn describe(c: Option<char>) -> String {
+    match c {
+        None => "end of file".to_string(),
+        Some('\n') => "end of line".to_string(),
+        Some(c) => format!("'{c}'"),
+    }
+}
+
+/// Reads typed fields out of a configuration table, keeping track of which
+/// keys have been consumed so that unrecognized keys (usually typos) can be
+/// reported.
+pub struct Fields<'a> {
+    table: &'a Table,
+    path: String,
+    used: BTreeSet<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    /// Creates a reader for `table`, which is located at `path` (e.g.
+    /// `steps.insert[0]`) in the configuration file. `path` is used in error
+    /// messages.
+    pub fn new(table: &'a Table, path: impl Into<String>) -> Self {
+        Self { table, path: path.into(), used: BTreeSet::new() }
+    }
+
+    fn name(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&'a Value> {
+        let (k, v) = self.table.get_key_value(key)?;
+        self.used.insert(k.as_str());
+        Some(v)
+    }
+
+    fn type_error(
+        &self,
+        key: &str,
+        expected: &str,
+        found: &Value,
+    ) -> anyhow::Error {
+        anyhow::anyhow!(
+            "'{}' should be {expected}, but is {}",
+            self.name(key),
+            found.type_name()
+        )
+    }
+
+    pub fn string(&mut self, key: &str) -> Result<Option<String>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(other) => Err(self.type_error(key, "a string", other)),
+        }
+    }
+
+    pub fn required_string(&mut self, key: &str) -> Result<String> {
+        self.string(key)?
+            .ok_or_else(|| anyhow::anyhow!("'{}' is required", self.name(key)))
+    }
+
+    pub fn integer(&mut self, key: &str) -> Result<Option<i64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) => Ok(Some(*i)),
+            Some(other) => Err(self.type_error(key, "an integer", other)),
+        }
+    }
+
+    /// Reads a non-negative integer that fits in a `T`.
+    pub fn unsigned<T: TryFrom<i64>>(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<T>> {
+        match self.integer(key)? {
+            None => Ok(None),
+            Some(i) => T::try_from(i).map(Some).map_err(|_| {
+                anyhow::anyhow!("'{}' is out of range: {i}", self.name(key))
+            }),
+        }
+    }
+
+    pub fn boolean(&mut self, key: &str) -> Result<Option<bool>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(*b)),
+            Some(other) => Err(self.type_error(key, "a boolean", other)),
+        }
+    }
+
+    pub fn string_array(&mut self, key: &str) -> Result<Vec<String>> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s.clone()),
+                    other => {
+                        Err(self.type_error(key, "an array of strings", other))
+                    }
+                })
+                .collect(),
+            Some(other) => {
+                Err(self.type_error(key, "an array of strings", other))
+            }
+        }
+    }
+
+    /// Consumes every key in this table, returning the keys and their values,
+    /// which must all be strings.
+    pub fn entries(&mut self) -> Result<Vec<(String, String)>> {
+        let keys: Vec<&'a String> = self.table.keys().collect();
+        keys.into_iter()
+            .map(|key| {
+                self.string(key).map(|value| (key.clone(), value.unwrap()))
+            })
+            .collect()
+    }
+
+    pub fn table(&mut self, key: &str) -> Result<Option<Fields<'a>>> {
+        let name = self.name(key);
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Table(t)) => Ok(Some(Fields::new(t, name))),
+            Some(other) => Err(self.type_error(key, "a table", other)),
+        }
+    }
+
+    /// Returns a reader for each table in the array of tables at `key`.
+    pub fn tables(&mut self, key: &str) -> Result<Vec<Fields<'a>>> {
+        let name = self.name(key);
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| match item {
+                    Value::Table(t) => {
+                        Ok(Fields::new(t, format!("{name}[{i}]")))
+                    }
+                    other => {
+                        Err(self.type_error(key, "an array of tables", other))
+                    }
+                })
+                .collect(),
+            Some(other) => {
+                Err(self.type_error(key, "an array of tables", other))
+            }
+        }
+    }
+
+    /// Returns an error if any keys in this table were never consumed.
+    pub fn finish(self) -> Result<()> {
+        let unknown: Vec<String> = self
+            .table
+            .keys()
+            .filter(|k| !self.used.contains(k.as_str()))
+            .map(|k| format!("'{}'", self.name(k)))
+            .collect();
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!(
+                "unrecognized configuration key(s): {}",
+                unknown.join(", ")
+            )
+        }
+    }
+}
+
+/// A user-defined step that runs a command or script.
+#[derive(Clone, Debug)]
+pub struct UserCommand {
+    /// A shell command line, run with `sh -c`.
+    pub command: Option<String>,
+
+    /// A path to an executable script, run directly.
+    pub script: Option<Utf8PathBuf>,
+
+    /// The label to display for this step.
+    pub label: Option<String>,
+}
+
+impl UserCommand {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let command = fields.string("command")?;
+        let script = fields.string("script")?.map(|s| base_dir.join(s));
+        let label = fields.string("label")?;
+        match (&command, &script) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "'{}' sets both 'command' and 'script'; set only one",
+                fields.path
+            ),
+            (None, None) => anyhow::bail!(
+                "'{}' must set one of 'command' or 'script'",
+                fields.path
+            ),
+            _ => {}
+        }
+
+        Ok(Self { command, script, label })
+    }
+}
+
+/// Where to insert a user-defined step relative to another step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    Before(String),
+    After(String),
+}
+
+impl Anchor {
+    pub fn target(&self) -> &str {
+        match self {
+            Anchor::Before(s) | Anchor::After(s) => s,
+        }
+    }
+}
+
+impl std::fmt::Display for Anchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anchor::Before(s) => write!(f, "before {s}"),
+            Anchor::After(s) => write!(f, "after {s}"),
+        }
+    }
+}
+
+/// A user-defined step to insert into a script.
+#[derive(Clone, Debug)]
+pub struct InsertedStep {
+    pub name: String,
+    pub anchor: Anchor,
+    pub run: UserCommand,
+}
+
+/// A user-defined command that replaces one of a script's built-in steps.
+#[derive(Clone, Debug)]
+pub struct ReplacedStep {
+    pub step: String,
+    pub run: UserCommand,
+}
+
+/// Changes to make to a script's built-in list of steps.
+#[derive(Clone, Debug, Default)]
+pub struct StepOverrides {
+    pub disable: Vec<String>,
+    pub insert: Vec<InsertedStep>,
+    pub replace: Vec<ReplacedStep>,
+}
+
+impl StepOverrides {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let disable = fields.string_array("disable")?;
+
+        let mut insert = Vec::new();
+        for mut step in fields.tables("insert")? {
+            let name = step.required_string("name")?;
+            let anchor = match (step.string("before")?, step.string("after")?) {
+                (Some(before), None) => Anchor::Before(before),
+                (None, Some(after)) => Anchor::After(after),
+                _ => anyhow::bail!(
+                    "'{}' must set exactly one of 'before' or 'after'",
+                    step.path
+                ),
+            };
+            let run = UserCommand::read(&mut step, base_dir)?;
+            step.finish()?;
+            insert.push(InsertedStep { name, anchor, run });
+        }
+
+        let mut replace = Vec::new();
+        for mut step in fields.tables("replace")? {
+            let name = step.required_string("step")?;
+            let run = UserCommand::read(&mut step, base_dir)?;
+            step.finish()?;
+            replace.push(ReplacedStep { step: name, run });
+        }
+
+        Ok(Self { disable, insert, replace })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.disable.is_empty()
+            && self.insert.is_empty()
+            && self.replace.is_empty()
+    }
+}
+
+/// Commands to run around the steps a build runs, from the `[hooks]` table.
+/// Each is a command line run with `sh -c`.
+#[derive(Clone, Debug, Default)]
+pub struct StepHooks {
+    /// Runs before each attempt at a step. If it fails, so does the step.
+    pub pre_step: Option<String>,
+
+    /// Runs after each step succeeds. If it fails, so does the step.
+    pub post_step: Option<String>,
+
+    /// Runs after each failed attempt at a step.
+    pub on_failure: Option<String>,
+
+    /// The steps to run the hooks around, or empty to run them around every
+    /// step.
+    pub steps: Vec<String>,
+}
+
+impl StepHooks {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        Ok(Self {
+            pre_step: fields.string("pre_step")?,
+            post_step: fields.string("post_step")?,
+            on_failure: fields.string("on_failure")?,
+            steps: fields.string_array("steps")?,
+        })
+    }
+
+    /// Returns whether any hooks run around the step `name`.
+    pub fn apply_to(&self, name: &str) -> bool {
+        (self.pre_step.is_some()
+            || self.post_step.is_some()
+            || self.on_failure.is_some())
+            && (self.steps.is_empty() || self.steps.iter().any(|s| s == name))
+    }
+}
+
+/// How to retry steps that fail, from the `[retry]` table.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times to run a step before letting it fail. 1 turns retries
+    /// off.
+    pub attempts: u32,
+
+    /// How long to wait before a step's second attempt. Each later attempt
+    /// waits twice as long as the one before it.
+    pub backoff_secs: u64,
+
+    /// The steps to retry, or empty to retry the steps that can fail for
+    /// passing reasons, like downloads.
+    pub steps: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { attempts: 3, backoff_secs: 10, steps: Vec::new() }
+    }
+}
+
+impl RetryPolicy {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let default = Self::default();
+        let attempts = fields.unsigned("attempts")?.unwrap_or(default.attempts);
+        if attempts == 0 {
+            anyhow::bail!("'{}' must be at least 1", fields.name("attempts"));
+        }
+
+        Ok(Self {
+            attempts,
+            backoff_secs: fields
+                .unsigned("backoff_secs")?
+                .unwrap_or(default.backoff_secs),
+            steps: fields.string_array("steps")?,
+        })
+    }
+
+    /// Returns how many times to run the step `name`, which can fail for
+    /// passing reasons if `retryable` is set.
+    pub fn attempts_for(&self, name: &str, retryable: bool) -> u32 {
+        let retried = if self.steps.is_empty() {
+            retryable
+        } else {
+            self.steps.iter().any(|s| s == name)
+        };
+        if retried {
+            self.attempts
+        } else {
+            1
+        }
+    }
+
+    /// Returns how long to wait after a step's `attempt`th attempt fails
+    /// before trying again.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor =
+            1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_secs(self.backoff_secs.saturating_mul(factor))
+    }
+}
+
+/// What a functional test checks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestCheck {
+    /// Runs a PowerShell script in the guest. The test passes if the script
+    /// exits with `expect_exit` and, if `expect_output` is set, its standard
+    /// output matches it (ignoring leading and trailing whitespace).
+    PowerShell {
+        script: String,
+        expect_output: Option<String>,
+        expect_exit: i64,
+        via: Transport,
+    },
+
+    /// Connects to a TCP port in the guest from the host. The test passes if
+    /// something in the guest accepts the connection.
+    TcpPort(u16),
+}
+
+/// How PowerShell runs in the test VM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Through the QEMU guest agent, as `LocalSystem`.
+    #[default]
+    Agent,
+
+    /// Through WinRM, as the account in the `[tests.winrm]` table.
+    Winrm,
+}
+
+impl std::str::FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "agent" => Ok(Self::Agent),
+            "winrm" => Ok(Self::Winrm),
+            _ => Err(format!("'{s}' isn't \"agent\" or \"winrm\"")),
+        }
+    }
+}
+
+/// The default port WinRM listens on for HTTP.
+pub const DEFAULT_WINRM_PORT: u16 = 5985;
+
+/// The account and port WinRM tests connect with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WinrmConfig {
+    pub username: String,
+
+    /// Where to read the account's password from. If not set, it's read from
+    /// `--admin-password`'s source.
+    pub password: Option<crate::secrets::SecretSource>,
+    pub port: u16,
+}
+
+impl Default for WinrmConfig {
+    fn default() -> Self {
+        Self {
+            username: "Administrator".to_string(),
+            password: None,
+            port: DEFAULT_WINRM_PORT,
+        }
+    }
+}
+
+impl WinrmConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let defaults = Self::default();
+        let password = match fields.string("password")? {
+            Some(source) => Some(source.parse().with_context(|| {
+                format!("'{}' is invalid", fields.name("password"))
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            username: fields.string("username")?.unwrap_or(defaults.username),
+            password,
+            port: fields.unsigned("port")?.unwrap_or(defaults.port),
+        })
+    }
+}
+
+/// How a boot test decides that the finished image has booted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootCheck {
+    /// Waits for the banner Windows' Special Administration Console prints
+    /// to the serial port when the kernel starts it, which the guest setup
+    /// script enables.
+    Serial,
+
+    /// Waits for the QEMU guest agent to report that Windows has finished
+    /// setting up, as the functional tests do.
+    Agent,
+
+    /// Waits for something in the guest to accept connections on a TCP
+    /// port.
+    TcpPort(u16),
+}
+
+impl std::str::FromStr for BootCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "serial" => Ok(Self::Serial),
+            "agent" => Ok(Self::Agent),
+            _ => match s.strip_prefix("tcp:").map(str::parse::<u16>) {
+                Some(Ok(port)) if port != 0 => Ok(Self::TcpPort(port)),
+                _ => Err(format!(
+                    "'{s}' isn't \"serial\", \"agent\", or \"tcp:PORT\""
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for BootCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootCheck::Serial => write!(f, "serial"),
+            BootCheck::Agent => write!(f, "agent"),
+            BootCheck::TcpPort(port) => write!(f, "tcp:{port}"),
+        }
+    }
+}
+
+/// A functional test to run against a finished image.
+#[derive(Clone, Debug)]
+pub struct ImageTest {
+    pub name: String,
+    pub check: TestCheck,
+
+    /// Whether the build fails if this test fails. Failures of other tests
+    /// are reported as warnings.
+    pub required: bool,
+
+    /// How long to let the test run before treating it as failed.
+    pub timeout_secs: u64,
+}
+
+/// The default time to wait for a test VM to boot and start its guest agent.
+pub const DEFAULT_TEST_BOOT_TIMEOUT_SECS: u64 = 30 * 60;
+
+/// The default time to let each functional test run.
+pub const DEFAULT_TEST_TIMEOUT_SECS: u64 = 5 * 60;
+
+/// Functional tests to run against a finished image.
+#[derive(Clone, Debug)]
+pub struct ImageTests {
+    /// How long to wait for the test VM to boot and start its guest agent.
+    pub boot_timeout_secs: u64,
+
+    /// How to check that the image boots before running the tests, if it
+    /// should be checked. `--boot-test` takes precedence.
+    pub boot_check: Option<BootCheck>,
+
+    pub tests: Vec<ImageTest>,
+
+    /// How WinRM tests connect.
+    pub winrm: WinrmConfig,
+
+    /// PowerShell commands to run in the test VM after the tests, before
+    /// it's destroyed, and how to run them.
+    pub final_commands: Vec<String>,
+    pub final_commands_via: Transport,
+}
+
+impl Default for ImageTests {
+    fn default() -> Self {
+        Self {
+            boot_timeout_secs: DEFAULT_TEST_BOOT_TIMEOUT_SECS,
+            boot_check: None,
+            tests: Vec::new(),
+            winrm: WinrmConfig::default(),
+            final_commands: Vec::new(),
+            final_commands_via: Transport::Agent,
+        }
+    }
+}
+
+impl ImageTests {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let boot_timeout_secs = fields
+            .unsigned("boot_timeout_secs")?
+            .unwrap_or(DEFAULT_TEST_BOOT_TIMEOUT_SECS);
+        let boot_check = match fields.string("boot_check")? {
+            Some(check) => Some(check.parse().map_err(|e| {
+                anyhow::anyhow!(
+                    "'{}' is invalid: {e}",
+                    fields.name("boot_check")
+                )
+            })?),
+            None => None,
+        };
+
+        let mut tests: Vec<ImageTest> = Vec::new();
+        for mut test in fields.tables("check")? {
+            let name = test.required_string("name")?;
+            if tests.iter().any(|t| t.name == name) {
+                anyhow::bail!("more than one test is named '{name}'");
+            }
+
+            let check = Self::read_check(&mut test, base_dir)?;
+            let required = test.boolean("required")?.unwrap_or(true);
+            let timeout_secs = test
+                .unsigned("timeout_secs")?
+                .unwrap_or(DEFAULT_TEST_TIMEOUT_SECS);
+            test.finish()?;
+            tests.push(ImageTest { name, check, required, timeout_secs });
+        }
+
+        let winrm = match fields.table("winrm")? {
+            Some(mut winrm) => {
+                let config = WinrmConfig::read(&mut winrm)?;
+                winrm.finish()?;
+                config
+            }
+            None => WinrmConfig::default(),
+        };
+        let final_commands = fields.string_array("final_commands")?;
+        let final_commands_via =
+            Self::read_transport(fields, "final_commands_via")?;
+
+        Ok(Self {
+            boot_timeout_secs,
+            boot_check,
+            tests,
+            winrm,
+            final_commands,
+            final_commands_via,
+        })
+    }
+
+    fn read_transport(fields: &mut Fields<'_>, key: &str) -> Result<Transport> {
+        match fields.string(key)? {
+            Some(via) => via.parse().map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name(key))
+            }),
+            None => Ok(Transport::Agent),
+        }
+    }
+
+    /// Reads what the test in `test` checks. Besides a PowerShell script or a
+    /// TCP port, a test can use one of the built-in checks, which are
+    /// PowerShell scripts that print `True` if they pass.
+    fn read_check(
+        test: &mut Fields<'_>,
+        base_dir: &Utf8Path,
+    ) -> Result<TestCheck> {
+        let mut scripts = Vec::new();
+        if let Some(script) = test.string("powershell")? {
+            scripts.push((script, None));
+        }
+        if let Some(path) = test.string("script")? {
+            let path = base_dir.join(path);
+            let script = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading test script '{path}'"))?;
+            scripts.push((script, None));
+        }
+        if test.boolean("generalized")? == Some(true) {
+            // Sysprep records 7 once it has generalized the image.
+            scripts.push((
+                "(Get-ItemProperty HKLM:\\SYSTEM\\Setup\\Status\\\
+                SysprepStatus).GeneralizationState -eq 7"
+                    .to_string(),
+                Some("True"),
+            ));
+        }
+        if let Some(feature) = test.string("windows_feature")? {
+            scripts.push((
+                format!(
+                    "(Get-WindowsFeature -Name {}).Installed",
+                    crate::util::powershell_quote(&feature)
+                ),
+                Some("True"),
+            ));
+        }
+        if let Some(service) = test.string("service")? {
+            scripts.push((
+                format!(
+                    "[bool](Get-Service -Name {} -ErrorAction \
+                    SilentlyContinue)",
+                    crate::util::powershell_quote(&service)
+                ),
+                Some("True"),
+            ));
+        }
+
+        let expect_output = test.string("expect_output")?;
+        let expect_exit = test.integer("expect_exit")?;
+        let via = Self::read_transport(test, "via")?;
+        let port = test.unsigned::<u16>("tcp_port")?;
+        match (scripts.len(), port) {
+            (1, None) => {
+                let (script, builtin_output) = scripts.pop().unwrap();
+                if builtin_output.is_some() && expect_output.is_some() {
+                    anyhow::bail!(
+                        "'{}' uses a built-in check, so it can't set \
+                        'expect_output'",
+                        test.path
+                    );
+                }
+                Ok(TestCheck::PowerShell {
+                    script,
+                    expect_output: expect_output
+                        .or(builtin_output.map(str::to_string)),
+                    expect_exit: expect_exit.unwrap_or(0),
+                    via,
+                })
+            }
+            (0, Some(port)) if port != 0 => {
+                if expect_output.is_some() || expect_exit.is_some() {
+                    anyhow::bail!(
+                        "'{}' checks a TCP port, so it can't set \
+                        'expect_output' or 'expect_exit'",
+                        test.path
+                    );
+                }
+                if via != Transport::Agent {
+                    anyhow::bail!(
+                        "'{}' checks a TCP port from the host, so it can't \
+                        set 'via'",
+                        test.path
+                    );
+                }
+                Ok(TestCheck::TcpPort(port))
+            }
+            (0, Some(_)) => {
+                anyhow::bail!("'{}.tcp_port' can't be 0", test.path)
+            }
+            _ => anyhow::bail!(
+                "'{}' must set exactly one of 'powershell', 'script', \
+                'tcp_port', 'generalized', 'windows_feature', or 'service'",
+                test.path
+            ),
+        }
+    }
+
+    /// Returns these tests with the boot check `--boot-test` asked for, if it
+    /// asked for one.
+    pub fn with_boot_check(mut self, boot_check: Option<BootCheck>) -> Self {
+        if boot_check.is_some() {
+            self.boot_check = boot_check;
+        }
+        self
+    }
+
+    /// Returns whether there's nothing to check, i.e. the finished image
+    /// needn't be booted.
+    pub fn is_empty(&self) -> bool {
+        self.tests.is_empty()
+            && self.boot_check.is_none()
+            && self.final_commands.is_empty()
+    }
+
+    /// Returns whether any test or final command runs through WinRM.
+    pub fn uses_winrm(&self) -> bool {
+        let final_winrm = !self.final_commands.is_empty()
+            && self.final_commands_via == Transport::Winrm;
+        final_winrm
+            || self.tests.iter().any(|test| {
+                matches!(
+                    test.check,
+                    TestCheck::PowerShell { via: Transport::Winrm, .. }
+                )
+            })
+    }
+
+    /// Returns whether the checks talk to the QEMU guest agent, which the
+    /// guest setup script then has to install.
+    pub fn needs_agent(&self) -> bool {
+        !self.tests.is_empty()
+            || self.boot_check == Some(BootCheck::Agent)
+            || (!self.final_commands.is_empty()
+                && self.final_commands_via == Transport::Agent)
+    }
+
+    /// Returns the guest TCP ports the checks connect to.
+    pub fn tcp_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .tests
+            .iter()
+            .filter_map(|test| match test.check {
+                TestCheck::TcpPort(port) => Some(port),
+                TestCheck::PowerShell { .. } => None,
+            })
+            .collect();
+        if let Some(BootCheck::TcpPort(port)) = self.boot_check {
+            ports.push(port);
+        }
+        if self.uses_winrm() {
+            ports.push(self.winrm.port);
+        }
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+}
+
+/// Settings for the disk Windows is installed to.
+#[derive(Clone, Debug, Default)]
+pub struct DiskConfig {
+    /// The size of the output image. `--disk-size` takes precedence.
+    pub size: Option<DiskSize>,
+
+    /// Whether to zero the OS partition's free space at the end of setup, as
+    /// `--zero-free-space` does.
+    pub zero_free_space: bool,
+
+    /// Whether to make the output image as small as possible, as
+    /// `--minimal-image` does.
+    pub minimal_image: bool,
+
+    /// The free space to leave on the OS partition when shrinking it.
+    /// `--shrink-headroom` takes precedence.
+    pub shrink_headroom: Option<DiskSize>,
+
+    /// Whether deployed images grow their OS partition on first boot, as
+    /// `--expand-on-first-boot` has them do.
+    pub expand_on_first_boot: bool,
+}
+
+impl DiskConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let size = Self::read_size(fields, "size")?;
+        let zero_free_space =
+            fields.boolean("zero_free_space")?.unwrap_or(false);
+        let minimal_image = fields.boolean("minimal_image")?.unwrap_or(false);
+        let shrink_headroom = Self::read_size(fields, "shrink_headroom")?;
+        let expand_on_first_boot =
+            fields.boolean("expand_on_first_boot")?.unwrap_or(false);
+        Ok(Self {
+            size,
+            zero_free_space,
+            minimal_image,
+            shrink_headroom,
+            expand_on_first_boot,
+        })
+    }
+
+    fn read_size(
+        fields: &mut Fields<'_>,
+        key: &str,
+    ) -> Result<Option<DiskSize>> {
+        match fields.string(key)? {
+            Some(size) => Ok(Some(size.parse().map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name(key))
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Settings for the installation VM. The corresponding command-line options
+/// take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct VmConfig {
+    pub cpus: Option<u32>,
+    pub memory_mib: Option<u64>,
+    pub machine: Option<MachineType>,
+    pub disk_controller: Option<DiskController>,
+    pub nic_model: Option<NicModel>,
+}
+
+impl VmConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let cpus = fields.unsigned("cpus")?;
+        let memory_mib = fields.unsigned("memory_mib")?;
+        let machine = match fields.string("machine")? {
+            Some(machine) => Some(
+                clap::ValueEnum::from_str(&machine, true).map_err(|_| {
+                    anyhow::anyhow!(
+                        "'{}' should be \"pc\", \"q35\", or \"virt\", \
+                        not \"{machine}\"",
+                        fields.name("machine")
+                    )
+                })?,
+            ),
+            None => None,
+        };
+        let disk_controller = match fields.string("disk_controller")? {
+            Some(controller) => Some(
+                clap::ValueEnum::from_str(&controller, true).map_err(|_| {
+                    anyhow::anyhow!(
+                        "'{}' should be \"nvme\", \"virtio-blk\", \
+                        \"virtio-scsi\", or \"ahci\", not \"{controller}\"",
+                        fields.name("disk_controller")
+                    )
+                })?,
+            ),
+            None => None,
+        };
+        let nic_model = match fields.string("nic_model")? {
+            Some(model) => {
+                Some(clap::ValueEnum::from_str(&model, true).map_err(|_| {
+                    anyhow::anyhow!(
+                        "'{}' should be \"e1000e\", \"virtio-net\", or \
+                        \"none\", not \"{model}\"",
+                        fields.name("nic_model")
+                    )
+                })?)
+            }
+            None => None,
+        };
+
+        Ok(Self { cpus, memory_mib, machine, disk_controller, nic_model })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_none()
+            && self.memory_mib.is_none()
+            && self.machine.is_none()
+            && self.disk_controller.is_none()
+            && self.nic_model.is_none()
+    }
+}
+
+/// Reads the string at `key` as one of the values of the option type `T`,
+/// which `expected` lists for the error message.
+fn read_value_enum<T: clap::ValueEnum>(
+    fields: &mut Fields<'_>,
+    key: &str,
+    expected: &str,
+) -> Result<Option<T>> {
+    match fields.string(key)? {
+        Some(value) => Ok(Some(T::from_str(&value, true).map_err(|_| {
+            anyhow::anyhow!(
+                "'{}' should be {expected}, not \"{value}\"",
+                fields.name(key)
+            )
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Where the installation media and drivers come from. The corresponding
+/// command-line options take precedence; setting any of the Windows ISO
+/// options (or any of the driver options) on the command line replaces all
+/// of them.
+#[derive(Clone, Debug, Default)]
+pub struct MediaConfig {
+    pub windows_iso: Option<Utf8PathBuf>,
+    pub iso_url: Option<String>,
+    pub iso_sha256: Option<String>,
+    pub windows_version: Option<WindowsVersion>,
+    pub edition: Option<Edition>,
+    pub virtio_iso: Option<Utf8PathBuf>,
+    pub virtio_driver_dir: Option<Utf8PathBuf>,
+    pub virtio_iso_manifest: Option<Utf8PathBuf>,
+}
+
+impl MediaConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let windows_iso =
+            fields.string("windows_iso")?.map(|s| base_dir.join(s));
+        let iso_url = fields.string("iso_url")?;
+        let iso_sha256 = fields.string("iso_sha256")?;
+        if windows_iso.is_some() && iso_url.is_some() {
+            anyhow::bail!(
+                "'{}' and '{}' can't both be set",
+                fields.name("windows_iso"),
+                fields.name("iso_url")
+            );
+        }
+        if iso_url.is_some() != iso_sha256.is_some() {
+            anyhow::bail!(
+                "'{}' and '{}' must be set together",
+                fields.name("iso_url"),
+                fields.name("iso_sha256")
+            );
+        }
+
+        let windows_version = read_value_enum(
+            fields,
+            "windows_version",
+            "\"server2016\", \"server2019\", \"server2022\", \
+            \"server2025\", \"windows10\", or \"windows11\"",
+        )?;
+        let edition = match fields.string("edition")? {
+            Some(edition) => Some(edition.parse().map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name("edition"))
+            })?),
+            None => None,
+        };
+
+        let virtio_iso = fields.string("virtio_iso")?.map(|s| base_dir.join(s));
+        let virtio_driver_dir =
+            fields.string("virtio_driver_dir")?.map(|s| base_dir.join(s));
+        let virtio_iso_manifest =
+            fields.string("virtio_iso_manifest")?.map(|s| base_dir.join(s));
+        let driver_sources = [
+            virtio_iso.is_some(),
+            virtio_driver_dir.is_some(),
+            virtio_iso_manifest.is_some(),
+        ];
+        if driver_sources.iter().filter(|set| **set).count() > 1 {
+            anyhow::bail!(
+                "only one of '{}', '{}', and '{}' can be set",
+                fields.name("virtio_iso"),
+                fields.name("virtio_driver_dir"),
+                fields.name("virtio_iso_manifest")
+            );
+        }
+
+        Ok(Self {
+            windows_iso,
+            iso_url,
+            iso_sha256,
+            windows_version,
+            edition,
+            virtio_iso,
+            virtio_driver_dir,
+            virtio_iso_manifest,
+        })
+    }
+}
+
+/// The images a build produces. The corresponding command-line options take
+/// precedence.
+#[derive(Clone, Debug, Default)]
+pub struct OutputConfig {
+    /// The output image's format, as `--output-format` gives it. Linux only.
+    #[cfg(target_os = "linux")]
+    pub format: Option<OutputFormat>,
+
+    /// The output image, as `--output-image` names it.
+    pub image: Option<Utf8PathBuf>,
+    pub qcow2_image: Option<Utf8PathBuf>,
+    pub vhdx_image: Option<Utf8PathBuf>,
+    pub vmdk_image: Option<Utf8PathBuf>,
+    pub compress: Option<Compression>,
+
+    /// The key to sign the image manifest with, as `--sign-key` names it.
+    pub sign_key: Option<Utf8PathBuf>,
+}
+
+impl OutputConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        let format = read_value_enum(fields, "format", "\"raw\" or \"qcow2\"")?;
+        let image = fields.string("image")?.map(|s| base_dir.join(s));
+        let qcow2_image =
+            fields.string("qcow2_image")?.map(|s| base_dir.join(s));
+        let vhdx_image = fields.string("vhdx_image")?.map(|s| base_dir.join(s));
+        let vmdk_image = fields.string("vmdk_image")?.map(|s| base_dir.join(s));
+        let compress = read_value_enum(
+            fields,
+            "compress",
+            "\"none\", \"gzip\", \"zstd\", or \"xz\"",
+        )?;
+        let sign_key = fields.string("sign_key")?.map(|s| base_dir.join(s));
+
+        Ok(Self {
+            #[cfg(target_os = "linux")]
+            format,
+            image,
+            qcow2_image,
+            vhdx_image,
+            vmdk_image,
+            compress,
+            sign_key,
+        })
+    }
+}
+
+/// Settings for the unattend files injected into the image.
+#[derive(Clone, Debug, Default)]
+pub struct UnattendConfig {
+    /// A directory of unattend files that override those in the unattend
+    /// directory. `--unattend-overrides` takes precedence.
+    pub overrides: Option<Utf8PathBuf>,
+}
+
+impl UnattendConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let overrides = fields.string("overrides")?.map(|s| base_dir.join(s));
+        Ok(Self { overrides })
+    }
+}
+
+/// Scripts to run in the guest once Windows is installed, and Windows
+/// features to change before they run.
+#[derive(Clone, Debug, Default)]
+pub struct ProvisionConfig {
+    /// PowerShell scripts to run, in order. They run before any passed to
+    /// `--provision-script`.
+    pub scripts: Vec<Utf8PathBuf>,
+
+    /// Windows features to add, in addition to any passed to
+    /// `--windows-feature`.
+    pub windows_features: Vec<WindowsFeature>,
+
+    /// Windows features to remove, in addition to any passed to
+    /// `--remove-feature`.
+    pub remove_features: Vec<WindowsFeature>,
+}
+
+impl ProvisionConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let scripts = fields
+            .string_array("scripts")?
+            .into_iter()
+            .map(|s| base_dir.join(s))
+            .collect();
+        let mut features = |key: &str| -> Result<Vec<WindowsFeature>> {
+            fields
+                .string_array(key)?
+                .iter()
+                .map(|name| {
+                    name.parse().map_err(|e| {
+                        anyhow::anyhow!(
+                            "'{}' is invalid: {e}",
+                            fields.name(key)
+                        )
+                    })
+                })
+                .collect()
+        };
+        Ok(Self {
+            scripts,
+            windows_features: features("windows_features")?,
+            remove_features: features("remove_features")?,
+        })
+    }
+}
+
+/// How cloudbase-init is installed and configured.
+#[derive(Clone, Debug, Default)]
+pub struct CloudbaseInitConfig {
+    /// Leaves cloudbase-init out of the image.
+    pub skip: bool,
+
+    /// A cloudbase-init installer to use instead of downloading one.
+    pub msi: Option<Utf8PathBuf>,
+
+    /// Where the guest downloads the cloudbase-init installer from.
+    pub url: Option<String>,
+
+    /// Settings to apply to the cloudbase-init configuration files, before
+    /// any passed to `--cloudbase-init-setting`.
+    pub settings: Vec<Setting>,
+}
+
+impl CloudbaseInitConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let skip = fields.boolean("skip")?.unwrap_or(false);
+        let msi = fields.string("msi")?.map(|s| base_dir.join(s));
+        let url = fields.string("url")?;
+        if msi.is_some() && url.is_some() {
+            anyhow::bail!(
+                "'{}' and '{}' can't both be set",
+                fields.name("msi"),
+                fields.name("url")
+            );
+        }
+
+        let settings = match fields.table("settings")? {
+            Some(mut settings) => {
+                let entries = settings
+                    .entries()?
+                    .into_iter()
+                    .map(|(name, value)| {
+                        Setting::new(&name, &value).map_err(|e| {
+                            anyhow::anyhow!("in [cloudbase-init.settings]: {e}")
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                settings.finish()?;
+                entries
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Self { skip, msi, url, settings })
+    }
+}
+
+/// How built images are activated. The corresponding command-line options
+/// take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct ActivationConfig {
+    pub product_key: Option<ProductKey>,
+    pub kms_host: Option<KmsHost>,
+}
+
+impl ActivationConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let product_key = match fields.string("product_key")? {
+            Some(key) => Some(key.parse().map_err(|e| {
+                anyhow::anyhow!(
+                    "'{}' is invalid: {e}",
+                    fields.name("product_key")
+                )
+            })?),
+            None => None,
+        };
+
+        let kms_host = match fields.string("kms_host")? {
+            Some(host) => Some(host.parse().map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name("kms_host"))
+            })?),
+            None => None,
+        };
+
+        Ok(Self { product_key, kms_host })
+    }
+}
+
+/// The static network configuration deployed images get. The corresponding
+/// command-line options take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConfig {
+    pub address: Option<Ipv4Interface>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<IpAddr>,
+    pub interface: Option<String>,
+}
+
+impl NetworkConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let address = match fields.string("address")? {
+            Some(address) => Some(address.parse().map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name("address"))
+            })?),
+            None => None,
+        };
+        let gateway = match fields.string("gateway")? {
+            Some(gateway) => Some(gateway.parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "'{}' isn't an IPv4 address",
+                    fields.name("gateway")
+                )
+            })?),
+            None => None,
+        };
+        if gateway.is_some() && address.is_none() {
+            anyhow::bail!(
+                "'{}' needs '{}' to be set",
+                fields.name("gateway"),
+                fields.name("address")
+            );
+        }
+
+        let dns_servers = fields
+            .string_array("dns_servers")?
+            .into_iter()
+            .map(|server| {
+                server.parse().map_err(|_| {
+                    anyhow::anyhow!(
+                        "'{}' has '{server}', which isn't an IP address",
+                        fields.name("dns_servers")
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        let interface = fields.string("interface")?;
+        Ok(Self { address, gateway, dns_servers, interface })
+    }
+}
+
+/// Whether built images accept Remote Desktop connections. `--skip-rdp`
+/// takes precedence.
+#[derive(Clone, Debug)]
+pub struct RdpConfig {
+    pub enable: bool,
+}
+
+impl Default for RdpConfig {
+    fn default() -> Self {
+        Self { enable: true }
+    }
+}
+
+impl RdpConfig {
+    fn read(fields: &mut Fields<'_>) -> Result<Self> {
+        let enable = fields.boolean("enable")?.unwrap_or(true);
+        Ok(Self { enable })
+    }
+}
+
+/// Windows update packages to slipstream into the installation media, added
+/// before those passed on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct UpdatesConfig {
+    pub packages: Vec<Utf8PathBuf>,
+}
+
+impl UpdatesConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let packages = fields
+            .string_array("packages")?
+            .into_iter()
+            .map(|path| base_dir.join(path))
+            .collect();
+        Ok(Self { packages })
+    }
+}
+
+/// Certificates to add to built images' certificate stores, in addition to
+/// those passed on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct CertificatesConfig {
+    pub trusted: Vec<Utf8PathBuf>,
+    pub intermediate: Vec<Utf8PathBuf>,
+}
+
+impl CertificatesConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let mut paths = |key: &str| -> Result<Vec<Utf8PathBuf>> {
+            Ok(fields
+                .string_array(key)?
+                .into_iter()
+                .map(|path| base_dir.join(path))
+                .collect())
+        };
+        Ok(Self {
+            trusted: paths("trusted")?,
+            intermediate: paths("intermediate")?,
+        })
+    }
+}
+
+/// Whether built images get the OpenSSH server, and which keys can log on
+/// with it. The corresponding command-line options take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    pub enable: bool,
+    pub authorized_keys: Option<Utf8PathBuf>,
+}
+
+impl SshConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let enable = fields.boolean("enable")?.unwrap_or(false);
+        let authorized_keys =
+            fields.string("authorized_keys")?.map(|s| base_dir.join(s));
+        Ok(Self { enable, authorized_keys })
+    }
+}
+
+/// How built images join an Active Directory domain. The command-line
+/// options replace the whole table if they join a domain themselves.
+#[derive(Clone, Debug, Default)]
+pub struct DomainJoinConfig {
+    pub domain: Option<String>,
+    pub ou: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<SecretSource>,
+    pub unsecure: bool,
+    pub machine_password: Option<SecretSource>,
+    pub djoin_blob: Option<Utf8PathBuf>,
+}
+
+impl DomainJoinConfig {
+    fn read(fields: &mut Fields<'_>, base_dir: &Utf8Path) -> Result<Self> {
+        let mut secret = |key: &str| -> Result<Option<SecretSource>> {
+            match fields.string(key)? {
+                Some(source) => {
+                    Ok(Some(source.parse().with_context(|| {
+                        format!("'{}' is invalid", fields.name(key))
+                    })?))
+                }
+                None => Ok(None),
+            }
+        };
+        let password = secret("password")?;
+        let machine_password = secret("machine_password")?;
+        let config = Self {
+            domain: fields.string("domain")?,
+            ou: fields.string("ou")?,
+            user: fields.string("user")?,
+            password,
+            unsecure: fields.boolean("unsecure")?.unwrap_or(false),
+            machine_password,
+            djoin_blob: fields.string("djoin_blob")?.map(|s| base_dir.join(s)),
+        };
+
+        let (domain, ou, user, unsecure, blob) = (
+            config.domain.is_some(),
+            config.ou.is_some(),
+            config.user.is_some(),
+            config.unsecure,
+            config.djoin_blob.is_some(),
+        );
+        let (password, machine) =
+            (config.password.is_some(), config.machine_password.is_some());
+        for (key, is_set, needs, needed) in [
+            ("ou", ou, "domain", domain),
+            ("user", user, "domain", domain),
+            ("user", user, "password", password),
+            ("password", password, "user", user),
+            ("unsecure", unsecure, "domain", domain),
+            ("unsecure", unsecure, "machine_password", machine),
+            ("machine_password", machine, "unsecure", unsecure),
+        ] {
+            if is_set && !needed {
+                anyhow::bail!(
+                    "'{}' needs '{}' to be set",
+                    fields.name(key),
+                    fields.name(needs)
+                );
+            }
+        }
+        for (first, first_set, second, second_set) in [
+            ("djoin_blob", blob, "user", user),
+            ("djoin_blob", blob, "ou", ou),
+            ("djoin_blob", blob, "unsecure", unsecure),
+            ("unsecure", unsecure, "user", user),
+            ("unsecure", unsecure, "ou", ou),
+        ] {
+            if first_set && second_set {
+                anyhow::bail!(
+                    "'{}' and '{}' can't both be set",
+                    fields.name(first),
+                    fields.name(second)
+                );
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Reads a `[[registry]]` table, whose `data` is a string, integer, or array
+/// of strings according to its `type`.
+fn read_registry_setting(fields: &mut Fields<'_>) -> Result<RegistrySetting> {
+    let path = fields.required_string("path")?;
+    let name = fields.string("name")?;
+    let kind = fields.required_string("type")?;
+    let required = |fields: &Fields<'_>| {
+        anyhow::anyhow!("'{}' is required", fields.name("data"))
+    };
+    let data = match kind.to_ascii_uppercase().as_str() {
+        "REG_SZ" => Data::String(fields.required_string("data")?),
+        "REG_EXPAND_SZ" => Data::ExpandString(fields.required_string("data")?),
+        "REG_MULTI_SZ" => Data::MultiString(fields.string_array("data")?),
+        "REG_DWORD" => Data::Dword(
+            fields.unsigned("data")?.ok_or_else(|| required(fields))?,
+        ),
+        "REG_QWORD" => Data::Qword(
+            fields.unsigned("data")?.ok_or_else(|| required(fields))?,
+        ),
+        "REG_BINARY" => Data::Binary(
+            crate::registry_settings::parse_binary(
+                &fields.required_string("data")?,
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", fields.name("data"))
+            })?,
+        ),
+        _ => anyhow::bail!(
+            "'{}' should be one of {}, but is '{kind}'",
+            fields.name("type"),
+            crate::registry_settings::TYPES.join(", ")
+        ),
+    };
+
+    RegistrySetting::new(&path, name, data).map_err(|e| {
+        anyhow::anyhow!("'{}' is invalid: {e}", fields.name("path"))
+    })
+}
+
+/// The contents of a wimsy configuration file.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Changes to the selected script's steps.
+    pub steps: StepOverrides,
+
+    /// Commands to run around each step.
+    pub hooks: StepHooks,
+
+    /// How to retry steps that fail.
+    pub retry: RetryPolicy,
+
+    /// Functional tests to run against the finished image.
+    pub tests: ImageTests,
+
+    /// Settings for the output image's disk.
+    pub disk: DiskConfig,
+
+    /// Settings for the installation VM.
+    pub vm: VmConfig,
+
+    /// Settings for the unattend files.
+    pub unattend: UnattendConfig,
+
+    /// Where the installation media and drivers come from.
+    pub media: MediaConfig,
+
+    /// Updates to slipstream into the installation media.
+    pub updates: UpdatesConfig,
+
+    /// The images to produce.
+    pub output: OutputConfig,
+
+    /// How built images are activated.
+    pub activation: ActivationConfig,
+
+    /// The static network configuration deployed images get.
+    pub network: NetworkConfig,
+
+    /// Whether built images accept Remote Desktop connections.
+    pub rdp: RdpConfig,
+
+    /// Whether built images get the OpenSSH server.
+    pub ssh: SshConfig,
+
+    /// How built images join an Active Directory domain.
+    pub domain_join: DomainJoinConfig,
+
+    /// Scripts to run in the guest.
+    pub provision: ProvisionConfig,
+
+    /// Host files to copy into the image.
+    pub files: Vec<FileCopy>,
+
+    /// Certificates to add to the image's certificate stores.
+    pub certificates: CertificatesConfig,
+
+    /// Local user accounts to create in the image.
+    pub users: Vec<LocalUser>,
+
+    /// Registry values to set in the image.
+    pub registry: Vec<RegistrySetting>,
+
+    /// How cloudbase-init is installed and configured.
+    pub cloudbase_init: CloudbaseInitConfig,
+
+    /// Variables for unattend file templates. `--template-var` takes
+    /// precedence.
+    pub template_vars: Vec<UserVar>,
+
+    /// The names of the targets the file defines, in order.
+    pub targets: Vec<String>,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`. Relative paths in the file are
+    /// resolved relative to the directory containing it. If `target` is set,
+    /// the settings in the file's `[[targets]]` entry of that name are laid
+    /// over the rest of the file's.
+    pub fn load(path: &Utf8Path, target: Option<&str>) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading configuration file '{path}'"))?;
+        let base_dir = path.parent().unwrap_or(Utf8Path::new("."));
+        Self::from_str_for_target(&contents, base_dir, target)
+            .with_context(|| format!("parsing configuration file '{path}'"))
+    }
+
+    #[cfg(test)]
+    fn from_str(contents: &str, base_dir: &Utf8Path) -> Result<Self> {
+        Self::from_str_for_target(contents, base_dir, None)
+    }
+
+    fn from_str_for_target(
+        contents: &str,
+        base_dir: &Utf8Path,
+        target: Option<&str>,
+    ) -> Result<Self> {
+        let mut table = parse(contents)?;
+        let targets = take_targets(&mut table)?;
+        if let Some(name) = target {
+            let Some((_, overlay)) = targets.iter().find(|(n, _)| n == name)
+            else {
+                let names: Vec<_> =
+                    targets.iter().map(|(name, _)| name.as_str()).collect();
+                anyhow::bail!(
+                    "there's no target named '{name}' (the file defines {})",
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                );
+            };
+            overlay_table(&mut table, overlay.clone());
+        }
+
+        let mut config = match target {
+            Some(name) => Self::from_table(&table, base_dir)
+                .with_context(|| format!("in target '{name}'"))?,
+            None => Self::from_table(&table, base_dir)?,
+        };
+        config.targets = targets.into_iter().map(|(name, _)| name).collect();
+        Ok(config)
+    }
+
+    fn from_table(table: &Table, base_dir: &Utf8Path) -> Result<Self> {
+        let mut fields = Fields::new(table, "");
+        let steps = match fields.table("steps")? {
+            Some(mut steps) => {
+                let overrides = StepOverrides::read(&mut steps, base_dir)?;
+                steps.finish()?;
+                overrides
+            }
+            None => StepOverrides::default(),
+        };
+
+        let hooks = match fields.table("hooks")? {
+            Some(mut hooks) => {
+                let config = StepHooks::read(&mut hooks)?;
+                hooks.finish()?;
+                config
+            }
+            None => StepHooks::default(),
+        };
+
+        let retry = match fields.table("retry")? {
+            Some(mut retry) => {
+                let policy = RetryPolicy::read(&mut retry)?;
+                retry.finish()?;
+                policy
+            }
+            None => RetryPolicy::default(),
+        };
+
+        let tests = match fields.table("tests")? {
+            Some(mut tests) => {
+                let image_tests = ImageTests::read(&mut tests, base_dir)?;
+                tests.finish()?;
+                image_tests
+            }
+            None => ImageTests::default(),
+        };
+
+        let disk = match fields.table("disk")? {
+            Some(mut disk) => {
+                let config = DiskConfig::read(&mut disk)?;
+                disk.finish()?;
+                config
+            }
+            None => DiskConfig::default(),
+        };
+
+        let vm = match fields.table("vm")? {
+            Some(mut vm) => {
+                let config = VmConfig::read(&mut vm)?;
+                vm.finish()?;
+                config
+            }
+            None => VmConfig::default(),
+        };
+
+        let unattend = match fields.table("unattend")? {
+            Some(mut unattend) => {
+                let config = UnattendConfig::read(&mut unattend, base_dir)?;
+                unattend.finish()?;
+                config
+            }
+            None => UnattendConfig::default(),
+        };
+
+        let media = match fields.table("media")? {
+            Some(mut media) => {
+                let config = MediaConfig::read(&mut media, base_dir)?;
+                media.finish()?;
+                config
+            }
+            None => MediaConfig::default(),
+        };
+
+        let updates = match fields.table("updates")? {
+            Some(mut updates) => {
+                let config = UpdatesConfig::read(&mut updates, base_dir)?;
+                updates.finish()?;
+                config
+            }
+            None => UpdatesConfig::default(),
+        };
+
+        let output = match fields.table("output")? {
+            Some(mut output) => {
+                let config = OutputConfig::read(&mut output, base_dir)?;
+                output.finish()?;
+                config
+            }
+            None => OutputConfig::default(),
+        };
+
+        let activation = match fields.table("activation")? {
+            Some(mut activation) => {
+                let config = ActivationConfig::read(&mut activation)?;
+                activation.finish()?;
+                config
+            }
+            None => ActivationConfig::default(),
+        };
+
+        let network = match fields.table("network")? {
+            Some(mut network) => {
+                let config = NetworkConfig::read(&mut network)?;
+                network.finish()?;
+                config
+            }
+            None => NetworkConfig::default(),
+        };
+
+        let rdp = match fields.table("rdp")? {
+            Some(mut rdp) => {
+                let config = RdpConfig::read(&mut rdp)?;
+                rdp.finish()?;
+                config
+            }
+            None => RdpConfig::default(),
+        };
+
+        let ssh = match fields.table("ssh")? {
+            Some(mut ssh) => {
+                let config = SshConfig::read(&mut ssh, base_dir)?;
+                ssh.finish()?;
+                config
+            }
+            None => SshConfig::default(),
+        };
+
+        let domain_join = match fields.table("domain_join")? {
+            Some(mut domain_join) => {
+                let config =
+                    DomainJoinConfig::read(&mut domain_join, base_dir)?;
+                domain_join.finish()?;
+                config
+            }
+            None => DomainJoinConfig::default(),
+        };
+
+        let provision = match fields.table("provision")? {
+            Some(mut provision) => {
+                let config = ProvisionConfig::read(&mut provision, base_dir)?;
+                provision.finish()?;
+                config
+            }
+            None => ProvisionConfig::default(),
+        };
+
+        let cloudbase_init = match fields.table("cloudbase-init")? {
+            Some(mut cloudbase_init) => {
+                let config =
+                    CloudbaseInitConfig::read(&mut cloudbase_init, base_dir)?;
+                cloudbase_init.finish()?;
+                config
+            }
+            None => CloudbaseInitConfig::default(),
+        };
+
+        let mut files = Vec::new();
+        for mut file in fields.tables("files")? {
+            let source = base_dir.join(file.required_string("source")?);
+            let dest = file.required_string("dest")?;
+            files.push(FileCopy::new(source, &dest).map_err(|e| {
+                anyhow::anyhow!("'{}' is invalid: {e}", file.name("dest"))
+            })?);
+            file.finish()?;
+        }
+
+        let certificates = match fields.table("certificates")? {
+            Some(mut certificates) => {
+                let config =
+                    CertificatesConfig::read(&mut certificates, base_dir)?;
+                certificates.finish()?;
+                config
+            }
+            None => CertificatesConfig::default(),
+        };
+
+        let mut users = Vec::new();
+        for mut user in fields.tables("users")? {
+            let name = user.required_string("name")?;
+            let password =
+                user.required_string("password")?.parse().with_context(
+                    || format!("'{}' is invalid", user.name("password")),
+                )?;
+            let groups = user.string_array("groups")?;
+            users.push(LocalUser::new(&name, password, groups).map_err(
+                |e| anyhow::anyhow!("'{}' is invalid: {e}", user.name("name")),
+            )?);
+            user.finish()?;
+        }
+
+        let mut registry = Vec::new();
+        for mut value in fields.tables("registry")? {
+            registry.push(read_registry_setting(&mut value)?);
+            value.finish()?;
+        }
+
+        let template_vars = match fields.table("template-vars")? {
+            Some(mut vars) => vars
+                .entries()?
+                .into_iter()
+                .map(|(name, value)| {
+                    UserVar::new(&name, &value)
+                        .map_err(|e| anyhow::anyhow!("in [template-vars]: {e}"))
+                })
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+
+        fields.finish()?;
+        Ok(Self {
+            steps,
+            hooks,
+            retry,
+            tests,
+            disk,
+            vm,
+            unattend,
+            media,
+            updates,
+            output,
+            activation,
+            network,
+            rdp,
+            ssh,
+            domain_join,
+            provision,
+            files,
+            certificates,
+            users,
+            registry,
+            cloudbase_init,
+            template_vars,
+            targets: Vec::new(),
+        })
+    }
+}
+
+/// Removes the `[[targets]]` array from `table`, returning each target's
+/// name and the settings it lays over the rest of the file's.
+fn take_targets(table: &mut Table) -> Result<Vec<(String, Table)>> {
+    let entries = match table.remove("targets") {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(entries)) => entries,
+        Some(other) => anyhow::bail!(
+            "'targets' should be an array of tables, not {}",
+            other.type_name()
+        ),
+    };
+
+    let mut targets: Vec<(String, Table)> = Vec::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let Value::Table(mut settings) = entry else {
+            anyhow::bail!(
+                "'targets[{i}]' should be a table, not {}",
+                entry.type_name()
+            );
+        };
+
+        let name = match settings.remove("name") {
+            Some(Value::String(name)) => name,
+            Some(other) => anyhow::bail!(
+                "'targets[{i}].name' should be a string, not {}",
+                other.type_name()
+            ),
+            None => anyhow::bail!("'targets[{i}]' has no name"),
+        };
+        // Names become the names of the targets' work directories.
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            anyhow::bail!(
+                "target name '{name}' should consist of letters, digits, \
+                '-', '_', and '.', and not start with '.'"
+            );
+        }
+        if targets.iter().any(|(other, _)| *other == name) {
+            anyhow::bail!("there's more than one target named '{name}'");
+        }
+
+        targets.push((name, settings));
+    }
+
+    Ok(targets)
+}
+
+/// Lays `overlay` over `base`: tables in both are merged, and any other
+/// setting in `overlay` replaces `base`'s.
+fn overlay_table(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => {
+                overlay_table(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_toml_subset() {
+        let table = parse(
+            r#"
+# comment
+title = "wimsy" # trailing comment
+count = 1_000
+enabled = true
+paths = [
+    'C:\Windows',
+    "line\nbreak",
+]
+inline = { a = 1, b.c = "x" }
+
+[a.b]
+c = -3
+
+[[runs]]
+name = """
+first"""
+
+[[runs]]
+name = "second"
+sub.key = 'v'
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(table["title"], Value::String("wimsy".into()));
+        assert_eq!(table["count"], Value::Integer(1000));
+        assert_eq!(table["enabled"], Value::Boolean(true));
+        assert_eq!(
+            table["paths"],
+            Value::Array(vec![
+                Value::String("C:\\Windows".into()),
+                Value::String("line\nbreak".into()),
+            ])
+        );
+
+        let Value::Table(inline) = &table["inline"] else { panic!() };
+        assert_eq!(inline["a"], Value::Integer(1));
+
+        let Value::Table(a) = &table["a"] else { panic!() };
+        let Value::Table(b) = &a["b"] else { panic!() };
+        assert_eq!(b["c"], Value::Integer(-3));
+
+        let Value::Array(runs) = &table["runs"] else { panic!() };
+        assert_eq!(runs.len(), 2);
+        let Value::Table(first) = &runs[0] else { panic!() };
+        assert_eq!(first["name"], Value::String("first".into()));
+        let Value::Table(second) = &runs[1] else { panic!() };
+        assert!(matches!(&second["sub"], Value::Table(_)));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for (source, expected) in [
+            ("a = 1\na = 2", "line 2: key 'a' is defined more than once"),
+            ("[t]\n[t]", "line 2: table [t] is defined more than once"),
+            ("a = bare", "line 1: 'bare' is not a valid value"),
+            ("a = \"open", "line 1: unterminated string"),
+            ("a = 1 b = 2", "line 1: expected end of line"),
+        ] {
+            let err = parse(source).unwrap_err().to_string();
+            assert!(err.starts_with(expected), "{source:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn reads_step_overrides() {
+        let config = Config::from_str(
+            r#"
+[steps]
+disable = ["repair-secondary-gpt"]
+
+[[steps.insert]]
+name = "scan"
+after = "install-windows"
+command = "true"
+
+[[steps.replace]]
+step = "shrink-output-image"
+script = "shrink.sh"
+"#,
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+
+        assert_eq!(config.steps.disable, vec!["repair-secondary-gpt"]);
+        assert_eq!(
+            config.steps.insert[0].anchor,
+            Anchor::After("install-windows".into())
+        );
+        assert_eq!(
+            config.steps.replace[0].run.script.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/shrink.sh"))
+        );
+    }
+
+    #[test]
+    fn reads_hooks() {
+        let config = Config::from_str(
+            "[hooks]\npost_step = \"notify done\"\n\
+            on_failure = \"notify failed\"\nsteps = [\"install-windows\"]",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(config.hooks.post_step.as_deref(), Some("notify done"));
+        assert!(config.hooks.apply_to("install-windows"));
+        assert!(!config.hooks.apply_to("create-output-image"));
+
+        let config =
+            Config::from_str("[hooks]\npre_step = \"x\"", Utf8Path::new("."))
+                .unwrap();
+        assert!(config.hooks.apply_to("any"));
+        assert!(!Config::default().hooks.apply_to("any"));
+    }
+
+    #[test]
+    fn reads_retry_policy() {
+        let retry = Config::default().retry;
+        assert_eq!(retry.attempts_for("download-windows-iso", true), 3);
+        assert_eq!(retry.attempts_for("install-windows", false), 1);
+        assert_eq!(retry.backoff(1), Duration::from_secs(10));
+        assert_eq!(retry.backoff(3), Duration::from_secs(40));
+
+        let config = Config::from_str(
+            "[retry]\nattempts = 5\nbackoff_secs = 1\nsteps = [\"my-step\"]",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(config.retry.attempts_for("my-step", false), 5);
+        assert_eq!(config.retry.attempts_for("download-windows-iso", true), 1);
+
+        let err = Config::from_str("[retry]\nattempts = 0", Utf8Path::new("."))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("must be at least 1"), "{err}");
+    }
+
+    #[test]
+    fn reads_disk_size() {
+        let config =
+            Config::from_str("[disk]\nsize = \"48G\"", Utf8Path::new("."))
+                .unwrap();
+        assert_eq!(config.disk.size, Some(DiskSize(48 << 30)));
+        assert!(!config.disk.zero_free_space);
+
+        let config = Config::from_str(
+            "[disk]\nzero_free_space = true",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert!(config.disk.zero_free_space);
+
+        let config = Config::from_str(
+            "[disk]\nminimal_image = true\nshrink_headroom = \"512M\"\n\
+            expand_on_first_boot = true",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert!(config.disk.minimal_image);
+        assert_eq!(config.disk.shrink_headroom, Some(DiskSize(512 << 20)));
+        assert!(config.disk.expand_on_first_boot);
+
+        let err =
+            Config::from_str("[disk]\nsize = \"big\"", Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+        assert!(err.contains("'disk.size' is invalid"), "{err}");
+    }
+
+    #[test]
+    fn reads_vm_settings() {
+        let config = Config::from_str(
+            "[vm]\ncpus = 4\nmemory_mib = 8192\nmachine = \"q35\"\n\
+            disk_controller = \"virtio-scsi\"\nnic_model = \"e1000e\"",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(config.vm.cpus, Some(4));
+        assert_eq!(config.vm.memory_mib, Some(8192));
+        assert_eq!(config.vm.machine, Some(MachineType::Q35));
+        assert_eq!(config.vm.disk_controller, Some(DiskController::VirtioScsi));
+        assert_eq!(config.vm.nic_model, Some(NicModel::E1000e));
+
+        let err =
+            Config::from_str("[vm]\nmachine = \"isapc\"", Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+        assert!(err.contains("'vm.machine' should be"), "{err}");
+    }
+
+    #[test]
+    fn reads_media_and_output_settings() {
+        let config = Config::from_str(
+            "[media]\niso_url = \"https://example.com/server.iso\"\n\
+            iso_sha256 = \"00\"\nwindows_version = \"server2022\"\n\
+            edition = \"datacenter-core\"\nvirtio_iso = \"virtio.iso\"\n\
+            [output]\nqcow2_image = \"out.qcow2\"\ncompress = \"zstd\"\n\
+            sign_key = \"keys/wimsy.key\"",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.media.iso_url.as_deref(),
+            Some("https://example.com/server.iso")
+        );
+        assert_eq!(
+            config.media.windows_version,
+            Some(WindowsVersion::Server2022)
+        );
+        assert!(config.media.edition.is_some());
+        assert_eq!(
+            config.media.virtio_iso.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/virtio.iso"))
+        );
+        assert_eq!(
+            config.output.qcow2_image.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/out.qcow2"))
+        );
+        assert_eq!(config.output.compress, Some(Compression::Zstd));
+        assert_eq!(
+            config.output.sign_key.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/keys/wimsy.key"))
+        );
+
+        for (source, expected) in [
+            (
+                "[media]\nwindows_iso = \"a.iso\"\niso_url = \"u\"\n\
+                iso_sha256 = \"00\"",
+                "can't both be set",
+            ),
+            ("[media]\niso_url = \"u\"", "must be set together"),
+            (
+                "[media]\nvirtio_iso = \"a\"\nvirtio_driver_dir = \"b\"",
+                "only one of",
+            ),
+            ("[output]\ncompress = \"lz4\"", "'output.compress' should be"),
+        ] {
+            let err = Config::from_str(source, Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(expected), "{source:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn lays_targets_over_shared_settings() {
+        let source = r#"
+[media]
+virtio_iso = "virtio.iso"
+windows_version = "server2019"
+
+[disk]
+size = "40G"
+
+[[targets]]
+name = "ws2019"
+output.image = "ws2019.img"
+
+[[targets]]
+name = "ws2022"
+output.image = "ws2022.img"
+
+[targets.media]
+windows_version = "server2022"
+"#;
+        let base = Utf8Path::new("/etc/wimsy");
+        let config = Config::from_str(source, base).unwrap();
+        assert_eq!(config.targets, ["ws2019", "ws2022"]);
+        assert_eq!(config.output.image, None);
+
+        let ws2022 =
+            Config::from_str_for_target(source, base, Some("ws2022")).unwrap();
+        assert_eq!(
+            ws2022.media.windows_version,
+            Some(WindowsVersion::Server2022)
+        );
+        assert_eq!(
+            ws2022.media.virtio_iso.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/virtio.iso"))
+        );
+        assert_eq!(
+            ws2022.output.image.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/ws2022.img"))
+        );
+        assert_eq!(ws2022.disk.size, config.disk.size);
+
+        let err = Config::from_str_for_target(source, base, Some("ws2025"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("defines ws2019, ws2022"), "{err}");
+
+        for (source, expected) in [
+            ("[[targets]]\noutput.image = \"a\"", "has no name"),
+            ("[[targets]]\nname = \"../a\"", "should consist of"),
+            (
+                "[[targets]]\nname = \"a\"\n[[targets]]\nname = \"a\"",
+                "more than one",
+            ),
+        ] {
+            let err = Config::from_str(source, Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(expected), "{source:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn reads_unattend_settings() {
+        let config = Config::from_str(
+            "[unattend]\noverrides = \"site-unattend\"",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.unattend.overrides.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/site-unattend"))
+        );
+
+        assert!(Config::from_str(
+            "[unattend]\ndir = \"unattend\"",
+            Utf8Path::new(".")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reads_network_settings() {
+        let config = Config::from_str(
+            "[network]\naddress = \"10.0.0.5/24\"\ngateway = \"10.0.0.1\"\n\
+            dns_servers = [\"10.0.0.2\", \"10.0.0.3\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        let network = &config.network;
+        assert_eq!(network.address, Some("10.0.0.5/24".parse().unwrap()));
+        assert_eq!(network.gateway, Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(network.dns_servers.len(), 2);
+        assert_eq!(network.interface, None);
+
+        for (toml, error) in [
+            ("address = \"10.0.0.5\"", "prefix length"),
+            ("gateway = \"10.0.0.1\"", "needs 'network.address'"),
+            ("dns_servers = [\"dns.example.com\"]", "isn't an IP address"),
+        ] {
+            let e = Config::from_str(
+                &format!("[network]\n{toml}"),
+                Utf8Path::new("/etc/wimsy"),
+            )
+            .unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{toml}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn reads_certificate_and_update_paths() {
+        let config = Config::from_str(
+            "[certificates]\ntrusted = [\"certs/root.pem\"]\n\
+            intermediate = [\"/etc/pki/issuing.cer\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(config.certificates.trusted, ["/etc/wimsy/certs/root.pem"]);
+        assert_eq!(config.certificates.intermediate, ["/etc/pki/issuing.cer"]);
+
+        let config = Config::from_str(
+            "[updates]\npackages = [\"kb1.msu\", \"/srv/kb2.cab\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.updates.packages,
+            ["/etc/wimsy/kb1.msu", "/srv/kb2.cab"]
+        );
+    }
+
+    #[test]
+    fn reads_registry_settings() {
+        let base_dir = Utf8Path::new("/etc/wimsy");
+        let config = Config::from_str(
+            "[[registry]]\n\
+            path = 'HKLM\\SYSTEM\\CurrentControlSet\\Control\\FileSystem'\n\
+            name = \"LongPathsEnabled\"\ntype = \"REG_DWORD\"\ndata = 1\n\n\
+            [[registry]]\npath = 'HKLM\\SOFTWARE\\Wimsy'\n\
+            type = \"reg_multi_sz\"\ndata = [\"a\", \"b\"]",
+            base_dir,
+        )
+        .unwrap();
+        assert_eq!(config.registry.len(), 2);
+        assert_eq!(
+            config.registry[0].name.as_deref(),
+            Some("LongPathsEnabled")
+        );
+        assert_eq!(config.registry[0].data, Data::Dword(1));
+        assert_eq!(
+            config.registry[1].key,
+            "HKEY_LOCAL_MACHINE\\SOFTWARE\\Wimsy"
+        );
+        assert_eq!(config.registry[1].name, None);
+
+        for (toml, error) in [
+            (
+                "type = \"REG_DWORD\"\ndata = 1",
+                "'registry[0].path' is required",
+            ),
+            ("path = 'HKLM\\A'\ntype = \"REG_DWORD\"", "data' is required"),
+            (
+                "path = 'HKLM\\A'\ntype = \"REG_DWORD\"\ndata = \"1\"",
+                "should be an integer",
+            ),
+            ("path = 'HKLM\\A'\ntype = \"dword\"\ndata = 1", "one of REG_SZ"),
+            (
+                "path = 'HKCU\\A'\ntype = \"REG_SZ\"\ndata = \"x\"",
+                "isn't under HKLM",
+            ),
+        ] {
+            let e =
+                Config::from_str(&format!("[[registry]]\n{toml}"), base_dir)
+                    .unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{toml}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn reads_local_users() {
+        let base_dir = Utf8Path::new("/etc/wimsy");
+        let config = Config::from_str(
+            "[[users]]\nname = \"deploy\"\npassword = \"env:DEPLOY\"\n\
+            groups = [\"Administrators\"]\n\n\
+            [[users]]\nname = \"viewer\"\npassword = \"prompt\"",
+            base_dir,
+        )
+        .unwrap();
+        let names: Vec<_> =
+            config.users.iter().map(|user| user.name.as_str()).collect();
+        assert_eq!(names, ["deploy", "viewer"]);
+        assert_eq!(config.users[0].groups, ["Administrators"]);
+        assert_eq!(config.users[1].groups, [crate::users::DEFAULT_GROUP]);
+
+        for (toml, error) in [
+            ("name = \"deploy\"", "password"),
+            ("name = \"guest\"\npassword = \"prompt\"", "built-in Guest"),
+            ("name = \"deploy\"\npassword = \"deploy\"", "is invalid"),
+        ] {
+            let e = Config::from_str(&format!("[[users]]\n{toml}"), base_dir)
+                .unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{toml}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn reads_domain_join_settings() {
+        let base_dir = Utf8Path::new("/etc/wimsy");
+        let config = Config::from_str(
+            "[domain_join]\ndomain = \"corp.example.com\"\n\
+            user = \"CORP\\\\joiner\"\npassword = \"env:JOIN_PASSWORD\"",
+            base_dir,
+        )
+        .unwrap();
+        let join = &config.domain_join;
+        assert_eq!(join.domain.as_deref(), Some("corp.example.com"));
+        assert_eq!(join.user.as_deref(), Some("CORP\\joiner"));
+        assert_eq!(
+            join.password,
+            Some(SecretSource::Env("JOIN_PASSWORD".to_string()))
+        );
+
+        let config = Config::from_str(
+            "[domain_join]\ndjoin_blob = \"web01.djoin\"",
+            base_dir,
+        )
+        .unwrap();
+        assert_eq!(
+            config.domain_join.djoin_blob.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/web01.djoin"))
+        );
+
+        for (toml, error) in [
+            ("ou = \"OU=Servers\"", "needs 'domain_join.domain'"),
+            (
+                "domain = \"corp\"\nuser = \"joiner\"",
+                "needs 'domain_join.password'",
+            ),
+            (
+                "domain = \"corp\"\nunsecure = true",
+                "needs 'domain_join.machine_password'",
+            ),
+            ("domain = \"corp\"\npassword = \"bad\"", "is invalid"),
+            (
+                "djoin_blob = \"a.djoin\"\nunsecure = true\n\
+                domain = \"corp\"\nmachine_password = \"prompt\"",
+                "can't both be set",
+            ),
+        ] {
+            let e =
+                Config::from_str(&format!("[domain_join]\n{toml}"), base_dir)
+                    .unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{toml}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn reads_remote_access_settings() {
+        let base_dir = Utf8Path::new("/etc/wimsy");
+        assert!(Config::from_str("", base_dir).unwrap().rdp.enable);
+
+        let config = Config::from_str(
+            "[rdp]\nenable = false\n\n\
+            [ssh]\nenable = true\nauthorized_keys = \"keys.pub\"",
+            base_dir,
+        )
+        .unwrap();
+        assert!(!config.rdp.enable);
+        assert!(config.ssh.enable);
+        assert_eq!(
+            config.ssh.authorized_keys,
+            Some(Utf8PathBuf::from("/etc/wimsy/keys.pub"))
+        );
+    }
+
+    #[test]
+    fn reads_provision_settings() {
+        let config = Config::from_str(
+            "[provision]\nscripts = [\"tools.ps1\", \"/opt/agent.ps1\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.provision.scripts,
+            [
+                Utf8PathBuf::from("/etc/wimsy/tools.ps1"),
+                Utf8PathBuf::from("/opt/agent.ps1")
+            ]
+        );
+
+        let config = Config::from_str(
+            "[provision]\nwindows_features = [\"Web-Server\", \"NetFx3\"]\n\
+            remove_features = [\"XPS-Viewer\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        let names = |features: &[WindowsFeature]| -> Vec<String> {
+            features.iter().map(ToString::to_string).collect()
+        };
+        assert_eq!(
+            names(&config.provision.windows_features),
+            ["Web-Server", "NetFx3"]
+        );
+        assert_eq!(names(&config.provision.remove_features), ["XPS-Viewer"]);
+        assert!(Config::from_str(
+            "[provision]\nwindows_features = [\"Web Server\"]",
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reads_files() {
+        let config = Config::from_str(
+            r#"
+[[files]]
+source = "agent.msi"
+dest = 'C:\setup\'
+
+[[files]]
+source = "/opt/agent.conf"
+dest = 'C:\ProgramData\Agent\agent.conf'
+"#,
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.files.iter().map(FileCopy::target).collect::<Vec<_>>(),
+            ["C:\\setup\\agent.msi", "C:\\ProgramData\\Agent\\agent.conf"]
+        );
+        assert_eq!(
+            config.files[0].source,
+            Utf8PathBuf::from("/etc/wimsy/agent.msi")
+        );
+
+        let err = Config::from_str(
+            "[[files]]\nsource = \"a\"\ndest = \"setup\"",
+            Utf8Path::new("."),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("'files[0].dest' is invalid"), "{err}");
+    }
+
+    #[test]
+    fn reads_cloudbase_init_settings() {
+        let config = Config::from_str(
+            r#"
+[cloudbase-init]
+msi = "CloudbaseInitSetup_x64.msi"
+
+[cloudbase-init.settings]
+username = "admin"
+"config_drive.types" = "iso"
+"#,
+            Utf8Path::new("/etc/wimsy"),
+        )
+        .unwrap();
+        let cloudbase_init = &config.cloudbase_init;
+        assert!(!cloudbase_init.skip);
+        assert_eq!(
+            cloudbase_init.msi.as_deref(),
+            Some(Utf8Path::new("/etc/wimsy/CloudbaseInitSetup_x64.msi"))
+        );
+        assert_eq!(
+            cloudbase_init.settings,
+            [
+                Setting::new("config_drive.types", "iso").unwrap(),
+                Setting::new("username", "admin").unwrap()
+            ]
+        );
+
+        for bad in [
+            "[cloudbase-init]\nmsi = \"a.msi\"\nurl = \"https://x/a.msi\"",
+            "[cloudbase-init.settings]\n\"a b\" = \"c\"",
+        ] {
+            assert!(
+                Config::from_str(bad, Utf8Path::new(".")).is_err(),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_activation_settings() {
+        let config = Config::from_str(
+            "[activation]\nproduct_key = \"kms\"\nkms_host = \"kms:1689\"",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(config.activation.product_key, Some(ProductKey::Kms));
+        assert_eq!(
+            config.activation.kms_host,
+            Some(KmsHost { host: "kms".to_string(), port: Some(1689) })
+        );
+
+        assert!(Config::from_str(
+            "[activation]\nproduct_key = \"retail\"",
+            Utf8Path::new(".")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reads_template_vars() {
+        let config = Config::from_str(
+            "[template-vars]
+locale = \"de-DE\"
+timezone = \"UTC\"",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(
+            config.template_vars,
+            [
+                UserVar::new("locale", "de-DE").unwrap(),
+                UserVar::new("timezone", "UTC").unwrap()
+            ]
+        );
+
+        for bad in [
+            "[template-vars]
+count = 1",
+            "[template-vars]
+\"a-b\" = \"c\"",
+        ] {
+            assert!(
+                Config::from_str(bad, Utf8Path::new(".")).is_err(),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_image_tests() {
+        let config = Config::from_str(
+            r#"
+[tests]
+boot_timeout_secs = 600
+boot_check = "tcp:5985"
+
+[[tests.check]]
+name = "rdp"
+tcp_port = 3389
+
+[[tests.check]]
+name = "cloudbase-init"
+powershell = "(Get-Service cloudbase-init).StartType"
+expect_output = "Automatic"
+required = false
+timeout_secs = 30
+
+[[tests.check]]
+name = "generalized"
+generalized = true
+via = "winrm"
+
+[tests.winrm]
+username = "tester"
+password = "env:WINRM_PASSWORD"
+"#,
+            Utf8Path::new("."),
+        )
+        .unwrap();
+
+        let tests = &config.tests;
+        assert_eq!(tests.boot_timeout_secs, 600);
+        assert_eq!(tests.tests[0].check, TestCheck::TcpPort(3389));
+        assert!(tests.tests[0].required);
+        assert_eq!(tests.tests[0].timeout_secs, DEFAULT_TEST_TIMEOUT_SECS);
+        assert_eq!(
+            tests.tests[1].check,
+            TestCheck::PowerShell {
+                script: "(Get-Service cloudbase-init).StartType".into(),
+                expect_output: Some("Automatic".into()),
+                expect_exit: 0,
+                via: Transport::Agent,
+            }
+        );
+        assert!(!tests.tests[1].required);
+        let TestCheck::PowerShell { script, expect_output, via, .. } =
+            &tests.tests[2].check
+        else {
+            panic!("{:?}", tests.tests[2].check);
+        };
+        assert!(script.contains(r"HKLM:\SYSTEM\Setup\Status\SysprepStatus"));
+        assert_eq!(expect_output.as_deref(), Some("True"));
+        assert_eq!(*via, Transport::Winrm);
+        assert_eq!(tests.winrm.username, "tester");
+        assert_eq!(tests.winrm.port, DEFAULT_WINRM_PORT);
+        assert!(tests.uses_winrm());
+        assert_eq!(tests.boot_check, Some(BootCheck::TcpPort(5985)));
+        assert!(tests.needs_agent());
+        assert_eq!(tests.tcp_ports(), [3389, 5985]);
+
+        let config = Config::from_str(
+            "[tests]\nfinal_commands = [\"Get-Date\"]\n\
+            final_commands_via = \"winrm\"\n\n\
+            [tests.winrm]\nport = 5986",
+            Utf8Path::new("."),
+        )
+        .unwrap();
+        assert!(config.tests.uses_winrm() && !config.tests.needs_agent());
+        assert_eq!(config.tests.tcp_ports(), [5986]);
+
+        let tests = ImageTests::default();
+        assert!(tests.is_empty());
+        let tests = tests.with_boot_check("serial".parse().ok());
+        assert!(!tests.is_empty() && !tests.needs_agent());
+        for check in ["tcp:0", "tcp:", "ssh"] {
+            assert!(check.parse::<BootCheck>().is_err(), "{check}");
+        }
+
+        for (source, expected) in [
+            ("[[tests.check]]\nname = \"a\"", "must set exactly one"),
+            (
+                "[[tests.check]]\nname = \"a\"\ntcp_port = 70000",
+                "'tests.check[0].tcp_port' is out of range",
+            ),
+            (
+                "[[tests.check]]\nname = \"a\"\ntcp_port = 22\nexpect_exit = 1",
+                "can't set 'expect_output' or 'expect_exit'",
+            ),
+            (
+                "[[tests.check]]\nname = \"a\"\npowershell = \"1\"\n\
+                [[tests.check]]\nname = \"a\"\npowershell = \"2\"",
+                "more than one test is named 'a'",
+            ),
+            (
+                "[[tests.check]]\nname = \"a\"\npowershell = \"1\"\n\
+                required = \"yes\"",
+                "should be a boolean",
+            ),
+            ("[tests]\nboot_check = \"ping\"", "'tests.boot_check' is invalid"),
+            (
+                "[[tests.check]]\nname = \"a\"\nservice = \"sshd\"\n\
+                expect_output = \"Running\"",
+                "uses a built-in check",
+            ),
+            (
+                "[[tests.check]]\nname = \"a\"\ntcp_port = 22\n\
+                via = \"winrm\"",
+                "so it can't set 'via'",
+            ),
+        ] {
+            let err = Config::from_str(source, Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(expected), "{source:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let err =
+            Config::from_str("[steps]\ndisabled = [\"a\"]", Utf8Path::new("."))
+                .unwrap_err()
+                .to_string();
+        assert!(err.contains("'steps.disabled'"), "{err}");
+    }
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The convert command, which converts an existing image between the formats
+//! builds can produce, with the options the build's own conversion steps
+//! use, and then checks that the result is intact and presents the same disk.
+//!
+//! `qemu-img convert` leaves runs of zeros in the source unallocated in the
+//! result, so sparse images stay sparse (except as fixed-size VHDX images,
+//! which are allocated in full by design).
+
+use std::{os::unix::fs::MetadataExt, process::Command};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{
+    app::{ImageFormat, Qcow2Compression, VhdxSubformat},
+    steps::{describe_get_image_virtual_size, get_image_virtual_size},
+    ui::{StandaloneUi, Ui},
+    util::{format_command, format_size, run_command_check_status},
+};
+
+/// How to convert an image.
+pub struct Conversion<'a> {
+    pub input: &'a Utf8Path,
+    pub output: &'a Utf8Path,
+    pub format: ImageFormat,
+    pub qcow2_compression: Qcow2Compression,
+    pub vhdx_subformat: VhdxSubformat,
+}
+
+impl Conversion<'_> {
+    /// Returns the commands that convert the input image (in `input_format`)
+    /// and then check the result.
+    fn commands(&self, input_format: &str) -> Vec<Command> {
+        let (input, output) = (self.input, self.output);
+        let mut commands = match self.format {
+            ImageFormat::Raw => {
+                let mut cmd = Command::new("qemu-img");
+                cmd.args(["convert", "-f", input_format, "-O", "raw"])
+                    .args([input.as_str(), output.as_str()]);
+                return vec![cmd];
+            }
+            ImageFormat::Qcow2 => vec![crate::qcow2::command(
+                input,
+                input_format,
+                output,
+                self.qcow2_compression,
+            )],
+            ImageFormat::Vhdx => {
+                return crate::vhdx::commands(
+                    input.as_str(),
+                    input_format,
+                    output.as_str(),
+                    &self.vhdx_subformat.to_string(),
+                )
+                .into();
+            }
+            ImageFormat::Vmdk => vec![crate::vmdk::command(
+                input.as_str(),
+                input_format,
+                output.as_str(),
+            )],
+        };
+
+        // Raw images have nothing to check, and the VHDX commands already
+        // include a check.
+        let mut check = Command::new("qemu-img");
+        check.args(["check", "-f", &self.format.to_string(), output.as_str()]);
+        commands.push(check);
+        commands
+    }
+}
+
+/// Converts an image as `conversion` describes. With `dry_run`, only prints
+/// the commands that would.
+pub fn run(conversion: &Conversion, dry_run: bool) -> Result<()> {
+    let Conversion { input, output, format, .. } = *conversion;
+    let input_format = crate::nbd::image_format(input)?;
+    if output.canonicalize_utf8().ok() == Some(input.canonicalize_utf8()?) {
+        anyhow::bail!("can't convert '{input}' in place");
+    }
+
+    let output_format = format.to_string();
+    let commands = conversion.commands(input_format);
+    if dry_run {
+        println!(
+            "{}",
+            describe_get_image_virtual_size(input.as_str(), input_format)
+        );
+        for cmd in &commands {
+            println!("{}", format_command(cmd));
+        }
+        println!(
+            "{}",
+            describe_get_image_virtual_size(output.as_str(), &output_format)
+        );
+        return Ok(());
+    }
+
+    let ui = StandaloneUi;
+    let virtual_size =
+        get_image_virtual_size(input.as_str(), input_format, &ui)?;
+    if format == ImageFormat::Vhdx {
+        if let Some(warning) =
+            crate::vhdx::size_warning(&format!("'{input}'"), virtual_size)
+        {
+            ui.warn(&warning);
+        }
+    }
+
+    let result = convert(commands, &ui).and_then(|()| {
+        let converted_size =
+            get_image_virtual_size(output.as_str(), &output_format, &ui)?;
+        if converted_size != virtual_size {
+            anyhow::bail!(
+                "'{output}' presents a {converted_size}-byte disk, but \
+                '{input}' presents a {virtual_size}-byte one"
+            );
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(output);
+        return Err(e);
+    }
+
+    if format == ImageFormat::Qcow2 {
+        crate::qcow2::write_metadata(
+            output,
+            input,
+            conversion.qcow2_compression,
+            virtual_size,
+        )?;
+    }
+
+    let metadata = std::fs::metadata(output)
+        .with_context(|| format!("reading metadata for '{output}'"))?;
+    println!(
+        "Converted '{input}' ({input_format}) to '{output}' ({output_format})"
+    );
+    println!(
+        "  Disk size: {}; file size: {}, of which {} is allocated",
+        format_size(virtual_size),
+        format_size(metadata.len()),
+        format_size(metadata.blocks() * 512)
+    );
+    Ok(())
+}
+
+/// Runs the conversion and its checks.
+fn convert(commands: Vec<Command>, ui: &dyn Ui) -> Result<()> {
+    let mut commands = commands.into_iter();
+    if let Some(mut cmd) = commands.next() {
+        run_command_check_status(&mut cmd, ui)?;
+    }
+    for mut check in commands {
+        run_command_check_status(&mut check, ui)
+            .context("checking the converted image")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_converted_images() {
+        let conversion = |format| Conversion {
+            input: Utf8Path::new("in.qcow2"),
+            output: Utf8Path::new("out"),
+            format,
+            qcow2_compression: Qcow2Compression::Zstd,
+            vhdx_subformat: VhdxSubformat::Fixed,
+        };
+        let commands = |format| {
+            conversion(format)
+                .commands("qcow2")
+                .iter()
+                .map(format_command)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            commands(ImageFormat::Raw),
+            ["qemu-img convert -f qcow2 -O raw in.qcow2 out"]
+        );
+        assert_eq!(
+            commands(ImageFormat::Qcow2),
+            [
+                "qemu-img convert -f qcow2 -O qcow2 -c -o \
+                compression_type=zstd in.qcow2 out",
+                "qemu-img check -f qcow2 out",
+            ]
+        );
+        assert_eq!(
+            commands(ImageFormat::Vhdx),
+            [
+                "qemu-img convert -f qcow2 -O vhdx -o subformat=fixed \
+                in.qcow2 out",
+                "qemu-img check -f vhdx out",
+            ]
+        );
+        assert_eq!(
+            commands(ImageFormat::Vmdk)[1],
+            "qemu-img check -f vmdk out"
+        );
+    }
+}
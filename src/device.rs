@@ -0,0 +1,494 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Writes the finished output image to a block device, such as a zvol or a
+//! thinly-provisioned logical volume.
+//!
+//! Writing an image to a thin device byte-for-byte allocates backing storage
+//! for every block, including the runs of zeroes that make up most of a fresh
+//! Windows image, and leaves blocks the device's previous contents allocated.
+//! If the user explicitly asks for it with `--discard-output-device`, this
+//! module instead discards the whole device before writing the image and
+//! asks the device to zero (rather than writing zeroes to) the image's empty
+//! regions, which thin devices implement by deallocating them. Discard is
+//! destructive, so it's never done implicitly.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    os::unix::fs::{FileExt, FileTypeExt},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    hash::{self, Sha256},
+    json::Json,
+    nbd::RawImage,
+    runner::Context,
+    trace,
+    ui::Ui,
+};
+
+/// The amount of the image to read and write at a time. Runs of zeroes
+/// shorter than this are written normally.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Returns an error unless `path` is a block device.
+fn check_block_device(path: &Utf8Path) -> Result<std::fs::Metadata> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("output device '{path}' not found"))?;
+    if !metadata.file_type().is_block_device() {
+        anyhow::bail!("output device '{path}' is not a block device");
+    }
+
+    Ok(metadata)
+}
+
+/// Returns the path to the sysfs queue directory for the block device with
+/// the supplied device number. Partitions don't have queue directories of
+/// their own, so for a partition this is its parent disk's queue directory.
+#[cfg(target_os = "linux")]
+fn queue_dir(rdev: u64) -> Utf8PathBuf {
+    // SAFETY: `major` and `minor` just extract bit fields from `rdev`.
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    let dev_dir = Utf8PathBuf::from(format!("/sys/dev/block/{major}:{minor}"));
+    let queue = dev_dir.join("queue");
+    if queue.exists() {
+        queue
+    } else {
+        dev_dir.join("../queue")
+    }
+}
+
+/// Returns the discard granularity of the block device with the supplied
+/// device number, or 0 if it doesn't support discard.
+#[cfg(target_os = "linux")]
+fn discard_granularity(rdev: u64) -> Result<u64> {
+    let path = queue_dir(rdev).join("discard_granularity");
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading '{path}'"))?;
+    contents
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing '{}' from '{path}'", contents.trim()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn discard_granularity(_rdev: u64) -> Result<u64> {
+    anyhow::bail!("discarding output devices is only supported on Linux")
+}
+
+/// Checks that `device` is a block device that isn't in use and, if
+/// `discard` is set, that it supports discard. Returns a message describing
+/// each problem.
+pub fn check_prerequisites(device: &Utf8Path, discard: bool) -> Vec<String> {
+    let metadata = match check_block_device(device) {
+        Ok(metadata) => metadata,
+        Err(e) => return vec![format!("{e:#}")],
+    };
+
+    let mut errors = Vec::new();
+    if let Err(e) = open_exclusive(device) {
+        errors.push(format!("{e:#}"));
+    }
+
+    if discard {
+        use std::os::unix::fs::MetadataExt;
+        match discard_granularity(metadata.rdev()) {
+            Ok(0) => errors.push(format!(
+                "output device '{device}' doesn't support discard; drop \
+                --discard-output-device to write the image without \
+                discarding"
+            )),
+            Ok(_) => {}
+            Err(e) => errors.push(format!(
+                "couldn't determine whether '{device}' supports discard: {e:#}"
+            )),
+        }
+    }
+
+    errors
+}
+
+/// Opens `device` for writing. On Linux, the device is opened exclusively,
+/// which fails if it's mounted or otherwise in use.
+fn open_exclusive(device: &Utf8Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_EXCL);
+    }
+    options.open(device).with_context(|| {
+        format!("opening output device '{device}' (is it in use?)")
+    })
+}
+
+/// Linux block device ioctls that take a `[start, length]` byte range.
+#[cfg(target_os = "linux")]
+mod ioctl {
+    /// `_IO(0x12, 119)`: discards a range of the device.
+    pub const BLKDISCARD: libc::c_ulong = 0x1277;
+
+    /// `_IO(0x12, 127)`: zeroes a range of the device, deallocating it if
+    /// the device supports that.
+    pub const BLKZEROOUT: libc::c_ulong = 0x127f;
+}
+
+#[cfg(not(target_os = "linux"))]
+mod ioctl {
+    pub const BLKDISCARD: u64 = 0;
+    pub const BLKZEROOUT: u64 = 0;
+}
+
+/// Issues the block device ioctl `request` for the `len` bytes of `device`
+/// starting at `start`.
+#[cfg(target_os = "linux")]
+fn range_ioctl(
+    device: &File,
+    request: libc::c_ulong,
+    start: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let range = [start, len];
+
+    // SAFETY: `device` is an open file descriptor, and both of these ioctls
+    // read a two-element `u64` array that outlives the call.
+    let rc = unsafe {
+        libc::ioctl(device.as_raw_fd(), request as _, range.as_ptr())
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn range_ioctl(_: &File, _: u64, _: u64, _: u64) -> std::io::Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+/// Byte counts for a completed write to an output device.
+#[derive(Default)]
+struct WriteStats {
+    /// Bytes discarded before the image was written.
+    discarded: u64,
+
+    /// Bytes of the image written to the device.
+    written: u64,
+
+    /// Bytes of the image that were all zeroes and were zeroed by the device
+    /// instead of being written.
+    zeroed: u64,
+
+    /// The SHA-256 digest of the image, in lowercase hex.
+    sha256: String,
+}
+
+/// What to do with part of the image.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    /// Write these bytes of the image to the same place on the device.
+    Write(Range<u64>),
+
+    /// Ask the device to zero these bytes, which are all zeroes in the image.
+    Zero(Range<u64>),
+}
+
+/// Decides, chunk by chunk, which parts of the image are written and which
+/// are zeroed. When zeroing, runs of consecutive all-zero chunks are zeroed
+/// with one request; partial chunks (only ever the last one) are written.
+struct ChunkClassifier {
+    zero: bool,
+    offset: u64,
+
+    /// The start of the current run of all-zero chunks, if the previous chunk
+    /// was all zeroes.
+    zero_run_start: Option<u64>,
+}
+
+impl ChunkClassifier {
+    /// Returns a classifier for an image that's written from its start,
+    /// zeroing runs of zeroes if `zero` is set.
+    fn new(zero: bool) -> Self {
+        Self { zero, offset: 0, zero_run_start: None }
+    }
+
+    /// Classifies the next `len` bytes of the image, which are all zeroes if
+    /// `all_zero` is set, returning what to do before reading the next
+    /// chunk.
+    fn chunk(&mut self, len: usize, all_zero: bool) -> Vec<Action> {
+        let start = self.offset;
+        self.offset += len as u64;
+        if self.zero && len == CHUNK_SIZE && all_zero {
+            self.zero_run_start.get_or_insert(start);
+            return Vec::new();
+        }
+
+        let mut actions: Vec<_> =
+            self.end_zero_run(start).into_iter().collect();
+        actions.push(Action::Write(start..self.offset));
+        actions
+    }
+
+    /// Returns what's left to do once the whole image has been classified.
+    fn finish(&mut self) -> Option<Action> {
+        self.end_zero_run(self.offset)
+    }
+
+    fn end_zero_run(&mut self, end: u64) -> Option<Action> {
+        self.zero_run_start.take().map(|start| Action::Zero(start..end))
+    }
+}
+
+/// Writes the raw disk at `image`, which may itself be a block device, to the
+/// start of `device`. If `discard` is set, first discards the whole device,
+/// then zeroes runs of zeroes in the image instead of writing them.
+fn write_image(
+    image: &Utf8Path,
+    device: &Utf8Path,
+    discard: bool,
+    ui: &dyn Ui,
+) -> Result<WriteStats> {
+    let mut source = File::open(image)
+        .with_context(|| format!("opening output image '{image}'"))?;
+    let image_len = source
+        .seek(SeekFrom::End(0))
+        .with_context(|| format!("getting the size of '{image}'"))?;
+    source.rewind()?;
+    let mut target = open_exclusive(device)?;
+    let device_len = target
+        .seek(SeekFrom::End(0))
+        .with_context(|| format!("getting the size of '{device}'"))?;
+    if image_len > device_len {
+        anyhow::bail!(
+            "output image '{image}' ({image_len} bytes) is larger than output \
+            device '{device}' ({device_len} bytes)"
+        );
+    }
+
+    let mut stats = WriteStats::default();
+    if discard {
+        ui.set_substep(&format!("discarding {device}"));
+        trace::debug!("discarding output device", bytes = device_len);
+        range_ioctl(&target, ioctl::BLKDISCARD, 0, device_len)
+            .with_context(|| format!("discarding '{device}'"))?;
+        stats.discarded = device_len;
+    }
+
+    ui.set_substep(&format!("writing {image} to {device}"));
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut classifier = ChunkClassifier::new(discard);
+
+    // Only ever the chunk just read, `chunk`, is written.
+    let mut apply = |action: Action, chunk: &[u8]| {
+        match action {
+            Action::Write(range) => {
+                target
+                    .write_all_at(chunk, range.start)
+                    .with_context(|| format!("writing to '{device}'"))?;
+                stats.written += chunk.len() as u64;
+            }
+            Action::Zero(range) => {
+                let (start, len) = (range.start, range.end - range.start);
+                range_ioctl(&target, ioctl::BLKZEROOUT, start, len)
+                    .with_context(|| {
+                        format!("zeroing '{device}' at offset {start}")
+                    })?;
+                stats.zeroed += len;
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    loop {
+        let len = read_chunk(&mut source, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        let chunk = &buf[..len];
+        hasher.update(chunk);
+        for action in classifier.chunk(len, chunk.iter().all(|&b| b == 0)) {
+            apply(action, chunk)?;
+        }
+    }
+
+    if let Some(action) = classifier.finish() {
+        apply(action, &[])?;
+    }
+    target.sync_all().with_context(|| format!("flushing '{device}'"))?;
+    stats.sha256 = hash::to_hex(&hasher.finish());
+    Ok(stats)
+}
+
+/// Fills as much of `buf` as possible from `source`, returning the number of
+/// bytes read (which is less than the buffer's length only at the end of the
+/// file).
+fn read_chunk(source: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Describes what [`write_output_device`] would do.
+pub fn describe_write_output_device(ctx: &mut Context) -> Vec<String> {
+    let Some(device) = ctx.get_var("output_device") else {
+        return Vec::new();
+    };
+
+    let image = ctx.get_var("output_image").unwrap();
+    let discard = if ctx.get_var("discard_output_device").is_some() {
+        "discard and "
+    } else {
+        ""
+    };
+    crate::nbd::describe_raw_access(
+        image,
+        crate::steps::output_format(ctx),
+        |raw| vec![format!("{discard}write {raw} to {device}")],
+    )
+}
+
+/// Writes the output image to the block device named by the `output_device`
+/// context variable, discarding it first if `discard_output_device` is set.
+/// Does nothing if no output device was requested.
+pub fn write_output_device(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(device) = ctx.get_var("output_device") else {
+        return Ok(());
+    };
+
+    let device = Utf8PathBuf::from(device);
+    let image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let discard = ctx.get_var("discard_output_device").is_some();
+    check_block_device(&device)?;
+
+    let raw = RawImage::open(&image, crate::steps::output_format(ctx), ui)?;
+    let stats = write_image(raw.path(), &device, discard, ui)?;
+    let raw_is_image = raw.path() == image;
+    drop(raw);
+    if raw_is_image {
+        crate::manifest::record_image_digest(ctx, &image, &stats.sha256);
+    }
+    trace::debug!(
+        "wrote output device",
+        written = stats.written,
+        zeroed = stats.zeroed,
+        discarded = stats.discarded
+    );
+    ui.record_metric("output_device_bytes_written", Json::from(stats.written));
+    ui.record_metric("output_device_bytes_zeroed", Json::from(stats.zeroed));
+    ui.record_metric(
+        "output_device_bytes_discarded",
+        Json::from(stats.discarded),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::{testing::TestUi, RecordingRunner};
+
+    #[test]
+    fn classifies_chunks() {
+        let mb = CHUNK_SIZE as u64;
+        let mut classifier = ChunkClassifier::new(true);
+        assert_eq!(classifier.chunk(CHUNK_SIZE, false), [Action::Write(0..mb)]);
+        assert!(classifier.chunk(CHUNK_SIZE, true).is_empty());
+        assert!(classifier.chunk(CHUNK_SIZE, true).is_empty());
+        assert_eq!(
+            classifier.chunk(CHUNK_SIZE, false),
+            [Action::Zero(mb..3 * mb), Action::Write(3 * mb..4 * mb)]
+        );
+        assert!(classifier.chunk(CHUNK_SIZE, true).is_empty());
+        assert_eq!(classifier.finish(), Some(Action::Zero(4 * mb..5 * mb)));
+        assert_eq!(classifier.finish(), None);
+
+        // A partial trailing chunk is written even if it's all zeroes, and
+        // ends the run before it.
+        let mut classifier = ChunkClassifier::new(true);
+        assert!(classifier.chunk(CHUNK_SIZE, true).is_empty());
+        assert_eq!(
+            classifier.chunk(512, true),
+            [Action::Zero(0..mb), Action::Write(mb..mb + 512)]
+        );
+        assert_eq!(classifier.finish(), None);
+
+        // Without discard, everything is written.
+        let mut classifier = ChunkClassifier::new(false);
+        assert_eq!(classifier.chunk(CHUNK_SIZE, true), [Action::Write(0..mb)]);
+        assert_eq!(classifier.finish(), None);
+    }
+
+    #[test]
+    fn writes_image_to_a_file() {
+        let dir = Utf8PathBuf::try_from(
+            std::env::temp_dir().join("wimsy-device-test"),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Two and a half chunks, with a chunk of zeroes in the middle.
+        let mut image = vec![0u8; CHUNK_SIZE * 5 / 2];
+        image[..4].copy_from_slice(b"wims");
+        *image.last_mut().unwrap() = 0x55;
+        let (image_path, target_path) =
+            (dir.join("image.raw"), dir.join("dev"));
+        std::fs::write(&image_path, &image).unwrap();
+        std::fs::write(&target_path, vec![0xffu8; CHUNK_SIZE * 3]).unwrap();
+
+        let runner = RecordingRunner::new();
+        let stats = write_image(
+            &image_path,
+            &target_path,
+            false,
+            &TestUi { runner: &runner },
+        )
+        .unwrap();
+        assert_eq!(stats.written, image.len() as u64);
+        assert_eq!(stats.written + stats.zeroed, image.len() as u64);
+        assert_eq!((stats.zeroed, stats.discarded), (0, 0));
+        assert_eq!(stats.sha256, hash::to_hex(&hash::sha256(&image)));
+
+        let target = std::fs::read(&target_path).unwrap();
+        assert_eq!(&target[..image.len()], image.as_slice());
+        assert!(target[image.len()..].iter().all(|&b| b == 0xff));
+
+        // Images larger than the device are refused, and files that aren't
+        // block devices can't be output devices.
+        std::fs::write(&target_path, [0u8; 512]).unwrap();
+        assert!(write_image(
+            &image_path,
+            &target_path,
+            false,
+            &TestUi { runner: &runner }
+        )
+        .is_err());
+        assert!(check_prerequisites(&target_path, false)[0]
+            .contains("is not a block device"));
+        assert!(check_prerequisites(&dir.join("missing"), false)[0]
+            .contains("not found"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
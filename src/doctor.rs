@@ -0,0 +1,485 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that the host can build images before any long-running step starts.
+//!
+//! The `doctor` command runs every check and reports the results without
+//! building anything. Builds run the checks that matter for their options as
+//! part of checking their prerequisites.
+
+use std::process::Command;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
+
+use crate::{monitor, trace};
+
+/// The outcome of a single check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The result of checking one aspect of the host.
+pub struct Finding {
+    /// What was checked, e.g. "qemu-img".
+    pub subject: String,
+    pub status: Status,
+
+    /// What the check found.
+    pub detail: String,
+
+    /// What the user can do about a warning or error.
+    pub remedy: Option<String>,
+}
+
+/// The results of a set of host checks.
+#[derive(Default)]
+pub struct Report {
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn add(
+        &mut self,
+        subject: impl Into<String>,
+        status: Status,
+        detail: impl Into<String>,
+        remedy: Option<String>,
+    ) {
+        self.findings.push(Finding {
+            subject: subject.into(),
+            status,
+            detail: detail.into(),
+            remedy,
+        });
+    }
+
+    pub fn ok(
+        &mut self,
+        subject: impl Into<String>,
+        detail: impl Into<String>,
+    ) {
+        self.add(subject, Status::Ok, detail, None);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|f| f.status == Status::Error)
+    }
+
+    /// Prints one line per finding, followed by its remedy (if any), and a
+    /// summary line.
+    pub fn print(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        for finding in &self.findings {
+            let label = match finding.status {
+                Status::Ok => "ok".green(),
+                Status::Warning => "warning".yellow(),
+                Status::Error => "error".red(),
+            };
+
+            writeln!(w, "  [{label}] {}: {}", finding.subject, finding.detail)?;
+            if let Some(remedy) = &finding.remedy {
+                writeln!(w, "      To fix this, {remedy}.")?;
+            }
+        }
+
+        let count = |status| {
+            self.findings.iter().filter(|f| f.status == status).count()
+        };
+        writeln!(w)?;
+        writeln!(
+            w,
+            "{} checks: {} ok, {} warnings, {} errors",
+            self.findings.len(),
+            count(Status::Ok),
+            count(Status::Warning),
+            count(Status::Error)
+        )
+    }
+}
+
+/// A program a build may run.
+pub struct Tool {
+    pub name: &'static str,
+
+    /// What the build uses the tool for.
+    pub purpose: &'static str,
+
+    /// Whether a build with the options being checked can't finish without
+    /// the tool.
+    pub required: bool,
+}
+
+/// Checks that each of `tools` is on the `PATH`.
+pub fn check_tools(report: &mut Report, tools: &[Tool]) {
+    for tool in tools {
+        match which::which(tool.name) {
+            Ok(path) => {
+                report.ok(tool.name, format!("found at {}", path.display()))
+            }
+            Err(_) => report.add(
+                tool.name,
+                if tool.required { Status::Error } else { Status::Warning },
+                format!("not found; it's needed {}", tool.purpose),
+                Some(format!("install {} or add it to your PATH", tool.name)),
+            ),
+        }
+    }
+}
+
+/// Extracts the version from the first line of `qemu-img --version`, e.g.
+/// "qemu-img version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)".
+pub fn parse_qemu_img_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .skip_while(|&word| word != "version")
+        .nth(1)?;
+    let mut parts = version.split('.').map(|part| {
+        let digits: String =
+            part.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse::<u32>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let micro = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, micro))
+}
+
+/// Returns whether the host's `qemu-img resize` accepts `--shrink`, or `None`
+/// if `qemu-img` couldn't be run.
+pub fn qemu_img_supports_shrink() -> Option<bool> {
+    let output = Command::new("qemu-img").arg("--help").output().ok()?;
+    let help = String::from_utf8_lossy(&output.stdout);
+    Some(help.contains("--shrink"))
+}
+
+/// Returns a warning if the host's `qemu-img` can't be asked to shrink
+/// images. Builds still work, since the shrink step retries without
+/// `--shrink`, but such an old `qemu-img` may lack other features too.
+pub fn shrink_warning() -> Option<String> {
+    match qemu_img_supports_shrink() {
+        Some(false) => Some(
+            "qemu-img doesn't support 'resize --shrink' (added in QEMU 2.11); \
+            consider upgrading QEMU"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+/// Checks the host's `qemu-img` version and whether it supports shrinking
+/// images. Does nothing if `qemu-img` isn't installed, which
+/// [`check_tools`] reports.
+pub fn check_qemu_img(report: &mut Report) {
+    let Ok(output) = Command::new("qemu-img").arg("--version").output() else {
+        return;
+    };
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    match parse_qemu_img_version(&text) {
+        Some((major, minor, micro)) => {
+            report.ok("qemu-img version", format!("{major}.{minor}.{micro}"))
+        }
+        None => report.add(
+            "qemu-img version",
+            Status::Warning,
+            "couldn't determine qemu-img's version",
+            None,
+        ),
+    }
+
+    match qemu_img_supports_shrink() {
+        Some(true) => report.ok("qemu-img --shrink", "supported"),
+        Some(false) | None => report.add(
+            "qemu-img --shrink",
+            Status::Warning,
+            "not supported; the shrink step will fall back to resizing without \
+            it, which some versions refuse to do",
+            Some("upgrade to QEMU 2.11 or later".to_string()),
+        ),
+    }
+}
+
+/// Returns the directory whose filesystem will hold `path`, which may not
+/// exist yet: the nearest ancestor of `path` that does.
+fn existing_dir(path: &Utf8Path) -> Utf8PathBuf {
+    let mut dir = path;
+    loop {
+        if dir.is_dir() {
+            return dir.to_path_buf();
+        }
+
+        match dir.parent() {
+            Some(parent) if !parent.as_str().is_empty() => dir = parent,
+            _ => return Utf8PathBuf::from("."),
+        }
+    }
+}
+
+/// Describes a shortfall in free space on the filesystem holding `path`,
+/// which needs room for `needed` bytes. Returns `Ok(None)` if there's room.
+fn free_space_problem(
+    path: &Utf8Path,
+    needed: u64,
+) -> anyhow::Result<Option<(Status, String)>> {
+    let dir = existing_dir(path);
+    let free = monitor::free_bytes(&dir)?;
+    let min_free = monitor::DEFAULT_MIN_FREE_MIB * 1024 * 1024;
+    let free_gib = gib(free);
+    let needed_gib = gib(needed);
+    if free < min_free {
+        Ok(Some((
+            Status::Error,
+            format!(
+                "{dir} has only {free_gib:.1} GiB free, below the {} MiB at \
+                which builds stop",
+                monitor::DEFAULT_MIN_FREE_MIB
+            ),
+        )))
+    } else if free < needed {
+        Ok(Some((
+            Status::Warning,
+            format!(
+                "{dir} has {free_gib:.1} GiB free, but the image may grow to \
+                {needed_gib:.1} GiB"
+            ),
+        )))
+    } else {
+        trace::debug!(
+            "enough free space",
+            dir = dir.as_str(),
+            free_bytes = free
+        );
+        Ok(None)
+    }
+}
+
+/// Checks that the filesystem holding `path` has room for `needed` bytes.
+/// `label` says what `path` is, e.g. "output image".
+pub fn check_free_space(
+    report: &mut Report,
+    label: &str,
+    path: &Utf8Path,
+    needed: u64,
+) {
+    let subject = format!("free space for {label}");
+    match free_space_problem(path, needed) {
+        Ok(None) => {
+            report.ok(subject, format!("enough room in {}", existing_dir(path)))
+        }
+        Ok(Some((status, detail))) => report.add(
+            subject,
+            status,
+            detail,
+            Some("free up space or choose a different location".to_string()),
+        ),
+        Err(e) => report.add(subject, Status::Warning, format!("{e:#}"), None),
+    }
+}
+
+/// The most space a build's ISOs and scratch files take up in the work
+/// directory. The virtio driver ISO is by far the largest of them.
+pub const WORK_DIR_SPACE_BYTES: u64 = 2 << 30;
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 30) as f64
+}
+
+/// The space a build may need on each filesystem it writes to.
+#[derive(Default)]
+pub struct SpaceNeeds {
+    needs: Vec<(String, Utf8PathBuf, u64)>,
+}
+
+impl SpaceNeeds {
+    /// Estimates the space a build needs from the paths it writes: its
+    /// ISOs and scratch files in `work_dir`, an output image that may grow to
+    /// `disk_size` bytes, each of the `converted` images (which may be as
+    /// large as the output image), and the compressed copy of the output
+    /// image, if any.
+    pub fn for_build(
+        work_dir: &Utf8Path,
+        output_image: &Utf8Path,
+        disk_size: u64,
+        converted: &[(&str, Option<&Utf8Path>)],
+        compression: crate::compress::Compression,
+    ) -> Self {
+        let mut needs = Self::default();
+        needs.add("work directory", work_dir, WORK_DIR_SPACE_BYTES);
+        needs.add("output image", output_image, disk_size);
+        for (label, path) in converted {
+            if let Some(path) = path {
+                needs.add(*label, path, disk_size);
+            }
+        }
+        if compression != crate::compress::Compression::None {
+            let compressed = Utf8PathBuf::from(format!(
+                "{output_image}{}",
+                compression.extension()
+            ));
+            needs.add("compressed image", &compressed, disk_size);
+        }
+
+        needs
+    }
+
+    /// Records that the file or directory at `path`, described by `label`
+    /// (e.g. "output image"), may grow to `bytes`.
+    pub fn add(
+        &mut self,
+        label: impl Into<String>,
+        path: &Utf8Path,
+        bytes: u64,
+    ) {
+        self.needs.push((label.into(), path.to_path_buf(), bytes));
+    }
+
+    /// Returns an error for each filesystem that doesn't have room for
+    /// everything the build writes to it plus the free space at which builds
+    /// stop. Filesystems whose free space can't be read aren't checked, since
+    /// the build monitors free space as it runs anyway.
+    pub fn check(&self) -> Vec<String> {
+        use std::os::unix::fs::MetadataExt;
+
+        // A directory on each device, and what will be written to it.
+        let mut filesystems = std::collections::BTreeMap::new();
+        for (label, path, bytes) in &self.needs {
+            let dir = existing_dir(path);
+            let Ok(metadata) = std::fs::metadata(&dir) else {
+                continue;
+            };
+            filesystems
+                .entry(metadata.dev())
+                .or_insert_with(|| (dir, Vec::new()))
+                .1
+                .push((label.as_str(), *bytes));
+        }
+
+        let margin = monitor::DEFAULT_MIN_FREE_MIB * 1024 * 1024;
+        filesystems
+            .into_values()
+            .filter_map(|(dir, items)| match monitor::free_bytes(&dir) {
+                Ok(free) => shortfall(&dir, free, &items, margin),
+                Err(e) => {
+                    trace::debug!(
+                        "couldn't check free space",
+                        dir = dir.as_str(),
+                        error = format!("{e:#}")
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Describes the shortfall if `free` bytes in `dir` can't hold `items` (what
+/// will be written there and how large each may grow) plus `margin`.
+fn shortfall(
+    dir: &Utf8Path,
+    free: u64,
+    items: &[(&str, u64)],
+    margin: u64,
+) -> Option<String> {
+    let needed = items
+        .iter()
+        .fold(margin, |total, (_, bytes)| total.saturating_add(*bytes));
+    if free >= needed {
+        return None;
+    }
+
+    let items = items
+        .iter()
+        .map(|(label, bytes)| format!("{label} {:.1} GiB", gib(*bytes)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(format!(
+        "the filesystem holding {dir} has {:.1} GiB free, but the build may \
+        need {:.1} GiB there ({items}, plus the {} MiB at which builds stop); \
+        free up space, choose a different location, or pass \
+        --force-disk-space to start anyway",
+        gib(free),
+        gib(needed),
+        margin / (1024 * 1024)
+    ))
+}
+
+/// Prints `report` and fails if it contains any errors.
+pub fn run(report: Report) -> anyhow::Result<()> {
+    println!("{}", "Host checks:".bold());
+    report.print(&mut std::io::stdout())?;
+    if report.has_errors() {
+        anyhow::bail!(
+            "this host can't build images until the errors are fixed"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_qemu_img_versions() {
+        assert_eq!(
+            parse_qemu_img_version(
+                "qemu-img version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\n\
+                Copyright (c) 2003-2023 Fabrice Bellard"
+            ),
+            Some((8, 2, 2))
+        );
+        assert_eq!(
+            parse_qemu_img_version("qemu-img version 2.11.0\n"),
+            Some((2, 11, 0))
+        );
+        assert_eq!(
+            parse_qemu_img_version("qemu-img version 9.0.50-rc1"),
+            Some((9, 0, 50))
+        );
+        assert_eq!(
+            parse_qemu_img_version("qemu-img version 7"),
+            Some((7, 0, 0))
+        );
+        assert_eq!(parse_qemu_img_version("qemu-img: unknown option"), None);
+        assert_eq!(parse_qemu_img_version(""), None);
+    }
+
+    #[test]
+    fn reports_space_shortfalls() {
+        const GIB: u64 = 1 << 30;
+        let dir = Utf8Path::new("/var/tmp");
+        let items = [("output image", 30 * GIB), ("qcow2 image", 30 * GIB)];
+        assert_eq!(shortfall(dir, 61 * GIB, &items, GIB), None);
+
+        let message = shortfall(dir, 40 * GIB, &items, GIB).unwrap();
+        assert!(message.starts_with(
+            "the filesystem holding /var/tmp has 40.0 GiB free, but the \
+            build may need 61.0 GiB there (output image 30.0 GiB, qcow2 \
+            image 30.0 GiB, plus the 1024 MiB"
+        ));
+    }
+
+    #[test]
+    fn sums_needs_on_one_filesystem() {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir()).unwrap();
+        let free = monitor::free_bytes(&dir).unwrap();
+
+        // Each fits on its own, but not both together.
+        let mut needs = SpaceNeeds::default();
+        needs.add("output image", &dir.join("a.img"), free / 5 * 3);
+        needs.add("qcow2 image", &dir.join("b.qcow2"), free / 5 * 3);
+        let errors = needs.check();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("output image"));
+        assert!(errors[0].contains("qcow2 image"));
+
+        assert!(SpaceNeeds::default().check().is_empty());
+    }
+}
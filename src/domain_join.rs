@@ -0,0 +1,464 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Joins images to an Active Directory domain by adding a
+//! Microsoft-Windows-UnattendedJoin component to a specialize pass. Images
+//! built with `--skip-generalize` join during Windows Setup, from
+//! Autounattend.xml; generalized images join when they're first deployed,
+//! from specialize-unattend.xml.
+//!
+//! Setup doesn't report join failures (it just leaves the machine in a
+//! workgroup), so for joins during setup `OxidePrepBaseImage.ps1` checks
+//! whether the join succeeded and reports a failure over the serial port,
+//! which fails the build.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    app::DomainJoinOptions, autounattend::AutounattendUpdater,
+    hostname::Hostname, runner::Context, secrets::SecretSource,
+    template::xml_escape, ui::Ui,
+};
+
+const COMPONENT_NAME: &str = "Microsoft-Windows-UnattendedJoin";
+
+/// The answer file generalized images' join settings go in.
+const DEPLOYMENT_ANSWER_FILE: &str = "specialize-unattend.xml";
+
+/// Checks that the domain join options are usable, returning a message for
+/// each problem. `hostname` is the image's `--hostname`, which an unsecure
+/// join's computer account must be named.
+pub fn check_prerequisites(
+    options: &DomainJoinOptions,
+    hostname: Option<&Hostname>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    if !options.is_requested() {
+        return errors;
+    }
+
+    if options.djoin_blob.is_none()
+        && options.join_user.is_none()
+        && !options.join_unsecure
+    {
+        errors.push(
+            "--join-domain requires --join-user and --join-password, \
+            --join-unsecure, or --djoin-blob"
+                .to_string(),
+        );
+    }
+
+    if options.join_unsecure
+        && hostname.is_none_or(|name| name.to_string().contains('*'))
+    {
+        errors.push(
+            "--join-unsecure requires a --hostname without a '*', naming \
+            the computer account created for the image"
+                .to_string(),
+        );
+    }
+
+    for (password, what) in [
+        (&options.join_password, "domain join password"),
+        (&options.join_machine_password, "domain join machine password"),
+    ] {
+        if let Some(Err(e)) = password.as_ref().map(|p| p.check(what)) {
+            errors.push(format!("{e:#}"));
+        }
+    }
+
+    if let Some(blob) = &options.djoin_blob {
+        if let Err(e) = read_djoin_blob(blob) {
+            errors.push(format!("{e:#}"));
+        }
+    }
+
+    errors
+}
+
+/// Reads an offline domain join blob. `djoin /provision` writes these as
+/// UTF-16 text with a byte order mark; UTF-8 files are also accepted.
+fn read_djoin_blob(path: &Utf8Path) -> Result<String> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("reading domain join blob '{path}'"))?;
+    let text = match bytes.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16(&units)
+                .with_context(|| format!("decoding '{path}' as UTF-16"))?
+        }
+        None => String::from_utf8(bytes)
+            .with_context(|| format!("decoding '{path}' as UTF-8"))?,
+    };
+
+    let blob = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    if blob.is_empty() {
+        anyhow::bail!("domain join blob '{path}' is empty");
+    }
+
+    Ok(blob.to_string())
+}
+
+/// Splits a join account name of the form `DOMAIN\user` into its domain and
+/// user name. Names without a domain are taken to be in `default_domain`.
+fn split_account<'a>(
+    account: &'a str,
+    default_domain: &'a str,
+) -> (&'a str, &'a str) {
+    account.split_once('\\').unwrap_or((default_domain, account))
+}
+
+/// Builds the UnattendedJoin component from the domain join settings in
+/// `ctx`, reading the join password if necessary. Returns `None` if no domain
+/// join was requested.
+fn component_xml(ctx: &Context, ui: &dyn Ui) -> Result<Option<String>> {
+    let identification = if let Some(blob) = ctx.get_var("djoin_blob") {
+        let blob = read_djoin_blob(Utf8Path::new(blob))?;
+        format!(
+            "<Provisioning><AccountData>{}</AccountData></Provisioning>",
+            xml_escape(&blob)
+        )
+    } else if let Some(domain) = ctx
+        .get_var("join_domain")
+        .filter(|_| ctx.get_var("join_unsecure").is_some())
+    {
+        let source: SecretSource = ctx
+            .get_var("join_machine_password")
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "--join-unsecure requires --join-machine-password"
+                )
+            })?
+            .parse()?;
+        let password = source.resolve("domain join machine password", ui)?;
+        format!(
+            "<JoinDomain>{}</JoinDomain><MachinePassword>{}</MachinePassword>\
+            <UnsecureJoin>true</UnsecureJoin>",
+            xml_escape(domain),
+            xml_escape(&password),
+        )
+    } else if let Some(domain) = ctx.get_var("join_domain") {
+        let account = ctx.get_var("join_user").ok_or_else(|| {
+            anyhow::anyhow!("--join-domain requires --join-user")
+        })?;
+        let source: SecretSource = ctx
+            .get_var("join_password")
+            .ok_or_else(|| {
+                anyhow::anyhow!("--join-user requires --join-password")
+            })?
+            .parse()?;
+        let password = source.resolve("domain join password", ui)?;
+        let (account_domain, user) = split_account(account, domain);
+        let ou = ctx
+            .get_var("join_ou")
+            .map(|ou| {
+                format!("<MachineObjectOU>{}</MachineObjectOU>", xml_escape(ou))
+            })
+            .unwrap_or_default();
+
+        format!(
+            "<Credentials><Domain>{}</Domain><Username>{}</Username>\
+            <Password>{}</Password></Credentials>\
+            <JoinDomain>{}</JoinDomain>{ou}",
+            xml_escape(account_domain),
+            xml_escape(user),
+            xml_escape(&password),
+            xml_escape(domain),
+        )
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(format!(
+        "<component name=\"{COMPONENT_NAME}\" processorArchitecture=\"{}\" \
+        publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" \
+        versionScope=\"nonSxS\" xmlns=\"urn:schemas-microsoft-com:unattend\">\
+        <Identification>{identification}</Identification></component>",
+        crate::steps::architecture(ctx).windows_name()
+    )))
+}
+
+/// Returns true if the image joins its domain during Windows Setup rather
+/// than when it's deployed.
+fn joins_during_setup(ctx: &Context) -> bool {
+    ctx.get_var("skip_generalize").is_some()
+}
+
+/// Adds the UnattendedJoin component described by the domain join settings
+/// in `ctx` to the specialize pass that `updater` writes, if a domain join
+/// was requested for an image built with `--skip-generalize`.
+pub fn add_to_autounattend(
+    updater: AutounattendUpdater,
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<AutounattendUpdater> {
+    if !joins_during_setup(ctx) {
+        return Ok(updater);
+    }
+
+    match component_xml(ctx, ui)? {
+        Some(xml) => updater.with_component("specialize", COMPONENT_NAME, &xml),
+        None => Ok(updater),
+    }
+}
+
+/// Adds the UnattendedJoin component described by the domain join settings
+/// in `ctx` to the specialize pass in the unattend directory's
+/// specialize-unattend.xml, so that a generalized image joins the domain
+/// when it's first deployed.
+pub fn configure_deployment_join(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    if joins_during_setup(ctx) {
+        return Ok(());
+    }
+    let Some(xml) = component_xml(ctx, ui)? else {
+        return Ok(());
+    };
+
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    let path = unattend_dir.join(DEPLOYMENT_ANSWER_FILE);
+    if !path.exists() {
+        anyhow::bail!(
+            "generalized images join their domain from \
+            {DEPLOYMENT_ANSWER_FILE}, but the unattend directory doesn't have \
+            one"
+        );
+    }
+
+    ui.set_substep(&format!("adding domain join settings to {path}"));
+    let updated = path.with_extension("xml.join");
+    AutounattendUpdater::new(None, None)
+        .with_component("specialize", COMPONENT_NAME, &xml)?
+        .run(&path, &updated)
+        .with_context(|| format!("adding domain join settings to {path}"))?;
+    std::fs::rename(&updated, &path)
+        .with_context(|| format!("renaming '{updated}' to '{path}'"))
+}
+
+pub fn describe_configure_deployment_join(ctx: &mut Context) -> Vec<String> {
+    let requested = ctx.get_var("join_domain").is_some()
+        || ctx.get_var("djoin_blob").is_some();
+    if !requested || joins_during_setup(ctx) {
+        return Vec::new();
+    }
+
+    vec![format!(
+        "add a {COMPONENT_NAME} component to the specialize pass in \
+        {DEPLOYMENT_ANSWER_FILE}"
+    )]
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::command::{testing::TestUi, RecordingRunner};
+
+    fn test_dir(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::try_from(
+            std::env::temp_dir().join(format!("wimsy-{name}-test")),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn context(vars: &[(&str, &str)]) -> Context {
+        Context::new(
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    #[test]
+    fn reads_djoin_blobs() {
+        let dir = test_dir("djoin-blob");
+        let path = dir.join("blob.txt");
+
+        // djoin writes UTF-16LE with a byte order mark and a trailing NUL.
+        let mut utf16 = vec![0xff, 0xfe];
+        for unit in "ARAAAAAA\r\n\0".encode_utf16() {
+            utf16.extend(unit.to_le_bytes());
+        }
+        std::fs::write(&path, &utf16).unwrap();
+        assert_eq!(read_djoin_blob(&path).unwrap(), "ARAAAAAA");
+
+        std::fs::write(&path, "ARAAAAAA\n").unwrap();
+        assert_eq!(read_djoin_blob(&path).unwrap(), "ARAAAAAA");
+
+        std::fs::write(&path, [0xc3, 0x28]).unwrap();
+        let err = format!("{:#}", read_djoin_blob(&path).unwrap_err());
+        assert!(err.contains("as UTF-8"), "{err}");
+
+        // An unpaired surrogate isn't valid UTF-16.
+        std::fs::write(&path, [0xff, 0xfe, 0x00, 0xd8]).unwrap();
+        let err = format!("{:#}", read_djoin_blob(&path).unwrap_err());
+        assert!(err.contains("as UTF-16"), "{err}");
+
+        std::fs::write(&path, "\r\n").unwrap();
+        let err = read_djoin_blob(&path).unwrap_err().to_string();
+        assert!(err.contains("is empty"), "{err}");
+
+        assert!(read_djoin_blob(&dir.join("missing.txt")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn splits_account_names() {
+        assert_eq!(split_account("CORP\\u", "d"), ("CORP", "u"));
+        assert_eq!(split_account("u", "d"), ("d", "u"));
+    }
+
+    #[test]
+    fn builds_component_for_each_kind_of_join() {
+        let dir = test_dir("join-component");
+        let runner = RecordingRunner::new();
+        let ui = TestUi { runner: &runner };
+
+        assert_eq!(component_xml(&context(&[]), &ui).unwrap(), None);
+
+        let password = dir.join("password");
+        std::fs::write(&password, "p<w>\n").unwrap();
+        let password_var = format!("file:{password}");
+        let xml = component_xml(
+            &context(&[
+                ("join_domain", "corp.example.com"),
+                ("join_user", "CORP\\joiner"),
+                ("join_password", &password_var),
+                ("join_ou", "OU=Images,DC=corp"),
+                ("arch", "aarch64"),
+            ]),
+            &ui,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(xml.contains("processorArchitecture=\"arm64\""), "{xml}");
+        assert!(
+            xml.contains(
+                "<Credentials><Domain>CORP</Domain>\
+                <Username>joiner</Username>\
+                <Password>p&lt;w&gt;</Password></Credentials>\
+                <JoinDomain>corp.example.com</JoinDomain>\
+                <MachineObjectOU>OU=Images,DC=corp</MachineObjectOU>"
+            ),
+            "{xml}"
+        );
+
+        let err = component_xml(
+            &context(&[("join_domain", "corp"), ("join_user", "joiner")]),
+            &ui,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("requires --join-password"), "{err}");
+
+        let blob = dir.join("blob.txt");
+        std::fs::write(&blob, "AR&A\n").unwrap();
+        let xml = component_xml(
+            &context(&[
+                ("djoin_blob", blob.as_str()),
+                ("join_domain", "corp.example.com"),
+            ]),
+            &ui,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(xml.contains("processorArchitecture=\"amd64\""), "{xml}");
+        assert!(
+            xml.contains(
+                "<Identification><Provisioning>\
+                <AccountData>AR&amp;A</AccountData>\
+                </Provisioning></Identification>"
+            ),
+            "{xml}"
+        );
+        assert!(!xml.contains("<JoinDomain>"), "{xml}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn joins_from_autounattend_only_without_generalization() {
+        let dir = test_dir("join-autounattend");
+        let runner = RecordingRunner::new();
+        let ui = TestUi { runner: &runner };
+        let blob = dir.join("blob.txt");
+        std::fs::write(&blob, "ARAAAAAA").unwrap();
+        let input = dir.join("Autounattend.xml");
+        std::fs::write(
+            &input,
+            "<unattend xmlns=\"urn:schemas-microsoft-com:unattend\">\
+            </unattend>",
+        )
+        .unwrap();
+        let output = dir.join("out.xml");
+
+        let update = |vars: &[(&str, &str)]| {
+            let updater = add_to_autounattend(
+                AutounattendUpdater::new(None, None),
+                &context(vars),
+                &ui,
+            )
+            .unwrap();
+            updater.run(&input, &output).unwrap();
+            std::fs::read_to_string(&output).unwrap()
+        };
+
+        let xml =
+            update(&[("djoin_blob", blob.as_str()), ("skip_generalize", "")]);
+        assert!(xml.contains("<settings pass=\"specialize\">"), "{xml}");
+        assert!(xml.contains(COMPONENT_NAME), "{xml}");
+
+        // A generalized image joins when it's deployed instead, so Setup's
+        // answer file is left alone.
+        let xml = update(&[("djoin_blob", blob.as_str())]);
+        assert!(!xml.contains(COMPONENT_NAME), "{xml}");
+
+        let xml = update(&[("skip_generalize", "")]);
+        assert!(!xml.contains(COMPONENT_NAME), "{xml}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn joins_generalized_images_from_specialize_unattend() {
+        let dir = test_dir("join-deployment");
+        let runner = RecordingRunner::new();
+        let ui = TestUi { runner: &runner };
+        let blob = dir.join("blob.txt");
+        std::fs::write(&blob, "ARAAAAAA").unwrap();
+        let mut ctx = context(&[
+            ("djoin_blob", blob.as_str()),
+            ("unattend_dir", dir.as_str()),
+        ]);
+
+        let err =
+            configure_deployment_join(&mut ctx, &ui).unwrap_err().to_string();
+        assert!(err.contains("doesn't have one"), "{err}");
+
+        let path = dir.join(DEPLOYMENT_ANSWER_FILE);
+        std::fs::write(
+            &path,
+            "<unattend xmlns=\"urn:schemas-microsoft-com:unattend\">\
+            </unattend>",
+        )
+        .unwrap();
+        configure_deployment_join(&mut ctx, &ui).unwrap();
+        let xml = std::fs::read_to_string(&path).unwrap();
+        assert!(xml.contains("<AccountData>ARAAAAAA</AccountData>"), "{xml}");
+        assert_eq!(describe_configure_deployment_join(&mut ctx).len(), 1);
+
+        // Images built with --skip-generalize already joined during setup.
+        ctx.set_var("skip_generalize", String::new());
+        std::fs::remove_file(&path).unwrap();
+        configure_deployment_join(&mut ctx, &ui).unwrap();
+        assert!(!path.exists());
+        assert!(describe_configure_deployment_join(&mut ctx).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Downloads build inputs with `curl`, verifying each against the SHA-256
+//! digest the user pinned for it.
+//!
+//! Downloads are cached in the work directory under the digest they're
+//! expected to have, so later builds that use the same work directory reuse
+//! them instead of fetching them again. Files are downloaded to a `.part` file
+//! next to their final location and only moved into place once their digest
+//! has been checked; an interrupted download is resumed from its `.part` file
+//! by the next build that needs it.
+
+use std::{io::Read, process::Command};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    error::{Error, ErrorKind},
+    hash::{self, Sha256},
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// The name of the work directory's download cache.
+pub const CACHE_DIR_NAME: &str = "downloads";
+
+/// A file to download and the digest it must have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Download {
+    pub url: String,
+
+    /// The expected SHA-256 digest of the file, in lowercase hex.
+    pub sha256: String,
+}
+
+/// Checks that `digest` is a hex-encoded SHA-256 digest, returning it in
+/// lowercase.
+pub fn parse_sha256(digest: &str) -> Result<String> {
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!(
+            "'{digest}' isn't a SHA-256 digest (expected 64 hex digits)"
+        );
+    }
+
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// Returns the SHA-256 digest of the file at `path`, in lowercase hex.
+pub fn file_sha256(path: &Utf8Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("opening '{path}'"))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let read =
+            file.read(&mut buf).with_context(|| format!("reading '{path}'"))?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hash::to_hex(&hasher.finish()))
+}
+
+impl Download {
+    pub fn new(url: &str, sha256: &str) -> Result<Self> {
+        if !url.contains("://") {
+            anyhow::bail!("'{url}' isn't a URL");
+        }
+
+        Ok(Self { url: url.to_string(), sha256: parse_sha256(sha256)? })
+    }
+
+    /// The name of the downloaded file: the last segment of the URL's path.
+    pub fn file_name(&self) -> &str {
+        let url = self.url.split(['?', '#']).next().unwrap_or_default();
+        let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+        rest.split_once('/')
+            .and_then(|(_, path)| path.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("download")
+    }
+
+    /// The path at which this download is cached in `work_dir`.
+    pub fn cached_path(&self, work_dir: &Utf8Path) -> Utf8PathBuf {
+        work_dir.join(CACHE_DIR_NAME).join(&self.sha256).join(self.file_name())
+    }
+
+    fn partial_path(&self, work_dir: &Utf8Path) -> Utf8PathBuf {
+        let path = self.cached_path(work_dir);
+        path.with_file_name(format!("{}.part", self.file_name()))
+    }
+
+    /// Returns the `curl` command that downloads this file to `partial`,
+    /// resuming from whatever `partial` already contains.
+    fn command(&self, partial: &Utf8Path) -> Command {
+        let mut cmd = Command::new("curl");
+        cmd.args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--retry",
+            "3",
+            "--continue-at",
+            "-",
+            "--output",
+            partial.as_str(),
+            &self.url,
+        ]);
+        cmd
+    }
+
+    /// Describes what [`Download::fetch`] would do, for `--dry-run`.
+    pub fn describe(&self, work_dir: &Utf8Path) -> Vec<String> {
+        let path = self.cached_path(work_dir);
+        if path.exists() {
+            return vec![format!("reuse {path} if its SHA-256 digest matches")];
+        }
+
+        vec![
+            format_command(&self.command(&self.partial_path(work_dir))),
+            format!(
+                "check the download's SHA-256 digest and move it to {path}"
+            ),
+        ]
+    }
+
+    /// Makes this file available in `work_dir`'s download cache, downloading
+    /// it if it isn't there already, and returns its path. Fails if the file
+    /// doesn't have the expected digest.
+    pub fn fetch(
+        &self,
+        work_dir: &Utf8Path,
+        ui: &dyn Ui,
+    ) -> Result<Utf8PathBuf> {
+        let path = self.cached_path(work_dir);
+        if path.exists() {
+            ui.set_substep(&format!("verifying cached {}", self.file_name()));
+            if file_sha256(&path)? == self.sha256 {
+                trace::debug!("reusing cached download", path = path.as_str());
+                return Ok(path);
+            }
+
+            ui.warn(&format!(
+                "cached download {path} doesn't have the expected digest; \
+                downloading it again"
+            ));
+            std::fs::remove_file(&path)
+                .with_context(|| format!("removing '{path}'"))?;
+        }
+
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating download cache '{dir}'"))?;
+        let partial = self.partial_path(work_dir);
+        ui.set_substep(&format!("downloading {}", self.url));
+        let result = run_command_check_status(&mut self.command(&partial), ui);
+
+        // A download that finished in an earlier build but wasn't moved into
+        // place leaves nothing to resume, which makes curl fail; that's fine
+        // as long as the digest matches. Other failures keep the partial file
+        // so that the next build can resume from it.
+        if let Err(e) = result {
+            if !partial.exists() || file_sha256(&partial)? != self.sha256 {
+                return Err(e)
+                    .with_context(|| format!("downloading {}", self.url));
+            }
+        }
+
+        let digest = file_sha256(&partial)?;
+        if digest != self.sha256 {
+            // Don't resume from a corrupt file next time.
+            let _ = std::fs::remove_file(&partial);
+            return Err(Error::new(
+                ErrorKind::BadInstallMedia,
+                format!(
+                    "{} has SHA-256 digest {digest}, but {} was expected",
+                    self.url, self.sha256
+                ),
+            )
+            .with_hint(
+                "the download may have been corrupted, or the file at the URL \
+                may have changed; check the URL and the expected digest",
+            )
+            .into());
+        }
+
+        std::fs::rename(&partial, &path)
+            .with_context(|| format!("renaming '{partial}' to '{path}'"))?;
+        trace::debug!("downloaded file", url = self.url.as_str());
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIGEST: &str =
+        "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+
+    #[test]
+    fn names_cached_files() {
+        let download = Download::new(
+            "https://example.com/isos/virtio-win.iso?token=1",
+            DIGEST,
+        )
+        .unwrap();
+        assert_eq!(download.file_name(), "virtio-win.iso");
+        assert_eq!(
+            download.cached_path(Utf8Path::new("/work")),
+            format!("/work/downloads/{}/virtio-win.iso", DIGEST.to_lowercase())
+        );
+
+        for bare in ["https://example.com/", "https://example.com"] {
+            let download = Download::new(bare, DIGEST).unwrap();
+            assert_eq!(download.file_name(), "download");
+        }
+
+        assert!(Download::new("example.com/a.iso", DIGEST).is_err());
+        assert!(Download::new("https://example.com/a.iso", "abc").is_err());
+    }
+}
@@ -0,0 +1,349 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Makes the virtio drivers available to Windows Setup so that Windows can be
+//! installed to, and reach the network through, virtio devices.
+//!
+//! The drivers come from a virtio driver ISO (such as the Fedora project's
+//! virtio-win ISO) or from a local directory laid out the same way, which the
+//! Linux scripts pack into an ISO. Either way, the drivers are attached to the
+//! installation VM as a CD-ROM. Setup can't find a virtio disk to install to
+//! unless it loads the storage drivers in Windows PE, so this module adds a
+//! Microsoft-Windows-PnpCustomizationsWinPE component to Autounattend.xml that
+//! points at each driver on each drive letter the driver disc might be given.
+//! Setup carries the drivers it loads for the installation disk over into the
+//! installed image.
+//!
+//! Instead of naming a driver ISO, users can pin a virtio-win release in a
+//! manifest file, which the `download-virtio-iso` step fetches (see
+//! [`crate::download`]).
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    app::{DiskController, NicModel},
+    autounattend::{Architecture, AutounattendUpdater, WindowsVersion},
+    config::Fields,
+    download::Download,
+    json::Json,
+    runner::Context,
+    ui::Ui,
+};
+
+const COMPONENT_NAME: &str = "Microsoft-Windows-PnpCustomizationsWinPE";
+
+/// The drive letters Windows PE may assign to the driver disc. The letter
+/// depends on how many other CD-ROMs and disks the VM has, so every candidate
+/// is listed; Setup skips paths that don't exist.
+const DRIVER_DISC_LETTERS: [char; 3] = ['D', 'E', 'F'];
+
+/// The version directory the template Autounattend.xml's driver paths use,
+/// which is used in Windows PE too if no Windows version was specified.
+const DEFAULT_VERSION_DIR: &str = "2k22";
+
+/// A virtio driver that Windows PE loads during setup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtioDriver {
+    /// The virtio-blk storage driver.
+    Viostor,
+
+    /// The virtio-scsi storage driver.
+    Vioscsi,
+
+    /// The virtio-net network driver.
+    NetKvm,
+}
+
+impl VirtioDriver {
+    pub const ALL: [VirtioDriver; 3] =
+        [VirtioDriver::Viostor, VirtioDriver::Vioscsi, VirtioDriver::NetKvm];
+
+    /// The name of the driver's top-level directory on a driver disc.
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            VirtioDriver::Viostor => "viostor",
+            VirtioDriver::Vioscsi => "vioscsi",
+            VirtioDriver::NetKvm => "NetKVM",
+        }
+    }
+
+    /// Whether builds that install to a disk on `controller`, in a VM with
+    /// the network adapter `nic`, need this driver. The virtio-scsi driver is
+    /// only needed to install to a virtio-scsi disk, so driver sources
+    /// without it still work for the default configuration, and NetKVM is
+    /// only needed for a virtio-net adapter.
+    pub fn required(&self, controller: DiskController, nic: NicModel) -> bool {
+        match self {
+            VirtioDriver::Viostor => true,
+            VirtioDriver::Vioscsi => controller == DiskController::VirtioScsi,
+            VirtioDriver::NetKvm => nic == NicModel::VirtioNet,
+        }
+    }
+
+    /// The path, relative to the root of a driver disc or directory, of the
+    /// driver's files for `version` on `arch`.
+    pub fn relative_dir(
+        &self,
+        version: Option<WindowsVersion>,
+        arch: Architecture,
+    ) -> String {
+        let version = version
+            .map(|v| v.as_driver_path_component())
+            .unwrap_or(DEFAULT_VERSION_DIR);
+        format!("{}/{version}/{}", self.dir_name(), arch.driver_dir())
+    }
+}
+
+/// Returns the driver paths Windows PE should search for drivers for
+/// `version` on `arch`, in the order they're listed in the answer file.
+fn winpe_driver_paths(
+    version: Option<WindowsVersion>,
+    arch: Architecture,
+) -> Vec<String> {
+    let mut paths = Vec::new();
+    for letter in DRIVER_DISC_LETTERS {
+        for driver in VirtioDriver::ALL {
+            let dir = driver.relative_dir(version, arch).replace('/', "\\");
+            paths.push(format!("{letter}:\\{dir}"));
+        }
+    }
+    paths
+}
+
+/// Builds the component that makes Windows PE load the virtio drivers for
+/// `version` on `arch` from the driver disc.
+fn component_xml(
+    version: Option<WindowsVersion>,
+    arch: Architecture,
+) -> String {
+    let paths: String = winpe_driver_paths(version, arch)
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            format!(
+                "<PathAndCredentials wcm:action=\"add\" wcm:keyValue=\"{}\">\
+                <Path>{path}</Path></PathAndCredentials>",
+                i + 1
+            )
+        })
+        .collect();
+
+    format!(
+        "<component name=\"{COMPONENT_NAME}\" processorArchitecture=\"{}\" \
+        publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" \
+        versionScope=\"nonSxS\" xmlns=\"urn:schemas-microsoft-com:unattend\" \
+        xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\">\
+        <DriverPaths>{paths}</DriverPaths></component>",
+        arch.windows_name()
+    )
+}
+
+/// Adds the component that loads the virtio drivers for `version` in Windows
+/// PE to the windowsPE pass that `updater` writes, unless the user asked to
+/// keep the answer file's own driver settings.
+pub fn add_to_autounattend(
+    updater: AutounattendUpdater,
+    ctx: &Context,
+    version: Option<WindowsVersion>,
+) -> Result<AutounattendUpdater> {
+    if ctx.get_var("skip_winpe_drivers").is_some() {
+        return Ok(updater);
+    }
+
+    let arch = crate::steps::architecture(ctx);
+    updater.with_component(
+        "windowsPE",
+        COMPONENT_NAME,
+        &component_xml(version, arch),
+    )
+}
+
+/// Checks that `dir` contains the drivers for `version` on `arch`, returning
+/// errors for missing drivers that builds installing to a disk on
+/// `controller`, with the network adapter `nic`, need and warnings for other
+/// missing drivers.
+pub fn check_driver_dir(
+    dir: &Utf8Path,
+    version: Option<WindowsVersion>,
+    arch: Architecture,
+    controller: DiskController,
+    nic: NicModel,
+) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    if !dir.is_dir() {
+        errors.push(format!("virtio driver directory '{dir}' not found"));
+        return (errors, warnings);
+    }
+
+    for driver in VirtioDriver::ALL {
+        let driver_dir = dir.join(driver.relative_dir(version, arch));
+        if has_inf_file(&driver_dir) {
+            continue;
+        }
+
+        let message = format!(
+            "virtio driver directory '{dir}' has no {} driver (expected an \
+            .inf file in '{driver_dir}')",
+            driver.dir_name()
+        );
+        if driver.required(controller, nic) {
+            errors.push(message);
+        } else {
+            warnings.push(message);
+        }
+    }
+
+    (errors, warnings)
+}
+
+fn has_inf_file(dir: &Utf8Path) -> bool {
+    let Ok(entries) = dir.read_dir_utf8() else {
+        return false;
+    };
+
+    entries.flatten().any(|entry| {
+        entry
+            .path()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("inf"))
+    })
+}
+
+/// A virtio-win driver ISO release pinned by a manifest file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtioManifest {
+    /// The release's version, e.g. "0.1.266".
+    pub version: String,
+    pub download: Download,
+}
+
+impl VirtioManifest {
+    /// Reads a manifest from `path`. Manifests are TOML files with a
+    /// `[virtio-win]` table:
+    ///
+    /// ```toml
+    /// [virtio-win]
+    /// version = "0.1.266"
+    /// url = "https://example.com/virtio-win-0.1.266.iso"
+    /// sha256 = "<64 hex digits>"
+    /// ```
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading virtio manifest '{path}'"))?;
+        Self::from_str(&contents)
+            .with_context(|| format!("parsing virtio manifest '{path}'"))
+    }
+
+    fn from_str(contents: &str) -> Result<Self> {
+        let table = crate::config::parse(contents)?;
+        let mut fields = Fields::new(&table, "");
+        let Some(mut release) = fields.table("virtio-win")? else {
+            anyhow::bail!("manifest has no [virtio-win] table");
+        };
+
+        let version = release.required_string("version")?;
+        let download = Download::new(
+            &release.required_string("url")?,
+            &release.required_string("sha256")?,
+        )?;
+        release.finish()?;
+        fields.finish()?;
+        Ok(Self { version, download })
+    }
+}
+
+/// Downloads the driver ISO pinned by the manifest named by the
+/// `virtio_iso_manifest` context variable, or reuses the copy cached by an
+/// earlier build, and points the `virtio_iso` variable at it. Does nothing if
+/// no manifest was supplied.
+pub fn download_virtio_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(manifest) = ctx.get_var("virtio_iso_manifest") else {
+        return Ok(());
+    };
+
+    let manifest = VirtioManifest::load(Utf8Path::new(manifest))?;
+    let work_dir = Utf8Path::new(ctx.get_var("work_dir").unwrap());
+    let iso = manifest.download.fetch(work_dir, ui)?;
+    ui.record_metric("virtio_win_version", Json::from(manifest.version));
+    ctx.set_var("virtio_iso", iso.to_string());
+    Ok(())
+}
+
+/// Describes what [`download_virtio_iso`] would do.
+pub fn describe_download_virtio_iso(ctx: &mut Context) -> Vec<String> {
+    let Some(path) = ctx.get_var("virtio_iso_manifest") else {
+        return Vec::new();
+    };
+
+    let manifest = match VirtioManifest::load(Utf8Path::new(path)) {
+        Ok(manifest) => manifest,
+        Err(e) => return vec![format!("can't read the manifest: {e:#}")],
+    };
+
+    let work_dir = Utf8Path::new(ctx.get_var("work_dir").unwrap());
+    let lines = manifest.download.describe(work_dir);
+    ctx.set_var(
+        "virtio_iso",
+        manifest.download.cached_path(work_dir).to_string(),
+    );
+    lines
+}
+
+/// Returns the path at which the Linux scripts create an ISO from a local
+/// driver directory.
+pub fn driver_iso_path(work_dir: &Utf8Path) -> Utf8PathBuf {
+    crate::workspace::isos_dir(work_dir).join("virtio-drivers.iso")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lists_winpe_driver_paths() {
+        let paths = winpe_driver_paths(
+            Some(WindowsVersion::Server2019),
+            Architecture::X86_64,
+        );
+        assert_eq!(paths.len(), 9);
+        assert_eq!(paths[0], "D:\\viostor\\2k19\\amd64");
+        assert_eq!(paths[1], "D:\\vioscsi\\2k19\\amd64");
+        assert_eq!(paths[8], "F:\\NetKVM\\2k19\\amd64");
+        assert_eq!(
+            winpe_driver_paths(None, Architecture::X86_64)[2],
+            "D:\\NetKVM\\2k22\\amd64"
+        );
+        assert_eq!(
+            winpe_driver_paths(
+                Some(WindowsVersion::Windows11),
+                Architecture::Aarch64
+            )[0],
+            "D:\\viostor\\w11\\ARM64"
+        );
+    }
+
+    #[test]
+    fn reads_manifests() {
+        let digest = "ab".repeat(32);
+        let manifest = VirtioManifest::from_str(&format!(
+            "[virtio-win]\nversion = \"0.1.266\"\n\
+            url = \"https://example.com/virtio-win.iso\"\n\
+            sha256 = \"{digest}\"\n"
+        ))
+        .unwrap();
+        assert_eq!(manifest.version, "0.1.266");
+        assert_eq!(manifest.download.sha256, digest);
+
+        for bad in [
+            "version = \"0.1.266\"",
+            "[virtio-win]\nversion = \"1\"\nurl = \"https://a/b\"",
+            "[virtio-win]\nversion = \"1\"\nurl = \"https://a/b\"\n\
+            sha256 = \"abc\"",
+        ] {
+            assert!(VirtioManifest::from_str(bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Classes of build failure that have a known fix.
+//!
+//! Most failures are plain [`anyhow`] errors. Those whose cause is usually one
+//! of a few things, like a missing tool or a Setup that never finished, carry
+//! an [`Error`] somewhere in their chain instead, which says what class of
+//! failure it was and what to do about it. The wimsy command prints the hint
+//! after the error and exits with the class's [`ErrorKind::exit_code`], so that
+//! scripts can tell the classes apart.
+
+/// A class of failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The host or the build's options don't meet a prerequisite that isn't
+    /// covered by a more specific class.
+    Prerequisite,
+
+    /// A tool the build runs isn't installed.
+    MissingTool,
+
+    /// The requested accelerator (KVM, usually) can't be used.
+    AccelUnavailable,
+
+    /// The installation media isn't Windows installation media, or is
+    /// corrupt.
+    BadInstallMedia,
+
+    /// Windows Setup didn't finish in time, or stopped making progress.
+    SetupTimeout,
+
+    /// The installed image's partition table couldn't be read.
+    PartitionTable,
+
+    /// The build was stopped before it finished, e.g. at a `--pause-after`
+    /// prompt.
+    Cancelled,
+}
+
+impl ErrorKind {
+    /// The exit code of builds that fail this way. Other failures exit with
+    /// 1, and invalid command lines with 2.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Prerequisite => 3,
+            ErrorKind::MissingTool => 4,
+            ErrorKind::AccelUnavailable => 5,
+            ErrorKind::BadInstallMedia => 6,
+            ErrorKind::SetupTimeout => 7,
+            ErrorKind::PartitionTable => 8,
+            // The status of a build cancelled with Ctrl-C.
+            ErrorKind::Cancelled => 128 + libc::SIGINT as u8,
+        }
+    }
+
+    /// The class's name in progress events and the build report.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Prerequisite => "prerequisite",
+            ErrorKind::MissingTool => "missing_tool",
+            ErrorKind::AccelUnavailable => "accel_unavailable",
+            ErrorKind::BadInstallMedia => "bad_install_media",
+            ErrorKind::SetupTimeout => "setup_timeout",
+            ErrorKind::PartitionTable => "partition_table",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    /// What to do about failures of this class, if the error doesn't say
+    /// anything more specific.
+    fn hint(self) -> &'static str {
+        match self {
+            ErrorKind::Prerequisite => {
+                "fix the problems listed above; the doctor command checks the \
+                rest of the host's setup"
+            }
+            ErrorKind::MissingTool => {
+                "install the missing tools (install_prerequisites.sh installs \
+                them on Debian-based hosts), or pass --tools-container to run \
+                qemu-img and sgdisk in a container"
+            }
+            ErrorKind::AccelUnavailable => {
+                "add your user to the kvm group and log in again (or load the \
+                kvm module, or enable virtualization in the host's firmware), \
+                or pass --accel tcg to build without KVM"
+            }
+            ErrorKind::BadInstallMedia => {
+                "check that the ISO is Windows installation media for the \
+                right architecture; if it was downloaded, download it again \
+                and check its SHA-256 digest"
+            }
+            ErrorKind::SetupTimeout => {
+                "look at the serial log and screenshots in the work directory \
+                to see where Setup stopped; if it was only slow, raise \
+                --install-timeout or --hang-timeout"
+            }
+            ErrorKind::PartitionTable => {
+                "Setup may not have partitioned the disk the way the unattend \
+                files ask; check the DiskConfiguration in Autounattend.xml and \
+                the sgdisk logs in the work directory"
+            }
+            ErrorKind::Cancelled => {
+                "pass --resume to continue from the last step that completed"
+            }
+        }
+    }
+}
+
+/// A classified failure.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    hint: String,
+}
+
+impl Error {
+    /// Returns an error of class `kind` that says `message`, with the class's
+    /// usual hint.
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into(), hint: kind.hint().to_string() }
+    }
+
+    /// Replaces the error's hint with `hint`.
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = hint.into();
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn hint(&self) -> &str {
+        &self.hint
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns the classified failure behind `error`, if it's one. Either `error`
+/// or any of its causes can be an [`Error`], or [`Error`] can have been
+/// attached to it as context.
+pub fn classify(error: &anyhow::Error) -> Option<&Error> {
+    error
+        .downcast_ref::<Error>()
+        .or_else(|| error.chain().find_map(|cause| cause.downcast_ref()))
+}
+
+/// Returns the exit code for a run that failed with `error`.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    classify(error).map_or(1, |error| error.kind.exit_code())
+}
+
+/// Returns the Debian package that provides `tool`, if it isn't named after
+/// the tool.
+pub fn package_for_tool(tool: &str) -> Option<&'static str> {
+    Some(match tool {
+        "sgdisk" => "gdisk",
+        "qemu-img" | "qemu-nbd" => "qemu-utils",
+        "qemu-system-x86_64" => "qemu-system-x86",
+        "qemu-system-aarch64" => "qemu-system-arm",
+        "virsh" => "libvirt-clients",
+        "aws" => "awscli",
+        "ssh-keygen" => "openssh-client",
+        _ => return None,
+    })
+}
+
+/// Returns the error for a build that needs `tools`, which aren't installed.
+pub fn missing_tools(tools: &[&str]) -> Error {
+    let error = Error::new(
+        ErrorKind::MissingTool,
+        format!("required tools aren't installed: {}", tools.join(", ")),
+    );
+    let packages: Vec<String> = tools
+        .iter()
+        .map(|tool| match package_for_tool(tool) {
+            Some(package) => format!("{package} (for {tool})"),
+            None => tool.to_string(),
+        })
+        .collect();
+    let mut hint = format!(
+        "install {} (install_prerequisites.sh installs the tools builds need \
+        on Debian-based hosts)",
+        packages.join(", ")
+    );
+    if tools.iter().all(|tool| crate::command::CONTAINER_TOOLS.contains(tool)) {
+        hint.push_str(", or pass --tools-container to run them in a container");
+    }
+    error.with_hint(hint)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context as _;
+
+    #[test]
+    fn classifies_errors_through_context() {
+        let error: anyhow::Error =
+            Error::new(ErrorKind::SetupTimeout, "Setup hung").into();
+        let error = error.context("installing Windows");
+        assert_eq!(classify(&error).unwrap().kind(), ErrorKind::SetupTimeout);
+        assert_eq!(exit_code(&error), 7);
+
+        let error = Err::<(), _>(std::io::Error::other("bad superblock"))
+            .context(Error::new(ErrorKind::PartitionTable, "no table"))
+            .context("shrinking the image")
+            .unwrap_err();
+        assert_eq!(classify(&error).unwrap().kind(), ErrorKind::PartitionTable);
+
+        let error: anyhow::Error =
+            Error::new(ErrorKind::Cancelled, "stopped").into();
+        assert_eq!(exit_code(&error), 130);
+
+        assert!(classify(&anyhow::anyhow!("something else")).is_none());
+        assert_eq!(exit_code(&anyhow::anyhow!("something else")), 1);
+    }
+
+    #[test]
+    fn suggests_packages_for_missing_tools() {
+        let error = missing_tools(&["sgdisk", "qemu-img", "swtpm"]);
+        assert_eq!(
+            error.to_string(),
+            "required tools aren't installed: sgdisk, qemu-img, swtpm"
+        );
+        assert!(error.hint().starts_with(
+            "install gdisk (for sgdisk), qemu-utils (for qemu-img), swtpm ("
+        ));
+        assert!(!error.hint().contains("--tools-container"));
+        assert!(missing_tools(&["sgdisk"])
+            .hint()
+            .contains("--tools-container"));
+    }
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Windows features to add to or remove from the image, from
+//! `--windows-feature` and `--remove-feature` or the configuration file's
+//! `provision.windows_features` and `provision.remove_features`.
+//!
+//! `OxidePrepBaseImage.ps1` changes them before it runs any provisioning
+//! scripts. A name can be a server role or feature (as `Install-WindowsFeature`
+//! knows them, e.g. `Web-Server`) or an optional feature (as DISM knows them,
+//! e.g. `NetFx3`); the script looks for a role or feature first on Windows
+//! Server, where both kinds exist.
+
+/// The context variable listing the features to add, separated by newlines.
+pub const WINDOWS_FEATURES_VAR: &str = "windows_features";
+
+/// The context variable listing the features to remove, separated by
+/// newlines.
+pub const REMOVE_FEATURES_VAR: &str = "remove_features";
+
+/// The name of a Windows feature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowsFeature(String);
+
+impl std::str::FromStr for WindowsFeature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-._".contains(c);
+        if s.is_empty() || !s.chars().all(valid) {
+            return Err(format!(
+                "'{s}' isn't a feature name like NetFx3 or Web-Server"
+            ));
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for WindowsFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks that no feature is both added and removed, returning a message for
+/// each one that is.
+pub fn check_prerequisites(
+    add: &[WindowsFeature],
+    remove: &[WindowsFeature],
+) -> Vec<String> {
+    add.iter()
+        .filter(|feature| {
+            remove.iter().any(|r| r.0.eq_ignore_ascii_case(&feature.0))
+        })
+        .map(|feature| {
+            format!("the Windows feature {feature} is both added and removed")
+        })
+        .collect()
+}
+
+/// Returns the encoding of `features` in a features variable.
+pub fn features_var(features: &[WindowsFeature]) -> String {
+    itertools::join(features, "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_feature_names() {
+        for name in ["NetFx3", "Web-Server", "Microsoft-Hyper-V-All", "A_B.1"] {
+            assert!(name.parse::<WindowsFeature>().is_ok(), "{name}");
+        }
+        for name in ["", "Web Server", "NetFx3;calc", "'x'"] {
+            assert!(name.parse::<WindowsFeature>().is_err(), "{name}");
+        }
+
+        let features = |names: &[&str]| -> Vec<WindowsFeature> {
+            names.iter().map(|name| name.parse().unwrap()).collect()
+        };
+        let add = features(&["Containers", "NetFx3"]);
+        assert!(
+            check_prerequisites(&add, &features(&["XPS-Viewer"])).is_empty()
+        );
+        assert_eq!(
+            check_prerequisites(&add, &features(&["netfx3"])),
+            ["the Windows feature NetFx3 is both added and removed"]
+        );
+        assert_eq!(features_var(&add), "Containers\nNetFx3");
+    }
+}
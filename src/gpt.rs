@@ -0,0 +1,460 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads the primary GUID partition table from a raw disk image.
+//!
+//! `sgdisk` is still used to change partition tables, but its text output
+//! varies between versions (and renders partition names that aren't plain
+//! ASCII inconsistently), so steps that read partition tables use this parser
+//! instead. Partition names are stored as UTF-16 in the GPT and are decoded
+//! here. The secondary table at the end of the disk is only checked, with
+//! [`check_secondary`], not read.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The sector sizes to look for a GPT header with. The header is always in
+/// the second logical block, so its offset reveals the sector size.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// The size of the fields this parser reads from each partition entry.
+const MIN_ENTRY_SIZE: u32 = 128;
+
+/// The largest partition entry array this parser will read. The UEFI
+/// specification requires at least 128 entries; real tables don't approach
+/// this limit.
+const MAX_ENTRY_ARRAY_BYTES: u64 = 1024 * 1024;
+
+/// A GUID as stored in a GPT: the first three fields are little-endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The "Microsoft basic data" partition type, used for NTFS and FAT
+    /// volumes.
+    pub const BASIC_DATA: Guid = Guid([
+        0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6,
+        0xb7, 0x26, 0x99, 0xc7,
+    ]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl std::str::FromStr for Guid {
+    type Err = anyhow::Error;
+
+    /// Parses a GUID in its usual textual form, e.g.
+    /// `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`.
+    fn from_str(s: &str) -> Result<Self> {
+        let hex: String = s.chars().filter(|&c| c != '-').collect();
+        let groups: Vec<usize> = s.split('-').map(str::len).collect();
+        if groups != [8, 4, 4, 4, 12]
+            || !hex.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            anyhow::bail!("'{s}' isn't a GUID");
+        }
+
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .with_context(|| format!("'{s}' isn't a GUID"))?;
+        }
+
+        // The first three fields are stored little-endian.
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Ok(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A used entry in a partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// The partition's 1-based number, i.e. its index in the partition entry
+    /// array plus one. This is the number `sgdisk` uses.
+    pub number: u32,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl Partition {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+}
+
+/// A disk's partition table.
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub sector_size: u64,
+    pub disk_guid: Guid,
+    pub partitions: Vec<Partition>,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid_at(data: &[u8], offset: usize) -> Guid {
+    Guid(data[offset..offset + 16].try_into().unwrap())
+}
+
+/// Decodes a partition name: up to 36 UTF-16LE code units, terminated by a
+/// NUL if shorter. Invalid code units decode to U+FFFD.
+fn decode_name(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take_while(|&unit| unit != 0);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Computes the CRC-32 (the one zlib uses) of `data`, as GPT headers record
+/// for themselves and their partition entry arrays.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc =
+                if crc & 1 == 0 { crc >> 1 } else { (crc >> 1) ^ 0xedb8_8320 };
+        }
+    }
+    !crc
+}
+
+/// Returns whether `header`'s CRC field matches the header's contents.
+fn header_crc_matches(header: &[u8]) -> bool {
+    let size = u32_at(header, 12) as usize;
+    if !(92..=header.len()).contains(&size) {
+        return false;
+    }
+
+    let mut header = header[..size].to_vec();
+    let recorded = u32_at(&header, 16);
+    header[16..20].fill(0);
+    crc32(&header) == recorded
+}
+
+/// Finds the primary GPT header on `disk`, returning the disk's sector size
+/// (as revealed by the header's offset) and the sector holding the header.
+fn read_primary_header<R: Read + Seek>(disk: &mut R) -> Result<(u64, Vec<u8>)> {
+    for size in SECTOR_SIZES {
+        let mut header = vec![0u8; size as usize];
+        disk.seek(SeekFrom::Start(size))?;
+        if disk.read_exact(&mut header).is_ok() && &header[..8] == SIGNATURE {
+            return Ok((size, header));
+        }
+    }
+
+    anyhow::bail!("no GPT header found");
+}
+
+/// Reads the primary partition table from `disk`.
+pub fn read<R: Read + Seek>(disk: &mut R) -> Result<PartitionTable> {
+    let (sector_size, header) = read_primary_header(disk)?;
+
+    let disk_guid = guid_at(&header, 56);
+    let entries_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80);
+    let entry_size = u32_at(&header, 84);
+    if entry_size < MIN_ENTRY_SIZE || !entry_size.is_multiple_of(8) {
+        anyhow::bail!(
+            "GPT header has invalid partition entry size {entry_size}"
+        );
+    }
+
+    let array_bytes = u64::from(entry_count) * u64::from(entry_size);
+    if array_bytes > MAX_ENTRY_ARRAY_BYTES {
+        anyhow::bail!(
+            "GPT header describes an implausibly large partition entry array \
+            ({entry_count} entries of {entry_size} bytes)"
+        );
+    }
+
+    let mut entries = vec![0u8; array_bytes as usize];
+    disk.seek(SeekFrom::Start(entries_lba * sector_size))?;
+    disk.read_exact(&mut entries).context("reading GPT partition entries")?;
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size as usize).enumerate()
+    {
+        let type_guid = guid_at(entry, 0);
+        if type_guid.is_zero() {
+            continue;
+        }
+
+        let number = index as u32 + 1;
+        let first_lba = u64_at(entry, 32);
+        let last_lba = u64_at(entry, 40);
+        if last_lba < first_lba {
+            anyhow::bail!(
+                "GPT partition {number} ends (sector {last_lba}) before it \
+                starts (sector {first_lba})"
+            );
+        }
+
+        partitions.push(Partition {
+            number,
+            type_guid,
+            unique_guid: guid_at(entry, 16),
+            first_lba,
+            last_lba,
+            attributes: u64_at(entry, 48),
+            name: decode_name(&entry[56..128]),
+        });
+    }
+
+    Ok(PartitionTable { sector_size, disk_guid, partitions })
+}
+
+/// Checks that `disk`'s secondary GPT is in its last sector, where the primary
+/// header says it is, and that it's intact and describes the same partitions
+/// as the primary. It usually isn't after a disk image is resized without the
+/// table being repaired (e.g. with `sgdisk -e`).
+pub fn check_secondary<R: Read + Seek>(disk: &mut R) -> Result<()> {
+    let (sector_size, primary) = read_primary_header(disk)?;
+    let last_lba = disk.seek(SeekFrom::End(0))? / sector_size - 1;
+    let backup_lba = u64_at(&primary, 32);
+    if backup_lba != last_lba {
+        anyhow::bail!(
+            "the primary GPT header puts the secondary one in sector \
+            {backup_lba}, but the disk's last sector is {last_lba}"
+        );
+    }
+
+    let mut backup = vec![0u8; sector_size as usize];
+    disk.seek(SeekFrom::Start(backup_lba * sector_size))?;
+    disk.read_exact(&mut backup).context("reading secondary GPT header")?;
+    if &backup[..8] != SIGNATURE {
+        anyhow::bail!("there's no GPT header in the disk's last sector");
+    }
+    if !header_crc_matches(&backup) || u64_at(&backup, 24) != backup_lba {
+        anyhow::bail!("the secondary GPT header is corrupt");
+    }
+
+    let entries_crc = u32_at(&backup, 88);
+    if entries_crc != u32_at(&primary, 88) {
+        anyhow::bail!(
+            "the secondary GPT's partition entries differ from the primary's"
+        );
+    }
+
+    let array_bytes =
+        u64::from(u32_at(&backup, 80)) * u64::from(u32_at(&backup, 84));
+    if array_bytes > MAX_ENTRY_ARRAY_BYTES {
+        anyhow::bail!("the secondary GPT header is corrupt");
+    }
+    let mut entries = vec![0u8; array_bytes as usize];
+    disk.seek(SeekFrom::Start(u64_at(&backup, 72) * sector_size))?;
+    disk.read_exact(&mut entries)
+        .context("reading secondary GPT partition entries")?;
+    if crc32(&entries) != entries_crc {
+        anyhow::bail!("the secondary GPT's partition entries are corrupt");
+    }
+
+    Ok(())
+}
+
+/// Reads the primary partition table from the raw disk image at `path`.
+pub fn read_image(path: &Utf8Path) -> Result<PartitionTable> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("opening disk image '{path}'"))?;
+    read(&mut file)
+        .with_context(|| format!("reading partition table from '{path}'"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASIC_DATA: [u8; 16] = Guid::BASIC_DATA.0;
+
+    /// Builds a disk image with 512-byte sectors and a primary GPT holding
+    /// partitions with the supplied names and type GUIDs, each 8 sectors
+    /// long.
+    fn disk_with_partitions(parts: &[(&str, [u8; 16])]) -> Vec<u8> {
+        const SECTOR: usize = 512;
+        const ENTRIES: usize = 128;
+        let mut disk = vec![0u8; SECTOR * (2 + ENTRIES * 128 / SECTOR)];
+        let header = &mut disk[SECTOR..2 * SECTOR];
+        header[..8].copy_from_slice(SIGNATURE);
+        header[56..72].copy_from_slice(&[0x11; 16]);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+        for (i, (name, type_guid)) in parts.iter().enumerate() {
+            let offset = 2 * SECTOR + i * 128;
+            let entry = &mut disk[offset..offset + 128];
+            entry[..16].copy_from_slice(type_guid);
+            entry[16..32].copy_from_slice(&[i as u8 + 1; 16]);
+            let first = 2048 + 8 * i as u64;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&(first + 7).to_le_bytes());
+            let name: Vec<u8> =
+                name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            entry[56..56 + name.len()].copy_from_slice(&name);
+        }
+
+        disk
+    }
+
+    #[test]
+    fn reads_partition_table() {
+        let mut disk = disk_with_partitions(&[
+            ("Basic data partition", BASIC_DATA),
+            ("Données de base", BASIC_DATA),
+            ("回復 🛟", [0x22; 16]),
+        ]);
+
+        // Leave a hole in the table; partition numbers follow entry indices.
+        let unused = 2 * 512 + 128;
+        disk[unused..unused + 16].fill(0);
+
+        let table = read(&mut std::io::Cursor::new(&disk)).unwrap();
+        assert_eq!(table.sector_size, 512);
+        let names: Vec<(u32, &str)> = table
+            .partitions
+            .iter()
+            .map(|p| (p.number, p.name.as_str()))
+            .collect();
+        assert_eq!(names, [(1, "Basic data partition"), (3, "回復 🛟")]);
+        assert_eq!(table.partitions[1].first_lba, 2064);
+        assert_eq!(table.partitions[1].sectors(), 8);
+        assert_eq!(
+            table.partitions[0].type_guid.to_string(),
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+        );
+
+        assert!(read(&mut std::io::Cursor::new(vec![0u8; 8192])).is_err());
+    }
+
+    /// Fills in the CRCs of `disk`'s primary GPT and appends a secondary GPT
+    /// that matches it.
+    fn add_secondary(disk: &mut Vec<u8>) {
+        const SECTOR: usize = 512;
+        let entries = disk[2 * SECTOR..].to_vec();
+        let entry_sectors = (entries.len() / SECTOR) as u64;
+        let backup_lba = (disk.len() / SECTOR) as u64 + entry_sectors;
+
+        let mut header = disk[SECTOR..SECTOR + 92].to_vec();
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&1u64.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let mut backup = header.clone();
+        backup[24..32].copy_from_slice(&backup_lba.to_le_bytes());
+        backup[32..40].copy_from_slice(&1u64.to_le_bytes());
+        backup[72..80]
+            .copy_from_slice(&(backup_lba - entry_sectors).to_le_bytes());
+        for header in [&mut header, &mut backup] {
+            let crc = crc32(header);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+        }
+
+        disk[SECTOR..SECTOR + 92].copy_from_slice(&header);
+        disk.extend_from_slice(&entries);
+        backup.resize(SECTOR, 0);
+        disk.extend_from_slice(&backup);
+    }
+
+    #[test]
+    fn checks_secondary_table() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut disk = disk_with_partitions(&[("OS", BASIC_DATA)]);
+        add_secondary(&mut disk);
+        let check = |disk: &[u8]| {
+            check_secondary(&mut std::io::Cursor::new(disk))
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(check(&disk), Ok(()));
+
+        // Growing the disk leaves the secondary table behind.
+        let mut grown = disk.clone();
+        grown.resize(disk.len() + 512, 0);
+        assert!(check(&grown).unwrap_err().contains("last sector is"));
+
+        let last = disk.len() - 512;
+        let mut stale = disk.clone();
+        stale[last - 512 + 56] ^= 1;
+        assert!(check(&stale).unwrap_err().contains("entries are corrupt"));
+
+        let mut corrupt = disk;
+        corrupt[last + 60] ^= 1;
+        assert!(check(&corrupt).unwrap_err().contains("header is corrupt"));
+    }
+
+    #[test]
+    fn parses_guids() {
+        let text = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+        let guid: Guid = text.parse().unwrap();
+        assert_eq!(guid, Guid::BASIC_DATA);
+        assert_eq!(guid.to_string(), text);
+        assert_eq!(
+            "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7".parse::<Guid>().unwrap(),
+            Guid::BASIC_DATA
+        );
+
+        for bad in [
+            "",
+            "EBD0A0A2B9E5443387C068B6B72699C7",
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699CZ",
+        ] {
+            assert!(bad.parse::<Guid>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_names() {
+        let encode = |units: &[u16]| -> Vec<u8> {
+            let mut data: Vec<u8> =
+                units.iter().flat_map(|u| u.to_le_bytes()).collect();
+            data.resize(72, 0);
+            data
+        };
+
+        assert_eq!(decode_name(&encode(&[0x52, 0xe9, 0x63])), "Réc");
+        assert_eq!(decode_name(&encode(&[0xd83d, 0xdee0])), "🛠");
+
+        // An unpaired surrogate doesn't hide the rest of the name.
+        assert_eq!(decode_name(&encode(&[0xd83d, 0x41])), "\u{fffd}A");
+
+        // Names that fill the field have no terminator.
+        assert_eq!(decode_name(&encode(&[0x41; 36])), "A".repeat(36));
+    }
+}
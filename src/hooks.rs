@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the commands a configuration file's `[hooks]` table names around each
+//! step, so that builds can send notifications, check a step's results, or
+//! copy its outputs somewhere without a step of their own.
+//!
+//! Hooks see the same `WIMSY_`-prefixed context variables as user-supplied
+//! steps, along with:
+//!
+//! - `WIMSY_HOOK`: `pre_step`, `post_step`, or `on_failure`;
+//! - `WIMSY_STEP`, `WIMSY_STEP_LABEL`, and `WIMSY_STEP_NUMBER`: the step's
+//!   name, label, and (1-based) position in the plan;
+//! - `WIMSY_LOGS_DIR`: the directory holding the build's logs; and
+//! - `WIMSY_ERROR`: for `on_failure` hooks, why the step failed.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{
+    config::StepHooks,
+    plan::pass_context,
+    runner::{Context, Step},
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// A step with hooks run around it.
+pub struct HookedStep<'a> {
+    step: &'a dyn Step,
+
+    /// The step's 1-based position in the plan.
+    number: usize,
+
+    /// The hooks to run, if any run around this step.
+    hooks: Option<&'a StepHooks>,
+
+    /// The tools the step requires, plus the shell if it has hooks.
+    tools: Vec<&'static str>,
+}
+
+/// Returns `steps`, in order, with `hooks` run around those they apply to.
+/// Fails if `hooks` names steps that aren't among them.
+pub fn attach<'a>(
+    steps: &[&'a dyn Step],
+    hooks: &'a StepHooks,
+) -> Result<Vec<HookedStep<'a>>> {
+    for name in &hooks.steps {
+        if !steps.iter().any(|step| step.name() == name) {
+            anyhow::bail!(
+                "the configuration's hooks name step '{name}', which isn't in \
+                the plan"
+            );
+        }
+    }
+
+    Ok(steps
+        .iter()
+        .enumerate()
+        .map(|(i, &step)| {
+            let hooks = hooks.apply_to(step.name()).then_some(hooks);
+            let mut tools = step.required_tools().to_vec();
+            if hooks.is_some() && !tools.contains(&"sh") {
+                tools.push("sh");
+            }
+            HookedStep { step, number: i + 1, hooks, tools }
+        })
+        .collect())
+}
+
+impl HookedStep<'_> {
+    fn command(&self, hook: &str, command: &str, ctx: &Context) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        pass_context(&mut cmd, ctx);
+        cmd.env("WIMSY_HOOK", hook)
+            .env("WIMSY_STEP", self.step.name())
+            .env("WIMSY_STEP_LABEL", self.step.label())
+            .env("WIMSY_STEP_NUMBER", self.number.to_string());
+        if let Some(work_dir) = ctx.get_var("work_dir") {
+            cmd.env(
+                "WIMSY_LOGS_DIR",
+                crate::workspace::logs_dir(Utf8Path::new(work_dir)),
+            );
+        }
+        cmd
+    }
+
+    fn run(
+        &self,
+        hook: &str,
+        command: &str,
+        error: Option<&anyhow::Error>,
+        ctx: &Context,
+        ui: &dyn Ui,
+    ) -> Result<()> {
+        ui.set_substep(&format!("running {hook} hook"));
+        let mut cmd = self.command(hook, command, ctx);
+        if let Some(error) = error {
+            cmd.env("WIMSY_ERROR", format!("{error:#}"));
+        }
+        run_command_check_status(&mut cmd, ui)
+            .with_context(|| format!("running {hook} hook"))?;
+        Ok(())
+    }
+}
+
+impl Step for HookedStep<'_> {
+    fn name(&self) -> &str {
+        self.step.name()
+    }
+
+    fn label(&self) -> &str {
+        self.step.label()
+    }
+
+    fn required_tools(&self) -> &[&'static str] {
+        &self.tools
+    }
+
+    fn provided_vars(&self) -> &[&'static str] {
+        self.step.provided_vars()
+    }
+
+    fn retryable(&self) -> bool {
+        self.step.retryable()
+    }
+
+    fn dry_run(&self, ctx: &mut Context) -> Vec<String> {
+        let Some(hooks) = self.hooks else {
+            return self.step.dry_run(ctx);
+        };
+
+        let describe = |hook: &str, command: &Option<String>, ctx: &Context| {
+            command.as_ref().map(|command| {
+                format!(
+                    "{hook} hook: {}",
+                    format_command(&self.command(hook, command, ctx))
+                )
+            })
+        };
+        let mut lines: Vec<String> =
+            describe("pre_step", &hooks.pre_step, ctx).into_iter().collect();
+        lines.extend(self.step.dry_run(ctx));
+        lines.extend(describe("post_step", &hooks.post_step, ctx));
+        lines.extend(
+            describe("on_failure", &hooks.on_failure, ctx)
+                .map(|line| format!("if the step fails, {line}")),
+        );
+        lines
+    }
+
+    fn execute(&self, ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+        let Some(hooks) = self.hooks else {
+            return self.step.execute(ctx, ui);
+        };
+
+        let result = match &hooks.pre_step {
+            Some(command) => self.run("pre_step", command, None, ctx, ui),
+            None => Ok(()),
+        }
+        .and_then(|()| self.step.execute(ctx, ui))
+        .and_then(|()| match &hooks.post_step {
+            Some(command) => self.run("post_step", command, None, ctx, ui),
+            None => Ok(()),
+        });
+
+        if let (Err(error), Some(command)) = (&result, &hooks.on_failure) {
+            if let Err(e) =
+                self.run("on_failure", command, Some(error), ctx, ui)
+            {
+                ui.warn(&format!("{e:#}"));
+            }
+        }
+
+        result
+    }
+}
@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Computer names for `--hostname`, which may have a `*` that each build
+//! replaces with random characters so that images built from the same
+//! configuration get different names.
+//!
+//! A name that's just `*` is left for Windows, which makes up a new name
+//! each time a generalized image is deployed.
+
+use std::io::Read;
+
+use anyhow::{Context as _, Result};
+
+/// How long Windows lets computer names be (their NetBIOS names' limit).
+const MAX_LEN: usize = 15;
+
+/// How many characters replace a `*`.
+const SUFFIX_LEN: usize = 5;
+
+/// The characters random suffixes are made of. Computer names aren't case
+/// sensitive, and these leave out the letters most easily mistaken for
+/// digits.
+const SUFFIX_CHARS: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// A computer name, possibly with a `*` to fill in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hostname(String);
+
+impl Hostname {
+    /// Returns whether this name has a `*` that builds fill in, rather than
+    /// being left for Windows.
+    pub fn has_suffix(&self) -> bool {
+        self.0 != "*" && self.0.contains('*')
+    }
+}
+
+impl std::str::FromStr for Hostname {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self(s.to_string()));
+        }
+
+        let len = match s.matches('*').count() {
+            0 => s.len(),
+            1 => s.len() - 1 + SUFFIX_LEN,
+            _ => return Err(format!("'{s}' can only have one '*'")),
+        };
+        if len > MAX_LEN {
+            return Err(format!(
+                "'{s}' is too long: computer names can have at most \
+                {MAX_LEN} characters, and a '*' becomes {SUFFIX_LEN}"
+            ));
+        }
+
+        let valid_char =
+            |c: char| c.is_ascii_alphanumeric() || "-*".contains(c);
+        if s.is_empty()
+            || !s.chars().all(valid_char)
+            || s.starts_with('-')
+            || s.ends_with('-')
+        {
+            return Err(format!(
+                "'{s}' isn't a valid computer name (use letters, digits, and \
+                hyphens, not at the start or end)"
+            ));
+        }
+
+        if s.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("'{s}' can't be all digits"));
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for Hostname {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Replaces the `*` in `name` with characters chosen from `seed`. A name
+/// that's just `*`, or has none, is returned as it is.
+fn fill_in(name: &str, seed: &[u8]) -> String {
+    if name == "*" {
+        return name.to_string();
+    }
+
+    let suffix: String = seed
+        .iter()
+        .take(SUFFIX_LEN)
+        .map(|b| SUFFIX_CHARS[(b % 32) as usize] as char)
+        .collect();
+    name.replacen('*', &suffix, 1)
+}
+
+/// Fills in the `*` in `name` with random characters, or, if `pinned`, with
+/// characters derived from the name itself, so that reproducible builds
+/// always choose the same name.
+pub fn choose(name: &str, pinned: bool) -> Result<String> {
+    let seed = if pinned {
+        crate::hash::sha256(format!("computer name {name}").as_bytes()).to_vec()
+    } else {
+        let mut seed = vec![0; SUFFIX_LEN];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut seed))
+            .context("reading random bytes for the computer name")?;
+        seed
+    };
+
+    Ok(fill_in(name, &seed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_fills_in_hostnames() {
+        for name in ["*", "web01", "LAB-*", "*-sql", "a-b-c-*"] {
+            assert!(name.parse::<Hostname>().is_ok(), "{name}");
+        }
+        for name in
+            ["", "-web", "web-", "web_01", "12345", "a*b*", "sixteen-chars-xy"]
+        {
+            assert!(name.parse::<Hostname>().is_err(), "{name}");
+        }
+        assert!("abcdefghij*".parse::<Hostname>().is_ok());
+        assert!("abcdefghijk*".parse::<Hostname>().is_err());
+        assert!(!"*".parse::<Hostname>().unwrap().has_suffix());
+        assert!("web-*".parse::<Hostname>().unwrap().has_suffix());
+
+        assert_eq!(fill_in("web-*", &[0, 1, 10, 31, 32]), "web-01AZ0");
+        assert_eq!(fill_in("*", &[0; 5]), "*");
+        assert_eq!(
+            choose("web-*", true).unwrap(),
+            choose("web-*", true).unwrap()
+        );
+        assert_eq!(choose("web-*", false).unwrap().len(), 9);
+    }
+}
@@ -0,0 +1,265 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the installation VM under bhyve(8) instead of propolis-standalone.
+//!
+//! The VM gets the same devices, at the same PCI slots, as the Propolis
+//! configuration: the output image and installation disk as NVMe disks, and
+//! a viona (in-kernel virtio-net) NIC on the build's VNIC. bhyve exits when
+//! the guest resets instead of rebooting it in place, and Windows Setup
+//! reboots the VM several times, so wimsy starts bhyve again after each reset
+//! until the guest powers off, then destroys the VM.
+
+use std::{
+    os::unix::net::UnixStream,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    memory,
+    monitor::{
+        DiskSpaceLimits, DiskSpaceMonitor, ProgressLimits, SerialWatcher,
+        Watchdog,
+    },
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{
+        command_span, describe_vm_exit, format_command,
+        run_command_check_status,
+    },
+};
+
+/// The name of the bhyve VM, which is how `bhyvectl` finds it.
+const VM_NAME: &str = "wimsy-server";
+
+/// Where illumos distributions install bhyve's UEFI firmware, in order of
+/// preference.
+const BOOTROM_CANDIDATES: &[&str] = &[
+    // OmniOS and OpenIndiana (system/bhyve/firmware).
+    "/usr/share/bhyve/firmware/BHYVE_RELEASE.fd",
+    "/usr/share/bhyve/firmware/BHYVE.fd",
+    // SmartOS.
+    "/usr/share/bhyve/uefi-rom.bin",
+];
+
+/// The name of the socket in the work directory that the VM's COM1 listens
+/// on.
+const SERIAL_SOCKET_NAME: &str = "bhyve-com1";
+
+/// How long to wait for bhyve to create the serial socket.
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times the guest may reset before wimsy gives up on it. Windows
+/// Setup reboots the VM a handful of times; a guest that keeps resetting is
+/// stuck in a boot loop.
+const MAX_BOOTS: u32 = 20;
+
+/// Returns `given`, or the first of the usual bhyve firmware locations that
+/// has a file in it. If there's none, returns the locations that were
+/// searched instead.
+pub(super) fn find_bootrom(
+    given: Option<&Utf8Path>,
+) -> Result<Utf8PathBuf, Vec<Utf8PathBuf>> {
+    if let Some(path) = given {
+        return Ok(path.to_owned());
+    }
+
+    let searched: Vec<Utf8PathBuf> =
+        BOOTROM_CANDIDATES.iter().map(Utf8PathBuf::from).collect();
+    match searched.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => Err(searched),
+    }
+}
+
+/// How a bhyve process ended, according to its exit status.
+#[derive(Debug)]
+enum Exit {
+    Reset,
+    PoweredOff,
+    Other(String),
+}
+
+impl Exit {
+    fn from_status(status: std::process::ExitStatus) -> Self {
+        // See the EXIT STATUS section of bhyve(8).
+        match status.code() {
+            Some(0) => Exit::Reset,
+            Some(1) => Exit::PoweredOff,
+            Some(2) => Exit::Other("the guest halted".to_string()),
+            Some(3) => Exit::Other("the guest triple-faulted".to_string()),
+            _ => Exit::Other(describe_vm_exit("bhyve", status)),
+        }
+    }
+}
+
+fn serial_socket(ctx: &Context) -> Utf8PathBuf {
+    crate::workspace::scratch_path(ctx, SERIAL_SOCKET_NAME)
+}
+
+fn bhyve_command(ctx: &Context) -> Command {
+    let mut cmd = Command::new("pfexec");
+    cmd.args(["bhyve", "-c", ctx.get_var("vm_cpus").unwrap()])
+        .args(["-m", &format!("{}M", ctx.get_var("vm_memory_mib").unwrap())])
+        // Yield the host CPU when the guest halts, generate ACPI tables, and
+        // ignore the model-specific registers bhyve doesn't emulate, which
+        // Windows probes for.
+        .args(["-H", "-A", "-w"])
+        .args([
+            "-l",
+            &format!("bootrom,{}", ctx.get_var("bhyve_bootrom").unwrap()),
+        ])
+        .args(["-l", &format!("com1,socket,{}", serial_socket(ctx))])
+        .args(["-s", "0,hostbridge", "-s", "31,lpc"])
+        .args([
+            "-s",
+            &format!(
+                "8,virtio-net-viona,{}",
+                ctx.get_var("vnic_name").unwrap()
+            ),
+        ])
+        .args([
+            "-s",
+            &format!("16,nvme,{}", ctx.get_var("output_image").unwrap()),
+        ])
+        .args([
+            "-s",
+            &format!("17,nvme,{}", ctx.get_var("installer_image").unwrap()),
+        ])
+        .arg(VM_NAME);
+    cmd
+}
+
+fn destroy_command() -> Command {
+    let mut cmd = Command::new("pfexec");
+    cmd.args(["bhyvectl", &format!("--vm={VM_NAME}"), "--destroy"]);
+    cmd
+}
+
+/// Describes the commands [`run_installation`] runs.
+pub(super) fn describe_installation(ctx: &mut Context) -> Vec<String> {
+    vec![
+        format!(
+            "{} (if a VM is left over)",
+            format_command(&destroy_command())
+        ),
+        format!(
+            "{} (again after each guest reset)",
+            format_command(&bhyve_command(ctx))
+        ),
+        format_command(&destroy_command()),
+    ]
+}
+
+/// Waits for bhyve to create the VM's serial socket, lets this process
+/// connect to it, and connects.
+fn connect_serial(socket: &Utf8Path, ui: &dyn Ui) -> Result<UnixStream> {
+    let deadline = Instant::now() + SERIAL_TIMEOUT;
+    while !socket.exists() {
+        if Instant::now() > deadline {
+            anyhow::bail!("timed out waiting for bhyve to create '{socket}'");
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // bhyve runs as root, so its socket is only writable by root, and
+    // connecting to a Unix socket needs write access.
+    run_command_check_status(
+        Command::new("pfexec").args(["chmod", "666", socket.as_str()]),
+        ui,
+    )?;
+    UnixStream::connect(socket)
+        .with_context(|| format!("connecting to bhyve's COM1 at '{socket}'"))
+}
+
+/// Installs Windows by running the installation VM in bhyve, starting it
+/// again each time the guest resets, until the guest powers off.
+pub(super) fn run_installation(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let work_dir = Utf8PathBuf::from(ctx.get_var("work_dir").unwrap());
+    let output_image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let mut monitor = DiskSpaceMonitor::new(
+        &[&work_dir, &output_image],
+        DiskSpaceLimits::from_context(ctx)?,
+    )?;
+    monitor.watch_progress(Watchdog::new(
+        ProgressLimits::from_context(ctx)?,
+        Some(&output_image),
+    ));
+
+    let memory_mib = ctx
+        .get_var("vm_memory_mib")
+        .unwrap()
+        .parse()
+        .context("parsing vm_memory_mib")?;
+    let _slot = crate::slots::take_vm_slot(ctx, ui)?;
+    memory::check_before_launch(memory_mib, ctx, ui)?;
+
+    // A VM left over from an interrupted build would keep bhyve from
+    // creating this one.
+    let _ = destroy_command()
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+
+    let result = (|| {
+        for boot in 1..=MAX_BOOTS {
+            let socket = serial_socket(ctx);
+            let _ = std::fs::remove_file(&socket);
+
+            ui.set_substep(&format!("booting the installation VM ({boot})"));
+            let mut cmd = bhyve_command(ctx);
+            cmd.stdout(ui.child_stdout("bhyve")?)
+                .stderr(ui.child_stderr("bhyve")?);
+            ui.command_started(&cmd);
+            let _span = command_span(&cmd);
+            let mut bhyve = cmd.spawn().context("spawning bhyve")?;
+            let _cancel = crate::interrupt::kill_on_cancel(&bhyve);
+
+            let stream = match connect_serial(&socket, ui) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = bhyve.kill();
+                    let _ = bhyve.wait();
+                    return Err(e);
+                }
+            };
+            monitor.watch_serial(SerialWatcher::spawn(
+                stream,
+                ui.serial_log(&format!("{VM_NAME} (boot {boot})"))?,
+            ));
+
+            ui.set_substep(
+                "Waiting for the installation VM to power off (this may take \
+                a while)",
+            );
+            let status = monitor
+                .wait(&mut bhyve, ui)
+                .context("waiting for bhyve to exit")?;
+            match Exit::from_status(status) {
+                Exit::Reset => {
+                    trace::info!("installation VM reset", boot = boot);
+                }
+                Exit::PoweredOff => return Ok(()),
+                Exit::Other(reason) => {
+                    anyhow::bail!("the installation VM stopped: {reason}")
+                }
+            }
+        }
+
+        anyhow::bail!(
+            "the installation VM reset {MAX_BOOTS} times without powering \
+            off; it's probably stuck in a boot loop"
+        )
+    })();
+
+    let destroyed =
+        run_command_check_status(&mut destroy_command(), ui).map(|_| ());
+    result.and(destroyed)
+}
@@ -9,20 +9,18 @@ use std::{
 };
 
 use anyhow::{Context as _, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
-use itertools::iproduct;
 
 use crate::{
-    app::ImageSources,
-    runner::{Context, MissingPrerequisites, Script, ScriptStep},
+    app::{DiskController, ImageSources, NicModel},
+    autounattend::Architecture,
+    drivers::VirtioDriver,
+    media::InstallMedia,
+    runner::{Context, MissingPrerequisites, Script, ScriptStep, Step},
     steps::get_gpt_partition_information,
     ui::Ui,
-    util::{
-        check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
-    },
-    UNATTEND_FILES,
+    util::{check_file_prerequisites, run_command_check_status},
 };
 
 pub struct BuildInstallationDiskArgs {
@@ -32,7 +30,7 @@ pub struct BuildInstallationDiskArgs {
 }
 
 pub struct BuildInstallationDiskScript {
-    steps: Vec<ScriptStep>,
+    steps: Vec<Box<dyn Step>>,
     args: BuildInstallationDiskArgs,
 }
 
@@ -43,7 +41,7 @@ impl BuildInstallationDiskScript {
 }
 
 impl Script for BuildInstallationDiskScript {
-    fn steps(&self) -> &[ScriptStep] {
+    fn steps(&self) -> &[Box<dyn Step>] {
         self.steps.as_slice()
     }
 
@@ -59,19 +57,53 @@ impl Script for BuildInstallationDiskScript {
         let args = &self.args;
         let sources = &args.sources;
         writeln!(w, "  {}: {}", "Working directory".bold(), args.work_dir)?;
-        writeln!(w, "  {}: {}", "Windows ISO".bold(), sources.windows_iso)?;
         writeln!(
             w,
             "  {}: {}",
-            "Virtio driver ISO".bold(),
-            sources.virtio_iso
+            "Windows ISO".bold(),
+            sources.windows_iso_source()
         )?;
+        let (driver_label, driver_source) = sources.driver_source();
+        writeln!(w, "  {}: {}", driver_label.bold(), driver_source)?;
         writeln!(
             w,
             "  {}: {}",
             "Unattend file directory".bold(),
             sources.unattend_dir
         )?;
+        if let Some(dir) = &sources.unattend_overrides {
+            writeln!(w, "  {}: {}", "Unattend overrides".bold(), dir)?;
+        }
+        for cert in &sources.trusted_certs {
+            writeln!(w, "  {}: {}", "Trusted certificate".bold(), cert)?;
+        }
+        for cert in &sources.intermediate_certs {
+            writeln!(w, "  {}: {}", "Intermediate certificate".bold(), cert)?;
+        }
+        for script in &sources.provision_scripts {
+            writeln!(w, "  {}: {}", "Provisioning script".bold(), script)?;
+        }
+        if !sources.windows_features.is_empty() {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Windows features to add".bold(),
+                itertools::join(&sources.windows_features, ", ")
+            )?;
+        }
+        if !sources.remove_features.is_empty() {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Windows features to remove".bold(),
+                itertools::join(&sources.remove_features, ", ")
+            )?;
+        }
+        for file in &sources.files {
+            writeln!(w, "  {}: {}", "File to copy".bold(), file)?;
+        }
+        crate::steps::print_cloudbase_init_options(&mut w, sources)?;
+        crate::steps::print_guest_options(&mut w, sources)?;
 
         writeln!(w)?;
 
@@ -81,14 +113,19 @@ impl Script for BuildInstallationDiskScript {
                 "  Image index to insert into Autounattend.xml: {}",
                 index
             )?;
+        } else if let Some(edition) = &sources.edition {
+            writeln!(w, "  Edition to install: {edition}")?;
         } else {
             writeln!(w, "  Will use default image index in Autounattend.xml")?;
         }
+        for update in &sources.updates {
+            writeln!(w, "  {}: {}", "Update to slipstream".bold(), update)?;
+        }
 
         if let Some(version) = sources.windows_version {
             writeln!(w, "  Target Windows version: {}", version)?;
         } else {
-            writeln!(w, "  Windows version defaulted to Server 2022")?;
+            writeln!(w, "  Will detect Windows version from the Windows ISO")?;
         }
 
         writeln!(w)?;
@@ -100,34 +137,77 @@ impl Script for BuildInstallationDiskScript {
     fn check_prerequisites(&self) -> MissingPrerequisites {
         let mut errors = Vec::new();
         let mut warnings = Vec::new();
-        let mut files = vec![
-            self.args.sources.windows_iso.clone(),
-            self.args.sources.virtio_iso.clone(),
-        ];
-
-        errors.extend(check_file_prerequisites(&files));
-
-        files.clear();
-        for file in UNATTEND_FILES {
-            let mut path = self.args.sources.unattend_dir.clone();
-            path.push(file);
-            files.push(path);
-        }
+        let (iso_errors, iso_warnings) =
+            self.args.sources.check_windows_iso_prerequisites();
+        errors.extend(iso_errors);
+        warnings.extend(iso_warnings);
+        let (driver_errors, driver_warnings) =
+            self.args.sources.check_driver_prerequisites(
+                Architecture::X86_64,
+                DiskController::default(),
+                NicModel::default(),
+            );
+        errors.extend(driver_errors);
+        warnings.extend(driver_warnings);
+
+        let files = self.args.sources.unattend_source_paths();
 
         warnings.extend(check_file_prerequisites(&files));
-        errors.extend(check_executable_prerequisites(self.steps()));
+        errors.extend(self.args.sources.check_unattend_overrides());
+        errors.extend(self.args.sources.check_admin_password());
+        errors.extend(crate::certs::check_prerequisites(
+            &self.args.sources.trusted_certs,
+        ));
+        errors.extend(crate::certs::check_prerequisites(
+            &self.args.sources.intermediate_certs,
+        ));
+        errors.extend(crate::provision::check_prerequisites(
+            &self.args.sources.provision_scripts,
+        ));
+        errors.extend(crate::provision::check_file_prerequisites(
+            &self.args.sources.files,
+        ));
+        errors.extend(crate::cloudbase_init::check_prerequisites(
+            self.args.sources.cloudbase_init.cloudbase_init_msi.as_deref(),
+        ));
+        errors.extend(crate::ssh::check_prerequisites(
+            self.args.sources.ssh_authorized_keys.as_deref(),
+        ));
+        errors.extend(crate::network::check_prerequisites(
+            &self.args.sources.network,
+        ));
+        errors.extend(crate::users::check_prerequisites(
+            &self.args.sources.local_users,
+        ));
+        errors.extend(crate::features::check_prerequisites(
+            &self.args.sources.windows_features,
+            &self.args.sources.remove_features,
+        ));
+        if !self.args.sources.updates.is_empty() {
+            errors.push(
+                "slipstreaming updates isn't supported on illumos; pass an \
+                ISO that already includes them with --windows-iso"
+                    .to_string(),
+            );
+        }
+        errors.extend(crate::domain_join::check_prerequisites(
+            &self.args.sources.domain_join,
+            self.args.sources.hostname.as_ref(),
+        ));
 
         MissingPrerequisites::from_messages(errors, warnings)
     }
 
+    fn input_files(&self) -> Vec<Utf8PathBuf> {
+        self.args.sources.input_files()
+    }
+
     fn initial_context(&self) -> HashMap<String, String> {
         let args = &self.args;
         let sources = &self.args.sources;
 
         let mut ctx: HashMap<String, String> = [
             ("work_dir".to_string(), args.work_dir.to_string()),
-            ("windows_iso".to_string(), sources.windows_iso.to_string()),
-            ("virtio_iso".to_string(), sources.virtio_iso.to_string()),
             ("unattend_dir".to_string(), sources.unattend_dir.to_string()),
             ("output_image".to_string(), args.output_image.to_string()),
         ]
@@ -144,12 +224,11 @@ impl Script for BuildInstallationDiskScript {
         if let Some(windows_version) = sources.windows_version {
             ctx.insert(
                 "windows_version".to_string(),
-                windows_version.as_driver_path_component().to_string(),
+                format!("{windows_version:?}"),
             );
-        } else {
-            ctx.insert("windows_version".to_string(), "2k22".to_string());
         }
 
+        ctx.extend(sources.context_vars());
         ctx
     }
 }
@@ -307,58 +386,50 @@ fn extract_setup_to_winpe_partition(
     ctx: &mut Context,
     ui: &dyn Ui,
 ) -> Result<()> {
-    run_command_check_status(
-        Command::new("7z").args([
-            "x",
-            "-x!sources/install.wim",
-            ctx.get_var("windows_iso").unwrap(),
-            &format!("-o{}", &ctx.get_var("setup_mount").unwrap()),
-        ]),
-        ui,
+    let mut media =
+        InstallMedia::open(Utf8Path::new(ctx.get_var("windows_iso").unwrap()))?;
+    let root = media.root().clone();
+    ui.set_substep(&format!("extracting setup files ({})", media.filesystem()));
+
+    // install.wim goes in its own partition.
+    media.extract_tree(
+        &root,
+        Utf8Path::new(ctx.get_var("setup_mount").unwrap()),
+        &|path| path.eq_ignore_ascii_case("sources/install.wim"),
     )
-    .map(|_| ())
 }
 
 fn copy_unattend_files_to_work_dir(
     ctx: &mut Context,
-    _ui: &dyn Ui,
+    ui: &dyn Ui,
 ) -> Result<()> {
-    let mut work_unattend =
-        Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
-    work_unattend.push("unattend");
-    std::fs::create_dir_all(&work_unattend)
-        .context("creating temporary directory for unattend files")?;
+    let work_unattend = crate::workspace::scratch_path(ctx, "unattend");
 
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
 
-    for filename in UNATTEND_FILES {
-        let mut src = unattend_dir.clone();
-        src.push(filename);
-        let mut dst = work_unattend.clone();
-        dst.push(filename);
-        if let Err(e) = std::fs::copy(&src, &dst) {
-            match e.kind() {
-                std::io::ErrorKind::NotFound => {}
-                _ => {
-                    return Err(e)
-                        .with_context(|| format!("copying {}", filename))
-                }
-            }
-        }
-    }
+    crate::steps::copy_unattend_files(
+        &unattend_dir,
+        ctx.get_var("unattend_overrides").map(Utf8Path::new),
+        &work_unattend,
+        Some(&crate::workspace::log_path(ctx, "unattend")),
+        &crate::steps::unattend_template_vars(ctx, ui)?,
+        ui,
+    )?;
 
-    // Make subsequent steps use unattend files from
+    // Make subsequent steps use unattend files from the working copy.
     ctx.set_var("unattend_dir", work_unattend.to_string());
     Ok(())
 }
 
-fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+fn customize_autounattend_xml(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     let customizer = crate::autounattend::AutounattendUpdater::new(
         ctx.get_var("unattend_image_index")
             .map(|val| val.parse::<u32>().unwrap()),
         None,
     );
+    let customizer =
+        crate::domain_join::add_to_autounattend(customizer, ctx, ui)?;
 
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
@@ -407,6 +478,72 @@ fn copy_unattend_to_winpe_partition(
         })?;
     }
 
+    let settings = unattend_dir.join(crate::steps::GUEST_SETTINGS_FILE);
+    if settings.exists() {
+        let dst = Utf8PathBuf::from_str(setup_mount)
+            .unwrap()
+            .join(crate::steps::GUEST_SETTINGS_FILE);
+        std::fs::copy(&settings, &dst)
+            .context("copying guest settings to WinPE partition")?;
+    }
+
+    let registry = unattend_dir.join(crate::registry_settings::REGISTRY_FILE);
+    if registry.exists() {
+        let dst = Utf8PathBuf::from_str(setup_mount)
+            .unwrap()
+            .join(crate::registry_settings::REGISTRY_FILE);
+        std::fs::copy(&registry, &dst)
+            .context("copying registry settings to WinPE partition")?;
+    }
+
+    let setup_mount = Utf8Path::new(setup_mount);
+    for dir in
+        [crate::certs::TRUSTED_CERTS_DIR, crate::certs::INTERMEDIATE_CERTS_DIR]
+    {
+        let certs_dir = unattend_dir.join(dir);
+        if certs_dir.exists() {
+            ui.set_substep(&format!("  copying {dir} to WinPE partition"));
+            copy_dir_to_winpe_partition(&certs_dir, &setup_mount.join(dir))?;
+        }
+    }
+
+    let files_dir = unattend_dir.join(crate::provision::FILES_DIR);
+    if files_dir.exists() {
+        ui.set_substep("  copying files for the image to WinPE partition");
+        copy_dir_to_winpe_partition(
+            &files_dir,
+            &setup_mount.join(crate::provision::FILES_DIR),
+        )?;
+    }
+
+    let provision_dir = unattend_dir.join(crate::provision::PROVISION_DIR);
+    if provision_dir.exists() {
+        ui.set_substep("  copying provisioning scripts to WinPE partition");
+        copy_dir_to_winpe_partition(
+            &provision_dir,
+            &setup_mount.join(crate::provision::PROVISION_DIR),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Copies the files in `src_dir` to `dst_dir`, a directory on the WinPE
+/// partition.
+fn copy_dir_to_winpe_partition(
+    src_dir: &Utf8Path,
+    dst_dir: &Utf8Path,
+) -> Result<()> {
+    std::fs::create_dir_all(dst_dir)
+        .with_context(|| format!("creating '{dst_dir}' in WinPE partition"))?;
+    for entry in src_dir.read_dir_utf8()? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), dst_dir.join(entry.file_name()))
+            .with_context(|| {
+                format!("copying {} to WinPE partition", entry.file_name())
+            })?;
+    }
+
     Ok(())
 }
 
@@ -431,6 +568,14 @@ fn copy_cloudbase_init_to_winpe_partition(
         })?;
     }
 
+    let msi = unattend_dir.join(crate::cloudbase_init::MSI_FILE);
+    if msi.exists() {
+        let dst = Utf8Path::new(ctx.get_var("setup_mount").unwrap())
+            .join(crate::cloudbase_init::MSI_FILE);
+        std::fs::copy(&msi, &dst)
+            .context("copying cloudbase-init installer to WinPE partition")?;
+    }
+
     Ok(())
 }
 
@@ -439,24 +584,76 @@ fn copy_virtio_to_winpe_partition(
     ui: &dyn Ui,
 ) -> Result<()> {
     let setup_mount = ctx.get_var("setup_mount").unwrap();
-    for (driver, ext) in iproduct!(["viostor", "NetKVM"], ["cat", "inf", "sys"])
-    {
-        run_command_check_status(
-            Command::new("7z").args([
-                "e",
-                ctx.get_var("virtio_iso").unwrap(),
-                &format!("-o{}/virtio-drivers/", setup_mount),
-                &format!(
-                    "{driver}/{}/amd64/*.{ext}",
-                    ctx.get_var("windows_version").unwrap()
-                ),
-            ]),
-            ui,
-        )?;
+    let version = crate::steps::windows_version(ctx);
+
+    // Propolis presents NVMe disks and virtio-net adapters, so only the
+    // drivers the default configuration needs are copied.
+    let drivers = VirtioDriver::ALL
+        .into_iter()
+        .filter(|driver| {
+            driver.required(DiskController::default(), NicModel::default())
+        })
+        .map(|driver| driver.relative_dir(version, Architecture::X86_64));
+    let dst = Utf8PathBuf::from(format!("{setup_mount}/virtio-drivers"));
+    std::fs::create_dir_all(&dst)
+        .context("creating driver directory in WinPE partition")?;
+    if let Some(driver_dir) = ctx.get_var("virtio_driver_dir") {
+        for driver in drivers {
+            let src = Utf8PathBuf::from(driver_dir).join(driver);
+            copy_driver_files(&src, &dst)?;
+        }
+
+        return Ok(());
+    }
+
+    let mut media =
+        InstallMedia::open(Utf8Path::new(ctx.get_var("virtio_iso").unwrap()))?;
+    for src in drivers {
+        ui.set_substep(&format!("extracting {src}"));
+        let Some(dir) = media.find(&src)? else {
+            anyhow::bail!("driver ISO has no '{src}' directory");
+        };
+
+        for entry in media.read_dir(&dir)? {
+            if !entry.is_dir && is_driver_collateral(&entry.name) {
+                media.extract(&entry, &dst.join(&entry.name))?;
+            }
+        }
     }
     Ok(())
 }
 
+/// Returns whether `name` is driver collateral: a `.cat`, `.inf`, or `.sys`
+/// file.
+fn is_driver_collateral(name: &str) -> bool {
+    Utf8Path::new(name).extension().is_some_and(|ext| {
+        ["cat", "inf", "sys"]
+            .iter()
+            .any(|wanted| ext.eq_ignore_ascii_case(wanted))
+    })
+}
+
+/// Copies the driver collateral in `src` to `dst`.
+fn copy_driver_files(src: &Utf8Path, dst: &Utf8Path) -> Result<()> {
+    let entries = src
+        .read_dir_utf8()
+        .with_context(|| format!("reading driver directory '{src}'"))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("reading driver directory '{src}'"))?;
+        if !is_driver_collateral(entry.file_name()) {
+            continue;
+        }
+
+        let target = dst.join(entry.file_name());
+        std::fs::copy(entry.path(), &target).with_context(|| {
+            format!("copying '{}' to '{target}'", entry.path())
+        })?;
+    }
+
+    Ok(())
+}
+
 fn unmount_winpe_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     let setup_mount = ctx.get_var("setup_mount").unwrap();
     run_command_check_status(
@@ -473,9 +670,9 @@ fn get_wim_partition_parameters(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ui,
     )?;
 
-    ctx.set_var("sector_size", params.sector_size);
-    ctx.set_var("first_sector", params.first_sector);
-    ctx.set_var("partition_sectors", params.partition_sectors);
+    ctx.set_var("sector_size", params.sector_size.to_string());
+    ctx.set_var("first_sector", params.first_sector.to_string());
+    ctx.set_var("partition_sectors", params.partition_sectors.to_string());
 
     Ok(())
 }
@@ -530,16 +727,17 @@ fn mount_wim_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
 }
 
 fn copy_install_wim(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    run_command_check_status(
-        Command::new("7z").args([
-            "e",
-            "-i!sources/install.wim",
-            ctx.get_var("windows_iso").unwrap(),
-            &format!("-o{}", ctx.get_var("image_mount").unwrap()),
-        ]),
-        ui,
+    let mut media =
+        InstallMedia::open(Utf8Path::new(ctx.get_var("windows_iso").unwrap()))?;
+    let Some(wim) = media.find("sources/install.wim")? else {
+        anyhow::bail!("Windows ISO has no sources/install.wim");
+    };
+
+    ui.set_substep(&format!("extracting install.wim ({} bytes)", wim.size));
+    media.extract(
+        &wim,
+        &Utf8Path::new(ctx.get_var("image_mount").unwrap()).join("install.wim"),
     )
-    .map(|_| ())
 }
 
 fn unmount_wim_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
@@ -566,91 +764,219 @@ fn remove_loopback_device(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     .map(|_| ())
 }
 
-fn get_script() -> Vec<ScriptStep> {
+fn get_script() -> Vec<Box<dyn Step>> {
     let steps = vec![
+        ScriptStep::new(
+            "download-windows-iso",
+            "download Windows setup ISO",
+            crate::steps::download_windows_iso,
+        )
+        .retryable()
+        .provides(&["windows_iso"])
+        .describe(crate::steps::describe_download_windows_iso),
+        ScriptStep::new(
+            "download-virtio-iso",
+            "download virtio driver ISO",
+            crate::drivers::download_virtio_iso,
+        )
+        .retryable()
+        .provides(&["virtio_iso"])
+        .describe(crate::drivers::describe_download_virtio_iso),
+        ScriptStep::new(
+            "select-edition",
+            "select Windows edition from setup ISO",
+            crate::steps::select_edition,
+        )
+        .describe(crate::steps::describe_select_edition),
+        ScriptStep::new(
+            "detect-windows-version",
+            "detect Windows version from setup ISO",
+            crate::steps::detect_windows_version,
+        )
+        .describe(crate::steps::describe_detect_windows_version),
+        ScriptStep::new(
+            "select-product-key",
+            "select product key for activation",
+            crate::activation::select_product_key,
+        )
+        .describe(crate::activation::describe_select_product_key),
         ScriptStep::with_prereqs(
+            "create-installer-disk",
             "create new disk to hold installer image",
             create_installer_disk,
             &["qemu-img"],
         ),
         ScriptStep::with_prereqs(
+            "set-up-installer-gpt",
             "set up GPT partition table on installer disk",
             set_up_installer_gpt_table,
             &["sgdisk"],
         ),
         ScriptStep::with_prereqs(
+            "create-installer-partitions",
             "create partitions on installer disk",
             create_installer_disk_partitions,
             &["sgdisk"],
         ),
         ScriptStep::with_prereqs(
+            "set-installer-partition-ids",
             "set partition IDs for partitions on installer disk",
             set_installer_disk_partition_ids,
             &["sgdisk"],
         ),
         ScriptStep::new(
+            "mount-installer-disk",
             "mount installation image as loopback device",
             mount_installer_disk_as_loopback_device,
-        ),
+        )
+        .provides(&[
+            "repack_loop",
+            "repack_loop_setup_raw",
+            "repack_loop_setup",
+            "repack_loop_image",
+        ]),
         ScriptStep::new(
+            "create-winpe-fat32",
             "create FAT32 filesystem on WinPE partition",
             create_winpe_fat32,
         ),
-        ScriptStep::new("mount WinPE partition", mount_winpe_partition),
-        ScriptStep::with_prereqs(
+        ScriptStep::new(
+            "mount-winpe-partition",
+            "mount WinPE partition",
+            mount_winpe_partition,
+        )
+        .provides(&["setup_mount"]),
+        ScriptStep::new(
+            "extract-setup-files",
             "extract setup files to WinPE partition",
             extract_setup_to_winpe_partition,
-            &["7z"],
         ),
         ScriptStep::new(
+            "copy-unattend-files",
             "copy unattend files to working directory",
             copy_unattend_files_to_work_dir,
+        )
+        .provides(&["unattend_dir"]),
+        ScriptStep::new(
+            "configure-cloudbase-init",
+            "configure cloudbase-init",
+            crate::cloudbase_init::configure_cloudbase_init,
+        )
+        .describe(crate::cloudbase_init::describe_configure_cloudbase_init),
+        ScriptStep::new(
+            "configure-network",
+            "add static network settings to specialize-unattend.xml",
+            crate::network::configure_network,
+        )
+        .describe(crate::network::describe_configure_network),
+        ScriptStep::new(
+            "configure-domain-join",
+            "add domain join settings to specialize-unattend.xml",
+            crate::domain_join::configure_deployment_join,
+        )
+        .describe(crate::domain_join::describe_configure_deployment_join),
+        ScriptStep::new(
+            "stage-trusted-certs",
+            "stage trusted certificates",
+            crate::certs::stage_trusted_certs,
         ),
         ScriptStep::new(
+            "stage-files",
+            "stage files to copy into the image",
+            crate::provision::stage_files,
+        )
+        .describe(crate::provision::describe_stage_files),
+        ScriptStep::new(
+            "stage-provision-scripts",
+            "stage provisioning scripts",
+            crate::provision::stage_provision_scripts,
+        )
+        .describe(crate::provision::describe_stage_provision_scripts),
+        ScriptStep::new(
+            "write-registry-settings",
+            "compile registry settings into a .reg file",
+            crate::registry_settings::write_registry_settings,
+        )
+        .describe(crate::registry_settings::describe_write_registry_settings),
+        ScriptStep::new(
+            "write-guest-settings",
+            "write guest setup script settings",
+            crate::steps::write_guest_settings,
+        ),
+        ScriptStep::new(
+            "customize-autounattend",
             "customizing Autounattend.xml",
             customize_autounattend_xml,
         ),
         ScriptStep::new(
+            "validate-autounattend",
+            "check Autounattend.xml for settings that stop Setup",
+            crate::validate::validate_autounattend,
+        ),
+        ScriptStep::new(
+            "copy-unattend-to-winpe",
             "copying unattend scripts to WinPE partition",
             copy_unattend_to_winpe_partition,
         ),
         ScriptStep::new(
+            "copy-cloudbase-init-to-winpe",
             "copying cloudbase-init scripts to WinPE partition",
             copy_cloudbase_init_to_winpe_partition,
         ),
-        ScriptStep::with_prereqs(
+        ScriptStep::new(
+            "copy-virtio-drivers",
             "copying virtio drivers to WinPE partition",
             copy_virtio_to_winpe_partition,
-            &["7z"],
         ),
-        ScriptStep::new("unmounting WinPE partition", unmount_winpe_partition),
+        ScriptStep::new(
+            "unmount-winpe-partition",
+            "unmounting WinPE partition",
+            unmount_winpe_partition,
+        ),
         ScriptStep::with_prereqs(
+            "get-wim-partition-parameters",
             "reading partition parameters for WIM partition",
             get_wim_partition_parameters,
             &["sgdisk"],
-        ),
+        )
+        .provides(&[
+            "sector_size",
+            "first_sector",
+            "partition_sectors",
+        ]),
         ScriptStep::with_prereqs(
+            "create-wim-ntfs",
             "creating NTFS filesystem on WIM partition",
             create_wim_partition_ntfs,
             &["mkntfs"],
         ),
         ScriptStep::with_prereqs(
+            "mount-wim-partition",
             "mounting WIM partition",
             mount_wim_partition,
             &["ntfs-3g"],
-        ),
-        ScriptStep::with_prereqs(
+        )
+        .provides(&["image_mount"]),
+        ScriptStep::new(
+            "copy-install-wim",
             "unpacking install.wim into WIM partition",
             copy_install_wim,
-            &["7z"],
         ),
-        ScriptStep::new("unmounting image partition", unmount_wim_partition),
-        ScriptStep::new("flushing changes to disk", |_ctx, ui| {
+        ScriptStep::new(
+            "unmount-wim-partition",
+            "unmounting image partition",
+            unmount_wim_partition,
+        ),
+        ScriptStep::new("sync", "flushing changes to disk", |_ctx, ui| {
             let mut sync = Command::new("sync");
             run_command_check_status(&mut sync, ui).map(|_| ())
         }),
-        ScriptStep::new("remove loopback device", remove_loopback_device),
+        ScriptStep::new(
+            "remove-loopback-device",
+            "remove loopback device",
+            remove_loopback_device,
+        ),
     ];
 
-    steps
+    steps.into_iter().map(Into::into).collect()
 }
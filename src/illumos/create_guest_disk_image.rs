@@ -5,11 +5,24 @@
 use std::{os::unix::net::UnixStream, process::Command, str::FromStr};
 
 use crate::{
-    runner::{Context, MissingPrerequisites, Script, ScriptStep},
+    app::{
+        CompressOptions, DiskSize, Hypervisor, OutputDeviceOptions,
+        OxidePublishOptions, Qcow2Options, S3PublishOptions, VhdxOptions,
+        VmdkOptions,
+    },
+    command::CommandRunner,
+    compress::Compression,
+    memory,
+    monitor::{
+        DiskSpaceLimits, DiskSpaceMonitor, ProgressLimits, SerialWatcher,
+        Watchdog,
+    },
+    qcow2::CodecSelection,
+    runner::{Context, MissingPrerequisites, Script, ScriptStep, Step},
     ui::Ui,
     util::{
-        check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
+        check_file_prerequisites, command_span, describe_vm_exit,
+        format_command, run_command_check_status,
     },
 };
 
@@ -19,27 +32,106 @@ use colored::Colorize;
 
 const VNIC_NAME: &str = "vnic0";
 
+/// The number of vCPUs to give the installation VM by default.
+pub(super) const GUEST_CPUS: u32 = 2;
+
+/// The amount of memory, in MiB, to give the installation VM by default.
+pub(super) const GUEST_MEMORY_MIB: u64 = 2048;
+
 pub struct CreateGuestDiskImageArgs {
     pub work_dir: Utf8PathBuf,
     pub output_image: Utf8PathBuf,
-    pub vnic_link: String,
+    pub vnic_link: Option<String>,
     pub installer_image: Utf8PathBuf,
-    pub propolis_bootrom: Utf8PathBuf,
+    pub hypervisor: Hypervisor,
+    pub propolis_bootrom: Option<Utf8PathBuf>,
+    pub bhyve_bootrom: Option<Utf8PathBuf>,
+    pub disk_size: DiskSize,
+    pub vm_cpus: u32,
+    pub vm_memory_mib: u64,
+
+    /// Whether the user asked for a QEMU machine type, which Propolis doesn't
+    /// have.
+    pub vm_machine_requested: bool,
+    /// Whether the user chose a disk controller, which only the Linux
+    /// scripts can change.
+    pub disk_controller_requested: bool,
+    /// Whether the user chose a network adapter, which only the Linux scripts
+    /// can change.
+    pub nic_model_requested: bool,
+    pub force_memory: bool,
+    pub force_disk_space: bool,
+    /// Whether other builds share the host through `--slots-dir`, so that
+    /// this one waits for room before launching its VM.
+    pub shares_host: bool,
+    pub qcow2: Qcow2Options,
+    pub vhdx: VhdxOptions,
+    pub vmdk: VmdkOptions,
+    pub output_device: OutputDeviceOptions,
+    pub oxide: OxidePublishOptions,
+    pub s3: S3PublishOptions,
+    pub compress: CompressOptions,
+
+    /// Whether the configuration file asks for image tests, which this
+    /// script can't run.
+    pub image_tests: bool,
 }
 
 pub struct CreateGuestDiskImageScript {
-    steps: Vec<ScriptStep>,
+    steps: Vec<Box<dyn Step>>,
     args: CreateGuestDiskImageArgs,
+
+    /// The codec with which to compress the qcow2 image, if one was
+    /// requested.
+    qcow2_codec: CodecSelection,
+
+    /// The bootrom the installation VM will boot from, or the places wimsy
+    /// looked for one if it had to look and found none. (Instances on a rack
+    /// boot the rack's own firmware, so there's never one with
+    /// `Hypervisor::Oxide`.)
+    bootrom: Result<Utf8PathBuf, Vec<Utf8PathBuf>>,
 }
 
 impl CreateGuestDiskImageScript {
-    pub(super) fn new(script_args: CreateGuestDiskImageArgs) -> Self {
-        Self { steps: get_script(), args: script_args }
+    pub(super) fn new(
+        script_args: CreateGuestDiskImageArgs,
+        runner: &dyn CommandRunner,
+    ) -> Self {
+        let qcow2_codec = CodecSelection::probe(
+            &script_args.qcow2,
+            runner,
+            &script_args.work_dir,
+        );
+        let bootrom = match script_args.hypervisor {
+            Hypervisor::Propolis => {
+                script_args.propolis_bootrom.clone().ok_or_else(Vec::new)
+            }
+            Hypervisor::Bhyve => {
+                super::bhyve::find_bootrom(script_args.bhyve_bootrom.as_deref())
+            }
+            Hypervisor::Oxide => Err(Vec::new()),
+        };
+
+        Self {
+            steps: get_script(script_args.hypervisor),
+            args: script_args,
+            qcow2_codec,
+            bootrom,
+        }
+    }
+}
+
+/// Describes where the installation VM runs under `hypervisor`.
+fn hypervisor_description(hypervisor: Hypervisor) -> &'static str {
+    match hypervisor {
+        Hypervisor::Propolis => "in propolis-standalone",
+        Hypervisor::Bhyve => "in bhyve",
+        Hypervisor::Oxide => "on an Oxide rack",
     }
 }
 
 impl Script for CreateGuestDiskImageScript {
-    fn steps(&self) -> &[ScriptStep] {
+    fn steps(&self) -> &[Box<dyn Step>] {
         self.steps.as_slice()
     }
 
@@ -47,74 +139,367 @@ impl Script for CreateGuestDiskImageScript {
         &self,
         mut w: Box<dyn std::io::Write>,
     ) -> std::io::Result<()> {
+        let args = &self.args;
         writeln!(
             w,
-            "Installing Windows in propolis-standalone with these options:\n"
+            "Installing Windows {} with these options:\n",
+            hypervisor_description(args.hypervisor)
         )?;
 
-        let args = &self.args;
         writeln!(w, "  {}: {}", "Working directory".bold(), args.work_dir)?;
         writeln!(w, "  {}: {}", "Installer disk".bold(), args.installer_image)?;
-        writeln!(w, "  {}: {}", "Guest bootrom".bold(), args.propolis_bootrom)?;
-        writeln!(w, "  {}: {}", "VNIC physical link".bold(), args.vnic_link)?;
-        writeln!(w, "  {}: {}", "VNIC name".bold(), VNIC_NAME)?;
+        match &self.bootrom {
+            _ if args.hypervisor == Hypervisor::Oxide => {}
+            Ok(path) => {
+                let detected = if args.hypervisor == Hypervisor::Bhyve
+                    && args.bhyve_bootrom.is_none()
+                {
+                    " (auto-detected)"
+                } else {
+                    ""
+                };
+                writeln!(w, "  {}: {path}{detected}", "Guest bootrom".bold())?
+            }
+            Err(_) => writeln!(w, "  {}: none found", "Guest bootrom".bold())?,
+        }
+        if let Some(link) = &args.vnic_link {
+            writeln!(w, "  {}: {}", "VNIC physical link".bold(), link)?;
+            writeln!(w, "  {}: {}", "VNIC name".bold(), VNIC_NAME)?;
+        }
         writeln!(w)?;
-        writeln!(w, "  {}: {}", "Output file".bold(), args.output_image)?;
+        if args.hypervisor == Hypervisor::Oxide {
+            writeln!(w, "  {}: {}", "Disk size".bold(), args.disk_size)?;
+        } else {
+            writeln!(
+                w,
+                "  {}: {} ({})",
+                "Output file".bold(),
+                args.output_image,
+                args.disk_size
+            )?;
+        }
+        writeln!(
+            w,
+            "  {}: {} vCPUs, {} MiB memory",
+            "Installation VM".bold(),
+            args.vm_cpus,
+            args.vm_memory_mib
+        )?;
+        if let Some(qcow2_image) = &args.qcow2.qcow2_image {
+            writeln!(
+                w,
+                "  {}: {} ({}-compressed)",
+                "qcow2 image".bold(),
+                qcow2_image,
+                self.qcow2_codec.selected()
+            )?;
+        }
+        if let Some(vhdx_image) = &args.vhdx.vhdx_image {
+            writeln!(
+                w,
+                "  {}: {} ({})",
+                "VHDX image".bold(),
+                vhdx_image,
+                args.vhdx.vhdx_subformat
+            )?;
+        }
+        if let Some(vmdk_image) = &args.vmdk.vmdk_image {
+            writeln!(
+                w,
+                "  {}: {} (stream-optimized)",
+                "VMDK image".bold(),
+                vmdk_image
+            )?;
+        }
+        if let Some(device) = &args.output_device.output_device {
+            let discard = if args.output_device.discard_output_device {
+                " (discarding it first)"
+            } else {
+                ""
+            };
+            writeln!(w, "  {}: {}{}", "Output device".bold(), device, discard)?;
+        }
+        if let (Some(project), Some(image)) =
+            (&args.oxide.oxide_project, &args.oxide.oxide_image_name)
+        {
+            writeln!(
+                w,
+                "  {}: {} in project {}",
+                "Oxide image".bold(),
+                image,
+                project
+            )?;
+        }
+        if let Some(url) = &args.s3.publish {
+            writeln!(
+                w,
+                "  {}: {} ({} compression)",
+                "Publish to".bold(),
+                url,
+                args.s3.publish_compression
+            )?;
+        }
+        if args.compress.compression() != Compression::None {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Compression".bold(),
+                args.compress.compression()
+            )?;
+        }
 
         Ok(())
     }
 
     fn check_prerequisites(&self) -> MissingPrerequisites {
         let mut errors = Vec::new();
-        let files = vec![
-            self.args.installer_image.clone(),
-            self.args.propolis_bootrom.clone(),
-        ];
+        let mut warnings = Vec::new();
+        errors.extend(check_file_prerequisites(&self.input_files()));
+        match (self.args.hypervisor, &self.bootrom) {
+            (_, Ok(_)) | (Hypervisor::Oxide, _) => {}
+            (Hypervisor::Propolis, Err(_)) => errors.push(
+                "--propolis-bootrom is required with --hypervisor propolis"
+                    .to_string(),
+            ),
+            (Hypervisor::Bhyve, Err(searched)) => {
+                let searched: Vec<&str> =
+                    searched.iter().map(|path| path.as_str()).collect();
+                errors.push(format!(
+                    "no bhyve UEFI firmware was found in any of the usual \
+                    locations. To fix this, install your distribution's bhyve \
+                    firmware package (system/bhyve/firmware on OmniOS and \
+                    OpenIndiana), or pass the path to a firmware image with \
+                    --bhyve-bootrom (wimsy looked for {})",
+                    searched.join(", ")
+                ));
+            }
+        }
+        if self.args.hypervisor == Hypervisor::Bhyve
+            && self.args.propolis_bootrom.is_some()
+        {
+            errors.push(
+                "--propolis-bootrom only applies with --hypervisor propolis; \
+                use --bhyve-bootrom to choose bhyve's firmware"
+                    .to_string(),
+            );
+        }
+        if self.args.hypervisor == Hypervisor::Propolis
+            && self.args.bhyve_bootrom.is_some()
+        {
+            errors.push(
+                "--bhyve-bootrom only applies with --hypervisor bhyve"
+                    .to_string(),
+            );
+        }
+        if self.args.hypervisor != Hypervisor::Oxide
+            && self.args.vnic_link.is_none()
+        {
+            errors.push(format!(
+                "--vnic-link is required with --hypervisor {}",
+                self.args.hypervisor
+            ));
+        }
+
+        // The Windows version was chosen when the installation disk was
+        // built, so check the disk against every version's minimum.
+        errors.extend(crate::steps::check_disk_size(self.args.disk_size, None));
+        errors.extend(crate::steps::check_vm_resources(
+            self.args.vm_cpus,
+            self.args.vm_memory_mib,
+        ));
+        if self.args.vm_machine_requested {
+            errors.push(
+                "QEMU machine types (--vm-machine or the configuration \
+                file's vm.machine) are only supported when building images \
+                on Linux"
+                    .to_string(),
+            );
+        }
+        if self.args.disk_controller_requested {
+            errors.push(
+                "choosing a disk controller (--disk-controller or the \
+                configuration file's vm.disk_controller) is only supported \
+                when building images on Linux"
+                    .to_string(),
+            );
+        }
+        if self.args.nic_model_requested {
+            errors.push(
+                "choosing a network adapter (--nic-model or the configuration \
+                file's vm.nic_model) is only supported when building images \
+                on Linux"
+                    .to_string(),
+            );
+        }
+        if self.args.image_tests {
+            errors.push(
+                "the configuration file's [tests] table is only supported \
+                when building images on Linux"
+                    .to_string(),
+            );
+        }
+
+        // A build on a rack doesn't use this host's memory or disk, and its
+        // image never leaves the rack.
+        if self.args.hypervisor == Hypervisor::Oxide {
+            errors.extend(super::rack::check_prerequisites(&self.args));
+            return MissingPrerequisites::from_messages(errors, warnings);
+        }
+
+        warnings.extend(crate::doctor::shrink_warning());
+        if !self.args.force_disk_space {
+            errors.extend(
+                crate::doctor::SpaceNeeds::for_build(
+                    &self.args.work_dir,
+                    &self.args.output_image,
+                    self.args.disk_size.bytes(),
+                    &[
+                        ("qcow2 image", self.args.qcow2.qcow2_image.as_deref()),
+                        ("VHDX image", self.args.vhdx.vhdx_image.as_deref()),
+                        ("VMDK image", self.args.vmdk.vmdk_image.as_deref()),
+                    ],
+                    self.args.compress.compression(),
+                )
+                .check(),
+            );
+        }
+        // Memory that other builds' VMs are using now may be free by the time
+        // this build's VM gets a slot, which is when the memory is checked
+        // again.
+        if !self.args.shares_host {
+            memory::check_prerequisites(
+                self.args.vm_memory_mib,
+                self.args.force_memory,
+                &mut errors,
+                &mut warnings,
+            );
+        }
+        self.qcow2_codec.check_prerequisites(
+            self.args.qcow2.strict_qcow2_compression,
+            &mut errors,
+            &mut warnings,
+        );
+        if let Some(device) = &self.args.output_device.output_device {
+            errors.extend(crate::device::check_prerequisites(
+                device,
+                self.args.output_device.discard_output_device,
+            ));
+        }
 
-        errors.extend(check_file_prerequisites(&files));
-        errors.extend(check_executable_prerequisites(self.steps()));
+        if self.args.oxide.oxide_project.is_some() {
+            errors.extend(crate::oxide::check_prerequisites("raw"));
+        }
+
+        if self.args.s3.publish.is_some() {
+            errors.extend(crate::s3::check_prerequisites(
+                self.args.s3.publish_compression,
+            ));
+        }
+
+        errors.extend(crate::compress::check_prerequisites(
+            self.args.compress.compression(),
+        ));
 
-        MissingPrerequisites::from_messages(errors, vec![])
+        MissingPrerequisites::from_messages(errors, warnings)
+    }
+
+    fn input_files(&self) -> Vec<Utf8PathBuf> {
+        let mut files = vec![self.args.installer_image.clone()];
+        files.extend(self.bootrom.iter().cloned());
+        files
     }
 
     fn initial_context(&self) -> std::collections::HashMap<String, String> {
         let args = &self.args;
-        [
+        let mut ctx: std::collections::HashMap<String, String> = [
             ("work_dir".to_string(), args.work_dir.to_string()),
-            ("vnic_link".to_string(), args.vnic_link.clone()),
             ("vnic_name".to_string(), VNIC_NAME.to_string()),
             ("installer_image".to_string(), args.installer_image.to_string()),
             ("output_image".to_string(), args.output_image.to_string()),
-            ("propolis_bootrom".to_string(), args.propolis_bootrom.to_string()),
+            ("hypervisor".to_string(), args.hypervisor.to_string()),
+            ("disk_size".to_string(), args.disk_size.bytes().to_string()),
+            ("vm_cpus".to_string(), args.vm_cpus.to_string()),
+            ("vm_memory_mib".to_string(), args.vm_memory_mib.to_string()),
         ]
         .into_iter()
-        .collect()
+        .collect();
+
+        if let Some(link) = &args.vnic_link {
+            ctx.insert("vnic_link".to_string(), link.clone());
+        }
+
+        if let Ok(bootrom) = &self.bootrom {
+            let name = match args.hypervisor {
+                Hypervisor::Propolis => "propolis_bootrom",
+                Hypervisor::Bhyve => "bhyve_bootrom",
+                Hypervisor::Oxide => {
+                    unreachable!("rack builds have no bootrom")
+                }
+            };
+            ctx.insert(name.to_string(), bootrom.to_string());
+        }
+
+        if args.force_memory {
+            ctx.insert("force_memory".to_string(), String::new());
+        }
+
+        if let Some(qcow2_image) = &args.qcow2.qcow2_image {
+            ctx.insert("qcow2_image".to_string(), qcow2_image.to_string());
+            ctx.insert(
+                "qcow2_compression".to_string(),
+                self.qcow2_codec.selected().to_string(),
+            );
+        }
+
+        ctx.extend(args.vhdx.context_vars());
+        ctx.extend(args.vmdk.context_vars());
+        ctx.extend(args.output_device.context_vars());
+        ctx.extend(args.oxide.context_vars());
+        ctx.extend(args.s3.context_vars());
+        ctx.extend(args.compress.context_vars());
+        ctx
     }
 }
 
+fn create_vnic_command(ctx: &Context) -> Command {
+    // The link is only missing in dry runs whose prerequisites weren't
+    // satisfied, which still describe the steps.
+    let mut cmd = Command::new("pfexec");
+    cmd.args([
+        "dladm",
+        "create-vnic",
+        "-t",
+        "-l",
+        ctx.get_var("vnic_link").unwrap_or("LINK"),
+        ctx.get_var("vnic_name").unwrap(),
+    ]);
+    cmd
+}
+
 fn create_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    run_command_check_status(
-        Command::new("pfexec").args([
-            "dladm",
-            "create-vnic",
-            "-t",
-            "-l",
-            ctx.get_var("vnic_link").unwrap(),
-            ctx.get_var("vnic_name").unwrap(),
-        ]),
+    run_command_check_status(&mut create_vnic_command(ctx), ui).map(|_| ())
+}
+
+fn create_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    // Propolis only supports raw disks.
+    crate::steps::create_output_image(
+        ctx.get_var("output_image").unwrap(),
+        "raw",
+        ctx.get_var("disk_size").unwrap(),
         ui,
     )
-    .map(|_| ())
 }
 
-fn create_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    crate::steps::create_output_image(ctx.get_var("output_image").unwrap(), ui)
+fn describe_create_output_image(ctx: &mut Context) -> Vec<String> {
+    crate::steps::describe_create_output_image(
+        ctx.get_var("output_image").unwrap(),
+        "raw",
+        ctx.get_var("disk_size").unwrap(),
+    )
 }
 
 fn write_vm_toml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
-    let mut vm_toml_path =
-        Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
-    vm_toml_path.push("vm.toml");
+    let vm_toml_path = crate::workspace::scratch_path(ctx, "vm.toml");
 
     std::fs::write(
         &vm_toml_path,
@@ -122,8 +507,8 @@ fn write_vm_toml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
             r#"
 [main]
 name = "wimsy-server"
-cpus = 2
-memory = 2048
+cpus = {}
+memory = {}
 bootrom = "{}"
 
 [block_dev.win_image]
@@ -147,6 +532,8 @@ driver = "pci-virtio-viona"
 vnic = "{}"
 pci-path = "0.8.0"
 "#,
+            ctx.get_var("vm_cpus").unwrap(),
+            ctx.get_var("vm_memory_mib").unwrap(),
             ctx.get_var("propolis_bootrom").unwrap(),
             ctx.get_var("output_image").unwrap(),
             ctx.get_var("installer_image").unwrap(),
@@ -168,10 +555,32 @@ fn run_propolis_standalone(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     let work_dir =
         Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
 
-    std::env::set_current_dir(&work_dir).context(
+    // propolis-standalone creates the guest's serial socket, ttya, in its
+    // working directory.
+    let scratch_dir = crate::workspace::scratch_dir(&work_dir);
+    std::env::set_current_dir(&scratch_dir).context(
         "setting working directory before launching propolis-standalone",
     )?;
 
+    let output_image =
+        Utf8PathBuf::from_str(ctx.get_var("output_image").unwrap()).unwrap();
+    let mut monitor = DiskSpaceMonitor::new(
+        &[&work_dir, &output_image],
+        DiskSpaceLimits::from_context(ctx)?,
+    )?;
+    monitor.watch_progress(Watchdog::new(
+        ProgressLimits::from_context(ctx)?,
+        Some(&output_image),
+    ));
+
+    let memory_mib = ctx
+        .get_var("vm_memory_mib")
+        .unwrap()
+        .parse()
+        .context("parsing vm_memory_mib")?;
+    let _slot = crate::slots::take_vm_slot(ctx, ui)?;
+    memory::check_before_launch(memory_mib, ctx, ui)?;
+
     let executable = "propolis-standalone";
     let mut propolis = Command::new("pfexec");
     propolis
@@ -179,12 +588,13 @@ fn run_propolis_standalone(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         .stdout::<std::fs::File>(ui.child_stdout(executable)?)
         .stderr::<std::fs::File>(ui.child_stderr(executable)?);
 
-    ui.set_substep(&format!("Launching propolis-standalone: {:?}", propolis));
+    ui.command_started(&propolis);
+    let _span = command_span(&propolis);
     let mut propolis =
         propolis.spawn().context("spawning propolis-standalone")?;
+    let _cancel = crate::interrupt::kill_on_cancel(&propolis);
 
-    let mut ttya_path = work_dir.clone();
-    ttya_path.push("ttya");
+    let ttya_path = scratch_dir.join("ttya");
 
     ui.set_substep("Waiting for propolis-standalone to create ttya");
     for _ in 0..=5 {
@@ -208,18 +618,21 @@ fn run_propolis_standalone(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ui,
     )?;
 
-    let _stream = UnixStream::connect(&ttya_path)
+    let stream = UnixStream::connect(&ttya_path)
         .context("connecting to propolis-standalone's ttya")?;
+    monitor
+        .watch_serial(SerialWatcher::spawn(stream, ui.serial_log(executable)?));
 
     ui.set_substep(
         "Waiting for propolis-standalone to exit (this may take a while)",
     );
 
-    let status =
-        propolis.wait().context("waiting for propolis-standalone to exit")?;
+    let status = monitor
+        .wait(&mut propolis, ui)
+        .context("waiting for propolis-standalone to exit")?;
 
     if !status.success() {
-        anyhow::bail!("propolis-server exited with error {:?}", status);
+        anyhow::bail!("{}", describe_vm_exit("propolis-standalone", status));
     }
 
     std::env::set_current_dir(current_dir).context(
@@ -236,65 +649,186 @@ fn get_partition_size(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
             ui,
         )?;
 
-    ctx.set_var("sector_size", sector_size);
-    ctx.set_var("last_sector", last_sector);
+    ctx.set_var("sector_size", sector_size.to_string());
+    ctx.set_var("last_sector", last_sector.to_string());
     Ok(())
 }
 
+fn describe_get_partition_size(ctx: &mut Context) -> Vec<String> {
+    vec![format!(
+        "read the partition table of {}",
+        ctx.get_var("output_image").unwrap()
+    )]
+}
+
 fn shrink_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     crate::steps::shrink_output_image(
         ctx.get_var("output_image").unwrap(),
+        "raw",
         ctx.get_var("sector_size").unwrap(),
         ctx.get_var("last_sector").unwrap(),
         ui,
     )
 }
 
+fn describe_shrink(ctx: &mut Context) -> Vec<String> {
+    crate::steps::describe_shrink_output_image(
+        ctx.get_var("output_image").unwrap(),
+        "raw",
+        ctx.get_var("sector_size").unwrap(),
+        ctx.get_var("last_sector").unwrap(),
+    )
+}
+
 fn repair_secondary_gpt(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     crate::steps::repair_secondary_gpt(ctx.get_var("output_image").unwrap(), ui)
 }
 
-fn remove_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    run_command_check_status(
-        Command::new("pfexec").args([
-            "dladm",
-            "delete-vnic",
-            ctx.get_var("vnic_name").unwrap(),
-        ]),
-        ui,
+fn describe_repair(ctx: &mut Context) -> Vec<String> {
+    crate::steps::describe_repair_secondary_gpt(
+        ctx.get_var("output_image").unwrap(),
     )
-    .map(|_| ())
 }
 
-fn get_script() -> Vec<ScriptStep> {
-    vec![
-        ScriptStep::new("create VNIC for installation VM", create_vnic),
-        ScriptStep::with_prereqs(
+fn remove_vnic_command(ctx: &Context) -> Command {
+    let mut cmd = Command::new("pfexec");
+    cmd.args(["dladm", "delete-vnic", ctx.get_var("vnic_name").unwrap()]);
+    cmd
+}
+
+fn remove_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    run_command_check_status(&mut remove_vnic_command(ctx), ui).map(|_| ())
+}
+
+/// Returns the steps that run the installation VM under `hypervisor`.
+fn install_steps(hypervisor: Hypervisor) -> Vec<ScriptStep> {
+    match hypervisor {
+        Hypervisor::Propolis => vec![
+            ScriptStep::new(
+                "write-vm-toml",
+                "write config TOML for installation VM",
+                write_vm_toml,
+            )
+            .provides(&["vm_toml_path"]),
+            ScriptStep::with_prereqs(
+                "install-windows",
+                "run installation in propolis-standalone",
+                run_propolis_standalone,
+                &["propolis-standalone"],
+            ),
+        ],
+        Hypervisor::Bhyve => vec![ScriptStep::with_prereqs(
+            "install-windows",
+            "run installation in bhyve",
+            super::bhyve::run_installation,
+            &["bhyve", "bhyvectl"],
+        )
+        .describe(super::bhyve::describe_installation)],
+        Hypervisor::Oxide => unreachable!("rack builds have their own steps"),
+    }
+}
+
+fn get_script(hypervisor: Hypervisor) -> Vec<Box<dyn Step>> {
+    if hypervisor == Hypervisor::Oxide {
+        return super::rack::steps().into_iter().map(Into::into).collect();
+    }
+
+    let mut steps = vec![
+        ScriptStep::new(
+            "create-vnic",
+            "create VNIC for installation VM",
+            create_vnic,
+        )
+        .describe(|ctx| vec![format_command(&create_vnic_command(ctx))]),
+        ScriptStep::new(
+            "create-output-image",
             "create output image",
             create_output_image,
-            &["qemu-img"],
-        ),
-        ScriptStep::new("write config TOML for installation VM", write_vm_toml),
-        ScriptStep::with_prereqs(
-            "run installation in propolis-standalone",
-            run_propolis_standalone,
-            &["propolis-standalone"],
-        ),
+        )
+        .describe(describe_create_output_image),
+    ];
+    steps.extend(install_steps(hypervisor));
+    steps.extend([
         ScriptStep::with_prereqs(
+            "get-partition-size",
             "get size of primary installation partition",
             get_partition_size,
             &["sgdisk"],
-        ),
+        )
+        .retryable()
+        .provides(&["sector_size", "last_sector"])
+        .describe(describe_get_partition_size),
         ScriptStep::with_prereqs(
+            "shrink-output-image",
             "trim unused sectors from output image",
             shrink_output_image,
             &["qemu-img"],
-        ),
+        )
+        .retryable()
+        .describe(describe_shrink),
         ScriptStep::with_prereqs(
+            "repair-secondary-gpt",
             "repair secondary GPT in output image",
             repair_secondary_gpt,
             &["sgdisk"],
-        ),
-        ScriptStep::new("remove installation VM VNIC", remove_vnic),
-    ]
+        )
+        .retryable()
+        .describe(describe_repair),
+        ScriptStep::new(
+            "publish-oxide-image",
+            "upload output image to Oxide rack",
+            crate::oxide::publish_image,
+        )
+        .describe(crate::oxide::describe_publish_image),
+        ScriptStep::new(
+            "publish-s3",
+            "upload output image to object storage",
+            crate::s3::publish_image,
+        )
+        .retryable()
+        .describe(crate::s3::describe_publish_image),
+        ScriptStep::with_prereqs(
+            "convert-output-image",
+            "convert output image to compressed qcow2",
+            crate::qcow2::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::qcow2::describe_conversion),
+        ScriptStep::with_prereqs(
+            "convert-output-image-vhdx",
+            "convert output image to VHDX",
+            crate::vhdx::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::vhdx::describe_conversion),
+        ScriptStep::with_prereqs(
+            "convert-output-image-vmdk",
+            "convert output image to VMDK",
+            crate::vmdk::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::vmdk::describe_conversion),
+        ScriptStep::new(
+            "write-output-device",
+            "write output image to output device",
+            crate::device::write_output_device,
+        )
+        .describe(crate::device::describe_write_output_device),
+        ScriptStep::new(
+            "compress-output-image",
+            "compress output image",
+            crate::compress::compress_output_image,
+        )
+        .describe(crate::compress::describe_compression),
+        ScriptStep::new(
+            "remove-vnic",
+            "remove installation VM VNIC",
+            remove_vnic,
+        )
+        .describe(|ctx| vec![format_command(&remove_vnic_command(ctx))]),
+    ]);
+    steps.into_iter().map(Into::into).collect()
 }
@@ -0,0 +1,113 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host checks for building images with propolis-standalone or bhyve.
+
+use camino::Utf8PathBuf;
+
+use crate::{
+    app::DiskSize,
+    doctor::{self, Report, Status, Tool},
+    monitor,
+};
+
+/// The device through which bhyve guests access hardware virtualization.
+const VMM_CONTROL_DEVICE: &str = "/dev/vmmctl";
+
+pub(super) struct DoctorArgs {
+    pub work_dir: Utf8PathBuf,
+    pub output_image: Utf8PathBuf,
+    pub disk_size: DiskSize,
+}
+
+pub(super) fn check_host(args: &DoctorArgs) -> Report {
+    let mut report = Report::default();
+    doctor::check_tools(
+        &mut report,
+        &[
+            Tool {
+                name: "propolis-standalone",
+                purpose: "to run the installation VM",
+                required: true,
+            },
+            Tool {
+                name: "bhyve",
+                purpose: "to run the installation VM with --hypervisor bhyve",
+                required: false,
+            },
+            Tool {
+                name: "oxide",
+                purpose: "to build images on an Oxide rack with --hypervisor \
+                    oxide",
+                required: false,
+            },
+            Tool {
+                name: "qemu-img",
+                purpose: "to create and resize disk images",
+                required: true,
+            },
+            Tool {
+                name: "sgdisk",
+                purpose: "to edit partition tables",
+                required: true,
+            },
+            Tool {
+                name: "curl",
+                purpose: "to download virtio driver ISOs pinned by a manifest",
+                required: false,
+            },
+            Tool {
+                name: "mkntfs",
+                purpose: "to format the installation disk's NTFS partition",
+                required: true,
+            },
+            Tool {
+                name: "ntfs-3g",
+                purpose: "to mount the installation disk's NTFS partition",
+                required: true,
+            },
+            Tool {
+                name: "pfexec",
+                purpose: "to run commands that need elevated privileges",
+                required: true,
+            },
+            Tool {
+                name: "dladm",
+                purpose: "to create the installation VM's VNIC",
+                required: true,
+            },
+        ],
+    );
+    doctor::check_qemu_img(&mut report);
+
+    if camino::Utf8Path::new(VMM_CONTROL_DEVICE).exists() {
+        report.ok("bhyve", format!("{VMM_CONTROL_DEVICE} exists"));
+    } else {
+        report.add(
+            "bhyve",
+            Status::Error,
+            format!("{VMM_CONTROL_DEVICE} doesn't exist"),
+            Some(
+                "make sure the host supports hardware virtualization and the \
+                vmm driver is installed"
+                    .to_string(),
+            ),
+        );
+    }
+
+    doctor::check_free_space(
+        &mut report,
+        "work directory",
+        &args.work_dir,
+        monitor::DEFAULT_WARN_FREE_MIB * 1024 * 1024,
+    );
+    doctor::check_free_space(
+        &mut report,
+        "output image",
+        &args.output_image,
+        args.disk_size.bytes(),
+    );
+
+    report
+}
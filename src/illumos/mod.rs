@@ -3,7 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Commands for creating a Windows installation disk and a generic Windows
-//! image using illumos and propolis-standalone.
+//! image using illumos and propolis-standalone (or bhyve).
 //!
 //! Because Propolis doesn't support presenting virtual disks as removable disks
 //! (at least at the time of this writing), the only way to inject an answer
@@ -19,41 +19,149 @@
 //! disk and the installation media, and then boot the VM; Windows Setup will
 //! take care of the rest.
 
-use crate::{app::Command, runner::Script};
+use crate::{
+    app::{Command, DiskSize},
+    doctor::Report,
+    runner::Script,
+};
 
 use self::{
     build_installation_disk::{
         BuildInstallationDiskArgs, BuildInstallationDiskScript,
     },
     create_guest_disk_image::{
-        CreateGuestDiskImageArgs, CreateGuestDiskImageScript,
+        CreateGuestDiskImageArgs, CreateGuestDiskImageScript, GUEST_CPUS,
+        GUEST_MEMORY_MIB,
     },
+    doctor::DoctorArgs,
 };
 
+mod bhyve;
 mod build_installation_disk;
 mod create_guest_disk_image;
+mod doctor;
+mod rack;
 
-pub fn get_script(app: &crate::app::App) -> Box<dyn Script> {
-    match &app.command {
+/// Returns the script for `app`'s command, as configured by `config`.
+/// `runner` runs the commands with which the script checks what the host's
+/// tools support.
+pub fn get_script(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+    runner: &dyn crate::command::CommandRunner,
+) -> anyhow::Result<Box<dyn Script>> {
+    Ok(match &app.command {
         Command::BuildInstallationDisk { sources } => Box::new(
             BuildInstallationDiskScript::new(BuildInstallationDiskArgs {
                 work_dir: app.work_dir.clone(),
-                output_image: app.output_image.clone(),
-                sources: sources.clone(),
+                output_image: app.output_image().to_path_buf(),
+                sources: sources.clone().with_config_defaults(config)?,
             }),
         ),
         Command::CreateGuestDiskImage {
             vnic_link,
             installer_image,
+            hypervisor,
             propolis_bootrom,
+            bhyve_bootrom,
+            disk_size,
+            vm,
+            force_memory,
+            force_disk_space,
+            qcow2,
+            vhdx,
+            vmdk,
+            output_device,
+            oxide,
+            s3,
+            compress,
         } => Box::new(CreateGuestDiskImageScript::new(
             CreateGuestDiskImageArgs {
                 work_dir: app.work_dir.clone(),
-                output_image: app.output_image.clone(),
+                output_image: app.output_image().to_path_buf(),
                 vnic_link: vnic_link.clone(),
                 installer_image: installer_image.clone(),
+                hypervisor: *hypervisor,
                 propolis_bootrom: propolis_bootrom.clone(),
+                bhyve_bootrom: bhyve_bootrom.clone(),
+                disk_size: disk_size
+                    .or(config.disk.size)
+                    .unwrap_or(DiskSize::DEFAULT),
+                vm_cpus: vm.vm_cpus.or(config.vm.cpus).unwrap_or(GUEST_CPUS),
+                vm_memory_mib: vm
+                    .vm_memory_mib
+                    .or(config.vm.memory_mib)
+                    .unwrap_or(GUEST_MEMORY_MIB),
+                vm_machine_requested: vm.vm_machine.is_some()
+                    || config.vm.machine.is_some(),
+                disk_controller_requested: vm.disk_controller.is_some()
+                    || config.vm.disk_controller.is_some(),
+                nic_model_requested: vm.nic_model.is_some()
+                    || config.vm.nic_model.is_some(),
+                force_memory: *force_memory,
+                force_disk_space: *force_disk_space,
+                shares_host: app.slots_dir.is_some(),
+                qcow2: qcow2.clone().with_config_defaults(config),
+                vhdx: vhdx.clone().with_config_defaults(config),
+                vmdk: vmdk.clone().with_config_defaults(config),
+                output_device: output_device.clone(),
+                oxide: oxide.clone(),
+                s3: s3.clone(),
+                compress: compress.clone().with_config_defaults(config),
+                image_tests: !config.tests.is_empty(),
             },
+            runner,
         )),
-    }
+        Command::Doctor { .. } => {
+            unreachable!("the doctor command doesn't run a script")
+        }
+        Command::ListEditions { .. } => {
+            unreachable!("the list-editions command doesn't run a script")
+        }
+        Command::Inspect { .. } => {
+            unreachable!("the inspect command doesn't run a script")
+        }
+        Command::Shrink { .. } => {
+            unreachable!("the shrink command doesn't run a script")
+        }
+        Command::Convert { .. } => {
+            unreachable!("the convert command doesn't run a script")
+        }
+        Command::BuildAll { .. } => {
+            unreachable!("the build-all command runs other wimsy processes")
+        }
+    })
+}
+
+/// Checks whether this host can build images with the options passed to the
+/// doctor command.
+pub fn check_host(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+) -> Report {
+    let Command::Doctor { disk_size } = &app.command else {
+        unreachable!("only the doctor command checks the host");
+    };
+
+    doctor::check_host(&DoctorArgs {
+        work_dir: app.work_dir.clone(),
+        output_image: app.output_image().to_path_buf(),
+        disk_size: disk_size.or(config.disk.size).unwrap_or(DiskSize::DEFAULT),
+    })
+}
+
+/// Returns the vCPUs and memory, in MiB, that the installation VM of the
+/// build `app` describes will get, or `None` if `app` doesn't run one.
+pub fn installation_vm_size(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+) -> Option<(u32, u64)> {
+    let Command::CreateGuestDiskImage { vm, .. } = &app.command else {
+        return None;
+    };
+
+    Some((
+        vm.vm_cpus.or(config.vm.cpus).unwrap_or(GUEST_CPUS),
+        vm.vm_memory_mib.or(config.vm.memory_mib).unwrap_or(GUEST_MEMORY_MIB),
+    ))
 }
@@ -0,0 +1,558 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Installs Windows in a temporary instance on an Oxide rack instead of in a
+//! local VM, for hosts that can't run VMs themselves (e.g. because they're
+//! VMs that don't offer nested virtualization).
+//!
+//! wimsy uploads the installation disk to the rack, creates a blank disk to
+//! install Windows to and an instance with both disks attached, and waits for
+//! Windows Setup to stop the instance, following the guest's serial console
+//! through the rack's API as it goes. It then deletes the instance, snapshots
+//! the installed disk, creates an image from the snapshot, and deletes both
+//! disks. The rack can't export disks, so the image stays there, and nothing
+//! is written to the output image.
+//!
+//! Like [`crate::oxide`], this module drives the `oxide` CLI, which finds the
+//! rack and credentials from `OXIDE_HOST` and `OXIDE_TOKEN` or a saved
+//! profile.
+
+use std::{
+    io::Write as _,
+    os::unix::net::UnixStream,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    compress::Compression,
+    json::Json,
+    monitor::{ProgressLimits, SerialWatcher, Watchdog},
+    runner::{Context, ScriptStep},
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+use super::create_guest_disk_image::CreateGuestDiskImageArgs;
+
+/// How often to check on the instance and fetch its serial output.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most serial output to fetch from the rack at a time.
+const SERIAL_CHUNK_BYTES: usize = 64 * 1024;
+
+/// The rack sizes disks in whole GiB.
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Checks that the build can run on a rack: the `oxide` CLI must be
+/// installed, the project and image must be named, the VM must be sized the
+/// way the rack requires, and none of the options that work on the local
+/// output image may be set.
+pub(super) fn check_prerequisites(
+    args: &CreateGuestDiskImageArgs,
+) -> Vec<String> {
+    let mut errors = crate::oxide::check_prerequisites("raw");
+    if args.oxide.oxide_project.is_none() {
+        errors.push(
+            "--hypervisor oxide needs --oxide-project and --oxide-image-name \
+            to name the project to build in and the image to create"
+                .to_string(),
+        );
+    }
+
+    if !args.vm_memory_mib.is_multiple_of(1024) {
+        errors.push(format!(
+            "Oxide instances' memory must be a whole number of GiB, but the \
+            installation VM would get {} MiB (pass e.g. --vm-memory-mib 4096)",
+            args.vm_memory_mib
+        ));
+    }
+
+    if !args.disk_size.bytes().is_multiple_of(GIB) {
+        errors.push(format!(
+            "Oxide disks must be a whole number of GiB, but the disk size is \
+            {} (pass e.g. --disk-size 40G)",
+            args.disk_size
+        ));
+    }
+
+    let local_only = [
+        ("--propolis-bootrom", args.propolis_bootrom.is_some()),
+        ("--bhyve-bootrom", args.bhyve_bootrom.is_some()),
+        ("--vnic-link", args.vnic_link.is_some()),
+        ("--qcow2-image", args.qcow2.qcow2_image.is_some()),
+        ("--vhdx-image", args.vhdx.vhdx_image.is_some()),
+        ("--vmdk-image", args.vmdk.vmdk_image.is_some()),
+        ("--output-device", args.output_device.output_device.is_some()),
+        ("--publish", args.s3.publish.is_some()),
+        ("--compress", args.compress.compression() != Compression::None),
+    ];
+    for (option, _) in local_only.iter().filter(|(_, set)| *set) {
+        errors.push(format!(
+            "{option} can't be used with --hypervisor oxide, since the image \
+            is built on the rack and can't be downloaded from it"
+        ));
+    }
+
+    errors
+}
+
+/// Returns the `oxide_project` and `oxide_image` context variables. They're
+/// only missing in dry runs whose prerequisites weren't satisfied, which
+/// still describe the steps.
+fn project_and_image(ctx: &Context) -> (&str, &str) {
+    (
+        ctx.get_var("oxide_project").unwrap_or("PROJECT"),
+        ctx.get_var("oxide_image").unwrap_or("IMAGE"),
+    )
+}
+
+/// The names of the resources the build creates on the rack, which are all
+/// named after the image so that leftovers from a failed build are easy to
+/// find. (Image names leave room for these suffixes.)
+fn installer_disk_name(ctx: &Context) -> String {
+    format!("{}-install", project_and_image(ctx).1)
+}
+
+fn target_disk_name(ctx: &Context) -> String {
+    format!("{}-disk", project_and_image(ctx).1)
+}
+
+fn instance_name(ctx: &Context) -> String {
+    format!("{}-build", project_and_image(ctx).1)
+}
+
+fn snapshot_name(ctx: &Context) -> String {
+    format!("{}-snapshot", project_and_image(ctx).1)
+}
+
+/// Returns an `oxide` command that uses the `oxide_profile` context variable's
+/// profile, if one was named.
+fn oxide(ctx: &Context) -> Command {
+    let mut cmd = Command::new("oxide");
+    if let Some(profile) = ctx.get_var("oxide_profile") {
+        cmd.args(["--profile", profile]);
+    }
+    cmd
+}
+
+/// Returns the path of the file in which to pass the body of a request named
+/// `name` to the CLI.
+fn body_path(ctx: &Context, name: &str) -> Utf8PathBuf {
+    crate::workspace::scratch_path(ctx, &format!("rack-{name}.json"))
+}
+
+/// Writes `body` to the file for the request named `name`, returning its
+/// path.
+fn write_body(ctx: &Context, name: &str, body: &Json) -> Result<Utf8PathBuf> {
+    let path = body_path(ctx, name);
+    std::fs::write(&path, format!("{body:#}\n"))
+        .with_context(|| format!("writing '{path}'"))?;
+    Ok(path)
+}
+
+/// Runs `cmd` and parses what it prints as JSON.
+fn run_json(cmd: &mut Command, ui: &dyn Ui) -> Result<Json> {
+    let output = run_command_check_status(cmd, ui)?;
+    let stdout = String::from_utf8(output.stdout)
+        .context("the oxide CLI printed invalid UTF-8")?;
+    Json::parse(&stdout).context("parsing the oxide CLI's output")
+}
+
+fn import_installer_command(ctx: &Context) -> Command {
+    let (project, image) = project_and_image(ctx);
+    let mut cmd = oxide(ctx);
+    cmd.args(["disk", "import", "--project", project])
+        .args(["--path", ctx.get_var("installer_image").unwrap()])
+        .args(["--disk", &installer_disk_name(ctx)])
+        .args([
+            "--description",
+            &format!("wimsy installation disk for {image}"),
+        ]);
+    cmd
+}
+
+fn upload_installer(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    ui.set_substep("uploading the installation disk to the rack");
+    run_command_check_status(&mut import_installer_command(ctx), ui).map(|_| ())
+}
+
+fn describe_upload_installer(ctx: &mut Context) -> Vec<String> {
+    vec![format_command(&import_installer_command(ctx))]
+}
+
+fn create_command(ctx: &Context, resource: &str, body: &str) -> Command {
+    let mut cmd = oxide(ctx);
+    cmd.args([resource, "create", "--project", project_and_image(ctx).0])
+        .args(["--json-body", body_path(ctx, body).as_str()]);
+    cmd
+}
+
+fn create_target_disk(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let image = project_and_image(ctx).1;
+    let size: u64 = ctx
+        .get_var("disk_size")
+        .unwrap()
+        .parse()
+        .context("parsing disk_size")?;
+    let body = Json::object()
+        .with("name", target_disk_name(ctx))
+        .with("description", format!("wimsy installation target for {image}"))
+        .with("size", size)
+        // 512-byte sectors, like the local VMs' disks.
+        .with(
+            "disk_source",
+            Json::object().with("type", "blank").with("block_size", 512u32),
+        );
+    write_body(ctx, "disk", &body)?;
+    run_command_check_status(&mut create_command(ctx, "disk", "disk"), ui)
+        .map(|_| ())
+}
+
+fn describe_create_target_disk(ctx: &mut Context) -> Vec<String> {
+    vec![format!(
+        "{} (a blank {}-byte disk named {})",
+        format_command(&create_command(ctx, "disk", "disk")),
+        ctx.get_var("disk_size").unwrap(),
+        target_disk_name(ctx)
+    )]
+}
+
+fn create_instance(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let image = project_and_image(ctx).1;
+    let ncpus: u32 =
+        ctx.get_var("vm_cpus").unwrap().parse().context("parsing vm_cpus")?;
+    let memory_mib: u64 = ctx
+        .get_var("vm_memory_mib")
+        .unwrap()
+        .parse()
+        .context("parsing vm_memory_mib")?;
+    let attach =
+        |name: String| Json::object().with("type", "attach").with("name", name);
+    let name = instance_name(ctx);
+    // The installation disk is the only bootable disk until Setup installs
+    // Windows to the target disk and adds its boot entry, so the instance
+    // doesn't need a boot disk.
+    let body = Json::object()
+        .with("name", name.as_str())
+        .with("description", format!("wimsy installation VM for {image}"))
+        .with("hostname", name.as_str())
+        .with("ncpus", ncpus)
+        .with("memory", memory_mib * 1024 * 1024)
+        .with(
+            "disks",
+            vec![
+                attach(target_disk_name(ctx)),
+                attach(installer_disk_name(ctx)),
+            ],
+        )
+        .with("network_interfaces", Json::object().with("type", "default"))
+        .with("start", true);
+    write_body(ctx, "instance", &body)?;
+
+    ui.set_substep("creating and starting the installation instance");
+    run_command_check_status(
+        &mut create_command(ctx, "instance", "instance"),
+        ui,
+    )
+    .map(|_| ())
+}
+
+fn describe_create_instance(ctx: &mut Context) -> Vec<String> {
+    vec![format!(
+        "{} (an instance named {} with {} vCPUs and {} MiB of memory)",
+        format_command(&create_command(ctx, "instance", "instance")),
+        instance_name(ctx),
+        ctx.get_var("vm_cpus").unwrap(),
+        ctx.get_var("vm_memory_mib").unwrap()
+    )]
+}
+
+fn view_instance_command(ctx: &Context) -> Command {
+    let mut cmd = oxide(ctx);
+    cmd.args(["instance", "view", "--project", project_and_image(ctx).0])
+        .args(["--instance", &instance_name(ctx)]);
+    cmd
+}
+
+fn serial_history_command(ctx: &Context, offset: u64) -> Command {
+    let mut cmd = oxide(ctx);
+    cmd.arg("api").arg(format!(
+        "/v1/instances/{}/serial-console?project={}&from_start={offset}\
+        &max_bytes={SERIAL_CHUNK_BYTES}",
+        instance_name(ctx),
+        project_and_image(ctx).0
+    ));
+    cmd
+}
+
+/// Fetches the serial output the instance has produced since `offset`,
+/// returning it and the offset of the output that follows it.
+fn fetch_serial(
+    ctx: &Context,
+    offset: u64,
+    ui: &dyn Ui,
+) -> Result<(Vec<u8>, u64)> {
+    let reply = run_json(&mut serial_history_command(ctx, offset), ui)?;
+    let data = match reply.get("data") {
+        Some(Json::Array(bytes)) => bytes
+            .iter()
+            .map(|byte| byte.as_i64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>(),
+        _ => None,
+    }
+    .context("the rack's serial console reply has no data")?;
+    let next = reply
+        .get("last_byte_offset")
+        .and_then(Json::as_i64)
+        .and_then(|offset| u64::try_from(offset).ok())
+        .context("the rack's serial console reply has no offset")?;
+    Ok((data, next))
+}
+
+fn stop_instance_command(ctx: &Context) -> Command {
+    let mut cmd = oxide(ctx);
+    cmd.args(["instance", "stop", "--project", project_and_image(ctx).0])
+        .args(["--instance", &instance_name(ctx)]);
+    cmd
+}
+
+/// Waits for Windows Setup to stop the installation instance, copying its
+/// serial output to the usual log as it goes and failing if the guest
+/// reports a failure there. If the installation outlasts --install-timeout,
+/// stops the instance and fails. (The instance's disk isn't visible from
+/// here, so hang detection doesn't apply.)
+fn wait_for_install(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    // Feed the serial output through a socket pair so that it's watched for
+    // failures and provisioning results just like a local VM's.
+    let (mut serial_in, serial_out) =
+        UnixStream::pair().context("creating a socket pair")?;
+    let watcher =
+        SerialWatcher::spawn(serial_out, ui.serial_log(&instance_name(ctx))?);
+
+    ui.set_substep(
+        "Waiting for the installation instance to stop (this may take a \
+        while)",
+    );
+    let mut watchdog = Watchdog::new(ProgressLimits::from_context(ctx)?, None);
+    let started = Instant::now();
+    let mut offset = 0;
+    let result = loop {
+        let state = run_json(&mut view_instance_command(ctx), ui)?;
+        let state = state
+            .get("run_state")
+            .and_then(Json::as_str)
+            .unwrap_or("unknown")
+            .to_string();
+
+        // Whatever the state, collect the output the guest wrote before it.
+        loop {
+            let (data, next) = fetch_serial(ctx, offset, ui)?;
+            serial_in.write_all(&data).context("copying serial output")?;
+            offset = next;
+            if data.len() < SERIAL_CHUNK_BYTES {
+                break;
+            }
+        }
+
+        // Give the watcher a moment to read what was just written.
+        std::thread::sleep(Duration::from_millis(100));
+        if let Some(failure) = watcher.failure() {
+            break Err(anyhow::anyhow!(
+                "the guest reported a failure: {failure}"
+            ));
+        }
+
+        if let Err(e) = watchdog.check(Some(&watcher)) {
+            trace::debug!("stopping the installation instance");
+            if let Err(stop) =
+                run_command_check_status(&mut stop_instance_command(ctx), ui)
+            {
+                ui.warn(&format!(
+                    "couldn't stop the installation instance: {stop:#}"
+                ));
+            }
+            break Err(e);
+        }
+
+        match state.as_str() {
+            "stopped" => break Ok(()),
+            "failed" | "destroyed" => {
+                break Err(anyhow::anyhow!(
+                    "the installation instance is {state}"
+                ));
+            }
+            _ => {
+                trace::debug!(
+                    "installation instance still running",
+                    state = state.as_str(),
+                    elapsed_secs = started.elapsed().as_secs()
+                );
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+
+    if let Some(status) = watcher.provision_status() {
+        ui.record_metric("provision_script_status", status);
+    }
+    result
+}
+
+fn describe_wait_for_install(ctx: &mut Context) -> Vec<String> {
+    vec![
+        format!(
+            "{} (every {} seconds, until the instance stops)",
+            format_command(&view_instance_command(ctx)),
+            POLL_INTERVAL.as_secs()
+        ),
+        format_command(&serial_history_command(ctx, 0)),
+    ]
+}
+
+fn delete_instance_command(ctx: &Context) -> Command {
+    let mut cmd = oxide(ctx);
+    cmd.args(["instance", "delete", "--project", project_and_image(ctx).0])
+        .args(["--instance", &instance_name(ctx)]);
+    cmd
+}
+
+fn delete_instance(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    run_command_check_status(&mut delete_instance_command(ctx), ui).map(|_| ())
+}
+
+fn snapshot_command(ctx: &Context) -> Command {
+    let (project, image) = project_and_image(ctx);
+    let mut cmd = oxide(ctx);
+    cmd.args(["snapshot", "create", "--project", project])
+        .args(["--disk", &target_disk_name(ctx)])
+        .args(["--name", &snapshot_name(ctx)])
+        .args(["--description", &format!("Snapshot by wimsy for {image}")]);
+    cmd
+}
+
+/// Snapshots the installed disk and creates the image from the snapshot.
+fn create_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let (project, image) = project_and_image(ctx);
+    ui.set_substep("snapshotting the installed disk");
+    let snapshot = run_json(&mut snapshot_command(ctx), ui)?;
+    let snapshot_id = snapshot
+        .get("id")
+        .and_then(Json::as_str)
+        .context("the oxide CLI didn't print the snapshot's ID")?;
+
+    let version = ctx
+        .get_var("oxide_image_version")
+        .map(str::to_string)
+        .unwrap_or_else(|| crate::oxide::default_version(ctx));
+    let body = Json::object()
+        .with("name", image)
+        .with(
+            "description",
+            ctx.get_var("oxide_image_description")
+                .unwrap_or(crate::oxide::DEFAULT_DESCRIPTION),
+        )
+        .with("os", "windows")
+        .with("version", version)
+        .with(
+            "source",
+            Json::object().with("type", "snapshot").with("id", snapshot_id),
+        );
+    write_body(ctx, "image", &body)?;
+
+    ui.set_substep(&format!("creating image {image}"));
+    run_command_check_status(&mut create_command(ctx, "image", "image"), ui)?;
+
+    let mut published = Json::object();
+    published.insert("project", project);
+    published.insert("image", image);
+    ui.record_metric("oxide_image", published);
+    Ok(())
+}
+
+fn describe_create_image(ctx: &mut Context) -> Vec<String> {
+    vec![
+        format_command(&snapshot_command(ctx)),
+        format!(
+            "{} (an image of the snapshot named {})",
+            format_command(&create_command(ctx, "image", "image")),
+            project_and_image(ctx).1
+        ),
+    ]
+}
+
+fn delete_disk_commands(ctx: &Context) -> Vec<Command> {
+    [installer_disk_name(ctx), target_disk_name(ctx)]
+        .into_iter()
+        .map(|disk| {
+            let mut cmd = oxide(ctx);
+            cmd.args(["disk", "delete", "--project", project_and_image(ctx).0])
+                .args(["--disk", &disk]);
+            cmd
+        })
+        .collect()
+}
+
+fn delete_disks(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    for mut cmd in delete_disk_commands(ctx) {
+        run_command_check_status(&mut cmd, ui)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the steps that build the image on the rack.
+pub(super) fn steps() -> Vec<ScriptStep> {
+    vec![
+        ScriptStep::with_prereqs(
+            "upload-installer-disk",
+            "upload installation disk to Oxide rack",
+            upload_installer,
+            &["oxide"],
+        )
+        .describe(describe_upload_installer),
+        ScriptStep::new(
+            "create-rack-disk",
+            "create installation target disk on rack",
+            create_target_disk,
+        )
+        .describe(describe_create_target_disk),
+        ScriptStep::new(
+            "create-rack-instance",
+            "create installation instance on rack",
+            create_instance,
+        )
+        .describe(describe_create_instance),
+        ScriptStep::new(
+            "install-windows",
+            "run installation on Oxide rack",
+            wait_for_install,
+        )
+        .describe(describe_wait_for_install),
+        ScriptStep::new(
+            "delete-rack-instance",
+            "delete installation instance from rack",
+            delete_instance,
+        )
+        .describe(|ctx| vec![format_command(&delete_instance_command(ctx))]),
+        ScriptStep::new(
+            "create-rack-image",
+            "create Oxide image from installed disk",
+            create_image,
+        )
+        .describe(describe_create_image),
+        ScriptStep::new(
+            "delete-rack-disks",
+            "delete installation disks from rack",
+            delete_disks,
+        )
+        .describe(|ctx| {
+            delete_disk_commands(ctx).iter().map(format_command).collect()
+        }),
+    ]
+}
@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The inspect command, which describes a disk image (one from elsewhere, or
+//! from an earlier build) so that it can be checked before it's deployed: its
+//! partition table, whether its secondary GPT is intact, and which Windows is
+//! installed on it, as read from the SOFTWARE registry hive.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{
+    autounattend::WindowsVersion,
+    error::{Error, ErrorKind},
+    gpt::{Guid, PartitionTable},
+    nbd::RawImage,
+    registry::{Hive, Value},
+    ui::StandaloneUi,
+    util::format_size,
+};
+
+/// Where Windows keeps the hive that records its version.
+const SOFTWARE_HIVE: &str = "Windows/System32/config/SOFTWARE";
+
+/// The key in the SOFTWARE hive holding the version.
+const CURRENT_VERSION_KEY: &str = "Microsoft\\Windows NT\\CurrentVersion";
+
+/// The names of the partition types Windows images usually have.
+const PARTITION_TYPES: [(&str, &str); 7] = [
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI system"),
+    ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft reserved"),
+    ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "Microsoft basic data"),
+    ("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC", "Windows recovery"),
+    ("5808C8AA-7E8F-42E0-85D2-E1E90434CFB3", "LDM metadata"),
+    ("AF9B60A0-1431-4F62-BC68-3311714A69AD", "LDM data"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+];
+
+fn type_name(guid: &Guid) -> String {
+    PARTITION_TYPES
+        .iter()
+        .find(|(text, _)| text.parse::<Guid>().ok().as_ref() == Some(guid))
+        .map_or_else(|| guid.to_string(), |(_, name)| name.to_string())
+}
+
+/// Lays out `table`'s partitions as a table with a header row.
+fn partition_lines(table: &PartitionTable) -> Vec<String> {
+    let mut rows =
+        vec![["NUMBER", "START", "END", "SIZE", "TYPE", "NAME"]
+            .map(str::to_string)];
+    for partition in &table.partitions {
+        rows.push([
+            partition.number.to_string(),
+            partition.first_lba.to_string(),
+            partition.last_lba.to_string(),
+            format_size(partition.sectors() * table.sector_size),
+            type_name(&partition.type_guid),
+            partition.name.clone(),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (cell, width) in row.iter().zip(widths) {
+                line.push_str(&format!("{cell:width$}  "));
+            }
+            line.push_str(&row[5]);
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+/// Finds installed Windows in `table`'s basic data partitions, largest
+/// first, returning the partition's number and its SOFTWARE hive. If it
+/// isn't found, returns why not for each partition.
+fn find_windows<R: Read + Seek>(
+    disk: &mut R,
+    table: &PartitionTable,
+) -> std::result::Result<(u32, Hive), Vec<String>> {
+    let mut partitions: Vec<_> = table
+        .partitions
+        .iter()
+        .filter(|p| p.type_guid == Guid::BASIC_DATA)
+        .collect();
+    partitions.sort_by_key(|p| std::cmp::Reverse(p.sectors()));
+
+    let mut reasons = Vec::new();
+    for partition in partitions {
+        let offset = partition.first_lba * table.sector_size;
+        let hive = crate::ntfs::Volume::open(&mut *disk, offset)
+            .and_then(|mut volume| volume.read_file(SOFTWARE_HIVE))
+            .and_then(Hive::new);
+        match hive {
+            Ok(hive) => return Ok((partition.number, hive)),
+            Err(e) => {
+                reasons.push(format!("partition {}: {e:#}", partition.number))
+            }
+        }
+    }
+    if reasons.is_empty() {
+        reasons.push("there are no basic data partitions".to_string());
+    }
+    Err(reasons)
+}
+
+/// Describes the Windows whose SOFTWARE hive is `hive`.
+fn describe_windows(hive: &Hive) -> Result<Vec<(&'static str, String)>> {
+    let string = |name| hive.string(CURRENT_VERSION_KEY, name);
+    let build = string("CurrentBuildNumber")?;
+    let ubr = match hive.value(CURRENT_VERSION_KEY, "UBR")? {
+        Some(Value::Dword(ubr)) => Some(ubr),
+        _ => None,
+    };
+    let installation_type = string("InstallationType")?;
+
+    // Windows 11's ProductName still says Windows 10, so name the release
+    // after its build number where that's known.
+    let client = installation_type.as_deref() == Some("Client");
+    let version = build
+        .as_deref()
+        .and_then(|build| build.parse().ok())
+        .and_then(|build| WindowsVersion::from_build(build, client));
+
+    let mut fields = Vec::new();
+    fields.extend(version.map(|version| ("Version", version.to_string())));
+    fields.extend(string("ProductName")?.map(|name| ("Product name", name)));
+    fields.extend(string("EditionID")?.map(|edition| ("Edition", edition)));
+    let release = match string("DisplayVersion")? {
+        Some(release) => Some(release),
+        None => string("ReleaseId")?,
+    };
+    fields.extend(release.map(|release| ("Release", release)));
+    fields.extend(build.map(|build| {
+        let build = match ubr {
+            Some(ubr) => format!("{build}.{ubr}"),
+            None => build,
+        };
+        ("Build", build)
+    }));
+    fields.extend(installation_type.map(|kind| ("Installation type", kind)));
+    Ok(fields)
+}
+
+/// Describes the raw disk `disk`, which holds the image at `image`, in
+/// `format`.
+fn describe<R: Read + Seek>(
+    disk: &mut R,
+    image: &Utf8Path,
+    format: &str,
+) -> Result<Vec<String>> {
+    let size = disk.seek(SeekFrom::End(0))?;
+    let mut lines = vec![format!(
+        "Image:          {image} ({format}, {})",
+        format_size(size)
+    )];
+
+    let table = crate::gpt::read(disk).map_err(|e| {
+        Error::new(ErrorKind::PartitionTable, format!("{e:#}")).with_hint(
+            "only images partitioned with a GUID partition table can be \
+            inspected",
+        )
+    })?;
+    lines.push(format!(
+        "Partition table: GPT, {}-byte sectors, disk GUID {}",
+        table.sector_size, table.disk_guid
+    ));
+    lines.push(String::new());
+    lines.extend(partition_lines(&table).into_iter().map(|l| format!("  {l}")));
+    lines.push(String::new());
+
+    lines.push(match crate::gpt::check_secondary(disk) {
+        Ok(()) => "Secondary GPT:  intact".to_string(),
+        Err(e) => format!("Secondary GPT:  damaged ({e:#})"),
+    });
+
+    match find_windows(disk, &table) {
+        Ok((partition, hive)) => {
+            lines.push(format!("Windows:        on partition {partition}"));
+            for (label, value) in describe_windows(&hive)? {
+                lines.push(format!("  {:<20}{value}", format!("{label}:")));
+            }
+        }
+        Err(reasons) => {
+            lines.push("Windows:        not found".to_string());
+            lines.extend(reasons.iter().map(|reason| format!("  {reason}")));
+        }
+    }
+    Ok(lines)
+}
+
+/// Prints a description of the raw or qcow2 image at `image`. qcow2 images
+/// are attached to a network block device to be read, as the build's steps
+/// attach them.
+pub fn run(image: &Utf8Path) -> Result<()> {
+    let format = crate::nbd::image_format(image)?;
+    crate::nbd::ensure_can_attach(image, format)?;
+
+    let raw = RawImage::open(image, format, &StandaloneUi)?;
+    let mut disk = File::open(raw.path())
+        .with_context(|| format!("opening '{}'", raw.path()))?;
+    for line in describe(&mut disk, image, format)? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpt::Partition;
+
+    #[test]
+    fn lists_partitions() {
+        let partition =
+            |number, first_lba, last_lba, type_guid: &str| Partition {
+                number,
+                type_guid: type_guid.parse().unwrap(),
+                unique_guid: Guid([0; 16]),
+                first_lba,
+                last_lba,
+                attributes: 0,
+                name: String::new(),
+            };
+        let linux = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+        let mut os = partition(4, 1_820_672, 62_912_511, PARTITION_TYPES[2].0);
+        os.name = "Basic data partition".to_string();
+        let table = PartitionTable {
+            sector_size: 512,
+            disk_guid: Guid([0; 16]),
+            partitions: vec![
+                partition(1, 2048, 1_026_047, linux),
+                partition(2, 1_026_048, 1_558_527, PARTITION_TYPES[0].0),
+                partition(3, 1_558_528, 1_820_671, PARTITION_TYPES[1].0),
+                os,
+            ],
+        };
+
+        assert_eq!(
+            partition_lines(&table),
+            [
+                "NUMBER  START    END       SIZE      \
+                TYPE                                  NAME",
+                "1       2048     1026047   500 MiB   \
+                0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+                "2       1026048  1558527   260 MiB   EFI system",
+                "3       1558528  1820671   128 MiB   Microsoft reserved",
+                "4       1820672  62912511  29.1 GiB  \
+                Microsoft basic data                  Basic data partition",
+            ]
+        );
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(12), "12 bytes");
+    }
+}
@@ -0,0 +1,280 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Handles SIGINT and SIGTERM. Normally either signal cancels the build:
+//! `wimsy` stops the long-running child processes it started (some of which,
+//! like the installation VM, don't receive the terminal's Ctrl-C), removes
+//! the files it was partway through writing, undoes host changes such as
+//! attached network block devices, says where the logs are, and exits.
+//! Long-running steps can instead respond to Ctrl-C themselves (e.g. by
+//! shutting the installation VM down cleanly) while a [`CatchInterrupts`] is
+//! alive.
+
+use std::{
+    collections::BTreeMap,
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::trace;
+
+/// How long cancelled child processes get to exit after SIGTERM before
+/// they're killed.
+const CHILD_EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// The number of SIGINTs received since the current [`CatchInterrupts`] was
+/// created.
+static INTERRUPTS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a [`CatchInterrupts`] is alive.
+static CATCHING: AtomicBool = AtomicBool::new(false);
+
+/// The write end of the pipe through which the signal handler wakes the
+/// cancellation thread, or -1 if [`handle_cancellation`] hasn't been called.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// What to clean up if the build is cancelled.
+enum Cleanup {
+    Kill(u32),
+    Run(Command),
+    Remove(Utf8PathBuf),
+}
+
+static NEXT_CLEANUP_ID: AtomicU64 = AtomicU64::new(0);
+static CLEANUPS: Mutex<BTreeMap<u64, Cleanup>> = Mutex::new(BTreeMap::new());
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if signal == libc::SIGINT && CATCHING.load(Ordering::SeqCst) {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    if fd < 0 {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(128 + signal) };
+    }
+
+    // SAFETY: `write` is async-signal-safe, and `byte` outlives the call.
+    let byte = signal as u8;
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+fn install_handler(signal: libc::c_int) -> libc::sighandler_t {
+    let handler = on_signal as extern "C" fn(libc::c_int);
+
+    // SAFETY: the handler only touches atomics and calls async-signal-safe
+    // functions.
+    unsafe { libc::signal(signal, handler as libc::sighandler_t) }
+}
+
+/// Makes SIGINT and SIGTERM cancel the build, cleaning up what was registered
+/// with [`kill_on_cancel`], [`run_on_cancel`], and [`remove_on_cancel`] and
+/// then exiting.
+/// `work_dir` is named in the message saying where the logs were kept.
+pub fn handle_cancellation(work_dir: &Utf8Path) -> std::io::Result<()> {
+    let mut fds = [0; 2];
+
+    // SAFETY: `fds` has room for the two descriptors `pipe` returns.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let [read_fd, write_fd] = fds;
+    let work_dir = work_dir.to_path_buf();
+    std::thread::Builder::new().name("cancellation".to_string()).spawn(
+        move || {
+            let mut byte = 0u8;
+
+            // SAFETY: `byte` is a valid, writable one-byte buffer.
+            let read = unsafe {
+                libc::read(
+                    read_fd,
+                    &mut byte as *mut u8 as *mut libc::c_void,
+                    1,
+                )
+            };
+            if read == 1 {
+                cancel(libc::c_int::from(byte), &work_dir);
+            }
+        },
+    )?;
+
+    SIGNAL_PIPE.store(write_fd, Ordering::SeqCst);
+    install_handler(libc::SIGINT);
+    install_handler(libc::SIGTERM);
+    Ok(())
+}
+
+/// Cleans up like [`clean_up`] and exits as though killed by `signal`.
+fn cancel(signal: libc::c_int, work_dir: &Utf8Path) -> ! {
+    let name = if signal == libc::SIGTERM { "SIGTERM" } else { "SIGINT" };
+    trace::info!("build cancelled", signal = name);
+    eprintln!("\nwimsy: cancelling the build ({name})");
+    clean_up();
+    eprintln!(
+        "wimsy: logs are in {}; pass --resume to continue from the last step \
+        that completed",
+        crate::workspace::logs_dir(work_dir)
+    );
+    std::process::exit(128 + signal);
+}
+
+/// Stops the registered child processes, runs the registered commands, and
+/// removes the registered files, as cancelling the build does. Builds that
+/// stop early for other reasons, such as at a user's request, call this
+/// themselves.
+pub fn clean_up() {
+    let cleanups = std::mem::take(
+        &mut *CLEANUPS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+    let mut children = Vec::new();
+    let mut commands = Vec::new();
+    let mut files = Vec::new();
+    for cleanup in cleanups.into_values() {
+        match cleanup {
+            Cleanup::Kill(pid) => children.push(pid as libc::pid_t),
+            Cleanup::Run(cmd) => commands.push(cmd),
+            Cleanup::Remove(path) => files.push(path),
+        }
+    }
+
+    for pid in &children {
+        // SAFETY: sending a signal has no memory-safety requirements.
+        unsafe { libc::kill(*pid, libc::SIGTERM) };
+    }
+
+    // Reap the children here, since the thread that spawned them won't get
+    // the chance. `waitpid` also fails once a child has been reaped
+    // elsewhere.
+    let deadline = Instant::now() + CHILD_EXIT_GRACE;
+    let mut running = children;
+    while !running.is_empty() {
+        running.retain(|pid| {
+            // SAFETY: a null status pointer is allowed.
+            let reaped = unsafe {
+                libc::waitpid(*pid, std::ptr::null_mut(), libc::WNOHANG)
+            };
+            reaped == 0
+        });
+        if Instant::now() >= deadline {
+            for pid in &running {
+                trace::info!("killing child process", pid = i64::from(*pid));
+                // SAFETY: as above.
+                unsafe { libc::kill(*pid, libc::SIGKILL) };
+            }
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // The commands undo what the stopped children left behind, so they run
+    // once the children are gone.
+    for mut cmd in commands {
+        let command = crate::util::format_command(&cmd);
+        match cmd.output() {
+            Ok(output) if output.status.success() => {
+                eprintln!("wimsy: ran {command}");
+            }
+            Ok(output) => {
+                eprintln!("wimsy: {command} failed ({})", output.status);
+            }
+            Err(e) => eprintln!("wimsy: couldn't run {command}: {e}"),
+        }
+    }
+
+    for path in &files {
+        match std::fs::remove_file(path) {
+            Ok(()) => eprintln!("wimsy: removed partially written {path}"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("wimsy: couldn't remove {path}: {e}"),
+        }
+    }
+}
+
+/// Undoes a [`kill_on_cancel`], [`run_on_cancel`], or [`remove_on_cancel`]
+/// registration when dropped.
+#[must_use]
+pub struct CancelGuard(u64);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        CLEANUPS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.0);
+    }
+}
+
+fn register(cleanup: Cleanup) -> CancelGuard {
+    let id = NEXT_CLEANUP_ID.fetch_add(1, Ordering::SeqCst);
+    CLEANUPS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(id, cleanup);
+    CancelGuard(id)
+}
+
+/// Stops `child` if the build is cancelled while the returned guard is alive.
+pub fn kill_on_cancel(child: &Child) -> CancelGuard {
+    register(Cleanup::Kill(child.id()))
+}
+
+/// Runs `cmd` if the build is cancelled while the returned guard is alive,
+/// e.g. to undo a change to the host that dropping a value would otherwise
+/// undo.
+pub fn run_on_cancel(cmd: Command) -> CancelGuard {
+    register(Cleanup::Run(cmd))
+}
+
+/// Removes `path` if the build is cancelled while the returned guard is
+/// alive, i.e. while it's only partly written.
+pub fn remove_on_cancel(path: &Utf8Path) -> CancelGuard {
+    register(Cleanup::Remove(path.to_path_buf()))
+}
+
+/// Counts SIGINTs instead of letting them cancel the build (or terminate the
+/// process), for as long as it's alive. Only one should exist at a time.
+pub struct CatchInterrupts {
+    previous: libc::sighandler_t,
+}
+
+impl CatchInterrupts {
+    pub fn new() -> Self {
+        INTERRUPTS.store(0, Ordering::SeqCst);
+        CATCHING.store(true, Ordering::SeqCst);
+        Self { previous: install_handler(libc::SIGINT) }
+    }
+
+    /// Returns how many times the user has pressed Ctrl-C.
+    pub fn count(&self) -> u32 {
+        INTERRUPTS.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for CatchInterrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for CatchInterrupts {
+    fn drop(&mut self) {
+        CATCHING.store(false, Ordering::SeqCst);
+
+        // SAFETY: restores the handler that was installed before this one
+        // (which is this module's own if `handle_cancellation` was called).
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
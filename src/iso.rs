@@ -0,0 +1,601 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Writes ISO 9660 images with Joliet extensions.
+//!
+//! The ISOs wimsy attaches to installation VMs (the unattend files and packed
+//! driver directories) are small and only need to be readable, so this writer
+//! supports just enough of the format to lay out a directory tree: a primary
+//! volume descriptor with uppercase, length-limited names and a Joliet
+//! supplementary volume descriptor that preserves the files' real names.
+//! Windows reads the Joliet names. Both trees share a single copy of each
+//! file's data. Timestamps are left unspecified, so an image's contents depend
+//! only on the directory it was made from.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{BufWriter, Read, Write},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+const SECTOR_SIZE: usize = 2048;
+
+/// The sector holding the first volume descriptor. The sectors before it are
+/// the system area, which is left empty.
+const FIRST_DESCRIPTOR_SECTOR: u32 = 16;
+
+/// The longest identifier (including the ";1" version suffix) allowed in the
+/// primary directory tree. This is ISO 9660 level 2's limit, which some
+/// readers enforce.
+const MAX_PRIMARY_IDENT: usize = 31;
+
+/// The longest name, in UTF-16 code units, allowed in the Joliet tree.
+const MAX_JOLIET_NAME: usize = 64;
+
+/// The length of a directory record with a one-byte identifier, such as the
+/// "." and ".." records at the start of each directory.
+const DOT_RECORD_LEN: usize = 34;
+
+/// A file or directory in the tree being written.
+struct Node {
+    name: String,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    /// A file, identified by its index in the list of files whose data the
+    /// image holds.
+    File(usize),
+    Dir(Vec<Node>),
+}
+
+/// A file whose contents will be written to the image.
+struct FileData {
+    path: Utf8PathBuf,
+    size: u32,
+}
+
+/// Reads the tree rooted at `dir`, adding the files in it to `files`.
+fn scan(dir: &Utf8Path, files: &mut Vec<FileData>) -> Result<Vec<Node>> {
+    let entries = dir
+        .read_dir_utf8()
+        .with_context(|| format!("reading directory '{dir}'"))?;
+
+    let mut nodes = Vec::new();
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!("reading directory '{dir}'"))?;
+        let path = entry.path();
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("getting metadata for '{path}'"))?;
+        let name = entry.file_name().to_string();
+        if metadata.is_dir() {
+            let children = scan(path, files)?;
+            nodes.push(Node { name, kind: NodeKind::Dir(children) });
+        } else if metadata.is_file() {
+            let size = u32::try_from(metadata.len()).map_err(|_| {
+                anyhow::anyhow!("'{path}' is too large to put in an ISO")
+            })?;
+            files.push(FileData { path: path.to_path_buf(), size });
+            nodes.push(Node { name, kind: NodeKind::File(files.len() - 1) });
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// The two naming schemes an image's directory trees use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Naming {
+    /// Uppercase letters, digits, and underscores, with a ";1" version
+    /// suffix on file names.
+    Primary,
+
+    /// The files' real names, encoded as big-endian UCS-2.
+    Joliet,
+}
+
+/// Maps `s` to the characters the primary tree allows, keeping at most `max`
+/// of them.
+fn d_characters(s: &str, max: usize) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .take(max)
+        .collect()
+}
+
+/// Returns the primary-tree identifier for the entry `name`. `suffix` is
+/// appended to the end of the file name (or directory name) to make it
+/// unique among its siblings.
+fn primary_ident(name: &str, is_dir: bool, suffix: &str) -> String {
+    if is_dir {
+        let base = d_characters(name, MAX_PRIMARY_IDENT - suffix.len());
+        return format!("{base}{suffix}");
+    }
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (name, ""),
+    };
+
+    // Leave room for the ".", the ";1", and at least a few characters of
+    // the stem.
+    let ext = d_characters(ext, 8);
+    let stem_max = MAX_PRIMARY_IDENT - 3 - ext.len() - suffix.len();
+    let stem = d_characters(stem, stem_max);
+    format!("{stem}{suffix}.{ext};1")
+}
+
+impl Naming {
+    /// Returns the identifier of each of `nodes`, which are siblings, in
+    /// this naming scheme.
+    fn identifiers(self, nodes: &[Node]) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Naming::Primary => {
+                let mut used = HashSet::new();
+                Ok(nodes
+                    .iter()
+                    .map(|node| {
+                        let is_dir = matches!(node.kind, NodeKind::Dir(_));
+                        let mut ident = primary_ident(&node.name, is_dir, "");
+                        let mut n = 1;
+                        while !used.insert(ident.clone()) {
+                            let suffix = format!("_{n}");
+                            ident = primary_ident(&node.name, is_dir, &suffix);
+                            n += 1;
+                        }
+                        ident.into_bytes()
+                    })
+                    .collect())
+            }
+            Naming::Joliet => nodes
+                .iter()
+                .map(|node| {
+                    let units: Vec<u16> = node
+                        .name
+                        .chars()
+                        .map(|c| match c {
+                            '*' | '/' | ':' | ';' | '?' | '\\' => '_',
+                            c => c,
+                        })
+                        .collect::<String>()
+                        .encode_utf16()
+                        .collect();
+                    if units.len() > MAX_JOLIET_NAME {
+                        anyhow::bail!(
+                            "'{}' is longer than the {MAX_JOLIET_NAME} \
+                            characters ISO file names can have",
+                            node.name
+                        );
+                    }
+
+                    Ok(units.iter().flat_map(|u| u.to_be_bytes()).collect())
+                })
+                .collect(),
+        }
+    }
+
+    /// Encodes `s` for a text field of `len` bytes in this scheme's volume
+    /// descriptor, padding it with spaces.
+    fn text_field(self, s: &str, len: usize) -> Vec<u8> {
+        let mut field: Vec<u8> = match self {
+            Naming::Primary => d_characters(s, len).into_bytes(),
+            Naming::Joliet => s
+                .encode_utf16()
+                .take(len / 2)
+                .flat_map(|u| u.to_be_bytes())
+                .collect(),
+        };
+
+        while field.len() < len {
+            match self {
+                Naming::Primary => field.push(b' '),
+                Naming::Joliet => field.extend([0, b' ']),
+            }
+        }
+        field.truncate(len);
+        field
+    }
+}
+
+/// What a directory record points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    /// A directory, by its index in its tree's directory list.
+    Dir(usize),
+
+    /// A file, by its index in the image's file list.
+    File(usize),
+}
+
+/// A directory in one of the image's directory trees.
+struct TreeDir {
+    ident: Vec<u8>,
+
+    /// The index of the directory's parent in its tree's directory list.
+    /// The root directory is its own parent.
+    parent: usize,
+
+    /// The directory's entries (other than "." and ".."), sorted by
+    /// identifier.
+    records: Vec<(Vec<u8>, Target)>,
+}
+
+/// Lays out the directories under `root` in `naming`'s scheme. Directories
+/// are listed breadth-first, with siblings sorted by identifier, which is the
+/// order the path table requires.
+fn build_tree(root: &[Node], naming: Naming) -> Result<Vec<TreeDir>> {
+    let mut dirs =
+        vec![TreeDir { ident: vec![0], parent: 0, records: Vec::new() }];
+    let mut queue = VecDeque::from([(0, root)]);
+    while let Some((index, nodes)) = queue.pop_front() {
+        let mut children: Vec<(Vec<u8>, &Node)> =
+            naming.identifiers(nodes)?.into_iter().zip(nodes).collect();
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        for (ident, node) in children {
+            let target = match &node.kind {
+                NodeKind::File(file) => Target::File(*file),
+                NodeKind::Dir(children) => {
+                    dirs.push(TreeDir {
+                        ident: ident.clone(),
+                        parent: index,
+                        records: Vec::new(),
+                    });
+                    queue.push_back((dirs.len() - 1, children.as_slice()));
+                    Target::Dir(dirs.len() - 1)
+                }
+            };
+            dirs[index].records.push((ident, target));
+        }
+    }
+
+    Ok(dirs)
+}
+
+fn record_len(ident_len: usize) -> usize {
+    let len = 33 + ident_len;
+    len + len % 2
+}
+
+fn sectors(bytes: usize) -> u32 {
+    bytes.div_ceil(SECTOR_SIZE) as u32
+}
+
+/// Returns the size, in bytes, of a directory extent holding records of the
+/// supplied lengths. Records can't cross sector boundaries.
+fn extent_size(record_lens: impl Iterator<Item = usize>) -> usize {
+    let mut pos = 0;
+    for len in record_lens {
+        if pos % SECTOR_SIZE + len > SECTOR_SIZE {
+            pos = pos.next_multiple_of(SECTOR_SIZE);
+        }
+        pos += len;
+    }
+    pos.next_multiple_of(SECTOR_SIZE)
+}
+
+impl TreeDir {
+    fn extent_size(&self) -> usize {
+        let records = self.records.iter().map(|(ident, _)| ident.len());
+        extent_size(
+            [DOT_RECORD_LEN, DOT_RECORD_LEN]
+                .into_iter()
+                .chain(records.map(record_len)),
+        )
+    }
+}
+
+fn both_endian_u16(value: u16) -> [u8; 4] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [le[0], le[1], be[0], be[1]]
+}
+
+fn both_endian_u32(value: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+    bytes[4..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+/// Encodes a directory record. The recording date is left unspecified.
+fn dir_record(ident: &[u8], extent: u32, size: u32, is_dir: bool) -> Vec<u8> {
+    let mut record = vec![0; record_len(ident.len())];
+    record[0] = record.len() as u8;
+    record[2..10].copy_from_slice(&both_endian_u32(extent));
+    record[10..18].copy_from_slice(&both_endian_u32(size));
+    record[25] = if is_dir { 2 } else { 0 };
+    record[28..32].copy_from_slice(&both_endian_u16(1));
+    record[32] = ident.len() as u8;
+    record[33..33 + ident.len()].copy_from_slice(ident);
+    record
+}
+
+/// A directory tree whose directories have been assigned locations in the
+/// image.
+struct PlacedTree {
+    naming: Naming,
+    dirs: Vec<TreeDir>,
+
+    /// The first sector and size of each directory's extent.
+    extents: Vec<(u32, u32)>,
+    path_table_size: usize,
+
+    /// The sectors holding the little- and big-endian path tables.
+    path_tables: (u32, u32),
+}
+
+/// A file whose data has been assigned a location in the image.
+struct PlacedFile {
+    data: FileData,
+    sector: u32,
+}
+
+impl PlacedTree {
+    fn path_table(&self, big_endian: bool) -> Vec<u8> {
+        let mut table = Vec::with_capacity(self.path_table_size);
+        for (dir, (extent, _)) in self.dirs.iter().zip(&self.extents) {
+            let parent = (dir.parent + 1) as u16;
+            table.push(dir.ident.len() as u8);
+            table.push(0);
+            if big_endian {
+                table.extend(extent.to_be_bytes());
+                table.extend(parent.to_be_bytes());
+            } else {
+                table.extend(extent.to_le_bytes());
+                table.extend(parent.to_le_bytes());
+            }
+            table.extend(&dir.ident);
+            if dir.ident.len() % 2 == 1 {
+                table.push(0);
+            }
+        }
+        table
+    }
+
+    fn target_record(
+        &self,
+        ident: &[u8],
+        target: Target,
+        files: &[PlacedFile],
+    ) -> Vec<u8> {
+        match target {
+            Target::Dir(dir) => {
+                let (extent, size) = self.extents[dir];
+                dir_record(ident, extent, size, true)
+            }
+            Target::File(file) => {
+                let file = &files[file];
+                dir_record(ident, file.sector, file.data.size, false)
+            }
+        }
+    }
+
+    /// Encodes the extent of the directory at `index`.
+    fn dir_extent(&self, index: usize, files: &[PlacedFile]) -> Vec<u8> {
+        let dir = &self.dirs[index];
+        let mut records = vec![
+            self.target_record(&[0], Target::Dir(index), files),
+            self.target_record(&[1], Target::Dir(dir.parent), files),
+        ];
+        for (ident, target) in &dir.records {
+            records.push(self.target_record(ident, *target, files));
+        }
+
+        let mut extent = Vec::with_capacity(self.extents[index].1 as usize);
+        for record in records {
+            if extent.len() % SECTOR_SIZE + record.len() > SECTOR_SIZE {
+                extent.resize(extent.len().next_multiple_of(SECTOR_SIZE), 0);
+            }
+            extent.extend(record);
+        }
+        extent.resize(extent.len().next_multiple_of(SECTOR_SIZE), 0);
+        extent
+    }
+
+    /// Encodes this tree's volume descriptor.
+    fn volume_descriptor(
+        &self,
+        volume_id: &str,
+        volume_sectors: u32,
+        files: &[PlacedFile],
+    ) -> Vec<u8> {
+        // If no dates are specified, each date field holds sixteen ASCII
+        // zeroes and a zero time zone offset.
+        const NO_DATE: &[u8; 17] = b"0000000000000000\0";
+
+        let mut desc = vec![0; SECTOR_SIZE];
+        desc[0] = match self.naming {
+            Naming::Primary => 1,
+            Naming::Joliet => 2,
+        };
+        desc[1..6].copy_from_slice(b"CD001");
+        desc[6] = 1;
+        desc[8..40].copy_from_slice(&self.naming.text_field("", 32));
+        desc[40..72].copy_from_slice(&self.naming.text_field(volume_id, 32));
+        desc[80..88].copy_from_slice(&both_endian_u32(volume_sectors));
+        if self.naming == Naming::Joliet {
+            // UCS-2 level 3.
+            desc[88..91].copy_from_slice(b"%/E");
+        }
+        desc[120..124].copy_from_slice(&both_endian_u16(1));
+        desc[124..128].copy_from_slice(&both_endian_u16(1));
+        desc[128..132].copy_from_slice(&both_endian_u16(SECTOR_SIZE as u16));
+        desc[132..140]
+            .copy_from_slice(&both_endian_u32(self.path_table_size as u32));
+        desc[140..144].copy_from_slice(&self.path_tables.0.to_le_bytes());
+        desc[148..152].copy_from_slice(&self.path_tables.1.to_be_bytes());
+        desc[156..190].copy_from_slice(&self.target_record(
+            &[0],
+            Target::Dir(0),
+            files,
+        ));
+        for (start, len) in [(190, 128), (318, 128), (446, 128)] {
+            desc[start..start + len]
+                .copy_from_slice(&self.naming.text_field("", len));
+        }
+        desc[574..702].copy_from_slice(&self.naming.text_field("WIMSY", 128));
+        for (start, len) in [(702, 37), (739, 37), (776, 37)] {
+            desc[start..start + len]
+                .copy_from_slice(&self.naming.text_field("", len));
+        }
+        for start in [813, 830, 847, 864] {
+            desc[start..start + 17].copy_from_slice(NO_DATE);
+        }
+        desc[881] = 1;
+        desc
+    }
+}
+
+/// Writes an ISO image of the directory `source` to `output`, giving it the
+/// volume label `volume_id`.
+pub fn create_iso(
+    source: &Utf8Path,
+    output: &Utf8Path,
+    volume_id: &str,
+) -> Result<()> {
+    let _cancel = crate::interrupt::remove_on_cancel(output);
+    let mut files = Vec::new();
+    let root = scan(source, &mut files)?;
+    let trees = [
+        build_tree(&root, Naming::Primary)?,
+        build_tree(&root, Naming::Joliet)?,
+    ];
+
+    // Lay out the image: the system area, the primary and Joliet volume
+    // descriptors and the descriptor set terminator, then each tree's path
+    // tables and directories, then the files' contents.
+    let mut next_sector = FIRST_DESCRIPTOR_SECTOR + 3;
+    let mut placed = Vec::new();
+    for (naming, dirs) in
+        [Naming::Primary, Naming::Joliet].into_iter().zip(trees)
+    {
+        let path_table_size = dirs
+            .iter()
+            .map(|dir| 8 + dir.ident.len() + dir.ident.len() % 2)
+            .sum();
+        let table_sectors = sectors(path_table_size);
+        let path_tables = (next_sector, next_sector + table_sectors);
+        next_sector += 2 * table_sectors;
+        placed.push(PlacedTree {
+            naming,
+            dirs,
+            extents: Vec::new(),
+            path_table_size,
+            path_tables,
+        });
+    }
+
+    for tree in &mut placed {
+        for dir in &tree.dirs {
+            let size = dir.extent_size();
+            tree.extents.push((next_sector, size as u32));
+            next_sector += sectors(size);
+        }
+    }
+
+    let files: Vec<PlacedFile> = files
+        .into_iter()
+        .map(|data| {
+            let sector = next_sector;
+            next_sector += sectors(data.size as usize);
+            PlacedFile { data, sector }
+        })
+        .collect();
+
+    let file = File::create(output)
+        .with_context(|| format!("creating ISO '{output}'"))?;
+    let mut out = BufWriter::new(file);
+    let write_err = || format!("writing ISO '{output}'");
+    out.write_all(&vec![0; FIRST_DESCRIPTOR_SECTOR as usize * SECTOR_SIZE])
+        .with_context(write_err)?;
+    for tree in &placed {
+        out.write_all(&tree.volume_descriptor(volume_id, next_sector, &files))
+            .with_context(write_err)?;
+    }
+
+    let mut terminator = vec![0; SECTOR_SIZE];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+    out.write_all(&terminator).with_context(write_err)?;
+
+    for tree in &placed {
+        for big_endian in [false, true] {
+            let mut table = tree.path_table(big_endian);
+            table.resize(table.len().next_multiple_of(SECTOR_SIZE), 0);
+            out.write_all(&table).with_context(write_err)?;
+        }
+    }
+
+    for tree in &placed {
+        for index in 0..tree.dirs.len() {
+            out.write_all(&tree.dir_extent(index, &files))
+                .with_context(write_err)?;
+        }
+    }
+
+    for file in &files {
+        let path = &file.data.path;
+        let mut input = File::open(path)
+            .with_context(|| format!("opening '{path}'"))?
+            .take(file.data.size as u64);
+        let copied = std::io::copy(&mut input, &mut out)
+            .with_context(|| format!("copying '{path}' into ISO"))?;
+        if copied != file.data.size as u64 {
+            anyhow::bail!("'{path}' changed while it was being copied");
+        }
+
+        let padding =
+            (copied as usize).next_multiple_of(SECTOR_SIZE) - copied as usize;
+        out.write_all(&vec![0; padding]).with_context(write_err)?;
+    }
+
+    out.into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(write_err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_primary_names() {
+        assert_eq!(
+            primary_ident("Autounattend.xml", false, ""),
+            "AUTOUNATTEND.XML;1"
+        );
+        assert_eq!(primary_ident("prep.cmd", false, "_1"), "PREP_1.CMD;1");
+        assert_eq!(primary_ident(".hidden", false, ""), "_HIDDEN.;1");
+        assert_eq!(primary_ident("NetKVM", true, ""), "NETKVM");
+
+        let long = primary_ident(&"a".repeat(40), false, "");
+        assert_eq!(long.len(), MAX_PRIMARY_IDENT);
+
+        let nodes: Vec<Node> = ["a-b.txt", "a_b.txt", "A.B.TXT"]
+            .iter()
+            .map(|name| Node {
+                name: name.to_string(),
+                kind: NodeKind::File(0),
+            })
+            .collect();
+        let idents = Naming::Primary.identifiers(&nodes).unwrap();
+        let idents: Vec<&[u8]> = idents.iter().map(Vec::as_slice).collect();
+        assert_eq!(idents, [&b"A_B.TXT;1"[..], b"A_B_1.TXT;1", b"A_B_2.TXT;1"]);
+    }
+
+    #[test]
+    fn keeps_records_within_sectors() {
+        assert_eq!(extent_size([34, 34].into_iter()), SECTOR_SIZE);
+        assert_eq!(extent_size(std::iter::repeat_n(100, 20)), SECTOR_SIZE);
+        assert_eq!(extent_size(std::iter::repeat_n(100, 21)), 2 * SECTOR_SIZE);
+    }
+}
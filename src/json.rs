@@ -0,0 +1,457 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal JSON value type for writing machine-readable output and reading
+//! the replies of tools that speak JSON (such as the QEMU guest agent).
+
+/// A JSON value. Objects preserve the order in which their members were
+/// added.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an empty object.
+    pub fn object() -> Self {
+        Json::Object(Vec::new())
+    }
+
+    /// Adds a member to an object, returning the object. Panics if `self` is
+    /// not an object.
+    pub fn with(mut self, key: &str, value: impl Into<Json>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Adds a member to an object. Panics if `self` is not an object.
+    pub fn insert(&mut self, key: &str, value: impl Into<Json>) {
+        match self {
+            Json::Object(members) => {
+                members.push((key.to_string(), value.into()))
+            }
+            _ => panic!("can only insert members into JSON objects"),
+        }
+    }
+
+    /// Returns the value of an object's member named `key`, or `None` if
+    /// `self` isn't an object or has no such member.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => {
+                members.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Parses `text`, which must contain exactly one JSON value (optionally
+    /// surrounded by whitespace).
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser { chars: text.char_indices().peekable() };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if let Some((pos, c)) = parser.chars.next() {
+            anyhow::bail!("unexpected '{c}' after JSON value at offset {pos}");
+        }
+
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.chars.next();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> anyhow::Result<char> {
+        self.chars
+            .next()
+            .map(|(_, c)| c)
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of JSON input"))
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => anyhow::bail!("expected '{expected}' in JSON, found '{c}'"),
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<Json> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.chars.next();
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.chars.next();
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    self.expect('"')?;
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(':')?;
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => break,
+                        c => anyhow::bail!(
+                            "expected ',' or '}}' in JSON object, found '{c}'"
+                        ),
+                    }
+                }
+                Ok(Json::Object(members))
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => break,
+                        c => anyhow::bail!(
+                            "expected ',' or ']' in JSON array, found '{c}'"
+                        ),
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some('"') => {
+                self.chars.next();
+                self.string().map(Json::String)
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) =
+                    self.peek().filter(char::is_ascii_alphabetic)
+                {
+                    word.push(c);
+                    self.chars.next();
+                }
+                match word.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    _ => anyhow::bail!("invalid JSON literal '{word}'"),
+                }
+            }
+            Some(c) => anyhow::bail!("unexpected '{c}' in JSON"),
+            None => anyhow::bail!("unexpected end of JSON input"),
+        }
+    }
+
+    /// Parses the remainder of a string after its opening quote.
+    fn string(&mut self) -> anyhow::Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let high = self.hex4()?;
+                        let code = if (0xd800..0xdc00).contains(&high) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                anyhow::bail!("unpaired surrogate in JSON");
+                            }
+                            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                        } else {
+                            high
+                        };
+                        s.push(char::from_u32(code).ok_or_else(|| {
+                            anyhow::anyhow!("invalid escape in JSON string")
+                        })?);
+                    }
+                    c => anyhow::bail!("invalid escape '\\{c}' in JSON"),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let c = self.next()?;
+            let digit = c.to_digit(16).ok_or_else(|| {
+                anyhow::anyhow!("invalid hex digit '{c}' in JSON escape")
+            })?;
+            value = value * 16 + digit;
+        }
+
+        Ok(value)
+    }
+
+    fn number(&mut self) -> anyhow::Result<Json> {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                text.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok(Json::Integer(i));
+        }
+
+        text.parse::<f64>()
+            .map(Json::Float)
+            .map_err(|_| anyhow::anyhow!("invalid JSON number '{text}'"))
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Json::Integer(value)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Self {
+        Json::Integer(value.into())
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::Integer(value.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Integer(value.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Float(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(Json::Null)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(value: Vec<T>) -> Self {
+        Json::Array(value.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Formats the value as compact JSON. Use the alternate flag (`{:#}`) to
+/// pretty-print it with two-space indentation.
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write(
+            value: &Json,
+            f: &mut std::fmt::Formatter<'_>,
+            indent: usize,
+        ) -> std::fmt::Result {
+            let pretty = f.alternate();
+            let newline = |f: &mut std::fmt::Formatter<'_>, indent: usize| {
+                if pretty {
+                    write!(f, "\n{:width$}", "", width = indent * 2)
+                } else {
+                    Ok(())
+                }
+            };
+
+            match value {
+                Json::Null => f.write_str("null"),
+                Json::Bool(b) => write!(f, "{b}"),
+                Json::Integer(i) => write!(f, "{i}"),
+                Json::Float(x) if x.is_finite() => write!(f, "{x}"),
+                Json::Float(_) => f.write_str("null"),
+                Json::String(s) => write_string(f, s),
+                Json::Array(items) if items.is_empty() => f.write_str("[]"),
+                Json::Array(items) => {
+                    f.write_str("[")?;
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(",")?;
+                        }
+                        newline(f, indent + 1)?;
+                        write(item, f, indent + 1)?;
+                    }
+                    newline(f, indent)?;
+                    f.write_str("]")
+                }
+                Json::Object(members) if members.is_empty() => {
+                    f.write_str("{}")
+                }
+                Json::Object(members) => {
+                    f.write_str("{")?;
+                    for (i, (key, value)) in members.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(",")?;
+                        }
+                        newline(f, indent + 1)?;
+                        write_string(f, key)?;
+                        f.write_str(if pretty { ": " } else { ":" })?;
+                        write(value, f, indent + 1)?;
+                    }
+                    newline(f, indent)?;
+                    f.write_str("}")
+                }
+            }
+        }
+
+        write(self, f, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_values() {
+        let value = Json::object()
+            .with("name", "a \"quoted\"\nline")
+            .with("count", 3i64)
+            .with("missing", Option::<String>::None)
+            .with("items", vec![true, false]);
+
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"quoted\"\nline","count":3,"missing":null,"items":[true,false]}"#
+        );
+        assert_eq!(
+            format!("{:#}", Json::object().with("a", vec![1i64])),
+            "{\n  \"a\": [\n    1\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn parses_values() {
+        let value = Json::parse(
+            r#" {"return": {"exited": true, "exitcode": -1,
+                "out-data": "aGk=", "list": [1.5, null, "\u00e9\ud83d\ude00"],
+                "empty": {}}} "#,
+        )
+        .unwrap();
+        let ret = value.get("return").unwrap();
+        assert_eq!(ret.get("exited").and_then(Json::as_bool), Some(true));
+        assert_eq!(ret.get("exitcode").and_then(Json::as_i64), Some(-1));
+        assert_eq!(ret.get("out-data").and_then(Json::as_str), Some("aGk="));
+        assert_eq!(
+            ret.get("list"),
+            Some(&Json::Array(vec![
+                Json::Float(1.5),
+                Json::Null,
+                Json::from("\u{e9}\u{1f600}"),
+            ]))
+        );
+        assert_eq!(ret.get("empty"), Some(&Json::object()));
+
+        // Values written by `Display` parse back to themselves.
+        let written = Json::object().with("s", "a\"\\\n\u{1}").with("n", 7i64);
+        assert_eq!(Json::parse(&written.to_string()).unwrap(), written);
+
+        for bad in ["", "{", "[1,]", "{\"a\" 1}", "tru", "\"\\x\"", "1 2"] {
+            assert!(Json::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
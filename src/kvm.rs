@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Works out whether QEMU will be able to use KVM to accelerate the
+//! installation VM, and explains how to fix things if it won't, from facts
+//! about the host's KVM device that the platform code gathers.
+
+/// The device through which QEMU uses KVM.
+pub const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Returns whether the x86 CPU described by `cpuinfo` (the contents of
+/// `/proc/cpuinfo`) advertises hardware virtualization support, i.e. the
+/// `vmx` or `svm` flag. Other CPUs don't advertise it this way.
+pub fn cpu_supports_virtualization(cpuinfo: &str) -> bool {
+    cpuinfo.lines().any(|line| {
+        line.starts_with("flags")
+            && line
+                .split_whitespace()
+                .any(|flag| flag == "vmx" || flag == "svm")
+    })
+}
+
+/// What the host revealed about its KVM device.
+pub struct KvmFacts {
+    /// The result of opening the device for reading and writing.
+    pub open: std::io::Result<()>,
+
+    /// The device's group, if members of that group can read and write it.
+    pub group: Option<String>,
+
+    /// Whether the host CPU advertises hardware virtualization support, or
+    /// `None` if it doesn't say (as on aarch64 hosts).
+    pub cpu_supports_virtualization: Option<bool>,
+}
+
+/// Why KVM can't be used, and what the user can do about it.
+pub struct KvmProblem {
+    pub cause: String,
+    pub remedy: String,
+}
+
+/// The result of probing the host for KVM support.
+pub enum KvmProbe {
+    /// The KVM device exists and this process can open it for reading and
+    /// writing.
+    Available,
+
+    /// The KVM device doesn't exist.
+    Missing {
+        /// Whether the host CPU advertises hardware virtualization support
+        /// (the `vmx` or `svm` flags in `/proc/cpuinfo`), if it's an x86 CPU.
+        cpu_supports_virtualization: Option<bool>,
+    },
+
+    /// The KVM device exists, but opening it failed. `group` names the
+    /// device's group if members of that group can read and write it.
+    NotAccessible { error: std::io::Error, group: Option<String> },
+}
+
+impl KvmProbe {
+    /// Interprets what the host revealed about its KVM device.
+    pub fn resolve(facts: KvmFacts) -> Self {
+        match facts.open {
+            Ok(()) => KvmProbe::Available,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                KvmProbe::Missing {
+                    cpu_supports_virtualization: facts
+                        .cpu_supports_virtualization,
+                }
+            }
+            Err(error) => KvmProbe::NotAccessible { error, group: facts.group },
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        matches!(self, KvmProbe::Available)
+    }
+
+    /// Describes why KVM isn't usable and how to fix it. Returns `None` if KVM
+    /// is available.
+    pub fn problem(&self) -> Option<KvmProblem> {
+        match self {
+            KvmProbe::Available => None,
+            KvmProbe::Missing { cpu_supports_virtualization: Some(true) } => {
+                Some(KvmProblem {
+                    cause: format!(
+                        "{KVM_DEVICE} does not exist, but the CPU supports \
+                        hardware virtualization"
+                    ),
+                    remedy: "load the KVM module for your CPU (`sudo modprobe \
+                        kvm_intel` or `sudo modprobe kvm_amd`)"
+                        .to_string(),
+                })
+            }
+            KvmProbe::Missing { cpu_supports_virtualization: Some(false) } => {
+                Some(KvmProblem {
+                    cause: format!(
+                        "{KVM_DEVICE} does not exist and the CPU does not \
+                        advertise hardware virtualization support"
+                    ),
+                    remedy: "enable VT-x or AMD-V in the system firmware (or, \
+                        if this host is itself a VM, enable nested \
+                        virtualization on its hypervisor)"
+                        .to_string(),
+                })
+            }
+            KvmProbe::Missing { cpu_supports_virtualization: None } => {
+                Some(KvmProblem {
+                    cause: format!("{KVM_DEVICE} does not exist"),
+                    remedy: "enable virtualization in the system firmware and \
+                        load the KVM module (`sudo modprobe kvm`), or, if \
+                        this host is itself a VM, enable nested \
+                        virtualization on its hypervisor"
+                        .to_string(),
+                })
+            }
+            KvmProbe::NotAccessible { error, group } => Some(KvmProblem {
+                cause: format!(
+                    "{KVM_DEVICE} exists but could not be opened for reading \
+                    and writing ({error})"
+                ),
+                remedy: match group {
+                    Some(group) => format!(
+                        "add your user to the '{group}' group (`sudo usermod \
+                        -aG {group} $USER`, then log in again)"
+                    ),
+                    None => format!(
+                        "make sure your user can read and write {KVM_DEVICE}"
+                    ),
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probe(error: Option<std::io::ErrorKind>, cpu: Option<bool>) -> KvmProbe {
+        KvmProbe::resolve(KvmFacts {
+            open: error.map_or(Ok(()), |kind| Err(kind.into())),
+            group: Some("kvm".to_string()),
+            cpu_supports_virtualization: cpu,
+        })
+    }
+
+    #[test]
+    fn detects_virtualization_flags() {
+        assert!(cpu_supports_virtualization(
+            "processor\t: 0\nflags\t\t: fpu vme vmx sse\n"
+        ));
+        assert!(cpu_supports_virtualization("flags\t\t: svm lm\n"));
+        assert!(!cpu_supports_virtualization("flags\t\t: fpu vmxfoo\n"));
+        assert!(!cpu_supports_virtualization(""));
+    }
+
+    #[test]
+    fn explains_kvm_problems() {
+        use std::io::ErrorKind::{NotFound, PermissionDenied};
+
+        let no_group = KvmProbe::resolve(KvmFacts {
+            open: Err(PermissionDenied.into()),
+            group: None,
+            cpu_supports_virtualization: Some(true),
+        });
+        let cases = [
+            (probe(None, Some(false)), None),
+            (
+                probe(Some(NotFound), Some(true)),
+                Some("sudo modprobe kvm_intel"),
+            ),
+            (probe(Some(NotFound), Some(false)), Some("enable VT-x or AMD-V")),
+            // aarch64 CPUs don't advertise virtualization in their flags.
+            (probe(Some(NotFound), None), Some("`sudo modprobe kvm`")),
+            (
+                probe(Some(PermissionDenied), Some(true)),
+                Some("add your user to the 'kvm' group"),
+            ),
+            (no_group, Some("make sure your user can read and write")),
+        ];
+
+        for (probe, remedy) in cases {
+            assert_eq!(probe.is_available(), remedy.is_none());
+            match (probe.problem(), remedy) {
+                (None, None) => {}
+                (Some(problem), Some(remedy)) => {
+                    assert!(
+                        problem.remedy.contains(remedy),
+                        "{}",
+                        problem.remedy
+                    )
+                }
+                (problem, _) => {
+                    panic!("unexpected problem: {:?}", problem.map(|p| p.cause))
+                }
+            }
+        }
+    }
+}
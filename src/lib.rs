@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! wimsy: a playful way to manipulate Windows images for use in an Oxide rack.
+//!
+//! The `wimsy` command is a thin wrapper around this library, which other
+//! Rust programs can use to build images themselves. [`run`] runs the command
+//! an [`App`] describes, which can be parsed from the same options the
+//! command takes, and can pass the build's progress events to a callback
+//! instead of printing them:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use clap::Parser as _;
+//! use wimsy::{app::App, RunHooks};
+//!
+//! let app = App::try_parse_from([
+//!     "wimsy",
+//!     "--work-dir=/var/tmp/wimsy",
+//!     "--output-image=windows.img",
+//!     "create-guest-disk-image",
+//!     "--windows-iso=windows.iso",
+//!     "--virtio-iso=virtio-win.iso",
+//!     "--unattend-dir=unattend",
+//! ])?;
+//! wimsy::run(
+//!     app,
+//!     RunHooks {
+//!         on_event: Some(Arc::new(|event| eprintln!("{event}"))),
+//!         ..RunHooks::default()
+//!     },
+//! )?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The pieces [`run`] is built from are public too. [`get_script`] returns
+//! the steps a build runs as a [`runner::Script`], which
+//! [`runner::run_script`] runs much as the command does; steps implement
+//! [`runner::Step`] and report their progress through the [`ui::Ui`] trait;
+//! and [`config::Config`] reads configuration files.
+
+use anyhow::Context as _;
+use app::{App, Command};
+
+pub const UNATTEND_FILES: &[&str] = &[
+    "Autounattend.xml",
+    "cloudbase-init-unattend.conf",
+    "cloudbase-init.conf",
+    "OxidePrepBaseImage.ps1",
+    "prep.cmd",
+    "specialize-unattend.xml",
+];
+
+#[cfg(target_os = "illumos")]
+pub mod illumos;
+#[cfg(target_os = "illumos")]
+pub use illumos::{check_host, get_script, installation_vm_size};
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::{check_host, get_script, installation_vm_size};
+
+#[cfg(not(any(target_os = "illumos", target_os = "linux")))]
+compile_error!("only Linux and illumos targets are supported");
+
+pub mod accel;
+pub mod activation;
+pub mod app;
+pub mod autounattend;
+pub mod certs;
+pub mod checkpoint;
+pub mod cloudbase_init;
+pub mod command;
+pub mod compress;
+pub mod config;
+pub mod convert;
+pub mod device;
+pub mod doctor;
+pub mod domain_join;
+pub mod download;
+pub mod drivers;
+pub mod error;
+pub mod features;
+pub mod gpt;
+pub mod hash;
+pub mod hooks;
+pub mod hostname;
+pub mod inspect;
+pub mod interrupt;
+pub mod iso;
+pub mod json;
+pub mod kvm;
+pub mod lock;
+pub mod manifest;
+pub mod matrix;
+pub mod media;
+pub mod memory;
+pub mod monitor;
+pub mod nbd;
+pub mod network;
+pub mod ntfs;
+pub mod oxide;
+pub mod plan;
+pub mod provision;
+pub mod qcow2;
+pub mod registry;
+pub mod registry_settings;
+pub mod remote;
+pub mod report;
+pub mod reproducible;
+pub mod runner;
+pub mod s3;
+pub mod secrets;
+pub mod shrink;
+pub mod slots;
+pub mod ssh;
+pub mod steps;
+pub mod template;
+pub mod trace;
+pub mod ui;
+pub mod updates;
+pub mod users;
+pub mod util;
+pub mod validate;
+pub mod vhdx;
+pub mod vmdk;
+pub mod wim;
+pub mod workspace;
+
+/// How [`run`] reports on and cleans up after a build.
+#[derive(Clone, Default)]
+pub struct RunHooks {
+    /// Receives the build's progress events, which are then neither printed
+    /// nor written to stdout. Builds with a callback aren't interactive, and
+    /// print their other messages to stderr, as with `--progress json`.
+    pub on_event: Option<ui::EventCallback>,
+
+    /// Whether SIGINT and SIGTERM cancel the build and exit the process, as
+    /// they do for the wimsy command (see [`interrupt`]).
+    pub handle_signals: bool,
+
+    /// Runs the commands the build's steps run to completion, in place of
+    /// running them on this host (see [`command`]).
+    pub command_runner: Option<std::sync::Arc<dyn command::CommandRunner>>,
+}
+
+impl RunHooks {
+    /// Returns the hooks the wimsy command runs with.
+    pub fn command_line() -> Self {
+        Self { handle_signals: true, ..Self::default() }
+    }
+}
+
+/// Runs the command `app` describes, as the wimsy command does.
+pub fn run(mut app: App, hooks: RunHooks) -> anyhow::Result<()> {
+    let json_progress =
+        app.progress == app::ProgressFormat::Json || hooks.on_event.is_some();
+    let interactive = match app.interactive {
+        Some(true) if json_progress => {
+            anyhow::bail!("--progress json can't be used in interactive mode")
+        }
+        Some(val) => val,
+        None => !json_progress && atty::is(atty::Stream::Stdout),
+    };
+    if app.reproducible && !cfg!(target_os = "linux") {
+        anyhow::bail!("--reproducible builds are only supported on Linux");
+    }
+    if interactive && app.verbose > 0 {
+        anyhow::bail!(
+            "-v can only be used in non-interactive mode (interactive mode \
+            shows command output below the step list)"
+        );
+    }
+
+    // The remote host loads the configuration file and runs the command
+    // itself.
+    if app.remote.remote_host.is_some() {
+        return remote::run_remotely(&app.remote, app.dry_run, interactive);
+    }
+
+    let config = match &app.config {
+        Some(path) => config::Config::load(path, app.target.as_deref())?,
+        None => config::Config::default(),
+    };
+
+    if let Command::ListEditions { windows_iso } = &app.command {
+        return wim::list_editions(windows_iso);
+    }
+
+    if let Command::Inspect { image } = &app.command {
+        return inspect::run(image);
+    }
+
+    if let Command::Shrink { image } = &app.command {
+        return shrink::run(image, app.dry_run);
+    }
+
+    if let Command::Convert {
+        input,
+        output,
+        format,
+        qcow2_compression,
+        vhdx_subformat,
+    } = &app.command
+    {
+        let conversion = convert::Conversion {
+            input,
+            output,
+            format: *format,
+            qcow2_compression: *qcow2_compression,
+            vhdx_subformat: *vhdx_subformat,
+        };
+        return convert::run(&conversion, app.dry_run);
+    }
+
+    if let Command::BuildAll { jobs, build_args } = &app.command {
+        return matrix::build_all(
+            &app,
+            &config,
+            *jobs,
+            build_args,
+            interactive,
+        );
+    }
+
+    if app.output_image.is_none() {
+        app.output_image.clone_from(&config.output.image);
+    }
+    if app.sign_key.is_none() {
+        app.sign_key.clone_from(&config.output.sign_key);
+    }
+    if app.output_image.is_none() {
+        anyhow::bail!(
+            "no output image was given: pass --output-image, or set \
+            output.image in the configuration file"
+        );
+    }
+
+    if let Command::Doctor { .. } = &app.command {
+        return doctor::run(check_host(&app, &config));
+    }
+
+    // Keep other builds out of the work directory and output image. Dry runs
+    // don't write to either, so they needn't lock them.
+    let _lock = if app.dry_run {
+        None
+    } else {
+        workspace::create(&app.work_dir)?;
+        Some(lock::BuildLock::acquire(
+            &app.work_dir,
+            app.output_image(),
+            app.force,
+        )?)
+    };
+
+    // Start tracing before building the script so that traces include the
+    // decisions made while configuring it. Dry runs don't write anything to
+    // the work directory, including traces. Tracing stops when this build
+    // returns, so that a later build in this process traces to its own files.
+    let mut _trace = None;
+    if !app.dry_run {
+        let logs_dir = workspace::logs_dir(&app.work_dir);
+        match trace::init(&logs_dir, app.verbose) {
+            Ok(guard) => _trace = Some(guard),
+            Err(e) => eprintln!("Warning: not writing traces: {e:#}"),
+        }
+
+        if hooks.handle_signals {
+            if let Err(e) = interrupt::handle_cancellation(&app.work_dir) {
+                eprintln!("Warning: Ctrl-C won't clean up the build: {e}");
+            }
+        }
+    }
+
+    let command_runner = match (hooks.command_runner, &app.tools_container) {
+        (Some(runner), _) => Some(runner),
+        (None, Some(image)) => {
+            let mut dirs = vec![app.work_dir.as_path()];
+            dirs.extend(app.output_image().parent());
+            let runner = command::ContainerRunner::new(image, &dirs)
+                .context("setting up the tools container")?;
+            Some(std::sync::Arc::new(runner) as _)
+        }
+        (None, None) => None,
+    };
+
+    let signer =
+        app.sign_key.as_deref().map(manifest::Signer::new).transpose()?;
+    let script = get_script(
+        &app,
+        &config,
+        command_runner.as_deref().unwrap_or(&command::HostRunner),
+    )?;
+    runner::run_script(
+        script,
+        runner::RunOptions {
+            interactive,
+            work_dir: app.work_dir.clone(),
+            config,
+            pause_after: ui::PauseAfter::from_args(&app.pause_after),
+            vars: app
+                .disk_monitor
+                .context_vars()
+                .into_iter()
+                .chain(
+                    app.slots_dir
+                        .as_ref()
+                        .map(|dir| ("slots_dir".to_string(), dir.to_string())),
+                )
+                .chain(
+                    app.reproducible
+                        .then(|| ("reproducible".to_string(), String::new())),
+                )
+                .collect(),
+            progress: app.progress,
+            on_event: hooks.on_event,
+            resume: app.resume,
+            selection: plan::StepSelection {
+                skip: app.skip_step.clone(),
+                only: app.only_step.clone(),
+                start_at: app.start_at.clone(),
+            },
+            dry_run: app.dry_run,
+            follow_serial: app.follow_serial,
+            command_runner,
+            keep_work_dir: app.keep_work_dir,
+            signer,
+        },
+    )
+}
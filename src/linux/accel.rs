@@ -0,0 +1,56 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Selects the accelerator for the installation and test VMs on this host and
+//! the QEMU arguments that use it.
+
+use crate::{
+    accel::{HostAccel, TCG_TIMEOUT_FACTOR},
+    app::Accelerator,
+    autounattend::Architecture,
+    runner::Context,
+};
+
+/// Probes the host for the accelerators that could run `arch` guests given
+/// the one the user `requested`.
+pub(super) fn probe(requested: Accelerator, arch: Architecture) -> HostAccel {
+    let host_arch = std::env::consts::ARCH;
+    let kvm = HostAccel::wants_kvm(requested, arch, host_arch)
+        .then(super::kvm::probe);
+    HostAccel::new(arch, host_arch, kvm)
+}
+
+/// Returns the QEMU arguments that select the accelerator and CPU model named
+/// by the `accel` context variable.
+pub(super) fn qemu_args(ctx: &Context) -> &'static [&'static str] {
+    match ctx.get_var("accel") {
+        // Hyper-V enlightenments require KVM, so emulate the most capable CPU
+        // TCG offers instead of passing through the host's.
+        Some("tcg") => &["-accel", "tcg,thread=multi", "-cpu", "max"],
+        // The hv_* enlightenments are x86-only; Windows on Arm runs on the
+        // host's CPU model as is.
+        _ if crate::steps::architecture(ctx) == Architecture::Aarch64 => {
+            &["-enable-kvm", "-cpu", "host"]
+        }
+        _ => &[
+            "-enable-kvm",
+            // Use the host CPU model so that Windows installs with a CPU
+            // configuration compatible with both the build host and Oxide
+            // hardware. kvm=off hides the KVM CPUID leaf so the guest uses
+            // Hyper-V enlightenments via the hv_* flags below.
+            "-cpu",
+            "host,kvm=off,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time",
+        ],
+    }
+}
+
+/// Returns how many times longer than configured the VMs named by the
+/// `accel` context variable may take to finish their work.
+pub(super) fn timeout_factor(ctx: &Context) -> u32 {
+    if ctx.get_var("accel") == Some("tcg") {
+        TCG_TIMEOUT_FACTOR
+    } else {
+        1
+    }
+}
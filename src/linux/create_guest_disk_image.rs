@@ -8,47 +8,310 @@
 use std::{
     collections::HashMap,
     io::Write,
-    process::Command,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    app::ImageSources,
-    autounattend::WindowsVersion,
-    runner::{Context, MissingPrerequisites, Script, ScriptStep},
+    accel::HostAccel,
+    app::{
+        Accelerator, CompressOptions, DiskController, DiskSize, Hypervisor,
+        ImageSources, MachineType, NicModel, OutputDeviceOptions, OutputFormat,
+        OxidePublishOptions, Qcow2Options, S3PublishOptions, VhdxOptions,
+        VmdkOptions,
+    },
+    autounattend::Architecture,
+    certs,
+    command::CommandRunner,
+    compress::Compression,
+    config::ImageTests,
+    domain_join,
+    error::ErrorKind,
+    gpt::Guid,
+    interrupt::CatchInterrupts,
+    json::Json,
+    memory,
+    monitor::{
+        DiskSpaceLimits, DiskSpaceMonitor, ProgressLimits, SerialWatcher, Vm,
+        Watchdog,
+    },
+    nbd::RawImage,
+    qcow2::CodecSelection,
+    runner::{Context, MissingPrerequisites, Script, ScriptStep, Step},
+    steps::output_format,
+    trace,
     ui::Ui,
     util::{
-        check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
+        check_file_prerequisites, command_span, describe_vm_exit,
+        format_command, run_command_check_status,
     },
-    UNATTEND_FILES,
 };
 
 use anyhow::{Context as _, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
 
+use super::{accel, ovmf::Firmware, qmp::Qmp, screenshot::Screenshots};
+
+/// The name of the installation VM's QMP socket in the work directory.
+const QMP_SOCKET_NAME: &str = "qmp.sock";
+
+/// How long to wait for QEMU to create its QMP socket.
+const QMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times the installation VM may reset before wimsy decides it's
+/// stuck in a boot loop. Windows Setup reboots a handful of times.
+const MAX_INSTALL_RESETS: u32 = 20;
+
+/// How long the guest gets to power down after the build is interrupted
+/// before QEMU is told to quit.
+const POWERDOWN_GRACE: Duration = Duration::from_secs(60);
+
 pub struct CreateGuestDiskImageArgs {
     pub work_dir: Utf8PathBuf,
     pub output_image: Utf8PathBuf,
     pub sources: ImageSources,
-    pub ovmf_path: Utf8PathBuf,
+    pub arch: Architecture,
+    pub ovmf_path: Option<Utf8PathBuf>,
+    pub secure_boot: bool,
+    pub ovmf_vars_template: Option<Utf8PathBuf>,
+    pub tpm: bool,
     pub vga_console: bool,
+    pub screenshot_interval_secs: Option<u64>,
+    pub accel: Accelerator,
+    pub hypervisor: Hypervisor,
+    pub libvirt_uri: Option<String>,
+    pub output_format: OutputFormat,
+    pub disk_size: DiskSize,
+    pub vm_cpus: Option<u32>,
+    pub vm_memory_mib: Option<u64>,
+    pub vm_machine: MachineType,
+    pub disk_controller: DiskController,
+    pub nic_model: NicModel,
+    pub force_memory: bool,
+    pub force_disk_space: bool,
+    /// Whether other builds share the host through `--slots-dir`, so that
+    /// this one waits for room before launching its VM.
+    pub shares_host: bool,
+    pub qcow2: Qcow2Options,
+    pub vhdx: VhdxOptions,
+    pub vmdk: VmdkOptions,
+    pub output_device: OutputDeviceOptions,
+    pub oxide: OxidePublishOptions,
+    pub s3: S3PublishOptions,
+    pub compress: CompressOptions,
+    pub tests: ImageTests,
 }
 
 pub struct CreateGuestDiskImageScript {
-    steps: Vec<ScriptStep>,
+    steps: Vec<Box<dyn Step>>,
     args: CreateGuestDiskImageArgs,
+
+    /// The accelerators this host offers for the installation VM.
+    host_accel: HostAccel,
+
+    /// The accelerator the installation VM will use, i.e. the requested
+    /// accelerator with "auto" resolved using `host_accel`.
+    accel: Accelerator,
+
+    /// The codec with which to compress the qcow2 image, if one was
+    /// requested.
+    qcow2_codec: CodecSelection,
+
+    /// The resources to give the installation VM, with any the user didn't
+    /// choose sized to fit the host.
+    vm: VmResources,
+
+    /// The firmware the installation VM boots from: `--ovmf-path`, or the
+    /// result of looking for it on this host.
+    firmware: Firmware,
+}
+
+/// The size, machine type, disk controller, and network adapter of a QEMU VM.
+pub(super) struct VmResources {
+    pub cpus: u32,
+    pub memory_mib: u64,
+    pub machine: MachineType,
+    pub disk_controller: DiskController,
+    pub nic_model: NicModel,
+}
+
+impl VmResources {
+    /// Reads the resources for the script's VMs from the `vm_cpus`,
+    /// `vm_memory_mib`, `vm_machine`, `disk_controller`, and `nic_model`
+    /// context variables.
+    pub fn from_context(ctx: &Context) -> Result<Self> {
+        let cpus = ctx
+            .get_var("vm_cpus")
+            .unwrap()
+            .parse()
+            .context("parsing vm_cpus")?;
+        let memory_mib = ctx
+            .get_var("vm_memory_mib")
+            .unwrap()
+            .parse()
+            .context("parsing vm_memory_mib")?;
+        let machine = clap::ValueEnum::from_str(
+            ctx.get_var("vm_machine").unwrap(),
+            false,
+        )
+        .map_err(|e| anyhow::anyhow!("invalid vm_machine: {e}"))?;
+        let disk_controller = clap::ValueEnum::from_str(
+            ctx.get_var("disk_controller").unwrap(),
+            false,
+        )
+        .map_err(|e| anyhow::anyhow!("invalid disk_controller: {e}"))?;
+        let nic_model =
+            clap::ValueEnum::from_str(ctx.get_var("nic_model").unwrap(), false)
+                .map_err(|e| anyhow::anyhow!("invalid nic_model: {e}"))?;
+        Ok(Self { cpus, memory_mib, machine, disk_controller, nic_model })
+    }
+
+    /// Returns the QEMU arguments that select this machine type, memory size,
+    /// and vCPU count.
+    pub fn qemu_args(&self) -> Vec<String> {
+        let machine = match self.machine {
+            // Windows on Arm needs a GICv3 interrupt controller, which the
+            // virt board only provides if asked.
+            MachineType::Virt => "virt,gic-version=max".to_string(),
+            machine => machine.to_string(),
+        };
+        let mut args = vec![
+            "-M".to_string(),
+            machine,
+            "-m".to_string(),
+            self.memory_mib.to_string(),
+            "-smp".to_string(),
+            format!("{},sockets=1,cores={}", self.cpus, self.cpus),
+        ];
+
+        // The virt board has no keyboard of its own, and the installation VM
+        // needs one to get past the prompt to boot from CD.
+        if self.machine == MachineType::Virt {
+            args.extend(
+                ["-device", "qemu-xhci,id=usb", "-device", "usb-kbd,bus=usb.0"]
+                    .map(str::to_string),
+            );
+        }
+        args
+    }
+
+    /// Returns the QEMU arguments that attach the drive with ID `drive` as
+    /// the VM's boot disk, on this VM's disk controller.
+    pub fn disk_args(&self, drive: &str) -> Vec<String> {
+        // Present 512-byte sectors whatever controller the disk is on, since
+        // that's what Oxide's disks use, and the image's partition table is
+        // laid out in them.
+        let disk = format!(
+            "drive={drive},serial=01de01de,physical_block_size=512,\
+            logical_block_size=512,discard_granularity=512,bootindex=1"
+        );
+        let devices = match self.disk_controller {
+            DiskController::Nvme => vec![format!("nvme,{disk}")],
+            DiskController::VirtioBlk => vec![format!("virtio-blk-pci,{disk}")],
+            DiskController::VirtioScsi => vec![
+                "virtio-scsi-pci,id=scsi0".to_string(),
+                format!("scsi-hd,bus=scsi0.0,{disk}"),
+            ],
+            // The disk gets its own AHCI controller so that it doesn't
+            // compete with the CD-ROMs for q35's ports.
+            DiskController::Ahci => vec![
+                "ahci,id=ahci0".to_string(),
+                format!("ide-hd,bus=ahci0.0,{disk}"),
+            ],
+        };
+
+        devices
+            .into_iter()
+            .flat_map(|device| ["-device".to_string(), device])
+            .collect()
+    }
+
+    /// Returns the QEMU arguments that give the VM its network adapter, on a
+    /// user-mode network that forwards each `(host, guest)` TCP port pair in
+    /// `forwards`, or no arguments if the VM has no network adapter.
+    pub fn nic_args(&self, forwards: &[(u16, u16)]) -> Vec<String> {
+        let device = match self.nic_model {
+            NicModel::E1000e => "e1000e",
+            NicModel::VirtioNet => "virtio-net-pci",
+            NicModel::None => return Vec::new(),
+        };
+
+        let mut netdev = "user,id=net0".to_string();
+        for (host, guest) in forwards {
+            netdev.push_str(&format!(",hostfwd=tcp:127.0.0.1:{host}-:{guest}"));
+        }
+        vec![
+            "-netdev".to_string(),
+            netdev,
+            "-device".to_string(),
+            format!("{device},netdev=net0"),
+        ]
+    }
+
+    /// Returns the device that attaches the `index`th CD-ROM drive, which
+    /// reads the drive with ID `drive`, to this machine's disk controller.
+    /// The pc machine's IDE buses each take two drives; each of q35's AHCI
+    /// ports takes one. The virt machine has neither, so its CD-ROMs are USB
+    /// mass storage devices.
+    fn cdrom_device(&self, index: u32, drive: &str) -> String {
+        let id = format!("drive={drive},id=cd-disk{index}");
+        match self.machine {
+            MachineType::Pc => {
+                format!("ide-cd,{id},bus=ide.{},unit={}", index % 2, index / 2)
+            }
+            MachineType::Q35 => format!("ide-cd,{id},bus=ide.{index},unit=0"),
+            MachineType::Virt => format!("usb-storage,{id},bus=usb.0"),
+        }
+    }
 }
 
 impl CreateGuestDiskImageScript {
-    pub(super) fn new(script_args: CreateGuestDiskImageArgs) -> Self {
-        Self { steps: get_script(), args: script_args }
+    pub(super) fn new(
+        script_args: CreateGuestDiskImageArgs,
+        runner: &dyn CommandRunner,
+    ) -> Self {
+        let host_accel = accel::probe(script_args.accel, script_args.arch);
+        let accel = host_accel.resolve(script_args.accel);
+        let qcow2_codec = CodecSelection::probe(
+            &script_args.qcow2,
+            runner,
+            &script_args.work_dir,
+        );
+        let vm = VmResources {
+            cpus: script_args.vm_cpus.unwrap_or_else(detect_physical_cores),
+            memory_mib: script_args
+                .vm_memory_mib
+                .unwrap_or_else(detect_qemu_ram_mb),
+            machine: script_args.vm_machine,
+            disk_controller: script_args.disk_controller,
+            nic_model: script_args.nic_model,
+        };
+        let firmware = Firmware::find(
+            script_args.ovmf_path.as_deref(),
+            script_args.arch,
+            script_args.secure_boot,
+        );
+        Self {
+            steps: get_script(
+                &script_args.tests,
+                script_args.arch,
+                script_args.hypervisor,
+            ),
+            args: script_args,
+            host_accel,
+            accel,
+            qcow2_codec,
+            vm,
+            firmware,
+        }
     }
 }
 
 impl Script for CreateGuestDiskImageScript {
-    fn steps(&self) -> &[ScriptStep] {
+    fn steps(&self) -> &[Box<dyn Step>] {
         self.steps.as_slice()
     }
 
@@ -64,20 +327,118 @@ impl Script for CreateGuestDiskImageScript {
         let args = &self.args;
         let sources = &args.sources;
         writeln!(w, "  {}: {}", "Working directory".bold(), args.work_dir)?;
-        writeln!(w, "  {}: {}", "Windows ISO".bold(), sources.windows_iso)?;
         writeln!(
             w,
             "  {}: {}",
-            "Virtio driver ISO".bold(),
-            sources.virtio_iso
+            "Windows ISO".bold(),
+            sources.windows_iso_source()
         )?;
+        let (driver_label, driver_source) = sources.driver_source();
+        writeln!(w, "  {}: {}", driver_label.bold(), driver_source)?;
         writeln!(
             w,
             "  {}: {}",
             "Unattend file directory".bold(),
             sources.unattend_dir
         )?;
-        writeln!(w, "  {}: {}", "Guest bootrom".bold(), args.ovmf_path)?;
+        if let Some(dir) = &sources.unattend_overrides {
+            writeln!(w, "  {}: {}", "Unattend overrides".bold(), dir)?;
+        }
+        if args.arch != Architecture::X86_64 {
+            writeln!(w, "  {}: {}", "Architecture".bold(), args.arch)?;
+        }
+        writeln!(w, "  {}: {}", "Guest bootrom".bold(), self.firmware)?;
+        if let Some(template) = &args.ovmf_vars_template {
+            writeln!(
+                w,
+                "  {}: enforced (varstore template {})",
+                "Secure Boot".bold(),
+                template
+            )?;
+        }
+        if args.tpm {
+            writeln!(w, "  {}: emulated TPM 2.0 (swtpm)", "TPM".bold())?;
+        }
+        for cert in &sources.trusted_certs {
+            writeln!(w, "  {}: {}", "Trusted certificate".bold(), cert)?;
+        }
+        for cert in &sources.intermediate_certs {
+            writeln!(w, "  {}: {}", "Intermediate certificate".bold(), cert)?;
+        }
+        for script in &sources.provision_scripts {
+            writeln!(w, "  {}: {}", "Provisioning script".bold(), script)?;
+        }
+        if !sources.windows_features.is_empty() {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Windows features to add".bold(),
+                itertools::join(&sources.windows_features, ", ")
+            )?;
+        }
+        if !sources.remove_features.is_empty() {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Windows features to remove".bold(),
+                itertools::join(&sources.remove_features, ", ")
+            )?;
+        }
+        for file in &sources.files {
+            writeln!(w, "  {}: {}", "File to copy".bold(), file)?;
+        }
+        crate::steps::print_cloudbase_init_options(&mut w, sources)?;
+        crate::steps::print_guest_options(&mut w, sources)?;
+        let mut notes = Vec::new();
+        if args.accel == Accelerator::Auto {
+            notes.push("auto-detected".to_string());
+        }
+        if self.accel == Accelerator::Tcg && !args.tests.is_empty() {
+            notes.push(format!(
+                "image test timeouts extended {}x",
+                crate::accel::TCG_TIMEOUT_FACTOR
+            ));
+        }
+        if notes.is_empty() {
+            writeln!(w, "  {}: {}", "Accelerator".bold(), self.accel)?;
+        } else {
+            writeln!(
+                w,
+                "  {}: {} ({})",
+                "Accelerator".bold(),
+                self.accel,
+                notes.join("; ")
+            )?;
+        }
+        if let Some(secs) = args.screenshot_interval_secs {
+            writeln!(
+                w,
+                "  {}: every {secs} seconds, in {}",
+                "Screenshots".bold(),
+                crate::workspace::logs_dir(&args.work_dir)
+                    .join(super::screenshot::SCREENSHOT_DIR_NAME)
+            )?;
+        }
+        if args.hypervisor == Hypervisor::Libvirt {
+            let uri = args
+                .libvirt_uri
+                .as_deref()
+                .unwrap_or("virsh's default connection");
+            writeln!(w, "  {}: libvirt ({uri})", "Hypervisor".bold())?;
+        }
+        let nic = match self.vm.nic_model {
+            NicModel::None => "no network adapter".to_string(),
+            model => format!("{model} network adapter"),
+        };
+        writeln!(
+            w,
+            "  {}: {} vCPUs, {} MiB memory, {} machine, {} disk, {nic}",
+            "Installation VM".bold(),
+            self.vm.cpus,
+            self.vm.memory_mib,
+            self.vm.machine,
+            self.vm.disk_controller
+        )?;
 
         writeln!(w)?;
 
@@ -87,21 +448,107 @@ impl Script for CreateGuestDiskImageScript {
                 "  Image index to insert into Autounattend.xml: {}",
                 index
             )?;
+        } else if let Some(edition) = &sources.edition {
+            writeln!(w, "  Edition to install: {edition}")?;
         } else {
             writeln!(w, "  Will use default image index in Autounattend.xml")?;
         }
+        for update in &sources.updates {
+            writeln!(w, "  {}: {}", "Update to slipstream".bold(), update)?;
+        }
 
         if let Some(version) = sources.windows_version {
             writeln!(w, "  Target Windows version: {}", version)?;
         } else {
+            writeln!(w, "  Will detect Windows version from the Windows ISO")?;
+        }
+
+        writeln!(w)?;
+        writeln!(
+            w,
+            "  {}: {} ({}, {})",
+            "Output file".bold(),
+            args.output_image,
+            args.output_format,
+            args.disk_size
+        )?;
+        if let Some(qcow2_image) = &args.qcow2.qcow2_image {
+            writeln!(
+                w,
+                "  {}: {} ({}-compressed)",
+                "qcow2 image".bold(),
+                qcow2_image,
+                self.qcow2_codec.selected()
+            )?;
+        }
+        if let Some(vhdx_image) = &args.vhdx.vhdx_image {
+            writeln!(
+                w,
+                "  {}: {} ({})",
+                "VHDX image".bold(),
+                vhdx_image,
+                args.vhdx.vhdx_subformat
+            )?;
+        }
+        if let Some(vmdk_image) = &args.vmdk.vmdk_image {
             writeln!(
                 w,
-                "  Will use default Windows version in Autounattend.xml"
+                "  {}: {} (stream-optimized)",
+                "VMDK image".bold(),
+                vmdk_image
+            )?;
+        }
+        if let Some(device) = &args.output_device.output_device {
+            let discard = if args.output_device.discard_output_device {
+                " (discarding it first)"
+            } else {
+                ""
+            };
+            writeln!(w, "  {}: {}{}", "Output device".bold(), device, discard)?;
+        }
+        if let (Some(project), Some(image)) =
+            (&args.oxide.oxide_project, &args.oxide.oxide_image_name)
+        {
+            writeln!(
+                w,
+                "  {}: {} in project {}",
+                "Oxide image".bold(),
+                image,
+                project
+            )?;
+        }
+        if let Some(url) = &args.s3.publish {
+            writeln!(
+                w,
+                "  {}: {} ({} compression)",
+                "Publish to".bold(),
+                url,
+                args.s3.publish_compression
+            )?;
+        }
+        if args.compress.compression() != Compression::None {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Compression".bold(),
+                args.compress.compression()
             )?;
         }
 
-        writeln!(w)?;
-        writeln!(w, "  {}: {}", "Output file".bold(), args.output_image)?;
+        if let Some(check) = args.tests.boot_check {
+            writeln!(w, "  {}: {}", "Boot test".bold(), check)?;
+        }
+        let tests = &args.tests.tests;
+        if !tests.is_empty() {
+            let required = tests.iter().filter(|t| t.required).count();
+            writeln!(
+                w,
+                "  {}: {} ({} required)",
+                "Image tests".bold(),
+                tests.len(),
+                required
+            )?;
+        }
 
         Ok(())
     }
@@ -109,14 +556,25 @@ impl Script for CreateGuestDiskImageScript {
     fn check_prerequisites(&self) -> MissingPrerequisites {
         let mut errors = Vec::new();
         let mut warnings = Vec::new();
-        let mut files = vec![
-            self.args.sources.windows_iso.clone(),
-            self.args.sources.virtio_iso.clone(),
-            self.args.ovmf_path.clone(),
-        ];
-
-        // The ISOs and bootrom are strictly required to proceed.
+        let mut files: Vec<Utf8PathBuf> =
+            self.firmware.path().map(Utf8Path::to_owned).into_iter().collect();
+
+        // The ISOs (or driver directory) and bootrom are strictly required to
+        // proceed.
+        let (iso_errors, iso_warnings) =
+            self.args.sources.check_windows_iso_prerequisites();
+        errors.extend(iso_errors);
+        warnings.extend(iso_warnings);
         errors.extend(check_file_prerequisites(&files));
+        errors.extend(self.firmware.problem());
+        let (driver_errors, driver_warnings) =
+            self.args.sources.check_driver_prerequisites(
+                self.args.arch,
+                self.vm.disk_controller,
+                self.vm.nic_model,
+            );
+        errors.extend(driver_errors);
+        warnings.extend(driver_warnings);
 
         // The unattend files are generally desirable, but it's possible to run
         // without them. For example:
@@ -127,119 +585,527 @@ impl Script for CreateGuestDiskImageScript {
         //   cloudbase-init and so doesn't care about injecting its
         //   configuration files.
         files.clear();
-        for file in UNATTEND_FILES {
-            let mut path = self.args.sources.unattend_dir.clone();
-            path.push(file);
-            files.push(path);
-        }
+        files.extend(self.args.sources.unattend_source_paths());
 
         warnings.extend(check_file_prerequisites(&files));
+        errors.extend(self.args.sources.check_unattend_overrides());
+        errors.extend(self.args.sources.check_admin_password());
+        errors.extend(certs::check_prerequisites(
+            &self.args.sources.trusted_certs,
+        ));
+        errors.extend(certs::check_prerequisites(
+            &self.args.sources.intermediate_certs,
+        ));
+        errors.extend(crate::provision::check_prerequisites(
+            &self.args.sources.provision_scripts,
+        ));
+        errors.extend(crate::provision::check_file_prerequisites(
+            &self.args.sources.files,
+        ));
+        errors.extend(crate::cloudbase_init::check_prerequisites(
+            self.args.sources.cloudbase_init.cloudbase_init_msi.as_deref(),
+        ));
+        errors.extend(crate::ssh::check_prerequisites(
+            self.args.sources.ssh_authorized_keys.as_deref(),
+        ));
+        errors.extend(crate::network::check_prerequisites(
+            &self.args.sources.network,
+        ));
+        errors.extend(crate::users::check_prerequisites(
+            &self.args.sources.local_users,
+        ));
+        errors.extend(crate::features::check_prerequisites(
+            &self.args.sources.windows_features,
+            &self.args.sources.remove_features,
+        ));
+        errors.extend(crate::updates::check_prerequisites(
+            &self.args.sources.updates,
+            self.args.sources.edition.is_some()
+                || self.args.sources.unattend_image_index.is_some(),
+        ));
+        errors.extend(domain_join::check_prerequisites(
+            &self.args.sources.domain_join,
+            self.args.sources.hostname.as_ref(),
+        ));
+
+        if self.args.tests.uses_winrm() {
+            errors.extend(super::winrm::check_prerequisites());
+            if self.args.tests.winrm.password.is_none()
+                && self.args.sources.admin_password.is_none()
+            {
+                errors.push(
+                    "WinRM tests need the account's password; set \
+                    tests.winrm.password in the configuration file, or pass \
+                    --admin-password to test as Administrator"
+                        .to_string(),
+                );
+            }
+        }
+
+        if (self.args.arch == Architecture::Aarch64)
+            != (self.vm.machine == MachineType::Virt)
+        {
+            let expected = match self.args.arch {
+                Architecture::X86_64 => "pc or q35",
+                Architecture::Aarch64 => "virt",
+            };
+            errors.push(format!(
+                "{} builds can't use the {} machine type (pass --vm-machine \
+                {expected})",
+                self.args.arch, self.vm.machine
+            ));
+        }
+
+        if self.vm.nic_model == NicModel::None {
+            let cloudbase_init = &self.args.sources.cloudbase_init;
+            if !cloudbase_init.skip_cloudbase_init
+                && cloudbase_init.cloudbase_init_msi.is_none()
+            {
+                errors.push(
+                    "the installation VM has no network adapter, so it can't \
+                    download cloudbase-init; pass --cloudbase-init-msi to \
+                    install it from a local installer, or \
+                    --skip-cloudbase-init"
+                        .to_string(),
+                );
+            }
+
+            if !self.args.tests.tcp_ports().is_empty() {
+                errors.push(
+                    "image tests and boot tests that connect to a guest port \
+                    need the test VM to have a network adapter, which \
+                    --nic-model none leaves out"
+                        .to_string(),
+                );
+            }
+        }
 
-        // All the relevant executables are required to proceed.
-        errors.extend(check_executable_prerequisites(self.steps()));
+        let static_ip = self.args.sources.network.static_ip;
+        if static_ip.is_some_and(|ip| {
+            ip.address != crate::network::USER_NETWORK_GUEST_ADDRESS
+        }) && !self.args.tests.tcp_ports().is_empty()
+        {
+            errors.push(format!(
+                "image tests and boot tests that connect to a guest port \
+                reach the test VM at {}, QEMU's user-mode network address, \
+                but --static-ip gives the image a different address",
+                crate::network::USER_NETWORK_GUEST_ADDRESS
+            ));
+        }
+
+        let (accel_errors, accel_warnings) =
+            self.host_accel.check(self.args.accel);
+        let accel_unavailable = !accel_errors.is_empty();
+        errors.extend(accel_errors);
+        warnings.extend(accel_warnings);
+
+        errors.extend(crate::steps::check_vm_resources(
+            self.vm.cpus,
+            self.vm.memory_mib,
+        ));
+        // Memory that other builds' VMs are using now may be free by the time
+        // this build's VM gets a slot, which is when the memory is checked
+        // again.
+        if !self.args.shares_host {
+            memory::check_prerequisites(
+                self.vm.memory_mib,
+                self.args.force_memory,
+                &mut errors,
+                &mut warnings,
+            );
+        }
+        self.qcow2_codec.check_prerequisites(
+            self.args.qcow2.strict_qcow2_compression,
+            &mut errors,
+            &mut warnings,
+        );
+        errors.extend(crate::steps::check_disk_size(
+            self.args.disk_size,
+            self.args.sources.windows_version,
+        ));
+        // Raw output images are created natively, but other formats need
+        // qemu-img from the start.
+        if self.args.output_format != OutputFormat::Raw
+            && which::which("qemu-img").is_err()
+        {
+            errors.push(format!(
+                "{} output images require qemu-img, which wasn't found (is it \
+                on your PATH?)",
+                self.args.output_format
+            ));
+        }
+        errors.extend(crate::nbd::check_prerequisites(
+            &self.args.output_format.to_string(),
+        ));
+        warnings.extend(crate::doctor::shrink_warning());
+        if !self.args.force_disk_space {
+            errors.extend(
+                crate::doctor::SpaceNeeds::for_build(
+                    &self.args.work_dir,
+                    &self.args.output_image,
+                    self.args.disk_size.bytes(),
+                    &[
+                        ("qcow2 image", self.args.qcow2.qcow2_image.as_deref()),
+                        ("VHDX image", self.args.vhdx.vhdx_image.as_deref()),
+                        ("VMDK image", self.args.vmdk.vmdk_image.as_deref()),
+                    ],
+                    self.args.compress.compression(),
+                )
+                .check(),
+            );
+        }
+        if let Some(device) = &self.args.output_device.output_device {
+            errors.extend(crate::device::check_prerequisites(
+                device,
+                self.args.output_device.discard_output_device,
+            ));
+        }
+
+        if self.args.oxide.oxide_project.is_some() {
+            errors.extend(crate::oxide::check_prerequisites(
+                &self.args.output_format.to_string(),
+            ));
+        }
+
+        if self.args.s3.publish.is_some() {
+            errors.extend(crate::s3::check_prerequisites(
+                self.args.s3.publish_compression,
+            ));
+        }
 
-        MissingPrerequisites::from_messages(errors, warnings)
+        errors.extend(crate::compress::check_prerequisites(
+            self.args.compress.compression(),
+        ));
+        errors.extend(super::firmware::check_prerequisites(
+            self.args.secure_boot,
+            self.vm.machine,
+        ));
+        errors.extend(super::tpm::check_prerequisites(self.args.tpm));
+        if self.args.hypervisor == Hypervisor::Libvirt {
+            if !self.args.tests.is_empty() {
+                errors.push(
+                    "the image tests launch QEMU directly, so neither \
+                    --boot-test nor the configuration file's [tests] table \
+                    can be used with --hypervisor libvirt"
+                        .to_string(),
+                );
+            }
+            if self.args.screenshot_interval_secs.is_some() {
+                errors.push(
+                    "screenshots are taken through QEMU's QMP socket, so \
+                    --screenshot-interval-secs can't be used with \
+                    --hypervisor libvirt (use virsh screenshot instead)"
+                        .to_string(),
+                );
+            }
+            if !self.args.sources.updates.is_empty() {
+                errors.push(
+                    "updates are slipstreamed in a servicing VM that QEMU \
+                    runs directly, so neither --update nor the configuration \
+                    file's [updates] table can be used with --hypervisor \
+                    libvirt"
+                        .to_string(),
+                );
+            }
+        } else if self.args.libvirt_uri.is_some() {
+            errors.push(
+                "--libvirt-uri only applies with --hypervisor libvirt"
+                    .to_string(),
+            );
+        }
+
+        let mut missing = MissingPrerequisites::from_messages(errors, warnings);
+        if accel_unavailable {
+            missing.classify(ErrorKind::AccelUnavailable);
+        }
+        missing
+    }
+
+    fn input_files(&self) -> Vec<Utf8PathBuf> {
+        let mut files = self.args.sources.input_files();
+        files.extend(self.firmware.path().map(Utf8Path::to_owned));
+        files.extend(self.args.ovmf_vars_template.clone());
+        files
     }
 
     fn initial_context(&self) -> HashMap<String, String> {
         let args = &self.args;
         let mut ctx: HashMap<String, String> = [
             ("work_dir".to_string(), args.work_dir.to_string()),
-            ("windows_iso".to_string(), args.sources.windows_iso.to_string()),
-            ("virtio_iso".to_string(), args.sources.virtio_iso.to_string()),
             ("unattend_dir".to_string(), args.sources.unattend_dir.to_string()),
             ("output_image".to_string(), args.output_image.to_string()),
-            ("ovmf_path".to_string(), args.ovmf_path.to_string()),
+            ("arch".to_string(), args.arch.to_string()),
+            ("accel".to_string(), self.accel.to_string()),
+            ("hypervisor".to_string(), args.hypervisor.to_string()),
+            ("output_format".to_string(), args.output_format.to_string()),
+            ("disk_size".to_string(), args.disk_size.bytes().to_string()),
+            ("vm_cpus".to_string(), self.vm.cpus.to_string()),
+            ("vm_memory_mib".to_string(), self.vm.memory_mib.to_string()),
+            ("vm_machine".to_string(), self.vm.machine.to_string()),
+            (
+                "disk_controller".to_string(),
+                self.vm.disk_controller.to_string(),
+            ),
+            ("nic_model".to_string(), self.vm.nic_model.to_string()),
         ]
         .into_iter()
         .collect();
 
+        if let Some(path) = self.firmware.path() {
+            ctx.insert("ovmf_path".to_string(), path.to_string());
+        }
+
         if args.vga_console {
             ctx.insert("vga_console".to_string(), String::new());
         }
 
+        if let Some(secs) = args.screenshot_interval_secs {
+            ctx.insert(
+                "screenshot_interval_secs".to_string(),
+                secs.to_string(),
+            );
+        }
+
+        if let Some(uri) = &args.libvirt_uri {
+            ctx.insert("libvirt_uri".to_string(), uri.clone());
+        }
+
+        if let Some(template) = &args.ovmf_vars_template {
+            ctx.insert("secure_boot".to_string(), String::new());
+            ctx.insert("ovmf_vars_template".to_string(), template.to_string());
+        }
+
+        if args.tpm {
+            ctx.insert("tpm".to_string(), String::new());
+        }
+
+        if args.force_memory {
+            ctx.insert("force_memory".to_string(), String::new());
+        }
+
+        if let Some(image_index) = args.sources.unattend_image_index {
+            ctx.insert(
+                "unattend_image_index".to_string(),
+                image_index.to_string(),
+            );
+        }
+
         if let Some(version) = &args.sources.windows_version {
             ctx.insert("windows_version".to_string(), format!("{:?}", version));
         }
 
+        if let Some(qcow2_image) = &args.qcow2.qcow2_image {
+            ctx.insert("qcow2_image".to_string(), qcow2_image.to_string());
+            ctx.insert(
+                "qcow2_compression".to_string(),
+                self.qcow2_codec.selected().to_string(),
+            );
+        }
+
+        // The image tests talk to the guest through the QEMU guest agent, so
+        // have the guest setup script install it.
+        if args.tests.needs_agent() {
+            ctx.insert("install_guest_agent".to_string(), String::new());
+        }
+
+        ctx.extend(args.sources.context_vars());
+        ctx.extend(args.vhdx.context_vars());
+        ctx.extend(args.vmdk.context_vars());
+        ctx.extend(args.output_device.context_vars());
+        ctx.extend(args.oxide.context_vars());
+        ctx.extend(args.s3.context_vars());
+        ctx.extend(args.compress.context_vars());
         ctx
     }
 }
 
 fn create_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    crate::steps::create_output_image(ctx.get_var("output_image").unwrap(), ui)
+    crate::steps::create_output_image(
+        ctx.get_var("output_image").unwrap(),
+        output_format(ctx),
+        ctx.get_var("disk_size").unwrap(),
+        ui,
+    )
 }
 
-fn create_config_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    let mut unattend_iso =
-        Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
-    unattend_iso.push("unattend.iso");
+/// Makes the output image available as a raw disk for the duration of a step
+/// that needs to run `sgdisk` on it.
+fn open_raw_output_image(ctx: &Context, ui: &dyn Ui) -> Result<RawImage> {
+    RawImage::open(
+        Utf8Path::new(ctx.get_var("output_image").unwrap()),
+        output_format(ctx),
+        ui,
+    )
+}
+
+/// Partitions the blank output image of a `--reproducible` build the way
+/// Setup would, but with pinned GUIDs. See [`crate::reproducible`].
+fn partition_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    if !crate::reproducible::enabled(ctx) {
+        return Ok(());
+    }
+
+    let raw = open_raw_output_image(ctx, ui)?;
+    ui.set_substep("partitioning output image with pinned GUIDs");
+    let version = crate::steps::windows_version(ctx);
     run_command_check_status(
-        Command::new("genisoimage").args([
-            "-J",
-            "-R",
-            "-o",
-            unattend_iso.as_str(),
-            ctx.get_var("unattend_dir").unwrap(),
-        ]),
+        &mut crate::reproducible::partition_command(
+            raw.path().as_str(),
+            version,
+        ),
         ui,
+    )
+    .context("partitioning the output image")?;
+    Ok(())
+}
+
+fn describe_partition_output_image(ctx: &mut Context) -> Vec<String> {
+    if !crate::reproducible::enabled(ctx) {
+        return Vec::new();
+    }
+
+    let version = crate::steps::windows_version(ctx);
+    describe_raw_output_image(ctx, |disk| {
+        vec![format_command(&crate::reproducible::partition_command(
+            disk, version,
+        ))]
+    })
+}
+
+fn describe_create_output_image(ctx: &mut Context) -> Vec<String> {
+    crate::steps::describe_create_output_image(
+        ctx.get_var("output_image").unwrap(),
+        output_format(ctx),
+        ctx.get_var("disk_size").unwrap(),
+    )
+}
+
+/// Describes the commands that make the output image available as a raw disk
+/// around the commands `inner` returns for the raw disk's path.
+fn describe_raw_output_image(
+    ctx: &Context,
+    inner: impl FnOnce(&str) -> Vec<String>,
+) -> Vec<String> {
+    crate::nbd::describe_raw_access(
+        ctx.get_var("output_image").unwrap(),
+        output_format(ctx),
+        inner,
+    )
+}
+
+fn config_iso_path(ctx: &Context) -> Utf8PathBuf {
+    crate::workspace::iso_path(ctx, "unattend.iso")
+}
+
+fn driver_iso_path(ctx: &Context) -> Utf8PathBuf {
+    crate::drivers::driver_iso_path(Utf8Path::new(
+        ctx.get_var("work_dir").unwrap(),
+    ))
+}
+
+/// Packs the local virtio driver directory, if one was supplied, into an ISO
+/// to attach to the installation VM in place of a driver ISO.
+fn create_driver_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(driver_dir) = ctx.get_var("virtio_driver_dir") else {
+        return Ok(());
+    };
+
+    let driver_iso = driver_iso_path(ctx);
+    ui.set_substep(&format!("writing {driver_iso}"));
+    crate::iso::create_iso(Utf8Path::new(driver_dir), &driver_iso, "VIRTIO")?;
+    ctx.set_var("virtio_iso", driver_iso.to_string());
+    Ok(())
+}
+
+fn describe_create_driver_iso(ctx: &mut Context) -> Vec<String> {
+    let Some(driver_dir) = ctx.get_var("virtio_driver_dir") else {
+        return Vec::new();
+    };
+
+    let driver_iso = driver_iso_path(ctx);
+    let line = format!("write an ISO of {driver_dir} to {driver_iso}");
+    ctx.set_var("virtio_iso", driver_iso.to_string());
+    vec![line]
+}
+
+fn create_config_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let unattend_iso = config_iso_path(ctx);
+    ui.set_substep(&format!("writing {unattend_iso}"));
+    crate::iso::create_iso(
+        Utf8Path::new(ctx.get_var("unattend_dir").unwrap()),
+        &unattend_iso,
+        "CDROM",
     )?;
 
     ctx.set_var("unattend_iso", unattend_iso.to_string());
     Ok(())
 }
 
+fn describe_create_config_iso(ctx: &mut Context) -> Vec<String> {
+    let unattend_iso = config_iso_path(ctx);
+    let line = format!(
+        "write an ISO of {} to {unattend_iso}",
+        ctx.get_var("unattend_dir").unwrap()
+    );
+    ctx.set_var("unattend_iso", unattend_iso.to_string());
+    vec![line]
+}
+
+fn work_unattend_dir(ctx: &Context) -> Utf8PathBuf {
+    crate::workspace::scratch_path(ctx, "unattend")
+}
+
 fn copy_unattend_files_to_work_dir(
     ctx: &mut Context,
     ui: &dyn Ui,
 ) -> Result<()> {
-    let mut work_unattend =
-        Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
-    work_unattend.push("unattend");
-    std::fs::create_dir_all(&work_unattend)
-        .context("creating temporary directory for unattend files")?;
-
+    let work_unattend = work_unattend_dir(ctx);
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
 
-    for filename in UNATTEND_FILES {
-        ui.set_substep(&format!("copying {}", filename));
-        let mut src = unattend_dir.clone();
-        src.push(filename);
-        let mut dst = work_unattend.clone();
-        dst.push(filename);
-        if let Err(e) = std::fs::copy(&src, &dst) {
-            match e.kind() {
-                std::io::ErrorKind::NotFound => {}
-                _ => {
-                    return Err(e)
-                        .with_context(|| format!("copying {}", filename))
-                }
-            }
-        }
-    }
+    crate::steps::copy_unattend_files(
+        &unattend_dir,
+        ctx.get_var("unattend_overrides").map(Utf8Path::new),
+        &work_unattend,
+        Some(&crate::workspace::log_path(ctx, "unattend")),
+        &crate::steps::unattend_template_vars(ctx, ui)?,
+        ui,
+    )?;
 
     // Make subsequent steps use unattend files from the working copy.
     ctx.set_var("unattend_dir", work_unattend.to_string());
     Ok(())
 }
 
-fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
-    let windows_version = ctx.get_var("windows_version").and_then(|s| {
-        match s.as_ref() {
-            "Server2016" => Some(WindowsVersion::Server2016),
-            "Server2019" => Some(WindowsVersion::Server2019),
-            "Server2022" => Some(WindowsVersion::Server2022),
-            "Server2025" => Some(WindowsVersion::Server2025),
-            _ => None,
-        }
-    });
+fn describe_copy_unattend_files(ctx: &mut Context) -> Vec<String> {
+    let work_unattend = work_unattend_dir(ctx);
+    let line = match ctx.get_var("unattend_overrides") {
+        Some(overrides) => format!(
+            "copy {} to {work_unattend} with overrides from {overrides}, \
+            filling in templates",
+            ctx.get_var("unattend_dir").unwrap()
+        ),
+        None => format!(
+            "copy {} to {work_unattend}, filling in templates",
+            ctx.get_var("unattend_dir").unwrap()
+        ),
+    };
+    ctx.set_var("unattend_dir", work_unattend.to_string());
+    vec![line]
+}
+
+fn customize_autounattend_xml(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let windows_version = crate::steps::windows_version(ctx);
 
     let customizer = crate::autounattend::AutounattendUpdater::new(
         ctx.get_var("unattend_image_index")
             .map(|val| val.parse::<u32>().unwrap()),
         windows_version,
     );
+    let customizer =
+        crate::drivers::add_to_autounattend(customizer, ctx, windows_version)?;
+    let customizer =
+        crate::domain_join::add_to_autounattend(customizer, ctx, ui)?;
 
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
@@ -262,7 +1128,7 @@ fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
 
 /// Returns the number of physical CPU cores to allocate to the QEMU build VM:
 /// total physical cores on the host minus one, with a minimum of one.
-fn detect_physical_cores() -> u32 {
+pub(super) fn detect_physical_cores() -> u32 {
     let output = std::process::Command::new("lscpu").output().ok();
     if let Some(output) = output {
         let text = String::from_utf8_lossy(&output.stdout);
@@ -289,16 +1155,18 @@ fn detect_physical_cores() -> u32 {
 ///
 /// Formula: `min(16 GB, max(4 GB, total_ram - 4 GB))`. Leaves at least 4 GB
 /// for the host OS, caps at 16 GB to avoid monopolising systems with large RAM.
-fn detect_qemu_ram_mb() -> u64 {
+pub(super) fn detect_qemu_ram_mb() -> u64 {
     if let Ok(contents) = std::fs::read_to_string("/proc/meminfo") {
         for line in contents.lines() {
             if let Some(rest) = line.strip_prefix("MemTotal:") {
                 // Format: "MemTotal:       32907264 kB"
-                if let Ok(kb) = rest.split_whitespace().next().unwrap_or("").parse::<u64>() {
+                if let Ok(kb) =
+                    rest.split_whitespace().next().unwrap_or("").parse::<u64>()
+                {
                     let total_mb = kb / 1024;
                     let host_reserve_mb = 4096;
                     let computed = total_mb.saturating_sub(host_reserve_mb);
-                    return computed.max(4096).min(16384);
+                    return computed.clamp(4096, 16384);
                 }
             }
         }
@@ -306,33 +1174,40 @@ fn detect_qemu_ram_mb() -> u64 {
     8192
 }
 
-fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    let qemu_cpus = detect_physical_cores();
-    let qemu_ram_mb = detect_qemu_ram_mb();
-    ui.set_substep(&format!(
-        "allocating {qemu_cpus} vCPUs and {qemu_ram_mb} MB RAM to build VM"
-    ));
+/// Returns the QEMU system emulator that runs guests of architecture `arch`.
+pub(super) fn qemu_program(arch: Architecture) -> &'static str {
+    match arch {
+        Architecture::X86_64 => "qemu-system-x86_64",
+        Architecture::Aarch64 => "qemu-system-aarch64",
+    }
+}
 
-    // Launch a VM in QEMU with the installation target disk attached as an
-    // NVMe drive and CD-ROM drives containing the Windows installation media,
-    // the virtio driver disk, and the answer file ISO created previously.
-    // Windows setup will detect the presence of the answer file ISO and use
-    // the Autounattend.xml located there to drive installation.
+/// The name of the installation VM's TPM, whose state is kept in the work
+/// directory while the VM runs.
+const INSTALL_TPM_NAME: &str = "install";
+
+/// Returns the QEMU command that installs Windows to the output image in a VM
+/// with the resources in `vm`.
+fn install_command(ctx: &Context, vm: &VmResources) -> Command {
+    // Launch a VM in QEMU with the installation target disk attached to the
+    // requested disk controller and CD-ROM drives containing the Windows
+    // installation media, the virtio driver disk, and the answer file ISO
+    // created previously. Windows setup will detect the presence of the answer
+    // file ISO and use the Autounattend.xml located there to drive
+    // installation.
     //
-    // Using NVMe for the installation target disk matches how Oxide presents
-    // its boot disk. viostor (the VirtIO block driver) is still injected via
+    // NVMe, the default disk controller, matches how Oxide presents its boot
+    // disk. viostor (the VirtIO block driver) is still injected via
     // the offlineServicing pass in Autounattend.xml to handle the cloud-init
     // metadata drive, which Oxide presents as a VirtIO block device (DEV_1042).
     // viostor does not need to be boot-critical because Oxide boots via NVMe
     // using the inbox stornvme.sys driver.
-    let pflash_arg = format!(
-        "if=pflash,format=raw,readonly=on,file={}",
-        ctx.get_var("ovmf_path").unwrap()
-    );
-
+    let firmware_args = super::firmware::install_qemu_args(ctx);
+    let tpm_args = super::tpm::qemu_args(ctx, INSTALL_TPM_NAME);
     let install_disk_arg = format!(
-        "if=none,id=drivec,file={},format=raw,cache=writeback",
-        ctx.get_var("output_image").unwrap()
+        "if=none,id=drivec,file={},format={},cache=writeback",
+        ctx.get_var("output_image").unwrap(),
+        output_format(ctx)
     );
 
     let windows_iso_arg = format!(
@@ -350,46 +1225,40 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ctx.get_var("unattend_iso").unwrap()
     );
 
-    let ram_str = qemu_ram_mb.to_string();
-    let smp_str = format!("{qemu_cpus},sockets=1,cores={qemu_cpus}");
-
-    let mut args = vec![
-        "-nodefaults",
-        "-enable-kvm",
-        "-M",
-        "pc",
-        "-m",
-        &ram_str,
-        // Use the host CPU model so that Windows installs with a CPU
-        // configuration compatible with both the build host and Oxide hardware.
-        // kvm=off hides the KVM CPUID leaf so the guest uses Hyper-V
-        // enlightenments via the hv_* flags below.
-        "-cpu",
-        "host,kvm=off,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time",
-        "-smp",
-        &smp_str,
-        "-rtc",
-        "base=localtime",
-        "-drive",
-        &pflash_arg,
-        "-netdev",
-        "user,id=net0",
-        "-device",
-        "virtio-net-pci,netdev=net0",
-        "-device",
-        "nvme,drive=drivec,serial=01de01de,physical_block_size=512,logical_block_size=512,discard_granularity=512,bootindex=1",
+    let vm_args = vm.qemu_args();
+    let disk_args = vm.disk_args("drivec");
+    let nic_args = vm.nic_args(&[]);
+    let windows_cd_arg =
+        format!("{},bootindex=2", vm.cdrom_device(0, "win-disk"));
+    let virtio_cd_arg = vm.cdrom_device(1, "virtio-disk");
+    let unattend_cd_arg = vm.cdrom_device(2, "unattend-disk");
+    let qmp_arg = format!("unix:{},server=on,wait=off", qmp_socket(ctx));
+
+    let mut args = vec!["-nodefaults"];
+    args.extend_from_slice(super::accel::qemu_args(ctx));
+    args.extend(vm_args.iter().map(String::as_str));
+    args.extend(firmware_args.iter().map(String::as_str));
+    args.extend(tpm_args.iter().map(String::as_str));
+    args.extend_from_slice(&["-rtc", "base=localtime"]);
+    let uuid = crate::reproducible::vm_uuid();
+    if crate::reproducible::enabled(ctx) {
+        args.extend_from_slice(&["-uuid", &uuid]);
+    }
+    args.extend(nic_args.iter().map(String::as_str));
+    args.extend(disk_args.iter().map(String::as_str));
+    args.extend_from_slice(&[
         "-drive",
         &install_disk_arg,
         "-device",
-        "ide-cd,drive=win-disk,id=cd-disk0,unit=0,bus=ide.0,bootindex=2",
+        &windows_cd_arg,
         "-drive",
         &windows_iso_arg,
         "-device",
-        "ide-cd,drive=virtio-disk,id=cd-disk1,unit=0,bus=ide.1",
+        &virtio_cd_arg,
         "-drive",
         &virtio_iso_arg,
         "-device",
-        "ide-cd,drive=unattend-disk,id=cd-disk2,unit=1,bus=ide.0",
+        &unattend_cd_arg,
         "-drive",
         &unattend_iso_arg,
         // prep.cmd, the wrapper script that executes OxidePrepBaseImage.ps1,
@@ -399,72 +1268,456 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         // to do with this output.
         "-serial",
         "stdio",
-        // Set up the QEMU monitor to allow the runner to send keyboard
-        // commands via TCP.
-        "-monitor",
-        "telnet:localhost:8888,server,nowait",
-    ];
-
-    if ctx.get_var("vga_console").is_some() {
-        args.extend_from_slice(&["-vga", "std", "-display", "gtk"]);
-    } else {
-        args.extend_from_slice(&["-display", "none"]);
-    }
-
-    let qemu = "qemu-system-x86_64";
-    let qemu = Command::new(qemu)
-        .args(&args)
-        .stdout::<std::fs::File>(ui.child_stdout(qemu)?)
-        .stderr::<std::fs::File>(ui.child_stderr(qemu)?)
-        .spawn()?;
-
-    ui.set_substep("connecting to QEMU's telnet control interface");
-    let mut attempts = 0;
-    let mut telnet = loop {
-        match std::net::TcpStream::connect("127.0.0.1:8888") {
-            Ok(stream) => break Ok(stream),
-            Err(_) => {
-                if attempts < 10 {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                    attempts += 1;
-                } else {
-                    break Err(anyhow::anyhow!(
-                        "timed out waiting for QEMU to start a telnet server"
-                    ));
-                }
-            }
+        // Let the runner send keystrokes, follow the guest's resets and
+        // shutdowns, and shut the VM down through QMP.
+        "-qmp",
+        &qmp_arg,
+    ]);
+
+    args.extend(display_args(ctx, vm));
+
+    let mut cmd = Command::new(qemu_program(crate::steps::architecture(ctx)));
+    cmd.args(&args);
+    cmd
+}
+
+/// Returns the QEMU arguments that give an unattended VM a display adapter
+/// if the build shows its console or takes screenshots of it.
+fn display_args(ctx: &Context, vm: &VmResources) -> Vec<&'static str> {
+    // The virt board has no VGA adapter, but Windows on Arm can draw to a
+    // plain framebuffer. Screenshots need an adapter even when there's no
+    // window to show it in.
+    let mut args = Vec::new();
+    let vga_console = ctx.get_var("vga_console").is_some();
+    if vga_console || ctx.get_var("screenshot_interval_secs").is_some() {
+        if vm.machine == MachineType::Virt {
+            args.extend(["-device", "ramfb"]);
+        } else {
+            args.extend(["-vga", "std"]);
         }
-    }?;
+    }
+    args.extend(["-display", if vga_console { "gtk" } else { "none" }]);
+    args
+}
+
+/// Returns the QEMU command that boots the Windows ISO in a VM with the
+/// resources in `vm` to slipstream updates into its install image, with the
+/// servicing disc and scratch disk in `servicing` attached. The VM has no
+/// network adapter, since Windows PE doesn't need one to add packages.
+fn service_command(
+    ctx: &Context,
+    vm: &VmResources,
+    servicing: &crate::updates::Servicing,
+) -> Command {
+    let firmware_args = super::firmware::servicing_qemu_args(ctx);
+    let windows_iso_arg = format!(
+        "file={},if=none,id=win-disk,media=cdrom,readonly=on",
+        ctx.get_var("windows_iso").unwrap()
+    );
+    let updates_iso_arg = format!(
+        "file={},if=none,id=updates-disk,media=cdrom,readonly=on",
+        servicing.updates_iso
+    );
+    let scratch_disk_arg = format!(
+        "if=none,id=scratch,file={},format=raw,cache=unsafe",
+        servicing.scratch_disk
+    );
+    let vm_args = vm.qemu_args();
+    let windows_cd_arg =
+        format!("{},bootindex=1", vm.cdrom_device(0, "win-disk"));
+    let updates_cd_arg = vm.cdrom_device(1, "updates-disk");
+    let qmp_arg = format!("unix:{},server=on,wait=off", qmp_socket(ctx));
+
+    let mut args = vec!["-nodefaults"];
+    args.extend_from_slice(super::accel::qemu_args(ctx));
+    args.extend(vm_args.iter().map(String::as_str));
+    args.extend(firmware_args.iter().map(String::as_str));
+    args.extend_from_slice(&[
+        "-drive",
+        &scratch_disk_arg,
+        "-device",
+        "nvme,drive=scratch,serial=5c4a7c40,physical_block_size=512,\
+        logical_block_size=512",
+        "-device",
+        &windows_cd_arg,
+        "-drive",
+        &windows_iso_arg,
+        "-device",
+        &updates_cd_arg,
+        "-drive",
+        &updates_iso_arg,
+        // The servicing script reports its progress, and any failure, on
+        // COM1.
+        "-serial",
+        "stdio",
+        "-qmp",
+        &qmp_arg,
+    ]);
+    args.extend(display_args(ctx, vm));
+
+    let mut cmd = Command::new(qemu_program(crate::steps::architecture(ctx)));
+    cmd.args(&args);
+    cmd
+}
+
+fn describe_service_windows_iso(ctx: &mut Context) -> Vec<String> {
+    if ctx.get_var(crate::updates::UPDATES_VAR).is_none() {
+        return Vec::new();
+    }
+
+    let servicing = crate::updates::Servicing::new(ctx);
+    let vm_command = match VmResources::from_context(ctx) {
+        Ok(vm) => format_command(&service_command(ctx, &vm, &servicing)),
+        Err(e) => format!("can't size the servicing VM: {e:#}"),
+    };
+    crate::updates::describe_servicing(ctx, vm_command)
+}
+
+/// Slipstreams the update packages the build was given into the Windows ISO's
+/// install image by booting it in a servicing VM, then points the rest of the
+/// build at the serviced ISO. Does nothing if there are no packages.
+fn service_windows_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(servicing) = crate::updates::prepare_servicing(ctx, ui)? else {
+        return Ok(());
+    };
+
+    let vm = VmResources::from_context(ctx)?;
+    let exit = {
+        let _slot = crate::slots::take_vm_slot(ctx, ui)?;
+        memory::check_before_launch(vm.memory_mib, ctx, ui)?;
+        super::firmware::create_servicing_varstore(ctx, ui)?;
+        run_unattended_vm(
+            ctx,
+            ui,
+            service_command(ctx, &vm, &servicing),
+            &servicing.scratch_disk,
+            "waiting for the servicing VM to add updates",
+        )?
+    };
+    if exit.interrupted {
+        anyhow::bail!(
+            "interrupted; the servicing VM was shut down before it finished \
+            adding updates"
+        );
+    }
+    if !exit.status.success() {
+        anyhow::bail!("{}", describe_vm_exit("QEMU", exit.status));
+    }
+    if let Some(reason) = exit
+        .shutdown_reason
+        .as_deref()
+        .filter(|reason| *reason != "guest-shutdown")
+    {
+        anyhow::bail!(
+            "the servicing VM stopped before the guest powered it off (QEMU \
+            reported {reason})"
+        );
+    }
+
+    crate::updates::finish_servicing(ctx, ui, &servicing)
+}
+
+fn describe_install(ctx: &mut Context) -> Vec<String> {
+    let mut descriptions = super::tpm::describe(ctx, INSTALL_TPM_NAME);
+    descriptions.push(match VmResources::from_context(ctx) {
+        Ok(vm) => format_command(&install_command(ctx, &vm)),
+        Err(e) => format!("can't size the build VM: {e:#}"),
+    });
+    descriptions
+}
+
+fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let vm = VmResources::from_context(ctx)?;
+    ui.set_substep(&format!(
+        "allocating {} vCPUs and {} MiB RAM to {} build VM",
+        vm.cpus, vm.memory_mib, vm.machine
+    ));
+
+    // If other builds share the host, wait until there's room for this VM.
+    let _slot = crate::slots::take_vm_slot(ctx, ui)?;
+    memory::check_before_launch(vm.memory_mib, ctx, ui)?;
+
+    // Stopped when this function returns, by which point QEMU has exited (or
+    // the step has failed).
+    let _tpm = super::tpm::Swtpm::start(ctx, INSTALL_TPM_NAME, ui)?;
+
+    let output_image =
+        Utf8PathBuf::from_str(ctx.get_var("output_image").unwrap()).unwrap();
+    let exit = run_unattended_vm(
+        ctx,
+        ui,
+        install_command(ctx, &vm),
+        &output_image,
+        "waiting for guest to complete installation",
+    )?;
+    if exit.interrupted {
+        anyhow::bail!(
+            "interrupted; the installation VM was shut down before Windows \
+            finished installing"
+        );
+    }
+    if !exit.status.success() {
+        anyhow::bail!("{}", describe_vm_exit("QEMU", exit.status));
+    }
+    match exit.shutdown_reason.as_deref() {
+        None | Some("guest-shutdown") => Ok(()),
+        Some(reason) => anyhow::bail!(
+            "the installation VM stopped before the guest powered it off \
+            (QEMU reported {reason})"
+        ),
+    }
+}
+
+/// Runs `cmd`, which launches a VM whose guest runs unattended until it
+/// powers itself off, and waits for it to exit. While it runs, the guest's
+/// serial output is copied to the serial log and watched for reported
+/// failures, and the VM is stopped if it appears to be hung (i.e. neither
+/// writes to its serial port nor to `disk`) or the host runs low on space.
+fn run_unattended_vm(
+    ctx: &Context,
+    ui: &dyn Ui,
+    mut cmd: Command,
+    disk: &Utf8Path,
+    substep: &str,
+) -> Result<InstallExit> {
+    let work_dir =
+        Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
+    let mut monitor = DiskSpaceMonitor::new(
+        &[&work_dir, disk],
+        DiskSpaceLimits::from_context(ctx)?,
+    )?;
+    monitor.watch_progress(Watchdog::new(
+        ProgressLimits::from_context(ctx)?,
+        Some(disk),
+    ));
+
+    // The guest's serial console goes to QEMU's stdout. Watch it for
+    // failures reported by the guest's setup scripts while copying it to the
+    // serial log.
+    let qemu = qemu_program(crate::steps::architecture(ctx));
+    let serial_log = ui.serial_log(qemu)?;
+    cmd.stdout(Stdio::piped()).stderr::<std::fs::File>(ui.child_stderr(qemu)?);
+    // Keep Ctrl-C from reaching QEMU directly, so that it can be asked to
+    // shut the guest down instead (see `InstallVm`).
+    cmd.process_group(0);
+    let _ = std::fs::remove_file(qmp_socket(ctx));
+    ui.command_started(&cmd);
+    let _span = command_span(&cmd);
+    let mut qemu = cmd.spawn()?;
+    let _cancel = crate::interrupt::kill_on_cancel(&qemu);
+    monitor.watch_serial(SerialWatcher::spawn(
+        qemu.stdout.take().unwrap(),
+        serial_log,
+    ));
+
+    ui.set_substep("connecting to QEMU's QMP socket");
+    let qmp = match Qmp::connect(&qmp_socket(ctx), QMP_CONNECT_TIMEOUT) {
+        Ok(qmp) => qmp,
+        Err(e) => {
+            Vm::kill(&mut qemu);
+            return Err(e);
+        }
+    };
+    let screenshots = match Screenshots::from_context(ctx) {
+        Ok(screenshots) => screenshots,
+        Err(e) => {
+            Vm::kill(&mut qemu);
+            return Err(e);
+        }
+    };
+    let mut vm = InstallVm {
+        qemu,
+        qmp,
+        screenshots,
+        failure_screenshot: None,
+        interrupts: CatchInterrupts::new(),
+        powerdown_requested: None,
+        quit_requested: false,
+        resets: 0,
+        shutdown_reason: None,
+    };
 
     // Simulate mashing the Enter key to get past the "Press any key to boot
     // from CD or DVD" prompt and the Windows boot menu.
-    ui.set_substep("waiting for guest to complete installation");
+    ui.set_substep(substep);
     for _ in 0..20 {
-        telnet.write_all("sendkey ret\n".as_bytes())?;
-        telnet.flush()?;
+        if let Err(e) = vm.qmp.send_key("ret") {
+            vm.kill();
+            return Err(e);
+        }
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
 
-    let output = qemu.wait_with_output()?;
-    if !output.status.success() {
-        anyhow::bail!("QEMU returned non-success exit code: {:?}", output);
+    let exit = monitor.wait(&mut vm, ui);
+    ui.record_metric("installation_vm_resets", vm.resets.into());
+    if let Some(screenshots) = &vm.screenshots {
+        ui.record_metric("screenshots", screenshots.taken().into());
+    }
+    if let Some(path) = &vm.failure_screenshot {
+        ui.warn(&format!("saved a screenshot of the VM's display to {path}"));
+    }
+    exit
+}
+
+fn qmp_socket(ctx: &Context) -> Utf8PathBuf {
+    crate::workspace::scratch_path(ctx, QMP_SOCKET_NAME)
+}
+
+/// The installation VM, followed through its QMP socket.
+///
+/// Windows Setup reboots the VM several times, which QEMU handles itself;
+/// each reboot is counted, and a VM that keeps rebooting is stopped. If the
+/// user presses Ctrl-C, the guest is asked to power down, and QEMU is told to
+/// quit if it hasn't within [`POWERDOWN_GRACE`] or if the user presses
+/// Ctrl-C again. If the VM has a display, it's captured periodically (if
+/// requested) and when the VM is killed because the installation failed.
+struct InstallVm {
+    qemu: Child,
+    qmp: Qmp,
+    screenshots: Option<Screenshots>,
+    failure_screenshot: Option<Utf8PathBuf>,
+    interrupts: CatchInterrupts,
+
+    /// When the guest was asked to power down after an interruption.
+    powerdown_requested: Option<Instant>,
+    quit_requested: bool,
+    resets: u32,
+
+    /// The reason QEMU gave in the VM's `SHUTDOWN` event, if it's sent one.
+    shutdown_reason: Option<String>,
+}
+
+/// How the installation VM stopped.
+struct InstallExit {
+    status: ExitStatus,
+    interrupted: bool,
+    shutdown_reason: Option<String>,
+}
+
+impl Vm for InstallVm {
+    type Exit = InstallExit;
+
+    fn try_wait(&mut self) -> Result<Option<InstallExit>> {
+        if let Some(screenshots) = &mut self.screenshots {
+            screenshots.tick(&mut self.qmp);
+        }
+
+        for event in self.qmp.events() {
+            match event.name.as_str() {
+                "RESET" => {
+                    self.resets += 1;
+                    trace::info!(
+                        "installation VM reset",
+                        resets = self.resets,
+                        guest = event
+                            .data
+                            .get("guest")
+                            .and_then(Json::as_bool)
+                            .unwrap_or(false)
+                    );
+                    if self.resets > MAX_INSTALL_RESETS {
+                        self.kill();
+                        anyhow::bail!(
+                            "the installation VM reset {MAX_INSTALL_RESETS} \
+                            times without powering off; it's probably stuck \
+                            in a boot loop"
+                        );
+                    }
+                }
+                "SHUTDOWN" => {
+                    self.shutdown_reason = event
+                        .data
+                        .get("reason")
+                        .and_then(Json::as_str)
+                        .map(str::to_string);
+                }
+                "GUEST_PANICKED" => {
+                    self.kill();
+                    anyhow::bail!("the guest crashed (QEMU reported a panic)");
+                }
+                _ => {}
+            }
+        }
+
+        let interrupts = self.interrupts.count();
+        if interrupts > 0 && self.powerdown_requested.is_none() {
+            trace::info!(
+                "interrupted; asking the installation VM to power down"
+            );
+            if let Err(e) = self.qmp.execute("system_powerdown", None) {
+                trace::debug!(
+                    "couldn't request a power down",
+                    error = format!("{e:#}")
+                );
+            }
+            self.powerdown_requested = Some(Instant::now());
+        }
+
+        let grace_expired = self
+            .powerdown_requested
+            .is_some_and(|requested| requested.elapsed() > POWERDOWN_GRACE);
+        if (interrupts > 1 || grace_expired) && !self.quit_requested {
+            trace::info!("telling QEMU to quit");
+            if self.qmp.execute("quit", None).is_err() {
+                self.kill();
+            }
+            self.quit_requested = true;
+        }
+
+        Ok(self.qemu.try_wait()?.map(|status| InstallExit {
+            status,
+            interrupted: interrupts > 0,
+            shutdown_reason: self.shutdown_reason.clone(),
+        }))
     }
 
-    Ok(())
+    fn kill(&mut self) {
+        if let Some(screenshots) = &mut self.screenshots {
+            match screenshots.capture(&mut self.qmp, "failure") {
+                Ok(path) => self.failure_screenshot = Some(path),
+                Err(e) => trace::debug!(
+                    "couldn't take a screenshot of the failure",
+                    error = format!("{e:#}")
+                ),
+            }
+        }
+
+        Vm::kill(&mut self.qemu);
+    }
+}
+
+fn describe_get_partition_size(ctx: &mut Context) -> Vec<String> {
+    describe_raw_output_image(ctx, |disk| {
+        vec![format!("read the partition table of {disk}")]
+    })
 }
 
 fn get_partition_size(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let raw = open_raw_output_image(ctx, ui)?;
     let (sector_size, last_sector) =
-        crate::steps::get_output_image_partition_size(
-            ctx.get_var("output_image").unwrap(),
-            ui,
-        )?;
+        crate::steps::get_output_image_partition_size(raw.path().as_str(), ui)?;
+    drop(raw);
 
-    ctx.set_var("sector_size", sector_size);
-    ctx.set_var("last_sector", last_sector);
+    ctx.set_var("sector_size", sector_size.to_string());
+    ctx.set_var("last_sector", last_sector.to_string());
     Ok(())
 }
 
+fn delete_partition_command(disk: &str, part_num: &str) -> Command {
+    let mut cmd = Command::new("sgdisk");
+    cmd.args(["-d", part_num, disk]);
+    cmd
+}
+
+fn describe_delete_trailing_partitions(ctx: &mut Context) -> Vec<String> {
+    describe_raw_output_image(ctx, |disk| {
+        vec![format!(
+            "{} for each partition after the Windows partition",
+            format_command(&delete_partition_command(disk, "<partition>"))
+        )]
+    })
+}
+
 /// Removes any partitions that trail the OS (Basic data, type 0700) partition.
 ///
 /// Windows Server 2025 creates a second Recovery partition (type 2700) after
@@ -477,70 +1730,28 @@ fn delete_trailing_recovery_partition(
     ui: &dyn Ui,
 ) -> Result<()> {
     let output_image = ctx.get_var("output_image").unwrap().to_string();
+    let raw = open_raw_output_image(ctx, ui)?;
+    let disk = raw.path().as_str();
     ui.set_substep("scanning partition table for trailing recovery partitions");
 
-    let output = run_command_check_status(
-        Command::new("sgdisk").args(["-p", &output_image]),
-        ui,
-    )
-    .context("running 'sgdisk -p' to list partitions")?;
+    let table = crate::steps::read_partition_table(disk, ui)?;
 
-    struct PartEntry {
-        number: u32,
-        end_sector: u64,
-        type_code: String,
-    }
-
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let mut partitions: Vec<PartEntry> = Vec::new();
-    for line in output_str.lines() {
-        let trimmed = line.trim_start();
-        if !trimmed.starts_with(|c: char| c.is_ascii_digit()) {
-            continue;
-        }
-        let mut cols = trimmed.split_whitespace();
-        let number: u32 = cols
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("missing partition number in line '{line}'"))?
-            .parse()
-            .with_context(|| format!("parsing partition number from '{line}'"))?;
-        let _start: u64 = cols
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("missing start sector in line '{line}'"))?
-            .parse()
-            .with_context(|| format!("parsing start sector from '{line}'"))?;
-        let end_sector: u64 = cols
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("missing end sector in line '{line}'"))?
-            .parse()
-            .with_context(|| format!("parsing end sector from '{line}'"))?;
-        // Skip the size value and unit columns (e.g. "16.4" "GiB").
-        let _size_val = cols.next();
-        let _size_unit = cols.next();
-        let type_code = cols
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("missing type code in line '{line}'"))?
-            .to_string();
-
-        partitions.push(PartEntry { number, end_sector, type_code });
-    }
-
-    // Find the end sector of the largest Basic data (0700) partition — the OS.
-    let os_end = partitions
+    // Find the end sector of the largest Basic data partition — the OS.
+    let os_end = table
+        .partitions
         .iter()
-        .filter(|p| p.type_code == "0700")
-        .map(|p| p.end_sector)
+        .filter(|p| p.type_guid == Guid::BASIC_DATA)
+        .map(|p| p.last_lba)
         .max()
         .ok_or_else(|| {
-            anyhow::anyhow!(
-                "no Basic data (0700) partition found in '{output_image}'"
-            )
+            anyhow::anyhow!("no Basic data partition found in '{output_image}'")
         })?;
 
     // Collect partitions that lie entirely after the OS partition.
-    let trailing: Vec<u32> = partitions
+    let trailing: Vec<u32> = table
+        .partitions
         .iter()
-        .filter(|p| p.end_sector > os_end)
+        .filter(|p| p.last_lba > os_end)
         .map(|p| p.number)
         .collect();
 
@@ -554,8 +1765,7 @@ fn delete_trailing_recovery_partition(
             "deleting trailing partition {part_num} from '{output_image}'"
         ));
         run_command_check_status(
-            Command::new("sgdisk")
-                .args(["-d", &part_num.to_string(), &output_image]),
+            &mut delete_partition_command(disk, &part_num.to_string()),
             ui,
         )
         .with_context(|| {
@@ -569,60 +1779,294 @@ fn delete_trailing_recovery_partition(
 fn shrink_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     crate::steps::shrink_output_image(
         ctx.get_var("output_image").unwrap(),
+        output_format(ctx),
         ctx.get_var("sector_size").unwrap(),
         ctx.get_var("last_sector").unwrap(),
         ui,
     )
 }
 
+fn describe_shrink(ctx: &mut Context) -> Vec<String> {
+    crate::steps::describe_shrink_output_image(
+        ctx.get_var("output_image").unwrap(),
+        output_format(ctx),
+        ctx.get_var("sector_size").unwrap(),
+        ctx.get_var("last_sector").unwrap(),
+    )
+}
+
+fn describe_repair(ctx: &mut Context) -> Vec<String> {
+    describe_raw_output_image(ctx, crate::steps::describe_repair_secondary_gpt)
+}
+
 fn repair_secondary_gpt(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    crate::steps::repair_secondary_gpt(ctx.get_var("output_image").unwrap(), ui)
+    let raw = open_raw_output_image(ctx, ui)?;
+    crate::steps::repair_secondary_gpt(raw.path().as_str(), ui)
 }
 
-fn get_script() -> Vec<ScriptStep> {
+/// Returns the step that installs Windows to the output image, in a VM run by
+/// `hypervisor`.
+fn install_step(arch: Architecture, hypervisor: Hypervisor) -> ScriptStep {
+    match hypervisor {
+        Hypervisor::Qemu => ScriptStep::with_prereqs(
+            "install-windows",
+            "install Windows to output image using QEMU",
+            install_via_qemu,
+            &[qemu_program(arch)],
+        )
+        .describe(describe_install),
+        Hypervisor::Libvirt => ScriptStep::with_prereqs(
+            "install-windows",
+            "install Windows to output image using libvirt",
+            super::libvirt::install_windows,
+            &["virsh"],
+        )
+        .describe(super::libvirt::describe_install),
+    }
+}
+
+fn get_script(
+    tests: &ImageTests,
+    arch: Architecture,
+    hypervisor: Hypervisor,
+) -> Vec<Box<dyn Step>> {
+    let qemu = qemu_program(arch);
+    let tests = tests.clone();
     vec![
-        ScriptStep::with_prereqs(
+        ScriptStep::new(
+            "download-windows-iso",
+            "download Windows setup ISO",
+            crate::steps::download_windows_iso,
+        )
+        .retryable()
+        .provides(&["windows_iso"])
+        .describe(crate::steps::describe_download_windows_iso),
+        ScriptStep::new(
+            "download-virtio-iso",
+            "download virtio driver ISO",
+            crate::drivers::download_virtio_iso,
+        )
+        .retryable()
+        .provides(&["virtio_iso"])
+        .describe(crate::drivers::describe_download_virtio_iso),
+        ScriptStep::new(
+            "select-edition",
+            "select Windows edition from setup ISO",
+            crate::steps::select_edition,
+        )
+        .describe(crate::steps::describe_select_edition),
+        ScriptStep::new(
+            "detect-windows-version",
+            "detect Windows version from setup ISO",
+            crate::steps::detect_windows_version,
+        )
+        .describe(crate::steps::describe_detect_windows_version),
+        ScriptStep::new(
+            "select-product-key",
+            "select product key for activation",
+            crate::activation::select_product_key,
+        )
+        .describe(crate::activation::describe_select_product_key),
+        ScriptStep::new(
+            "service-windows-iso",
+            "slipstream updates into the Windows setup ISO",
+            service_windows_iso,
+        )
+        .provides(&["windows_iso", "unattend_image_index"])
+        .describe(describe_service_windows_iso),
+        ScriptStep::new(
+            "create-output-image",
             "create output image",
             create_output_image,
-            &["qemu-img"],
-        ),
+        )
+        .describe(describe_create_output_image),
         ScriptStep::with_prereqs(
-            "create guest configuration ISO",
-            create_config_iso,
-            &["genisoimage"],
-        ),
+            "partition-output-image",
+            "partition output image for a reproducible build",
+            partition_output_image,
+            &["sgdisk"],
+        )
+        .describe(describe_partition_output_image),
         ScriptStep::new(
+            "copy-unattend-files",
             "copy unattend files to work directory",
             copy_unattend_files_to_work_dir,
+        )
+        .provides(&["unattend_dir"])
+        .describe(describe_copy_unattend_files),
+        ScriptStep::new(
+            "configure-cloudbase-init",
+            "configure cloudbase-init",
+            crate::cloudbase_init::configure_cloudbase_init,
+        )
+        .describe(crate::cloudbase_init::describe_configure_cloudbase_init),
+        ScriptStep::new(
+            "configure-network",
+            "add static network settings to specialize-unattend.xml",
+            crate::network::configure_network,
+        )
+        .describe(crate::network::describe_configure_network),
+        ScriptStep::new(
+            "configure-domain-join",
+            "add domain join settings to specialize-unattend.xml",
+            crate::domain_join::configure_deployment_join,
+        )
+        .describe(crate::domain_join::describe_configure_deployment_join),
+        ScriptStep::new(
+            "stage-trusted-certs",
+            "stage trusted certificates",
+            crate::certs::stage_trusted_certs,
+        ),
+        ScriptStep::new(
+            "stage-files",
+            "stage files to copy into the image",
+            crate::provision::stage_files,
+        )
+        .describe(crate::provision::describe_stage_files),
+        ScriptStep::new(
+            "stage-provision-scripts",
+            "stage provisioning scripts",
+            crate::provision::stage_provision_scripts,
+        )
+        .describe(crate::provision::describe_stage_provision_scripts),
+        ScriptStep::new(
+            "write-registry-settings",
+            "compile registry settings into a .reg file",
+            crate::registry_settings::write_registry_settings,
+        )
+        .describe(crate::registry_settings::describe_write_registry_settings),
+        ScriptStep::new(
+            "write-guest-settings",
+            "write guest setup script settings",
+            crate::steps::write_guest_settings,
         ),
         ScriptStep::new(
+            "customize-autounattend",
             "customize Autounattend.xml",
             customize_autounattend_xml,
         ),
-        ScriptStep::with_prereqs(
-            "install Windows to output image using QEMU",
-            install_via_qemu,
-            &["qemu-system-x86_64"],
+        ScriptStep::new(
+            "validate-autounattend",
+            "check Autounattend.xml for settings that stop Setup",
+            crate::validate::validate_autounattend,
         ),
+        ScriptStep::new(
+            "create-driver-iso",
+            "pack virtio driver directory into an ISO",
+            create_driver_iso,
+        )
+        .provides(&["virtio_iso"])
+        .describe(describe_create_driver_iso),
+        ScriptStep::new(
+            "create-config-iso",
+            "create guest configuration ISO",
+            create_config_iso,
+        )
+        .provides(&["unattend_iso"])
+        .describe(describe_create_config_iso),
+        ScriptStep::new(
+            "create-ovmf-vars",
+            "create UEFI varstore for the installation VM",
+            super::firmware::create_varstore,
+        )
+        .describe(super::firmware::describe_create_varstore),
+        install_step(arch, hypervisor),
+        ScriptStep::new(
+            "save-ovmf-vars",
+            "save OVMF varstore next to output image",
+            super::firmware::save_varstore,
+        )
+        .describe(super::firmware::describe_save_varstore),
         ScriptStep::with_prereqs(
+            "delete-trailing-partitions",
             "delete trailing recovery partition",
             delete_trailing_recovery_partition,
             &["sgdisk"],
-        ),
+        )
+        .retryable()
+        .describe(describe_delete_trailing_partitions),
         ScriptStep::with_prereqs(
+            "get-partition-size",
             "get size of primary installation partition",
             get_partition_size,
             &["sgdisk"],
-        ),
+        )
+        .retryable()
+        .provides(&["sector_size", "last_sector"])
+        .describe(describe_get_partition_size),
         ScriptStep::with_prereqs(
+            "shrink-output-image",
             "trim unused sectors from output image",
             shrink_output_image,
             &["qemu-img"],
-        ),
+        )
+        .retryable()
+        .describe(describe_shrink),
         ScriptStep::with_prereqs(
+            "repair-secondary-gpt",
             "repair secondary GPT in output image",
             repair_secondary_gpt,
             &["sgdisk"],
+        )
+        .retryable()
+        .describe(describe_repair),
+        ScriptStep::with_prereqs(
+            "test-output-image",
+            "boot output image and run functional tests against it",
+            move |ctx, ui| super::image_tests::run_image_tests(&tests, ctx, ui),
+            &["qemu-img", qemu],
         ),
+        ScriptStep::new(
+            "publish-oxide-image",
+            "upload output image to Oxide rack",
+            crate::oxide::publish_image,
+        )
+        .describe(crate::oxide::describe_publish_image),
+        ScriptStep::new(
+            "publish-s3",
+            "upload output image to object storage",
+            crate::s3::publish_image,
+        )
+        .retryable()
+        .describe(crate::s3::describe_publish_image),
+        ScriptStep::with_prereqs(
+            "convert-output-image",
+            "convert output image to compressed qcow2",
+            crate::qcow2::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::qcow2::describe_conversion),
+        ScriptStep::with_prereqs(
+            "convert-output-image-vhdx",
+            "convert output image to VHDX",
+            crate::vhdx::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::vhdx::describe_conversion),
+        ScriptStep::with_prereqs(
+            "convert-output-image-vmdk",
+            "convert output image to VMDK",
+            crate::vmdk::convert_output_image,
+            &["qemu-img"],
+        )
+        .retryable()
+        .describe(crate::vmdk::describe_conversion),
+        ScriptStep::new(
+            "write-output-device",
+            "write output image to output device",
+            crate::device::write_output_device,
+        )
+        .describe(crate::device::describe_write_output_device),
+        ScriptStep::new(
+            "compress-output-image",
+            "compress output image",
+            crate::compress::compress_output_image,
+        )
+        .describe(crate::compress::describe_compression),
     ]
+    .into_iter()
+    .map(Into::into)
+    .collect()
 }
@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Host checks for building images with QEMU.
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    app::{DiskSize, OutputFormat},
+    autounattend::Architecture,
+    doctor::{self, Report, Status, Tool},
+    monitor,
+};
+
+use super::ovmf::Firmware;
+
+pub(super) struct DoctorArgs {
+    pub work_dir: Utf8PathBuf,
+    pub output_image: Utf8PathBuf,
+    pub ovmf_path: Option<Utf8PathBuf>,
+    pub output_format: OutputFormat,
+    pub disk_size: DiskSize,
+}
+
+pub(super) fn check_host(args: &DoctorArgs) -> Report {
+    let mut report = Report::default();
+    let mut tools = vec![
+        Tool {
+            name: "qemu-system-x86_64",
+            purpose: "to run the installation VM",
+            required: true,
+        },
+        Tool {
+            name: "qemu-img",
+            purpose: "to resize and convert the output image",
+            required: true,
+        },
+        Tool {
+            name: "sgdisk",
+            purpose: "to edit the output image's partition table",
+            required: true,
+        },
+        Tool {
+            name: "virsh",
+            purpose: "to run the installation VM with --hypervisor libvirt",
+            required: false,
+        },
+        Tool {
+            name: "curl",
+            purpose: "to download virtio driver ISOs pinned by a manifest",
+            required: false,
+        },
+    ];
+    if args.output_format != OutputFormat::Raw {
+        tools.push(Tool {
+            name: "qemu-nbd",
+            purpose: "to edit the partition tables of qcow2 output images",
+            required: true,
+        });
+    }
+
+    doctor::check_tools(&mut report, &tools);
+    doctor::check_qemu_img(&mut report);
+
+    match super::kvm::probe().problem() {
+        None => report.ok("KVM", "available"),
+        Some(problem) => report.add(
+            "KVM",
+            Status::Warning,
+            format!(
+                "{}; installation VMs will use TCG and run much more slowly",
+                problem.cause
+            ),
+            Some(problem.remedy),
+        ),
+    }
+
+    check_ovmf(&mut report, args.ovmf_path.as_deref());
+
+    if args.output_format != OutputFormat::Raw {
+        let format = args.output_format.to_string();
+        // qemu-nbd's absence has already been reported above.
+        for error in crate::nbd::check_prerequisites(&format)
+            .into_iter()
+            .filter(|e| !e.contains("qemu-nbd"))
+        {
+            report.add(format!("{format} output"), Status::Error, error, None);
+        }
+    }
+
+    doctor::check_free_space(
+        &mut report,
+        "work directory",
+        &args.work_dir,
+        monitor::DEFAULT_WARN_FREE_MIB * 1024 * 1024,
+    );
+    doctor::check_free_space(
+        &mut report,
+        "output image",
+        &args.output_image,
+        args.disk_size.bytes(),
+    );
+
+    report
+}
+
+fn check_ovmf(report: &mut Report, ovmf_path: Option<&Utf8Path>) {
+    if let Some(path) = ovmf_path {
+        if path.is_file() {
+            report.ok("OVMF", format!("found at {path}"));
+        } else {
+            report.add(
+                "OVMF",
+                Status::Error,
+                format!("'{path}' doesn't exist or isn't a file"),
+                Some("pass the path to an OVMF_CODE.fd file".to_string()),
+            );
+        }
+
+        return;
+    }
+
+    let firmware = Firmware::find(None, Architecture::X86_64, false);
+    match firmware.path() {
+        Some(path) => report.ok(
+            "OVMF",
+            format!("found at {path} (builds will use it by default)"),
+        ),
+        None => report.add(
+            "OVMF",
+            Status::Warning,
+            "no OVMF bootrom found in the usual locations",
+            firmware.remedy(),
+        ),
+    }
+}
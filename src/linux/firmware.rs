@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sets up the OVMF firmware the installation and test VMs boot from.
+//!
+//! By default the VMs get a single read-only OVMF image, which keeps its UEFI
+//! variables in memory and forgets them when the VM exits. Secure Boot needs
+//! OVMF split into its code and a separate variable store ("varstore") that
+//! holds the Secure Boot keys. wimsy copies a varstore template with
+//! Microsoft's keys already enrolled (which distributions ship alongside
+//! their Secure Boot OVMF builds) into the work directory, installs Windows
+//! with it, and saves it next to the output image, since it also holds the
+//! boot entries Windows setup created.
+//!
+//! AAVMF, OVMF's counterpart for aarch64 VMs, always needs a varstore, since
+//! QEMU's virt board maps its firmware from two flash devices. Without Secure
+//! Boot, wimsy gives it an empty varstore, which AAVMF formats on first boot,
+//! and keeps it with the image the same way.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    app::MachineType, autounattend::Architecture, runner::Context, ui::Ui,
+};
+
+/// The name of the installation VM's varstore in the work directory.
+const VARS_FILE_NAME: &str = "OVMF_VARS.fd";
+
+/// The name of the test VM's copy of the saved varstore in the work
+/// directory.
+const TEST_VARS_FILE_NAME: &str = "test-OVMF_VARS.fd";
+
+/// The name of the servicing VM's varstore in the work directory. The VM
+/// only runs Windows PE, so its varstore is thrown away.
+const SERVICING_VARS_FILE_NAME: &str = "servicing-OVMF_VARS.fd";
+
+/// The size of the flash devices on QEMU's virt board, which AAVMF's code
+/// image and varstore must both fill exactly.
+const VIRT_FLASH_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Returns the path to which the varstore of the image at `output_image` is
+/// saved.
+pub(super) fn saved_vars_path(output_image: &str) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{output_image}.vars.fd"))
+}
+
+/// Whether the VMs' firmware keeps its variables in a varstore file: always
+/// for aarch64 VMs, and for x86_64 VMs that use Secure Boot.
+fn has_varstore(ctx: &Context) -> bool {
+    ctx.get_var("secure_boot").is_some()
+        || crate::steps::architecture(ctx) == Architecture::Aarch64
+}
+
+/// Checks that Secure Boot, if requested, can be used with `machine`.
+pub(super) fn check_prerequisites(
+    secure_boot: bool,
+    machine: MachineType,
+) -> Vec<String> {
+    if secure_boot && machine == MachineType::Pc {
+        vec![format!(
+            "Secure Boot requires the q35 machine type (pass --vm-machine \
+            q35), since OVMF's Secure Boot builds need SMM, which the \
+            {machine} machine type doesn't support"
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns the QEMU arguments that load the firmware, using the varstore at
+/// `vars` if it has one.
+fn qemu_args(ctx: &Context, vars: &Utf8Path) -> Vec<String> {
+    // The firmware is only missing in dry runs whose prerequisites weren't
+    // satisfied, which still describe the VM.
+    let code = ctx.get_var("ovmf_path").unwrap_or("OVMF_CODE.fd");
+    if !has_varstore(ctx) {
+        return vec![
+            "-drive".to_string(),
+            format!("if=pflash,format=raw,readonly=on,file={code}"),
+        ];
+    }
+
+    if crate::steps::architecture(ctx) == Architecture::Aarch64 {
+        return pflash_args(code, vars);
+    }
+
+    let mut args: Vec<String> = [
+        // Secure Boot OVMF builds keep the varstore safe from the guest
+        // kernel by only writing it from System Management Mode, so the
+        // machine needs SMM, and the flash devices need to refuse writes from
+        // outside it.
+        "-machine",
+        "smm=on",
+        "-global",
+        "driver=cfi.pflash01,property=secure,value=on",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect();
+    args.extend(pflash_args(code, vars));
+    args
+}
+
+/// Returns the arguments that map the firmware's code image and varstore
+/// `vars` to the VM's two flash devices.
+fn pflash_args(code: &str, vars: &Utf8Path) -> Vec<String> {
+    vec![
+        "-drive".to_string(),
+        format!("if=pflash,format=raw,unit=0,readonly=on,file={code}"),
+        "-drive".to_string(),
+        format!("if=pflash,format=raw,unit=1,file={vars}"),
+    ]
+}
+
+/// Returns the installation VM's firmware code image and, if the firmware has
+/// one, the path of its varstore.
+pub(super) fn install_images(ctx: &Context) -> (&str, Option<Utf8PathBuf>) {
+    let code = ctx.get_var("ovmf_path").unwrap_or("OVMF_CODE.fd");
+    let vars = has_varstore(ctx)
+        .then(|| crate::workspace::scratch_path(ctx, VARS_FILE_NAME));
+    (code, vars)
+}
+
+/// Returns the QEMU arguments that load the installation VM's firmware.
+pub(super) fn install_qemu_args(ctx: &Context) -> Vec<String> {
+    qemu_args(ctx, &crate::workspace::scratch_path(ctx, VARS_FILE_NAME))
+}
+
+/// Returns the QEMU arguments that load the test VM's firmware, copying the
+/// saved varstore so that the tests don't change it. The copy is recreated
+/// every time the test VM launches.
+pub(super) fn test_qemu_args(ctx: &Context) -> Result<Vec<String>> {
+    let vars = crate::workspace::scratch_path(ctx, TEST_VARS_FILE_NAME);
+    if has_varstore(ctx) {
+        let saved = saved_vars_path(ctx.get_var("output_image").unwrap());
+        std::fs::copy(&saved, &vars)
+            .with_context(|| format!("copying '{saved}' to '{vars}'"))?;
+    }
+
+    Ok(qemu_args(ctx, &vars))
+}
+
+/// Returns the QEMU arguments that load the servicing VM's firmware.
+pub(super) fn servicing_qemu_args(ctx: &Context) -> Vec<String> {
+    qemu_args(
+        ctx,
+        &crate::workspace::scratch_path(ctx, SERVICING_VARS_FILE_NAME),
+    )
+}
+
+/// Gives the servicing VM a fresh varstore, if its firmware needs one.
+pub(super) fn create_servicing_varstore(
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    if !has_varstore(ctx) {
+        return Ok(());
+    }
+
+    let vars = crate::workspace::scratch_path(ctx, SERVICING_VARS_FILE_NAME);
+    write_varstore(ctx, &vars, ui)
+}
+
+/// Copies the varstore template to `vars`, or creates an empty varstore
+/// there if there's no template.
+fn write_varstore(ctx: &Context, vars: &Utf8Path, ui: &dyn Ui) -> Result<()> {
+    let Some(template) = ctx.get_var("ovmf_vars_template") else {
+        ui.set_substep("creating an empty varstore");
+        let file = std::fs::File::create(vars)
+            .with_context(|| format!("creating '{vars}'"))?;
+        file.set_len(VIRT_FLASH_SIZE)
+            .with_context(|| format!("resizing '{vars}'"))?;
+        return Ok(());
+    };
+
+    ui.set_substep(&format!("copying {template}"));
+    std::fs::copy(template, vars)
+        .with_context(|| format!("copying '{template}' to '{vars}'"))?;
+    Ok(())
+}
+
+/// Copies the varstore template to the work directory for the installation
+/// VM, or creates an empty varstore there for an aarch64 VM without Secure
+/// Boot. Does nothing if the firmware doesn't need a varstore.
+pub(super) fn create_varstore(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    if !has_varstore(ctx) {
+        return Ok(());
+    }
+
+    let vars = crate::workspace::scratch_path(ctx, VARS_FILE_NAME);
+    write_varstore(ctx, &vars, ui)
+}
+
+pub(super) fn describe_create_varstore(ctx: &mut Context) -> Vec<String> {
+    if !has_varstore(ctx) {
+        return Vec::new();
+    }
+
+    let vars = crate::workspace::scratch_path(ctx, VARS_FILE_NAME);
+    vec![match ctx.get_var("ovmf_vars_template") {
+        Some(template) => format!("copy {template} to {vars}"),
+        None => {
+            format!("create an empty {VIRT_FLASH_SIZE}-byte varstore at {vars}")
+        }
+    }]
+}
+
+/// Saves the installation VM's varstore next to the output image. Does
+/// nothing if the firmware doesn't need a varstore.
+pub(super) fn save_varstore(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    if !has_varstore(ctx) {
+        return Ok(());
+    }
+
+    let vars = crate::workspace::scratch_path(ctx, VARS_FILE_NAME);
+    let saved = saved_vars_path(ctx.get_var("output_image").unwrap());
+    ui.set_substep(&format!("copying {vars} to {saved}"));
+    std::fs::copy(&vars, &saved)
+        .with_context(|| format!("copying '{vars}' to '{saved}'"))?;
+    ui.record_metric("ovmf_vars", saved.as_str().into());
+    Ok(())
+}
+
+pub(super) fn describe_save_varstore(ctx: &mut Context) -> Vec<String> {
+    if !has_varstore(ctx) {
+        return Vec::new();
+    }
+
+    vec![format!(
+        "copy {} to {}",
+        crate::workspace::scratch_path(ctx, VARS_FILE_NAME),
+        saved_vars_path(ctx.get_var("output_image").unwrap())
+    )]
+}
@@ -0,0 +1,987 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the functional tests in the configuration file's `[tests]` table
+//! against the finished output image, after checking that it boots if a boot
+//! test was asked for.
+//!
+//! Tests run in a throwaway VM that boots from a qcow2 overlay on top of the
+//! output image, so booting the image (which specializes it) doesn't change
+//! it. PowerShell tests run through the QEMU guest agent, which the guest
+//! setup script installs from the virtio driver ISO when tests are
+//! configured. The agent runs commands as `LocalSystem`, so tests don't need
+//! guest credentials. Tests can instead run through WinRM as a real account
+//! (see the `winrm` module). TCP port tests connect from the host through
+//! user-mode networking port forwards. The VM and overlay are destroyed when
+//! the tests finish, whether or not they pass.
+//!
+//! A boot test waits for one sign that Windows booted: the banner of the
+//! serial console the guest setup script enables, the guest agent, or a
+//! listening TCP port. The serial console banner appears as soon as the
+//! kernel has started, so it's the quickest check, and the only one that
+//! doesn't depend on anything the image runs once it's booted.
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    config::{
+        BootCheck, ImageTest, ImageTests, TestCheck, Transport,
+        DEFAULT_TEST_TIMEOUT_SECS,
+    },
+    interrupt::CancelGuard,
+    json::Json,
+    memory,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{
+        command_span, decode_base64, describe_vm_exit, encode_base64,
+        run_command_check_status,
+    },
+};
+
+use super::{
+    create_guest_disk_image::{qemu_program, VmResources},
+    winrm::WinrmClient,
+};
+
+/// The name of the overlay the test VM boots from, relative to the working
+/// directory.
+const OVERLAY_FILE_NAME: &str = "test-overlay.qcow2";
+
+/// The name of the socket connected to the test VM's guest agent, relative
+/// to the working directory.
+const AGENT_SOCKET_FILE_NAME: &str = "test-agent.sock";
+
+/// The name of the socket connected to the test VM's serial port, relative
+/// to the working directory.
+const SERIAL_SOCKET_FILE_NAME: &str = "test-serial.sock";
+
+/// What Windows' Special Administration Console prints to the serial port
+/// when the kernel starts it.
+const SAC_BANNER: &[u8] = b"SAC started and initialized";
+
+/// The name of the test VM's TPM.
+const TPM_NAME: &str = "test";
+
+const POWERSHELL: &str =
+    r"C:\Windows\System32\WindowsPowerShell\v1.0\powershell.exe";
+
+/// A PowerShell script that prints `True` once Windows has finished setting
+/// itself up. The guest agent starts during the specialize pass, before the
+/// guest reboots into OOBE, so tests wait for this before running.
+const SETUP_COMPLETE_SCRIPT: &str = "(Get-ItemProperty \
+    HKLM:\\SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Setup\\State)\
+    .ImageState -eq 'IMAGE_STATE_COMPLETE'";
+
+/// How long to wait for the guest agent to answer a request that doesn't
+/// run a command.
+const AGENT_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to poll for a command's exit and to retry waiting steps.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of bytes of each test's output to include in the build report.
+const REPORTED_OUTPUT_LIMIT: usize = 4096;
+
+/// A qcow2 overlay on the output image, deleted when dropped.
+struct Overlay {
+    path: Utf8PathBuf,
+}
+
+impl Overlay {
+    fn create(
+        image: &Utf8Path,
+        format: &str,
+        path: Utf8PathBuf,
+        ui: &dyn Ui,
+    ) -> Result<Self> {
+        run_command_check_status(
+            Command::new("qemu-img").args([
+                "create",
+                "-f",
+                "qcow2",
+                "-b",
+                image.as_str(),
+                "-F",
+                format,
+                path.as_str(),
+            ]),
+            ui,
+        )
+        .context("creating overlay for test VM")?;
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            trace::debug!(
+                "couldn't remove test overlay",
+                path = self.path.as_str(),
+                error = e.to_string()
+            );
+        }
+    }
+}
+
+/// A running test VM. Dropping it kills the VM and removes its overlay.
+struct TestVm {
+    qemu: Child,
+    socket: Utf8PathBuf,
+    serial_socket: Utf8PathBuf,
+
+    // Removed or stopped when the VM is dropped, after `drop` has killed the
+    // VM.
+    _overlay: Overlay,
+    _tpm: Option<super::tpm::Swtpm>,
+    _cancel: CancelGuard,
+
+    /// The host's room for this VM, if other builds share the host. Freed
+    /// once the VM has stopped.
+    _slot: Option<crate::slots::Slot>,
+}
+
+impl TestVm {
+    /// Boots a VM from a new overlay on the output image, forwarding each
+    /// `(host, guest)` TCP port pair in `forwards`.
+    fn launch(
+        ctx: &Context,
+        forwards: &[(u16, u16)],
+        ui: &dyn Ui,
+    ) -> Result<Self> {
+        let output_image = Utf8Path::new(ctx.get_var("output_image").unwrap());
+        let socket =
+            crate::workspace::scratch_path(ctx, AGENT_SOCKET_FILE_NAME);
+        let _ = std::fs::remove_file(&socket);
+        let serial_socket =
+            crate::workspace::scratch_path(ctx, SERIAL_SOCKET_FILE_NAME);
+        let _ = std::fs::remove_file(&serial_socket);
+
+        ui.set_substep("creating overlay for test VM");
+        let overlay = Overlay::create(
+            output_image,
+            crate::steps::output_format(ctx),
+            crate::workspace::scratch_path(ctx, OVERLAY_FILE_NAME),
+            ui,
+        )?;
+
+        let vm = VmResources::from_context(ctx)?;
+        let slot = crate::slots::take_vm_slot(ctx, ui)?;
+        memory::check_before_launch(vm.memory_mib, ctx, ui)?;
+
+        let firmware_args = super::firmware::test_qemu_args(ctx)?;
+        let tpm = super::tpm::Swtpm::start(ctx, TPM_NAME, ui)?;
+        let tpm_args = super::tpm::qemu_args(ctx, TPM_NAME);
+        let disk_arg = format!(
+            "if=none,id=drivec,file={},format=qcow2,cache=writeback",
+            overlay.path
+        );
+        let agent_arg =
+            format!("socket,id=qga0,path={socket},server=on,wait=off");
+        let serial_arg = format!(
+            "socket,id=serial0,path={serial_socket},server=on,wait=off"
+        );
+        let vm_args = vm.qemu_args();
+        let disk_args = vm.disk_args("drivec");
+        let nic_args = vm.nic_args(forwards);
+
+        let mut args = vec!["-nodefaults"];
+        args.extend_from_slice(super::accel::qemu_args(ctx));
+        args.extend(vm_args.iter().map(String::as_str));
+        args.extend(firmware_args.iter().map(String::as_str));
+        args.extend(tpm_args.iter().map(String::as_str));
+        args.extend_from_slice(&["-rtc", "base=localtime"]);
+        args.extend(nic_args.iter().map(String::as_str));
+        args.extend(disk_args.iter().map(String::as_str));
+        args.extend_from_slice(&[
+            "-drive",
+            &disk_arg,
+            "-chardev",
+            &agent_arg,
+            "-device",
+            "virtio-serial",
+            "-device",
+            "virtserialport,chardev=qga0,name=org.qemu.guest_agent.0",
+            "-chardev",
+            &serial_arg,
+            "-serial",
+            "chardev:serial0",
+            "-display",
+            "none",
+        ]);
+
+        ui.set_substep(&format!(
+            "booting test VM with {} vCPUs and {} MiB RAM",
+            vm.cpus, vm.memory_mib
+        ));
+        let qemu = qemu_program(crate::steps::architecture(ctx));
+        let mut cmd = Command::new(qemu);
+        cmd.args(&args)
+            .stdout::<std::fs::File>(ui.child_stdout(qemu)?)
+            .stderr::<std::fs::File>(ui.child_stderr(qemu)?)
+            .stdin(Stdio::null());
+        ui.command_started(&cmd);
+        let _span = command_span(&cmd);
+        let qemu = cmd.spawn().context("launching test VM")?;
+        let _cancel = crate::interrupt::kill_on_cancel(&qemu);
+        Ok(Self {
+            qemu,
+            socket,
+            serial_socket,
+            _overlay: overlay,
+            _tpm: tpm,
+            _cancel,
+            _slot: slot,
+        })
+    }
+
+    /// Returns an error if the VM has exited.
+    fn check_running(&mut self) -> Result<()> {
+        match self.qemu.try_wait()? {
+            Some(status) => anyhow::bail!(
+                "test VM stopped unexpectedly: {}",
+                describe_vm_exit("QEMU", status)
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Connects to the socket QEMU serves at `socket`, retrying until QEMU
+    /// has created it or `deadline` passes.
+    fn connect(
+        &mut self,
+        socket: &Utf8Path,
+        deadline: Instant,
+    ) -> Result<UnixStream> {
+        loop {
+            self.check_running()?;
+            match UnixStream::connect(socket) {
+                Ok(stream) => return Ok(stream),
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("connecting to socket '{socket}'")
+                    })
+                }
+            }
+        }
+    }
+
+    /// Connects to the guest agent and waits until it answers and reports
+    /// that Windows has finished setting up, or until `deadline` passes.
+    fn wait_for_agent(&mut self, deadline: Instant) -> Result<GuestAgent> {
+        let socket = self.socket.clone();
+        let stream = self
+            .connect(&socket, deadline)
+            .context("connecting to the guest agent")?;
+
+        let mut agent = GuestAgent::new(stream)?;
+        let mut last_error = None;
+        while Instant::now() < deadline {
+            self.check_running()?;
+            let ready = agent.sync().and_then(|()| {
+                let result = agent.powershell(
+                    SETUP_COMPLETE_SCRIPT,
+                    deadline.saturating_duration_since(Instant::now()),
+                )?;
+                Ok(result.exit_code == 0 && result.stdout.trim() == "True")
+            });
+
+            match ready {
+                Ok(true) => return Ok(agent),
+                Ok(false) => {
+                    last_error = Some("Windows is still setting up".to_string())
+                }
+                Err(e) => {
+                    trace::trace!(
+                        "guest agent not ready",
+                        error = format!("{e:#}")
+                    );
+                    last_error = Some(format!("{e:#}"));
+                }
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        anyhow::bail!(
+            "timed out waiting for the test VM's guest agent{}; check that \
+            the image installed the QEMU guest agent",
+            last_error
+                .map(|e| format!(" (last error: {e})"))
+                .unwrap_or_default()
+        )
+    }
+
+    /// Copies the VM's serial output to the build's serial log until it
+    /// includes the Special Administration Console's banner, or until
+    /// `deadline` passes.
+    fn wait_for_serial_banner(
+        &mut self,
+        deadline: Instant,
+        ui: &dyn Ui,
+    ) -> Result<()> {
+        let socket = self.serial_socket.clone();
+        let mut stream = self
+            .connect(&socket, deadline)
+            .context("connecting to the serial port")?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut log = ui.serial_log("test VM")?;
+
+        // Keep enough of the output to find a banner split across reads.
+        let mut recent = Vec::new();
+        let mut buf = [0u8; 4096];
+        while Instant::now() < deadline {
+            self.check_running()?;
+            let n = match stream.read(&mut buf) {
+                Ok(0) => anyhow::bail!("the serial port closed"),
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e).context("reading the serial port"),
+            };
+
+            log.write_all(&buf[..n])?;
+            recent.extend_from_slice(&buf[..n]);
+            if contains(&recent, SAC_BANNER) {
+                return Ok(());
+            }
+            let keep = recent.len().min(SAC_BANNER.len());
+            recent.drain(..recent.len() - keep);
+        }
+
+        anyhow::bail!(
+            "timed out waiting for Windows to start its serial console; if \
+            the image was built without the guest setup script, it may not \
+            have one (check with 'bcdedit /ems')"
+        )
+    }
+
+    /// Waits until something in the guest accepts connections on the port
+    /// forwarded to `host_port`, or until `deadline` passes.
+    fn wait_for_port(
+        &mut self,
+        host_port: u16,
+        deadline: Instant,
+    ) -> Result<()> {
+        loop {
+            self.check_running()?;
+            match check_tcp_port(host_port) {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(e).context("timed out waiting for a connection")
+                }
+                Err(_) => std::thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
+
+/// Returns whether `haystack` contains `needle`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+impl Drop for TestVm {
+    fn drop(&mut self) {
+        trace::debug!("destroying test VM");
+        let _ = self.qemu.kill();
+        let _ = self.qemu.wait();
+        let _ = std::fs::remove_file(&self.socket);
+        let _ = std::fs::remove_file(&self.serial_socket);
+    }
+}
+
+/// The result of running a command in the guest.
+pub(super) struct ExecResult {
+    pub(super) exit_code: i64,
+    pub(super) stdout: String,
+    pub(super) stderr: String,
+}
+
+/// A connection to a QEMU guest agent.
+struct GuestAgent {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+    next_id: i64,
+}
+
+impl GuestAgent {
+    fn new(stream: UnixStream) -> Result<Self> {
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self { writer: stream, reader, next_id: 1 })
+    }
+
+    /// Reads a line from the agent, waiting at most `timeout`.
+    fn read_line(&mut self, timeout: Duration) -> Result<String> {
+        self.reader
+            .get_ref()
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => anyhow::bail!("guest agent socket closed"),
+            Ok(_) => Ok(line),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                anyhow::bail!("guest agent didn't reply in time")
+            }
+            Err(e) => Err(e).context("reading from guest agent"),
+        }
+    }
+
+    /// Discards any replies to earlier requests and checks that the agent is
+    /// responding. If the guest rebooted or an earlier request timed out,
+    /// the connection may hold stale replies, or the agent may have half of
+    /// an old request buffered, so every exchange with the agent starts with
+    /// one of these.
+    fn sync(&mut self) -> Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // A 0xFF byte makes the agent discard any partial request.
+        self.writer.write_all(&[0xff])?;
+        let request = Json::object()
+            .with("execute", "guest-sync")
+            .with("arguments", Json::object().with("id", id));
+        writeln!(self.writer, "{request}")?;
+
+        let deadline = Instant::now() + AGENT_REPLY_TIMEOUT;
+        loop {
+            let line = self.read_line(
+                deadline.saturating_duration_since(Instant::now()),
+            )?;
+            let reply = Json::parse(line.trim_start_matches('\u{ff}').trim());
+            if let Ok(reply) = reply {
+                if reply.get("return").and_then(Json::as_i64) == Some(id) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sends a request and returns the contents of its reply.
+    fn execute(&mut self, command: &str, arguments: Json) -> Result<Json> {
+        let request = Json::object()
+            .with("execute", command)
+            .with("arguments", arguments);
+        writeln!(self.writer, "{request}")?;
+
+        let line = self.read_line(AGENT_REPLY_TIMEOUT)?;
+        let reply = Json::parse(&line)
+            .with_context(|| format!("parsing guest agent reply '{line}'"))?;
+        if let Some(error) = reply.get("error") {
+            anyhow::bail!(
+                "guest agent command {command} failed: {}",
+                error
+                    .get("desc")
+                    .and_then(Json::as_str)
+                    .unwrap_or("unknown error")
+            );
+        }
+
+        reply.get("return").cloned().ok_or_else(|| {
+            anyhow::anyhow!("unexpected guest agent reply '{}'", line.trim())
+        })
+    }
+
+    /// Runs `script` with PowerShell in the guest and waits up to `timeout`
+    /// for it to exit.
+    fn powershell(
+        &mut self,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<ExecResult> {
+        // Pass the script encoded so that it needn't survive Windows
+        // command-line quoting.
+        let utf16: Vec<u8> =
+            script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let args = vec![
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-EncodedCommand",
+        ];
+        let mut args: Vec<Json> = args.into_iter().map(Json::from).collect();
+        args.push(Json::from(encode_base64(&utf16)));
+
+        let started = self.execute(
+            "guest-exec",
+            Json::object()
+                .with("path", POWERSHELL)
+                .with("arg", Json::Array(args))
+                .with("capture-output", true),
+        )?;
+        let pid = started
+            .get("pid")
+            .and_then(Json::as_i64)
+            .ok_or_else(|| anyhow::anyhow!("guest-exec didn't return a pid"))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = self.execute(
+                "guest-exec-status",
+                Json::object().with("pid", pid),
+            )?;
+            if status.get("exited").and_then(Json::as_bool) == Some(true) {
+                let output = |key: &str| -> Result<String> {
+                    let data = status.get(key).and_then(Json::as_str);
+                    let bytes = decode_base64(data.unwrap_or_default())?;
+                    Ok(String::from_utf8_lossy(&bytes).into_owned())
+                };
+
+                return Ok(ExecResult {
+                    exit_code: status
+                        .get("exitcode")
+                        .and_then(Json::as_i64)
+                        .unwrap_or(-1),
+                    stdout: output("out-data")?,
+                    stderr: output("err-data")?,
+                });
+            }
+
+            if Instant::now() >= deadline {
+                anyhow::bail!("timed out after {} seconds", timeout.as_secs());
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// The outcome of one test.
+struct TestOutcome {
+    passed: bool,
+
+    /// Why the test failed, if it did.
+    failure: Option<String>,
+    exit_code: Option<i64>,
+    stdout: Option<String>,
+    elapsed: Duration,
+}
+
+impl TestOutcome {
+    fn to_json(&self, test: &ImageTest) -> Json {
+        Json::object()
+            .with("name", test.name.as_str())
+            .with("required", test.required)
+            .with("passed", self.passed)
+            .with("failure", self.failure.clone())
+            .with("exit_code", self.exit_code)
+            .with(
+                "stdout",
+                self.stdout
+                    .as_ref()
+                    .map(|s| truncate(s, REPORTED_OUTPUT_LIMIT)),
+            )
+            .with("elapsed_ms", self.elapsed.as_millis() as u64)
+    }
+}
+
+/// The ways to run PowerShell in the test VM.
+struct Shells {
+    /// The guest agent, if anything uses it.
+    agent: Option<GuestAgent>,
+
+    /// The WinRM account, if any tests use it.
+    winrm: Option<WinrmClient>,
+}
+
+impl Shells {
+    /// Runs `script` through `via`, waiting up to `timeout` for it to exit.
+    fn powershell(
+        &mut self,
+        via: Transport,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<ExecResult> {
+        match via {
+            Transport::Agent => {
+                let agent = self.agent.as_mut().unwrap();
+                agent.sync().and_then(|()| agent.powershell(script, timeout))
+            }
+            Transport::Winrm => {
+                self.winrm.as_ref().unwrap().powershell(script, timeout)
+            }
+        }
+    }
+}
+
+/// Runs a PowerShell test through `via`.
+fn run_powershell_test(
+    shells: &mut Shells,
+    via: Transport,
+    timeout: Duration,
+    script: &str,
+    expect_output: Option<&str>,
+    expect_exit: i64,
+) -> TestOutcome {
+    let started = Instant::now();
+    let result = shells.powershell(via, script, timeout);
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            return TestOutcome {
+                passed: false,
+                failure: Some(format!("{e:#}")),
+                exit_code: None,
+                stdout: None,
+                elapsed: started.elapsed(),
+            }
+        }
+    };
+
+    let failure = if result.exit_code != expect_exit {
+        let stderr = result.stderr.trim();
+        Some(format!(
+            "exited with status {} instead of {expect_exit}{}",
+            result.exit_code,
+            if stderr.is_empty() {
+                String::new()
+            } else {
+                format!(": {stderr}")
+            }
+        ))
+    } else {
+        match expect_output {
+            Some(expected) if result.stdout.trim() != expected.trim() => {
+                Some(format!(
+                    "printed '{}' instead of '{}'",
+                    result.stdout.trim(),
+                    expected.trim()
+                ))
+            }
+            _ => None,
+        }
+    };
+
+    TestOutcome {
+        passed: failure.is_none(),
+        failure,
+        exit_code: Some(result.exit_code),
+        stdout: Some(result.stdout),
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Checks that something in the guest accepts connections on the port
+/// forwarded to `host_port`.
+///
+/// QEMU's user-mode network stack accepts connections to forwarded ports
+/// itself and then connects to the guest, closing the host's connection if
+/// the guest refuses. A connection that's still open after a short wait (or
+/// on which the guest has sent data) thus means the guest is listening.
+fn check_tcp_port(host_port: u16) -> Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, host_port));
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    match stream.read(&mut [0u8; 1]) {
+        Ok(0) => anyhow::bail!("the guest closed the connection"),
+        Ok(_) => Ok(()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Runs a TCP port test, retrying until `timeout` passes in case the service
+/// that listens on the port is still starting.
+fn run_tcp_port_test(timeout: Duration, host_port: u16) -> TestOutcome {
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let failure = loop {
+        match check_tcp_port(host_port) {
+            Ok(()) => break None,
+            Err(e) if Instant::now() >= deadline => {
+                break Some(format!("nothing accepted connections: {e:#}"))
+            }
+            Err(_) => std::thread::sleep(POLL_INTERVAL),
+        }
+    };
+
+    TestOutcome {
+        passed: failure.is_none(),
+        failure,
+        exit_code: None,
+        stdout: None,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Waits until WinRM in the test VM runs a trivial command, or until
+/// `deadline` passes. WinRM starts a little after Windows finishes setting
+/// up.
+fn wait_for_winrm(
+    vm: &mut TestVm,
+    winrm: &WinrmClient,
+    deadline: Instant,
+) -> Result<()> {
+    loop {
+        vm.check_running()?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match winrm.powershell("$true", remaining.min(AGENT_REPLY_TIMEOUT * 6))
+        {
+            Ok(result) if result.exit_code == 0 => return Ok(()),
+            Ok(result) if Instant::now() >= deadline => anyhow::bail!(
+                "timed out waiting for WinRM (last exit code {})",
+                result.exit_code
+            ),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(e).context("timed out waiting for WinRM")
+            }
+            _ => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+/// Runs the final commands in `tests` through `shells`, recording each one's
+/// outcome in the build report. Failures are reported as warnings.
+fn run_final_commands(
+    tests: &ImageTests,
+    shells: &mut Shells,
+    timeout: Duration,
+    ui: &dyn Ui,
+) {
+    let mut results = Vec::new();
+    for (i, command) in tests.final_commands.iter().enumerate() {
+        ui.set_substep(&format!("running final command {}", i + 1));
+        let started = Instant::now();
+        let result =
+            shells.powershell(tests.final_commands_via, command, timeout);
+        let failure = match &result {
+            Ok(result) if result.exit_code == 0 => None,
+            Ok(result) => {
+                Some(format!("exited with status {}", result.exit_code))
+            }
+            Err(e) => Some(format!("{e:#}")),
+        };
+        if let Some(failure) = &failure {
+            ui.warn(&format!("final command {} failed: {failure}", i + 1));
+        }
+
+        let result = result.ok();
+        results.push(
+            Json::object()
+                .with("command", command.as_str())
+                .with("failure", failure)
+                .with("exit_code", result.as_ref().map(|r| r.exit_code))
+                .with(
+                    "stdout",
+                    result.map(|r| truncate(&r.stdout, REPORTED_OUTPUT_LIMIT)),
+                )
+                .with("elapsed_ms", started.elapsed().as_millis() as u64),
+        );
+    }
+    ui.record_metric("final_commands", Json::Array(results));
+}
+
+/// Returns at most the first `limit` bytes of `s`, cut at a character
+/// boundary.
+fn truncate(s: &str, limit: usize) -> String {
+    let mut end = s.len().min(limit);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_string()
+}
+
+/// Picks an unused TCP port on the host's loopback interface.
+pub(super) fn unused_host_port() -> Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .context("finding an unused host port to forward")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Boots the output image in a test VM, checks that it boots if `tests` has a
+/// boot check, and runs `tests` against it. Records the boot check's and
+/// each test's outcome in the build report and fails if the image doesn't
+/// boot or any required test fails. Does nothing if nothing's configured.
+pub(super) fn run_image_tests(
+    tests: &ImageTests,
+    ctx: &mut Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    if tests.is_empty() {
+        return Ok(());
+    }
+
+    let mut forwards: Vec<(u16, u16)> = Vec::new();
+    for port in tests.tcp_ports() {
+        forwards.push((unused_host_port()?, port));
+    }
+    let host_port = |port: u16| {
+        forwards.iter().find(|&&(_, guest)| guest == port).unwrap().0
+    };
+
+    // Give the tests longer if the VM is emulated.
+    let factor = u64::from(super::accel::timeout_factor(ctx));
+    let timeout = |secs: u64| Duration::from_secs(secs.saturating_mul(factor));
+
+    // Read the WinRM password before booting the VM, in case it has to be
+    // asked for.
+    let winrm = if tests.uses_winrm() {
+        let source = match &tests.winrm.password {
+            Some(source) => source.clone(),
+            None => ctx
+                .get_var("admin_password")
+                .context("WinRM tests need a password")?
+                .parse()?,
+        };
+        let password = source.resolve("WinRM password", ui)?;
+        Some(WinrmClient::new(
+            host_port(tests.winrm.port),
+            &tests.winrm.username,
+            password,
+        ))
+    } else {
+        None
+    };
+
+    let mut vm = TestVm::launch(ctx, &forwards, ui)?;
+    let booting = Instant::now();
+    let deadline = booting + timeout(tests.boot_timeout_secs);
+
+    let mut agent = None;
+    if let Some(check) = tests.boot_check {
+        ui.set_substep(&format!("waiting for the test VM to boot ({check})"));
+        let booted = match check {
+            BootCheck::Serial => vm.wait_for_serial_banner(deadline, ui),
+            BootCheck::Agent => {
+                vm.wait_for_agent(deadline).map(|a| agent = Some(a))
+            }
+            BootCheck::TcpPort(port) => {
+                vm.wait_for_port(host_port(port), deadline)
+            }
+        };
+        trace::info!("boot test finished", booted = booted.is_ok());
+        ui.record_metric(
+            "boot_test",
+            Json::object()
+                .with("check", check.to_string())
+                .with("booted", booted.is_ok())
+                .with("elapsed_ms", booting.elapsed().as_millis() as u64),
+        );
+        booted.context("the output image didn't boot")?;
+    }
+
+    if !tests.needs_agent() && !tests.uses_winrm() {
+        return Ok(());
+    }
+
+    let agent = match agent {
+        Some(agent) => Some(agent),
+        None if tests.needs_agent() => {
+            ui.set_substep("waiting for the test VM's guest agent");
+            Some(vm.wait_for_agent(deadline)?)
+        }
+        None => None,
+    };
+    if let Some(winrm) = &winrm {
+        ui.set_substep("waiting for WinRM in the test VM");
+        wait_for_winrm(&mut vm, winrm, deadline)?;
+    }
+    let mut shells = Shells { agent, winrm };
+
+    let mut results = Vec::new();
+    let mut failed_required = Vec::new();
+    for test in &tests.tests {
+        ui.set_substep(&format!("running test {}", test.name));
+        let _span = trace::span!(
+            trace::Level::Info,
+            "image test",
+            name = test.name.as_str()
+        );
+        let outcome = match &test.check {
+            TestCheck::PowerShell {
+                script,
+                expect_output,
+                expect_exit,
+                via,
+            } => run_powershell_test(
+                &mut shells,
+                *via,
+                timeout(test.timeout_secs),
+                script,
+                expect_output.as_deref(),
+                *expect_exit,
+            ),
+            TestCheck::TcpPort(port) => {
+                run_tcp_port_test(timeout(test.timeout_secs), host_port(*port))
+            }
+        };
+
+        trace::info!(
+            "image test finished",
+            passed = outcome.passed,
+            failure = outcome.failure.clone()
+        );
+        if let Some(failure) = &outcome.failure {
+            if test.required {
+                failed_required.push(format!("{} ({failure})", test.name));
+            } else {
+                ui.warn(&format!(
+                    "optional test {} failed: {failure}",
+                    test.name
+                ));
+            }
+        }
+
+        results.push(outcome.to_json(test));
+    }
+
+    if !results.is_empty() {
+        ui.record_metric("image_tests", Json::Array(results));
+    }
+    if !tests.final_commands.is_empty() {
+        run_final_commands(
+            tests,
+            &mut shells,
+            timeout(DEFAULT_TEST_TIMEOUT_SECS),
+            ui,
+        );
+    }
+    drop(shells);
+    drop(vm);
+
+    if !failed_required.is_empty() {
+        anyhow::bail!(
+            "{} required test(s) failed: {}",
+            failed_required.len(),
+            failed_required.join("; ")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks whether QEMU will be able to use KVM to accelerate the installation
+//! VM.
+
+use std::os::unix::fs::MetadataExt;
+
+use crate::kvm::{KvmFacts, KvmProbe, KVM_DEVICE};
+
+/// Probes the host for a usable KVM device.
+pub(super) fn probe() -> KvmProbe {
+    let (open, group) = match std::fs::metadata(KVM_DEVICE) {
+        Ok(metadata) => (
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(KVM_DEVICE)
+                .map(drop),
+            (metadata.mode() & 0o060 == 0o060)
+                .then(|| group_name(metadata.gid()))
+                .flatten(),
+        ),
+        Err(e) => (Err(e), None),
+    };
+
+    // Only x86 CPUs advertise virtualization support in their flags.
+    let cpu_supports_virtualization = (std::env::consts::ARCH == "x86_64")
+        .then(|| {
+            std::fs::read_to_string("/proc/cpuinfo").is_ok_and(|cpuinfo| {
+                crate::kvm::cpu_supports_virtualization(&cpuinfo)
+            })
+        });
+
+    KvmProbe::resolve(KvmFacts { open, group, cpu_supports_virtualization })
+}
+
+/// Looks up the name of the group with ID `gid` in `/etc/group`.
+fn group_name(gid: u32) -> Option<String> {
+    let groups = std::fs::read_to_string("/etc/group").ok()?;
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let _password = fields.next()?;
+        let id: u32 = fields.next()?.parse().ok()?;
+        (id == gid).then(|| name.to_string())
+    })
+}
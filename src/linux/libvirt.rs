@@ -0,0 +1,538 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the installation VM as a transient libvirt domain instead of
+//! launching QEMU directly.
+//!
+//! Some hosts only let QEMU run under libvirt, which labels the files a VM
+//! uses for SELinux, changes their ownership to its QEMU user, and puts the
+//! VM in a cgroup. For libvirt to do that, the VM's disks, firmware, and
+//! devices have to be described in the domain's XML rather than passed to
+//! QEMU as command-line arguments, so this module describes the same VM the
+//! QEMU script builds: the same machine type, disk controller, CD-ROM drives,
+//! network adapter, firmware, and TPM.
+//!
+//! libvirt owns the QEMU process, so wimsy talks to the VM through `virsh`
+//! and a TCP socket on the host's loopback interface that carries the guest's
+//! serial port. The domain is transient, so libvirt forgets it once the guest
+//! powers off.
+
+use std::{
+    net::{Ipv4Addr, TcpStream},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+use crate::{
+    app::{DiskController, MachineType, NicModel},
+    autounattend::Architecture,
+    memory,
+    monitor::{
+        DiskSpaceLimits, DiskSpaceMonitor, ProgressLimits, SerialWatcher, Vm,
+        Watchdog,
+    },
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+use super::create_guest_disk_image::VmResources;
+
+/// The name of the installation VM's domain.
+const DOMAIN_NAME: &str = "wimsy-install";
+
+/// The name of the file in the work directory that holds the domain's XML.
+const DOMAIN_XML_NAME: &str = "install-domain.xml";
+
+/// How long to wait for QEMU to start listening for serial connections.
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to ask libvirt whether the domain is still running.
+const DOMSTATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns a `virsh` command that connects to the libvirt URI named by the
+/// `libvirt_uri` context variable, or to virsh's default URI if it's unset.
+fn virsh(ctx: &Context) -> Command {
+    virsh_for(ctx.get_var("libvirt_uri"))
+}
+
+fn virsh_for(uri: Option<&str>) -> Command {
+    let mut cmd = Command::new("virsh");
+    if let Some(uri) = uri {
+        cmd.args(["-c", uri]);
+    }
+    cmd
+}
+
+fn domain_xml_path(ctx: &Context) -> Utf8PathBuf {
+    crate::workspace::scratch_path(ctx, DOMAIN_XML_NAME)
+}
+
+fn create_command(ctx: &Context) -> Command {
+    let mut cmd = virsh(ctx);
+    cmd.args(["create", domain_xml_path(ctx).as_str()]);
+    cmd
+}
+
+fn destroy_command(uri: Option<&str>) -> Command {
+    let mut cmd = virsh_for(uri);
+    cmd.args(["destroy", DOMAIN_NAME]);
+    cmd
+}
+
+fn send_enter_command(ctx: &Context) -> Command {
+    let mut cmd = virsh(ctx);
+    cmd.args(["send-key", DOMAIN_NAME, "KEY_ENTER"]);
+    cmd
+}
+
+/// Writes the XML for a domain, one element at a time.
+struct DomainWriter<'a> {
+    w: EventWriter<&'a mut Vec<u8>>,
+}
+
+impl DomainWriter<'_> {
+    fn open(&mut self, name: &str, attrs: &[(&str, &str)]) -> Result<()> {
+        let mut element = XmlEvent::start_element(name);
+        for (attr, value) in attrs {
+            element = element.attr(*attr, value);
+        }
+        self.w.write(element)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.w.write(XmlEvent::end_element())?;
+        Ok(())
+    }
+
+    /// Writes an element with attributes `attrs` and, unless it's empty,
+    /// text `text`.
+    fn leaf(
+        &mut self,
+        name: &str,
+        attrs: &[(&str, &str)],
+        text: &str,
+    ) -> Result<()> {
+        self.open(name, attrs)?;
+        if !text.is_empty() {
+            self.w.write(XmlEvent::characters(text))?;
+        }
+        self.close()
+    }
+}
+
+/// Hands out libvirt's target device names (`sda`, `sdb`, and so on), which
+/// must be unique among the disks that share a prefix.
+#[derive(Default)]
+struct TargetNames(Vec<(&'static str, u8)>);
+
+impl TargetNames {
+    fn next(&mut self, prefix: &'static str) -> String {
+        let index = match self.0.iter_mut().find(|(p, _)| *p == prefix) {
+            Some((_, next)) => next,
+            None => {
+                self.0.push((prefix, 0));
+                &mut self.0.last_mut().unwrap().1
+            }
+        };
+        let name = format!("{prefix}{}", (b'a' + *index) as char);
+        *index += 1;
+        name
+    }
+}
+
+/// Returns the XML for a domain that installs Windows to the output image in
+/// a VM with the resources in `vm`, whose serial port listens for
+/// connections on `serial_port` on the host's loopback interface.
+fn domain_xml(
+    ctx: &Context,
+    vm: &VmResources,
+    serial_port: u16,
+) -> Result<String> {
+    let arch = crate::steps::architecture(ctx);
+    let kvm = ctx.get_var("accel") == Some("kvm");
+    let secure_boot = ctx.get_var("secure_boot").is_some();
+    let reproducible = crate::reproducible::enabled(ctx);
+    // Hyper-V enlightenments, like in the QEMU script, are x86-only and need
+    // KVM.
+    let enlightened = kvm && arch == Architecture::X86_64;
+
+    let mut xml = Vec::new();
+    let mut w = DomainWriter {
+        w: EmitterConfig::new().perform_indent(true).create_writer(&mut xml),
+    };
+    w.open("domain", &[("type", if kvm { "kvm" } else { "qemu" })])?;
+    w.leaf("name", &[], DOMAIN_NAME)?;
+    if reproducible {
+        w.leaf("uuid", &[], &crate::reproducible::vm_uuid())?;
+    }
+    w.leaf("memory", &[("unit", "MiB")], &vm.memory_mib.to_string())?;
+    w.leaf("vcpu", &[], &vm.cpus.to_string())?;
+
+    // As in the QEMU script, pass the host's CPU model through under KVM,
+    // and emulate the most capable CPU TCG offers otherwise.
+    let cores = vm.cpus.to_string();
+    w.open(
+        "cpu",
+        &[("mode", if kvm { "host-passthrough" } else { "maximum" })],
+    )?;
+    w.leaf(
+        "topology",
+        &[("sockets", "1"), ("dies", "1"), ("cores", &cores), ("threads", "1")],
+        "",
+    )?;
+    w.close()?;
+
+    let (code, vars) = super::firmware::install_images(ctx);
+    let mut loader = vec![("readonly", "yes"), ("type", "pflash")];
+    if secure_boot {
+        loader.push(("secure", "yes"));
+    }
+    if vars.is_none() {
+        // Keep UEFI variables in memory, as the QEMU script does, instead of
+        // having libvirt create a varstore for the domain.
+        loader.push(("stateless", "yes"));
+    }
+    w.open("os", &[])?;
+    w.leaf(
+        "type",
+        &[("arch", &arch.to_string()), ("machine", &vm.machine.to_string())],
+        "hvm",
+    )?;
+    w.leaf("loader", &loader, code)?;
+    if let Some(vars) = &vars {
+        w.leaf("nvram", &[], vars.as_str())?;
+    }
+    w.close()?;
+
+    w.open("features", &[])?;
+    w.leaf("acpi", &[], "")?;
+    if arch == Architecture::X86_64 {
+        w.leaf("apic", &[], "")?;
+    }
+    if enlightened {
+        // The equivalent of the QEMU script's hv_relaxed, hv_vapic,
+        // hv_spinlocks, and kvm=off.
+        w.open("hyperv", &[("mode", "custom")])?;
+        w.leaf("relaxed", &[("state", "on")], "")?;
+        w.leaf("vapic", &[("state", "on")], "")?;
+        w.leaf("spinlocks", &[("state", "on"), ("retries", "8191")], "")?;
+        w.close()?;
+        w.open("kvm", &[])?;
+        w.leaf("hidden", &[("state", "on")], "")?;
+        w.close()?;
+    }
+    if secure_boot && arch == Architecture::X86_64 {
+        w.leaf("smm", &[("state", "on")], "")?;
+    }
+    if vm.machine == MachineType::Virt {
+        w.leaf("gic", &[("version", "max")], "")?;
+    }
+    w.close()?;
+
+    w.open("clock", &[("offset", "localtime")])?;
+    if enlightened {
+        w.leaf("timer", &[("name", "hypervclock"), ("present", "yes")], "")?;
+    }
+    w.close()?;
+    w.leaf("on_poweroff", &[], "destroy")?;
+    w.leaf("on_reboot", &[], "restart")?;
+    // Keep a crashed domain around long enough for wimsy to see that it
+    // crashed instead of powering off.
+    w.leaf("on_crash", &[], "preserve")?;
+
+    w.open("devices", &[])?;
+    let mut names = TargetNames::default();
+    let (disk_bus, disk_dev) = match vm.disk_controller {
+        DiskController::Nvme => ("nvme", "nvme0n1".to_string()),
+        DiskController::VirtioBlk => ("virtio", names.next("vd")),
+        DiskController::VirtioScsi => {
+            w.leaf(
+                "controller",
+                &[("type", "scsi"), ("model", "virtio-scsi")],
+                "",
+            )?;
+            ("scsi", names.next("sd"))
+        }
+        DiskController::Ahci => ("sata", names.next("sd")),
+    };
+    w.open("disk", &[("type", "file"), ("device", "disk")])?;
+    w.leaf(
+        "driver",
+        &[
+            ("name", "qemu"),
+            ("type", crate::steps::output_format(ctx)),
+            ("cache", "writeback"),
+            ("discard", "unmap"),
+        ],
+        "",
+    )?;
+    w.leaf("source", &[("file", ctx.get_var("output_image").unwrap())], "")?;
+    w.leaf("target", &[("dev", &disk_dev), ("bus", disk_bus)], "")?;
+    w.leaf("serial", &[], "01de01de")?;
+    // 512-byte sectors, like the QEMU script and Oxide's disks.
+    w.leaf(
+        "blockio",
+        &[("logical_block_size", "512"), ("physical_block_size", "512")],
+        "",
+    )?;
+    w.leaf("boot", &[("order", "1")], "")?;
+    w.close()?;
+
+    // The same buses the QEMU script puts its CD-ROM drives on.
+    let (cd_bus, cd_prefix) = match vm.machine {
+        MachineType::Pc => ("ide", "hd"),
+        MachineType::Q35 => ("sata", "sd"),
+        MachineType::Virt => ("usb", "sd"),
+    };
+    for (var, boot) in
+        [("windows_iso", true), ("virtio_iso", false), ("unattend_iso", false)]
+    {
+        w.open("disk", &[("type", "file"), ("device", "cdrom")])?;
+        w.leaf("driver", &[("name", "qemu"), ("type", "raw")], "")?;
+        w.leaf("source", &[("file", ctx.get_var(var).unwrap())], "")?;
+        w.leaf(
+            "target",
+            &[("dev", &names.next(cd_prefix)), ("bus", cd_bus)],
+            "",
+        )?;
+        w.leaf("readonly", &[], "")?;
+        if boot {
+            w.leaf("boot", &[("order", "2")], "")?;
+        }
+        w.close()?;
+    }
+
+    let nic = match vm.nic_model {
+        NicModel::E1000e => Some("e1000e"),
+        NicModel::VirtioNet => Some("virtio"),
+        NicModel::None => None,
+    };
+    if let Some(model) = nic {
+        w.open("interface", &[("type", "user")])?;
+        w.leaf("model", &[("type", model)], "")?;
+        if reproducible {
+            w.leaf("mac", &[("address", crate::reproducible::VM_MAC)], "")?;
+        }
+        w.close()?;
+    }
+
+    // Like the QEMU script's, the virt board needs a USB keyboard to get past
+    // the prompt to boot from CD.
+    if vm.machine == MachineType::Virt {
+        w.leaf("controller", &[("type", "usb"), ("model", "qemu-xhci")], "")?;
+        w.leaf("input", &[("type", "keyboard"), ("bus", "usb")], "")?;
+    }
+
+    let port = serial_port.to_string();
+    w.open("serial", &[("type", "tcp")])?;
+    w.leaf(
+        "source",
+        &[("mode", "bind"), ("host", "127.0.0.1"), ("service", &port)],
+        "",
+    )?;
+    w.leaf("protocol", &[("type", "raw")], "")?;
+    w.close()?;
+
+    if ctx.get_var("tpm").is_some() {
+        // libvirt starts and stops swtpm itself.
+        w.open("tpm", &[("model", "tpm-crb")])?;
+        w.leaf("backend", &[("type", "emulator"), ("version", "2.0")], "")?;
+        w.close()?;
+    }
+
+    if ctx.get_var("vga_console").is_some() {
+        let video = match vm.machine {
+            MachineType::Virt => "ramfb",
+            _ => "vga",
+        };
+        w.leaf(
+            "graphics",
+            &[("type", "vnc"), ("listen", "127.0.0.1"), ("autoport", "yes")],
+            "",
+        )?;
+        w.open("video", &[])?;
+        w.leaf("model", &[("type", video)], "")?;
+        w.close()?;
+    }
+
+    w.leaf("memballoon", &[("model", "none")], "")?;
+    w.close()?;
+    w.close()?;
+
+    drop(w);
+    Ok(String::from_utf8(xml).expect("the XML writer only writes UTF-8"))
+}
+
+/// How the installation VM's domain ended.
+enum DomainExit {
+    PoweredOff,
+    Crashed,
+}
+
+/// The installation VM's running domain. Dropping it destroys the domain if
+/// it's still running.
+struct Domain {
+    uri: Option<String>,
+    running: bool,
+    next_check: Instant,
+}
+
+impl Domain {
+    fn destroy(&mut self) {
+        trace::debug!("destroying the installation VM's domain");
+        let _ = destroy_command(self.uri.as_deref())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        self.running = false;
+    }
+}
+
+impl Vm for Domain {
+    type Exit = DomainExit;
+
+    fn try_wait(&mut self) -> Result<Option<DomainExit>> {
+        if Instant::now() < self.next_check {
+            return Ok(None);
+        }
+
+        self.next_check = Instant::now() + DOMSTATE_INTERVAL;
+        let output = virsh_for(self.uri.as_deref())
+            .args(["domstate", DOMAIN_NAME])
+            .output()
+            .context("running virsh domstate")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // libvirt forgets a transient domain as soon as it shuts off.
+            if stderr.contains("failed to get domain") {
+                self.running = false;
+                return Ok(Some(DomainExit::PoweredOff));
+            }
+
+            anyhow::bail!("virsh domstate failed: {}", stderr.trim());
+        }
+
+        Ok(match String::from_utf8_lossy(&output.stdout).trim() {
+            "shut off" => {
+                self.running = false;
+                Some(DomainExit::PoweredOff)
+            }
+            "crashed" => Some(DomainExit::Crashed),
+            _ => None,
+        })
+    }
+
+    fn kill(&mut self) {
+        self.destroy();
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        if self.running {
+            self.destroy();
+        }
+    }
+}
+
+/// Connects to the guest's serial port, waiting for QEMU to start listening.
+fn connect_serial(port: u16) -> Result<TcpStream> {
+    let deadline = Instant::now() + SERIAL_TIMEOUT;
+    loop {
+        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if Instant::now() > deadline => {
+                return Err(e).context(format!(
+                    "connecting to the installation VM's serial port on \
+                    127.0.0.1:{port}"
+                ));
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(250)),
+        }
+    }
+}
+
+pub(super) fn describe_install(ctx: &mut Context) -> Vec<String> {
+    vec![
+        format!(
+            "write the installation VM's domain definition to {}",
+            domain_xml_path(ctx)
+        ),
+        format_command(&create_command(ctx)),
+        format!(
+            "{} (20 times, once a second)",
+            format_command(&send_enter_command(ctx))
+        ),
+        format!("wait for the {DOMAIN_NAME} domain to shut off"),
+    ]
+}
+
+/// Installs Windows to the output image in a transient libvirt domain.
+pub(super) fn install_windows(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let vm = VmResources::from_context(ctx)?;
+    ui.set_substep(&format!(
+        "allocating {} vCPUs and {} MiB RAM to {} build VM",
+        vm.cpus, vm.memory_mib, vm.machine
+    ));
+
+    let output_image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let work_dir = Utf8PathBuf::from(ctx.get_var("work_dir").unwrap());
+    let mut monitor = DiskSpaceMonitor::new(
+        &[&work_dir, &output_image],
+        DiskSpaceLimits::from_context(ctx)?,
+    )?;
+    monitor.watch_progress(Watchdog::new(
+        ProgressLimits::from_context(ctx)?,
+        Some(&output_image),
+    ));
+
+    let _slot = crate::slots::take_vm_slot(ctx, ui)?;
+    memory::check_before_launch(vm.memory_mib, ctx, ui)?;
+
+    let serial_port = super::image_tests::unused_host_port()?;
+    let path = domain_xml_path(ctx);
+    std::fs::write(&path, domain_xml(ctx, &vm, serial_port)?)
+        .with_context(|| format!("writing '{path}'"))?;
+
+    // A domain left over from an interrupted build would keep libvirt from
+    // creating this one.
+    let uri = ctx.get_var("libvirt_uri").map(str::to_string);
+    let _ = destroy_command(uri.as_deref())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    ui.set_substep("starting the installation VM's libvirt domain");
+    run_command_check_status(&mut create_command(ctx), ui)?;
+    let mut domain = Domain { uri, running: true, next_check: Instant::now() };
+
+    let serial = connect_serial(serial_port)?;
+    monitor.watch_serial(SerialWatcher::spawn(
+        serial,
+        ui.serial_log(DOMAIN_NAME)?,
+    ));
+
+    // Simulate mashing the Enter key to get past the "Press any key to boot
+    // from CD or DVD" prompt and the Windows boot menu.
+    ui.set_substep("waiting for guest to complete installation");
+    for _ in 0..20 {
+        run_command_check_status(&mut send_enter_command(ctx), ui)?;
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    match monitor.wait(&mut domain, ui)? {
+        DomainExit::PoweredOff => Ok(()),
+        DomainExit::Crashed => anyhow::bail!(
+            "the installation VM crashed; libvirt's log for the {DOMAIN_NAME} \
+            domain (e.g. /var/log/libvirt/qemu/{DOMAIN_NAME}.log) may say why"
+        ),
+    }
+}
@@ -4,26 +4,177 @@
 
 //! Commands for creating a Windows guest image using QEMU.
 
-use crate::{app::Command, runner::Script};
+use crate::{
+    app::{Command, DiskSize, MachineType, OutputFormat},
+    autounattend::Architecture,
+    doctor::Report,
+    runner::Script,
+};
 
-use self::create_guest_disk_image::{
-    CreateGuestDiskImageArgs, CreateGuestDiskImageScript,
+use self::{
+    create_guest_disk_image::{
+        CreateGuestDiskImageArgs, CreateGuestDiskImageScript,
+    },
+    doctor::DoctorArgs,
 };
 
+mod accel;
 mod create_guest_disk_image;
+mod doctor;
+mod firmware;
+mod image_tests;
+mod kvm;
+mod libvirt;
+mod ovmf;
+mod qmp;
+mod screenshot;
+mod tpm;
+mod winrm;
 
-pub fn get_script(app: &crate::app::App) -> Box<dyn Script> {
-    match &app.command {
-        Command::CreateGuestDiskImage { sources, ovmf_path, vga_console } => {
-            Box::new(CreateGuestDiskImageScript::new(
-                CreateGuestDiskImageArgs {
-                    sources: sources.clone(),
-                    work_dir: app.work_dir.clone(),
-                    output_image: app.output_image.clone(),
-                    ovmf_path: ovmf_path.clone(),
-                    vga_console: *vga_console,
-                },
-            ))
+/// Returns the script for `app`'s command, as configured by `config`.
+/// `runner` runs the commands with which the script checks what the host's
+/// tools support.
+pub fn get_script(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+    runner: &dyn crate::command::CommandRunner,
+) -> anyhow::Result<Box<dyn Script>> {
+    Ok(match &app.command {
+        Command::CreateGuestDiskImage {
+            sources,
+            arch,
+            ovmf_path,
+            secure_boot,
+            ovmf_vars_template,
+            tpm,
+            vga_console,
+            screenshot_interval_secs,
+            boot_test,
+            accel,
+            hypervisor,
+            libvirt_uri,
+            output_format,
+            disk_size,
+            vm,
+            force_memory,
+            force_disk_space,
+            qcow2,
+            vhdx,
+            vmdk,
+            output_device,
+            oxide,
+            s3,
+            compress,
+        } => Box::new(CreateGuestDiskImageScript::new(
+            CreateGuestDiskImageArgs {
+                sources: sources.clone().with_config_defaults(config)?,
+                work_dir: app.work_dir.clone(),
+                output_image: app.output_image().to_path_buf(),
+                arch: *arch,
+                ovmf_path: ovmf_path.clone(),
+                secure_boot: *secure_boot,
+                ovmf_vars_template: ovmf_vars_template.clone(),
+                tpm: *tpm,
+                vga_console: *vga_console,
+                screenshot_interval_secs: *screenshot_interval_secs,
+                accel: *accel,
+                hypervisor: *hypervisor,
+                libvirt_uri: libvirt_uri.clone(),
+                output_format: output_format
+                    .or(config.output.format)
+                    .unwrap_or(OutputFormat::Raw),
+                disk_size: disk_size
+                    .or(config.disk.size)
+                    .unwrap_or(DiskSize::DEFAULT),
+                vm_cpus: vm.vm_cpus.or(config.vm.cpus),
+                vm_memory_mib: vm.vm_memory_mib.or(config.vm.memory_mib),
+                vm_machine: vm.vm_machine.or(config.vm.machine).unwrap_or(
+                    match arch {
+                        Architecture::X86_64 => MachineType::Pc,
+                        Architecture::Aarch64 => MachineType::Virt,
+                    },
+                ),
+                disk_controller: vm
+                    .disk_controller
+                    .or(config.vm.disk_controller)
+                    .unwrap_or_default(),
+                nic_model: vm
+                    .nic_model
+                    .or(config.vm.nic_model)
+                    .unwrap_or_default(),
+                force_memory: *force_memory,
+                force_disk_space: *force_disk_space,
+                shares_host: app.slots_dir.is_some(),
+                qcow2: qcow2.clone().with_config_defaults(config),
+                vhdx: vhdx.clone().with_config_defaults(config),
+                vmdk: vmdk.clone().with_config_defaults(config),
+                output_device: output_device.clone(),
+                oxide: oxide.clone(),
+                s3: s3.clone(),
+                compress: compress.clone().with_config_defaults(config),
+                tests: config.tests.clone().with_boot_check(*boot_test),
+            },
+            runner,
+        )),
+        Command::Doctor { .. } => {
+            unreachable!("the doctor command doesn't run a script")
+        }
+        Command::ListEditions { .. } => {
+            unreachable!("the list-editions command doesn't run a script")
+        }
+        Command::Inspect { .. } => {
+            unreachable!("the inspect command doesn't run a script")
+        }
+        Command::Shrink { .. } => {
+            unreachable!("the shrink command doesn't run a script")
+        }
+        Command::Convert { .. } => {
+            unreachable!("the convert command doesn't run a script")
+        }
+        Command::BuildAll { .. } => {
+            unreachable!("the build-all command runs other wimsy processes")
         }
-    }
+    })
+}
+
+/// Checks whether this host can build images with the options passed to the
+/// doctor command.
+pub fn check_host(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+) -> Report {
+    let Command::Doctor { ovmf_path, output_format, disk_size } = &app.command
+    else {
+        unreachable!("only the doctor command checks the host");
+    };
+
+    doctor::check_host(&DoctorArgs {
+        work_dir: app.work_dir.clone(),
+        output_image: app.output_image().to_path_buf(),
+        ovmf_path: ovmf_path.clone(),
+        output_format: output_format
+            .or(config.output.format)
+            .unwrap_or(OutputFormat::Raw),
+        disk_size: disk_size.or(config.disk.size).unwrap_or(DiskSize::DEFAULT),
+    })
+}
+
+/// Returns the vCPUs and memory, in MiB, that the installation VM of the
+/// build `app` describes will get, or `None` if `app` doesn't run one.
+pub fn installation_vm_size(
+    app: &crate::app::App,
+    config: &crate::config::Config,
+) -> Option<(u32, u64)> {
+    let Command::CreateGuestDiskImage { vm, .. } = &app.command else {
+        return None;
+    };
+
+    Some((
+        vm.vm_cpus
+            .or(config.vm.cpus)
+            .unwrap_or_else(create_guest_disk_image::detect_physical_cores),
+        vm.vm_memory_mib
+            .or(config.vm.memory_mib)
+            .unwrap_or_else(create_guest_disk_image::detect_qemu_ram_mb),
+    ))
 }
@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finds the OVMF firmware the installation VM boots from when the user
+//! doesn't name it with `--ovmf-path`.
+//!
+//! Every distribution installs OVMF somewhere different, and some under
+//! different names, so wimsy checks the places the common ones use, then the
+//! `share/qemu` directory next to QEMU's own binaries, where QEMU's bundled
+//! edk2 builds live (which is how Nix, Homebrew, and QEMU built from source
+//! ship firmware).
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::autounattend::Architecture;
+
+/// Where distributions install OVMF's code image for x86_64 guests, in order
+/// of preference.
+const X86_64_CANDIDATES: &[&str] = &[
+    // Debian and Ubuntu (ovmf); newer releases only ship the 4 MiB build.
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/OVMF/OVMF_CODE_4M.fd",
+    // Fedora, RHEL, and their relatives (edk2-ovmf).
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    // Arch Linux (edk2-ovmf), current and older layouts.
+    "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    // openSUSE (qemu-ovmf-x86_64).
+    "/usr/share/qemu/ovmf-x86_64-code.bin",
+    "/usr/share/qemu/OVMF.fd",
+    // NixOS hosts with libvirt enabled.
+    "/run/libvirt/nix-ovmf/OVMF_CODE.fd",
+];
+
+/// Where distributions install Secure Boot builds of OVMF's code image for
+/// x86_64 guests, in order of preference.
+const X86_64_SECURE_BOOT_CANDIDATES: &[&str] = &[
+    "/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+    "/usr/share/OVMF/OVMF_CODE.secboot.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.secure.4m.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.secure.fd",
+    "/usr/share/qemu/ovmf-x86_64-smm-ms-code.bin",
+];
+
+/// Where distributions install AAVMF's code image for aarch64 guests, in
+/// order of preference. AAVMF's Secure Boot support lives in its varstore,
+/// so the same images serve both kinds of build.
+const AARCH64_CANDIDATES: &[&str] = &[
+    // Debian and Ubuntu (qemu-efi-aarch64).
+    "/usr/share/AAVMF/AAVMF_CODE.fd",
+    // Fedora and its relatives (edk2-aarch64).
+    "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw",
+    // Arch Linux (edk2-aarch64).
+    "/usr/share/edk2/aarch64/QEMU_CODE.fd",
+    // openSUSE (qemu-uefi-aarch64).
+    "/usr/share/qemu/aavmf-aarch64-code.bin",
+];
+
+/// Returns the name of the edk2 build that QEMU bundles for `arch` guests.
+fn bundled_name(arch: Architecture, secure_boot: bool) -> &'static str {
+    match (arch, secure_boot) {
+        (Architecture::X86_64, false) => "edk2-x86_64-code.fd",
+        (Architecture::X86_64, true) => "edk2-x86_64-secure-code.fd",
+        (Architecture::Aarch64, _) => "edk2-aarch64-code.fd",
+    }
+}
+
+/// Returns the paths wimsy looks for firmware for `arch` guests at, in the
+/// order it looks.
+pub(super) fn candidates(
+    arch: Architecture,
+    secure_boot: bool,
+) -> Vec<Utf8PathBuf> {
+    let known = match (arch, secure_boot) {
+        (Architecture::X86_64, false) => X86_64_CANDIDATES,
+        (Architecture::X86_64, true) => X86_64_SECURE_BOOT_CANDIDATES,
+        (Architecture::Aarch64, _) => AARCH64_CANDIDATES,
+    };
+    let mut paths: Vec<Utf8PathBuf> =
+        known.iter().map(Utf8PathBuf::from).collect();
+
+    let bundled = bundled_name(arch, secure_boot);
+    paths.push(Utf8Path::new("/usr/share/qemu").join(bundled));
+    if let Some(share) = qemu_share_dir(arch) {
+        let path = share.join(bundled);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Returns the `share/qemu` directory of the QEMU installation whose binary
+/// would run `arch` guests, following symlinks (e.g. from a Nix profile into
+/// the store) to find where QEMU really lives.
+fn qemu_share_dir(arch: Architecture) -> Option<Utf8PathBuf> {
+    let program =
+        which::which(super::create_guest_disk_image::qemu_program(arch))
+            .ok()?;
+    let program = std::fs::canonicalize(program).ok()?;
+    let program = Utf8PathBuf::from_path_buf(program).ok()?;
+    Some(program.parent()?.parent()?.join("share/qemu"))
+}
+
+/// The firmware the installation VM will boot from.
+#[derive(Debug)]
+pub(super) enum Firmware {
+    /// The user named it with `--ovmf-path`.
+    Given(Utf8PathBuf),
+
+    /// wimsy found it at one of the usual locations.
+    Found(Utf8PathBuf),
+
+    /// The user didn't name any firmware, and there was none at any of the
+    /// `searched` locations.
+    Missing { searched: Vec<Utf8PathBuf> },
+}
+
+impl Firmware {
+    /// Returns `given` if it's set, or else looks for firmware for `arch`
+    /// guests (with Secure Boot, if `secure_boot` is set).
+    pub(super) fn find(
+        given: Option<&Utf8Path>,
+        arch: Architecture,
+        secure_boot: bool,
+    ) -> Self {
+        if let Some(path) = given {
+            return Firmware::Given(path.to_owned());
+        }
+
+        let searched = candidates(arch, secure_boot);
+        match searched.iter().find(|path| path.is_file()) {
+            Some(path) => Firmware::Found(path.clone()),
+            None => Firmware::Missing { searched },
+        }
+    }
+
+    /// The path of the firmware, if there is any.
+    pub(super) fn path(&self) -> Option<&Utf8Path> {
+        match self {
+            Firmware::Given(path) | Firmware::Found(path) => Some(path),
+            Firmware::Missing { .. } => None,
+        }
+    }
+
+    /// Suggests how to get firmware if none was found, listing the places
+    /// wimsy looked.
+    pub(super) fn remedy(&self) -> Option<String> {
+        let Firmware::Missing { searched } = self else {
+            return None;
+        };
+
+        let searched: Vec<&str> =
+            searched.iter().map(|path| path.as_str()).collect();
+        Some(format!(
+            "install your distribution's OVMF package (e.g. ovmf, edk2-ovmf, \
+            or qemu-efi-aarch64), or pass the path to a firmware image with \
+            --ovmf-path (wimsy looked for {})",
+            searched.join(", ")
+        ))
+    }
+
+    /// Explains that no firmware was found, if none was.
+    pub(super) fn problem(&self) -> Option<String> {
+        self.remedy().map(|remedy| {
+            format!(
+                "no OVMF firmware was found in any of the usual locations. \
+                To fix this, {remedy}."
+            )
+        })
+    }
+}
+
+impl std::fmt::Display for Firmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Firmware::Given(path) => write!(f, "{path}"),
+            Firmware::Found(path) => write!(f, "{path} (auto-detected)"),
+            Firmware::Missing { .. } => write!(f, "none found"),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal client for the QEMU Machine Protocol (QMP), which wimsy uses to
+//! send keystrokes to the installation VM, follow its resets and shutdowns,
+//! and shut it down when the build is interrupted.
+//!
+//! QMP messages are JSON objects, one per line. A background thread reads
+//! them from the socket and sorts them into command replies and
+//! asynchronous events.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{json::Json, trace};
+
+/// How long to wait for QEMU to reply to a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An asynchronous event QEMU reported, such as `RESET` or `SHUTDOWN`.
+#[derive(Debug)]
+pub(super) struct Event {
+    pub name: String,
+    pub data: Json,
+}
+
+pub(super) struct Qmp {
+    stream: UnixStream,
+    replies: Receiver<Json>,
+    events: Receiver<Event>,
+}
+
+impl Qmp {
+    /// Connects to the QMP socket at `path`, waiting up to `timeout` for QEMU
+    /// to create it, and leaves capabilities negotiation mode so that
+    /// commands can be sent.
+    pub fn connect(path: &Utf8Path, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => break stream,
+                Err(e) if Instant::now() > deadline => {
+                    return Err(e).with_context(|| {
+                        format!("connecting to QEMU's QMP socket at '{path}'")
+                    });
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        };
+
+        let (reply_tx, replies) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+        let reader = stream.try_clone().context("cloning the QMP socket")?;
+        trace::spawn("qmp-reader", move || {
+            for line in BufReader::new(reader).lines() {
+                let Ok(line) = line else { break };
+                let message = match Json::parse(&line) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        trace::debug!(
+                            "ignoring unparseable QMP message",
+                            error = format!("{e:#}")
+                        );
+                        continue;
+                    }
+                };
+
+                let sent = match message.get("event").and_then(Json::as_str) {
+                    Some(name) => {
+                        let event = Event {
+                            name: name.to_string(),
+                            data: message
+                                .get("data")
+                                .cloned()
+                                .unwrap_or(Json::Null),
+                        };
+                        trace::debug!("QMP event", name = name);
+                        event_tx.send(event).is_ok()
+                    }
+                    None => reply_tx.send(message).is_ok(),
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+
+        let mut qmp = Self { stream, replies, events };
+        qmp.reply().context("waiting for QEMU's QMP greeting")?;
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    fn reply(&self) -> Result<Json> {
+        match self.replies.recv_timeout(REPLY_TIMEOUT) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                anyhow::bail!("QEMU didn't reply within {REPLY_TIMEOUT:?}")
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("QEMU closed its QMP socket")
+            }
+        }
+    }
+
+    /// Runs the QMP command `command` with the given arguments, returning
+    /// what it returned.
+    pub fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<Json>,
+    ) -> Result<Json> {
+        let mut message = Json::object().with("execute", command);
+        if let Some(arguments) = arguments {
+            message.insert("arguments", arguments);
+        }
+        writeln!(self.stream, "{message}")
+            .and_then(|_| self.stream.flush())
+            .with_context(|| format!("sending QMP command {command}"))?;
+
+        let reply = self.reply().with_context(|| {
+            format!("waiting for QEMU to reply to {command}")
+        })?;
+        if let Some(error) = reply.get("error") {
+            let description = error
+                .get("desc")
+                .and_then(Json::as_str)
+                .unwrap_or("unknown error");
+            anyhow::bail!("QMP command {command} failed: {description}");
+        }
+
+        Ok(reply.get("return").cloned().unwrap_or(Json::Null))
+    }
+
+    /// Presses and releases the key with the QEMU key code `qcode` (e.g.
+    /// "ret").
+    pub fn send_key(&mut self, qcode: &str) -> Result<()> {
+        let key = Json::object().with("type", "qcode").with("data", qcode);
+        self.execute("send-key", Some(Json::object().with("keys", vec![key])))
+            .map(|_| ())
+    }
+
+    /// Returns the events QEMU has reported since the last call.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
+}
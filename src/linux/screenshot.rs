@@ -0,0 +1,124 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Captures the installation VM's display with QMP's `screendump` command,
+//! so that a build that stalls or fails leaves a picture of the dialog or
+//! crash screen Windows Setup stopped at.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{json::Json, runner::Context, trace};
+
+use super::qmp::Qmp;
+
+/// The name of the directory in the work directory that holds screenshots.
+pub(super) const SCREENSHOT_DIR_NAME: &str = "screenshots";
+
+pub(super) struct Screenshots {
+    dir: Utf8PathBuf,
+
+    /// How often to take a screenshot, if periodic screenshots were asked
+    /// for.
+    interval: Option<Duration>,
+    started: Instant,
+    next: Instant,
+    taken: u32,
+
+    /// Whether QEMU writes PNGs. Versions before 7.1 only write PPMs.
+    png: bool,
+}
+
+impl Screenshots {
+    /// Prepares to take the screenshots the context asks for. Returns `None`
+    /// if the VM has no display adapter to capture, i.e. if neither
+    /// `screenshot_interval_secs` nor `vga_console` is set.
+    pub fn from_context(ctx: &Context) -> Result<Option<Self>> {
+        let interval = ctx
+            .get_var("screenshot_interval_secs")
+            .map(|secs| {
+                secs.parse().context("parsing screenshot_interval_secs")
+            })
+            .transpose()?
+            .map(Duration::from_secs);
+        if interval.is_none() && ctx.get_var("vga_console").is_none() {
+            return Ok(None);
+        }
+
+        // Start afresh, so that the directory only holds this attempt's
+        // screenshots.
+        let dir = crate::workspace::log_path(ctx, SCREENSHOT_DIR_NAME);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating '{dir}'"))?;
+
+        let now = Instant::now();
+        Ok(Some(Self {
+            dir,
+            interval,
+            started: now,
+            next: now + interval.unwrap_or_default(),
+            taken: 0,
+            png: true,
+        }))
+    }
+
+    /// Takes a screenshot if the next periodic one is due.
+    pub fn tick(&mut self, qmp: &mut Qmp) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if Instant::now() < self.next {
+            return;
+        }
+
+        self.next = Instant::now() + interval;
+        let name =
+            format!("{:04}-{}s", self.taken, self.started.elapsed().as_secs());
+        if let Err(e) = self.capture(qmp, &name) {
+            trace::debug!(
+                "couldn't take a screenshot",
+                error = format!("{e:#}")
+            );
+        }
+    }
+
+    /// Saves a screenshot named `name` (plus an extension), returning its
+    /// path.
+    pub fn capture(
+        &mut self,
+        qmp: &mut Qmp,
+        name: &str,
+    ) -> Result<Utf8PathBuf> {
+        loop {
+            let extension = if self.png { "png" } else { "ppm" };
+            let path = self.dir.join(format!("{name}.{extension}"));
+            let mut arguments = Json::object().with("filename", path.as_str());
+            if self.png {
+                arguments.insert("format", "png");
+            }
+
+            match qmp.execute("screendump", Some(arguments)) {
+                Ok(_) => {
+                    self.taken += 1;
+                    trace::debug!("took a screenshot", path = path.as_str());
+                    return Ok(path);
+                }
+                // Older versions of QEMU reject the format argument.
+                Err(e) if self.png && format!("{e:#}").contains("format") => {
+                    trace::debug!("QEMU can't write PNG screenshots");
+                    self.png = false;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns how many screenshots have been taken.
+    pub fn taken(&self) -> u32 {
+        self.taken
+    }
+}
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Gives the installation and test VMs an emulated TPM 2.0.
+//!
+//! Windows 11 refuses to install without a TPM 2.0, and some hardened Server
+//! configurations (e.g. BitLocker or Credential Guard) want one. QEMU doesn't
+//! emulate a TPM itself; it talks to an `swtpm` process over a Unix socket.
+//! wimsy starts `swtpm` just before launching each VM and stops it when the
+//! VM exits (or when the step fails), so there's nothing to set up by hand.
+
+use std::{
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    interrupt::CancelGuard, runner::Context, trace, ui::Ui,
+    util::format_command,
+};
+
+/// How long to wait for `swtpm` to create its socket.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks that `swtpm` is installed if a TPM was requested.
+pub(super) fn check_prerequisites(tpm: bool) -> Vec<String> {
+    if tpm && which::which("swtpm").is_err() {
+        vec!["--tpm requires swtpm, which wasn't found (is it on your PATH?); \
+            most distributions package it as swtpm"
+            .to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns the directory that holds the state of the TPM named `name`, and
+/// the path of the socket it listens on.
+fn paths(ctx: &Context, name: &str) -> (Utf8PathBuf, Utf8PathBuf) {
+    (
+        crate::workspace::scratch_path(ctx, &format!("{name}-tpm")),
+        crate::workspace::scratch_path(ctx, &format!("{name}-tpm.sock")),
+    )
+}
+
+fn swtpm_command(state_dir: &Utf8Path, socket: &Utf8Path) -> Command {
+    let mut cmd = Command::new("swtpm");
+    cmd.args(["socket", "--tpm2"])
+        .args(["--tpmstate", &format!("dir={state_dir}")])
+        .args(["--ctrl", &format!("type=unixio,path={socket}")])
+        // Exit when QEMU disconnects, so that swtpm doesn't outlive the VM
+        // even if wimsy itself is killed.
+        .arg("--terminate");
+    cmd
+}
+
+/// Returns the QEMU arguments that attach the TPM named `name`, if a TPM
+/// was requested.
+pub(super) fn qemu_args(ctx: &Context, name: &str) -> Vec<String> {
+    if ctx.get_var("tpm").is_none() {
+        return Vec::new();
+    }
+
+    let (_, socket) = paths(ctx, name);
+    [
+        "-chardev",
+        &format!("socket,id=chrtpm,path={socket}"),
+        "-tpmdev",
+        "emulator,id=tpm0,chardev=chrtpm",
+        "-device",
+        "tpm-crb,tpmdev=tpm0",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+/// Describes the `swtpm` command [`Swtpm::start`] runs for the TPM named
+/// `name`, if a TPM was requested.
+pub(super) fn describe(ctx: &Context, name: &str) -> Vec<String> {
+    if ctx.get_var("tpm").is_none() {
+        return Vec::new();
+    }
+
+    let (state_dir, socket) = paths(ctx, name);
+    vec![format!("{} &", format_command(&swtpm_command(&state_dir, &socket)))]
+}
+
+/// A running `swtpm` process. Dropping it stops the process.
+pub(super) struct Swtpm {
+    child: Child,
+    socket: Utf8PathBuf,
+    _cancel: CancelGuard,
+}
+
+impl Swtpm {
+    /// Starts the TPM named `name` from a clean state and waits for it to
+    /// accept connections, if a TPM was requested.
+    pub(super) fn start(
+        ctx: &Context,
+        name: &str,
+        ui: &dyn Ui,
+    ) -> Result<Option<Self>> {
+        if ctx.get_var("tpm").is_none() {
+            return Ok(None);
+        }
+
+        let (state_dir, socket) = paths(ctx, name);
+        if state_dir.exists() {
+            std::fs::remove_dir_all(&state_dir)
+                .with_context(|| format!("removing '{state_dir}'"))?;
+        }
+        std::fs::create_dir_all(&state_dir)
+            .with_context(|| format!("creating '{state_dir}'"))?;
+        let _ = std::fs::remove_file(&socket);
+
+        ui.set_substep("starting the emulated TPM");
+        let mut cmd = swtpm_command(&state_dir, &socket);
+        cmd.stdin(Stdio::null())
+            .stdout(ui.child_stdout("swtpm")?)
+            .stderr(ui.child_stderr("swtpm")?);
+        ui.command_started(&cmd);
+        let child = cmd.spawn().context("starting swtpm")?;
+        let _cancel = crate::interrupt::kill_on_cancel(&child);
+        let mut tpm = Self { child, socket, _cancel };
+
+        let deadline = Instant::now() + START_TIMEOUT;
+        while !tpm.socket.exists() {
+            if let Some(status) = tpm.child.try_wait()? {
+                anyhow::bail!(
+                    "swtpm exited before QEMU could connect to it ({status}); \
+                    see its log for details"
+                );
+            }
+
+            if Instant::now() > deadline {
+                anyhow::bail!("timed out waiting for swtpm to start");
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(Some(tpm))
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        trace::debug!("stopping swtpm", socket = self.socket.as_str());
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs PowerShell in the test VM over WinRM, for tests that should run as a
+//! real account (instead of as `LocalSystem`, as guest agent tests do) or
+//! that check the image's remote management works.
+//!
+//! WinRM over HTTP requires messages to be encrypted with the session key of
+//! an NTLM or Kerberos login unless the guest is configured to allow
+//! unencrypted traffic, which a shipped image shouldn't be. Rather than
+//! implement NTLM, `wimsy` hands each command to a small Python script that
+//! uses pywinrm, passing the password on the script's standard input so that
+//! it doesn't appear in the host's process list.
+
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+
+use super::image_tests::ExecResult;
+use crate::{json::Json, trace};
+
+/// Runs the command described by a JSON request on standard input and
+/// prints the result as JSON.
+const CLIENT_SCRIPT: &str = r#"
+import json, sys
+try:
+    import winrm
+except ImportError:
+    sys.exit("pywinrm isn't installed")
+
+request = json.load(sys.stdin)
+session = winrm.Session(
+    "http://127.0.0.1:%d/wsman" % request["port"],
+    auth=(request["username"], request["password"]),
+    transport="ntlm",
+)
+result = session.run_ps(request["script"])
+json.dump({
+    "exit_code": result.status_code,
+    "stdout": result.std_out.decode("utf-8", "replace"),
+    "stderr": result.std_err.decode("utf-8", "replace"),
+}, sys.stdout)
+"#;
+
+/// How often to check whether the client has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Checks that WinRM tests can run on this host, returning a message
+/// describing each problem.
+pub(super) fn check_prerequisites() -> Vec<String> {
+    let found = Command::new("python3")
+        .args(["-c", "import winrm"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if found {
+        Vec::new()
+    } else {
+        vec!["WinRM tests need python3 with pywinrm installed (e.g. 'pip \
+            install pywinrm')"
+            .to_string()]
+    }
+}
+
+/// A WinRM account in the test VM, reached through a port forward.
+pub(super) struct WinrmClient {
+    host_port: u16,
+    username: String,
+    password: String,
+}
+
+impl WinrmClient {
+    pub(super) fn new(
+        host_port: u16,
+        username: &str,
+        password: String,
+    ) -> Self {
+        Self { host_port, username: username.to_string(), password }
+    }
+
+    /// Runs `script` with PowerShell in the guest and waits up to `timeout`
+    /// for it to exit.
+    pub(super) fn powershell(
+        &self,
+        script: &str,
+        timeout: Duration,
+    ) -> Result<ExecResult> {
+        let request = Json::object()
+            .with("port", u64::from(self.host_port))
+            .with("username", self.username.as_str())
+            .with("password", self.password.as_str())
+            .with("script", script);
+
+        let mut child = Command::new("python3")
+            .args(["-c", CLIENT_SCRIPT])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("running the WinRM client")?;
+        let _cancel = crate::interrupt::kill_on_cancel(&child);
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{request}")?;
+        drop(stdin);
+
+        // Read the client's output while waiting for it, so that it can't
+        // fill the pipes and stall.
+        let mut stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let stdout = std::thread::spawn(move || {
+            let mut out = String::new();
+            stdout.read_to_string(&mut out).map(|_| out)
+        });
+        let stderr = std::thread::spawn(move || {
+            let mut out = String::new();
+            stderr.read_to_string(&mut out).map(|_| out)
+        });
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("timed out after {} seconds", timeout.as_secs());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        let stdout = stdout.join().unwrap()?;
+        let stderr = stderr.join().unwrap()?;
+        if !status.success() {
+            trace::debug!("WinRM client failed", stderr = stderr.as_str());
+            let last_line = stderr.lines().rev().find(|l| !l.trim().is_empty());
+            anyhow::bail!(
+                "the WinRM client failed: {}",
+                last_line.map_or_else(|| status.to_string(), str::to_string)
+            );
+        }
+
+        let reply = Json::parse(&stdout).with_context(|| {
+            format!("parsing WinRM client output '{stdout}'")
+        })?;
+        let field = |key: &str| {
+            reply
+                .get(key)
+                .and_then(Json::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        Ok(ExecResult {
+            exit_code: reply
+                .get("exit_code")
+                .and_then(Json::as_i64)
+                .unwrap_or(-1),
+            stdout: field("stdout"),
+            stderr: field("stderr"),
+        })
+    }
+}
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Advisory locks that stop two builds (e.g. a CI job and its retry) from
+//! writing to the same work directory or output image at once.
+//!
+//! Each lock is a POSIX record lock on a small file that says which process
+//! holds it. The kernel releases the lock when that process exits, however it
+//! exits, so a lock normally can't outlive its build. One on a network
+//! filesystem can if the host holding it goes away; `--force` breaks such
+//! locks by replacing their files.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::trace;
+
+/// The name of the lock file in the work directory.
+pub const LOCK_FILE_NAME: &str = "wimsy.lock";
+
+/// A held lock. Dropping it removes its file and releases it.
+///
+/// The lock is held by this process rather than by the `Lock`: it only
+/// excludes other processes, so two builds run in the same process (e.g.
+/// through [`crate::run`]) don't exclude each other, and closing any other
+/// descriptor this process has for the file releases it.
+pub struct Lock {
+    path: Utf8PathBuf,
+
+    /// The open lock file. Closing it releases the lock.
+    _file: File,
+}
+
+impl Lock {
+    /// Takes the lock whose file is `path`, which guards `what` (e.g. "the
+    /// work directory"). Fails, naming the holder, if another process holds
+    /// it, unless `force` is set, in which case the lock is broken instead.
+    pub fn acquire(path: &Utf8Path, what: &str, force: bool) -> Result<Self> {
+        let mut force = force;
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .with_context(|| format!("opening lock file '{path}'"))?;
+
+            if !try_lock(&file).with_context(|| format!("locking '{path}'"))? {
+                let holder = std::fs::read_to_string(path).unwrap_or_default();
+                let holder = match holder.trim() {
+                    "" => "another process".to_string(),
+                    holder => holder.to_string(),
+                };
+                if !force {
+                    anyhow::bail!(
+                        "another wimsy build ({holder}) is using {what}; wait \
+                        for it to finish, or pass --force to break its lock \
+                        ('{path}') if it's no longer running"
+                    );
+                }
+
+                eprintln!("Warning: breaking the lock on {what} ({holder})");
+                trace::info!(
+                    "breaking lock",
+                    path = path.as_str(),
+                    holder = holder.as_str()
+                );
+                std::fs::remove_file(path)
+                    .with_context(|| format!("removing lock file '{path}'"))?;
+
+                // Only break the lock once, so that two forced builds can't
+                // take turns breaking each other's locks.
+                force = false;
+                continue;
+            }
+
+            // The previous holder may have removed the file between this
+            // process opening it and locking it, in which case the lock
+            // guards nothing and another process may hold a lock on a newer
+            // file at the same path.
+            let current = match std::fs::metadata(path) {
+                Ok(metadata) => Some(metadata),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("reading '{path}'"))
+                }
+            };
+            let locked =
+                file.metadata().with_context(|| format!("reading '{path}'"))?;
+            if !current.is_some_and(|current| {
+                current.dev() == locked.dev() && current.ino() == locked.ino()
+            }) {
+                continue;
+            }
+
+            file.set_len(0)
+                .and_then(|_| {
+                    writeln!(
+                        file,
+                        "pid {} on {}",
+                        std::process::id(),
+                        hostname()
+                    )
+                })
+                .with_context(|| format!("writing lock file '{path}'"))?;
+            trace::debug!("took lock", path = path.as_str());
+            return Ok(Self { path: path.to_path_buf(), _file: file });
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Remove the file while still holding the lock, so that no other
+        // process can lock the file and then have it removed from under it.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Tries to take a write lock on the whole of `file` without waiting.
+/// Returns whether the lock was taken.
+pub(crate) fn try_lock(file: &File) -> std::io::Result<bool> {
+    // SAFETY: an all-zero `flock` is a valid value, and `lock` outlives the
+    // `fcntl` call that reads it.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    // A length of zero locks the whole file, however long it grows.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+        return Ok(true);
+    }
+
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EAGAIN) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+
+    // SAFETY: `buf` is valid and writable for the length passed.
+    let rc = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if rc != 0 {
+        return "an unknown host".to_string();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The locks a build holds on its work directory and output image.
+pub struct BuildLock {
+    work_dir: Utf8PathBuf,
+    work_dir_lock: Option<Lock>,
+    output_image_lock: Option<Lock>,
+}
+
+impl BuildLock {
+    /// Locks `work_dir`, which must exist, and `output_image`. `force` breaks
+    /// locks other processes hold on them.
+    pub fn acquire(
+        work_dir: &Utf8Path,
+        output_image: &Utf8Path,
+        force: bool,
+    ) -> Result<Self> {
+        let work_dir_lock = Lock::acquire(
+            &work_dir.join(LOCK_FILE_NAME),
+            &format!("the work directory '{work_dir}'"),
+            force,
+        )?;
+
+        // If the output image's directory doesn't exist, the build will fail
+        // before it writes the image, and say why.
+        let output_image_lock = match output_image.parent() {
+            Some(dir) if dir.as_str().is_empty() || dir.is_dir() => {
+                Some(Lock::acquire(
+                    &lock_path(output_image),
+                    &format!("the output image '{output_image}'"),
+                    force,
+                )?)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            work_dir: work_dir.to_path_buf(),
+            work_dir_lock: Some(work_dir_lock),
+            output_image_lock,
+        })
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        // The lock files keep `workspace::clean_up` from removing an
+        // otherwise empty work directory, so make up for that here.
+        drop(self.output_image_lock.take());
+        drop(self.work_dir_lock.take());
+        let _ = std::fs::remove_dir(&self.work_dir);
+    }
+}
+
+/// Returns the path of the lock file for `output_image`.
+fn lock_path(output_image: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{output_image}.lock"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn removes_lock_files_on_release() {
+        let dir =
+            Utf8PathBuf::try_from(std::env::temp_dir().join("wimsy-lock-test"))
+                .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let output_image = dir.join("out.img");
+
+        let lock = BuildLock::acquire(&dir, &output_image, false).unwrap();
+        let contents =
+            std::fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap();
+        assert!(contents.starts_with(&format!("pid {} ", std::process::id())));
+        assert!(lock_path(&output_image).exists());
+
+        drop(lock);
+        assert!(!lock_path(&output_image).exists());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn refuses_locks_held_by_other_processes() {
+        let dir = Utf8PathBuf::try_from(
+            std::env::temp_dir().join("wimsy-lock-holder-test"),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join(LOCK_FILE_NAME);
+        std::fs::write(&path, "pid 1 on builder\n").unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+
+        // Record locks don't conflict within a process, so a child has to
+        // hold this one. It only makes async-signal-safe calls after the
+        // fork, and waits to be killed once it has the lock.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+            lock.l_type = libc::F_WRLCK as _;
+            lock.l_whence = libc::SEEK_SET as _;
+            let locked =
+                unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) };
+            let byte = u8::from(locked == 0);
+            unsafe {
+                libc::write(write_fd, &byte as *const u8 as *const _, 1);
+                loop {
+                    libc::pause();
+                }
+            }
+        }
+
+        let mut byte = 0u8;
+        let read =
+            unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut _, 1) };
+        let result = std::panic::catch_unwind(|| {
+            assert_eq!((read, byte), (1, 1), "the child didn't take the lock");
+
+            let err = Lock::acquire(&path, "the test directory", false)
+                .err()
+                .expect("took a lock another process holds")
+                .to_string();
+            assert!(
+                err.contains(
+                    "another wimsy build (pid 1 on builder) is using \
+                    the test directory"
+                ),
+                "{err}"
+            );
+
+            let lock = Lock::acquire(&path, "the test directory", true)
+                .expect("didn't break the lock");
+            let contents = std::fs::read_to_string(&path).unwrap();
+            assert!(
+                contents.starts_with(&format!("pid {} ", std::process::id())),
+                "{contents}"
+            );
+            drop(lock);
+        });
+
+        unsafe {
+            libc::kill(child, libc::SIGKILL);
+            libc::waitpid(child, std::ptr::null_mut(), 0);
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
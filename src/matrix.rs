@@ -0,0 +1,709 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Builds every target a configuration file defines (the build-all command).
+//!
+//! Each target is built by another copy of wimsy, run with `--target` and a
+//! work directory of its own, so that builds running in parallel don't share
+//! the process-wide state a build uses (its signal handlers, traces, and the
+//! terminal). Builds that run in parallel share the host through a slots
+//! directory (see [`crate::slots`]), which limits how many installation VMs
+//! they run at once to what the host's CPUs and memory can hold, and report
+//! their progress as JSON events, which are printed here with the name of the
+//! target they came from.
+
+use std::{
+    fs::File,
+    io::BufRead,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use colored::Colorize;
+
+use crate::{
+    app::{App, ProgressFormat},
+    config::Config,
+    json::Json,
+    memory::MEMORY_MARGIN_MIB,
+};
+
+/// How often to check whether the running builds have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The name of the slots directory in the work directory.
+const SLOTS_DIR_NAME: &str = "slots";
+
+/// A target to build.
+struct Target {
+    name: String,
+    output_image: Utf8PathBuf,
+
+    /// The vCPUs and memory, in MiB, of the target's installation VM.
+    vm_cpus: u32,
+    vm_memory_mib: u64,
+}
+
+/// How a target's build went.
+enum Outcome {
+    Succeeded,
+    Failed(ExitStatus),
+    NotStarted(String),
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Succeeded => write!(f, "succeeded"),
+            Outcome::Failed(status) => write!(f, "failed ({status})"),
+            Outcome::NotStarted(reason) => {
+                write!(f, "didn't start ({reason})")
+            }
+        }
+    }
+}
+
+/// A target's build that's running.
+struct Running {
+    index: usize,
+    started: Instant,
+    child: Child,
+}
+
+/// How the builds build-all runs share the host.
+struct Sharing<'a> {
+    /// The slots directory, or `None` if builds run one at a time.
+    slots_dir: Option<&'a Utf8Path>,
+
+    /// Whether builds report their progress to this process as JSON events.
+    json_progress: bool,
+
+    /// Whether builds may interact with the user.
+    interactive: bool,
+}
+
+/// A line of JSON progress a running build wrote.
+struct ProgressLine {
+    index: usize,
+    line: String,
+}
+
+/// Builds each of the targets in `config`, which was read from the file
+/// passed to `--config`, up to `jobs` at a time, passing `build_args` to
+/// create-guest-disk-image for each. Fails if any of them fails.
+pub fn build_all(
+    app: &App,
+    config: &Config,
+    jobs: usize,
+    build_args: &[String],
+    interactive: bool,
+) -> Result<()> {
+    let Some(config_path) = &app.config else {
+        anyhow::bail!(
+            "build-all builds the targets in a configuration file; pass one \
+            with --config"
+        );
+    };
+    if app.target.is_some() {
+        anyhow::bail!(
+            "build-all builds every target, so --target can't be used"
+        );
+    }
+    if app.output_image.is_some() {
+        anyhow::bail!(
+            "each target names its own output image (with output.image), so \
+            --output-image can't be used with build-all"
+        );
+    }
+    if !app.pause_after.is_empty() {
+        anyhow::bail!("--pause-after can't be used with build-all");
+    }
+    if config.targets.is_empty() {
+        anyhow::bail!("'{config_path}' doesn't define any [[targets]]");
+    }
+
+    let jobs = jobs.max(1);
+    let json = app.progress == ProgressFormat::Json;
+    let slots_dir = app.work_dir.join(SLOTS_DIR_NAME);
+    let sharing = Sharing {
+        slots_dir: (jobs > 1).then_some(slots_dir.as_path()),
+        json_progress: jobs > 1 || json,
+        interactive: interactive && jobs == 1,
+    };
+    let targets =
+        load_targets(app, config_path, &config.targets, build_args, &sharing)?;
+    std::fs::create_dir_all(&app.work_dir)
+        .with_context(|| format!("creating '{}'", app.work_dir))?;
+
+    // Messages go where the builds' human-readable output goes: to stderr if
+    // stdout carries JSON progress.
+    let say = |line: String| {
+        if json {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    };
+
+    if jobs > 1 {
+        let cpus = std::thread::available_parallelism()
+            .map_or(1, std::num::NonZeroUsize::get);
+        let memory_mib = crate::memory::available_mib().ok();
+        let vm_slots = vm_slots(&targets, jobs, cpus, memory_mib);
+        crate::slots::create(&slots_dir, vm_slots)?;
+        say(format!(
+            "{} building {} targets, {jobs} at a time; {}",
+            "==>".bold(),
+            targets.len(),
+            describe_vm_slots(vm_slots, cpus, memory_mib),
+        ));
+    }
+
+    let exe = std::env::current_exe()
+        .context("finding the path of the running wimsy")?;
+    let name_width =
+        targets.iter().map(|target| target.name.len()).max().unwrap_or(0);
+    let mut outcomes: Vec<Option<(Outcome, Duration)>> =
+        targets.iter().map(|_| None).collect();
+    let mut pending = 0..targets.len();
+    let mut running: Vec<Running> = Vec::new();
+    let (tx, rx) = mpsc::channel::<ProgressLine>();
+    loop {
+        while running.len() < jobs {
+            let Some(index) = pending.next() else {
+                break;
+            };
+
+            let target = &targets[index];
+            let mut cmd = Command::new(&exe);
+            cmd.args(target_args(app, config_path, &target.name, &sharing))
+                .args(build_args);
+            if jobs > 1 {
+                let log = app.work_dir.join(format!("{}.log", target.name));
+                match log_to(&mut cmd, &log) {
+                    Ok(()) => say(format!(
+                        "{} {}: started (output in {log})",
+                        "==>".bold(),
+                        target.name
+                    )),
+                    Err(e) => {
+                        outcomes[index] = Some((
+                            Outcome::NotStarted(format!("{e:#}")),
+                            Duration::ZERO,
+                        ));
+                        continue;
+                    }
+                }
+            } else {
+                // Events are tagged with their target before they're passed on.
+                if json {
+                    cmd.stdout(Stdio::piped());
+                }
+                say(format!("{} {}: started", "==>".bold(), target.name));
+            }
+
+            match cmd.spawn() {
+                Ok(mut child) => {
+                    if let Some(stdout) = child.stdout.take() {
+                        let tx = tx.clone();
+                        std::thread::spawn(move || {
+                            for line in std::io::BufReader::new(stdout)
+                                .lines()
+                                .map_while(Result::ok)
+                            {
+                                if tx
+                                    .send(ProgressLine { index, line })
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    running.push(Running {
+                        index,
+                        started: Instant::now(),
+                        child,
+                    });
+                }
+                Err(e) => {
+                    outcomes[index] = Some((
+                        Outcome::NotStarted(format!("running {exe:?}: {e}")),
+                        Duration::ZERO,
+                    ))
+                }
+            }
+        }
+
+        if running.is_empty() {
+            break;
+        }
+
+        // Wait for progress from the builds for a little while, then print
+        // whatever they've reported.
+        let mut lines: Vec<ProgressLine> =
+            rx.recv_timeout(POLL_INTERVAL).into_iter().collect();
+        lines.extend(rx.try_iter());
+        for ProgressLine { index, line } in lines {
+            let name = &targets[index].name;
+            if json {
+                println!("{}", with_target(&line, name));
+                continue;
+            }
+
+            let Ok(event) = Json::parse(&line) else {
+                say(format!("{name:<name_width$} | {line}"));
+                continue;
+            };
+            if let Some(text) = describe_event(&event) {
+                say(format!("{name:<name_width$} | {text}"));
+            }
+        }
+
+        let mut i = 0;
+        while i < running.len() {
+            let status = running[i].child.try_wait().with_context(|| {
+                format!("waiting for target {}", targets[running[i].index].name)
+            })?;
+            let Some(status) = status else {
+                i += 1;
+                continue;
+            };
+
+            let build = running.swap_remove(i);
+            let elapsed = build.started.elapsed();
+            let outcome = if status.success() {
+                Outcome::Succeeded
+            } else {
+                Outcome::Failed(status)
+            };
+            say(format!(
+                "{} {}: {outcome} after {}",
+                "==>".bold(),
+                targets[build.index].name,
+                crate::ui::format_elapsed(elapsed)
+            ));
+            outcomes[build.index] = Some((outcome, elapsed));
+        }
+    }
+
+    if jobs > 1 {
+        let _ = std::fs::remove_dir_all(&slots_dir);
+    }
+
+    let outcomes: Vec<_> = outcomes
+        .into_iter()
+        .map(|outcome| outcome.expect("every target was built"))
+        .collect();
+    say(String::new());
+    for line in summary_table(&targets, &outcomes) {
+        say(line);
+    }
+
+    let failed = outcomes
+        .iter()
+        .filter(|(outcome, _)| !matches!(outcome, Outcome::Succeeded))
+        .count();
+    if failed > 0 {
+        anyhow::bail!(
+            "{failed} of {} targets failed; their logs are in their work \
+            directories under '{}'",
+            targets.len(),
+            app.work_dir
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads each of the targets named `names` from the configuration file at
+/// `path`, and checks the options with which each will be built, so that a
+/// target with invalid settings fails the whole run before any builds start.
+fn load_targets(
+    app: &App,
+    path: &Utf8Path,
+    names: &[String],
+    build_args: &[String],
+    sharing: &Sharing,
+) -> Result<Vec<Target>> {
+    let mut targets: Vec<Target> = Vec::new();
+    for name in names {
+        let config = Config::load(path, Some(name))?;
+        let Some(output_image) = config.output.image.clone() else {
+            anyhow::bail!("target '{name}' doesn't set output.image");
+        };
+        if let Some(other) =
+            targets.iter().find(|target| target.output_image == output_image)
+        {
+            anyhow::bail!(
+                "targets '{}' and '{name}' both write '{output_image}'",
+                other.name
+            );
+        }
+
+        let args = std::iter::once("wimsy".to_string())
+            .chain(target_args(app, path, name, sharing))
+            .chain(build_args.iter().cloned());
+        let build = App::try_parse_from(args).map_err(|e| {
+            anyhow::anyhow!(
+                "the options for target '{name}' are invalid: {}",
+                e.render()
+            )
+        })?;
+        let (vm_cpus, vm_memory_mib) =
+            crate::installation_vm_size(&build, &config)
+                .expect("targets are built with create-guest-disk-image");
+
+        targets.push(Target {
+            name: name.clone(),
+            output_image,
+            vm_cpus,
+            vm_memory_mib,
+        });
+    }
+
+    Ok(targets)
+}
+
+/// Returns the options with which to run wimsy to build the target `name`,
+/// up to and including the command name.
+fn target_args(
+    app: &App,
+    config_path: &Utf8Path,
+    name: &str,
+    sharing: &Sharing,
+) -> Vec<String> {
+    let mut args = vec![
+        format!("--work-dir={}", app.work_dir.join(name)),
+        format!("--config={config_path}"),
+        format!("--target={name}"),
+        format!("--keep-work-dir={}", app.keep_work_dir),
+        format!("--interactive={}", sharing.interactive),
+    ];
+    if sharing.json_progress {
+        args.push("--progress=json".to_string());
+    }
+    if let Some(dir) = sharing.slots_dir {
+        args.push(format!("--slots-dir={dir}"));
+    }
+    if app.verbose > 0 {
+        args.push(format!("-{}", "v".repeat(app.verbose.into())));
+    }
+    for (set, flag) in [
+        (app.resume, "--resume"),
+        (app.force, "--force"),
+        (app.dry_run, "--dry-run"),
+        (app.follow_serial, "--follow-serial"),
+        (app.reproducible, "--reproducible"),
+    ] {
+        if set {
+            args.push(flag.to_string());
+        }
+    }
+    args.extend(app.skip_step.iter().map(|step| format!("--skip-step={step}")));
+    args.extend(app.only_step.iter().map(|step| format!("--only-step={step}")));
+    args.extend(app.start_at.iter().map(|step| format!("--start-at={step}")));
+    args.extend(
+        app.tools_container
+            .iter()
+            .map(|image| format!("--tools-container={image}")),
+    );
+    args.extend(app.sign_key.iter().map(|key| format!("--sign-key={key}")));
+    args.extend(app.disk_monitor.args());
+    args.push("create-guest-disk-image".to_string());
+    args
+}
+
+/// Sends `cmd`'s standard error to the file at `path`, and its standard
+/// output, which carries its progress events, to this process.
+fn log_to(cmd: &mut Command, path: &Utf8Path) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("creating '{path}'"))?;
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(file);
+    Ok(())
+}
+
+/// Returns how many installation VMs the host can run at once, given that
+/// up to `jobs` builds run at a time, that it has `cpus` CPUs and
+/// `memory_mib` of memory available (if that's known), and that any of the
+/// targets' VMs may run at the same time.
+fn vm_slots(
+    targets: &[Target],
+    jobs: usize,
+    cpus: usize,
+    memory_mib: Option<u64>,
+) -> usize {
+    let largest_cpus =
+        targets.iter().map(|target| target.vm_cpus).max().unwrap_or(1);
+    let largest_memory_mib =
+        targets.iter().map(|target| target.vm_memory_mib).max().unwrap_or(0);
+
+    let mut slots = jobs.min(targets.len());
+    slots = slots.min(cpus / largest_cpus.max(1) as usize);
+    if let Some(memory_mib) = memory_mib {
+        let per_vm = largest_memory_mib + MEMORY_MARGIN_MIB;
+        slots = slots.min((memory_mib / per_vm) as usize);
+    }
+
+    // A VM that doesn't fit on its own fails its build when it launches,
+    // saying why.
+    slots.max(1)
+}
+
+fn describe_vm_slots(
+    vm_slots: usize,
+    cpus: usize,
+    memory_mib: Option<u64>,
+) -> String {
+    let plural = |count: usize, noun: &str| {
+        if count == 1 {
+            format!("1 {noun}")
+        } else {
+            format!("{count} {noun}s")
+        }
+    };
+    let cpus = plural(cpus, "CPU");
+    let vms = plural(vm_slots, "installation VM");
+    match memory_mib {
+        Some(memory_mib) => format!(
+            "the host's {cpus} and {memory_mib} MiB of available memory have \
+            room for {vms} at a time"
+        ),
+        None => format!("the host's {cpus} have room for {vms} at a time"),
+    }
+}
+
+/// Returns the JSON progress event `line`, which the build of the target
+/// `name` wrote, with the target's name added.
+fn with_target(line: &str, name: &str) -> Json {
+    let mut event = Json::object().with("target", name);
+    match Json::parse(line) {
+        Ok(Json::Object(members)) => {
+            for (key, value) in members {
+                event.insert(&key, value);
+            }
+        }
+        _ => event.insert("output", line),
+    }
+    event
+}
+
+/// Describes the progress event `event` in a line of text, or returns `None`
+/// if it isn't worth printing.
+fn describe_event(event: &Json) -> Option<String> {
+    let field = |key| event.get(key).and_then(Json::as_str).unwrap_or("");
+    match field("event") {
+        "step_started" => {
+            let number = event.get("number").and_then(Json::as_i64)? + 1;
+            let mut line = match event.get("total").and_then(Json::as_i64) {
+                Some(total) => format!("step {number}/{total}: "),
+                None => format!("step {number}: "),
+            };
+            line.push_str(field("label"));
+            if let Some(attempt) = event
+                .get("attempt")
+                .and_then(Json::as_i64)
+                .filter(|&attempt| attempt > 1)
+            {
+                line.push_str(&format!(" (attempt {attempt})"));
+            }
+            Some(line)
+        }
+        "substep" => Some(format!("  {}", field("message"))),
+        "warning" => Some(format!(
+            "{} {}: {}",
+            "Warning:".bold().yellow(),
+            field("step"),
+            field("message")
+        )),
+        "step_finished" if field("outcome") == "failed" => Some(format!(
+            "{} {}: {}",
+            "Failed:".bold().red(),
+            field("step"),
+            field("error")
+        )),
+        _ => None,
+    }
+}
+
+/// Lays out how each target's build went as a table with a header row.
+fn summary_table(
+    targets: &[Target],
+    outcomes: &[(Outcome, Duration)],
+) -> Vec<String> {
+    let header = ["TARGET", "RESULT", "TIME", "OUTPUT IMAGE"];
+    let mut rows = vec![header.map(str::to_string)];
+    for (target, (outcome, elapsed)) in targets.iter().zip(outcomes) {
+        rows.push([
+            target.name.clone(),
+            outcome.to_string(),
+            match outcome {
+                Outcome::NotStarted(_) => "-".to_string(),
+                _ => crate::ui::format_elapsed(*elapsed),
+            },
+            target.output_image.to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 3];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            format!(
+                "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(name: &str, vm_cpus: u32, vm_memory_mib: u64) -> Target {
+        Target {
+            name: name.to_string(),
+            output_image: format!("out/{name}.img").into(),
+            vm_cpus,
+            vm_memory_mib,
+        }
+    }
+
+    #[test]
+    fn passes_build_options_to_targets() {
+        let app = App::try_parse_from([
+            "wimsy",
+            "--work-dir=/tmp/matrix",
+            "--config=build.toml",
+            "--resume",
+            "--install-timeout=90",
+            "--tools-container=example.com/tools",
+            "-vv",
+            "build-all",
+            "--jobs=2",
+            "--",
+            "--unattend-dir=unattend",
+        ])
+        .unwrap();
+        let sharing = Sharing {
+            slots_dir: Some(Utf8Path::new("/tmp/matrix/slots")),
+            json_progress: true,
+            interactive: false,
+        };
+
+        let args =
+            target_args(&app, Utf8Path::new("build.toml"), "ws2022", &sharing);
+        assert_eq!(
+            args[..7],
+            [
+                "--work-dir=/tmp/matrix/ws2022",
+                "--config=build.toml",
+                "--target=ws2022",
+                "--keep-work-dir=on-failure",
+                "--interactive=false",
+                "--progress=json",
+                "--slots-dir=/tmp/matrix/slots",
+            ]
+        );
+        assert!(args.contains(&"--resume".to_string()));
+        assert!(args.contains(&"--install-timeout=90".to_string()));
+        assert!(
+            args.contains(&"--tools-container=example.com/tools".to_string())
+        );
+        assert!(args.contains(&"-vv".to_string()));
+        assert!(!args.contains(&"--dry-run".to_string()));
+        assert_eq!(args.last().unwrap(), "create-guest-disk-image");
+
+        // Every option passed along must be one the child accepts.
+        let mut child = vec!["wimsy".to_string()];
+        child.extend(args);
+        child.push("--unattend-dir=unattend".to_string());
+        assert!(App::try_parse_from(child).is_ok());
+    }
+
+    #[test]
+    fn sizes_vm_slots_to_host() {
+        let targets = [target("a", 4, 8192), target("b", 2, 4096)];
+
+        // Limited by the number of jobs, CPUs, and memory in turn.
+        assert_eq!(vm_slots(&targets, 2, 64, Some(65536)), 2);
+        assert_eq!(vm_slots(&targets, 4, 8, Some(65536)), 2);
+        assert_eq!(vm_slots(&targets, 4, 64, Some(20000)), 2);
+        assert_eq!(vm_slots(&targets, 4, 64, None), 2);
+
+        // There's always room for one VM.
+        assert_eq!(vm_slots(&targets, 4, 2, Some(1024)), 1);
+    }
+
+    #[test]
+    fn describes_progress_events() {
+        let event = |text: &str| Json::parse(text).unwrap();
+        assert_eq!(
+            describe_event(&event(
+                r#"{"event":"build_started","steps":["a","b","c"]}"#
+            )),
+            None
+        );
+        assert_eq!(
+            describe_event(&event(
+                r#"{"event":"step_started","step":"b","label":"do b",
+                "number":1,"total":3,"attempt":2}"#
+            ))
+            .as_deref(),
+            Some("step 2/3: do b (attempt 2)")
+        );
+        assert_eq!(
+            describe_event(&event(
+                r#"{"event":"command","step":"b","program":"x"}"#
+            )),
+            None
+        );
+
+        let tagged = with_target(r#"{"event":"substep","time":1}"#, "ws2022");
+        assert_eq!(
+            tagged.to_string(),
+            r#"{"target":"ws2022","event":"substep","time":1}"#
+        );
+    }
+
+    #[test]
+    fn lays_out_summary() {
+        let targets =
+            [target("ws2019", 2, 4096), target("ws2022-datacenter", 2, 4096)];
+        let table = summary_table(
+            &targets,
+            &[
+                (Outcome::Succeeded, Duration::from_secs(2530)),
+                (Outcome::NotStarted("no space".to_string()), Duration::ZERO),
+            ],
+        );
+        assert_eq!(
+            table,
+            [
+                "TARGET             RESULT                   TIME     \
+                OUTPUT IMAGE",
+                "ws2019             succeeded                42m 10s  \
+                out/ws2019.img",
+                "ws2022-datacenter  didn't start (no space)  -        \
+                out/ws2022-datacenter.img",
+            ]
+        );
+    }
+}
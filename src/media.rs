@@ -0,0 +1,914 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads files from Windows installation media without mounting them.
+//!
+//! Microsoft's installation ISOs are "UDF bridge" discs: they carry both a
+//! UDF file system and an ISO 9660 one (with Joliet names) describing the same
+//! files. The UDF file system is used when it's present, since ISO 9660 can't
+//! describe files of 4 GiB or more except by splitting them into several
+//! extents, which not all authoring tools do; the ISO 9660 file system is used
+//! for discs that don't have UDF, such as those written by [`crate::iso`].
+//!
+//! Only the parts of each format that read-only, single-volume media use are
+//! supported: UDF volumes must have a single type 1 (physical) partition map.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::trace;
+
+const SECTOR_SIZE: u64 = 2048;
+
+/// The sector holding the first ISO 9660 volume descriptor (or UDF volume
+/// recognition descriptor).
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+
+/// How many sectors of volume descriptors to look through before giving up.
+const MAX_DESCRIPTORS: u64 = 64;
+
+/// The sector holding the UDF anchor volume descriptor pointer.
+const UDF_ANCHOR_SECTOR: u64 = 256;
+
+/// The largest directory this reader will load. Directories on real
+/// installation media are a small fraction of this.
+const MAX_DIR_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The magic number at the start of WIM (and ESD) files.
+pub const WIM_MAGIC: &[u8; 8] = b"MSWIM\0\0\0";
+
+/// The paths at which installation media keep the Windows image, in the order
+/// they're tried.
+pub const INSTALL_IMAGE_PATHS: [&str; 3] =
+    ["sources/install.wim", "sources/install.esd", "sources/install.swm"];
+
+/// Part of a file's contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    /// `len` bytes stored at byte `offset` of the image.
+    Disk { offset: u64, len: u64 },
+
+    /// `len` unrecorded bytes, which read as zeroes.
+    Zeros(u64),
+
+    /// Bytes stored in the file's UDF file entry.
+    Inline(Vec<u8>),
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Disk { len, .. } | Piece::Zeros(len) => *len,
+            Piece::Inline(bytes) => bytes.len() as u64,
+        }
+    }
+}
+
+/// A file or directory on installation media.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pieces: Vec<Piece>,
+}
+
+/// The file system an image is read through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Filesystem {
+    Udf { partition_start: u64, block_size: u64 },
+    Iso9660 { joliet: bool },
+}
+
+/// An open installation media image.
+pub struct InstallMedia<R> {
+    reader: R,
+    fs: Filesystem,
+    root: Entry,
+}
+
+fn u16_le(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_le(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn utf16_be(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Decodes an ISO 9660 file identifier, dropping its version suffix (";1")
+/// and the separator that ends names without an extension.
+fn iso_name(ident: &[u8], joliet: bool, is_dir: bool) -> String {
+    let name = if joliet {
+        utf16_be(ident)
+    } else {
+        ident.iter().map(|&b| b as char).collect()
+    };
+
+    if is_dir {
+        return name;
+    }
+
+    let name = name.split_once(';').map_or(name.as_str(), |(name, _)| name);
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+/// Parses the ISO 9660 directory record at the start of `record` into an
+/// entry, returning it and whether more extents of the same file follow.
+fn parse_iso_record(record: &[u8], joliet: bool) -> Result<(Entry, bool)> {
+    if record.len() < 34 || record.len() < 33 + record[32] as usize {
+        anyhow::bail!("truncated ISO 9660 directory record");
+    }
+
+    let extent = u32_le(record, 2) as u64;
+    let size = u32_le(record, 10) as u64;
+    let flags = record[25];
+    let is_dir = flags & 0x02 != 0;
+    let ident = &record[33..33 + record[32] as usize];
+    let entry = Entry {
+        name: iso_name(ident, joliet, is_dir),
+        is_dir,
+        size,
+        pieces: vec![Piece::Disk { offset: extent * SECTOR_SIZE, len: size }],
+    };
+
+    Ok((entry, flags & 0x80 != 0))
+}
+
+/// Parses the contents of an ISO 9660 directory.
+fn parse_iso_dir(data: &[u8], joliet: bool) -> Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut continues = false;
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        if len == 0 {
+            // Records don't cross sector boundaries; the rest of this sector
+            // is padding.
+            pos = (pos + 1).next_multiple_of(SECTOR_SIZE as usize);
+            continue;
+        }
+
+        let record = data
+            .get(pos..pos + len)
+            .context("ISO 9660 directory record runs off the directory")?;
+        pos += len;
+
+        // Skip the "." and ".." records.
+        if record.len() > 33 && record[32] == 1 && record[33] <= 1 {
+            continue;
+        }
+
+        let (entry, more) = parse_iso_record(record, joliet)?;
+        match entries.last_mut() {
+            Some(last) if continues => {
+                last.size += entry.size;
+                last.pieces.extend(entry.pieces);
+            }
+            _ => entries.push(entry),
+        }
+        continues = more;
+    }
+
+    Ok(entries)
+}
+
+/// Decodes a UDF file identifier ("OSTA compressed Unicode").
+fn udf_name(ident: &[u8]) -> String {
+    match ident.split_first() {
+        Some((8, rest)) => rest.iter().map(|&b| b as char).collect(),
+        Some((16, rest)) => utf16_be(rest),
+        _ => String::new(),
+    }
+}
+
+/// Checks that `block` starts with a UDF descriptor tag with identifier
+/// `id`. The tag's checksum is verified; its CRC isn't.
+fn check_udf_tag(block: &[u8], id: u16) -> Result<()> {
+    if block.len() < 16 {
+        anyhow::bail!("truncated UDF descriptor");
+    }
+
+    let checksum = block[..16]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 4)
+        .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    if checksum != block[4] {
+        anyhow::bail!("UDF descriptor has a bad tag checksum");
+    }
+
+    let found = u16_le(block, 0);
+    if found != id {
+        anyhow::bail!("expected UDF descriptor {id}, found {found}");
+    }
+
+    Ok(())
+}
+
+impl InstallMedia<File> {
+    /// Opens the ISO image at `path`.
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("opening '{path}'"))?;
+        Self::new(file).with_context(|| format!("reading ISO '{path}'"))
+    }
+}
+
+impl<R: Read + Seek> InstallMedia<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut primary = None;
+        let mut joliet = None;
+        let mut has_udf = false;
+        for sector in
+            FIRST_DESCRIPTOR_SECTOR..FIRST_DESCRIPTOR_SECTOR + MAX_DESCRIPTORS
+        {
+            let mut desc = vec![0; SECTOR_SIZE as usize];
+            reader.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            if reader.read_exact(&mut desc).is_err() {
+                break;
+            }
+
+            match &desc[1..6] {
+                b"CD001" => match desc[0] {
+                    1 => primary = Some(desc),
+                    2 if matches!(&desc[88..91], b"%/@" | b"%/C" | b"%/E") => {
+                        joliet = Some(desc)
+                    }
+                    _ => {}
+                },
+                b"NSR02" | b"NSR03" => has_udf = true,
+                b"BEA01" | b"TEA01" | b"BOOT2" | b"CDW02" => {}
+                _ => break,
+            }
+        }
+
+        if has_udf {
+            match Self::open_udf(&mut reader) {
+                Ok((fs, root)) => return Ok(Self { reader, fs, root }),
+                Err(e) if primary.is_some() => {
+                    trace::debug!(
+                        "falling back to ISO 9660",
+                        error = format!("{e:#}")
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (desc, joliet) = match (joliet, primary) {
+            (Some(desc), _) => (desc, true),
+            (None, Some(desc)) => (desc, false),
+            (None, None) => {
+                anyhow::bail!("no ISO 9660 or UDF file system found")
+            }
+        };
+
+        let (mut root, _) = parse_iso_record(&desc[156..190], joliet)?;
+        root.name = String::new();
+        Ok(Self { reader, fs: Filesystem::Iso9660 { joliet }, root })
+    }
+
+    /// Finds the root directory of the UDF file system.
+    fn open_udf(reader: &mut R) -> Result<(Filesystem, Entry)> {
+        let anchor = read_bytes(
+            reader,
+            UDF_ANCHOR_SECTOR * SECTOR_SIZE,
+            SECTOR_SIZE as usize,
+        )?;
+        check_udf_tag(&anchor, 2).context("reading UDF anchor")?;
+
+        // Find the partition and the file set in the main volume descriptor
+        // sequence.
+        let vds_len = u32_le(&anchor, 16) as u64;
+        let vds_start = u32_le(&anchor, 20) as u64;
+        let mut partitions = Vec::new();
+        let mut volume = None;
+        for sector in vds_start..vds_start + vds_len.div_ceil(SECTOR_SIZE) {
+            let desc =
+                read_bytes(reader, sector * SECTOR_SIZE, SECTOR_SIZE as usize)?;
+            match u16_le(&desc, 0) {
+                5 => {
+                    check_udf_tag(&desc, 5)?;
+                    partitions.push((u16_le(&desc, 22), u32_le(&desc, 188)));
+                }
+                6 => {
+                    check_udf_tag(&desc, 6)?;
+                    volume = Some(desc);
+                }
+                8 => break,
+                _ => {}
+            }
+        }
+
+        let volume = volume.context("UDF volume has no logical volume")?;
+        let block_size = u32_le(&volume, 212) as u64;
+        if u32_le(&volume, 268) != 1 || volume[440] != 1 {
+            anyhow::bail!(
+                "only UDF volumes with one physical partition are supported"
+            );
+        }
+
+        let partition_number = u16_le(&volume, 444);
+        let start = partitions
+            .iter()
+            .find(|(number, _)| *number == partition_number)
+            .map(|(_, start)| *start as u64)
+            .context("UDF volume's partition not found")?;
+        let fs =
+            Filesystem::Udf { partition_start: start * block_size, block_size };
+
+        // The file set descriptor's location is a long_ad in the logical
+        // volume descriptor's "contents use" field.
+        let file_set_block = u32_le(&volume, 252) as u64;
+        let file_set = read_bytes(
+            reader,
+            start * block_size + file_set_block * block_size,
+            block_size as usize,
+        )?;
+        check_udf_tag(&file_set, 256).context("reading UDF file set")?;
+
+        let root_block = u32_le(&file_set, 404) as u64;
+        let root = read_udf_entry(reader, fs, root_block, String::new())?;
+        Ok((fs, root))
+    }
+
+    /// Describes the file system this image is being read through.
+    pub fn filesystem(&self) -> &'static str {
+        match self.fs {
+            Filesystem::Udf { .. } => "UDF",
+            Filesystem::Iso9660 { joliet: true } => "ISO 9660 (Joliet)",
+            Filesystem::Iso9660 { joliet: false } => "ISO 9660",
+        }
+    }
+
+    pub fn root(&self) -> &Entry {
+        &self.root
+    }
+
+    /// Lists the entries in the directory `dir`.
+    pub fn read_dir(&mut self, dir: &Entry) -> Result<Vec<Entry>> {
+        if !dir.is_dir {
+            anyhow::bail!("'{}' isn't a directory", dir.name);
+        }
+
+        if dir.size > MAX_DIR_BYTES {
+            anyhow::bail!("directory '{}' is implausibly large", dir.name);
+        }
+
+        let mut data = vec![0; dir.size as usize];
+        self.read_exact_at(dir, 0, &mut data)?;
+        match self.fs {
+            Filesystem::Iso9660 { joliet } => parse_iso_dir(&data, joliet),
+            Filesystem::Udf { .. } => self.parse_udf_dir(&data),
+        }
+    }
+
+    /// Parses the file identifier descriptors in a UDF directory's contents.
+    fn parse_udf_dir(&mut self, data: &[u8]) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 38 <= data.len() {
+            let fid = &data[pos..];
+            check_udf_tag(fid, 257).context("reading UDF directory")?;
+            let characteristics = fid[18];
+            let name_len = fid[19] as usize;
+            let block = u32_le(fid, 24) as u64;
+            let impl_len = u16_le(fid, 36) as usize;
+            let len = 38 + impl_len + name_len;
+            let name = fid
+                .get(38 + impl_len..len)
+                .context("truncated UDF file identifier")?;
+            pos += len.next_multiple_of(4);
+
+            // Skip deleted entries and the parent directory.
+            if characteristics & 0x0c != 0 {
+                continue;
+            }
+
+            let name = udf_name(name);
+            entries.push(read_udf_entry(
+                &mut self.reader,
+                self.fs,
+                block,
+                name,
+            )?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Finds the entry at `path`, a `/`-separated path relative to the root
+    /// of the image. Names are matched case-insensitively, as Windows does.
+    pub fn find(&mut self, path: &str) -> Result<Option<Entry>> {
+        let mut entry = self.root.clone();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !entry.is_dir {
+                return Ok(None);
+            }
+
+            let children = self.read_dir(&entry)?;
+            let Some(child) = children
+                .into_iter()
+                .find(|child| child.name.eq_ignore_ascii_case(component))
+            else {
+                return Ok(None);
+            };
+            entry = child;
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Reads bytes from `entry` starting at `offset` into `buf`, returning
+    /// the number of bytes read, which is less than `buf.len()` only at the
+    /// end of the file.
+    pub fn read_at(
+        &mut self,
+        entry: &Entry,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let end = entry.size.min(offset.saturating_add(buf.len() as u64));
+        let mut piece_start = 0;
+        let mut read = 0;
+        for piece in &entry.pieces {
+            let piece_end = piece_start + piece.len();
+            let from = (offset + read as u64).max(piece_start);
+            let to = end.min(piece_end);
+            if from < to {
+                let dst = &mut buf[read..read + (to - from) as usize];
+                let skip = from - piece_start;
+                match piece {
+                    Piece::Disk { offset, .. } => {
+                        self.reader.seek(SeekFrom::Start(offset + skip))?;
+                        self.reader.read_exact(dst).with_context(|| {
+                            format!("reading '{}'", entry.name)
+                        })?;
+                    }
+                    Piece::Zeros(_) => dst.fill(0),
+                    Piece::Inline(bytes) => dst.copy_from_slice(
+                        &bytes[skip as usize..skip as usize + dst.len()],
+                    ),
+                }
+                read += dst.len();
+            }
+
+            piece_start = piece_end;
+            if piece_start >= end {
+                break;
+            }
+        }
+
+        Ok(read)
+    }
+
+    fn read_exact_at(
+        &mut self,
+        entry: &Entry,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        if self.read_at(entry, offset, buf)? != buf.len() {
+            anyhow::bail!("'{}' is shorter than its recorded size", entry.name);
+        }
+
+        Ok(())
+    }
+
+    /// Copies the file `entry` to `dest`.
+    pub fn extract(&mut self, entry: &Entry, dest: &Utf8Path) -> Result<()> {
+        use std::io::Write;
+
+        let mut out = std::io::BufWriter::new(
+            File::create(dest).with_context(|| format!("creating '{dest}'"))?,
+        );
+        let mut buf = vec![0; 1024 * 1024];
+        let mut offset = 0;
+        while offset < entry.size {
+            let read = self.read_at(entry, offset, &mut buf)?;
+            if read == 0 {
+                anyhow::bail!(
+                    "'{}' is shorter than its recorded size",
+                    entry.name
+                );
+            }
+
+            out.write_all(&buf[..read])
+                .with_context(|| format!("writing '{dest}'"))?;
+            offset += read as u64;
+        }
+
+        out.into_inner()
+            .map_err(|e| e.into_error())
+            .with_context(|| format!("writing '{dest}'"))?;
+        Ok(())
+    }
+
+    /// Copies the contents of the directory `dir` to `dest`, skipping files
+    /// whose paths relative to `dir` (using `/` separators) `skip` matches.
+    pub fn extract_tree(
+        &mut self,
+        dir: &Entry,
+        dest: &Utf8Path,
+        skip: &dyn Fn(&str) -> bool,
+    ) -> Result<()> {
+        self.extract_tree_at(dir, dest, "", skip)
+    }
+
+    fn extract_tree_at(
+        &mut self,
+        dir: &Entry,
+        dest: &Utf8Path,
+        prefix: &str,
+        skip: &dyn Fn(&str) -> bool,
+    ) -> Result<()> {
+        std::fs::create_dir_all(dest)
+            .with_context(|| format!("creating '{dest}'"))?;
+        for child in self.read_dir(dir)? {
+            let path = format!("{prefix}{}", child.name);
+            if skip(&path) {
+                continue;
+            }
+
+            let child_dest = dest.join(&child.name);
+            if child.is_dir {
+                self.extract_tree_at(
+                    &child,
+                    &child_dest,
+                    &format!("{path}/"),
+                    skip,
+                )?;
+            } else {
+                self.extract(&child, &child_dest)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_bytes<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf).context("reading past the end of the image")?;
+    Ok(buf)
+}
+
+/// Reads the UDF file entry at logical block `block` of the partition.
+fn read_udf_entry<R: Read + Seek>(
+    reader: &mut R,
+    fs: Filesystem,
+    block: u64,
+    name: String,
+) -> Result<Entry> {
+    let Filesystem::Udf { partition_start, block_size } = fs else {
+        unreachable!("UDF entries are only read from UDF file systems");
+    };
+
+    let block_offset = |block: u64| partition_start + block * block_size;
+    let data = read_bytes(reader, block_offset(block), block_size as usize)?;
+    let (ea_len_at, ad_start) = match u16_le(&data, 0) {
+        261 => (168, 176),
+        266 => (208, 216),
+        id => {
+            anyhow::bail!("expected a UDF file entry for '{name}', found {id}")
+        }
+    };
+    check_udf_tag(&data, u16_le(&data, 0))?;
+
+    let is_dir = data[27] == 4;
+    let size = u64_le(&data, 56);
+    let ad_type = u16_le(&data, 34) & 0x7;
+    let start = ad_start + u32_le(&data, ea_len_at) as usize;
+    let ad_len = u32_le(&data, ea_len_at + 4) as usize;
+    let mut descriptors = data
+        .get(start..start + ad_len)
+        .with_context(|| format!("truncated UDF file entry for '{name}'"))?
+        .to_vec();
+
+    if ad_type == 3 {
+        let pieces = vec![Piece::Inline(descriptors)];
+        return Ok(Entry { name, is_dir, size, pieces });
+    }
+
+    let ad_size = match ad_type {
+        0 => 8,
+        1 => 16,
+        _ => {
+            anyhow::bail!("unsupported UDF allocation descriptors in '{name}'")
+        }
+    };
+
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+    while pos + ad_size <= descriptors.len() {
+        let raw_len = u32_le(&descriptors, pos);
+        let location = u32_le(&descriptors, pos + 4) as u64;
+        pos += ad_size;
+
+        let len = (raw_len & 0x3fff_ffff) as u64;
+        if len == 0 {
+            break;
+        }
+
+        match raw_len >> 30 {
+            0 => {
+                pieces.push(Piece::Disk { offset: block_offset(location), len })
+            }
+            3 => {
+                // The descriptors continue in an allocation extent
+                // descriptor.
+                let next = read_bytes(
+                    reader,
+                    block_offset(location),
+                    block_size as usize,
+                )?;
+                check_udf_tag(&next, 258)?;
+                let next_len = u32_le(&next, 20) as usize;
+                descriptors = next
+                    .get(24..24 + next_len)
+                    .context("truncated UDF allocation extent")?
+                    .to_vec();
+                pos = 0;
+            }
+            _ => pieces.push(Piece::Zeros(len)),
+        }
+    }
+
+    Ok(Entry { name, is_dir, size, pieces })
+}
+
+/// Returns the Windows image on `media`, if it has one at one of
+/// [`INSTALL_IMAGE_PATHS`].
+pub fn find_install_image<R: Read + Seek>(
+    media: &mut InstallMedia<R>,
+) -> Result<Option<Entry>> {
+    for image_path in INSTALL_IMAGE_PATHS {
+        if let Some(entry) = media.find(image_path)? {
+            if !entry.is_dir {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Checks that the image at `path` looks like Windows installation media,
+/// returning warnings describing any problems.
+pub fn check_installation_media(path: &Utf8Path) -> Vec<String> {
+    let mut media = match InstallMedia::open(path) {
+        Ok(media) => media,
+        Err(e) => return vec![format!("{e:#}")],
+    };
+
+    let entry = match find_install_image(&mut media) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            return vec![format!(
+                "Windows ISO '{path}' has no {}; it may not be Windows \
+                installation media",
+                INSTALL_IMAGE_PATHS.join(" or ")
+            )];
+        }
+        Err(e) => return vec![format!("reading '{path}': {e:#}")],
+    };
+
+    let mut magic = [0; 8];
+    match media.read_at(&entry, 0, &mut magic) {
+        Ok(8) if &magic == WIM_MAGIC => Vec::new(),
+        Ok(_) => vec![format!(
+            "'sources/{}' in Windows ISO '{path}' isn't a Windows image",
+            entry.name
+        )],
+        Err(e) => vec![format!("reading '{path}': {e:#}")],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_written_isos() {
+        let tmp = camino::Utf8PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("wimsy-media-test-{}", std::process::id()));
+        let src = tmp.join("src");
+        std::fs::create_dir_all(src.join("sources/Nested Dir")).unwrap();
+        let mut wim = WIM_MAGIC.to_vec();
+        wim.extend((0..5000u32).map(|i| i as u8));
+        std::fs::write(src.join("sources/install.wim"), &wim).unwrap();
+        std::fs::write(src.join("sources/Nested Dir/a file.txt"), "hi")
+            .unwrap();
+        std::fs::write(src.join("setup.exe"), "").unwrap();
+        let iso = tmp.join("test.iso");
+        crate::iso::create_iso(&src, &iso, "TEST").unwrap();
+
+        let mut media = InstallMedia::open(&iso).unwrap();
+        assert_eq!(media.filesystem(), "ISO 9660 (Joliet)");
+        let root = media.root().clone();
+        let mut names: Vec<String> = media
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["setup.exe", "sources"]);
+
+        let entry = media.find("SOURCES/install.WIM").unwrap().unwrap();
+        assert_eq!(entry.size, wim.len() as u64);
+        let mut buf = vec![0; 10];
+        assert_eq!(media.read_at(&entry, 4995, &mut buf).unwrap(), 10);
+        assert_eq!(buf, wim[4995..5005]);
+        assert_eq!(media.read_at(&entry, 5000, &mut buf).unwrap(), 8);
+
+        let nested = media.find("sources/nested dir/a file.txt").unwrap();
+        assert_eq!(nested.unwrap().size, 2);
+        assert!(media.find("sources/missing").unwrap().is_none());
+        assert!(check_installation_media(&iso).is_empty());
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    /// Builds a small UDF image: a root directory holding `sources`
+    /// (containing a two-extent `install.wim`), an embedded `readme.txt`, and
+    /// a deleted entry.
+    fn udf_image(wim: &[u8]) -> Vec<u8> {
+        const PARTITION: usize = 300;
+        let mut image = vec![0; 2048 * 320];
+        let mut put = |sector: usize, bytes: &[u8]| {
+            image[sector * 2048..sector * 2048 + bytes.len()]
+                .copy_from_slice(bytes);
+        };
+        let tag = |mut desc: Vec<u8>, id: u16| {
+            desc[0..2].copy_from_slice(&id.to_le_bytes());
+            desc[2] = 2;
+            desc[4] = (0..16)
+                .filter(|&i| i != 4)
+                .fold(0u8, |sum, i| sum.wrapping_add(desc[i]));
+            desc
+        };
+        let set = |desc: &mut [u8], offset: usize, bytes: &[u8]| {
+            desc[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+
+        for (i, id) in [b"BEA01", b"NSR02", b"TEA01"].iter().enumerate() {
+            put(16 + i, &[&[0][..], &id[..], &[1]].concat());
+        }
+
+        let mut anchor = vec![0; 2048];
+        set(&mut anchor, 16, &(3 * 2048u32).to_le_bytes());
+        set(&mut anchor, 20, &257u32.to_le_bytes());
+        put(256, &tag(anchor, 2));
+
+        let mut partition = vec![0; 2048];
+        set(&mut partition, 188, &(PARTITION as u32).to_le_bytes());
+        put(257, &tag(partition, 5));
+
+        let mut volume = vec![0; 2048];
+        set(&mut volume, 212, &2048u32.to_le_bytes());
+        set(&mut volume, 268, &1u32.to_le_bytes());
+        set(&mut volume, 440, &[1, 6]);
+        put(258, &tag(volume, 6));
+        put(259, &tag(vec![0; 2048], 8));
+
+        let mut file_set = vec![0; 2048];
+        set(&mut file_set, 404, &1u32.to_le_bytes());
+        put(PARTITION, &tag(file_set, 256));
+
+        let file_entry =
+            |file_type: u8, size: usize, ad_type: u16, ads: &[u8]| {
+                let mut entry = vec![0; 2048];
+                entry[27] = file_type;
+                set(&mut entry, 34, &ad_type.to_le_bytes());
+                set(&mut entry, 56, &(size as u64).to_le_bytes());
+                set(&mut entry, 172, &(ads.len() as u32).to_le_bytes());
+                set(&mut entry, 176, ads);
+                tag(entry, 261)
+            };
+        let short_ad = |len: usize, block: u32| {
+            [(len as u32).to_le_bytes(), block.to_le_bytes()].concat()
+        };
+        let fid = |name: &str, characteristics: u8, block: u32| {
+            let ident: Vec<u8> = if name.is_empty() {
+                Vec::new()
+            } else {
+                [&[8][..], name.as_bytes()].concat()
+            };
+            let mut fid = vec![0; 38 + ident.len()];
+            fid[18] = characteristics;
+            fid[19] = ident.len() as u8;
+            set(&mut fid, 24, &block.to_le_bytes());
+            set(&mut fid, 38, &ident);
+            let mut fid = tag(fid, 257);
+            fid.resize(fid.len().next_multiple_of(4), 0);
+            fid
+        };
+
+        let root = [
+            fid("", 0x0a, 1),
+            fid("sources", 0x02, 3),
+            fid("readme.txt", 0, 10),
+            fid("gone", 0x04, 10),
+        ]
+        .concat();
+        put(
+            PARTITION + 1,
+            &file_entry(4, root.len(), 0, &short_ad(root.len(), 2)),
+        );
+        put(PARTITION + 2, &root);
+
+        let sources = [fid("", 0x0a, 1), fid("install.wim", 0, 5)].concat();
+        put(
+            PARTITION + 3,
+            &file_entry(4, sources.len(), 0, &short_ad(sources.len(), 4)),
+        );
+        put(PARTITION + 4, &sources);
+
+        let ads = [short_ad(4096, 6), short_ad(wim.len() - 4096, 8)].concat();
+        put(PARTITION + 5, &file_entry(5, wim.len(), 0, &ads));
+        put(PARTITION + 6, &wim[..4096]);
+        put(PARTITION + 8, &wim[4096..]);
+        put(PARTITION + 10, &file_entry(5, 5, 3, b"hello"));
+        image
+    }
+
+    #[test]
+    fn reads_udf_images() {
+        let mut wim = WIM_MAGIC.to_vec();
+        wim.extend((0..5000u32).map(|i| (i % 251) as u8));
+        let image = udf_image(&wim);
+        let mut media = InstallMedia::new(std::io::Cursor::new(image)).unwrap();
+        assert_eq!(media.filesystem(), "UDF");
+
+        let root = media.root().clone();
+        let names: Vec<String> = media
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["sources", "readme.txt"]);
+
+        let entry = media.find("Sources/Install.wim").unwrap().unwrap();
+        assert_eq!(entry.size, wim.len() as u64);
+        let mut buf = vec![0; 100];
+        assert_eq!(media.read_at(&entry, 4050, &mut buf).unwrap(), 100);
+        assert_eq!(buf, wim[4050..4150]);
+
+        let readme = media.find("readme.txt").unwrap().unwrap();
+        let mut buf = vec![0; 10];
+        assert_eq!(media.read_at(&readme, 1, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ello");
+    }
+
+    #[test]
+    fn joins_multi_extent_files() {
+        let record = |ident: &[u8], extent: u32, size: u32, flags: u8| {
+            let mut record = vec![0; 34 + ident.len() - ident.len() % 2];
+            record[0] = record.len() as u8;
+            record[2..6].copy_from_slice(&extent.to_le_bytes());
+            record[10..14].copy_from_slice(&size.to_le_bytes());
+            record[25] = flags;
+            record[32] = ident.len() as u8;
+            record[33..33 + ident.len()].copy_from_slice(ident);
+            record
+        };
+
+        let mut dir = record(&[0], 20, 2048, 2);
+        dir.extend(record(&[1], 20, 2048, 2));
+        dir.extend(record(b"BIG.WIM;1", 30, 4096, 0x80));
+        dir.extend(record(b"BIG.WIM;1", 32, 100, 0));
+        dir.extend(record(b"README.;1", 40, 5, 0));
+        let entries = parse_iso_dir(&dir, false).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "BIG.WIM");
+        assert_eq!(entries[0].size, 4196);
+        assert_eq!(entries[0].pieces.len(), 2);
+        assert_eq!(entries[1].name, "README");
+    }
+}
@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that the host has enough free memory to run the installation VM.
+
+use anyhow::{Context as _, Result};
+
+use crate::{runner::Context, trace, ui::Ui};
+
+/// Memory, in MiB, to require on top of the guest's memory to account for
+/// the VMM's own overhead and to leave the host some room.
+pub const MEMORY_MARGIN_MIB: u64 = 512;
+
+/// Returns the amount of memory, in MiB, that can be allocated on this host
+/// without swapping.
+#[cfg(target_os = "linux")]
+pub fn available_mib() -> Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")
+        .context("reading /proc/meminfo")?;
+    for line in meminfo.lines() {
+        // Format: "MemAvailable:    1863680 kB"
+        if let Some(rest) = line.strip_prefix("MemAvailable:") {
+            let kb: u64 = rest
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .parse()
+                .with_context(|| format!("parsing '{line}'"))?;
+            return Ok(kb / 1024);
+        }
+    }
+
+    anyhow::bail!("MemAvailable not found in /proc/meminfo")
+}
+
+/// Returns the amount of memory, in MiB, that can be allocated on this host
+/// without swapping.
+#[cfg(target_os = "illumos")]
+pub fn available_mib() -> Result<u64> {
+    let output = std::process::Command::new("kstat")
+        .args(["-p", "unix:0:system_pages:availrmem"])
+        .output()
+        .context("running kstat to get available memory")?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let pages: u64 = output
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("unexpected kstat output: {output}"))?
+        .parse()
+        .with_context(|| format!("parsing kstat output '{output}'"))?;
+
+    // SAFETY: sysconf has no memory safety requirements.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as u64 / (1024 * 1024))
+}
+
+/// Checks whether a VM with `requested_mib` of memory fits in the host's
+/// available memory plus [`MEMORY_MARGIN_MIB`]. Returns a description of the
+/// shortfall if it doesn't.
+pub fn check_available(requested_mib: u64) -> Result<Option<String>> {
+    let available = available_mib()?;
+    trace::debug!(
+        "checked host memory",
+        requested_mib = requested_mib,
+        available_mib = available,
+        margin_mib = MEMORY_MARGIN_MIB
+    );
+    if requested_mib + MEMORY_MARGIN_MIB <= available {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "not enough host memory for the installation VM: requested \
+        {requested_mib} MiB, host has {available} MiB available (the VM needs \
+        an additional {MEMORY_MARGIN_MIB} MiB for its own overhead)"
+    )))
+}
+
+/// Adds the result of checking whether a VM with `requested_mib` of memory
+/// fits on this host to a script's prerequisite `errors` or, if `force` is
+/// set, its `warnings`.
+pub fn check_prerequisites(
+    requested_mib: u64,
+    force: bool,
+    errors: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) {
+    match check_available(requested_mib) {
+        Ok(None) => {}
+        Ok(Some(problem)) if force => warnings.push(format!(
+            "{problem}. Continuing anyway because --force-memory was passed."
+        )),
+        Ok(Some(problem)) => errors.push(format!(
+            "{problem}. Free some memory or pass --force-memory to build \
+            anyway (e.g. if the host has enough swap)."
+        )),
+        Err(e) => warnings.push(format!(
+            "couldn't determine how much memory is available on the host: \
+            {e:#}"
+        )),
+    }
+}
+
+/// Checks available memory again immediately before launching a VM with
+/// `requested_mib` of memory, since it may have changed since the
+/// prerequisite check. Fails if the VM won't fit unless the user passed
+/// `--force-memory`, in which case this only warns.
+pub fn check_before_launch(
+    requested_mib: u64,
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let Some(problem) = check_available(requested_mib)? else {
+        return Ok(());
+    };
+
+    if ctx.get_var("force_memory").is_some() {
+        trace::debug!("launching despite low memory because of --force-memory");
+        ui.warn(&format!("{problem}; continuing because of --force-memory"));
+        Ok(())
+    } else {
+        anyhow::bail!(
+            "{problem}; free some memory or pass --force-memory to launch \
+            the VM anyway"
+        )
+    }
+}
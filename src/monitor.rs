@@ -0,0 +1,624 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watches the host filesystems a build writes to while a long-running child
+//! process (i.e. the installation VM) runs, so that the build can stop with a
+//! useful error before a full filesystem corrupts the guest's disk. Also
+//! watches the guest's serial output for failures reported by its setup
+//! scripts, and stops a VM that takes too long or appears to be hung.
+
+use std::{
+    ffi::CString,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    process::{Child, ExitStatus},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    error::{Error, ErrorKind},
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// The default interval, in seconds, between free space samples.
+pub const DEFAULT_INTERVAL_SECS: u64 = 10;
+
+/// The default free space, in MiB, below which to warn the user.
+pub const DEFAULT_WARN_FREE_MIB: u64 = 4096;
+
+/// The default free space, in MiB, below which to stop the build.
+pub const DEFAULT_MIN_FREE_MIB: u64 = 1024;
+
+/// The default number of minutes the installation VM may go without writing
+/// to its serial port or disk before it's considered hung.
+pub const DEFAULT_HANG_TIMEOUT_MINS: u64 = 30;
+
+/// The prefix with which guest setup scripts mark a line of serial output that
+/// reports a fatal error, e.g. `WIMSY-FAILURE: domain join failed`.
+pub const GUEST_FAILURE_MARKER: &str = "WIMSY-FAILURE:";
+
+/// How often to check whether the monitored VM has exited.
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem containing `path`.
+pub fn free_bytes(path: &Utf8Path) -> Result<u64> {
+    let c_path = CString::new(path.as_std_path().as_os_str().as_bytes())
+        .with_context(|| format!("converting '{path}' to a C string"))?;
+
+    // SAFETY: `c_path` is a valid NUL-terminated string, and `stat` is a
+    // valid, writable `statvfs` structure that outlives the call.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let rc = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| {
+            format!("getting filesystem statistics for '{path}'")
+        });
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Thresholds and sampling settings for a [`DiskSpaceMonitor`].
+#[derive(Clone, Copy, Debug)]
+pub struct DiskSpaceLimits {
+    /// How often to sample free space.
+    pub interval: Duration,
+
+    /// Free space below which to warn the user.
+    pub warn_bytes: u64,
+
+    /// Free space below which to stop the build.
+    pub fail_bytes: u64,
+}
+
+impl DiskSpaceLimits {
+    /// Reads the monitor settings from the script context, falling back to
+    /// the defaults for any that aren't set.
+    pub fn from_context(ctx: &Context) -> Result<Self> {
+        let read = |var: &str, default: u64| -> Result<u64> {
+            match ctx.get_var(var) {
+                Some(value) => value.parse().with_context(|| {
+                    format!("parsing '{value}' as the value of {var}")
+                }),
+                None => Ok(default),
+            }
+        };
+
+        Ok(Self {
+            interval: Duration::from_secs(
+                read("disk_monitor_interval_secs", DEFAULT_INTERVAL_SECS)?
+                    .max(1),
+            ),
+            warn_bytes: read("disk_warn_free_mib", DEFAULT_WARN_FREE_MIB)?
+                * MIB,
+            fail_bytes: read("disk_min_free_mib", DEFAULT_MIN_FREE_MIB)? * MIB,
+        })
+    }
+}
+
+/// How long the installation VM may run, read from the `install_timeout_mins`
+/// and `hang_timeout_mins` context variables.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProgressLimits {
+    /// How long the whole installation may take.
+    pub timeout: Option<Duration>,
+
+    /// How long the VM may go without writing to its serial port or disk.
+    pub idle: Option<Duration>,
+}
+
+impl ProgressLimits {
+    pub fn from_context(ctx: &Context) -> Result<Self> {
+        let read = |var: &str| -> Result<Option<Duration>> {
+            let Some(value) = ctx.get_var(var) else {
+                return Ok(None);
+            };
+            let mins: u64 = value.parse().with_context(|| {
+                format!("parsing '{value}' as the value of {var}")
+            })?;
+            Ok((mins > 0).then(|| Duration::from_secs(mins * 60)))
+        };
+
+        Ok(Self {
+            timeout: read("install_timeout_mins")?,
+            idle: read("hang_timeout_mins")?,
+        })
+    }
+}
+
+/// What can be seen of a disk image's contents changing without reading it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct DiskSignature {
+    len: u64,
+    modified: SystemTime,
+}
+
+impl DiskSignature {
+    fn read(path: &Utf8Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        Some(Self { len: metadata.len(), modified: metadata.modified().ok()? })
+    }
+}
+
+/// Decides whether an installation VM is still making progress: whether
+/// it has run for longer than its timeout, and whether it has gone too long
+/// without writing to its serial port or its disk image. Its clock starts
+/// when it's created, so a VM that's booted several times is timed from its
+/// first boot.
+pub struct Watchdog {
+    limits: ProgressLimits,
+    started: Instant,
+    last_activity: Instant,
+    disk: Option<(Utf8PathBuf, Option<DiskSignature>)>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog for a VM installing to the disk image at `disk`,
+    /// if it's local. Hang detection needs to see disk writes, so it's
+    /// disabled if `disk` is `None` or isn't a regular file: a long stretch
+    /// of Windows Setup writes nothing to the serial port.
+    pub fn new(limits: ProgressLimits, disk: Option<&Utf8Path>) -> Self {
+        let disk = disk.and_then(|path| {
+            DiskSignature::read(path)
+                .map(|signature| (path.to_path_buf(), Some(signature)))
+        });
+        let limits = if disk.is_none() && limits.idle.is_some() {
+            trace::debug!(
+                "can't watch the installation disk; hang detection disabled"
+            );
+            ProgressLimits { idle: None, ..limits }
+        } else {
+            limits
+        };
+
+        let now = Instant::now();
+        Self { limits, started: now, last_activity: now, disk }
+    }
+
+    /// Returns an error describing why the VM should be stopped, if it has
+    /// run out of time or appears to be hung. `serial` watches the VM's
+    /// current serial output.
+    pub fn check(&mut self, serial: Option<&SerialWatcher>) -> Result<()> {
+        if let Some(last_output) = serial.and_then(SerialWatcher::last_output) {
+            self.last_activity = self.last_activity.max(last_output);
+        }
+
+        if let Some((path, signature)) = &mut self.disk {
+            let current = DiskSignature::read(path);
+            if current != *signature {
+                *signature = current;
+                self.last_activity = Instant::now();
+            }
+        }
+
+        let last_line = || match serial.and_then(SerialWatcher::last_line) {
+            Some(line) => format!(
+                "; the last line on its serial port was \"{line}\" (see \
+                {}/{} in the work directory)",
+                crate::workspace::LOGS_DIR_NAME,
+                crate::ui::SERIAL_LOG_NAME
+            ),
+            None => {
+                "; it hasn't written anything to its serial port".to_string()
+            }
+        };
+
+        if let Some(timeout) = self.limits.timeout {
+            if self.started.elapsed() >= timeout {
+                return Err(Error::new(
+                    ErrorKind::SetupTimeout,
+                    format!(
+                        "Windows didn't finish installing within {} minutes \
+                        (--install-timeout){}",
+                        timeout.as_secs() / 60,
+                        last_line()
+                    ),
+                )
+                .into());
+            }
+        }
+
+        if let Some(idle) = self.limits.idle {
+            if self.last_activity.elapsed() >= idle {
+                return Err(Error::new(
+                    ErrorKind::SetupTimeout,
+                    format!(
+                        "the installation VM appears to be hung: it hasn't \
+                        written to its serial port or disk for {} minutes \
+                        (--hang-timeout){}",
+                        idle.as_secs() / 60,
+                        last_line()
+                    ),
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A VM that [`DiskSpaceMonitor::wait`] can wait for: the process running
+/// it, or a handle to a VM some other process (e.g. libvirt) runs.
+pub trait Vm {
+    /// How the VM ended.
+    type Exit;
+
+    /// Returns how the VM ended if it has, without blocking.
+    fn try_wait(&mut self) -> Result<Option<Self::Exit>>;
+
+    /// Stops the VM, waiting until it has stopped.
+    fn kill(&mut self);
+}
+
+impl Vm for Child {
+    type Exit = ExitStatus;
+
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(Child::try_wait(self)?)
+    }
+
+    fn kill(&mut self) {
+        let _ = Child::kill(self);
+        let _ = self.wait();
+    }
+}
+
+/// A single host filesystem being watched.
+struct Filesystem {
+    /// A path on the filesystem, used to name it in messages.
+    path: Utf8PathBuf,
+
+    /// Free space on the filesystem when monitoring started.
+    initial_free: u64,
+
+    /// The lowest free space observed.
+    lowest_free: u64,
+
+    /// Whether the user has already been warned about this filesystem.
+    warned: bool,
+}
+
+/// Copies a guest's serial output to a log file on a background thread,
+/// remembering the first failure the guest reports with
+/// [`GUEST_FAILURE_MARKER`] and the exit status of each provisioning script
+/// it runs.
+pub struct SerialWatcher {
+    failure: Arc<Mutex<Option<String>>>,
+    provisioned: Arc<Mutex<Vec<(String, i64)>>>,
+    last_output: Arc<Mutex<Option<(Instant, String)>>>,
+}
+
+impl SerialWatcher {
+    /// Starts copying `serial` to `log`.
+    pub fn spawn(
+        serial: impl Read + Send + 'static,
+        mut log: impl Write + Send + 'static,
+    ) -> Self {
+        let failure = Arc::new(Mutex::new(None));
+        let thread_failure = failure.clone();
+        let provisioned = Arc::new(Mutex::new(Vec::new()));
+        let thread_provisioned = provisioned.clone();
+        let last_output = Arc::new(Mutex::new(None));
+        let thread_last_output = last_output.clone();
+        trace::spawn("serial-watcher", move || {
+            let mut serial = BufReader::new(serial);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match serial.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+
+                let _ = log.write_all(&line);
+                let text = String::from_utf8_lossy(&line);
+                {
+                    let mut last_output = thread_last_output.lock().unwrap();
+                    let trimmed = text.trim();
+                    match last_output.as_mut() {
+                        Some((at, _)) if trimmed.is_empty() => {
+                            *at = Instant::now()
+                        }
+                        _ => {
+                            *last_output =
+                                Some((Instant::now(), trimmed.to_string()))
+                        }
+                    }
+                }
+                if let Some((_, message)) =
+                    text.split_once(GUEST_FAILURE_MARKER)
+                {
+                    trace::info!(
+                        "guest reported a failure",
+                        message = message.trim()
+                    );
+                    let mut failure = thread_failure.lock().unwrap();
+                    if failure.is_none() {
+                        *failure = Some(message.trim().to_string());
+                    }
+                }
+
+                if let Some((script, status)) =
+                    crate::provision::parse_result(&text)
+                {
+                    trace::info!(
+                        "provisioning script finished",
+                        script = script.as_str(),
+                        status = status
+                    );
+                    thread_provisioned.lock().unwrap().push((script, status));
+                }
+            }
+
+            trace::debug!("guest serial output closed");
+        });
+
+        Self { failure, provisioned, last_output }
+    }
+
+    /// Returns when the guest last wrote a line, if it has.
+    pub fn last_output(&self) -> Option<Instant> {
+        self.last_output.lock().unwrap().as_ref().map(|(at, _)| *at)
+    }
+
+    /// Returns the last non-blank line the guest wrote, if any.
+    pub fn last_line(&self) -> Option<String> {
+        self.last_output
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, line)| line.clone())
+            .filter(|line| !line.is_empty())
+    }
+
+    /// Returns the first failure the guest reported, if any.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Yields a JSON object mapping each provisioning script the guest has
+    /// run to its exit status, or `None` if the guest hasn't run any.
+    pub fn provision_status(&self) -> Option<Json> {
+        let provisioned = self.provisioned.lock().unwrap();
+        if provisioned.is_empty() {
+            return None;
+        }
+
+        let mut status = Json::object();
+        for (script, code) in provisioned.iter() {
+            status.insert(script, *code);
+        }
+        Some(status)
+    }
+}
+
+/// Samples free space on a set of host filesystems.
+pub struct DiskSpaceMonitor {
+    filesystems: Vec<Filesystem>,
+    limits: DiskSpaceLimits,
+    serial: Option<SerialWatcher>,
+    watchdog: Option<Watchdog>,
+}
+
+impl DiskSpaceMonitor {
+    /// Creates a monitor for the filesystems containing each of `paths`.
+    /// Paths that reside on the same filesystem are monitored once.
+    pub fn new(paths: &[&Utf8Path], limits: DiskSpaceLimits) -> Result<Self> {
+        let mut devices = Vec::new();
+        let mut filesystems = Vec::new();
+        for path in paths {
+            // Monitor the directory that contains a file, since the file
+            // itself may not exist yet.
+            let dir = if path.is_dir() {
+                path.to_path_buf()
+            } else {
+                match path.parent() {
+                    Some(p) if !p.as_str().is_empty() => p.to_path_buf(),
+                    _ => Utf8PathBuf::from("."),
+                }
+            };
+
+            let device = std::fs::metadata(&dir)
+                .with_context(|| format!("reading metadata for '{dir}'"))?
+                .dev();
+            if devices.contains(&device) {
+                continue;
+            }
+
+            devices.push(device);
+            let free = free_bytes(&dir)?;
+            filesystems.push(Filesystem {
+                path: dir,
+                initial_free: free,
+                lowest_free: free,
+                warned: false,
+            });
+        }
+
+        Ok(Self { filesystems, limits, serial: None, watchdog: None })
+    }
+
+    /// Also watches for failures the guest reports over its serial port
+    /// while waiting for the child process to exit.
+    pub fn watch_serial(&mut self, serial: SerialWatcher) {
+        self.serial = Some(serial);
+    }
+
+    /// Also stops the VM if `watchdog` decides it has run too long or is
+    /// hung.
+    pub fn watch_progress(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Samples each filesystem, warning via `ui` about filesystems that are
+    /// running low on space. Returns an error if any filesystem has less free
+    /// space than the failure threshold.
+    pub fn sample(&mut self, ui: &dyn Ui) -> Result<()> {
+        for fs in &mut self.filesystems {
+            let free = free_bytes(&fs.path)?;
+            trace::trace!(
+                "sampled free space",
+                path = fs.path.as_str(),
+                free_mib = free / MIB
+            );
+            fs.lowest_free = fs.lowest_free.min(free);
+            if free < self.limits.fail_bytes {
+                anyhow::bail!(
+                    "host filesystem containing '{}' has only {} MiB free \
+                    (minimum {} MiB); stopped the build before the guest \
+                    runs out of disk space",
+                    fs.path,
+                    free / MIB,
+                    self.limits.fail_bytes / MIB
+                );
+            }
+
+            if free < self.limits.warn_bytes && !fs.warned {
+                ui.warn(&format!(
+                    "host filesystem containing '{}' is running low on space \
+                    ({} MiB free); the build will stop if it drops below {} \
+                    MiB",
+                    fs.path,
+                    free / MIB,
+                    self.limits.fail_bytes / MIB
+                ));
+                fs.warned = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for `vm` to exit, sampling free space at the configured interval.
+    /// If a filesystem drops below the failure threshold, the guest reports a
+    /// failure on its serial port, or the watchdog (if any) decides the VM is
+    /// taking too long, kills the VM and returns an error. Records the peak
+    /// space consumed on each filesystem, and the exit status of any
+    /// provisioning scripts the guest ran, via `ui` before returning.
+    pub fn wait<V: Vm>(&mut self, vm: &mut V, ui: &dyn Ui) -> Result<V::Exit> {
+        let result = self.wait_inner(vm, ui);
+        ui.record_metric("peak_host_disk_usage_mib", self.peak_usage());
+        if let Some(status) =
+            self.serial.as_ref().and_then(SerialWatcher::provision_status)
+        {
+            ui.record_metric("provision_script_status", status);
+        }
+        result
+    }
+
+    fn wait_inner<V: Vm>(
+        &mut self,
+        vm: &mut V,
+        ui: &dyn Ui,
+    ) -> Result<V::Exit> {
+        let mut next_sample = Instant::now();
+        loop {
+            if let Some(failure) =
+                self.serial.as_ref().and_then(SerialWatcher::failure)
+            {
+                trace::debug!("killing the VM after a guest-reported failure");
+                vm.kill();
+                anyhow::bail!("the guest reported a failure: {failure}");
+            }
+
+            if let Some(exit) = vm.try_wait()? {
+                return Ok(exit);
+            }
+
+            if let Some(watchdog) = &mut self.watchdog {
+                if let Err(e) = watchdog.check(self.serial.as_ref()) {
+                    trace::debug!(
+                        "killing the VM after it stopped making progress"
+                    );
+                    vm.kill();
+                    return Err(e);
+                }
+            }
+
+            if Instant::now() >= next_sample {
+                if let Err(e) = self.sample(ui) {
+                    trace::debug!(
+                        "killing the VM after a disk space check failed"
+                    );
+                    vm.kill();
+                    return Err(e);
+                }
+                next_sample = Instant::now() + self.limits.interval;
+            }
+
+            std::thread::sleep(CHILD_POLL_INTERVAL);
+        }
+    }
+
+    /// Yields a JSON object mapping each monitored filesystem to the most
+    /// space (in MiB) consumed on it since monitoring started.
+    fn peak_usage(&self) -> Json {
+        let mut usage = Json::object();
+        for fs in &self.filesystems {
+            usage.insert(
+                fs.path.as_str(),
+                fs.initial_free.saturating_sub(fs.lowest_free) / MIB,
+            );
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watchdog_enforces_install_timeout() {
+        let limits =
+            ProgressLimits { timeout: Some(Duration::ZERO), idle: None };
+        let error = Watchdog::new(limits, None).check(None).unwrap_err();
+        assert!(error.to_string().contains("--install-timeout"));
+    }
+
+    #[test]
+    fn hang_detection_needs_a_visible_disk() {
+        let limits =
+            ProgressLimits { timeout: None, idle: Some(Duration::ZERO) };
+        Watchdog::new(limits, None).check(None).unwrap();
+        Watchdog::new(limits, Some(Utf8Path::new("/nonexistent/disk.img")))
+            .check(None)
+            .unwrap();
+
+        let disk = std::env::temp_dir().join("wimsy-watchdog-test.img");
+        std::fs::write(&disk, b"disk").unwrap();
+        let disk = Utf8PathBuf::try_from(disk).unwrap();
+        let error = Watchdog::new(limits, Some(&disk)).check(None).unwrap_err();
+        assert!(error.to_string().contains("--hang-timeout"));
+        let _ = std::fs::remove_file(&disk);
+    }
+
+    #[test]
+    fn serial_watcher_remembers_last_line() {
+        let serial = std::io::Cursor::new(b"one\ntwo\n\n".to_vec());
+        let watcher = SerialWatcher::spawn(serial, std::io::sink());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while watcher.last_line().as_deref() != Some("two") {
+            assert!(Instant::now() < deadline, "serial output wasn't read");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(watcher.last_output().is_some());
+    }
+}
@@ -0,0 +1,250 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Gives tools that only understand raw disks, such as `sgdisk`, access to
+//! output images in other formats.
+//!
+//! Raw images are used as they are. Images in other formats (i.e. qcow2) are
+//! attached to a Linux network block device with `qemu-nbd` for as long as
+//! the tool needs them, which requires the `nbd` kernel module and root
+//! privileges.
+
+use std::{io::Read, process::Command};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    error::{Error, ErrorKind},
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// How long to wait for a newly attached network block device to report its
+/// size.
+const ATTACH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Returns the format of the image at `image`, as `qemu-img` names it: the
+/// format whose magic number the image starts with, or raw if it starts with
+/// none of them.
+pub fn image_format(image: &Utf8Path) -> Result<&'static str> {
+    let mut magic = [0u8; 8];
+    let mut file = std::fs::File::open(image)
+        .with_context(|| format!("opening '{image}'"))?;
+    if file.read_exact(&mut magic).is_err() {
+        return Ok("raw");
+    }
+
+    Ok(match magic {
+        [b'Q', b'F', b'I', 0xfb, ..] => "qcow2",
+        [b'K', b'D', b'M', b'V', ..] => "vmdk",
+        _ if &magic == b"vhdxfile" => "vhdx",
+        _ => "raw",
+    })
+}
+
+/// Fails with a prerequisite error if the image at `image`, which is in
+/// `format`, can't be attached.
+pub fn ensure_can_attach(image: &Utf8Path, format: &str) -> Result<()> {
+    let errors = check_prerequisites(format);
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::new(
+        ErrorKind::Prerequisite,
+        format!("can't read '{image}': {}", errors.join("; ")),
+    )
+    .into())
+}
+
+/// Checks that images in `format` can be attached, returning a message
+/// describing each problem.
+pub fn check_prerequisites(format: &str) -> Vec<String> {
+    if format == "raw" {
+        return Vec::new();
+    }
+
+    let mut errors = Vec::new();
+    if !cfg!(target_os = "linux") {
+        errors.push(format!(
+            "{format} output images are only supported when building on Linux"
+        ));
+        return errors;
+    }
+
+    if which::which("qemu-nbd").is_err() {
+        errors.push(format!(
+            "{format} output images require qemu-nbd, which wasn't found (is \
+            it on your PATH?)"
+        ));
+    }
+
+    if !Utf8Path::new("/sys/block/nbd0").exists() {
+        errors.push(format!(
+            "{format} output images require the nbd kernel module; load it \
+            with 'modprobe nbd'"
+        ));
+    }
+
+    // SAFETY: `geteuid` has no preconditions.
+    if unsafe { libc::geteuid() } != 0 {
+        errors.push(format!(
+            "{format} output images are attached to network block devices, \
+            which requires running as root"
+        ));
+    }
+
+    errors
+}
+
+/// Describes, for `--dry-run`, the commands that make the image at `image`
+/// (in `format`) available as a raw disk around the commands `inner` returns
+/// for the raw disk's path.
+pub fn describe_raw_access(
+    image: &str,
+    format: &str,
+    inner: impl FnOnce(&str) -> Vec<String>,
+) -> Vec<String> {
+    if format == "raw" {
+        return inner(image);
+    }
+
+    let device = "<nbd device>";
+    let mut lines =
+        vec![format_command(&connect_command(device, format, image))];
+    lines.extend(inner(device));
+    lines.push(format_command(&disconnect_command(device)));
+    lines
+}
+
+fn connect_command(device: &str, format: &str, image: &str) -> Command {
+    let mut cmd = Command::new("qemu-nbd");
+    cmd.args(["--connect", device, "--format", format, image]);
+    cmd
+}
+
+fn disconnect_command(device: &str) -> Command {
+    let mut cmd = Command::new("qemu-nbd");
+    cmd.args(["--disconnect", device]);
+    cmd
+}
+
+/// An output image made available as a raw disk. If the image had to be
+/// attached to a network block device, it's detached when this is dropped,
+/// or if the build is cancelled first.
+pub struct RawImage {
+    path: Utf8PathBuf,
+    attached: bool,
+
+    /// Detaches the device if the build is cancelled, since cancelling exits
+    /// without dropping this.
+    _detach_on_cancel: Option<crate::interrupt::CancelGuard>,
+}
+
+impl RawImage {
+    /// Makes the image at `image`, which is in `format`, available as a raw
+    /// disk.
+    pub fn open(image: &Utf8Path, format: &str, ui: &dyn Ui) -> Result<Self> {
+        if format == "raw" {
+            return Ok(Self {
+                path: image.to_path_buf(),
+                attached: false,
+                _detach_on_cancel: None,
+            });
+        }
+
+        let device = find_free_device()?;
+        ui.set_substep(&format!("attaching {image} to {device}"));
+        run_command_check_status(
+            &mut connect_command(device.as_str(), format, image.as_str()),
+            ui,
+        )
+        .with_context(|| format!("attaching '{image}' to '{device}'"))?;
+
+        // Construct the guard before waiting so that the device is detached
+        // even if it never becomes ready.
+        let detach = crate::interrupt::run_on_cancel(disconnect_command(
+            device.as_str(),
+        ));
+        let raw = Self {
+            path: device,
+            attached: true,
+            _detach_on_cancel: Some(detach),
+        };
+        raw.wait_until_ready()?;
+        trace::debug!(
+            "attached image to network block device",
+            image = image.as_str(),
+            device = raw.path.as_str()
+        );
+        Ok(raw)
+    }
+
+    /// The path at which the image can be read and written as a raw disk.
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// Waits for the kernel to report a size for the attached device, which
+    /// it does once `qemu-nbd` has finished connecting it.
+    fn wait_until_ready(&self) -> Result<()> {
+        let size_path = sysfs_dir(&self.path).join("size");
+        let deadline = std::time::Instant::now() + ATTACH_TIMEOUT;
+        loop {
+            let size = std::fs::read_to_string(&size_path).unwrap_or_default();
+            if size.trim().parse::<u64>().is_ok_and(|size| size > 0) {
+                return Ok(());
+            }
+
+            if std::time::Instant::now() >= deadline {
+                anyhow::bail!(
+                    "timed out waiting for '{}' to attach",
+                    self.path
+                );
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for RawImage {
+    fn drop(&mut self) {
+        if !self.attached {
+            return;
+        }
+
+        let result = disconnect_command(self.path.as_str()).output();
+        trace::debug!(
+            "detached network block device",
+            device = self.path.as_str(),
+            success = result.is_ok_and(|output| output.status.success())
+        );
+    }
+}
+
+/// Returns the sysfs directory describing the block device at `device`.
+fn sysfs_dir(device: &Utf8Path) -> Utf8PathBuf {
+    Utf8Path::new("/sys/block").join(device.file_name().unwrap_or_default())
+}
+
+/// Returns the path to a network block device that isn't attached to
+/// anything. A device is attached while its sysfs directory has a `pid` file.
+fn find_free_device() -> Result<Utf8PathBuf> {
+    for index in 0.. {
+        let device = Utf8PathBuf::from(format!("/dev/nbd{index}"));
+        let sysfs = sysfs_dir(&device);
+        if !sysfs.exists() {
+            break;
+        }
+
+        if !sysfs.join("pid").exists() {
+            return Ok(device);
+        }
+    }
+
+    anyhow::bail!("no free network block devices (/dev/nbd*) are available")
+}
@@ -0,0 +1,273 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Gives built images a static network configuration by adding
+//! Microsoft-Windows-TCPIP and Microsoft-Windows-DNS-Client components to
+//! the specialize pass in specialize-unattend.xml, which a generalized image
+//! runs when it's first deployed.
+//!
+//! The installation VM is left alone: it gets its address from QEMU's (or
+//! the hypervisor's) DHCP server as usual, so that it can still download
+//! what setup needs.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{
+    app::NetworkOptions, autounattend::AutounattendUpdater, runner::Context,
+    template::xml_escape, ui::Ui,
+};
+
+/// The answer file the network settings go in.
+const ANSWER_FILE: &str = "specialize-unattend.xml";
+
+/// The interface the settings apply to if none is named. Windows names the
+/// first wired adapter it finds "Ethernet".
+pub const DEFAULT_INTERFACE: &str = "Ethernet";
+
+/// The address QEMU's user-mode network gives the test VM, to which image
+/// tests forward their ports.
+pub const USER_NETWORK_GUEST_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+/// An IPv4 address and the length of its subnet's prefix, as in
+/// `10.0.0.5/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Interface {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Interface {
+    /// Returns whether `address` is in this address's subnet.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let mask = u32::MAX << (32 - u32::from(self.prefix_len));
+        u32::from(self.address) & mask == u32::from(address) & mask
+    }
+}
+
+impl std::str::FromStr for Ipv4Interface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = s.split_once('/').ok_or_else(|| {
+            format!(
+                "'{s}' should be an IPv4 address and prefix length, like \
+                10.0.0.5/24"
+            )
+        })?;
+        let address = address
+            .parse()
+            .map_err(|_| format!("'{address}' isn't an IPv4 address"))?;
+        let prefix_len = prefix_len
+            .parse()
+            .ok()
+            .filter(|len| (1..=32).contains(len))
+            .ok_or_else(|| {
+                format!("'{prefix_len}' isn't a prefix length from 1 to 32")
+            })?;
+        Ok(Self { address, prefix_len })
+    }
+}
+
+impl std::fmt::Display for Ipv4Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Checks that the network options are consistent, returning a message for
+/// each problem.
+pub fn check_prerequisites(options: &NetworkOptions) -> Vec<String> {
+    let mut errors = Vec::new();
+    match (options.static_ip, options.gateway) {
+        (None, Some(gateway)) => errors.push(format!(
+            "the gateway {gateway} needs a static address to go with it"
+        )),
+        (Some(interface), Some(gateway)) if !interface.contains(gateway) => {
+            errors.push(format!(
+                "the gateway {gateway} isn't in {interface}'s subnet"
+            ))
+        }
+        _ => {}
+    }
+
+    errors
+}
+
+/// Builds the components that configure the interface described by the
+/// network settings in `ctx`: the TCPIP component if a static address was
+/// given, and the DNS client component if DNS servers were.
+fn components(ctx: &Context) -> Vec<(&'static str, String)> {
+    let interface =
+        ctx.get_var("network_interface").unwrap_or(DEFAULT_INTERFACE);
+    let identifier =
+        format!("<Identifier>{}</Identifier>", xml_escape(interface));
+    let component = |name: &str, namespaces: &str, body: String| {
+        format!(
+            "<component name=\"{name}\" processorArchitecture=\"{}\" \
+            publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" \
+            versionScope=\"nonSxS\" xmlns=\"urn:schemas-microsoft-com:unattend\" \
+            {namespaces}><Interfaces><Interface wcm:action=\"add\">\
+            {body}</Interface></Interfaces></component>",
+            crate::steps::architecture(ctx).windows_name()
+        )
+    };
+    let wcm = "xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\"";
+
+    let mut components = Vec::new();
+    if let Some(address) = ctx.get_var("network_address") {
+        let route = match ctx.get_var("network_gateway") {
+            Some(gateway) => format!(
+                "<Routes><Route wcm:action=\"add\"><Identifier>0</Identifier>\
+                <Prefix>0.0.0.0/0</Prefix><NextHopAddress>{}</NextHopAddress>\
+                </Route></Routes>",
+                xml_escape(gateway)
+            ),
+            None => String::new(),
+        };
+        components.push((
+            "Microsoft-Windows-TCPIP",
+            component(
+                "Microsoft-Windows-TCPIP",
+                wcm,
+                format!(
+                    "<Ipv4Settings><DhcpEnabled>false</DhcpEnabled>\
+                    </Ipv4Settings>{identifier}<UnicastIpAddresses>\
+                    <IpAddress wcm:action=\"add\" wcm:keyValue=\"1\">{}\
+                    </IpAddress></UnicastIpAddresses>{route}",
+                    xml_escape(address)
+                ),
+            ),
+        ));
+    }
+
+    if let Some(servers) = ctx.get_var("network_dns_servers") {
+        let servers: String = servers
+            .split(',')
+            .enumerate()
+            .map(|(i, server)| {
+                format!(
+                    "<IpAddress wcm:action=\"add\" wcm:keyValue=\"{}\">{}\
+                    </IpAddress>",
+                    i + 1,
+                    xml_escape(server)
+                )
+            })
+            .collect();
+        components.push((
+            "Microsoft-Windows-DNS-Client",
+            component(
+                "Microsoft-Windows-DNS-Client",
+                wcm,
+                format!(
+                    "{identifier}<DNSServerSearchOrder>{servers}\
+                    </DNSServerSearchOrder>"
+                ),
+            ),
+        ));
+    }
+
+    components
+}
+
+/// Adds the network settings in `ctx`, if there are any, to the specialize
+/// pass in the unattend directory's specialize-unattend.xml.
+pub fn configure_network(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let components = components(ctx);
+    if components.is_empty() {
+        return Ok(());
+    }
+
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    let path = unattend_dir.join(ANSWER_FILE);
+    if !path.exists() {
+        anyhow::bail!(
+            "static network settings go in {ANSWER_FILE}, but the unattend \
+            directory doesn't have one"
+        );
+    }
+
+    ui.set_substep(&format!("adding network settings to {path}"));
+    let mut updater = AutounattendUpdater::new(None, None);
+    for (name, xml) in &components {
+        updater = updater.with_component("specialize", name, xml)?;
+    }
+    let updated = path.with_extension("xml.network");
+    updater
+        .run(&path, &updated)
+        .with_context(|| format!("adding network settings to {path}"))?;
+    std::fs::rename(&updated, &path)
+        .with_context(|| format!("renaming '{updated}' to '{path}'"))
+}
+
+pub fn describe_configure_network(ctx: &mut Context) -> Vec<String> {
+    components(ctx)
+        .into_iter()
+        .map(|(name, _)| {
+            format!("add a {name} component to the specialize pass in {ANSWER_FILE}")
+        })
+        .collect()
+}
+
+/// Returns the DNS servers in `servers`, separated by commas.
+pub fn dns_servers_var(servers: &[IpAddr]) -> String {
+    itertools::join(servers, ",")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_interface_addresses() {
+        let interface: Ipv4Interface = "10.0.0.5/24".parse().unwrap();
+        assert_eq!(interface.to_string(), "10.0.0.5/24");
+        assert!(interface.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!interface.contains(Ipv4Addr::new(10, 0, 1, 1)));
+        let host: Ipv4Interface = "192.168.1.9/32".parse().unwrap();
+        assert!(host.contains(host.address));
+
+        for bad in ["10.0.0.5", "10.0.0/24", "10.0.0.5/0", "10.0.0.5/33"] {
+            assert!(bad.parse::<Ipv4Interface>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn builds_network_components() {
+        let vars = |vars: &[(&str, &str)]| {
+            Context::new(
+                vars.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+        };
+        assert!(components(&vars(&[])).is_empty());
+
+        let components = components(&vars(&[
+            ("network_interface", "Ethernet 2"),
+            ("network_address", "10.0.0.5/24"),
+            ("network_gateway", "10.0.0.1"),
+            ("network_dns_servers", "10.0.0.2,2001:db8::53"),
+        ]));
+        let names: Vec<_> = components.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            ["Microsoft-Windows-TCPIP", "Microsoft-Windows-DNS-Client"]
+        );
+        let (tcpip, dns) = (&components[0].1, &components[1].1);
+        assert!(tcpip.contains("<Identifier>Ethernet 2</Identifier>"));
+        assert!(tcpip.contains("<DhcpEnabled>false</DhcpEnabled>"));
+        assert!(tcpip.contains(">10.0.0.5/24</IpAddress>"));
+        assert!(tcpip.contains("<NextHopAddress>10.0.0.1</NextHopAddress>"));
+        assert!(dns.contains("wcm:keyValue=\"2\">2001:db8::53</IpAddress>"));
+
+        // The components have to parse on their own to be injected.
+        let mut updater = AutounattendUpdater::new(None, None);
+        for (name, xml) in &components {
+            updater = updater.with_component("specialize", name, xml).unwrap();
+        }
+    }
+}
@@ -0,0 +1,678 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads files from the NTFS volumes in raw disk images, so that the inspect
+//! command can look at the Windows installed in an image without mounting it,
+//! and builds that slipstream updates can collect the images their servicing
+//! VM wrote.
+//!
+//! Only as much of NTFS is understood as reading a file by its path needs:
+//! file records, the attributes that hold directory indexes and file data
+//! (including those an attribute list moves to other records), and data runs.
+//! Compressed and encrypted files aren't read, and the log isn't replayed, so
+//! volumes are seen as they were when they were last cleanly unmounted. That's
+//! how finished images, whose VM shut down, always are.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{Context as _, Result};
+
+/// The file record of the volume's root directory.
+const ROOT_RECORD: u64 = 5;
+
+const ATTRIBUTE_LIST: u32 = 0x20;
+const DATA: u32 = 0x80;
+const INDEX_ROOT: u32 = 0x90;
+const INDEX_ALLOCATION: u32 = 0xa0;
+const BITMAP: u32 = 0xb0;
+const END_OF_ATTRIBUTES: u32 = 0xffff_ffff;
+
+/// The name of the attributes that hold directory indexes.
+const DIRECTORY_INDEX: &str = "$I30";
+
+/// NTFS protects each 512-byte block of a file or index record against torn
+/// writes by replacing its last two bytes with a sequence number, keeping
+/// the real bytes in the record's update sequence array.
+const FIXUP_STRIDE: usize = 512;
+
+/// The low 48 bits of a file reference are the record number; the rest are
+/// the record's sequence number, which changes when the record is reused.
+const RECORD_NUMBER_MASK: u64 = (1 << 48) - 1;
+
+/// The largest file this reader will read into memory.
+const MAX_FILE_BYTES: u64 = 1 << 30;
+
+/// How much of a file [`Volume::copy_file`] reads at a time.
+const COPY_CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Attribute flags marking data this reader can't read.
+const COMPRESSED_OR_ENCRYPTED: u16 = 0x0001 | 0x4000;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Decodes a name of `units` UTF-16LE code units at the start of `data`.
+fn utf16_at(data: &[u8], units: usize) -> Result<String> {
+    let bytes = data.get(..2 * units).ok_or_else(|| {
+        anyhow::anyhow!("name runs past the end of its record")
+    })?;
+    let units = bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    Ok(char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect())
+}
+
+/// Returns whether `boot_sector` is an NTFS volume's.
+pub fn is_ntfs(boot_sector: &[u8]) -> bool {
+    boot_sector.get(3..11) == Some(b"NTFS    ")
+}
+
+/// Decodes the size of a file or index record from its boot sector field:
+/// a count of clusters, or if negative, the log2 of a count of bytes.
+fn record_size(field: u8, cluster_size: u64) -> u64 {
+    match field as i8 {
+        clusters @ 0.. => clusters as u64 * cluster_size,
+        log2 => 1 << log2.unsigned_abs(),
+    }
+}
+
+/// Restores the bytes the update sequence array of `record` holds, checking
+/// that every block was written with the same sequence number.
+fn apply_fixups(record: &mut [u8]) -> Result<()> {
+    let array = u16_at(record, 4) as usize;
+    let count = u16_at(record, 6) as usize;
+    if count < 2
+        || array + 2 * count > record.len()
+        || (count - 1) * FIXUP_STRIDE > record.len()
+    {
+        anyhow::bail!("record has an invalid update sequence array");
+    }
+
+    for block in 1..count {
+        let end = block * FIXUP_STRIDE;
+        if record[end - 2..end] != record[array..array + 2] {
+            anyhow::bail!("record was torn by an interrupted write");
+        }
+        let saved = array + 2 * block;
+        record.copy_within(saved..saved + 2, end - 2);
+    }
+    Ok(())
+}
+
+/// A contiguous run of an attribute's clusters: `clusters` clusters starting
+/// at virtual cluster `vcn` of the attribute, stored from logical cluster
+/// `lcn` of the volume (or not stored at all, if the run is sparse).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Run {
+    vcn: u64,
+    lcn: Option<u64>,
+    clusters: u64,
+}
+
+/// Reads the little-endian integer of `bytes.len()` bytes in `bytes`,
+/// sign-extending it if `signed`.
+fn varint(bytes: &[u8], signed: bool) -> i64 {
+    let mut value = 0i64;
+    for (i, &byte) in bytes.iter().enumerate() {
+        value |= i64::from(byte) << (8 * i);
+    }
+    let bits = 8 * bytes.len();
+    if signed && bits < 64 && bytes.last().is_some_and(|&b| b & 0x80 != 0) {
+        value -= 1 << bits;
+    }
+    value
+}
+
+/// Decodes a mapping pairs array (i.e. a list of data runs) describing an
+/// attribute's clusters from virtual cluster `vcn` on.
+fn decode_runs(data: &[u8], mut vcn: u64) -> Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    let mut lcn = 0i64;
+    let mut i = 0;
+    while let Some(&header) = data.get(i).filter(|&&header| header != 0) {
+        let length_bytes = usize::from(header & 0xf);
+        let offset_bytes = usize::from(header >> 4);
+        let end = i + 1 + length_bytes + offset_bytes;
+        if length_bytes == 0 || length_bytes > 8 || offset_bytes > 8 {
+            anyhow::bail!("malformed data run");
+        }
+        let Some(run) = data.get(i + 1..end) else {
+            anyhow::bail!("data runs run past the end of their attribute");
+        };
+
+        let clusters = varint(&run[..length_bytes], false) as u64;
+        let run_lcn = if offset_bytes == 0 {
+            None
+        } else {
+            lcn += varint(&run[length_bytes..], true);
+            Some(
+                u64::try_from(lcn)
+                    .context("data run starts before the volume")?,
+            )
+        };
+        runs.push(Run { vcn, lcn: run_lcn, clusters });
+        vcn += clusters;
+        i = end;
+    }
+    Ok(runs)
+}
+
+/// An attribute in a file record.
+struct Attribute<'a> {
+    kind: u32,
+    name: String,
+    raw: &'a [u8],
+}
+
+impl Attribute<'_> {
+    fn is_resident(&self) -> bool {
+        self.raw[8] == 0
+    }
+
+    fn flags(&self) -> u16 {
+        u16_at(self.raw, 12)
+    }
+
+    fn resident_value(&self) -> Result<&[u8]> {
+        let length = u32_at(self.raw, 16) as usize;
+        let offset = u16_at(self.raw, 20) as usize;
+        self.raw
+            .get(offset..offset + length)
+            .ok_or_else(|| anyhow::anyhow!("attribute value is out of bounds"))
+    }
+
+    fn first_vcn(&self) -> u64 {
+        u64_at(self.raw, 16)
+    }
+
+    fn runs(&self) -> Result<Vec<Run>> {
+        let offset = u16_at(self.raw, 32) as usize;
+        decode_runs(
+            self.raw.get(offset..).unwrap_or_default(),
+            self.first_vcn(),
+        )
+    }
+
+    fn data_size(&self) -> u64 {
+        u64_at(self.raw, 48)
+    }
+}
+
+/// Lists the attributes in the file record `record`.
+fn attributes(record: &[u8]) -> Result<Vec<Attribute<'_>>> {
+    let mut attributes = Vec::new();
+    let mut offset = u16_at(record, 20) as usize;
+    loop {
+        if offset + 8 > record.len() {
+            anyhow::bail!("file record's attributes aren't terminated");
+        }
+        let kind = u32_at(record, offset);
+        if kind == END_OF_ATTRIBUTES {
+            return Ok(attributes);
+        }
+
+        let length = u32_at(record, offset + 4) as usize;
+        let Some(raw) =
+            record.get(offset..offset + length).filter(|raw| raw.len() >= 24)
+        else {
+            anyhow::bail!("file record has a malformed attribute");
+        };
+        let name_offset = u16_at(raw, 10) as usize;
+        let name = utf16_at(
+            raw.get(name_offset..).unwrap_or_default(),
+            raw[9].into(),
+        )?;
+        attributes.push(Attribute { kind, name, raw });
+        offset += length;
+    }
+}
+
+/// An attribute's value, gathered from all of the records holding it.
+enum Stream {
+    Resident(Vec<u8>),
+    NonResident { runs: Vec<Run>, size: u64, flags: u16 },
+}
+
+/// Finds the entry for the file called `name` in the directory index node
+/// `node` (which starts with an index node header), returning its file
+/// reference. Names are compared as Windows compares them, ignoring case.
+fn find_index_entry(node: &[u8], name: &str) -> Result<Option<u64>> {
+    let first = u32_at(node, 0) as usize;
+    let end = u32_at(node, 4) as usize;
+    if end > node.len() {
+        anyhow::bail!("directory index node is out of bounds");
+    }
+
+    let name = name.to_uppercase();
+    let mut offset = first;
+    while offset + 16 <= end {
+        let length = u16_at(node, offset + 8) as usize;
+        let key_length = u16_at(node, offset + 10) as usize;
+        let last = u32_at(node, offset + 12) & 2 != 0;
+        if last {
+            break;
+        }
+        if length < 16 || offset + length > end {
+            anyhow::bail!("directory index has a malformed entry");
+        }
+
+        // The key is a $FILE_NAME value. Skip the short names of files whose
+        // long names are in other entries.
+        let key = &node[offset + 16..offset + 16 + key_length.min(length - 16)];
+        if key.len() >= 0x42 && key[0x41] != 2 {
+            let entry_name = utf16_at(&key[0x42..], key[0x40].into())?;
+            if entry_name.to_uppercase() == name {
+                return Ok(Some(u64_at(node, offset)));
+            }
+        }
+        offset += length;
+    }
+    Ok(None)
+}
+
+/// An NTFS volume in a disk image.
+pub struct Volume<R> {
+    disk: R,
+
+    /// The volume's offset in the disk image, in bytes.
+    offset: u64,
+
+    cluster_size: u64,
+    record_size: u64,
+
+    /// Where the master file table is.
+    mft: Vec<Run>,
+}
+
+impl<R: Read + Seek> Volume<R> {
+    /// Opens the NTFS volume starting `offset` bytes into `disk`.
+    pub fn open(mut disk: R, offset: u64) -> Result<Self> {
+        let mut boot = [0u8; 512];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut boot).context("reading the boot sector")?;
+        if !is_ntfs(&boot) {
+            anyhow::bail!("the volume isn't NTFS");
+        }
+
+        let sector_size = u64::from(u16_at(&boot, 11));
+        let sectors_per_cluster = match boot[13] {
+            // Sizes above 128 are stored as 256 minus their log2.
+            exponent @ 0x81.. => 1 << (256 - u32::from(exponent)),
+            count => u64::from(count),
+        };
+        let cluster_size = sector_size * sectors_per_cluster;
+        let record_size = record_size(boot[0x40], cluster_size);
+        if cluster_size == 0 || !(1024..=65536).contains(&record_size) {
+            anyhow::bail!("the volume's boot sector is corrupt");
+        }
+
+        // The MFT's first record describes the MFT itself, and is always
+        // at the start of its first run.
+        let mft_lcn = u64_at(&boot, 0x30);
+        let mut volume = Self {
+            disk,
+            offset,
+            cluster_size,
+            record_size,
+            mft: vec![Run {
+                vcn: 0,
+                lcn: Some(mft_lcn),
+                clusters: record_size.div_ceil(cluster_size),
+            }],
+        };
+        let record = volume.record(0).context("reading the MFT's record")?;
+        let runs = attributes(&record)?
+            .iter()
+            .find(|attr| attr.kind == DATA && !attr.is_resident())
+            .map(Attribute::runs)
+            .transpose()?;
+        volume.mft = runs.ok_or_else(|| {
+            anyhow::anyhow!("the MFT's record doesn't say where the MFT is")
+        })?;
+        Ok(volume)
+    }
+
+    /// Reads `length` bytes at offset `start` of the attribute whose clusters
+    /// are `runs`.
+    fn read_runs(
+        &mut self,
+        runs: &[Run],
+        start: u64,
+        length: usize,
+    ) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        let mut done = 0;
+        while done < length {
+            let position = start + done as u64;
+            let vcn = position / self.cluster_size;
+            let Some(run) = runs
+                .iter()
+                .find(|run| (run.vcn..run.vcn + run.clusters).contains(&vcn))
+            else {
+                anyhow::bail!("attribute data ends before offset {position}");
+            };
+
+            let run_end = (run.vcn + run.clusters) * self.cluster_size;
+            let count = ((run_end - position) as usize).min(length - done);
+            if let Some(lcn) = run.lcn {
+                let within = position - run.vcn * self.cluster_size;
+                self.disk.seek(SeekFrom::Start(
+                    self.offset + lcn * self.cluster_size + within,
+                ))?;
+                self.disk.read_exact(&mut data[done..done + count])?;
+            }
+            done += count;
+        }
+        Ok(data)
+    }
+
+    /// Reads file record `number` from the MFT.
+    fn record(&mut self, number: u64) -> Result<Vec<u8>> {
+        let runs = std::mem::take(&mut self.mft);
+        let record = self.read_runs(
+            &runs,
+            number * self.record_size,
+            self.record_size as usize,
+        );
+        self.mft = runs;
+
+        let mut record = record?;
+        if &record[..4] != b"FILE" {
+            anyhow::bail!("file record {number} is corrupt");
+        }
+        apply_fixups(&mut record)
+            .with_context(|| format!("reading file record {number}"))?;
+        Ok(record)
+    }
+
+    /// Returns the record number `reference` refers to, if it's still the
+    /// file it was when the reference was made.
+    fn resolve(&mut self, reference: u64) -> Result<Option<u64>> {
+        let number = reference & RECORD_NUMBER_MASK;
+        let record = self.record(number)?;
+        let in_use = u16_at(&record, 22) & 1 != 0;
+        let sequence = u64::from(u16_at(&record, 16));
+        Ok((in_use && sequence == reference >> 48).then_some(number))
+    }
+
+    /// Finds the attribute of type `kind` called `name` of file record
+    /// `number`, gathering the pieces of it an attribute list says are in
+    /// other records.
+    fn find_attribute(
+        &mut self,
+        number: u64,
+        kind: u32,
+        name: &str,
+    ) -> Result<Option<Stream>> {
+        let base = self.record(number)?;
+        let mut records = Vec::new();
+        if let Some(list) =
+            attributes(&base)?.iter().find(|attr| attr.kind == ATTRIBUTE_LIST)
+        {
+            let list = if list.is_resident() {
+                list.resident_value()?.to_vec()
+            } else {
+                let runs = list.runs()?;
+                self.read_runs(&runs, 0, list.data_size() as usize)?
+            };
+
+            let mut numbers = Vec::new();
+            let mut offset = 0;
+            while offset + 26 <= list.len() {
+                let length = u16_at(&list, offset + 4) as usize;
+                let name_offset = offset + usize::from(list[offset + 7]);
+                let entry_name = utf16_at(
+                    list.get(name_offset..).unwrap_or_default(),
+                    list[offset + 6].into(),
+                )?;
+                let record = u64_at(&list, offset + 16) & RECORD_NUMBER_MASK;
+                if u32_at(&list, offset) == kind
+                    && entry_name == name
+                    && !numbers.contains(&record)
+                {
+                    numbers.push(record);
+                }
+                if length == 0 {
+                    break;
+                }
+                offset += length;
+            }
+
+            for record in numbers {
+                records.push(if record == number {
+                    base.clone()
+                } else {
+                    self.record(record)?
+                });
+            }
+        } else {
+            records.push(base);
+        }
+
+        let mut runs = Vec::new();
+        let (mut size, mut flags) = (0, 0);
+        for record in &records {
+            for attr in attributes(record)? {
+                if attr.kind != kind || attr.name != name {
+                    continue;
+                }
+                if attr.is_resident() {
+                    return Ok(Some(Stream::Resident(
+                        attr.resident_value()?.to_vec(),
+                    )));
+                }
+                if attr.first_vcn() == 0 {
+                    size = attr.data_size();
+                    flags = attr.flags();
+                }
+                runs.extend(attr.runs()?);
+            }
+        }
+
+        if runs.is_empty() {
+            return Ok(None);
+        }
+        runs.sort_by_key(|run| run.vcn);
+        Ok(Some(Stream::NonResident { runs, size, flags }))
+    }
+
+    fn read_stream(&mut self, stream: &Stream) -> Result<Vec<u8>> {
+        match stream {
+            Stream::Resident(data) => Ok(data.clone()),
+            Stream::NonResident { runs, size, flags } => {
+                if flags & COMPRESSED_OR_ENCRYPTED != 0 {
+                    anyhow::bail!("the file is compressed or encrypted");
+                }
+                if *size > MAX_FILE_BYTES {
+                    anyhow::bail!(
+                        "the file is too large to read ({size} bytes)"
+                    );
+                }
+                self.read_runs(runs, 0, *size as usize)
+            }
+        }
+    }
+
+    /// Finds the file called `name` in the directory whose file record is
+    /// `directory`, returning its file record.
+    ///
+    /// Index records not marked as in use in the index's bitmap may hold
+    /// stale entries, and are skipped. Otherwise the records are searched in
+    /// turn, not as a tree, which saves having to collate names exactly as
+    /// NTFS does.
+    fn find_in_directory(
+        &mut self,
+        directory: u64,
+        name: &str,
+    ) -> Result<Option<u64>> {
+        let Some(Stream::Resident(root)) =
+            self.find_attribute(directory, INDEX_ROOT, DIRECTORY_INDEX)?
+        else {
+            anyhow::bail!("file record {directory} isn't a directory");
+        };
+        if root.len() < 32 {
+            anyhow::bail!("directory {directory}'s index is corrupt");
+        }
+        if let Some(reference) = find_index_entry(&root[16..], name)? {
+            return self.resolve(reference);
+        }
+
+        let Some(allocation) =
+            self.find_attribute(directory, INDEX_ALLOCATION, DIRECTORY_INDEX)?
+        else {
+            return Ok(None);
+        };
+        let bitmap =
+            match self.find_attribute(directory, BITMAP, DIRECTORY_INDEX)? {
+                Some(bitmap) => self.read_stream(&bitmap)?,
+                None => Vec::new(),
+            };
+        let record_size = u32_at(&root, 8) as usize;
+        if record_size < FIXUP_STRIDE {
+            anyhow::bail!("directory {directory}'s index is corrupt");
+        }
+
+        let mut records = self.read_stream(&allocation)?;
+        for (i, record) in records.chunks_exact_mut(record_size).enumerate() {
+            let in_use =
+                bitmap.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0);
+            if !in_use || &record[..4] != b"INDX" {
+                continue;
+            }
+            apply_fixups(record).with_context(|| {
+                format!("reading directory {directory}'s index")
+            })?;
+            if let Some(reference) = find_index_entry(&record[0x18..], name)? {
+                return self.resolve(reference);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the file record of the file at `path`, if there is one.
+    fn lookup(&mut self, path: &str) -> Result<Option<u64>> {
+        let mut number = ROOT_RECORD;
+        for component in path.split('/') {
+            match self
+                .find_in_directory(number, component)
+                .with_context(|| format!("looking up '{path}'"))?
+            {
+                Some(child) => number = child,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(number))
+    }
+
+    /// Finds the data of the file at `path`.
+    fn file_data(&mut self, path: &str) -> Result<Stream> {
+        let number = self
+            .lookup(path)?
+            .ok_or_else(|| anyhow::anyhow!("'{path}' doesn't exist"))?;
+        self.find_attribute(number, DATA, "")?
+            .ok_or_else(|| anyhow::anyhow!("'{path}' isn't a file"))
+    }
+
+    /// Returns whether there's a file or directory at `path`.
+    pub fn contains(&mut self, path: &str) -> Result<bool> {
+        Ok(self.lookup(path)?.is_some())
+    }
+
+    /// Reads the file at `path`, whose components are separated by slashes
+    /// and, as Windows does, matched ignoring case.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let data = self.file_data(path)?;
+        self.read_stream(&data).with_context(|| format!("reading '{path}'"))
+    }
+
+    /// Copies the file at `path` to `out` a chunk at a time, so that files
+    /// too large for [`Volume::read_file`] can be copied, and returns its
+    /// size.
+    pub fn copy_file(
+        &mut self,
+        path: &str,
+        out: &mut dyn Write,
+    ) -> Result<u64> {
+        let (runs, size) = match self.file_data(path)? {
+            Stream::Resident(data) => {
+                out.write_all(&data)?;
+                return Ok(data.len() as u64);
+            }
+            Stream::NonResident { flags, .. }
+                if flags & COMPRESSED_OR_ENCRYPTED != 0 =>
+            {
+                anyhow::bail!("'{path}' is compressed or encrypted");
+            }
+            Stream::NonResident { runs, size, .. } => (runs, size),
+        };
+
+        let mut done = 0;
+        while done < size {
+            let count = (size - done).min(COPY_CHUNK_BYTES);
+            let chunk = self
+                .read_runs(&runs, done, count as usize)
+                .with_context(|| format!("reading '{path}'"))?;
+            out.write_all(&chunk)?;
+            done += count;
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_data_runs() {
+        // 0x18 clusters at 0x5634, 0x30 clusters 16 clusters before that, a
+        // sparse run of 0x10 clusters, and 2 clusters 0x100 clusters on.
+        let data = [
+            0x21, 0x18, 0x34, 0x56, 0x11, 0x30, 0xf0, 0x01, 0x10, 0x21, 0x02,
+            0x00, 0x01, 0x00, 0xff,
+        ];
+        let runs = decode_runs(&data, 4).unwrap();
+        let expected = [
+            Run { vcn: 4, lcn: Some(0x5634), clusters: 0x18 },
+            Run { vcn: 0x1c, lcn: Some(0x5624), clusters: 0x30 },
+            Run { vcn: 0x4c, lcn: None, clusters: 0x10 },
+            Run { vcn: 0x5c, lcn: Some(0x5724), clusters: 2 },
+        ];
+        assert_eq!(runs, expected);
+
+        assert!(decode_runs(&[0x21, 0x18], 0).is_err());
+        assert!(decode_runs(&[0x11, 0x01, 0x80], 0).is_err());
+    }
+
+    #[test]
+    fn applies_fixups() {
+        let mut record = vec![0u8; 1024];
+        record[..4].copy_from_slice(b"FILE");
+        record[4..6].copy_from_slice(&48u16.to_le_bytes());
+        record[6..8].copy_from_slice(&3u16.to_le_bytes());
+        record[48..54].copy_from_slice(&[7, 0, 0xaa, 0xbb, 0xcc, 0xdd]);
+        for end in [510, 1022] {
+            record[end..end + 2].copy_from_slice(&[7, 0]);
+        }
+
+        let mut fixed = record.clone();
+        apply_fixups(&mut fixed).unwrap();
+        assert_eq!(fixed[510..512], [0xaa, 0xbb]);
+        assert_eq!(fixed[1022..1024], [0xcc, 0xdd]);
+
+        record[1022] = 8;
+        assert!(apply_fixups(&mut record).is_err());
+    }
+}
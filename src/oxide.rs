@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Publishes the finished output image to an Oxide rack.
+//!
+//! Creating an image from a local disk file takes several API calls: create
+//! a disk in the import-ready state, upload its blocks in chunks, finalize it
+//! with a snapshot, and create an image from the snapshot. The `oxide` CLI's
+//! `disk import` command does all of them (uploading blocks in parallel), so
+//! this module runs it rather than reimplementing the sequence. The CLI finds
+//! the rack and credentials the way it always does: from `OXIDE_HOST` and
+//! `OXIDE_TOKEN`, or from the profile `oxide auth login` saved.
+
+use std::process::Command;
+
+use anyhow::Result;
+
+use crate::{
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// The image description used if the user doesn't supply one.
+pub const DEFAULT_DESCRIPTION: &str = "Windows image built by wimsy";
+
+/// The longest image name that leaves room for the suffixes of the import
+/// disk and snapshot names within Oxide's 63-character limit.
+const MAX_IMAGE_NAME_LEN: usize = 63 - "-snapshot".len();
+
+/// Checks that `s` is a valid Oxide image name: lowercase ASCII letters,
+/// digits, and dashes, starting with a letter and not ending with a dash,
+/// short enough to name the import disk and snapshot after.
+pub fn parse_image_name(s: &str) -> Result<String, String> {
+    let valid = s.len() <= MAX_IMAGE_NAME_LEN
+        && s.starts_with(|c: char| c.is_ascii_lowercase())
+        && !s.ends_with('-')
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(format!(
+            "'{s}' isn't a valid Oxide image name (use up to \
+            {MAX_IMAGE_NAME_LEN} lowercase letters, digits, and dashes, \
+            starting with a letter)"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+/// Returns the image version to record if the user doesn't supply one: the
+/// Windows release being installed (e.g. "Server 2022"), if it's known.
+pub fn default_version(ctx: &Context) -> String {
+    match crate::steps::windows_version(ctx) {
+        Some(version) => {
+            let version = version.to_string();
+            version.strip_prefix("Windows ").unwrap_or(&version).to_string()
+        }
+        None => "unknown".to_string(),
+    }
+}
+
+/// Returns the `oxide disk import` command that publishes the output image,
+/// or `None` if no project was named to publish it to.
+fn import_command(ctx: &Context) -> Option<Command> {
+    let project = ctx.get_var("oxide_project")?;
+    let image = ctx.get_var("oxide_image").unwrap();
+    let description =
+        ctx.get_var("oxide_image_description").unwrap_or(DEFAULT_DESCRIPTION);
+    let version = ctx
+        .get_var("oxide_image_version")
+        .map(str::to_string)
+        .unwrap_or_else(|| default_version(ctx));
+
+    // The CLI imports the image into a disk that it then snapshots to create
+    // the image. Name both after the image so that they're easy to find (and
+    // delete) afterwards; the names must be unique in the project, so a
+    // leftover disk from an earlier publish makes the import fail rather than
+    // overwriting anything.
+    let disk = format!("{image}-import");
+    let mut cmd = Command::new("oxide");
+    if let Some(profile) = ctx.get_var("oxide_profile") {
+        cmd.args(["--profile", profile]);
+    }
+    cmd.args(["disk", "import", "--project", project])
+        .args(["--path", ctx.get_var("output_image").unwrap()])
+        .args(["--disk", &disk])
+        .args(["--description", &format!("Imported by wimsy for {image}")])
+        .args(["--snapshot", &format!("{image}-snapshot")])
+        .args(["--image", image])
+        .args(["--image-description", description])
+        .args(["--image-os", "windows"])
+        .args(["--image-version", &version]);
+    Some(cmd)
+}
+
+/// Checks that the output image can be published: the `oxide` CLI must be
+/// installed, and the image must be raw, since that's the only format the
+/// rack imports.
+pub fn check_prerequisites(output_format: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    if which::which("oxide").is_err() {
+        errors.push(
+            "publishing to an Oxide rack requires the oxide CLI, which wasn't \
+            found (is it on your PATH?)"
+                .to_string(),
+        );
+    }
+
+    if output_format != "raw" {
+        errors.push(format!(
+            "Oxide racks import raw images, but the output image is \
+            {output_format}; build a raw image to publish it"
+        ));
+    }
+
+    errors
+}
+
+/// Uploads the output image to the Oxide project named by the `oxide_project`
+/// context variable and creates an image from it. Does nothing if no project
+/// was named.
+pub fn publish_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(mut cmd) = import_command(ctx) else {
+        return Ok(());
+    };
+
+    let project = ctx.get_var("oxide_project").unwrap();
+    let image = ctx.get_var("oxide_image").unwrap();
+    ui.set_substep(&format!("uploading to Oxide project {project}"));
+    trace::debug!(
+        "publishing image to Oxide",
+        project = project,
+        image = image
+    );
+    run_command_check_status(&mut cmd, ui)?;
+
+    let mut published = Json::object();
+    published.insert("project", project);
+    published.insert("image", image);
+    ui.record_metric("oxide_image", published);
+    Ok(())
+}
+
+pub fn describe_publish_image(ctx: &mut Context) -> Vec<String> {
+    import_command(ctx)
+        .map(|cmd| vec![format_command(&cmd)])
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_image_names() {
+        for good in ["windows-2022", "w", "ws2025-datacenter-1"] {
+            assert!(parse_image_name(good).is_ok(), "{good}");
+        }
+
+        for bad in
+            ["", "Windows", "2022", "windows-", "win_2022", &"w".repeat(55)]
+        {
+            assert!(parse_image_name(bad).is_err(), "{bad}");
+        }
+    }
+}
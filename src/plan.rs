@@ -0,0 +1,612 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Builds the list of steps a script will actually run by applying the step
+//! overrides from a configuration file (disabled, inserted, and replaced
+//! steps) to the script's built-in steps.
+
+use std::{collections::BTreeSet, process::Command};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use colored::Colorize;
+
+use crate::{
+    config::{Anchor, StepOverrides, UserCommand},
+    runner::{Context, ScriptStep, Step},
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// A step in a plan, along with a record of where it came from.
+pub enum PlannedStep<'a> {
+    /// One of the script's built-in steps.
+    BuiltIn(&'a dyn Step),
+
+    /// A user command that replaces a built-in step.
+    Replaced { original: &'a dyn Step, step: ScriptStep },
+
+    /// A user command inserted relative to another step.
+    Inserted { anchor: Anchor, step: ScriptStep },
+}
+
+impl PlannedStep<'_> {
+    pub fn step(&self) -> &dyn Step {
+        match self {
+            PlannedStep::BuiltIn(step) => *step,
+            PlannedStep::Replaced { step, .. } => step,
+            PlannedStep::Inserted { step, .. } => step,
+        }
+    }
+
+    /// For inserted steps, returns the step this step was inserted after.
+    fn inserted_after(&self) -> Option<&str> {
+        match self {
+            PlannedStep::Inserted { anchor: Anchor::After(target), .. } => {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The steps of a plan that a build runs, as chosen with `--skip-step`,
+/// `--only-step`, and `--start-at`. By default, every step runs.
+#[derive(Clone, Debug, Default)]
+pub struct StepSelection {
+    /// The steps not to run.
+    pub skip: Vec<String>,
+
+    /// If not empty, the only steps to run.
+    pub only: Vec<String>,
+
+    /// The first step to run, if not the first in the plan.
+    pub start_at: Option<String>,
+}
+
+impl StepSelection {
+    /// Returns whether each of the steps named `names`, in plan order, runs.
+    /// Fails if the selection names a step that isn't in the plan.
+    pub fn apply(&self, names: &[&str]) -> Result<Vec<bool>> {
+        let named = self
+            .skip
+            .iter()
+            .map(|name| ("--skip-step", name))
+            .chain(self.only.iter().map(|name| ("--only-step", name)))
+            .chain(self.start_at.iter().map(|name| ("--start-at", name)));
+        for (flag, name) in named {
+            if !names.contains(&name.as_str()) {
+                anyhow::bail!(
+                    "{flag} names step '{name}', which isn't in the plan"
+                );
+            }
+        }
+
+        let start = match &self.start_at {
+            Some(start_at) => names.iter().position(|name| name == start_at),
+            None => None,
+        };
+        Ok(names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let name = name.to_string();
+                start.is_none_or(|start| i >= start)
+                    && !self.skip.contains(&name)
+                    && (self.only.is_empty() || self.only.contains(&name))
+            })
+            .collect())
+    }
+}
+
+/// The ordered list of steps a script will run.
+pub struct Plan<'a> {
+    steps: Vec<PlannedStep<'a>>,
+    disabled: Vec<&'a dyn Step>,
+}
+
+impl<'a> Plan<'a> {
+    /// Applies `overrides` to the built-in steps in `builtins`. Returns an
+    /// error if the overrides refer to steps that don't exist or aren't in
+    /// the plan, or if inserted steps are anchored to each other in a cycle.
+    pub fn new(
+        builtins: &'a [Box<dyn Step>],
+        overrides: &StepOverrides,
+        work_dir: &Utf8Path,
+    ) -> Result<Self> {
+        let mut builtin_names = BTreeSet::new();
+        for step in builtins {
+            assert!(
+                builtin_names.insert(step.name()),
+                "script has multiple steps named '{}'",
+                step.name()
+            );
+        }
+
+        let unknown_step =
+            |name: &str, what: &str| {
+                anyhow::anyhow!(
+                "configuration {what} unknown step '{name}' (this script's \
+                steps are: {})",
+                builtins.iter().map(|s| s.name()).collect::<Vec<_>>().join(", ")
+            )
+            };
+
+        let mut disabled_names = BTreeSet::new();
+        for name in &overrides.disable {
+            if !builtin_names.contains(name.as_str()) {
+                return Err(unknown_step(name, "disables"));
+            }
+            disabled_names.insert(name.as_str());
+        }
+
+        let mut replacements = Vec::new();
+        let mut replaced_names = BTreeSet::new();
+        for replacement in &overrides.replace {
+            let name = replacement.step.as_str();
+            if !builtin_names.contains(name) {
+                return Err(unknown_step(name, "replaces"));
+            }
+            if disabled_names.contains(name) {
+                anyhow::bail!(
+                    "configuration both disables and replaces step '{name}'"
+                );
+            }
+            if !replaced_names.insert(name) {
+                anyhow::bail!(
+                    "configuration replaces step '{name}' more than once"
+                );
+            }
+            replacements.push(replacement);
+        }
+
+        let mut inserted_names = BTreeSet::new();
+        for insertion in &overrides.insert {
+            let name = insertion.name.as_str();
+            if builtin_names.contains(name) {
+                anyhow::bail!(
+                    "inserted step '{name}' has the same name as a built-in \
+                    step"
+                );
+            }
+            if !inserted_names.insert(name) {
+                anyhow::bail!("more than one inserted step is named '{name}'");
+            }
+        }
+
+        for insertion in &overrides.insert {
+            let target = insertion.anchor.target();
+            if disabled_names.contains(target) {
+                anyhow::bail!(
+                    "step '{}' is inserted {}, but that step is disabled",
+                    insertion.name,
+                    insertion.anchor
+                );
+            }
+            if !builtin_names.contains(target)
+                && !inserted_names.contains(target)
+            {
+                anyhow::bail!(
+                    "step '{}' is inserted {}, but there is no step with that \
+                    name",
+                    insertion.name,
+                    insertion.anchor
+                );
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut disabled = Vec::new();
+        for step in builtins.iter().map(Box::as_ref) {
+            if disabled_names.contains(step.name()) {
+                disabled.push(step);
+            } else if let Some(replacement) =
+                replacements.iter().find(|r| r.step == step.name())
+            {
+                let label =
+                    replacement.run.label.clone().unwrap_or_else(|| {
+                        format!("{} (user-supplied)", step.label())
+                    });
+                steps.push(PlannedStep::Replaced {
+                    original: step,
+                    step: user_step(
+                        step.name().to_string(),
+                        label,
+                        &replacement.run,
+                        work_dir,
+                    )?,
+                });
+            } else {
+                steps.push(PlannedStep::BuiltIn(step));
+            }
+        }
+
+        // Place inserted steps once their anchors are in the plan. Each pass
+        // should place at least one step; if a pass places nothing, the
+        // remaining steps are all anchored (directly or indirectly) to each
+        // other.
+        let mut pending: Vec<_> = overrides.insert.iter().collect();
+        while !pending.is_empty() {
+            let before = pending.len();
+            let mut still_pending = Vec::new();
+            for insertion in pending {
+                let target = insertion.anchor.target();
+                let Some(position) =
+                    steps.iter().position(|s| s.step().name() == target)
+                else {
+                    still_pending.push(insertion);
+                    continue;
+                };
+
+                // Steps inserted after the same anchor run in the order in
+                // which they appear in the configuration.
+                let position = match insertion.anchor {
+                    Anchor::Before(_) => position,
+                    Anchor::After(_) => {
+                        let mut position = position + 1;
+                        while steps
+                            .get(position)
+                            .and_then(|s| s.inserted_after())
+                            .is_some_and(|t| t == target)
+                        {
+                            position += 1;
+                        }
+                        position
+                    }
+                };
+
+                let label = insertion.run.label.clone().unwrap_or_else(|| {
+                    format!("run user step '{}'", insertion.name)
+                });
+                steps.insert(
+                    position,
+                    PlannedStep::Inserted {
+                        anchor: insertion.anchor.clone(),
+                        step: user_step(
+                            insertion.name.clone(),
+                            label,
+                            &insertion.run,
+                            work_dir,
+                        )?,
+                    },
+                );
+            }
+
+            if still_pending.len() == before {
+                let mut cycle = vec![still_pending[0].name.as_str()];
+                loop {
+                    let last = cycle.last().unwrap();
+                    let next = still_pending
+                        .iter()
+                        .find(|i| i.name == *last)
+                        .unwrap()
+                        .anchor
+                        .target();
+                    let seen = cycle.contains(&next);
+                    cycle.push(next);
+                    if seen {
+                        break;
+                    }
+                }
+                anyhow::bail!(
+                    "inserted steps are anchored to each other in a cycle: {}",
+                    cycle.join(" -> ")
+                );
+            }
+
+            pending = still_pending;
+        }
+
+        Ok(Self { steps, disabled })
+    }
+
+    /// Yields the steps in this plan in the order they will run.
+    pub fn steps(&self) -> Vec<&dyn Step> {
+        self.steps.iter().map(PlannedStep::step).collect()
+    }
+
+    /// Prints the steps in this plan to `w`.
+    pub fn print(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        writeln!(w, "  {}:", "Steps".bold())?;
+        for (i, planned) in self.steps.iter().enumerate() {
+            let step = planned.step();
+            let note = match planned {
+                PlannedStep::BuiltIn(_) => String::new(),
+                PlannedStep::Replaced { original, .. } => {
+                    format!(" (replaces built-in: {})", original.label())
+                }
+                PlannedStep::Inserted { anchor, .. } => {
+                    format!(" (inserted {anchor})")
+                }
+            };
+            writeln!(
+                w,
+                "    {:>2}. {}: {}{}",
+                i + 1,
+                step.name(),
+                step.label(),
+                note.dimmed()
+            )?;
+        }
+
+        if !self.disabled.is_empty() {
+            writeln!(
+                w,
+                "  {}: {}",
+                "Disabled steps".bold(),
+                self.disabled
+                    .iter()
+                    .map(|s| s.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a step that runs a user-supplied command.
+fn user_step(
+    name: String,
+    label: String,
+    run: &UserCommand,
+    work_dir: &Utf8Path,
+) -> Result<ScriptStep> {
+    if let Some(script) = &run.script {
+        if !script.is_file() {
+            anyhow::bail!("script '{script}' for step '{name}' not found");
+        }
+    }
+
+    let prereqs: &[&'static str] =
+        if run.command.is_some() { &["sh"] } else { &[] };
+    let run = run.clone();
+    let context_out = crate::workspace::scratch_dir(work_dir)
+        .join(format!("{name}.context-out"));
+    let step_name = name.clone();
+    let description = format_command(&user_command(&run));
+    Ok(ScriptStep::with_prereqs(
+        name,
+        label,
+        move |ctx, ui| {
+            run_user_command(&step_name, &run, &context_out, ctx, ui)
+        },
+        prereqs,
+    )
+    .describe(move |_| vec![description.clone()]))
+}
+
+fn user_command(run: &UserCommand) -> Command {
+    match (&run.command, &run.script) {
+        (Some(command), _) => {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", command]);
+            cmd
+        }
+        (None, Some(script)) => Command::new(script),
+        (None, None) => unreachable!("user steps have a command or script"),
+    }
+}
+
+/// Passes each variable in `ctx` to `cmd` as an environment variable with a
+/// `WIMSY_` prefix.
+pub(crate) fn pass_context(cmd: &mut Command, ctx: &Context) {
+    for (var, value) in ctx.vars() {
+        cmd.env(format!("WIMSY_{}", var.to_ascii_uppercase()), value);
+    }
+}
+
+/// Runs a user-supplied command. Each variable in the script context is passed
+/// to the command as an environment variable with a `WIMSY_` prefix (e.g. the
+/// `output_image` variable is passed as `WIMSY_OUTPUT_IMAGE`).
+///
+/// The command can add or change context variables for subsequent steps by
+/// writing `name=value` lines to the file named by `WIMSY_CONTEXT_OUT`.
+fn run_user_command(
+    name: &str,
+    run: &UserCommand,
+    context_out: &Utf8Path,
+    ctx: &mut Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let mut cmd = user_command(run);
+    pass_context(&mut cmd, ctx);
+
+    match std::fs::remove_file(context_out) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| {
+                format!("removing stale context file '{context_out}'")
+            });
+        }
+        _ => {}
+    }
+
+    cmd.env("WIMSY_STEP", name).env("WIMSY_CONTEXT_OUT", context_out);
+    run_command_check_status(&mut cmd, ui)?;
+
+    let contents = match std::fs::read_to_string(context_out) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("reading context file '{context_out}'")
+            })
+        }
+    };
+
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let (var, value) = line.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!(
+                "line '{line}' in '{context_out}' is not of the form \
+                name=value"
+            )
+        })?;
+        ctx.set_var(var.trim(), value.to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{InsertedStep, ReplacedStep};
+
+    fn builtins() -> Vec<Box<dyn Step>> {
+        ["a", "b", "c"]
+            .into_iter()
+            .map(|name| ScriptStep::new(name, name, |_, _| Ok(())).into())
+            .collect()
+    }
+
+    fn command() -> UserCommand {
+        UserCommand {
+            command: Some("true".to_string()),
+            script: None,
+            label: None,
+        }
+    }
+
+    fn insert(name: &str, anchor: Anchor) -> InsertedStep {
+        InsertedStep { name: name.to_string(), anchor, run: command() }
+    }
+
+    fn names(plan: &Plan) -> Vec<String> {
+        plan.steps().iter().map(|s| s.name().to_string()).collect()
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let builtins = builtins();
+        let overrides = StepOverrides {
+            disable: vec!["c".to_string()],
+            insert: vec![
+                insert("y", Anchor::After("x".to_string())),
+                insert("x", Anchor::After("a".to_string())),
+                insert("z", Anchor::After("a".to_string())),
+                insert("w", Anchor::Before("b".to_string())),
+            ],
+            replace: vec![ReplacedStep {
+                step: "b".to_string(),
+                run: command(),
+            }],
+        };
+
+        let plan =
+            Plan::new(&builtins, &overrides, Utf8Path::new(".")).unwrap();
+        assert_eq!(names(&plan), ["a", "x", "y", "z", "w", "b"]);
+        assert!(matches!(plan.steps[5], PlannedStep::Replaced { .. }));
+    }
+
+    #[test]
+    fn rejects_bad_references() {
+        let builtins = builtins();
+        for (overrides, expected) in [
+            (
+                StepOverrides {
+                    disable: vec!["d".to_string()],
+                    ..Default::default()
+                },
+                "configuration disables unknown step 'd'",
+            ),
+            (
+                StepOverrides {
+                    insert: vec![insert("x", Anchor::Before("d".to_string()))],
+                    ..Default::default()
+                },
+                "step 'x' is inserted before d, but there is no step",
+            ),
+            (
+                StepOverrides {
+                    disable: vec!["a".to_string()],
+                    insert: vec![insert("x", Anchor::After("a".to_string()))],
+                    ..Default::default()
+                },
+                "step 'x' is inserted after a, but that step is disabled",
+            ),
+            (
+                StepOverrides {
+                    insert: vec![
+                        insert("x", Anchor::After("y".to_string())),
+                        insert("y", Anchor::Before("z".to_string())),
+                        insert("z", Anchor::After("x".to_string())),
+                    ],
+                    ..Default::default()
+                },
+                "cycle: x -> y -> z -> x",
+            ),
+        ] {
+            let err = Plan::new(&builtins, &overrides, Utf8Path::new("."))
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(err.contains(expected), "{err}");
+        }
+    }
+
+    #[test]
+    fn selects_steps() {
+        let names = ["a", "b", "c", "d"];
+        let select = |skip: &[&str], only: &[&str], start_at: Option<&str>| {
+            StepSelection {
+                skip: skip.iter().map(|name| name.to_string()).collect(),
+                only: only.iter().map(|name| name.to_string()).collect(),
+                start_at: start_at.map(str::to_string),
+            }
+            .apply(&names)
+        };
+
+        assert_eq!(select(&[], &[], None).unwrap(), [true; 4]);
+        assert_eq!(
+            select(&["b"], &[], Some("b")).unwrap(),
+            [false, false, true, true]
+        );
+        assert_eq!(
+            select(&[], &["a", "c"], None).unwrap(),
+            [true, false, true, false]
+        );
+        let err = select(&[], &[], Some("e")).unwrap_err().to_string();
+        assert_eq!(err, "--start-at names step 'e', which isn't in the plan");
+    }
+
+    /// A step that implements [`Step`] itself instead of wrapping a function.
+    struct Eject;
+
+    impl Step for Eject {
+        fn name(&self) -> &str {
+            "eject"
+        }
+
+        fn label(&self) -> &str {
+            "eject installation media"
+        }
+
+        fn required_tools(&self) -> &[&'static str] {
+            &["eject"]
+        }
+
+        fn execute(&self, _: &mut Context, _: &dyn Ui) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plans_steps_of_any_kind() {
+        let mut builtins = builtins();
+        builtins.insert(1, Box::new(Eject));
+        let overrides = StepOverrides {
+            insert: vec![insert("x", Anchor::After("eject".to_string()))],
+            ..Default::default()
+        };
+
+        let plan =
+            Plan::new(&builtins, &overrides, Utf8Path::new(".")).unwrap();
+        assert_eq!(names(&plan), ["a", "eject", "x", "b", "c"]);
+        assert_eq!(
+            plan.steps()[1].dry_run(&mut Context::new(Default::default())),
+            ["runs eject with arguments determined during the build"]
+        );
+    }
+}
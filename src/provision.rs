@@ -0,0 +1,369 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs user-supplied PowerShell scripts in the guest after Windows and the
+//! Oxide tools have been installed, and copies user-supplied files into the
+//! image.
+//!
+//! The scripts are staged in the unattend directory (and so end up on the
+//! guest configuration disc) in the order they were given. The guest setup
+//! script runs each of them before the image is cleaned up and generalized
+//! and reports each script's exit status on the serial port, which wimsy
+//! copies into the build log and report. A script that fails stops the build.
+//!
+//! Files to copy are staged alongside the scripts, together with a generated
+//! script that copies them to their destinations. That script runs before
+//! any of the user's, so their scripts can use the files (e.g. to run an
+//! installer).
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    json::Json, runner::Context, trace, ui::Ui, util::powershell_quote,
+};
+
+/// The name of the directory in the unattend directory that holds provisioning
+/// scripts.
+pub const PROVISION_DIR: &str = "Provision";
+
+/// The context variable that lists the provisioning scripts, one per line.
+pub const PROVISION_SCRIPTS_VAR: &str = "provision_scripts";
+
+/// The name of the directory in the unattend directory that holds files to
+/// copy into the image.
+pub const FILES_DIR: &str = "Files";
+
+/// The context variable that lists the files to copy into the image, one per
+/// line, each as a source path and destination separated by a tab.
+pub const PROVISION_FILES_VAR: &str = "provision_files";
+
+/// The name of the generated script that copies files into place. It sorts
+/// before the names of the user's scripts.
+const COPY_FILES_SCRIPT: &str = "00-copy-files.ps1";
+
+/// The prefix with which the guest setup script reports a provisioning
+/// script's exit status, e.g. `WIMSY-PROVISIONED: 01-tools.ps1 0`.
+pub const RESULT_MARKER: &str = "WIMSY-PROVISIONED:";
+
+/// Checks that each of `paths` is a PowerShell script that exists, returning
+/// a message for each that isn't.
+pub fn check_prerequisites(paths: &[Utf8PathBuf]) -> Vec<String> {
+    let mut errors = crate::util::check_file_prerequisites(paths);
+    for path in paths {
+        if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ps1"))
+        {
+            errors.push(format!(
+                "provisioning script '{path}' should be a PowerShell script \
+                with a .ps1 extension"
+            ));
+        }
+    }
+
+    errors
+}
+
+/// A host file to copy into the image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileCopy {
+    /// The file's path on the host.
+    pub source: Utf8PathBuf,
+
+    /// Where to put the file in the guest: an absolute Windows path, naming
+    /// a directory to copy the file into if it ends in a backslash.
+    pub dest: String,
+}
+
+impl FileCopy {
+    pub fn new(source: Utf8PathBuf, dest: &str) -> Result<Self, String> {
+        let bytes = dest.as_bytes();
+        if bytes.len() < 3
+            || !bytes[0].is_ascii_alphabetic()
+            || &bytes[1..3] != b":\\"
+        {
+            return Err(format!(
+                "'{dest}' isn't an absolute Windows path like C:\\setup\\"
+            ));
+        }
+
+        if dest.contains(['\t', '\n', '"', '*', '?', '<', '>', '|']) {
+            return Err(format!("'{dest}' contains an invalid character"));
+        }
+
+        Ok(Self { source, dest: dest.to_string() })
+    }
+
+    /// Returns the path the file is copied to in the guest.
+    pub fn target(&self) -> String {
+        if self.dest.ends_with('\\') {
+            format!("{}{}", self.dest, self.source.file_name().unwrap_or(""))
+        } else {
+            self.dest.clone()
+        }
+    }
+}
+
+impl std::str::FromStr for FileCopy {
+    type Err = String;
+
+    /// Parses `SOURCE=DEST`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, dest) = s.split_once('=').ok_or_else(|| {
+            format!("'{s}' should be SOURCE=DEST, e.g. agent.msi=C:\\setup\\")
+        })?;
+        Self::new(Utf8PathBuf::from(source), dest)
+    }
+}
+
+impl std::fmt::Display for FileCopy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.source, self.target())
+    }
+}
+
+/// Checks that each of `files` names a file that exists, returning a message
+/// for each that doesn't.
+pub fn check_file_prerequisites(files: &[FileCopy]) -> Vec<String> {
+    files
+        .iter()
+        .filter(|file| !file.source.is_file())
+        .map(|file| {
+            format!("file to copy into the image '{}' not found", file.source)
+        })
+        .collect()
+}
+
+/// Returns the encoding of `files` in the provisioning files variable.
+pub fn files_var(files: &[FileCopy]) -> String {
+    itertools::join(
+        files.iter().map(|file| format!("{}\t{}", file.source, file.dest)),
+        "\n",
+    )
+}
+
+fn files_from_var(var: &str) -> Vec<FileCopy> {
+    var.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(source, dest)| FileCopy {
+            source: Utf8PathBuf::from(source),
+            dest: dest.to_string(),
+        })
+        .collect()
+}
+
+/// Returns a PowerShell script that copies each of `files`, staged under the
+/// names in `staged`, to its destination.
+fn copy_files_script(files: &[FileCopy], staged: &[String]) -> String {
+    let mut script = vec![
+        "# Generated by wimsy to copy files into the image.".to_string(),
+        "$ErrorActionPreference = 'stop'".to_string(),
+        format!(
+            "$filesDir = Join-Path (Split-Path $PSScriptRoot) {}",
+            powershell_quote(FILES_DIR)
+        ),
+    ];
+
+    for (file, name) in files.iter().zip(staged) {
+        let target = file.target();
+        let (dir, _) = target.rsplit_once('\\').unwrap();
+        script.push(format!(
+            "Write-Host {}",
+            powershell_quote(&format!("Copying {name} to {target}"))
+        ));
+        script.push(format!(
+            "New-Item -ItemType Directory -Force -Path {} | Out-Null",
+            powershell_quote(&format!("{dir}\\"))
+        ));
+        script.push(format!(
+            "Copy-Item -LiteralPath (Join-Path $filesDir {}) -Destination {} \
+            -Force",
+            powershell_quote(name),
+            powershell_quote(&target)
+        ));
+    }
+
+    script.push(String::new());
+    script.join("\r\n")
+}
+
+/// Returns the name under which the `index`th provisioning script (counting
+/// from 0) is staged. The names sort in the order the scripts were given.
+fn staged_name(index: usize, path: &Utf8Path) -> String {
+    format!("{:02}-{}", index + 1, path.file_name().unwrap_or("script.ps1"))
+}
+
+/// Parses a line of guest serial output that reports a provisioning script's
+/// exit status, returning the script's staged name and status.
+pub fn parse_result(line: &str) -> Option<(String, i64)> {
+    let (_, result) = line.split_once(RESULT_MARKER)?;
+    let (script, status) = result.trim().rsplit_once(' ')?;
+    Some((script.trim().to_string(), status.parse().ok()?))
+}
+
+/// Copies each script listed in the provisioning scripts variable to the
+/// provisioning directory in `unattend_dir`.
+pub fn stage_provision_scripts(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(paths) = ctx.get_var(PROVISION_SCRIPTS_VAR) else {
+        return Ok(());
+    };
+
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    let dst_dir = unattend_dir.join(PROVISION_DIR);
+    std::fs::create_dir_all(&dst_dir)
+        .with_context(|| format!("creating '{dst_dir}'"))?;
+
+    let mut staged = Vec::new();
+    for (index, path) in paths.lines().map(Utf8Path::new).enumerate() {
+        let name = staged_name(index, path);
+        ui.set_substep(&format!("staging {path} as {name}"));
+        trace::debug!(
+            "staging provisioning script",
+            source = path.as_str(),
+            name = name.as_str()
+        );
+        let dst = dst_dir.join(&name);
+        std::fs::copy(path, &dst)
+            .with_context(|| format!("copying '{path}' to '{dst}'"))?;
+        staged.push(name);
+    }
+
+    ui.record_metric("provision_scripts", Json::from(staged));
+    Ok(())
+}
+
+/// Copies each file listed in the provisioning files variable to the files
+/// directory in `unattend_dir` and writes a provisioning script that copies
+/// them to their destinations in the guest.
+pub fn stage_files(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(var) = ctx.get_var(PROVISION_FILES_VAR) else {
+        return Ok(());
+    };
+
+    let files = files_from_var(var);
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    let files_dir = unattend_dir.join(FILES_DIR);
+    let provision_dir = unattend_dir.join(PROVISION_DIR);
+    for dir in [&files_dir, &provision_dir] {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating '{dir}'"))?;
+    }
+
+    let mut staged = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        let name = staged_name(index, &file.source);
+        ui.set_substep(&format!("staging {file}"));
+        trace::debug!(
+            "staging file to copy into the image",
+            source = file.source.as_str(),
+            target = file.target().as_str()
+        );
+        let dst = files_dir.join(&name);
+        std::fs::copy(&file.source, &dst)
+            .with_context(|| format!("copying '{}' to '{dst}'", file.source))?;
+        staged.push(name);
+    }
+
+    let script = provision_dir.join(COPY_FILES_SCRIPT);
+    std::fs::write(&script, copy_files_script(&files, &staged))
+        .with_context(|| format!("writing '{script}'"))?;
+
+    ui.record_metric(
+        "provision_files",
+        Json::from(files.iter().map(FileCopy::target).collect::<Vec<_>>()),
+    );
+    Ok(())
+}
+
+pub fn describe_stage_files(ctx: &mut Context) -> Vec<String> {
+    let Some(var) = ctx.get_var(PROVISION_FILES_VAR) else {
+        return Vec::new();
+    };
+
+    let unattend_dir = Utf8Path::new(ctx.get_var("unattend_dir").unwrap());
+    let mut descriptions: Vec<_> = files_from_var(var)
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            format!(
+                "copy {} to {}/{FILES_DIR}/{}",
+                file.source,
+                unattend_dir,
+                staged_name(index, &file.source)
+            )
+        })
+        .collect();
+    descriptions.push(format!(
+        "write {unattend_dir}/{PROVISION_DIR}/{COPY_FILES_SCRIPT}"
+    ));
+    descriptions
+}
+
+pub fn describe_stage_provision_scripts(ctx: &mut Context) -> Vec<String> {
+    let Some(paths) = ctx.get_var(PROVISION_SCRIPTS_VAR) else {
+        return Vec::new();
+    };
+
+    let dst_dir =
+        Utf8Path::new(ctx.get_var("unattend_dir").unwrap()).join(PROVISION_DIR);
+    paths
+        .lines()
+        .map(Utf8Path::new)
+        .enumerate()
+        .map(|(index, path)| {
+            format!("copy {path} to {dst_dir}/{}", staged_name(index, path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_results() {
+        assert_eq!(
+            staged_name(0, Utf8Path::new("scripts/install tools.ps1")),
+            "01-install tools.ps1"
+        );
+        assert_eq!(
+            parse_result("WIMSY-PROVISIONED: 01-install tools.ps1 0\r\n"),
+            Some(("01-install tools.ps1".to_string(), 0))
+        );
+        assert_eq!(
+            parse_result("junk WIMSY-PROVISIONED: 02-a.ps1 -1"),
+            Some(("02-a.ps1".to_string(), -1))
+        );
+        assert_eq!(parse_result("WIMSY-PROVISIONED: 01-a.ps1"), None);
+        assert_eq!(parse_result("Installing 01-a.ps1 0"), None);
+    }
+
+    #[test]
+    fn copies_files() {
+        let into_dir: FileCopy = "out/agent.msi=C:\\setup\\".parse().unwrap();
+        assert_eq!(into_dir.target(), "C:\\setup\\agent.msi");
+        let renamed: FileCopy =
+            "it's.conf=C:\\Program Files\\Agent\\agent.conf".parse().unwrap();
+        assert_eq!(renamed.target(), "C:\\Program Files\\Agent\\agent.conf");
+        for bad in ["agent.msi", "agent.msi=setup\\", "a=C:/setup", "a=C:\\a|b"]
+        {
+            assert!(bad.parse::<FileCopy>().is_err(), "{bad}");
+        }
+
+        let files = [into_dir, renamed];
+        assert_eq!(files_from_var(&files_var(&files)), files);
+
+        let script = copy_files_script(
+            &files,
+            &["01-agent.msi".to_string(), "02-it's.conf".to_string()],
+        );
+        assert!(script.contains(
+            "New-Item -ItemType Directory -Force -Path \
+            'C:\\Program Files\\Agent\\' | Out-Null"
+        ));
+        assert!(script.contains(
+            "Copy-Item -LiteralPath (Join-Path $filesDir '02-it''s.conf') \
+            -Destination 'C:\\Program Files\\Agent\\agent.conf' -Force"
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Converts a finished raw output image into a compressed qcow2 image for
+//! distribution.
+//!
+//! qcow2 images can be compressed with zlib, which every qcow2 reader
+//! supports, or zstd, which yields smaller images but requires QEMU 5.1 or
+//! later (built with zstd support) both to write images and to read them.
+//! Since consumers of a zstd image need to know that, the conversion step
+//! writes the codec it used to a metadata file next to the image.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    app::{Qcow2Compression, Qcow2Options},
+    command::CommandRunner,
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// The suffix appended to the qcow2 image's path to name its metadata file.
+pub const METADATA_SUFFIX: &str = ".json";
+
+/// The compression codec the conversion step will use, given the one the user
+/// asked for and what the `qemu-img` the build runs supports.
+pub struct CodecSelection {
+    requested: Qcow2Compression,
+    selected: Qcow2Compression,
+
+    /// Why the requested codec can't be used, if it can't.
+    problem: Option<String>,
+}
+
+impl CodecSelection {
+    /// Checks whether `qemu-img`, as `runner` runs it, can write qcow2 images
+    /// with the codec `options` requests, falling back to zlib if it can't.
+    /// The probe image is written in `work_dir` (which a tools container can
+    /// see) if it exists yet.
+    pub fn probe(
+        options: &Qcow2Options,
+        runner: &dyn CommandRunner,
+        work_dir: &Utf8Path,
+    ) -> Self {
+        let requested = options.qcow2_compression;
+        let problem = match (&options.qcow2_image, requested) {
+            // zlib is the default codec, so the conversion step doesn't need
+            // to ask for it explicitly, and every qemu-img supports it.
+            (None, _) | (Some(_), Qcow2Compression::Zlib) => None,
+            (Some(_), codec) => probe_codec(codec, runner, work_dir).err(),
+        };
+
+        let selected =
+            if problem.is_some() { Qcow2Compression::Zlib } else { requested };
+        trace::debug!(
+            "selected qcow2 compression",
+            requested = requested.to_string(),
+            selected = selected.to_string(),
+            problem = problem.clone()
+        );
+
+        Self { requested, selected, problem }
+    }
+
+    pub fn selected(&self) -> Qcow2Compression {
+        self.selected
+    }
+
+    /// Adds a problem with the requested codec to a script's prerequisite
+    /// `errors` if `strict` is set, or to its `warnings` otherwise.
+    pub fn check_prerequisites(
+        &self,
+        strict: bool,
+        errors: &mut Vec<String>,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(problem) = &self.problem else {
+            return;
+        };
+
+        if strict {
+            errors.push(format!(
+                "qemu-img can't write {}-compressed qcow2 images ({problem}); \
+                install a qemu-img built with {} support, or drop \
+                --strict-qcow2-compression to fall back to zlib",
+                self.requested, self.requested
+            ));
+        } else {
+            warnings.push(format!(
+                "qemu-img can't write {}-compressed qcow2 images ({problem}), \
+                so the qcow2 image will be compressed with {} instead",
+                self.requested, self.selected
+            ));
+        }
+    }
+}
+
+/// Tries to create a small qcow2 image with the supplied compression `codec`
+/// in `work_dir`, or the system's temporary directory if the work directory
+/// doesn't exist yet, returning a description of the failure if `qemu-img`
+/// (as `runner` runs it) can't.
+fn probe_codec(
+    codec: Qcow2Compression,
+    runner: &dyn CommandRunner,
+    work_dir: &Utf8Path,
+) -> std::result::Result<(), String> {
+    let dir = if work_dir.is_dir() {
+        work_dir.to_path_buf()
+    } else {
+        Utf8PathBuf::try_from(std::env::temp_dir())
+            .map_err(|e| format!("temporary directory path isn't UTF-8: {e}"))?
+    };
+    let path =
+        dir.join(format!("wimsy-qcow2-probe-{}.qcow2", std::process::id()));
+    let output = runner.output(
+        Command::new("qemu-img")
+            .args(["create", "-f", "qcow2", "-o"])
+            .arg(format!("compression_type={codec}"))
+            .args([path.as_str(), "1M"]),
+    );
+    let _ = std::fs::remove_file(&path);
+
+    let output = output.map_err(|e| format!("couldn't run qemu-img: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(match stderr.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.trim().to_string(),
+        None => format!("qemu-img exited with {}", output.status),
+    })
+}
+
+/// Returns the path of the metadata file that describes the qcow2 image at
+/// `image`.
+pub fn metadata_path(image: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{image}{METADATA_SUFFIX}"))
+}
+
+/// Returns the codec named by the `qcow2_compression` context variable.
+fn compression(ctx: &Context) -> Result<Qcow2Compression> {
+    match ctx.get_var("qcow2_compression") {
+        Some(codec) => clap::ValueEnum::from_str(codec, false)
+            .map_err(|e| anyhow::anyhow!("invalid qcow2 compression: {e}")),
+        None => Ok(Qcow2Compression::Zlib),
+    }
+}
+
+/// Returns the command that converts `output_image` (in `format`) to a qcow2
+/// image at `qcow2_image` compressed with `codec`.
+pub fn command(
+    output_image: &Utf8Path,
+    format: &str,
+    qcow2_image: &Utf8Path,
+    codec: Qcow2Compression,
+) -> Command {
+    let mut cmd = Command::new("qemu-img");
+    cmd.args(["convert", "-f", format]);
+    cmd.args(["-O", "qcow2", "-c"]);
+    if codec != Qcow2Compression::Zlib {
+        cmd.arg("-o").arg(format!("compression_type={codec}"));
+    }
+    cmd.args([output_image.as_str(), qcow2_image.as_str()]);
+    cmd
+}
+
+/// Describes the command [`convert_output_image`] would run.
+pub fn describe_conversion(ctx: &mut Context) -> Vec<String> {
+    let Some(qcow2_image) = ctx.get_var("qcow2_image") else {
+        return Vec::new();
+    };
+
+    let qcow2_image = Utf8Path::new(qcow2_image);
+    let output_image = Utf8Path::new(ctx.get_var("output_image").unwrap());
+    let codec = compression(ctx).unwrap_or(Qcow2Compression::Zlib);
+    let format = crate::steps::output_format(ctx);
+    let cmd = command(output_image, format, qcow2_image, codec);
+    vec![
+        format_command(&cmd),
+        crate::steps::describe_get_image_virtual_size(
+            output_image.as_str(),
+            format,
+        ),
+        format!(
+            "write a description of the image to {}",
+            metadata_path(qcow2_image)
+        ),
+    ]
+}
+
+/// Converts the raw output image to the compressed qcow2 image named by the
+/// `qcow2_image` context variable using the codec in `qcow2_compression`,
+/// then writes the image's metadata file. Does nothing if no qcow2 image was
+/// requested.
+pub fn convert_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(qcow2_image) = ctx.get_var("qcow2_image") else {
+        return Ok(());
+    };
+
+    let qcow2_image = Utf8PathBuf::from(qcow2_image);
+    let output_image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let format = crate::steps::output_format(ctx);
+    let codec = compression(ctx)?;
+    let mut cmd = command(&output_image, format, &qcow2_image, codec);
+    // Conversions are heavy on the disks, so builds that share the host take
+    // turns.
+    let slot = crate::slots::take_conversion_slot(ctx, ui)?;
+    let cancel = crate::interrupt::remove_on_cancel(&qcow2_image);
+    run_command_check_status(&mut cmd, ui)?;
+    drop(cancel);
+    drop(slot);
+
+    let virtual_size = crate::steps::get_image_virtual_size(
+        output_image.as_str(),
+        format,
+        ui,
+    )?;
+    let file_size =
+        write_metadata(&qcow2_image, &output_image, codec, virtual_size)?;
+    ui.record_metric("qcow2_compression", Json::from(codec.to_string()));
+    ui.record_metric("qcow2_image_size_bytes", Json::from(file_size));
+    Ok(())
+}
+
+/// Writes the metadata file for the qcow2 image at `qcow2_image`, which was
+/// converted from `source_image` with `codec` and presents a disk of
+/// `virtual_size` bytes. Returns the qcow2 image's size.
+pub fn write_metadata(
+    qcow2_image: &Utf8Path,
+    source_image: &Utf8Path,
+    codec: Qcow2Compression,
+    virtual_size: u64,
+) -> Result<u64> {
+    let file_size = std::fs::metadata(qcow2_image)
+        .with_context(|| format!("reading metadata for '{qcow2_image}'"))?
+        .len();
+
+    let metadata = Json::object()
+        .with("format", "qcow2")
+        .with("compression_type", codec.to_string())
+        .with(
+            "minimum_qemu_version",
+            match codec {
+                Qcow2Compression::Zlib => Json::Null,
+                Qcow2Compression::Zstd => Json::from("5.1"),
+            },
+        )
+        .with("virtual_size_bytes", virtual_size)
+        .with("file_size_bytes", file_size)
+        .with("source_image", source_image.as_str())
+        .with("created_by", concat!("wimsy ", env!("CARGO_PKG_VERSION")));
+    let metadata_path = metadata_path(qcow2_image);
+    std::fs::write(&metadata_path, format!("{metadata:#}\n"))
+        .with_context(|| format!("writing '{metadata_path}'"))?;
+    Ok(file_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::RecordingRunner;
+
+    #[test]
+    fn probes_codec_with_the_build_runner() {
+        let options = Qcow2Options {
+            qcow2_image: Some("/out/image.qcow2".into()),
+            qcow2_compression: Qcow2Compression::Zstd,
+            strict_qcow2_compression: false,
+        };
+        let work_dir = Utf8Path::new("/nonexistent/wimsy-work");
+        let check = |selection: &CodecSelection, strict| {
+            let (mut errors, mut warnings) = (Vec::new(), Vec::new());
+            selection.check_prerequisites(strict, &mut errors, &mut warnings);
+            (errors, warnings)
+        };
+
+        let runner = RecordingRunner::new();
+        let selection = CodecSelection::probe(&options, &runner, work_dir);
+        assert_eq!(selection.selected(), Qcow2Compression::Zstd);
+        assert_eq!(check(&selection, true), (Vec::new(), Vec::new()));
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0]
+            .starts_with("qemu-img create -f qcow2 -o compression_type=zstd"));
+
+        let runner = RecordingRunner::new().respond("qemu-img create", 1, "");
+        let selection = CodecSelection::probe(&options, &runner, work_dir);
+        assert_eq!(selection.selected(), Qcow2Compression::Zlib);
+        let (errors, warnings) = check(&selection, false);
+        assert!(errors.is_empty());
+        assert!(warnings[0].contains("compressed with zlib instead"));
+        let (errors, warnings) = check(&selection, true);
+        assert!(errors[0].contains("--strict-qcow2-compression"));
+        assert!(warnings.is_empty());
+
+        let zlib = Qcow2Options {
+            qcow2_compression: Qcow2Compression::Zlib,
+            ..options
+        };
+        let runner = RecordingRunner::new();
+        CodecSelection::probe(&zlib, &runner, work_dir);
+        assert!(runner.commands().is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads values from Windows registry hive files, such as the SOFTWARE hive
+//! in which Windows records its version.
+//!
+//! A hive is a base block followed by bins of cells, each holding a key
+//! node, a value, a list of either, or a value's data; cells refer to each
+//! other by offset from the end of the base block. Changes still in a hive's
+//! transaction logs aren't seen, but Windows flushes them to the hive when it
+//! shuts down cleanly.
+
+use anyhow::Result;
+
+/// The size of a hive's base block, which cell offsets are relative to the
+/// end of.
+const BASE_BLOCK_SIZE: usize = 4096;
+
+/// Set in a key node's flags, or a value's, if its name is stored as Latin-1
+/// instead of UTF-16.
+const KEY_COMP_NAME: u16 = 0x20;
+const VALUE_COMP_NAME: u16 = 0x1;
+
+/// Set in a value's data size if its data is stored in place of the data's
+/// offset.
+const DATA_IN_OFFSET: u32 = 0x8000_0000;
+
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_DWORD: u32 = 4;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2);
+    bytes
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("registry cell is truncated"))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4);
+    bytes
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("registry cell is truncated"))
+}
+
+/// Decodes a name stored as Latin-1 if `latin1`, and as UTF-16LE otherwise.
+fn decode_name(bytes: &[u8], latin1: bool) -> String {
+    if latin1 {
+        return bytes.iter().map(|&b| char::from(b)).collect();
+    }
+    let units = bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// A registry value's data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Dword(u32),
+
+    /// Data of another type, which this reader doesn't decode.
+    Other(u32),
+}
+
+/// A registry hive read into memory.
+pub struct Hive {
+    data: Vec<u8>,
+}
+
+impl Hive {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        if data.len() < BASE_BLOCK_SIZE || &data[..4] != b"regf" {
+            anyhow::bail!("the file isn't a registry hive");
+        }
+        Ok(Self { data })
+    }
+
+    /// Returns the contents of the cell at `offset`.
+    fn cell(&self, offset: u32) -> Result<&[u8]> {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        let size = u32_at(&self.data, start)? as i32;
+        self.data
+            .get(start + 4..start + size.unsigned_abs() as usize)
+            .ok_or_else(|| {
+                anyhow::anyhow!("registry cell at offset {offset} is corrupt")
+            })
+    }
+
+    /// Returns the key node at `offset`.
+    fn key_node(&self, offset: u32) -> Result<&[u8]> {
+        let node = self.cell(offset)?;
+        if !node.starts_with(b"nk") || node.len() < 0x4c {
+            anyhow::bail!("registry key at offset {offset} is corrupt");
+        }
+        Ok(node)
+    }
+
+    fn key_name(&self, node: &[u8]) -> Result<String> {
+        let length = usize::from(u16_at(node, 0x48)?);
+        let name = node.get(0x4c..0x4c + length).ok_or_else(|| {
+            anyhow::anyhow!("registry key's name is out of bounds")
+        })?;
+        Ok(decode_name(name, u16_at(node, 2)? & KEY_COMP_NAME != 0))
+    }
+
+    /// Finds the key called `name` in the subkey list at `list`.
+    fn find_subkey(&self, list: u32, name: &str) -> Result<Option<u32>> {
+        let cell = self.cell(list)?;
+        let count = usize::from(u16_at(cell, 2)?);
+        let stride = match cell.get(..2) {
+            Some(b"lf" | b"lh") => 8,
+            Some(b"li" | b"ri") => 4,
+            _ => anyhow::bail!("registry subkey list is corrupt"),
+        };
+
+        for i in 0..count {
+            let offset = u32_at(cell, 4 + stride * i)?;
+            let found = if cell.starts_with(b"ri") {
+                // An index of other subkey lists.
+                self.find_subkey(offset, name)?
+            } else {
+                let node = self.key_node(offset)?;
+                (self.key_name(node)?.to_uppercase() == name.to_uppercase())
+                    .then_some(offset)
+            };
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the key at `path` (names separated by backslashes, matched
+    /// ignoring case) under the hive's root key.
+    fn find_key(&self, path: &str) -> Result<Option<u32>> {
+        let mut key = u32_at(&self.data, 0x24)?;
+        for name in path.split('\\') {
+            let node = self.key_node(key)?;
+            if u32_at(node, 0x14)? == 0 {
+                return Ok(None);
+            }
+            match self.find_subkey(u32_at(node, 0x1c)?, name)? {
+                Some(subkey) => key = subkey,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(key))
+    }
+
+    /// Returns the value called `name` of the key at `path`, if both exist.
+    pub fn value(&self, path: &str, name: &str) -> Result<Option<Value>> {
+        let Some(key) = self.find_key(path)? else {
+            return Ok(None);
+        };
+        let node = self.key_node(key)?;
+        let count = u32_at(node, 0x24)? as usize;
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let list = self.cell(u32_at(node, 0x28)?)?;
+        for i in 0..count {
+            let value = self.cell(u32_at(list, 4 * i)?)?;
+            if !value.starts_with(b"vk") {
+                anyhow::bail!("registry value of key '{path}' is corrupt");
+            }
+            let name_length = usize::from(u16_at(value, 2)?);
+            let latin1 = u16_at(value, 0x10)? & VALUE_COMP_NAME != 0;
+            let value_name = value
+                .get(0x14..0x14 + name_length)
+                .map(|bytes| decode_name(bytes, latin1))
+                .unwrap_or_default();
+            if value_name.to_uppercase() == name.to_uppercase() {
+                return self.decode_value(value).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_value(&self, value: &[u8]) -> Result<Value> {
+        let size = u32_at(value, 4)?;
+        let kind = u32_at(value, 0xc)?;
+        let data = if size & DATA_IN_OFFSET != 0 {
+            let length = ((size & !DATA_IN_OFFSET) as usize).min(4);
+            value[8..8 + length].to_vec()
+        } else {
+            let cell = self.cell(u32_at(value, 8)?)?;
+            cell.get(..size as usize)
+                .filter(|_| !cell.starts_with(b"db"))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("registry value's data is out of bounds")
+                })?
+                .to_vec()
+        };
+
+        Ok(match kind {
+            REG_SZ | REG_EXPAND_SZ => Value::String(
+                decode_name(&data, false).trim_end_matches('\0').to_string(),
+            ),
+            REG_DWORD if data.len() == 4 => {
+                Value::Dword(u32::from_le_bytes(data.try_into().unwrap()))
+            }
+            kind => Value::Other(kind),
+        })
+    }
+
+    /// Returns the string value called `name` of the key at `path`, if it
+    /// exists and is a string.
+    pub fn string(&self, path: &str, name: &str) -> Result<Option<String>> {
+        Ok(match self.value(path, name)? {
+            Some(Value::String(value)) => Some(value),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds hives cell by cell.
+    struct HiveBuilder(Vec<u8>);
+
+    impl HiveBuilder {
+        fn new() -> Self {
+            let mut data = vec![0u8; BASE_BLOCK_SIZE + 32];
+            data[..4].copy_from_slice(b"regf");
+            data[BASE_BLOCK_SIZE..BASE_BLOCK_SIZE + 4].copy_from_slice(b"hbin");
+            Self(data)
+        }
+
+        /// Appends an allocated cell holding `contents`, returning its
+        /// offset.
+        fn cell(&mut self, contents: &[u8]) -> u32 {
+            let offset = (self.0.len() - BASE_BLOCK_SIZE) as u32;
+            let size = (contents.len() + 4).next_multiple_of(8);
+            self.0.extend_from_slice(&(-(size as i32)).to_le_bytes());
+            self.0.extend_from_slice(contents);
+            self.0.resize(BASE_BLOCK_SIZE + offset as usize + size, 0);
+            offset
+        }
+
+        fn key(&mut self, name: &str, subkeys: &[u32], values: &[u32]) -> u32 {
+            let mut node = vec![0u8; 0x4c];
+            node[..2].copy_from_slice(b"nk");
+            node[2..4].copy_from_slice(&KEY_COMP_NAME.to_le_bytes());
+            if !subkeys.is_empty() {
+                let mut list = b"lf".to_vec();
+                list.extend_from_slice(&(subkeys.len() as u16).to_le_bytes());
+                for subkey in subkeys {
+                    list.extend_from_slice(&subkey.to_le_bytes());
+                    list.extend_from_slice(&[0; 4]);
+                }
+                let list = self.cell(&list);
+                node[0x14..0x18]
+                    .copy_from_slice(&(subkeys.len() as u32).to_le_bytes());
+                node[0x1c..0x20].copy_from_slice(&list.to_le_bytes());
+            }
+            if !values.is_empty() {
+                let list: Vec<u8> =
+                    values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                let list = self.cell(&list);
+                node[0x24..0x28]
+                    .copy_from_slice(&(values.len() as u32).to_le_bytes());
+                node[0x28..0x2c].copy_from_slice(&list.to_le_bytes());
+            }
+            node[0x48..0x4a]
+                .copy_from_slice(&(name.len() as u16).to_le_bytes());
+            node.extend_from_slice(name.as_bytes());
+            self.cell(&node)
+        }
+
+        fn value(&mut self, name: &str, kind: u32, data: &[u8]) -> u32 {
+            let mut value = vec![0u8; 0x14];
+            value[..2].copy_from_slice(b"vk");
+            value[2..4].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            if data.len() <= 4 {
+                let size = data.len() as u32 | DATA_IN_OFFSET;
+                value[4..8].copy_from_slice(&size.to_le_bytes());
+                value[8..8 + data.len()].copy_from_slice(data);
+            } else {
+                let cell = self.cell(data);
+                value[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                value[8..12].copy_from_slice(&cell.to_le_bytes());
+            }
+            value[0xc..0x10].copy_from_slice(&kind.to_le_bytes());
+            value[0x10..0x12].copy_from_slice(&VALUE_COMP_NAME.to_le_bytes());
+            value.extend_from_slice(name.as_bytes());
+            self.cell(&value)
+        }
+
+        fn finish(mut self, root: u32) -> Hive {
+            self.0[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+            Hive::new(self.0).unwrap()
+        }
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn reads_values() {
+        let mut builder = HiveBuilder::new();
+        let product = builder.value(
+            "ProductName",
+            REG_SZ,
+            &utf16("Windows Server 2022 Datacenter"),
+        );
+        let ubr = builder.value("UBR", REG_DWORD, &2113u32.to_le_bytes());
+        let version = builder.key("CurrentVersion", &[], &[product, ubr]);
+        let nt = builder.key("Windows NT", &[version], &[]);
+        let other = builder.key("Classes", &[], &[]);
+        let microsoft = builder.key("Microsoft", &[other, nt], &[]);
+        let root = builder.key("ROOT", &[microsoft], &[]);
+        let hive = builder.finish(root);
+
+        let path = "microsoft\\WINDOWS NT\\CurrentVersion";
+        assert_eq!(
+            hive.string(path, "productname").unwrap().as_deref(),
+            Some("Windows Server 2022 Datacenter")
+        );
+        assert_eq!(hive.value(path, "UBR").unwrap(), Some(Value::Dword(2113)));
+        assert_eq!(hive.value(path, "EditionID").unwrap(), None);
+        assert_eq!(hive.value("Microsoft\\Windows", "UBR").unwrap(), None);
+        assert!(Hive::new(vec![0; BASE_BLOCK_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Registry values set from the configuration file's `[[registry]]` tables.
+//!
+//! The `write-registry-settings` step compiles the values into a `.reg` file
+//! in the unattend directory. `OxidePrepBaseImage.ps1` copies it into the
+//! image, where the repo's specialize-unattend.xml imports it with `reg.exe
+//! import` in the specialize pass; images built with `--skip-generalize`,
+//! which never run that pass, import it during setup instead.
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{runner::Context, ui::Ui};
+
+/// The name of the compiled registry file in the unattend directory, and in
+/// the image's `C:\Windows\Setup\Scripts`.
+pub const REGISTRY_FILE: &str = "WimsyRegistry.reg";
+
+/// The context variable holding the compiled registry file's contents.
+pub const REGISTRY_VAR: &str = "registry_settings";
+
+/// The value types `[[registry]]` tables can set.
+pub const TYPES: [&str; 6] = [
+    "REG_SZ",
+    "REG_EXPAND_SZ",
+    "REG_MULTI_SZ",
+    "REG_DWORD",
+    "REG_QWORD",
+    "REG_BINARY",
+];
+
+/// The root key names the settings can be under, and the name `.reg` files
+/// use for each. The specialize pass runs as SYSTEM, before any user has a
+/// profile, so only machine-wide keys make sense.
+const ROOTS: [(&str, &str); 2] = [
+    ("HKLM", "HKEY_LOCAL_MACHINE"),
+    ("HKEY_LOCAL_MACHINE", "HKEY_LOCAL_MACHINE"),
+];
+
+/// A registry value's data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Data {
+    String(String),
+    ExpandString(String),
+    MultiString(Vec<String>),
+    Dword(u32),
+    Qword(u64),
+    Binary(Vec<u8>),
+}
+
+/// A registry value to set in built images.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistrySetting {
+    /// The full path of the key, starting with `HKEY_LOCAL_MACHINE\`.
+    pub key: String,
+
+    /// The value's name, or `None` for the key's default value.
+    pub name: Option<String>,
+    pub data: Data,
+}
+
+impl RegistrySetting {
+    pub fn new(
+        path: &str,
+        name: Option<String>,
+        data: Data,
+    ) -> Result<Self, String> {
+        let path = path.trim_end_matches('\\');
+        let (root, subkey) = path.split_once('\\').unwrap_or((path, ""));
+        let Some((_, root)) =
+            ROOTS.iter().find(|(name, _)| name.eq_ignore_ascii_case(root))
+        else {
+            return Err(format!(
+                "'{path}' isn't under HKLM (HKEY_LOCAL_MACHINE), the only \
+                root key settings can be applied to"
+            ));
+        };
+        if subkey.is_empty()
+            || subkey.split('\\').any(str::is_empty)
+            || subkey.contains(['[', ']', '\r', '\n'])
+        {
+            return Err(format!("'{path}' isn't a valid key path"));
+        }
+
+        let mut strings = match &data {
+            Data::String(s) | Data::ExpandString(s) => vec![s.as_str()],
+            Data::MultiString(strings) => {
+                strings.iter().map(String::as_str).collect()
+            }
+            _ => Vec::new(),
+        };
+        strings.extend(name.as_deref());
+        if strings.iter().any(|s| s.contains(['\r', '\n', '\0'])) {
+            return Err(format!(
+                "the settings for '{path}' can't contain line breaks or NUL \
+                characters"
+            ));
+        }
+        let has_empty_string = match &data {
+            Data::MultiString(strings) => strings.iter().any(String::is_empty),
+            _ => false,
+        };
+        if has_empty_string {
+            return Err(format!(
+                "a REG_MULTI_SZ value in '{path}' can't contain empty strings"
+            ));
+        }
+
+        Ok(Self { key: format!("{root}\\{subkey}"), name, data })
+    }
+}
+
+/// Parses the data of a REG_BINARY setting, written as hex bytes that may be
+/// separated by commas or spaces (e.g. "de,ad,be,ef").
+pub fn parse_binary(s: &str) -> Result<Vec<u8>, String> {
+    let digits: String =
+        s.chars().filter(|c| !matches!(c, ',' | ' ')).collect();
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{s}' isn't hex bytes like \"de,ad\""));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("'{s}' has an odd number of hex digits"));
+    }
+
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Quotes `s` as a `.reg` file string.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Formats `bytes` as a `.reg` file's comma-separated hex bytes.
+fn hex(bytes: impl IntoIterator<Item = u8>) -> String {
+    itertools::join(bytes.into_iter().map(|b| format!("{b:02x}")), ",")
+}
+
+/// Returns `s`, followed by a NUL, in UTF-16LE, as Windows stores strings.
+fn utf16(s: &str) -> impl Iterator<Item = u8> + '_ {
+    s.encode_utf16().chain([0]).flat_map(u16::to_le_bytes)
+}
+
+/// Compiles `settings` into the contents of a `.reg` file.
+pub fn compile(settings: &[RegistrySetting]) -> String {
+    let mut lines = vec!["Windows Registry Editor Version 5.00".to_string()];
+    let mut key = None;
+    for setting in settings {
+        if key != Some(&setting.key) {
+            lines.push(String::new());
+            lines.push(format!("[{}]", setting.key));
+            key = Some(&setting.key);
+        }
+
+        let data = match &setting.data {
+            Data::String(s) => quote(s),
+            Data::ExpandString(s) => format!("hex(2):{}", hex(utf16(s))),
+            Data::MultiString(strings) => {
+                let bytes = strings.iter().flat_map(|s| utf16(s));
+                format!("hex(7):{}", hex(bytes.chain([0, 0])))
+            }
+            Data::Dword(value) => format!("dword:{value:08x}"),
+            Data::Qword(value) => {
+                format!("hex(b):{}", hex(value.to_le_bytes()))
+            }
+            Data::Binary(bytes) => {
+                format!("hex:{}", hex(bytes.iter().copied()))
+            }
+        };
+        let name = setting.name.as_deref().map_or("@".to_string(), quote);
+        lines.push(format!("{name}={data}"));
+    }
+
+    lines.push(String::new());
+    lines.join("\r\n")
+}
+
+/// Writes the compiled registry file in the context's registry variable to
+/// the unattend directory, in the UTF-16LE encoding `reg.exe` expects.
+pub fn write_registry_settings(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(contents) = ctx.get_var(REGISTRY_VAR) else {
+        return Ok(());
+    };
+
+    let unattend_dir = Utf8PathBuf::from(ctx.get_var("unattend_dir").unwrap());
+    let path = unattend_dir.join(REGISTRY_FILE);
+    ui.set_substep(&format!("writing {path}"));
+    let bytes: Vec<u8> = [0xfeff]
+        .into_iter()
+        .chain(contents.encode_utf16())
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    std::fs::write(&path, bytes).with_context(|| format!("writing '{path}'"))
+}
+
+pub fn describe_write_registry_settings(ctx: &mut Context) -> Vec<String> {
+    let Some(contents) = ctx.get_var(REGISTRY_VAR) else {
+        return Vec::new();
+    };
+
+    let count =
+        contents.lines().filter(|line| line.starts_with(['"', '@'])).count();
+    vec![format!(
+        "write {count} registry setting(s) to {}/{REGISTRY_FILE}",
+        ctx.get_var("unattend_dir").unwrap()
+    )]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compiles_registry_settings() {
+        let setting = |path: &str, name: Option<&str>, data| {
+            RegistrySetting::new(path, name.map(str::to_string), data).unwrap()
+        };
+        let settings = [
+            setting(
+                "HKLM\\SOFTWARE\\Policies\\Microsoft\\Windows\\DataCollection",
+                Some("AllowTelemetry"),
+                Data::Dword(0),
+            ),
+            setting(
+                "hklm\\SOFTWARE\\Policies\\Microsoft\\Windows\\DataCollection\\",
+                Some("Note"),
+                Data::String("C:\\say \"hi\"".to_string()),
+            ),
+            setting(
+                "HKEY_LOCAL_MACHINE\\SOFTWARE\\Wimsy",
+                None,
+                Data::ExpandString("%A%".to_string()),
+            ),
+            setting(
+                "HKLM\\SOFTWARE\\Wimsy",
+                Some("List"),
+                Data::MultiString(vec!["a".to_string(), "b".to_string()]),
+            ),
+            setting("HKLM\\SOFTWARE\\Wimsy", Some("Big"), Data::Qword(1 << 32)),
+            setting(
+                "HKLM\\SOFTWARE\\Wimsy",
+                Some("Blob"),
+                Data::Binary(parse_binary("de,AD be").unwrap()),
+            ),
+        ];
+
+        assert_eq!(
+            compile(&settings),
+            [
+                "Windows Registry Editor Version 5.00",
+                "",
+                "[HKEY_LOCAL_MACHINE\\SOFTWARE\\Policies\\Microsoft\\Windows\\\
+                DataCollection]",
+                "\"AllowTelemetry\"=dword:00000000",
+                "\"Note\"=\"C:\\\\say \\\"hi\\\"\"",
+                "",
+                "[HKEY_LOCAL_MACHINE\\SOFTWARE\\Wimsy]",
+                "@=hex(2):25,00,41,00,25,00,00,00",
+                "\"List\"=hex(7):61,00,00,00,62,00,00,00,00,00",
+                "\"Big\"=hex(b):00,00,00,00,01,00,00,00",
+                "\"Blob\"=hex:de,ad,be",
+                "",
+            ]
+            .join("\r\n")
+        );
+
+        for path in ["HKCU\\Software\\Wimsy", "HKLM", "HKLM\\\\SOFTWARE"] {
+            assert!(
+                RegistrySetting::new(path, None, Data::Dword(1)).is_err(),
+                "{path}"
+            );
+        }
+        assert!(RegistrySetting::new(
+            "HKLM\\SOFTWARE\\Wimsy",
+            None,
+            Data::String("two\nlines".to_string())
+        )
+        .is_err());
+        assert!(parse_binary("abc").is_err());
+        assert!(parse_binary("zz").is_err());
+        assert!(parse_binary("é1").is_err());
+    }
+}
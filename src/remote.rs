@@ -0,0 +1,497 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs a wimsy command on another host over SSH, so that a machine that
+//! can't build images itself (or can't build them quickly) can drive a build
+//! on one that can.
+//!
+//! Rather than running each external command over its own SSH session, which
+//! would mean copying every intermediate file back and forth, wimsy runs
+//! another copy of itself on the remote host with the same arguments:
+//!
+//! 1. Every file or directory named on the command line is uploaded to the
+//!    remote directory's `inputs` directory with rsync, which skips the
+//!    files that are already up to date, and the argument is rewritten to
+//!    name the uploaded copy.
+//! 2. The work directory and output images are rewritten to live in the
+//!    remote directory's `work` and `out` directories.
+//! 3. The remote wimsy runs the command, with its output (and, in
+//!    interactive mode, a terminal) passed through SSH.
+//! 4. The output images, and anything written next to them (compressed
+//!    copies, digests, and manifests), are downloaded to where the command line
+//!    asked for them, and the build report, traces, and logs are downloaded
+//!    to the local work directory, even if the build failed.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
+
+use crate::{
+    app::RemoteOptions,
+    report::REPORT_FILE_NAME,
+    util::{format_command, shell_quote},
+    workspace::LOGS_DIR_NAME,
+};
+
+/// The remote directory used if the user doesn't choose one, relative to the
+/// remote user's home directory.
+pub const DEFAULT_REMOTE_DIR: &str = "wimsy-remote";
+
+/// The options that name output images, which are written on the remote host
+/// and downloaded afterwards.
+const OUTPUT_OPTIONS: &[&str] =
+    &["--output-image", "--qcow2-image", "--vhdx-image", "--vmdk-image"];
+
+/// The options that choose the remote host, which the remote wimsy doesn't
+/// get.
+const REMOTE_OPTIONS: &[&str] =
+    &["--remote-host", "--remote-dir", "--remote-wimsy"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    File,
+    Dir,
+}
+
+/// A file or directory to copy between the hosts.
+#[derive(Debug, PartialEq, Eq)]
+struct Transfer {
+    local: Utf8PathBuf,
+    remote: String,
+    kind: Kind,
+}
+
+/// What to copy to the remote host, what to run there, and what to copy
+/// back.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Plan {
+    /// The arguments to pass to the remote wimsy.
+    args: Vec<String>,
+
+    /// The input files and directories to upload.
+    uploads: Vec<Transfer>,
+
+    /// The remote directories holding the output images, each of which is
+    /// downloaded into the local directory in which the command line asked
+    /// for its image.
+    outputs: Vec<Transfer>,
+
+    /// The remote work directory, whose report and logs are downloaded to
+    /// the local one.
+    work_dir: Option<Transfer>,
+}
+
+impl Plan {
+    /// Rewrites the command-line arguments `args` (without the program name)
+    /// to run in `remote_dir` on the remote host. `kind_of` says whether a
+    /// path names an existing local file or directory.
+    fn new(
+        args: &[String],
+        remote_dir: &str,
+        kind_of: impl Fn(&Utf8Path) -> Option<Kind>,
+    ) -> Self {
+        let mut plan = Plan::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name, Some(value.to_string()))
+                }
+                _ => (arg.as_str(), None),
+            };
+
+            let remote_option = REMOTE_OPTIONS.contains(&name);
+            let placed = name == "--work-dir" || OUTPUT_OPTIONS.contains(&name);
+            if !remote_option && !placed {
+                let arg = match inline {
+                    Some(value) => {
+                        format!(
+                            "{name}={}",
+                            plan.input(&value, remote_dir, &kind_of)
+                        )
+                    }
+                    None => plan.input(arg, remote_dir, &kind_of),
+                };
+                plan.args.push(arg);
+                continue;
+            }
+
+            let Some(value) = inline.or_else(|| args.next().cloned()) else {
+                // Let the remote wimsy complain about the missing value.
+                plan.args.push(arg.clone());
+                continue;
+            };
+            if remote_option {
+                continue;
+            }
+
+            let local = Utf8PathBuf::from(value);
+            let remote = if name == "--work-dir" {
+                let remote = format!("{remote_dir}/work");
+                plan.work_dir = Some(Transfer {
+                    local,
+                    remote: remote.clone(),
+                    kind: Kind::Dir,
+                });
+                remote
+            } else {
+                let dir = format!("{remote_dir}/out/{}", plan.outputs.len());
+                let file_name = local.file_name().unwrap_or("image");
+                let remote = format!("{dir}/{file_name}");
+                let parent = match local.parent() {
+                    Some(parent) if !parent.as_str().is_empty() => parent,
+                    _ => Utf8Path::new("."),
+                };
+                plan.outputs.push(Transfer {
+                    local: parent.to_owned(),
+                    remote: dir,
+                    kind: Kind::Dir,
+                });
+                remote
+            };
+            plan.args.push(format!("{name}={remote}"));
+        }
+
+        plan
+    }
+
+    /// Returns the argument to pass in place of `arg`: if it names a local
+    /// file or directory, the path of its uploaded copy, and otherwise `arg`
+    /// itself.
+    fn input(
+        &mut self,
+        arg: &str,
+        remote_dir: &str,
+        kind_of: impl Fn(&Utf8Path) -> Option<Kind>,
+    ) -> String {
+        let local = Utf8Path::new(arg);
+        let Some(kind) = kind_of(local) else {
+            return arg.to_string();
+        };
+
+        if let Some(upload) = self.uploads.iter().find(|u| u.local == local) {
+            return upload.remote.clone();
+        }
+
+        // Keep the file's name, so that messages from the remote wimsy are
+        // recognizable, unless another upload already has it.
+        let name = local.file_name().unwrap_or("input");
+        let mut remote = format!("{remote_dir}/inputs/{name}");
+        if self.uploads.iter().any(|u| u.remote == remote) {
+            remote =
+                format!("{remote_dir}/inputs/{}-{name}", self.uploads.len());
+        }
+        self.uploads.push(Transfer {
+            local: local.to_owned(),
+            remote: remote.clone(),
+            kind,
+        });
+        remote
+    }
+}
+
+fn local_kind(path: &Utf8Path) -> Option<Kind> {
+    if path.is_file() {
+        Some(Kind::File)
+    } else if path.is_dir() {
+        Some(Kind::Dir)
+    } else {
+        None
+    }
+}
+
+fn ssh(host: &str, tty: bool, words: &[&str]) -> Command {
+    let mut cmd = Command::new("ssh");
+    if tty {
+        cmd.arg("-t");
+    }
+    // ssh passes the command to the remote user's shell as a single string.
+    let command: Vec<String> = words.iter().map(|w| shell_quote(w)).collect();
+    cmd.arg(host).arg(command.join(" "));
+    cmd
+}
+
+fn rsync() -> Command {
+    let mut cmd = Command::new("rsync");
+    // Keep partially transferred files so that an interrupted upload of a
+    // large ISO picks up where it left off, and pass paths to the remote
+    // rsync without letting the remote shell interpret them.
+    cmd.args(["-a", "--partial", "-s", "-e", "ssh"]);
+    cmd
+}
+
+/// Returns the rsync command that copies `transfer` to the remote host.
+fn upload_command(host: &str, transfer: &Transfer) -> Command {
+    let mut cmd = rsync();
+    match transfer.kind {
+        Kind::File => {
+            cmd.arg(transfer.local.as_str())
+                .arg(format!("{host}:{}", transfer.remote));
+        }
+        Kind::Dir => {
+            cmd.arg("--delete")
+                .arg(format!("{}/", transfer.local))
+                .arg(format!("{host}:{}/", transfer.remote));
+        }
+    }
+    cmd
+}
+
+/// Returns the rsync command that downloads the contents of the remote
+/// directory in `transfer`.
+fn download_command(host: &str, transfer: &Transfer) -> Command {
+    let mut cmd = rsync();
+    cmd.arg(format!("{host}:{}/", transfer.remote))
+        .arg(format!("{}/", transfer.local));
+    cmd
+}
+
+/// Returns the rsync command that downloads the build report and the logs
+/// directory (which holds the traces and serial log) from the remote work
+/// directory. The logs directory is missing if the remote build removed it
+/// because of --keep-work-dir, which is fine.
+fn download_logs_command(host: &str, work_dir: &Transfer) -> Command {
+    let mut cmd = rsync();
+    cmd.arg(format!("--include={LOGS_DIR_NAME}/***"))
+        .arg(format!("--include={REPORT_FILE_NAME}"))
+        .arg("--exclude=*");
+    cmd.arg(format!("{host}:{}/", work_dir.remote))
+        .arg(format!("{}/", work_dir.local));
+    cmd
+}
+
+/// Runs `cmd`, failing if it doesn't succeed.
+fn run(cmd: &mut Command) -> Result<()> {
+    let status = cmd
+        .status()
+        .with_context(|| format!("running {}", format_command(cmd)))?;
+    if !status.success() {
+        anyhow::bail!("{} failed ({status})", format_command(cmd));
+    }
+
+    Ok(())
+}
+
+/// Runs this invocation of wimsy on the host named by `options` instead of
+/// this one. With `dry_run`, prints the commands that would copy files and
+/// run the build without running them.
+pub fn run_remotely(
+    options: &RemoteOptions,
+    dry_run: bool,
+    interactive: bool,
+) -> Result<()> {
+    let host = options.remote_host.as_deref().unwrap();
+    let args = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.into_string())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|arg| {
+            anyhow::anyhow!(
+                "{} isn't valid UTF-8, so it can't be passed to the remote \
+                host",
+                arg.to_string_lossy()
+            )
+        })?;
+    let plan = Plan::new(&args, &options.remote_dir, local_kind);
+
+    // Clear out earlier builds' outputs so that they aren't downloaded with
+    // this one's. Inputs and the work directory are kept, so that unchanged
+    // inputs needn't be uploaded again and --resume works.
+    let outputs_dir = format!("{}/out", options.remote_dir);
+    let clean = ["rm", "-rf", outputs_dir.as_str()];
+    let mut dirs = vec![
+        format!("{}/inputs", options.remote_dir),
+        format!("{}/work", options.remote_dir),
+    ];
+    dirs.extend(plan.outputs.iter().map(|output| output.remote.clone()));
+    let mut mkdir = vec!["mkdir", "-p"];
+    mkdir.extend(dirs.iter().map(String::as_str));
+    let mut build = vec![options.remote_wimsy.as_str()];
+    build.extend(plan.args.iter().map(String::as_str));
+
+    if dry_run {
+        println!("Building on {} with these commands:\n", host.bold());
+        println!("  {}", format_command(&ssh(host, false, &clean)));
+        println!("  {}", format_command(&ssh(host, false, &mkdir)));
+        for upload in &plan.uploads {
+            println!("  {}", format_command(&upload_command(host, upload)));
+        }
+        println!("  {}", format_command(&ssh(host, interactive, &build)));
+        for output in &plan.outputs {
+            println!("  {}", format_command(&download_command(host, output)));
+        }
+        if let Some(work_dir) = &plan.work_dir {
+            println!(
+                "  {}",
+                format_command(&download_logs_command(host, work_dir))
+            );
+        }
+        return Ok(());
+    }
+
+    let missing: Vec<&str> = ["ssh", "rsync"]
+        .into_iter()
+        .filter(|tool| which::which(tool).is_err())
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "building on a remote host requires {}, which weren't found (the \
+            remote host needs rsync too)",
+            missing.join(" and ")
+        );
+    }
+
+    run(&mut ssh(host, false, &clean))
+        .with_context(|| format!("removing '{outputs_dir}' on {host}"))?;
+    run(&mut ssh(host, false, &mkdir)).with_context(|| {
+        format!("creating '{}' on {host}", options.remote_dir)
+    })?;
+    for upload in &plan.uploads {
+        eprintln!("Uploading {} to {host}", upload.local.as_str().bold());
+        run(&mut upload_command(host, upload))
+            .with_context(|| format!("uploading '{}'", upload.local))?;
+    }
+
+    eprintln!("Running wimsy on {}", host.bold());
+    let built =
+        ssh(host, interactive, &build).status().context("running ssh")?;
+
+    // Download the logs whether or not the build succeeded: they're most
+    // useful when it didn't.
+    if let Some(work_dir) = &plan.work_dir {
+        std::fs::create_dir_all(&work_dir.local)
+            .with_context(|| format!("creating '{}'", work_dir.local))?;
+        if let Err(e) = run(&mut download_logs_command(host, work_dir)) {
+            eprintln!("Warning: couldn't download the build's logs: {e:#}");
+        }
+    }
+
+    if !built.success() {
+        anyhow::bail!("the build on {host} failed ({built})");
+    }
+
+    for output in &plan.outputs {
+        eprintln!("Downloading {} from {host}", output.remote.bold());
+        run(&mut download_command(host, output))
+            .with_context(|| format!("downloading '{}'", output.remote))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn kind_of(path: &Utf8Path) -> Option<Kind> {
+        match path.as_str() {
+            "/isos/server.iso" | "/other/server.iso" | "wimsy.toml" => {
+                Some(Kind::File)
+            }
+            "unattend" => Some(Kind::Dir),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn uploads_inputs_and_places_outputs() {
+        let plan = Plan::new(
+            &args(&[
+                "--work-dir",
+                "/tmp/work",
+                "--output-image=/images/out.raw",
+                "--remote-host",
+                "lab",
+                "--config",
+                "wimsy.toml",
+                "create-guest-disk-image",
+                "--windows-iso",
+                "/isos/server.iso",
+                "--unattend-dir=unattend",
+                "--qcow2-image",
+                "out.qcow2",
+            ]),
+            "wr",
+            kind_of,
+        );
+
+        assert_eq!(
+            plan.args,
+            args(&[
+                "--work-dir=wr/work",
+                "--output-image=wr/out/0/out.raw",
+                "--config",
+                "wr/inputs/wimsy.toml",
+                "create-guest-disk-image",
+                "--windows-iso",
+                "wr/inputs/server.iso",
+                "--unattend-dir=wr/inputs/unattend",
+                "--qcow2-image=wr/out/1/out.qcow2",
+            ])
+        );
+        assert_eq!(
+            plan.uploads.iter().map(|u| u.kind).collect::<Vec<_>>(),
+            [Kind::File, Kind::File, Kind::Dir]
+        );
+        assert_eq!(
+            plan.outputs,
+            [
+                Transfer {
+                    local: "/images".into(),
+                    remote: "wr/out/0".to_string(),
+                    kind: Kind::Dir,
+                },
+                Transfer {
+                    local: ".".into(),
+                    remote: "wr/out/1".to_string(),
+                    kind: Kind::Dir,
+                },
+            ]
+        );
+        assert_eq!(plan.work_dir.unwrap().local, "/tmp/work");
+    }
+
+    #[test]
+    fn keeps_uploads_with_the_same_name_apart() {
+        let plan = Plan::new(
+            &args(&[
+                "/isos/server.iso",
+                "/other/server.iso",
+                "/isos/server.iso",
+            ]),
+            "wr",
+            kind_of,
+        );
+        assert_eq!(
+            plan.args,
+            args(&[
+                "wr/inputs/server.iso",
+                "wr/inputs/1-server.iso",
+                "wr/inputs/server.iso",
+            ])
+        );
+        assert_eq!(plan.uploads.len(), 2);
+    }
+
+    #[test]
+    fn drops_remote_options() {
+        let plan = Plan::new(
+            &args(&[
+                "--remote-dir=builds",
+                "--remote-wimsy",
+                "/opt/wimsy",
+                "--dry-run",
+            ]),
+            "builds",
+            kind_of,
+        );
+        assert_eq!(plan.args, args(&["--dry-run"]));
+    }
+}
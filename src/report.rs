@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A machine-readable record of a script run, written to the work directory
+//! when the run finishes.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::json::Json;
+
+/// The name of the report file written to the work directory.
+pub const REPORT_FILE_NAME: &str = "build-report.json";
+
+/// What happened after a step failed: a choice the user made in interactive
+/// mode, or an automatic retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Run the step again.
+    Retry,
+
+    /// Run the step again, as the retry policy allows, without asking.
+    AutoRetry,
+
+    /// Skip the step and continue with the next one.
+    Skip,
+
+    /// Open a shell in the work directory, then run the step again.
+    Shell,
+
+    /// Stop running the script.
+    Abort,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Retry => "retry",
+            Decision::AutoRetry => "auto-retry",
+            Decision::Skip => "skip",
+            Decision::Shell => "shell",
+            Decision::Abort => "abort",
+        }
+    }
+}
+
+/// The final outcome of a step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+    Skipped,
+
+    /// The step completed in an earlier run of the same build and wasn't run
+    /// again.
+    Resumed,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+            Outcome::Resumed => "resumed",
+        }
+    }
+}
+
+/// A single attempt to run a step.
+struct Attempt {
+    duration: Duration,
+    error: Option<String>,
+    decision: Option<Decision>,
+    metrics: Vec<(String, Json)>,
+}
+
+/// Everything that happened to a single step.
+struct StepRecord {
+    name: String,
+    label: String,
+    attempts: Vec<Attempt>,
+    outcome: Option<Outcome>,
+}
+
+/// A record of a script run.
+pub struct BuildReport {
+    started: SystemTime,
+    steps: Vec<StepRecord>,
+}
+
+impl Default for BuildReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl BuildReport {
+    pub fn new() -> Self {
+        Self { started: SystemTime::now(), steps: Vec::new() }
+    }
+
+    /// Records that the step with the supplied `name` and `label` is about to
+    /// run for the first time.
+    pub fn begin_step(&mut self, name: &str, label: &str) {
+        self.steps.push(StepRecord {
+            name: name.to_string(),
+            label: label.to_string(),
+            attempts: Vec::new(),
+            outcome: None,
+        });
+    }
+
+    fn current(&mut self) -> &mut StepRecord {
+        self.steps.last_mut().expect("a step has begun")
+    }
+
+    /// Records the result of an attempt to run the current step, along with
+    /// any metrics the step recorded while it ran.
+    pub fn record_attempt(
+        &mut self,
+        duration: Duration,
+        result: &anyhow::Result<()>,
+        metrics: Vec<(String, Json)>,
+    ) {
+        let error = result.as_ref().err().map(|e| format!("{e:#}"));
+        self.current().attempts.push(Attempt {
+            duration,
+            error,
+            decision: None,
+            metrics,
+        });
+    }
+
+    /// Records the user's decision about what to do after the current step's
+    /// most recent attempt failed.
+    pub fn record_decision(&mut self, decision: Decision) {
+        if let Some(attempt) = self.current().attempts.last_mut() {
+            attempt.decision = Some(decision);
+        }
+    }
+
+    /// Records the current step's final outcome.
+    pub fn finish_step(&mut self, outcome: Outcome) {
+        self.current().outcome = Some(outcome);
+    }
+
+    /// Returns each step's name, outcome, and the total time its attempts
+    /// took, for the image manifest.
+    pub fn step_timings(&self) -> Json {
+        let steps: Vec<Json> = self
+            .steps
+            .iter()
+            .map(|step| {
+                let duration: Duration =
+                    step.attempts.iter().map(|attempt| attempt.duration).sum();
+                Json::object()
+                    .with("name", step.name.as_str())
+                    .with("outcome", step.outcome.map(|o| o.as_str()))
+                    .with("attempts", step.attempts.len())
+                    .with("duration_secs", duration.as_secs_f64())
+            })
+            .collect();
+        steps.into()
+    }
+
+    /// Converts the report to JSON. `result` is the overall result of the
+    /// run.
+    pub fn to_json(&self, result: &anyhow::Result<()>) -> Json {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| {
+                let attempts = step
+                    .attempts
+                    .iter()
+                    .map(|attempt| {
+                        Json::object()
+                            .with(
+                                "duration_secs",
+                                attempt.duration.as_secs_f64(),
+                            )
+                            .with("error", attempt.error.clone())
+                            .with(
+                                "decision",
+                                attempt.decision.map(|d| d.as_str()),
+                            )
+                            .with(
+                                "metrics",
+                                Json::Object(attempt.metrics.clone()),
+                            )
+                    })
+                    .collect::<Vec<_>>();
+
+                Json::object()
+                    .with("name", step.name.as_str())
+                    .with("label", step.label.as_str())
+                    .with("outcome", step.outcome.map(|o| o.as_str()))
+                    .with("attempts", attempts)
+            })
+            .collect::<Vec<_>>();
+
+        let elapsed = self.started.elapsed().unwrap_or_default();
+        let classified = result.as_ref().err().and_then(crate::error::classify);
+        Json::object()
+            .with("started_unix_secs", unix_seconds(self.started))
+            .with("duration_secs", elapsed.as_secs_f64())
+            .with("succeeded", result.is_ok())
+            .with("error", result.as_ref().err().map(|e| format!("{e:#}")))
+            .with("error_kind", classified.map(|e| e.kind().as_str()))
+            .with("hint", classified.map(|e| e.hint()))
+            .with("steps", steps)
+    }
+
+    /// Writes the report to the work directory, returning the path to the
+    /// written file.
+    pub fn write(
+        &self,
+        work_dir: &Utf8Path,
+        result: &anyhow::Result<()>,
+    ) -> Result<Utf8PathBuf> {
+        let path = work_dir.join(REPORT_FILE_NAME);
+        std::fs::write(&path, format!("{:#}\n", self.to_json(result)))
+            .with_context(|| format!("writing build report to '{path}'"))?;
+        Ok(path)
+    }
+}
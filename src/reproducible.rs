@@ -0,0 +1,171 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pins what `--reproducible` builds can of the things that would otherwise
+//! differ between two builds from the same inputs, and lists what it can't
+//! for the image manifest.
+//!
+//! Windows Setup gives the disk and each partition it creates a random GPT
+//! GUID, and the boot configuration it writes refers to the partitions by
+//! GUID, so they can't be changed once Windows is installed. Reproducible
+//! builds partition the output image before Setup runs instead, with GUIDs
+//! derived from fixed names and the layout Autounattend.xml would have
+//! created, and Autounattend.xml has Setup format those partitions instead of
+//! replacing them.
+
+use std::process::Command;
+
+use camino::Utf8Path;
+
+use crate::{
+    autounattend::WindowsVersion, gpt::Guid, hash, json::Json, runner::Context,
+};
+
+/// The MAC address the installation VM's network adapter gets. It's the one
+/// QEMU gives its first adapter by default; libvirt would pick a random one.
+pub const VM_MAC: &str = "52:54:00:12:34:56";
+
+/// Returns whether the build is a `--reproducible` one.
+pub fn enabled(ctx: &Context) -> bool {
+    ctx.get_var("reproducible").is_some()
+}
+
+/// Returns the GUID pinned for the thing called `name`: the first 16 bytes of
+/// a SHA-256 digest of the name, marked as a version 4 GUID.
+pub fn pinned_guid(name: &str) -> Guid {
+    let digest = hash::sha256(format!("wimsy reproducible {name}").as_bytes());
+    let mut bytes: [u8; 16] = digest[..16].try_into().unwrap();
+
+    // The version is the high nibble of the (little-endian) third field, and
+    // the variant the top bits of the fourth.
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Guid(bytes)
+}
+
+/// Returns the UUID the installation VM's firmware reports.
+pub fn vm_uuid() -> String {
+    pinned_guid("vm").to_string()
+}
+
+/// The partitions to create, in order, as `sgdisk` type codes and sizes in
+/// MiB (`None` for the rest of the disk). These match the CreatePartitions
+/// list in Autounattend.xml: a recovery partition, the EFI system partition,
+/// the MSR (16 MiB on Windows 10, 128 MiB on later releases), and Windows.
+fn partitions(
+    version: Option<WindowsVersion>,
+) -> [(&'static str, Option<u64>); 4] {
+    let msr_mib =
+        if version == Some(WindowsVersion::Windows10) { 16 } else { 128 };
+    [
+        ("2700", Some(500)),
+        ("ef00", Some(260)),
+        ("0c01", Some(msr_mib)),
+        ("0700", None),
+    ]
+}
+
+/// Returns the `sgdisk` command that gives the blank disk at `disk` the
+/// partitions Setup would create for Windows `version`, with pinned GUIDs.
+pub fn partition_command(
+    disk: &str,
+    version: Option<WindowsVersion>,
+) -> Command {
+    let mut cmd = Command::new("sgdisk");
+    cmd.arg("-o").arg(format!("--disk-guid={}", pinned_guid("disk")));
+    for (i, (type_code, size_mib)) in
+        partitions(version).into_iter().enumerate()
+    {
+        let number = i + 1;
+        let end = size_mib.map_or("0".to_string(), |mib| format!("+{mib}M"));
+        cmd.arg(format!("--new={number}:0:{end}"))
+            .arg(format!("--typecode={number}:{type_code}"))
+            .arg(format!(
+                "--partition-guid={number}:{}",
+                pinned_guid(&format!("partition {number}"))
+            ));
+    }
+    cmd.arg(disk);
+    cmd
+}
+
+/// Returns the manifest's account of how reproducible the image at `image`
+/// is: what the build pinned, and what it couldn't.
+pub fn manifest_section(ctx: &Context, image: &Utf8Path) -> Json {
+    let mut pinned = vec![
+        "installation VM UUID and MAC address",
+        "computer name during installation",
+    ];
+    let mut unpinned = vec![
+        "times recorded by Setup and the provisioning scripts (file \
+        timestamps, event logs, and registry keys), since the VM's clock \
+        follows the host's",
+        "NTFS and FAT volume serial numbers, which Setup chooses when it \
+        formats the partitions",
+        "the machine SID and other identifiers Windows generates during \
+        installation (generalizing the image replaces them when it's \
+        deployed)",
+        "where files are placed on the disk, which depends on the timing of \
+        the installation",
+    ];
+
+    // Custom unattend files may still have Setup repartition the disk.
+    // Other formats' tables can't be read without attaching them.
+    let gpt_pinned = crate::steps::output_format(ctx) != "raw"
+        || crate::gpt::read_image(image)
+            .is_ok_and(|table| table.disk_guid == pinned_guid("disk"));
+    if gpt_pinned {
+        pinned.insert(0, "GPT disk and partition GUIDs");
+    } else {
+        unpinned.insert(
+            0,
+            "GPT disk and partition GUIDs, since Autounattend.xml had Setup \
+            repartition the disk",
+        );
+    }
+
+    if ctx.get_var("vhdx_image").is_some() {
+        unpinned.push(
+            "the VHDX image's file and disk identifiers, which qemu-img \
+            chooses at random",
+        );
+    }
+    if ctx.get_var("vmdk_image").is_some() {
+        unpinned.push(
+            "the VMDK image's content ID, which qemu-img chooses at random",
+        );
+    }
+
+    Json::object().with("pinned", pinned).with("nondeterministic", unpinned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::format_command;
+
+    #[test]
+    fn pins_guids_and_partitions() {
+        let disk = pinned_guid("disk");
+        assert_eq!(disk, pinned_guid("disk"));
+        assert_ne!(disk, pinned_guid("partition 1"));
+        let text = disk.to_string();
+        assert_eq!(&text[14..15], "4");
+        assert!("89AB".contains(&text[19..20]));
+
+        let command = format_command(&partition_command(
+            "/tmp/disk.img",
+            Some(WindowsVersion::Windows10),
+        ));
+        assert!(command.starts_with(&format!("sgdisk -o --disk-guid={disk} ")));
+        assert!(command.contains(" --new=3:0:+16M --typecode=3:0c01 "));
+        assert!(command.ends_with(&format!(
+            "--new=4:0:0 --typecode=4:0700 --partition-guid=4:{} \
+            /tmp/disk.img",
+            pinned_guid("partition 4")
+        )));
+        assert!(format_command(&partition_command("d", None))
+            .contains(" --new=3:0:+128M "));
+    }
+}
@@ -6,60 +6,211 @@
 //! operations.
 
 use std::{
+    borrow::Cow,
     collections::HashMap,
     io::{Read, Write},
+    sync::Arc,
 };
 
-use camino::Utf8Path;
+use anyhow::Context as _;
+use camino::Utf8PathBuf;
 use colored::Colorize;
 
-use crate::ui::{Mode, Ui};
+use crate::{
+    app::ProgressFormat,
+    checkpoint::{self, Checkpoint},
+    command::{CommandRunner, HostRunner},
+    config::Config,
+    error::{Error, ErrorKind},
+    manifest::Signer,
+    plan::{Plan, StepSelection},
+    trace,
+    ui::{EventCallback, Events, Mode, PauseAfter, StepUiOptions, Ui},
+    util::check_executable_prerequisites,
+    workspace::KeepWorkDir,
+};
 
 type StepFn = dyn Fn(&mut Context, &dyn crate::ui::Ui) -> anyhow::Result<()>;
+type DescribeFn = dyn Fn(&mut Context) -> Vec<String>;
+
+/// A step in a scripted procedure. A script's plan is a list of these, which
+/// the runner checks, describes, skips, resumes, and runs the same way
+/// whatever the step does.
+///
+/// Most steps are [`ScriptStep`]s, which wrap a function; a step that needs
+/// state of its own can implement this trait directly instead.
+pub trait Step {
+    /// A short, stable name for this step. Configuration files use step names
+    /// to refer to specific steps, so these should not change once defined.
+    fn name(&self) -> &str;
+
+    /// A short description of this step, shown while it runs.
+    fn label(&self) -> &str;
+
+    /// The commands this step expects to launch via [`std::process::Command`].
+    /// The script runner checks that they're installed before running the
+    /// script.
+    fn required_tools(&self) -> &[&'static str] {
+        &[]
+    }
 
-/// A step in a scripted procedure.
+    /// The context variables this step sets for later steps to use. If the
+    /// user skips this step, the runner warns that later steps may fail for
+    /// want of these variables, and dry runs set them to placeholders.
+    fn provided_vars(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Describes what this step would do in a context like `ctx` without
+    /// doing it, for `--dry-run`: the commands it would run, with their
+    /// arguments resolved from the context, and the other changes it would
+    /// make. Steps should set the context variables they'd set, so that later
+    /// steps' descriptions can use them; any they leave unset get
+    /// placeholders. By default, steps are described by the tools they run.
+    fn dry_run(&self, ctx: &mut Context) -> Vec<String> {
+        let _ = ctx;
+        describe_tools(self.required_tools())
+    }
+
+    /// Whether this step can fail for reasons that pass (a dropped
+    /// connection, say, or busy storage) and can safely be run again after
+    /// failing partway. The runner retries such steps as the configuration
+    /// file's `[retry]` table says before giving up on them.
+    fn retryable(&self) -> bool {
+        false
+    }
+
+    /// Runs this step.
+    fn execute(&self, ctx: &mut Context, ui: &dyn Ui) -> anyhow::Result<()>;
+}
+
+/// Describes a step that runs `tools` for a dry run, in lieu of anything more
+/// specific.
+fn describe_tools(tools: &[&str]) -> Vec<String> {
+    if tools.is_empty() {
+        return Vec::new();
+    }
+
+    vec![format!(
+        "runs {} with arguments determined during the build",
+        tools.join(", ")
+    )]
+}
+
+/// A step that runs a function.
 pub struct ScriptStep {
+    /// A short, stable name for this step. Configuration files use step names
+    /// to refer to specific steps, so these should not change once defined.
+    name: Cow<'static, str>,
+
     /// A descriptive label for this procedure step.
-    label: &'static str,
+    label: Cow<'static, str>,
 
     /// The function to execute to run this procedure step.
     func: Box<StepFn>,
 
-    /// A list of commands that this step expects to launch via
-    /// [`std::process::Command`]. The script runner uses these to check for
-    /// missing dependencies before running the script.
+    /// See [`Step::required_tools`].
     prereq_commands: Vec<&'static str>,
+
+    /// See [`Step::provided_vars`].
+    provides: Vec<&'static str>,
+
+    /// Describes what this step would do, for `--dry-run`. See
+    /// [`ScriptStep::describe`].
+    describe: Option<Box<DescribeFn>>,
+
+    /// See [`Step::retryable`].
+    retryable: bool,
 }
 
 impl ScriptStep {
     pub fn new(
-        label: &'static str,
+        name: impl Into<Cow<'static, str>>,
+        label: impl Into<Cow<'static, str>>,
         func: impl Fn(&mut Context, &dyn Ui) -> anyhow::Result<()> + 'static,
     ) -> Self {
-        Self { label, func: Box::new(func), prereq_commands: Vec::new() }
+        Self::with_prereqs(name, label, func, &[])
     }
 
     pub fn with_prereqs(
-        label: &'static str,
+        name: impl Into<Cow<'static, str>>,
+        label: impl Into<Cow<'static, str>>,
         func: impl Fn(&mut Context, &dyn Ui) -> anyhow::Result<()> + 'static,
         commands: &[&'static str],
     ) -> Self {
-        Self { label, func: Box::new(func), prereq_commands: commands.to_vec() }
+        Self {
+            name: name.into(),
+            label: label.into(),
+            func: Box::new(func),
+            prereq_commands: commands.to_vec(),
+            provides: Vec::new(),
+            describe: None,
+            retryable: false,
+        }
+    }
+
+    /// Supplies a function that describes what this step would do without
+    /// doing it, as [`Step::dry_run`] does.
+    pub fn describe(
+        mut self,
+        func: impl Fn(&mut Context) -> Vec<String> + 'static,
+    ) -> Self {
+        self.describe = Some(Box::new(func));
+        self
+    }
+
+    /// Declares the context variables this step sets for later steps to use.
+    pub fn provides(mut self, vars: &[&'static str]) -> Self {
+        self.provides = vars.to_vec();
+        self
+    }
+
+    /// Marks this step as one the runner may retry (see [`Step::retryable`]).
+    pub fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+}
+
+impl Step for ScriptStep {
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    pub fn prereq_commands(&self) -> &[&'static str] {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn required_tools(&self) -> &[&'static str] {
         self.prereq_commands.as_slice()
     }
 
-    pub fn label(&self) -> &'static str {
-        self.label
+    fn provided_vars(&self) -> &[&'static str] {
+        self.provides.as_slice()
     }
 
-    pub fn run(&self, ctx: &mut Context, ui: &dyn Ui) -> anyhow::Result<()> {
+    fn dry_run(&self, ctx: &mut Context) -> Vec<String> {
+        match &self.describe {
+            Some(describe) => describe(ctx),
+            None => describe_tools(&self.prereq_commands),
+        }
+    }
+
+    fn retryable(&self) -> bool {
+        self.retryable
+    }
+
+    fn execute(&self, ctx: &mut Context, ui: &dyn Ui) -> anyhow::Result<()> {
         (self.func)(ctx, ui)
     }
 }
 
+impl From<ScriptStep> for Box<dyn Step> {
+    fn from(step: ScriptStep) -> Self {
+        Box::new(step)
+    }
+}
+
 /// Describes a set of files or commands a script expects to be present but that
 /// appear to be missing.
 #[derive(Default)]
@@ -72,11 +223,21 @@ pub struct MissingPrerequisites {
     /// prevent the script from working as intended, but that the user might
     /// also have intended and therefore know are safe to ignore.
     warnings: Vec<String>,
+
+    /// The class of failure the errors amount to, if they're all of one
+    /// class (see [`MissingPrerequisites::classify`]).
+    kind: Option<ErrorKind>,
 }
 
 impl MissingPrerequisites {
     pub fn from_messages(errors: Vec<String>, warnings: Vec<String>) -> Self {
-        Self { errors, warnings }
+        Self { errors, warnings, kind: None }
+    }
+
+    /// Records that the errors include one of class `kind`, which decides
+    /// the build's exit code if no earlier error was classified.
+    pub fn classify(&mut self, kind: ErrorKind) {
+        self.kind.get_or_insert(kind);
     }
 
     pub fn add_error(&mut self, error: String) {
@@ -91,7 +252,7 @@ impl MissingPrerequisites {
 /// Implemented by objects that can be used as scripts.
 pub trait Script {
     /// Yields a slice of steps that can be executed to run this script.
-    fn steps(&self) -> &[ScriptStep];
+    fn steps(&self) -> &[Box<dyn Step>];
 
     /// Prints a message to the specified writer describing what this script
     /// will do.
@@ -100,52 +261,342 @@ pub trait Script {
         w: Box<dyn std::io::Write>,
     ) -> std::io::Result<()>;
 
-    /// Checks that this script's prerequisites are
+    /// Checks that this script's prerequisites are present. The runner
+    /// separately checks that the commands each step expects to run are
+    /// available, so scripts need only check for files and other resources.
     fn check_prerequisites(&self) -> MissingPrerequisites;
 
+    /// Yields the files this script reads its inputs from. A build is only
+    /// resumed from a checkpoint if none of them have changed.
+    fn input_files(&self) -> Vec<Utf8PathBuf>;
+
     /// Yields a `HashMap` that contains key-value pairs that should be inserted
     /// into the script's [`Context`] prior to running it.
     fn initial_context(&self) -> HashMap<String, String>;
 }
 
+/// Options that control how a script is run.
+pub struct RunOptions {
+    /// Whether to prompt before running and display interactive progress.
+    pub interactive: bool,
+
+    /// The directory in which to write command logs.
+    pub work_dir: Utf8PathBuf,
+
+    /// The contents of the configuration file passed with `--config`, if any.
+    pub config: Config,
+
+    /// The steps after which to pause so the user can inspect the build.
+    pub pause_after: PauseAfter,
+
+    /// Additional variables to add to the script's initial context. These
+    /// carry options that apply to every script.
+    pub vars: Vec<(String, String)>,
+
+    /// How to report the build's progress. In JSON mode, stdout carries only
+    /// progress events, so the messages printed before the build starts go
+    /// to stderr instead.
+    pub progress: ProgressFormat,
+
+    /// Receives the build's progress events in place of stdout. Setting this
+    /// implies JSON progress (though stdout is left alone).
+    pub on_event: Option<EventCallback>,
+
+    /// Whether to skip the steps that completed in an earlier run of the same
+    /// build, as recorded in the work directory's checkpoint.
+    pub resume: bool,
+
+    /// The steps of the plan to run. Builds that don't run every step don't
+    /// update the checkpoint, and instead take the context variables the
+    /// steps they don't run would set from it.
+    pub selection: StepSelection,
+
+    /// Whether to print what each step would do instead of running the
+    /// script.
+    pub dry_run: bool,
+
+    /// Whether to copy the guest serial consoles to stderr as they're
+    /// written. Only allowed in non-interactive mode.
+    pub follow_serial: bool,
+
+    /// Runs the commands steps run to completion, if not this host.
+    pub command_runner: Option<Arc<dyn CommandRunner>>,
+
+    /// When to keep the work directory's logs and intermediate files.
+    pub keep_work_dir: KeepWorkDir,
+
+    /// Signs the image manifest written when the build succeeds, if set.
+    pub signer: Option<Signer>,
+}
+
 /// Runs a script, pretty-printing its various labels and the outcomes of each
 /// step.
 pub fn run_script(
     script: Box<dyn Script>,
-    interactive: bool,
-    work_dir: &Utf8Path,
+    options: RunOptions,
 ) -> anyhow::Result<()> {
-    script.print_configuration(Box::new(std::io::stdout()))?;
-    println!();
+    let RunOptions {
+        interactive,
+        work_dir,
+        config,
+        pause_after,
+        vars,
+        progress,
+        on_event,
+        resume,
+        selection,
+        dry_run,
+        follow_serial,
+        command_runner,
+        keep_work_dir,
+        signer,
+    } = options;
+    if !interactive && !pause_after.is_never() {
+        anyhow::bail!("--pause-after can only be used in interactive mode");
+    }
+    if interactive && follow_serial {
+        anyhow::bail!(
+            "--follow-serial can only be used in non-interactive mode \
+            (interactive mode shows serial output below the step list)"
+        );
+    }
+
+    let events = match on_event {
+        Some(callback) => Some(Events::Callback(callback)),
+        None if progress == ProgressFormat::Json => Some(Events::Stdout),
+        None => None,
+    };
+    let json = events.is_some();
+    let mut out: Box<dyn Write> = if json {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+
+    script.print_configuration(if json {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    })?;
+    writeln!(out)?;
+
+    let plan = Plan::new(script.steps(), &config.steps, &work_dir)
+        .context("applying step configuration")?;
+    plan.print(&mut out)?;
+    let hooked = crate::hooks::attach(&plan.steps(), &config.hooks)?;
+    for name in &config.retry.steps {
+        if !plan.steps().iter().any(|step| step.name() == name) {
+            anyhow::bail!(
+                "the configuration's retry policy names step '{name}', which \
+                isn't in the plan"
+            );
+        }
+    }
+    let steps: Vec<&dyn Step> =
+        hooked.iter().map(|step| step as &dyn Step).collect();
+    let names: Vec<&str> = steps.iter().map(|step| step.name()).collect();
+    let selected = selection.apply(&names)?;
+    let selective = selected.contains(&false);
+    if selective {
+        let (running, skipped): (Vec<_>, Vec<_>) =
+            names.iter().zip(&selected).partition(|(_, &selected)| selected);
+        let (label, listed) = if running.len() < skipped.len() {
+            ("Only running", running)
+        } else {
+            ("Skipped steps", skipped)
+        };
+        let listed: Vec<&str> = listed.iter().map(|(name, _)| **name).collect();
+        writeln!(out, "  {}: {}", label.bold(), listed.join(", "))?;
+    }
+    writeln!(out)?;
+
+    if let PauseAfter::Steps(names) = &pause_after {
+        for name in names {
+            if !plan.steps().iter().any(|s| s.name() == name) {
+                anyhow::bail!(
+                    "--pause-after names step '{name}', which isn't in the plan"
+                );
+            }
+        }
+    }
+
+    let selected_steps: Vec<&dyn Step> = steps
+        .iter()
+        .zip(&selected)
+        .filter(|(_, &selected)| selected)
+        .map(|(step, _)| *step)
+        .collect();
+    let command_runner = command_runner.unwrap_or_else(|| Arc::new(HostRunner));
+    let mut missing = script.check_prerequisites();
+    let mut missing_tools = check_executable_prerequisites(
+        &selected_steps,
+        command_runner.as_ref(),
+    );
+    if let Some(signer) = &signer {
+        if which::which(signer.tool()).is_err() {
+            missing_tools.push(signer.tool().to_string());
+        }
+    }
+    for tool in &missing_tools {
+        missing.add_error(match crate::error::package_for_tool(tool) {
+            Some(package) => format!(
+                "binary or command '{tool}' not found (is it on your PATH?); \
+                it's in the {package} package"
+            ),
+            None => {
+                format!("binary or command '{tool}' not found (is it on your PATH?)")
+            }
+        });
+    }
+
+    if let Some(events) = &events {
+        for (severity, messages) in
+            [("error", &missing.errors), ("warning", &missing.warnings)]
+        {
+            for message in messages {
+                events.emit(
+                    crate::ui::event("prerequisite")
+                        .with("severity", severity)
+                        .with("message", message.as_str()),
+                );
+            }
+        }
+    }
 
-    let missing = script.check_prerequisites();
     if !missing.errors.is_empty() {
-        println!("{}", "Some prerequisites were not satisfied:".bold());
+        writeln!(out, "{}", "Some prerequisites were not satisfied:".bold())?;
         for error in missing.errors.iter() {
-            println!("  {}", error);
+            writeln!(out, "  {}", error)?;
         }
 
-        println!();
+        writeln!(out)?;
         if !missing.warnings.is_empty() {
-            println!("The following warnings were also raised:");
+            writeln!(out, "The following warnings were also raised:")?;
             for warning in missing.warnings.iter() {
-                println!("  {}", warning);
+                writeln!(out, "  {}", warning)?;
             }
 
-            println!();
+            writeln!(out)?;
+        }
+
+        writeln!(
+            out,
+            "The doctor command can check the rest of this host's setup \
+            without starting a build."
+        )?;
+        if !dry_run {
+            let error = if missing_tools.is_empty() {
+                Error::new(
+                    missing.kind.unwrap_or(ErrorKind::Prerequisite),
+                    "some script prerequisites weren't satisfied",
+                )
+            } else {
+                let tools: Vec<&str> =
+                    missing_tools.iter().map(String::as_str).collect();
+                crate::error::missing_tools(&tools)
+            };
+            return Err(error.into());
         }
 
-        anyhow::bail!("some script prerequisites weren't satisfied");
+        writeln!(out)?;
     } else if !missing.warnings.is_empty() {
-        println!("{}", "Warning! Some prerequisites may be missing:".bold());
+        writeln!(
+            out,
+            "{}",
+            "Warning! Some prerequisites may be missing:".bold()
+        )?;
         for warning in missing.warnings.iter() {
-            println!("  {}", warning);
+            writeln!(out, "  {}", warning)?;
+        }
+
+        writeln!(out)?;
+    }
+
+    let mut ctx = Context::new(script.initial_context());
+    ctx.vars.extend(vars);
+    let fingerprint =
+        checkpoint::fingerprint(&ctx.vars, &steps, &script.input_files());
+    let checkpoint = if resume {
+        match resume_checkpoint(&work_dir, fingerprint.clone(), &steps, &ctx) {
+            Ok(checkpoint) => checkpoint,
+            Err(reason) => {
+                writeln!(out, "  Not resuming: {reason:#}")?;
+                Checkpoint::new(fingerprint)
+            }
+        }
+    } else {
+        Checkpoint::new(fingerprint)
+    };
+
+    if let Some(last) = checkpoint.completed().last() {
+        writeln!(
+            out,
+            "  Resuming after step '{}' ({} of {} steps already completed)",
+            last.name,
+            checkpoint.completed().len(),
+            steps.len()
+        )?;
+        for step in checkpoint.completed() {
+            ctx.vars.extend(step.vars.iter().cloned());
+        }
+    }
+
+    if selective {
+        borrow_skipped_vars(
+            &work_dir,
+            checkpoint.fingerprint(),
+            &names,
+            &selected,
+            &mut ctx,
+        );
+        let mut unset: Vec<&str> = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            if selected[i] || !selected[i..].contains(&true) {
+                continue;
+            }
+            for var in step.provided_vars() {
+                if ctx.get_var(var).is_none() && !unset.contains(var) {
+                    unset.push(var);
+                }
+            }
+        }
+        if !unset.is_empty() {
+            writeln!(
+                out,
+                "  {} no earlier build in this work directory recorded these \
+                variables, which steps this build skips would set, so steps \
+                that use them will fail: {}",
+                "Warning:".bold(),
+                unset.join(", ")
+            )?;
+        }
+    }
+
+    if dry_run {
+        print_dry_run(
+            &steps,
+            &selected,
+            ctx,
+            checkpoint.completed().len(),
+            out,
+            events.as_ref(),
+        )?;
+        if !missing.errors.is_empty() {
+            anyhow::bail!("some script prerequisites weren't satisfied");
         }
 
-        println!();
+        return Ok(());
     }
 
-    println!("  Command logs will be written to {}\n", work_dir);
+    writeln!(
+        out,
+        "  Command logs will be written to {}",
+        crate::workspace::logs_dir(&work_dir)
+    )?;
+    if let Some(path) = crate::trace::path() {
+        writeln!(out, "  Traces will be written to {path}")?;
+    }
+    writeln!(out)?;
 
     if interactive {
         println!("Press Enter to continue or CTRL-C to cancel.");
@@ -153,10 +604,165 @@ pub fn run_script(
         std::io::stdin().read_exact(&mut [0u8])?;
     }
 
-    let ctx = Context { vars: script.initial_context().clone() };
-    let mode =
-        if interactive { Mode::Interactive } else { Mode::NonInteractive };
-    crate::ui::run_script(script, ctx, work_dir, mode)
+    let mode = if let Some(events) = events {
+        Mode::Events(events)
+    } else if interactive {
+        Mode::Interactive { pause_after }
+    } else {
+        Mode::NonInteractive
+    };
+    let result = crate::ui::run_script(
+        &steps,
+        &selected,
+        ctx,
+        &work_dir,
+        mode,
+        (!selective).then_some(checkpoint),
+        StepUiOptions {
+            follow_serial,
+            command_runner,
+            retry: config.retry.clone(),
+            signer,
+        },
+    );
+
+    // Builds that only run some steps usually run in a work directory kept
+    // from an earlier build, which they shouldn't throw away when they work.
+    let keep = keep_work_dir.keeps(result.is_ok())
+        || (selective && keep_work_dir == KeepWorkDir::OnFailure);
+    if !keep {
+        trace::info!("cleaning up the work directory");
+        match crate::workspace::clean_up(&work_dir) {
+            Ok(()) if !json => println!(
+                "Removed the work directory's logs and intermediate files \
+                (--keep-work-dir {keep_work_dir})."
+            ),
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!(
+                    "Warning: couldn't clean up the work directory: {e:#}"
+                )
+            }
+        }
+    }
+
+    result
+}
+
+/// Prints what each of `steps` would do if run in order starting from `ctx`.
+/// The first `resumed` steps completed in an earlier run, and those not
+/// `selected` weren't chosen to run, so they would be skipped. Also emits an
+/// event for each step to `events`, if set.
+fn print_dry_run(
+    steps: &[&dyn Step],
+    selected: &[bool],
+    mut ctx: Context,
+    resumed: usize,
+    mut out: Box<dyn Write>,
+    events: Option<&Events>,
+) -> anyhow::Result<()> {
+    writeln!(out, "{}", "Dry run; nothing will be executed:".bold())?;
+    for (i, step) in steps.iter().enumerate() {
+        writeln!(out, "  {:>2}. {}: {}", i + 1, step.name(), step.label())?;
+        let lines = if i < resumed {
+            vec!["(completed earlier; would be skipped)".to_string()]
+        } else if !selected[i] {
+            vec!["(not selected to run; would be skipped)".to_string()]
+        } else {
+            step.dry_run(&mut ctx)
+        };
+        for var in step.provided_vars() {
+            if i >= resumed && ctx.get_var(var).is_none() {
+                ctx.set_var(var, format!("<{var}>"));
+            }
+        }
+
+        if lines.is_empty() {
+            writeln!(out, "      {}", "(no external commands)".dimmed())?;
+        }
+
+        for line in &lines {
+            writeln!(out, "      {line}")?;
+        }
+
+        if let Some(events) = events {
+            let commands: Vec<crate::json::Json> =
+                lines.iter().map(|line| line.as_str().into()).collect();
+            events.emit(
+                crate::ui::event("dry_run_step")
+                    .with("step", step.name())
+                    .with("commands", commands),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the context variables that the steps named `names` that aren't
+/// `selected` set in the last build in `work_dir`, if its checkpoint records
+/// them and it ran the same build (i.e. its fingerprint is `fingerprint`).
+fn borrow_skipped_vars(
+    work_dir: &Utf8PathBuf,
+    fingerprint: &str,
+    names: &[&str],
+    selected: &[bool],
+    ctx: &mut Context,
+) {
+    let checkpoint = match Checkpoint::load(work_dir) {
+        Ok(Some(checkpoint)) if checkpoint.fingerprint() == fingerprint => {
+            checkpoint
+        }
+        Ok(_) => return,
+        Err(e) => {
+            trace::info!("not reading checkpoint", error = format!("{e:#}"));
+            return;
+        }
+    };
+
+    for step in checkpoint.completed() {
+        let skipped = names
+            .iter()
+            .zip(selected)
+            .any(|(name, &selected)| *name == step.name && !selected);
+        if skipped {
+            ctx.vars.extend(step.vars.iter().cloned());
+        }
+    }
+}
+
+/// Loads the checkpoint in `work_dir` for a build with `fingerprint` that
+/// runs `steps`, keeping only the steps that can be skipped. Returns an error
+/// explaining why the build can't be resumed if it can't.
+fn resume_checkpoint(
+    work_dir: &Utf8PathBuf,
+    fingerprint: String,
+    steps: &[&dyn Step],
+    ctx: &Context,
+) -> anyhow::Result<Checkpoint> {
+    let Some(mut checkpoint) = Checkpoint::load(work_dir)? else {
+        anyhow::bail!("there's no checkpoint in {work_dir}");
+    };
+
+    if checkpoint.fingerprint() != fingerprint {
+        anyhow::bail!(
+            "the build's options, steps, or input files have changed since \
+            the checkpoint was written"
+        );
+    }
+
+    if checkpoint.retain_completed_prefix(steps) > 0 {
+        if let Some(image) = ctx.get_var("output_image") {
+            if !camino::Utf8Path::new(image).exists() {
+                anyhow::bail!(
+                    "the output image '{image}' that earlier steps created is \
+                    missing"
+                );
+            }
+        }
+    }
+
+    Ok(checkpoint)
 }
 
 /// A shared script execution context, provided to each step in a running
@@ -169,6 +775,11 @@ pub struct Context {
 }
 
 impl Context {
+    /// Creates a context whose store holds `vars`.
+    pub fn new(vars: HashMap<String, String>) -> Self {
+        Self { vars }
+    }
+
     /// Gets the value of the supplied `var`, returning `None` if the value is
     /// not in the store.
     pub fn get_var(&self, var: &str) -> Option<&str> {
@@ -180,4 +791,9 @@ impl Context {
     pub fn set_var(&mut self, var: &str, value: String) -> Option<String> {
         self.vars.insert(var.to_owned(), value)
     }
+
+    /// Yields the full contents of the key-value store.
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
 }
@@ -0,0 +1,327 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Uploads the finished output image to S3-compatible object storage.
+//!
+//! The upload is done by the AWS CLI, which handles credentials, regions,
+//! and multipart uploads for every S3-compatible service wimsy is likely to
+//! meet. wimsy streams the image (through `gzip` or `zstd` if the user asked
+//! for compression) into the CLI's standard input, hashing it on the way, so
+//! compressed images never need space on the host. The CLI sends a SHA-256
+//! checksum with each part, which the service verifies before accepting it.
+//! Once the upload is done, wimsy checks that the object has the size it
+//! uploaded and stores the whole object's SHA-256 digest next to it as
+//! `KEY.sha256`, in `sha256sum` format, for consumers to check downloads
+//! against.
+
+use std::{
+    io::{Read, Write},
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    compress::Compression,
+    hash::Sha256,
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// The suffix appended to the object key to name the checksum object.
+const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// The amount of the image to read and upload at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// An object in an S3 bucket, written as `s3://BUCKET/KEY`. A key that ends
+/// in a slash names a "directory" to upload the image into under its own
+/// file name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Url {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl std::str::FromStr for S3Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{s}' isn't an S3 URL like s3://bucket/key");
+        let rest = s.strip_prefix("s3://").ok_or_else(invalid)?;
+        let (bucket, key) = rest.split_once('/').ok_or_else(invalid)?;
+        if bucket.is_empty() || key.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self { bucket: bucket.to_string(), key: key.to_string() })
+    }
+}
+
+impl std::fmt::Display for S3Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+impl S3Url {
+    /// Returns the object to upload `image` to, compressed with
+    /// `compression`, choosing a name for it if this URL names a directory.
+    fn object_for(&self, image: &Utf8Path, compression: Compression) -> Self {
+        if !self.key.ends_with('/') {
+            return self.clone();
+        }
+
+        Self {
+            bucket: self.bucket.clone(),
+            key: format!(
+                "{}{}{}",
+                self.key,
+                image.file_name().unwrap_or("image"),
+                compression.extension()
+            ),
+        }
+    }
+}
+
+/// Checks that the tools the upload needs are installed.
+pub fn check_prerequisites(compression: Compression) -> Vec<String> {
+    let mut errors = Vec::new();
+    if which::which("aws").is_err() {
+        errors.push(
+            "publishing to object storage requires the AWS CLI (aws), which \
+            wasn't found (is it on your PATH?)"
+                .to_string(),
+        );
+    }
+
+    errors.extend(crate::compress::check_prerequisites(compression));
+    errors
+}
+
+/// Returns an `aws` command with the connection options from the context.
+fn aws_command(ctx: &Context) -> Command {
+    let mut cmd = Command::new("aws");
+    if let Some(endpoint) = ctx.get_var("publish_endpoint_url") {
+        cmd.args(["--endpoint-url", endpoint]);
+    }
+    cmd
+}
+
+/// Returns the command that uploads its standard input to `object`.
+fn upload_command(ctx: &Context, object: &S3Url, size: u64) -> Command {
+    let mut cmd = aws_command(ctx);
+    cmd.args(["s3", "cp", "-", &object.to_string()])
+        .args(["--checksum-algorithm", "SHA256"])
+        // The CLI sizes the parts of streamed uploads from this so that
+        // large images don't run into S3's limit of 10,000 parts. The image's
+        // uncompressed size is an upper bound on what gets streamed.
+        .args(["--expected-size", &size.to_string()])
+        .args(["--no-progress", "--only-show-errors"]);
+    cmd
+}
+
+fn head_command(ctx: &Context, object: &S3Url) -> Command {
+    let mut cmd = aws_command(ctx);
+    cmd.args(["s3api", "head-object"])
+        .args(["--bucket", &object.bucket, "--key", &object.key])
+        .args(["--output", "json"]);
+    cmd
+}
+
+fn checksum_object(object: &S3Url) -> S3Url {
+    S3Url {
+        bucket: object.bucket.clone(),
+        key: format!("{}{CHECKSUM_SUFFIX}", object.key),
+    }
+}
+
+/// Returns the requested publish target, if there is one, and the object
+/// the image will be uploaded to.
+fn target(ctx: &Context) -> Option<(S3Url, Compression)> {
+    let url: S3Url = ctx.get_var("publish_url")?.parse().ok()?;
+    let compression = ctx
+        .get_var("publish_compression")
+        .and_then(|c| clap::ValueEnum::from_str(c, true).ok())
+        .unwrap_or(Compression::None);
+    let image = Utf8Path::new(ctx.get_var("output_image").unwrap());
+    Some((url.object_for(image, compression), compression))
+}
+
+/// Waits for `child` to exit and fails if it didn't succeed.
+fn wait_success(child: &mut Child, name: &str) -> Result<()> {
+    let status = child.wait().with_context(|| format!("waiting for {name}"))?;
+    if !status.success() {
+        anyhow::bail!("{name} failed ({status}); see its log for details");
+    }
+
+    Ok(())
+}
+
+/// Streams the output image, compressed as requested, to the object named by
+/// the `publish_url` context variable, verifies its size, and uploads its
+/// checksum. Does nothing if no URL was given.
+pub fn publish_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some((object, compression)) = target(ctx) else {
+        return Ok(());
+    };
+
+    let image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let size = std::fs::metadata(&image)
+        .with_context(|| format!("reading metadata for '{image}'"))?
+        .len();
+
+    let file = std::fs::File::open(&image)
+        .with_context(|| format!("opening '{image}'"))?;
+    let mut compressor = None;
+    let mut source: Box<dyn Read> = match compression.command() {
+        Some(mut cmd) => {
+            let name = compression.to_string();
+            ui.command_started(&cmd);
+            cmd.stdin(file)
+                .stdout(Stdio::piped())
+                .stderr(ui.child_stderr(&name)?);
+            let mut child =
+                cmd.spawn().with_context(|| format!("starting {name}"))?;
+            let stdout = child.stdout.take().unwrap();
+            compressor = Some(child);
+            Box::new(stdout)
+        }
+        None => Box::new(file),
+    };
+
+    let mut upload = upload_command(ctx, &object, size);
+    ui.command_started(&upload);
+    ui.set_substep(&format!("uploading {image} to {object}"));
+    upload
+        .stdin(Stdio::piped())
+        .stdout(ui.child_stdout("aws")?)
+        .stderr(ui.child_stderr("aws")?);
+    let mut uploader = upload.spawn().context("starting aws")?;
+    let mut stdin = uploader.stdin.take().unwrap();
+
+    let mut hasher = Sha256::new();
+    let mut uploaded = 0u64;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let copied: Result<()> = (|| loop {
+        let read = source.read(&mut buf).context("reading the image")?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        hasher.update(&buf[..read]);
+        stdin.write_all(&buf[..read]).context("streaming to aws")?;
+        uploaded += read as u64;
+    })();
+    drop(stdin);
+    drop(source);
+
+    if let Some(child) = &mut compressor {
+        if copied.is_err() {
+            let _ = child.kill();
+        }
+        wait_success(child, &compression.to_string())?;
+    }
+    wait_success(&mut uploader, "aws s3 cp")?;
+    copied?;
+
+    let sha256 = crate::hash::to_hex(&hasher.finish());
+    trace::debug!(
+        "uploaded image to object storage",
+        object = object.to_string(),
+        bytes = uploaded,
+        sha256 = sha256.as_str()
+    );
+
+    ui.set_substep(&format!("verifying {object}"));
+    let output = run_command_check_status(&mut head_command(ctx, &object), ui)?;
+    let head = Json::parse(&String::from_utf8_lossy(&output.stdout))
+        .context("parsing aws s3api head-object output")?;
+    let stored = head.get("ContentLength").and_then(Json::as_i64);
+    if stored != Some(uploaded as i64) {
+        anyhow::bail!(
+            "{object} holds {} bytes, but {uploaded} bytes were uploaded",
+            stored
+                .map_or("an unknown number of".to_string(), |n| n.to_string())
+        );
+    }
+
+    let file_name = object.key.rsplit('/').next().unwrap();
+    let checksum_file = crate::workspace::scratch_path(
+        ctx,
+        &format!("{file_name}{CHECKSUM_SUFFIX}"),
+    );
+    std::fs::write(&checksum_file, format!("{sha256}  {file_name}\n"))
+        .with_context(|| format!("writing '{checksum_file}'"))?;
+    let mut put = aws_command(ctx);
+    put.args(["s3", "cp", checksum_file.as_str()])
+        .arg(checksum_object(&object).to_string())
+        .arg("--only-show-errors");
+    run_command_check_status(&mut put, ui)?;
+
+    // Without compression, what was uploaded is the image itself.
+    if compression == Compression::None {
+        crate::manifest::record_image_digest(ctx, &image, &sha256);
+    }
+
+    let mut published = Json::object();
+    published.insert("url", object.to_string());
+    published.insert("compression", compression.to_string());
+    published.insert("bytes", uploaded);
+    published.insert("sha256", sha256);
+    ui.record_metric("published_object", published);
+    Ok(())
+}
+
+pub fn describe_publish_image(ctx: &mut Context) -> Vec<String> {
+    let Some((object, compression)) = target(ctx) else {
+        return Vec::new();
+    };
+
+    let image = ctx.get_var("output_image").unwrap();
+    let size = std::fs::metadata(image).map(|m| m.len()).unwrap_or(0);
+    let upload = format_command(&upload_command(ctx, &object, size));
+    let mut lines = vec![match compression.command() {
+        Some(cmd) => format!("{} < {image} | {upload}", format_command(&cmd)),
+        None => format!("{upload} < {image}"),
+    }];
+    lines.push(format_command(&head_command(ctx, &object)));
+    lines.push(format!(
+        "upload the image's SHA-256 digest to {}",
+        checksum_object(&object)
+    ));
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_s3_urls() {
+        let url: S3Url = "s3://images/windows/".parse().unwrap();
+        assert_eq!(
+            url.object_for(Utf8Path::new("/tmp/ws2022.raw"), Compression::Zstd)
+                .to_string(),
+            "s3://images/windows/ws2022.raw.zst"
+        );
+
+        let url: S3Url = "s3://images/golden.img".parse().unwrap();
+        assert_eq!(
+            url.object_for(Utf8Path::new("out.raw"), Compression::Gzip),
+            S3Url {
+                bucket: "images".to_string(),
+                key: "golden.img".to_string()
+            }
+        );
+
+        for bad in ["images/key", "s3://images", "s3://images/", "s3:///key"] {
+            assert!(bad.parse::<S3Url>().is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,210 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Secret values (passwords and the like) supplied on the command line.
+//!
+//! Secrets are never passed directly as arguments, where they would be
+//! visible to other users of the host and saved in shell histories. Instead,
+//! the user names a source from which `wimsy` reads the secret when a step
+//! needs it. Only the source is stored in the script context, so secret
+//! values don't appear in the build report or in user steps' environments.
+
+use std::str::FromStr;
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::ui::Ui;
+
+/// Where to read a secret from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretSource {
+    /// The value of an environment variable (`env:NAME`).
+    Env(String),
+
+    /// The contents of a file, minus any trailing newline (`file:PATH`).
+    File(Utf8PathBuf),
+
+    /// Ask the user to type the secret when it's needed (`prompt`).
+    Prompt,
+}
+
+impl FromStr for SecretSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(name) = s.strip_prefix("env:") {
+            if name.is_empty() {
+                anyhow::bail!("'env:' must be followed by a variable name");
+            }
+            Ok(SecretSource::Env(name.to_string()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            if path.is_empty() {
+                anyhow::bail!("'file:' must be followed by a path");
+            }
+            Ok(SecretSource::File(Utf8PathBuf::from(path)))
+        } else if s == "prompt" {
+            Ok(SecretSource::Prompt)
+        } else {
+            anyhow::bail!(
+                "expected 'env:NAME', 'file:PATH', or 'prompt', got '{s}'"
+            )
+        }
+    }
+}
+
+impl std::fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Env(name) => write!(f, "env:{name}"),
+            SecretSource::File(path) => write!(f, "file:{path}"),
+            SecretSource::Prompt => write!(f, "prompt"),
+        }
+    }
+}
+
+impl SecretSource {
+    /// Checks, before a script starts, that the secret described by `what`
+    /// will be readable from this source when it's needed.
+    pub fn check(&self, what: &str) -> Result<()> {
+        match self {
+            SecretSource::Env(name) => match std::env::var(name) {
+                Ok(value) if !value.is_empty() => Ok(()),
+                _ => anyhow::bail!(
+                    "{what} is to be read from environment variable {name}, \
+                    which is not set"
+                ),
+            },
+            SecretSource::File(path) => std::fs::metadata(path)
+                .map(|_| ())
+                .with_context(|| format!("{what} file '{path}' is unreadable")),
+            SecretSource::Prompt if !atty::is(atty::Stream::Stdin) => {
+                anyhow::bail!(
+                    "{what} is to be entered at a prompt, but standard input \
+                    is not a terminal"
+                )
+            }
+            SecretSource::Prompt => Ok(()),
+        }
+    }
+
+    /// Reads the secret described by `what` from this source, prompting via
+    /// `ui` if necessary.
+    pub fn resolve(&self, what: &str, ui: &dyn Ui) -> Result<String> {
+        let value = match self {
+            SecretSource::Env(name) => {
+                std::env::var(name).with_context(|| {
+                    format!("reading {what} from environment variable {name}")
+                })?
+            }
+            SecretSource::File(path) => {
+                let mut contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {what} from '{path}'"))?;
+                let trimmed = contents.trim_end_matches(['\r', '\n']).len();
+                contents.truncate(trimmed);
+                contents
+            }
+            SecretSource::Prompt => {
+                ui.read_secret(&format!("Enter {what}: "))?
+            }
+        };
+
+        if value.is_empty() {
+            anyhow::bail!("{what} is empty");
+        }
+
+        Ok(value)
+    }
+}
+
+/// Encodes `password` the way answer files expect it when their `PlainText`
+/// setting is false: the UTF-16LE encoding of the password followed by the
+/// name of the element that holds it (e.g. `AdministratorPassword`), in
+/// base64. This only keeps the password from being readable at a glance; it
+/// isn't encryption.
+pub fn encode_unattend_password(password: &str, element: &str) -> String {
+    let bytes: Vec<u8> = format!("{password}{element}")
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    crate::util::encode_base64(&bytes)
+}
+
+/// Reads a line from the terminal attached to standard input without echoing
+/// it.
+pub fn read_line_without_echo() -> Result<String> {
+    if !atty::is(atty::Stream::Stdin) {
+        anyhow::bail!("standard input is not a terminal");
+    }
+
+    // SAFETY: `termios` is a plain C structure that `tcgetattr` fills in.
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("getting terminal attributes");
+    }
+
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+
+    // SAFETY: `silent` is a valid `termios` derived from the terminal's
+    // current attributes.
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) }
+        != 0
+    {
+        return Err(std::io::Error::last_os_error())
+            .context("disabling terminal echo");
+    }
+
+    let mut line = String::new();
+    let result = std::io::stdin().read_line(&mut line);
+
+    // SAFETY: `original` holds the attributes read from the terminal above.
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+
+    result.context("reading from terminal")?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            "env:JOIN_PASSWORD".parse::<SecretSource>().unwrap(),
+            SecretSource::Env("JOIN_PASSWORD".to_string())
+        );
+        assert_eq!(
+            "file:/run/secrets/join".parse::<SecretSource>().unwrap(),
+            SecretSource::File("/run/secrets/join".into())
+        );
+        assert_eq!(
+            "prompt".parse::<SecretSource>().unwrap(),
+            SecretSource::Prompt
+        );
+
+        for bad in ["", "env:", "file:", "hunter2", "Prompt"] {
+            assert!(bad.parse::<SecretSource>().is_err(), "{bad}");
+        }
+
+        for source in ["env:X", "file:a/b", "prompt"] {
+            assert_eq!(
+                source.parse::<SecretSource>().unwrap().to_string(),
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_unattend_passwords() {
+        assert_eq!(
+            encode_unattend_password("Password1", "AdministratorPassword"),
+            "UABhAHMAcwB3AG8AcgBkADEAQQBkAG0AaQBuAGkAcwB0AHIAYQB0AG8AcgBQAGEA\
+            cwBzAHcAbwByAGQA"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The shrink command, which trims an existing image the way a build trims
+//! its output image: it cuts off the unpartitioned space after the last
+//! partition and rewrites the secondary GPT at the new end of the disk.
+
+use anyhow::Result;
+use camino::Utf8Path;
+
+use crate::{
+    nbd::{describe_raw_access, RawImage},
+    steps::{
+        describe_repair_secondary_gpt, describe_shrink_output_image,
+        get_image_virtual_size, get_output_image_partition_size,
+        repair_secondary_gpt, shrink_output_image, shrunken_image_size,
+    },
+    ui::StandaloneUi,
+    util::format_size,
+};
+
+/// Shrinks the raw or qcow2 image at `image` in place. With `dry_run`, reads
+/// its partition table but only prints the commands that would change it.
+pub fn run(image: &Utf8Path, dry_run: bool) -> Result<()> {
+    let format = crate::nbd::image_format(image)?;
+    crate::nbd::ensure_can_attach(image, format)?;
+    let ui = StandaloneUi;
+
+    let (sector_size, last_sector) = {
+        let raw = RawImage::open(image, format, &ui)?;
+        get_output_image_partition_size(raw.path().as_str(), &ui)?
+    };
+    let (sector_size, last_sector) =
+        (sector_size.to_string(), last_sector.to_string());
+    let old_size = get_image_virtual_size(image.as_str(), format, &ui)?;
+    let new_size = shrunken_image_size(&sector_size, &last_sector)?;
+
+    // Images produced elsewhere may already be as small as their partitions
+    // allow, and resizing one without --shrink would grow it.
+    let shrink = new_size < old_size;
+    if dry_run {
+        if shrink {
+            for line in describe_shrink_output_image(
+                image.as_str(),
+                format,
+                &sector_size,
+                &last_sector,
+            ) {
+                println!("{line}");
+            }
+        }
+        for line in describe_raw_access(
+            image.as_str(),
+            format,
+            describe_repair_secondary_gpt,
+        ) {
+            println!("{line}");
+        }
+        return Ok(());
+    }
+
+    if shrink {
+        shrink_output_image(
+            image.as_str(),
+            format,
+            &sector_size,
+            &last_sector,
+            &ui,
+        )?;
+        println!(
+            "Shrank '{image}' from {} to {}",
+            format_size(old_size),
+            format_size(new_size)
+        );
+    } else {
+        println!(
+            "'{image}' is already {}, as small as its partitions allow",
+            format_size(old_size)
+        );
+    }
+
+    let raw = RawImage::open(image, format, &ui)?;
+    repair_secondary_gpt(raw.path().as_str(), &ui)?;
+    println!("Rewrote the secondary GPT at the end of the disk");
+    Ok(())
+}
@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Shares the host among the builds that build-all runs at once.
+//!
+//! build-all creates a slots directory holding one `vm-slot-N.lock` file per
+//! installation VM the host has room for, and passes the directory to each
+//! build with `--slots-dir`. A build locks one of the slot files before it
+//! launches a VM, and `conversion.lock` while it runs `qemu-img convert`, so
+//! that only so many VMs, and only one conversion, run at a time. The locks
+//! are released when the slot is dropped or the build exits.
+
+use std::{
+    fs::{File, OpenOptions},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{lock::try_lock, runner::Context, trace, ui::Ui};
+
+/// The name of the file builds lock while converting an image.
+const CONVERSION_LOCK_NAME: &str = "conversion.lock";
+
+/// How often to try again to take a slot another build holds.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+fn vm_slot_path(dir: &Utf8Path, index: usize) -> Utf8PathBuf {
+    dir.join(format!("vm-slot-{index}.lock"))
+}
+
+/// Creates the slots directory `dir` with room for `vm_slots` VMs at once.
+pub fn create(dir: &Utf8Path, vm_slots: usize) -> Result<()> {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("creating '{dir}'"))?;
+    for path in (0..vm_slots)
+        .map(|index| vm_slot_path(dir, index))
+        .chain([dir.join(CONVERSION_LOCK_NAME)])
+    {
+        File::create(&path).with_context(|| format!("creating '{path}'"))?;
+    }
+
+    Ok(())
+}
+
+/// A held slot. Dropping it frees the slot for another build.
+pub struct Slot {
+    /// The open slot file. Closing it releases the lock on it.
+    _file: File,
+}
+
+/// Waits for a free VM slot and takes it, if this build shares the host with
+/// others (i.e. if the `slots_dir` context variable is set).
+pub fn take_vm_slot(ctx: &Context, ui: &dyn Ui) -> Result<Option<Slot>> {
+    let Some(dir) = ctx.get_var("slots_dir") else {
+        return Ok(None);
+    };
+
+    let dir = Utf8Path::new(dir);
+    let mut paths = Vec::new();
+    while vm_slot_path(dir, paths.len()).exists() {
+        paths.push(vm_slot_path(dir, paths.len()));
+    }
+    if paths.is_empty() {
+        anyhow::bail!("'{dir}' has no VM slots");
+    }
+
+    take(&paths, "waiting for another target's VM to finish", ui).map(Some)
+}
+
+/// Waits until no other build is converting an image and then takes the
+/// conversion slot, if this build shares the host with others.
+pub fn take_conversion_slot(
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<Option<Slot>> {
+    let Some(dir) = ctx.get_var("slots_dir") else {
+        return Ok(None);
+    };
+
+    take(
+        &[Utf8Path::new(dir).join(CONVERSION_LOCK_NAME)],
+        "waiting for another target's image conversion to finish",
+        ui,
+    )
+    .map(Some)
+}
+
+/// Takes the first of the slots whose files are `paths` to come free, saying
+/// `waiting` if none is free at first.
+fn take(paths: &[Utf8PathBuf], waiting: &str, ui: &dyn Ui) -> Result<Slot> {
+    let mut said_waiting = false;
+    loop {
+        for path in paths {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .with_context(|| format!("opening slot file '{path}'"))?;
+            if try_lock(&file).with_context(|| format!("locking '{path}'"))? {
+                trace::debug!("took slot", path = path.as_str());
+                return Ok(Slot { _file: file });
+            }
+        }
+
+        if !said_waiting {
+            trace::debug!("waiting for a slot", waiting = waiting);
+            ui.set_substep(waiting);
+            said_waiting = true;
+        }
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+}
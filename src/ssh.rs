@@ -0,0 +1,133 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Loads the public keys passed with `--ssh-authorized-keys`, which the
+//! guest setup script installs as the image's
+//! `administrators_authorized_keys` when it enables the OpenSSH server.
+//!
+//! The keys are written into the guest settings file rather than staged as a
+//! file of their own, so they reach the guest however the settings do.
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::util::decode_base64;
+
+/// The context variable holding the path of the authorized keys file.
+pub const AUTHORIZED_KEYS_VAR: &str = "ssh_authorized_keys";
+
+/// The key types OpenSSH for Windows accepts in an authorized keys file.
+const KEY_TYPES: [&str; 8] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+    "ssh-dss",
+];
+
+/// Loads the keys in the authorized keys file at `path`, returning each key
+/// line (with any options and comment) in order.
+pub fn load_authorized_keys(path: &Utf8Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading authorized keys file '{path}'"))?;
+    parse(&contents)
+        .with_context(|| format!("parsing authorized keys in '{path}'"))
+}
+
+fn parse(contents: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        check_key_line(line).with_context(|| format!("line {}", i + 1))?;
+        keys.push(line.to_string());
+    }
+
+    Ok(keys)
+}
+
+/// Checks that `line` holds a public key: a key type, optionally preceded by
+/// options, followed by the base64-encoded key, whose encoding starts with
+/// the same key type.
+fn check_key_line(line: &str) -> Result<()> {
+    let mut words = line.split_whitespace();
+    let Some(key_type) = words.find(|word| KEY_TYPES.contains(word)) else {
+        anyhow::bail!("no supported key type (such as ssh-ed25519) found");
+    };
+    let Some(encoded) = words.next() else {
+        anyhow::bail!("the {key_type} key is missing");
+    };
+
+    let blob = decode_base64(encoded)
+        .with_context(|| format!("the {key_type} key isn't valid base64"))?;
+    let name_len = blob
+        .get(..4)
+        .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize);
+    let name = name_len.and_then(|len| blob.get(4..4 + len));
+    if name != Some(key_type.as_bytes()) {
+        anyhow::bail!("the key's encoding isn't a {key_type} key");
+    }
+
+    Ok(())
+}
+
+/// Checks that the authorized keys file at `path`, if one was given, holds
+/// at least one well-formed key.
+pub fn check_prerequisites(path: Option<&Utf8Path>) -> Vec<String> {
+    let Some(path) = path else {
+        return Vec::new();
+    };
+
+    match load_authorized_keys(path) {
+        Ok(keys) if keys.is_empty() => {
+            vec![format!("no SSH public keys found in '{path}'")]
+        }
+        Ok(_) => Vec::new(),
+        Err(e) => vec![format!("{e:#}")],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_authorized_keys() {
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIF4p3b0Ri4Ok9Y5wl3hYl1Fz\
+            k0AP8a+ByDlR3We3ydDI";
+        let keys = parse(&format!(
+            "# Build hosts\n\n{key} admin@build\n\
+            no-pty,from=\"10.0.0.0/8\" {key}\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            keys,
+            [
+                format!("{key} admin@build"),
+                format!("no-pty,from=\"10.0.0.0/8\" {key}")
+            ]
+        );
+
+        for (line, error) in [
+            ("AAAAC3NzaC1lZDI1NTE5", "no supported key type"),
+            ("ssh-ed25519", "missing"),
+            ("ssh-ed25519 not!base64", "isn't valid base64"),
+            (
+                "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIF4p3b0Ri4Ok9Y5wl3hYl1Fzk0AP8a\
+                +ByDlR3We3ydDI",
+                "isn't a ssh-rsa key",
+            ),
+        ] {
+            let e = parse(line).unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{line}: {e:#}");
+        }
+    }
+}
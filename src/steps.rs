@@ -4,84 +4,483 @@
 
 //! Common script steps that are shared between multiple OSes.
 
-use std::process::Command;
+use std::{collections::HashMap, process::Command};
 
 use crate::{
+    app::{DiskSize, ImageSources},
+    autounattend::{Architecture, WindowsVersion},
+    download::Download,
+    error::{Error, ErrorKind},
+    gpt::{Guid, Partition, PartitionTable},
+    json::Json,
+    runner::Context,
+    template::TEMPLATE_SUFFIX,
+    trace,
     ui::Ui,
-    util::{grep_command_for_row_and_column, run_command_check_status},
+    util::{format_command, run_command_check_status, unattend_source_path},
+    UNATTEND_FILES,
 };
 
 use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
+
+/// Returns the format of the output image: "raw" unless the script's
+/// `output_format` context variable says otherwise.
+pub fn output_format(ctx: &Context) -> &str {
+    ctx.get_var("output_format").unwrap_or("raw")
+}
+
+/// Returns an error message if `size` is too small a disk to install
+/// `version` of Windows on, or, if the version isn't known, to install any
+/// supported version on. (Versions that need more space are checked again
+/// once they're detected.)
+pub fn check_disk_size(
+    size: DiskSize,
+    version: Option<WindowsVersion>,
+) -> Option<String> {
+    let minimum = match version {
+        Some(version) => version.minimum_disk_size(),
+        None => <WindowsVersion as clap::ValueEnum>::value_variants()
+            .iter()
+            .map(WindowsVersion::minimum_disk_size)
+            .min()
+            .unwrap(),
+    };
+
+    if size >= minimum {
+        return None;
+    }
+
+    let target = match version {
+        Some(version) => version.to_string(),
+        None => "Windows".to_string(),
+    };
+    Some(format!(
+        "the output disk ({size}, {} bytes) is smaller than the {} bytes \
+        {target} requires; pass a larger --disk-size",
+        size.bytes(),
+        minimum.bytes()
+    ))
+}
+
+/// The least memory, in MiB, Windows Setup will install with.
+pub const MIN_VM_MEMORY_MIB: u64 = 2048;
+
+/// Returns a message describing each problem with the installation VM's
+/// requested vCPU count and memory size.
+pub fn check_vm_resources(cpus: u32, memory_mib: u64) -> Vec<String> {
+    let mut errors = Vec::new();
+    if cpus == 0 {
+        errors.push("the installation VM needs at least one vCPU".to_string());
+    }
+
+    if memory_mib < MIN_VM_MEMORY_MIB {
+        errors.push(format!(
+            "the installation VM's memory ({memory_mib} MiB) is less than \
+            the {MIN_VM_MEMORY_MIB} MiB Windows Setup requires"
+        ));
+    }
+
+    errors
+}
+
+/// Creates a blank output disk in `format`, `size_bytes` bytes long, to which
+/// Windows can be installed.
+///
+/// Raw disks are created directly as sparse files, so building a raw image
+/// doesn't need `qemu-img` for this step. They're deliberately not
+/// preallocated (e.g. with `fallocate`): the guest only writes a fraction of
+/// the disk, and the image is trimmed to fit its contents afterwards. Other
+/// formats are created with `qemu-img`.
+pub fn create_output_image(
+    image_path: &str,
+    format: &str,
+    size_bytes: &str,
+    ui: &dyn Ui,
+) -> Result<()> {
+    if format == "raw" {
+        let size: u64 = size_bytes
+            .parse()
+            .with_context(|| format!("parsing disk size '{size_bytes}'"))?;
+        ui.set_substep(&format!("creating {size}-byte sparse file"));
+        let file = std::fs::File::create(image_path)
+            .with_context(|| format!("creating '{image_path}'"))?;
+        file.set_len(size).with_context(|| {
+            format!("resizing '{image_path}' to {size} bytes")
+        })?;
+        trace::debug!("created sparse output image", bytes = size);
+        return Ok(());
+    }
 
-/// Uses `qemu-img` to create a blank output disk to which Windows can be
-/// installed.
-pub fn create_output_image(image_path: &str, ui: &dyn Ui) -> Result<()> {
     run_command_check_status(
-        Command::new("qemu-img")
-            .args(["create", "-f", "raw", image_path, "30G"]),
+        &mut create_image_command(image_path, format, size_bytes),
         ui,
     )
     .map(|_| ())
 }
 
+fn create_image_command(
+    image_path: &str,
+    format: &str,
+    size_bytes: &str,
+) -> Command {
+    let mut cmd = Command::new("qemu-img");
+    cmd.args(["create", "-f", format, image_path, size_bytes]);
+    cmd
+}
+
+/// Describes what [`create_output_image`] would do.
+pub fn describe_create_output_image(
+    image_path: &str,
+    format: &str,
+    size_bytes: &str,
+) -> Vec<String> {
+    if format == "raw" {
+        return vec![format!(
+            "create a {size_bytes}-byte sparse file at {image_path}"
+        )];
+    }
+
+    vec![format_command(&create_image_command(image_path, format, size_bytes))]
+}
+
 pub struct GptPartitionInformation {
-    pub sector_size: String,
-    pub first_sector: String,
-    pub last_sector: String,
-    pub partition_sectors: String,
+    pub sector_size: u64,
+    pub first_sector: u64,
+    pub last_sector: u64,
+    pub partition_sectors: u64,
 }
 
-/// Uses `sgdisk` to get the sector size, first and last sector offset, and
-/// partition size (in sectors) for an arbitrary partition ID in the supplied
-/// image.
-pub fn get_gpt_partition_information(
+impl GptPartitionInformation {
+    fn new(table: &PartitionTable, partition: &Partition) -> Self {
+        Self {
+            sector_size: table.sector_size,
+            first_sector: partition.first_lba,
+            last_sector: partition.last_lba,
+            partition_sectors: partition.sectors(),
+        }
+    }
+}
+
+/// Reads the partition table from the raw disk image at `image_path`.
+///
+/// The table is read with the native parser in [`crate::gpt`]. If that fails
+/// (e.g. because the table uses a layout the parser doesn't understand), this
+/// falls back to asking `sgdisk` to describe each partition.
+pub fn read_partition_table(
     image_path: &str,
-    partition_id: u32,
     ui: &dyn Ui,
-) -> Result<GptPartitionInformation> {
-    let partition_id_string = partition_id.to_string();
-    let sector_size = grep_command_for_row_and_column(
+) -> Result<PartitionTable> {
+    let native_error = match crate::gpt::read_image(Utf8Path::new(image_path)) {
+        Ok(table) => return Ok(table),
+        Err(e) => e,
+    };
+
+    trace::debug!(
+        "couldn't read partition table natively; falling back to sgdisk",
+        image = image_path,
+        error = format!("{native_error:#}")
+    );
+    read_partition_table_with_sgdisk(image_path, ui)
+        .with_context(|| {
+            format!(
+                "reading partition table with sgdisk after the built-in \
+                reader failed ({native_error:#})"
+            )
+        })
+        .context(Error::new(
+            ErrorKind::PartitionTable,
+            format!("couldn't read the partition table in '{image_path}'"),
+        ))
+}
+
+/// Reads the partition table from the disk image at `image_path` by parsing
+/// the output of `sgdisk -p` and `sgdisk -i`.
+fn read_partition_table_with_sgdisk(
+    image_path: &str,
+    ui: &dyn Ui,
+) -> Result<PartitionTable> {
+    let output = run_command_check_status(
         Command::new("sgdisk").args(["-p", image_path]),
-        "Sector size",
-        3,
         ui,
     )
-    .context("running 'sgdisk -p' to get sector size")?;
+    .context("running 'sgdisk -p' to list partitions")?;
+    let (sector_size, disk_guid, numbers) =
+        parse_sgdisk_table(&String::from_utf8_lossy(&output.stdout))?;
 
-    let first_sector = grep_command_for_row_and_column(
-        Command::new("sgdisk").args(["-i", &partition_id_string, image_path]),
-        "First sector",
-        2,
-        ui,
-    )
-    .context("getting first sector offset from 'sgdisk -i'")?;
+    let mut partitions = Vec::new();
+    for number in numbers {
+        let output = run_command_check_status(
+            Command::new("sgdisk").args([
+                "-i",
+                &number.to_string(),
+                image_path,
+            ]),
+            ui,
+        )
+        .with_context(|| format!("running 'sgdisk -i {number}'"))?;
+        partitions.push(parse_sgdisk_partition(
+            number,
+            &String::from_utf8_lossy(&output.stdout),
+        )?);
+    }
 
-    let last_sector = grep_command_for_row_and_column(
-        Command::new("sgdisk").args(["-i", &partition_id_string, image_path]),
-        "Last sector",
-        2,
-        ui,
-    )
-    .context("getting last sector offset from 'sgdisk -i'")?;
+    Ok(PartitionTable { sector_size, disk_guid, partitions })
+}
 
-    let partition_sectors = grep_command_for_row_and_column(
-        Command::new("sgdisk").args(["-i", &partition_id_string, image_path]),
-        "Partition size",
-        2,
-        ui,
-    )
-    .context("getting partition sector count from 'sgdisk -i'")?;
+/// Returns the value following `label` on the first line of `output` that
+/// starts with it.
+fn sgdisk_field<'a>(output: &'a str, label: &str) -> Result<&'a str> {
+    output
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix(label))
+        .map(str::trim)
+        .with_context(|| format!("sgdisk output has no '{label}' line"))
+}
+
+/// Parses the output of `sgdisk -p`, returning the disk's logical sector
+/// size, its GUID, and the numbers of its partitions.
+fn parse_sgdisk_table(output: &str) -> Result<(u64, Guid, Vec<u32>)> {
+    // Depending on the version, this line reads either "Sector size
+    // (logical): 512 bytes" or "Sector size (logical/physical): 512/4096
+    // bytes".
+    let sector_size = output
+        .lines()
+        .find(|line| line.starts_with("Sector size"))
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|size| size.split('/').next())
+        .context("sgdisk output has no sector size")?;
+    let sector_size = sector_size
+        .parse()
+        .with_context(|| format!("parsing sector size '{sector_size}'"))?;
+
+    let disk_guid = sgdisk_field(output, "Disk identifier (GUID):")?.parse()?;
+
+    let mut numbers = Vec::new();
+    for line in output.lines() {
+        let trimmed = line.trim_start();
+        if !trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let number = trimmed.split_whitespace().next().unwrap();
+        numbers.push(number.parse().with_context(|| {
+            format!("parsing partition number from '{line}'")
+        })?);
+    }
+
+    Ok((sector_size, disk_guid, numbers))
+}
+
+/// Parses the output of `sgdisk -i NUMBER`, which describes partition
+/// `number`.
+fn parse_sgdisk_partition(number: u32, output: &str) -> Result<Partition> {
+    // GUIDs are followed by a description (e.g. "(Microsoft basic data)"),
+    // and sectors by their offset (e.g. "(at 1024.0 KiB)").
+    let first_word = |label| -> Result<&str> {
+        let value = sgdisk_field(output, label)?;
+        Ok(value.split_whitespace().next().unwrap_or(value))
+    };
+    let sector = |label| -> Result<u64> {
+        let value = first_word(label)?;
+        value.parse().with_context(|| format!("parsing {label} '{value}'"))
+    };
 
-    Ok(GptPartitionInformation {
-        sector_size,
-        first_sector,
-        last_sector,
-        partition_sectors,
+    let attributes = first_word("Attribute flags:")?;
+    let name = sgdisk_field(output, "Partition name:")?;
+    Ok(Partition {
+        number,
+        type_guid: first_word("Partition GUID code:")?.parse()?,
+        unique_guid: first_word("Partition unique GUID:")?.parse()?,
+        first_lba: sector("First sector:")?,
+        last_lba: sector("Last sector:")?,
+        attributes: u64::from_str_radix(attributes, 16).with_context(|| {
+            format!("parsing attribute flags '{attributes}'")
+        })?,
+        name: name.trim_matches('\'').to_string(),
     })
 }
 
-/// Uses `sgdisk` to get the sector size and the offset of the last sector in an
-/// output image.
+/// Gets the sector size, first and last sector offset, and partition size (in
+/// sectors) for an arbitrary partition ID in the supplied image.
+pub fn get_gpt_partition_information(
+    image_path: &str,
+    partition_id: u32,
+    ui: &dyn Ui,
+) -> Result<GptPartitionInformation> {
+    let table = read_partition_table(image_path, ui)?;
+    let partition = table
+        .partitions
+        .iter()
+        .find(|p| p.number == partition_id)
+        .with_context(|| {
+            format!("'{image_path}' has no partition {partition_id}")
+        })?;
+
+    Ok(GptPartitionInformation::new(&table, partition))
+}
+
+/// How [`find_partition_by_name`] matches partition names.
+#[derive(Clone, Copy, Debug)]
+pub enum NamePattern<'a> {
+    /// Matches names equal to the pattern.
+    Exact(&'a str),
+
+    /// Matches names against a glob in which `*` matches any sequence of
+    /// characters and `?` matches any one character.
+    Glob(&'a str),
+}
+
+impl NamePattern<'_> {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(pattern) => name == *pattern,
+            NamePattern::Glob(pattern) => glob_matches(pattern, name),
+        }
+    }
+}
+
+impl std::fmt::Display for NamePattern<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NamePattern::Exact(pattern) => write!(f, "'{pattern}'"),
+            NamePattern::Glob(pattern) => write!(f, "glob '{pattern}'"),
+        }
+    }
+}
+
+/// Returns true if `name` matches the glob `pattern` (see
+/// [`NamePattern::Glob`]).
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+
+    // The pattern index just after the most recent `*`, and the name index
+    // that star is currently assumed to match up to.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Why [`find_partition_by_name`] didn't find a partition.
+#[derive(Debug)]
+pub enum FindPartitionError {
+    /// No partition's name matches the pattern.
+    NotFound { image: String, pattern: String },
+
+    /// More than one partition's name matches the pattern.
+    Ambiguous { image: String, pattern: String, partitions: Vec<u32> },
+
+    /// The image's partition table couldn't be read.
+    Unreadable(anyhow::Error),
+}
+
+impl std::fmt::Display for FindPartitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FindPartitionError::NotFound { image, pattern } => write!(
+                f,
+                "no partition in '{image}' has a name matching {pattern}"
+            ),
+            FindPartitionError::Ambiguous { image, pattern, partitions } => {
+                let (last, rest) = partitions.split_last().unwrap();
+                let rest: Vec<String> =
+                    rest.iter().map(|n| n.to_string()).collect();
+                write!(
+                    f,
+                    "partition name {pattern} in '{image}' is ambiguous: \
+                    matched partitions {}{} and {last}",
+                    rest.join(", "),
+                    if rest.len() > 1 { "," } else { "" }
+                )
+            }
+            FindPartitionError::Unreadable(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for FindPartitionError {}
+
+/// Returns the partition in `partitions` whose name matches `pattern`, which
+/// must match exactly one of them.
+fn select_partition_by_name<'a>(
+    partitions: &'a [crate::gpt::Partition],
+    pattern: NamePattern<'_>,
+    image_path: &str,
+) -> Result<&'a crate::gpt::Partition, FindPartitionError> {
+    let matches: Vec<&crate::gpt::Partition> =
+        partitions.iter().filter(|p| pattern.matches(&p.name)).collect();
+    match matches.as_slice() {
+        [partition] => Ok(partition),
+        [] => Err(FindPartitionError::NotFound {
+            image: image_path.to_string(),
+            pattern: pattern.to_string(),
+        }),
+        _ => Err(FindPartitionError::Ambiguous {
+            image: image_path.to_string(),
+            pattern: pattern.to_string(),
+            partitions: matches.iter().map(|p| p.number).collect(),
+        }),
+    }
+}
+
+/// Finds the partition in the image at `image_path` whose GPT partition name
+/// matches `name_pattern`, returning its number and geometry.
+///
+/// Names are read with the native GPT parser in [`crate::gpt`] rather than
+/// from `sgdisk`, which doesn't render names reliably when they aren't
+/// ASCII. Callers that care why no partition was found (for example, to
+/// treat a missing partition as optional but still fail on an ambiguous
+/// name) can match on the returned [`FindPartitionError`].
+pub fn find_partition_by_name(
+    image_path: &str,
+    name_pattern: NamePattern<'_>,
+    ui: &dyn Ui,
+) -> Result<(u32, GptPartitionInformation), FindPartitionError> {
+    ui.set_substep(&format!(
+        "finding partition named {name_pattern} in {image_path}"
+    ));
+    let table = crate::gpt::read_image(Utf8Path::new(image_path))
+        .map_err(FindPartitionError::Unreadable)?;
+    let partition =
+        select_partition_by_name(&table.partitions, name_pattern, image_path)?;
+    trace::debug!(
+        "found partition by name",
+        pattern = name_pattern.to_string(),
+        number = partition.number,
+        name = partition.name.as_str()
+    );
+
+    Ok((partition.number, GptPartitionInformation::new(&table, partition)))
+}
+
+/// Gets the sector size and the offset of the last sector in an output image.
 ///
 /// This function dynamically finds the last partition rather than assuming a
 /// fixed partition number. Windows Server 2016/2019/2022 create 4 partitions,
@@ -98,73 +497,27 @@ pub fn get_gpt_partition_information(
 ///
 /// - `Ok(sector size, last sector)` where last sector is the highest end sector
 ///   across all partitions.
-/// - `Err` if an `sgdisk` command failed, produced unexpected output, or
-///   contained no partition entries.
+/// - `Err` if the partition table couldn't be read or contained no partition
+///   entries.
 pub fn get_output_image_partition_size(
     image_path: &str,
     ui: &dyn Ui,
-) -> Result<(String, String)> {
-    let sector_size = grep_command_for_row_and_column(
-        Command::new("sgdisk").args(["-p", image_path]),
-        "Sector size",
-        3,
-        ui,
-    )
-    .context("running 'sgdisk -p' to get sector size")?;
+) -> Result<(u64, u64)> {
+    let table = read_partition_table(image_path, ui)?;
+    let last_sector =
+        table.partitions.iter().map(|p| p.last_lba).max().with_context(
+            || format!("no partitions found in '{image_path}'"),
+        )?;
 
-    let output = run_command_check_status(
-        Command::new("sgdisk").args(["-p", image_path]),
-        ui,
-    )
-    .context("running 'sgdisk -p' to list partitions")?;
-
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let mut max_end_sector: Option<u64> = None;
-    for line in output_str.lines() {
-        let trimmed = line.trim_start();
-        if !trimmed.starts_with(|c: char| c.is_ascii_digit()) {
-            continue;
-        }
-        let mut cols = trimmed.split_whitespace();
-        // column 0: partition number, column 1: start sector, column 2: end sector
-        let end_sector = cols
-            .nth(2)
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "partition line '{line}' does not have an end sector column"
-                )
-            })?
-            .parse::<u64>()
-            .with_context(|| {
-                format!(
-                    "parsing end sector from partition line '{line}'"
-                )
-            })?;
-        max_end_sector = Some(match max_end_sector {
-            Some(prev) => prev.max(end_sector),
-            None => end_sector,
-        });
-    }
-
-    let max_end_sector = max_end_sector.ok_or_else(|| {
-        anyhow::anyhow!(
-            "no partition entries found in 'sgdisk -p' output for '{image_path}'"
-        )
-    })?;
-
-    Ok((sector_size, max_end_sector.to_string()))
+    Ok((table.sector_size, last_sector))
 }
 
-/// Given an installed Windows image at `image_path` whose sector size is
-/// `sector_size` and where the last sector of the last partition on the disk is
-/// `last_sector`, trims unused sectors from the image, leaving just enough
-/// space at the end to fit a new secondary GUID partition table.
-pub fn shrink_output_image(
-    image_path: &str,
+/// Returns the size to which [`shrink_output_image`] trims an image with the
+/// supplied sector size and last partition sector.
+pub fn shrunken_image_size(
     sector_size: &str,
     last_sector: &str,
-    ui: &dyn Ui,
-) -> Result<()> {
+) -> Result<u64> {
     let sector_size =
         sector_size.parse::<u64>().context("parsing sector size as u64")?;
 
@@ -177,8 +530,48 @@ pub fn shrink_output_image(
     // Leave 34 sectors after the last partition for the secondary GPT. Note
     // that this GPT won't exist in the truncated disk; the caller needs to
     // recreate it, e.g. using `sgdisk -e`.
+    //
+    // Round the new size up to a whole number of MiB, since some consumers of
+    // converted images (notably Azure) reject disks of any other size.
     let new_disk_size = os_partition_size + (34 * sector_size);
-    let new_disk_size = new_disk_size.to_string();
+    Ok(new_disk_size.next_multiple_of(1024 * 1024))
+}
+
+/// Describes the command [`shrink_output_image`] would run. The size is only
+/// known if the partition layout is, which it usually isn't until Windows has
+/// been installed.
+pub fn describe_shrink_output_image(
+    image_path: &str,
+    format: &str,
+    sector_size: &str,
+    last_sector: &str,
+) -> Vec<String> {
+    let new_disk_size = shrunken_image_size(sector_size, last_sector)
+        .map(|size| size.to_string())
+        .unwrap_or_else(|_| "<new size>".to_string());
+    vec![format_command(Command::new("qemu-img").args([
+        "resize",
+        "--shrink",
+        "-f",
+        format,
+        image_path,
+        &new_disk_size,
+    ]))]
+}
+
+/// Given an installed Windows image in `format` at `image_path` whose sector
+/// size is `sector_size` and where the last sector of the last partition on
+/// the disk is `last_sector`, trims unused sectors from the image, leaving
+/// just enough space at the end to fit a new secondary GUID partition table.
+pub fn shrink_output_image(
+    image_path: &str,
+    format: &str,
+    sector_size: &str,
+    last_sector: &str,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let new_disk_size =
+        shrunken_image_size(sector_size, last_sector)?.to_string();
 
     // QEMU 5.10 and later require callers to pass the `--shrink` flag when
     // shrinking an image with `qemu-img resize`. This flag was added in QEMU
@@ -191,26 +584,984 @@ pub fn shrink_output_image(
     // to start with. If that fails, fall back to running without `--shrink` to
     // see if that resolves the problem.
     let mut args =
-        vec!["resize", "--shrink", "-f", "raw", image_path, &new_disk_size];
+        vec!["resize", "--shrink", "-f", format, image_path, &new_disk_size];
     if run_command_check_status(Command::new("qemu-img").args(&args), ui)
         .is_ok()
     {
         return Ok(());
     }
 
-    // This will overwrite the log file output from the previous invocation, but
-    // if this step fails, it's probably going to be for the same reason the
-    // previous invocation did (i.e. something else is probably wrong that
-    // isn't related to whether `--shrink` was used).
+    trace::debug!("qemu-img resize --shrink failed; retrying without it");
+
+    // This invocation's output is appended to the previous one's in the step's
+    // qemu-img logs, so if it fails too, both failures can be compared.
     assert_eq!(args.remove(1), "--shrink");
     run_command_check_status(Command::new("qemu-img").args(&args), ui)
         .map(|_| ())
 }
 
+/// Returns the size, in bytes, of the disk the image at `image_path` (which is
+/// in `format`) presents to a guest.
+pub fn get_image_virtual_size(
+    image_path: &str,
+    format: &str,
+    ui: &dyn Ui,
+) -> Result<u64> {
+    let output =
+        run_command_check_status(&mut info_command(image_path, format), ui)?;
+
+    let info = Json::parse(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("parsing qemu-img info for '{image_path}'"))?;
+    info.get("virtual-size")
+        .and_then(Json::as_i64)
+        .and_then(|size| u64::try_from(size).ok())
+        .with_context(|| {
+            format!("qemu-img info for '{image_path}' has no virtual size")
+        })
+}
+
+fn info_command(image_path: &str, format: &str) -> Command {
+    let mut cmd = Command::new("qemu-img");
+    cmd.args(["info", "--output=json", "-f", format, image_path]);
+    cmd
+}
+
+/// Describes the command [`get_image_virtual_size`] would run.
+pub fn describe_get_image_virtual_size(
+    image_path: &str,
+    format: &str,
+) -> String {
+    format_command(&info_command(image_path, format))
+}
+
 pub fn repair_secondary_gpt(image_path: &str, ui: &dyn Ui) -> Result<()> {
-    run_command_check_status(
-        Command::new("sgdisk").args(["-e", image_path]),
-        ui,
+    run_command_check_status(&mut repair_command(image_path), ui).map(|_| ())
+}
+
+fn repair_command(image_path: &str) -> Command {
+    let mut cmd = Command::new("sgdisk");
+    cmd.args(["-e", image_path]);
+    cmd
+}
+
+/// Describes the command [`repair_secondary_gpt`] would run.
+pub fn describe_repair_secondary_gpt(image_path: &str) -> Vec<String> {
+    vec![format_command(&repair_command(image_path))]
+}
+
+/// Yields the variables available to unattend file templates: the contents of
+/// the script context plus some values computed from them. See CONFIGURING.md
+/// for a description of each variable.
+pub fn template_vars(ctx: &Context) -> HashMap<String, String> {
+    let mut vars = ctx.vars().clone();
+    if let Some(index) = ctx.get_var("unattend_image_index") {
+        vars.insert("image_index".to_string(), index.to_string());
+    }
+
+    if let Some(version) = windows_version(ctx) {
+        vars.insert(
+            "driver_version".to_string(),
+            version.as_driver_path_component().to_string(),
+        );
+        if version.is_client() {
+            vars.insert("windows_client".to_string(), String::new());
+        }
+    }
+
+    let arch = architecture(ctx);
+    vars.insert(
+        "processor_architecture".to_string(),
+        arch.windows_name().to_string(),
+    );
+    vars.insert("driver_arch".to_string(), arch.driver_dir().to_string());
+
+    if let Some(size) = ctx.get_var("disk_size") {
+        let mib = size.parse::<u64>().map(|bytes| bytes >> 20);
+        if let Ok(mib) = mib {
+            vars.insert("disk_size_mb".to_string(), mib.to_string());
+        }
+    }
+
+    // User-defined variables come last so that they can override the others.
+    let user_vars: Vec<(String, String)> = ctx
+        .vars()
+        .iter()
+        .filter_map(|(var, value)| {
+            let name = var.strip_prefix(crate::template::USER_VAR_PREFIX)?;
+            Some((name.to_string(), value.clone()))
+        })
+        .collect();
+    vars.retain(|var, _| !var.starts_with(crate::template::USER_VAR_PREFIX));
+    vars.extend(user_vars);
+    vars
+}
+
+/// Returns [`template_vars`] plus the variables that hold secrets, which are
+/// read from their sources (prompting via `ui` if need be) only when the
+/// unattend files are rendered so that they never appear in the context.
+pub fn unattend_template_vars(
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<HashMap<String, String>> {
+    let mut vars = template_vars(ctx);
+    if let Some(name) = vars.get_mut("computer_name") {
+        *name =
+            crate::hostname::choose(name, crate::reproducible::enabled(ctx))?;
+        ui.record_metric("computer_name", Json::from(name.as_str()));
+    }
+
+    if let Some(source) = ctx.get_var("admin_password") {
+        let source: crate::secrets::SecretSource = source.parse()?;
+        let password = source.resolve("Administrator password", ui)?;
+        vars.insert(
+            "admin_password".to_string(),
+            crate::secrets::encode_unattend_password(
+                &password,
+                "AdministratorPassword",
+            ),
+        );
+    }
+
+    if let Some(users) = ctx.get_var(crate::users::USERS_VAR) {
+        vars.insert(
+            crate::users::LOCAL_ACCOUNTS_VAR.to_string(),
+            crate::users::local_accounts(users, ui)?,
+        );
+    }
+
+    Ok(vars)
+}
+
+/// The name of the PowerShell script, written to the unattend directory, that
+/// passes build options to `OxidePrepBaseImage.ps1`.
+pub const GUEST_SETTINGS_FILE: &str = "WimsySettings.ps1";
+
+/// Prints the cloudbase-init options in `sources` as part of a script's
+/// configuration.
+pub fn print_cloudbase_init_options(
+    w: &mut dyn std::io::Write,
+    sources: &ImageSources,
+) -> std::io::Result<()> {
+    let options = &sources.cloudbase_init;
+    if options.skip_cloudbase_init {
+        return writeln!(w, "  {}: skipped", "Cloudbase-init".bold());
+    }
+
+    if let Some(msi) = &options.cloudbase_init_msi {
+        writeln!(w, "  {}: {}", "Cloudbase-init installer".bold(), msi)?;
+    }
+
+    if let Some(url) = &options.cloudbase_init_url {
+        writeln!(w, "  {}: {}", "Cloudbase-init installer".bold(), url)?;
+    }
+
+    for setting in &options.cloudbase_init_settings {
+        writeln!(w, "  {}: {}", "Cloudbase-init setting".bold(), setting)?;
+    }
+
+    Ok(())
+}
+
+/// Prints the generalization and domain join options in `sources` as part of
+/// a script's configuration.
+pub fn print_guest_options(
+    w: &mut dyn std::io::Write,
+    sources: &ImageSources,
+) -> std::io::Result<()> {
+    if sources.skip_generalize {
+        writeln!(w, "  {}: skipped", "Generalization".bold())?;
+    }
+
+    if sources.zero_free_space {
+        writeln!(w, "  {}: zeroed at end of setup", "Free space".bold())?;
+    }
+
+    if sources.minimal_image {
+        writeln!(w, "  {}: minimal", "Image size".bold())?;
+    }
+
+    if let Some(headroom) = sources.shrink_headroom {
+        writeln!(w, "  {}: {}", "Shrink headroom".bold(), headroom)?;
+    }
+
+    if sources.expand_on_first_boot {
+        writeln!(w, "  {}: expanded on first boot", "OS partition".bold())?;
+    }
+
+    let regional = &sources.regional;
+    for (label, value) in [
+        ("Locale", &regional.locale),
+        ("Keyboard layout", &regional.keyboard),
+        ("Time zone", &regional.timezone),
+    ] {
+        if let Some(value) = value {
+            writeln!(w, "  {}: {value}", label.bold())?;
+        }
+    }
+
+    if let Some(key) = &sources.product_key {
+        let kms = match &sources.kms_host {
+            Some(host) => format!(" (KMS host {host})"),
+            None => String::new(),
+        };
+        writeln!(w, "  {}: {key}{kms}", "Product key".bold())?;
+    }
+
+    if let Some(source) = &sources.admin_password {
+        writeln!(w, "  {}: from {source}", "Administrator password".bold())?;
+    }
+    for user in &sources.local_users {
+        writeln!(
+            w,
+            "  {}: {} in {}, password from {}",
+            "Local user".bold(),
+            user.name,
+            user.groups.join(", "),
+            user.password
+        )?;
+    }
+
+    let network = &sources.network;
+    if let Some(ip) = network.static_ip {
+        let gateway = match network.gateway {
+            Some(gateway) => format!(" via {gateway}"),
+            None => String::new(),
+        };
+        writeln!(w, "  {}: static {ip}{gateway}", "Network".bold())?;
+    }
+
+    if !network.dns_servers.is_empty() {
+        let servers = itertools::join(&network.dns_servers, ", ");
+        writeln!(w, "  {}: {servers}", "DNS servers".bold())?;
+    }
+
+    if let Some(hostname) = &sources.hostname {
+        let note = if hostname.has_suffix() {
+            " (the build fills in the '*')"
+        } else {
+            ""
+        };
+        writeln!(w, "  {}: {hostname}{note}", "Computer name".bold())?;
+    }
+
+    if sources.skip_rdp {
+        writeln!(w, "  {}: disabled", "Remote Desktop".bold())?;
+    }
+
+    if let Some(path) = &sources.ssh_authorized_keys {
+        writeln!(w, "  {}: keys from {path}", "OpenSSH server".bold())?;
+    } else if sources.enable_ssh {
+        writeln!(w, "  {}: enabled", "OpenSSH server".bold())?;
+    }
+
+    let join = &sources.domain_join;
+    let when =
+        if sources.skip_generalize { "during setup" } else { "when deployed" };
+    if let Some(blob) = &join.djoin_blob {
+        writeln!(
+            w,
+            "  {}: {when}, offline, using {blob}",
+            "Domain join".bold()
+        )?;
+    } else if let (Some(domain), true) = (&join.join_domain, join.join_unsecure)
+    {
+        writeln!(
+            w,
+            "  {}: {when}, {domain} with a precreated computer account",
+            "Domain join".bold()
+        )?;
+    } else if let Some(domain) = &join.join_domain {
+        writeln!(
+            w,
+            "  {}: {when}, {domain} as {}{}",
+            "Domain join".bold(),
+            join.join_user.as_deref().unwrap_or("(no user)"),
+            join.join_ou
+                .as_ref()
+                .map(|ou| format!(" in {ou}"))
+                .unwrap_or_default()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns the Windows ISO download named by the `windows_iso_url` and
+/// `windows_iso_sha256` context variables, if they're set.
+fn windows_iso_download(ctx: &Context) -> Result<Option<Download>> {
+    let Some(url) = ctx.get_var("windows_iso_url") else {
+        return Ok(None);
+    };
+
+    let sha256 = ctx.get_var("windows_iso_sha256").unwrap_or_default();
+    Download::new(url, sha256).map(Some)
+}
+
+/// Downloads the Windows setup ISO the user asked for with `--iso-url`, or
+/// reuses the copy cached by an earlier build, and points the `windows_iso`
+/// variable at it.
+pub fn download_windows_iso(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(download) = windows_iso_download(ctx)? else {
+        return Ok(());
+    };
+
+    let work_dir = Utf8Path::new(ctx.get_var("work_dir").unwrap());
+    let iso = download.fetch(work_dir, ui)?;
+    ctx.set_var("windows_iso", iso.to_string());
+    Ok(())
+}
+
+pub fn describe_download_windows_iso(ctx: &mut Context) -> Vec<String> {
+    let download = match windows_iso_download(ctx) {
+        Ok(Some(download)) => download,
+        Ok(None) => return Vec::new(),
+        Err(e) => return vec![format!("invalid download: {e:#}")],
+    };
+
+    let work_dir = Utf8Path::new(ctx.get_var("work_dir").unwrap());
+    let lines = download.describe(work_dir);
+    ctx.set_var("windows_iso", download.cached_path(work_dir).to_string());
+    lines
+}
+
+/// Returns the Windows version in the `windows_version` context variable,
+/// which holds a [`WindowsVersion`] variant name.
+pub fn windows_version(ctx: &Context) -> Option<WindowsVersion> {
+    let version = ctx.get_var("windows_version")?;
+    <WindowsVersion as clap::ValueEnum>::from_str(version, true).ok()
+}
+
+/// Returns the architecture named by the `arch` context variable, or x86_64
+/// if it isn't set.
+pub fn architecture(ctx: &Context) -> Architecture {
+    ctx.get_var("arch")
+        .and_then(|arch| clap::ValueEnum::from_str(arch, true).ok())
+        .unwrap_or_default()
+}
+
+/// Looks up the edition named by `--edition` in the Windows ISO's image list
+/// and sets the `unattend_image_index` variable to its index. Does nothing if
+/// no edition was named (image indices passed to `--edition` are put in the
+/// variable directly).
+pub fn select_edition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(edition) = ctx.get_var("edition") else {
+        return Ok(());
+    };
+
+    let edition: crate::wim::Edition =
+        edition.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+    let iso = Utf8Path::new(ctx.get_var("windows_iso").unwrap());
+    ui.set_substep(&format!("reading image list from {iso}"));
+    let images = crate::wim::read_install_images(iso)?;
+    let image = edition.select(&images)?;
+    ui.set_substep(&format!(
+        "selected image {}: {}",
+        image.index,
+        image.title()
+    ));
+    trace::debug!(
+        "selected edition",
+        edition = edition.to_string(),
+        index = image.index
+    );
+    let index = image.index.to_string();
+    ctx.set_var("unattend_image_index", index);
+    Ok(())
+}
+
+pub fn describe_select_edition(ctx: &mut Context) -> Vec<String> {
+    let Some(edition) = ctx.get_var("edition") else {
+        return Vec::new();
+    };
+
+    vec![format!(
+        "look up edition {edition} in the image list in {}",
+        ctx.get_var("windows_iso").unwrap()
+    )]
+}
+
+/// Reads the metadata of the Windows image on the Windows ISO and sets the
+/// `windows_version`, `windows_build`, and `windows_edition` variables from
+/// the image Setup will install, so that unattend templates and driver paths
+/// match the ISO. A `--windows-version` the user supplied takes precedence
+/// over the detected version. Failing to read the metadata isn't fatal: the
+/// build goes on as if detection had been skipped.
+pub fn detect_windows_version(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let iso = ctx.get_var("windows_iso").unwrap().to_string();
+    ui.set_substep(&format!("reading image metadata from {iso}"));
+    let images = match crate::wim::read_install_images(Utf8Path::new(&iso)) {
+        Ok(images) => images,
+        Err(e) => {
+            ui.warn(&format!("can't detect the Windows version: {e:#}"));
+            return Ok(());
+        }
+    };
+
+    let index = ctx
+        .get_var("unattend_image_index")
+        .map(|index| index.parse::<u32>().unwrap());
+    let Some(image) = crate::wim::select_image(&images, index) else {
+        ui.warn(&format!(
+            "can't detect the Windows version: '{iso}' has no image {}",
+            index.unwrap_or(1)
+        ));
+        return Ok(());
+    };
+
+    let detected = image.windows_version();
+    trace::debug!(
+        "read Windows image metadata",
+        image = image.title(),
+        build = image.build.unwrap_or_default()
+    );
+    if let Some(build) = image.build {
+        ctx.set_var("windows_build", build.to_string());
+        ui.record_metric("windows_build", Json::from(build));
+    }
+
+    if let Some(edition) = &image.edition_id {
+        ctx.set_var("windows_edition", edition.clone());
+    }
+
+    match (windows_version(ctx), detected) {
+        (Some(requested), Some(detected)) if requested != detected => {
+            ui.warn(&format!(
+                "the Windows ISO contains {detected}, but --windows-version \
+                is {requested}; using {requested}"
+            ));
+        }
+        (Some(_), _) => {}
+        (None, Some(detected)) => {
+            ui.set_substep(&format!("detected {detected}"));
+            let size = ctx.get_var("disk_size").and_then(|s| s.parse().ok());
+            if let Some(error) = size.and_then(|size| {
+                check_disk_size(DiskSize(size), Some(detected))
+            }) {
+                anyhow::bail!(error);
+            }
+
+            ctx.set_var("windows_version", format!("{detected:?}"));
+        }
+        (None, None) => ui.warn(&format!(
+            "'{}' isn't a known Windows release (build {}); pass \
+            --windows-version if it needs other drivers than the default",
+            image.title(),
+            image.build.map_or("unknown".to_string(), |b| b.to_string())
+        )),
+    }
+
+    Ok(())
+}
+
+pub fn describe_detect_windows_version(ctx: &mut Context) -> Vec<String> {
+    let iso = ctx.get_var("windows_iso").unwrap();
+    if let Some(version) = windows_version(ctx) {
+        return vec![format!(
+            "check that {iso} contains {version}, as --windows-version says"
+        )];
+    }
+
+    vec![format!(
+        "read the Windows version from the Windows image metadata in {iso}"
+    )]
+}
+
+/// Writes [`GUEST_SETTINGS_FILE`] to the unattend directory if any of the
+/// build options that affect the guest's setup script are set.
+pub fn write_guest_settings(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let mut settings = Vec::new();
+    if ctx.get_var("skip_generalize").is_some() {
+        settings.push("$WimsySkipGeneralize = $true".to_string());
+    }
+
+    if windows_version(ctx).is_some_and(|version| version.is_client()) {
+        settings.push("$WimsyClient = $true".to_string());
+    }
+
+    // Generalized images don't join until they're deployed.
+    if (ctx.get_var("join_domain").is_some()
+        || ctx.get_var("djoin_blob").is_some())
+        && ctx.get_var("skip_generalize").is_some()
+    {
+        settings.push("$WimsyVerifyDomainJoin = $true".to_string());
+    }
+
+    if ctx.get_var("install_guest_agent").is_some() {
+        settings.push("$WimsyInstallGuestAgent = $true".to_string());
+    }
+
+    if ctx.get_var("zero_free_space").is_some() {
+        settings.push("$WimsyZeroFreeSpace = $true".to_string());
+    }
+
+    if ctx.get_var("minimal_image").is_some() {
+        settings.push("$WimsyMinimalImage = $true".to_string());
+    }
+
+    if let Some(headroom) = ctx.get_var("shrink_headroom") {
+        settings.push(format!("$WimsyShrinkHeadroom = {headroom}"));
+    }
+
+    if ctx.get_var("secure_boot").is_some() {
+        settings.push("$WimsyVerifySecureBoot = $true".to_string());
+    }
+
+    if ctx.get_var("expand_on_first_boot").is_some() {
+        settings.push("$WimsyExpandOnFirstBoot = $true".to_string());
+    }
+
+    if ctx.get_var("skip_cloudbase_init").is_some() {
+        settings.push("$WimsySkipCloudbaseInit = $true".to_string());
+    }
+
+    if ctx.get_var("skip_rdp").is_some() {
+        settings.push("$WimsySkipRdp = $true".to_string());
+    }
+
+    if ctx.get_var("enable_ssh").is_some() {
+        settings.push("$WimsyEnableSsh = $true".to_string());
+    }
+
+    if let Some(path) = ctx.get_var(crate::ssh::AUTHORIZED_KEYS_VAR) {
+        let keys = crate::ssh::load_authorized_keys(Utf8Path::new(path))?;
+        let keys: Vec<_> =
+            keys.iter().map(|key| crate::util::powershell_quote(key)).collect();
+        settings
+            .push(format!("$WimsySshAuthorizedKeys = @({})", keys.join(", ")));
+    }
+
+    for (var, setting) in [
+        (crate::features::WINDOWS_FEATURES_VAR, "WimsyWindowsFeatures"),
+        (crate::features::REMOVE_FEATURES_VAR, "WimsyRemoveFeatures"),
+    ] {
+        if let Some(features) = ctx.get_var(var) {
+            let names: Vec<_> =
+                features.lines().map(crate::util::powershell_quote).collect();
+            settings.push(format!("${setting} = @({})", names.join(", ")));
+        }
+    }
+
+    if let Some(url) = ctx.get_var("cloudbase_init_url") {
+        settings.push(format!(
+            "$WimsyCloudbaseInitUrl = {}",
+            crate::util::powershell_quote(url)
+        ));
+    }
+
+    if settings.is_empty() {
+        return Ok(());
+    }
+
+    let path = Utf8Path::new(ctx.get_var("unattend_dir").unwrap())
+        .join(GUEST_SETTINGS_FILE);
+    ui.set_substep(&format!("writing {path}"));
+    std::fs::write(
+        &path,
+        format!(
+            "# Generated by wimsy and dot-sourced by OxidePrepBaseImage.ps1.\r\n{}\r\n",
+            settings.join("\r\n")
+        ),
     )
-    .map(|_| ())
+    .with_context(|| format!("writing {path}"))
+}
+
+/// The unattend files that are answer files, whose configuration passes can
+/// be overridden individually.
+const ANSWER_FILES: [&str; 2] = ["Autounattend.xml", "specialize-unattend.xml"];
+
+/// Returns the name of the file in an overrides directory that replaces the
+/// settings for configuration pass `pass` in answer file `filename`, e.g.
+/// `Autounattend.oobeSystem.xml`.
+pub fn pass_override_name(filename: &str, pass: &str) -> String {
+    let stem = filename.strip_suffix(".xml").unwrap_or(filename);
+    format!("{stem}.{pass}.xml")
+}
+
+/// Returns the paths of the files in `overrides` that override answer file
+/// settings for individual configuration passes: for each, the answer file it
+/// overrides, the pass, and the file's path (which may be a template).
+pub fn pass_overrides(
+    overrides: &Utf8Path,
+) -> Vec<(&'static str, &'static str, Utf8PathBuf)> {
+    let mut found = Vec::new();
+    for filename in ANSWER_FILES {
+        for pass in crate::autounattend::PASSES {
+            let path = unattend_source_path(
+                overrides,
+                &pass_override_name(filename, pass),
+            );
+            if path.exists() {
+                found.push((filename, pass, path));
+            }
+        }
+    }
+
+    found
+}
+
+/// Returns the contents of the unattend file at `path`, rendering it using
+/// `vars` if it's a template.
+fn read_unattend_file(
+    path: &Utf8Path,
+    vars: &HashMap<String, String>,
+) -> Result<String> {
+    if path.as_str().ends_with(TEMPLATE_SUFFIX) {
+        crate::template::render_file(path, vars)
+    } else {
+        std::fs::read_to_string(path).with_context(|| format!("reading {path}"))
+    }
+}
+
+/// Copies each file in [`UNATTEND_FILES`] from `src_dir` to `dst_dir`. If
+/// `src_dir` contains a template for a file (i.e. a file with the same name
+/// plus [`TEMPLATE_SUFFIX`]), the template is rendered using `vars` and the
+/// output is written in the file's place. Files that are missing from
+/// `src_dir` are skipped.
+///
+/// If `overrides` is set, a file (or template) in that directory is used
+/// instead of the one in `src_dir`, and the settings for a single
+/// configuration pass in an answer file (e.g. `Autounattend.oobeSystem.xml`;
+/// see [`pass_override_name`]) replace that pass's settings in the copied
+/// answer file.
+///
+/// Any existing `dst_dir` is removed first so that files generated by a
+/// previous build (e.g. staged certificates) don't leak into this one.
+///
+/// If `rendered_dir` is set, the output of each rendered template is also
+/// written there (replacing anything a previous build left), so that it ends
+/// up with the build's logs.
+pub fn copy_unattend_files(
+    src_dir: &Utf8Path,
+    overrides: Option<&Utf8Path>,
+    dst_dir: &Utf8Path,
+    rendered_dir: Option<&Utf8Path>,
+    vars: &HashMap<String, String>,
+    ui: &dyn Ui,
+) -> Result<()> {
+    if dst_dir.exists() {
+        if dst_dir.canonicalize_utf8()? == src_dir.canonicalize_utf8()? {
+            anyhow::bail!(
+                "the unattend directory can't be the work directory's \
+                unattend subdirectory"
+            );
+        }
+
+        std::fs::remove_dir_all(dst_dir).with_context(|| {
+            format!(
+                "removing unattend files from previous build in '{dst_dir}'"
+            )
+        })?;
+    }
+
+    std::fs::create_dir_all(dst_dir)
+        .context("creating temporary directory for unattend files")?;
+
+    if let Some(dir) = rendered_dir.filter(|dir| dir.exists()) {
+        std::fs::remove_dir_all(dir).with_context(|| {
+            format!(
+                "removing rendered templates from previous build in '{dir}'"
+            )
+        })?;
+    }
+
+    for filename in UNATTEND_FILES {
+        let src_dir = overrides
+            .filter(|dir| unattend_source_path(dir, filename).exists())
+            .unwrap_or(src_dir);
+        let src = src_dir.join(filename);
+        let template = src_dir.join(format!("{filename}{TEMPLATE_SUFFIX}"));
+        let dst = dst_dir.join(filename);
+        if template.exists() {
+            ui.set_substep(&format!("rendering {filename}{TEMPLATE_SUFFIX}"));
+            let rendered = crate::template::render_file(&template, vars)?;
+            std::fs::write(&dst, &rendered)
+                .with_context(|| format!("writing rendered {filename}"))?;
+            if let Some(dir) = rendered_dir {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating '{dir}'"))?;
+                std::fs::write(dir.join(filename), &rendered).with_context(
+                    || format!("writing rendered {filename} to '{dir}'"),
+                )?;
+            }
+            continue;
+        }
+
+        ui.set_substep(&format!("copying {}", filename));
+        if let Err(e) = std::fs::copy(&src, &dst) {
+            match e.kind() {
+                std::io::ErrorKind::NotFound => {}
+                _ => {
+                    return Err(e)
+                        .with_context(|| format!("copying {}", filename))
+                }
+            }
+        }
+    }
+
+    let Some(overrides) = overrides else {
+        return Ok(());
+    };
+
+    for (filename, pass, path) in pass_overrides(overrides) {
+        let dst = dst_dir.join(filename);
+        if !dst.exists() {
+            anyhow::bail!(
+                "{path} overrides the {pass} pass in {filename}, but there's \
+                no {filename} to override"
+            );
+        }
+
+        ui.set_substep(&format!("replacing the {pass} pass in {filename}"));
+        let settings = read_unattend_file(&path, vars)?;
+        let merged = dst.with_extension("xml.merged");
+        crate::autounattend::AutounattendUpdater::new(None, None)
+            .with_pass(pass, &settings)
+            .with_context(|| format!("reading {path}"))?
+            .run(&dst, &merged)
+            .with_context(|| format!("merging {path} into {filename}"))?;
+        std::fs::rename(&merged, &dst)
+            .with_context(|| format!("renaming '{merged}' to '{dst}'"))?;
+        trace::debug!(
+            "replaced answer file pass",
+            file = filename,
+            pass = pass,
+            source = path.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::{testing::TestUi, RecordingRunner};
+
+    fn partitions(names: &[&str]) -> Vec<Partition> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Partition {
+                number: i as u32 + 1,
+                type_guid: Guid([1; 16]),
+                unique_guid: Guid([i as u8; 16]),
+                first_lba: 2048 * (i as u64 + 1),
+                last_lba: 2048 * (i as u64 + 2) - 1,
+                attributes: 0,
+                name: name.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_globs() {
+        for (pattern, name, expected) in [
+            ("Recovery", "Recovery", true),
+            ("Rec*", "Recovery", true),
+            ("*ry", "Recovery", true),
+            ("R?covery", "Recovery", true),
+            ("*c*v*", "Recovery", true),
+            ("*", "", true),
+            ("Rec", "Recovery", false),
+            ("?", "", false),
+            ("*x*", "Recovery", false),
+            ("Donn?es*", "Données de base", true),
+            ("回復*", "回復パーティション", true),
+            ("?", "🛟", true),
+        ] {
+            assert_eq!(
+                glob_matches(pattern, name),
+                expected,
+                "{pattern} {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn selects_partitions_by_name() {
+        let parts = partitions(&[
+            "EFI system partition",
+            "Microsoft reserved partition",
+            "Données de base",
+            "Récupération",
+            "Récupération",
+        ]);
+
+        let found = select_partition_by_name(
+            &parts,
+            NamePattern::Exact("Données de base"),
+            "disk.img",
+        )
+        .unwrap();
+        assert_eq!(found.number, 3);
+
+        // Exact patterns don't treat glob characters specially.
+        assert!(matches!(
+            select_partition_by_name(&parts, NamePattern::Exact("EFI*"), "d"),
+            Err(FindPartitionError::NotFound { .. })
+        ));
+        assert_eq!(
+            select_partition_by_name(&parts, NamePattern::Glob("EFI*"), "d")
+                .unwrap()
+                .number,
+            1
+        );
+
+        let err = select_partition_by_name(
+            &parts,
+            NamePattern::Glob("Récup*"),
+            "disk.img",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            FindPartitionError::Ambiguous { partitions, .. }
+                if partitions == &[4, 5]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "partition name glob 'Récup*' in 'disk.img' is ambiguous: \
+            matched partitions 4 and 5"
+        );
+
+        let err = select_partition_by_name(
+            &parts,
+            NamePattern::Glob("*"),
+            "disk.img",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("matched partitions 1, 2, 3, 4, and 5"));
+    }
+
+    #[test]
+    fn parses_sgdisk_output() {
+        let table = r#"Disk out.img: 62914560 sectors, 30.0 GiB
+Sector size (logical/physical): 512/4096 bytes
+Disk identifier (GUID): 0E2B6C3F-51A4-4C2C-9B1D-6E3F0C1A2B3C
+Partition table holds up to 128 entries
+Main partition table begins at sector 2 and ends at sector 33
+First usable sector is 34, last usable sector is 62914526
+Partitions will be aligned on 2048-sector boundaries
+Total free space is 4029 sectors (2.0 MiB)
+
+Number  Start (sector)    End (sector)  Size       Code  Name
+   1            2048         1026047   500.0 MiB   2700  Recovery
+   2         1026048         1558527   260.0 MiB   EF00  System
+   4         1820672        62912511   29.1 GiB    0700  Basic data partition
+"#;
+        let (sector_size, disk_guid, numbers) =
+            parse_sgdisk_table(table).unwrap();
+        assert_eq!(sector_size, 512);
+        assert_eq!(
+            disk_guid.to_string(),
+            "0E2B6C3F-51A4-4C2C-9B1D-6E3F0C1A2B3C"
+        );
+        assert_eq!(numbers, [1, 2, 4]);
+
+        let info = r#"Partition GUID code: EBD0A0A2-B9E5-4433-87C0-68B6B72699C7 (Microsoft basic data)
+Partition unique GUID: 6C1AE3A1-3F0B-4A8E-9D4C-1F2E3D4C5B6A
+First sector: 1820672 (at 889.0 MiB)
+Last sector: 62912511 (at 30.0 GiB)
+Partition size: 61091840 sectors (29.1 GiB)
+Attribute flags: 8000000000000001
+Partition name: 'Basic data partition'
+"#;
+        let partition = parse_sgdisk_partition(4, info).unwrap();
+        assert_eq!(partition.type_guid, Guid::BASIC_DATA);
+        assert_eq!(partition.first_lba, 1820672);
+        assert_eq!(partition.last_lba, 62912511);
+        assert_eq!(partition.attributes, 0x8000000000000001);
+        assert_eq!(partition.name, "Basic data partition");
+
+        let err = parse_sgdisk_partition(4, "First sector: 2048\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no 'Attribute flags:' line"), "{err}");
+    }
+
+    #[test]
+    fn shrinks_without_shrink_flag_if_qemu_img_lacks_it() {
+        let runner =
+            RecordingRunner::new().respond("qemu-img resize --shrink", 1, "");
+        let ui = TestUi { runner: &runner };
+        shrink_output_image("out.img", "raw", "512", "2047", &ui).unwrap();
+
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].starts_with("qemu-img resize --shrink -f raw"));
+        assert!(commands[1].starts_with("qemu-img resize -f raw out.img"));
+
+        let runner = RecordingRunner::new().respond("qemu-img", 1, "");
+        let ui = TestUi { runner: &runner };
+        assert!(
+            shrink_output_image("out.img", "raw", "512", "2047", &ui).is_err()
+        );
+        assert_eq!(runner.commands().len(), 2);
+    }
+
+    #[test]
+    fn reads_virtual_size_from_qemu_img_info() {
+        let runner = RecordingRunner::new().respond(
+            "qemu-img info",
+            0,
+            r#"{"virtual-size": 32212254720, "format": "qcow2"}"#,
+        );
+        let ui = TestUi { runner: &runner };
+        assert_eq!(
+            get_image_virtual_size("out.qcow2", "qcow2", &ui).unwrap(),
+            32212254720
+        );
+        assert_eq!(
+            runner.commands(),
+            ["qemu-img info --output=json -f qcow2 out.qcow2"]
+        );
+
+        let runner = RecordingRunner::new().respond("qemu-img info", 0, "{}");
+        let ui = TestUi { runner: &runner };
+        let err = get_image_virtual_size("out.qcow2", "qcow2", &ui)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("has no virtual size"), "{err}");
+    }
+
+    #[test]
+    fn keeps_rendered_templates_with_logs() {
+        let root = Utf8PathBuf::try_from(
+            std::env::temp_dir().join("wimsy-rendered-test"),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+        let (src, dst, rendered) =
+            (root.join("src"), root.join("dst"), root.join("rendered"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&rendered).unwrap();
+        std::fs::write(rendered.join("stale.xml"), "old").unwrap();
+        std::fs::write(src.join("prep.cmd"), "echo prep").unwrap();
+        std::fs::write(
+            src.join(format!("Autounattend.xml{TEMPLATE_SUFFIX}")),
+            "<name>{{ computer_name }}</name>",
+        )
+        .unwrap();
+
+        let runner = RecordingRunner::new();
+        let ui = TestUi { runner: &runner };
+        let vars =
+            HashMap::from([("computer_name".to_string(), "web".to_string())]);
+        copy_unattend_files(&src, None, &dst, Some(&rendered), &vars, &ui)
+            .unwrap();
+
+        let rendered_xml =
+            std::fs::read_to_string(rendered.join("Autounattend.xml")).unwrap();
+        assert_eq!(rendered_xml, "<name>web</name>");
+        assert_eq!(
+            std::fs::read_to_string(dst.join("Autounattend.xml")).unwrap(),
+            rendered_xml
+        );
+        assert!(dst.join("prep.cmd").exists());
+        assert!(!rendered.join("prep.cmd").exists());
+        assert!(!rendered.join("stale.xml").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,630 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small template engine for rendering user-supplied answer files, in-guest
+//! scripts, and cloudbase-init configuration files.
+//!
+//! The syntax is a subset of Jinja's:
+//!
+//! - `{{ name }}` substitutes the value of the variable `name`. Values can be
+//!   passed through filters: `{{ name | default("x") }}`, `{{ name | lower }}`,
+//!   `{{ name | upper }}`, and `{{ name | xml_escape }}`.
+//! - `{% if cond %}`, `{% elif cond %}`, `{% else %}`, and `{% endif %}`
+//!   conditionally include blocks of text. A condition is a series of terms
+//!   joined with `and` or `or`, where each term is `name`, `defined name`,
+//!   `name == "literal"`, or `name != "literal"`, optionally preceded by `not`.
+//! - `{# ... #}` is a comment and produces no output.
+//!
+//! All variables are strings. In a condition, a variable is false if it is
+//! empty, `"false"`, or `"0"` and true otherwise.
+//!
+//! References to undefined variables are errors, except as the operand of
+//! `defined` or when the reference is passed through the `default` filter.
+//! This keeps a typo in a template from silently producing an answer file that
+//! is missing a setting.
+//!
+//! If a block tag (`{% ... %}` or `{# ... #}`) is the only thing on its line,
+//! the entire line, including its line break, is removed from the output.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+/// The file name suffix that marks a file in an unattend directory as a
+/// template. `Autounattend.xml.j2` renders to `Autounattend.xml`.
+pub const TEMPLATE_SUFFIX: &str = ".j2";
+
+/// The prefix of the context variables that hold [`UserVar`]s. The prefix
+/// keeps user variables from being mistaken for the variables that control
+/// the build's steps.
+pub const USER_VAR_PREFIX: &str = "template_var.";
+
+/// A template variable defined by the user with `--template-var` or in the
+/// configuration file's `[template-vars]` table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserVar {
+    pub name: String,
+    pub value: String,
+}
+
+impl UserVar {
+    pub fn new(name: &str, value: &str) -> Result<Self, String> {
+        if !is_identifier(name) {
+            return Err(format!(
+                "'{name}' isn't a valid template variable name (use letters, \
+                digits, and underscores)"
+            ));
+        }
+
+        Ok(Self { name: name.to_string(), value: value.to_string() })
+    }
+}
+
+impl std::str::FromStr for UserVar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, value)) = s.split_once('=') else {
+            return Err(format!("'{s}' should look like NAME=VALUE"));
+        };
+
+        Self::new(name.trim(), value)
+    }
+}
+
+/// Renders the template in `source` using the variables in `vars`.
+pub fn render(source: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let tokens = tokenize(source)?;
+    let mut iter = tokens.into_iter().peekable();
+    let nodes = parse_nodes(&mut iter, &[])?;
+    if let Some(token) = iter.next() {
+        anyhow::bail!("line {}: unexpected '{}'", token.line, token.describe());
+    }
+
+    let mut out = String::with_capacity(source.len());
+    render_nodes(&nodes, vars, &mut out)?;
+    Ok(out)
+}
+
+/// Renders the template at `path` using the variables in `vars`.
+pub fn render_file(
+    path: impl AsRef<std::path::Path>,
+    vars: &HashMap<String, String>,
+) -> Result<String> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("reading template {}", path.display()))?;
+
+    render(&source, vars)
+        .with_context(|| format!("rendering template {}", path.display()))
+}
+
+#[derive(Debug)]
+enum TokenKind {
+    Text(String),
+    Expr(String),
+    Block(String),
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Text(_) => "text".to_string(),
+            TokenKind::Expr(e) => format!("{{{{ {e} }}}}"),
+            TokenKind::Block(b) => format!("{{% {b} %}}"),
+        }
+    }
+}
+
+/// Splits `source` into text, expression, and block tokens, applying the
+/// standalone-line trimming rule described in the module docs.
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut line = 1;
+    let mut pending_text = String::new();
+
+    while !rest.is_empty() {
+        let next_tag = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open).map(|pos| (pos, *open)))
+            .min_by_key(|(pos, _)| *pos);
+
+        let Some((pos, open)) = next_tag else {
+            pending_text.push_str(rest);
+            break;
+        };
+
+        pending_text.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+
+        let tag_line = line + pending_text.matches('\n').count();
+        let end = rest.find(close).ok_or_else(|| {
+            anyhow::anyhow!("line {tag_line}: unterminated '{open}' tag")
+        })?;
+
+        let inner = rest[open.len()..end].trim().to_string();
+        rest = &rest[end + close.len()..];
+
+        // Block tags and comments that sit alone on their lines consume the
+        // whole line.
+        let mut trimmed_newlines = 0;
+        if open != "{{" {
+            let line_start = pending_text.rfind('\n').map_or(0, |p| p + 1);
+            let before_blank = pending_text[line_start..]
+                .chars()
+                .all(|c| c == ' ' || c == '\t');
+            let after_len =
+                rest.find('\n').map(|p| p + 1).unwrap_or(rest.len());
+            let after_blank = rest[..after_len]
+                .trim_end_matches(['\r', '\n'])
+                .trim()
+                .is_empty();
+            if before_blank && after_blank {
+                pending_text.truncate(line_start);
+                trimmed_newlines = rest[..after_len].matches('\n').count();
+                rest = &rest[after_len..];
+            }
+        }
+
+        if !pending_text.is_empty() {
+            line += pending_text.matches('\n').count();
+            tokens.push(Token {
+                kind: TokenKind::Text(std::mem::take(&mut pending_text)),
+                line,
+            });
+        }
+
+        line += inner.matches('\n').count();
+        match open {
+            "{{" => tokens.push(Token { kind: TokenKind::Expr(inner), line }),
+            "{%" => tokens.push(Token { kind: TokenKind::Block(inner), line }),
+            _ => {}
+        }
+
+        line += trimmed_newlines;
+    }
+
+    if !pending_text.is_empty() {
+        tokens.push(Token { kind: TokenKind::Text(pending_text), line });
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Filter {
+    Default(String),
+    Lower,
+    Upper,
+    XmlEscape,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Expr { var: String, filters: Vec<Filter>, line: usize },
+    If { branches: Vec<(Condition, Vec<Node>)>, otherwise: Vec<Node> },
+}
+
+#[derive(Debug)]
+enum Term {
+    Truthy(String),
+    Defined(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+#[derive(Debug)]
+struct Condition {
+    /// A list of alternatives (joined by `or`), each of which is a list of
+    /// possibly-negated terms joined by `and`.
+    any_of: Vec<Vec<(bool, Term)>>,
+    line: usize,
+}
+
+type TokenIter = std::iter::Peekable<std::vec::IntoIter<Token>>;
+
+/// Parses nodes until reaching a block tag whose keyword is in `terminators`,
+/// which is left in the iterator for the caller to consume.
+fn parse_nodes(
+    iter: &mut TokenIter,
+    terminators: &[&str],
+) -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    while let Some(token) = iter.peek() {
+        if let TokenKind::Block(b) = &token.kind {
+            let keyword = b.split_whitespace().next().unwrap_or("");
+            if terminators.contains(&keyword) {
+                break;
+            }
+        }
+
+        let token = iter.next().unwrap();
+        match token.kind {
+            TokenKind::Text(t) => nodes.push(Node::Text(t)),
+            TokenKind::Expr(e) => nodes.push(parse_expr(&e, token.line)?),
+            TokenKind::Block(b) => {
+                let (keyword, rest) =
+                    b.split_once(char::is_whitespace).unwrap_or((&b, ""));
+                match keyword {
+                    "if" => nodes.push(parse_if(iter, rest, token.line)?),
+                    _ => anyhow::bail!(
+                        "line {}: unexpected block tag '{}'",
+                        token.line,
+                        keyword
+                    ),
+                }
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+fn parse_if(iter: &mut TokenIter, cond: &str, line: usize) -> Result<Node> {
+    let mut branches = vec![];
+    let mut cond = parse_condition(cond, line)?;
+    loop {
+        let body = parse_nodes(iter, &["elif", "else", "endif"])?;
+        let Some(token) = iter.next() else {
+            anyhow::bail!("line {line}: 'if' block is missing its 'endif'");
+        };
+
+        let TokenKind::Block(b) = token.kind else { unreachable!() };
+        let (keyword, rest) =
+            b.split_once(char::is_whitespace).unwrap_or((&b, ""));
+        branches.push((cond, body));
+        match keyword {
+            "elif" => cond = parse_condition(rest, token.line)?,
+            "else" => {
+                let otherwise = parse_nodes(iter, &["endif"])?;
+                if iter.next().is_none() {
+                    anyhow::bail!(
+                        "line {line}: 'if' block is missing its 'endif'"
+                    );
+                }
+                return Ok(Node::If { branches, otherwise });
+            }
+            _ => return Ok(Node::If { branches, otherwise: vec![] }),
+        }
+    }
+}
+
+fn parse_expr(expr: &str, line: usize) -> Result<Node> {
+    let mut parts = expr.split('|').map(str::trim);
+    let var = parts.next().unwrap_or("");
+    check_identifier(var, line)?;
+
+    let mut filters = Vec::new();
+    for filter in parts {
+        filters.push(match filter {
+            "lower" => Filter::Lower,
+            "upper" => Filter::Upper,
+            "xml_escape" => Filter::XmlEscape,
+            f if f.starts_with("default(") && f.ends_with(')') => {
+                let arg = f["default(".len()..f.len() - 1].trim();
+                Filter::Default(parse_string_literal(arg, line)?)
+            }
+            f => anyhow::bail!("line {line}: unknown filter '{f}'"),
+        });
+    }
+
+    Ok(Node::Expr { var: var.to_string(), filters, line })
+}
+
+fn parse_condition(cond: &str, line: usize) -> Result<Condition> {
+    let words = split_condition_words(cond, line)?;
+    if words.is_empty() {
+        anyhow::bail!("line {line}: empty condition");
+    }
+
+    let mut any_of = vec![];
+    let mut all_of = vec![];
+    let mut words = words.into_iter().peekable();
+    loop {
+        let mut negated = false;
+        while words.peek().map(String::as_str) == Some("not") {
+            words.next();
+            negated = !negated;
+        }
+
+        let Some(first) = words.next() else {
+            anyhow::bail!("line {line}: condition ends unexpectedly");
+        };
+
+        let term = if first == "defined" {
+            let var = words.next().ok_or_else(|| {
+                anyhow::anyhow!("line {line}: 'defined' needs a variable name")
+            })?;
+            check_identifier(&var, line)?;
+            Term::Defined(var)
+        } else {
+            check_identifier(&first, line)?;
+            match words.peek().map(String::as_str) {
+                Some("==") | Some("!=") => {
+                    let op = words.next().unwrap();
+                    let lit = words.next().ok_or_else(|| {
+                        anyhow::anyhow!("line {line}: '{op}' needs a value")
+                    })?;
+                    let lit = parse_string_literal(&lit, line)?;
+                    if op == "==" {
+                        Term::Equals(first, lit)
+                    } else {
+                        Term::NotEquals(first, lit)
+                    }
+                }
+                _ => Term::Truthy(first),
+            }
+        };
+
+        all_of.push((negated, term));
+        match words.next().as_deref() {
+            None => break,
+            Some("and") => {}
+            Some("or") => any_of.push(std::mem::take(&mut all_of)),
+            Some(w) => {
+                anyhow::bail!("line {line}: unexpected '{w}' in condition")
+            }
+        }
+    }
+
+    any_of.push(all_of);
+    Ok(Condition { any_of, line })
+}
+
+/// Splits a condition into words, keeping quoted string literals (including
+/// their quotes) together.
+fn split_condition_words(cond: &str, line: usize) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut chars = cond.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            let mut word = String::new();
+            word.push(chars.next().unwrap());
+            loop {
+                match chars.next() {
+                    Some(ch) if ch == c => {
+                        word.push(ch);
+                        break;
+                    }
+                    Some(ch) => word.push(ch),
+                    None => anyhow::bail!(
+                        "line {line}: unterminated string literal"
+                    ),
+                }
+            }
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() || ch == '"' || ch == '\'' {
+                    break;
+                }
+                word.push(ch);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+
+    Ok(words)
+}
+
+fn parse_string_literal(lit: &str, line: usize) -> Result<String> {
+    let quoted = lit.len() >= 2
+        && ((lit.starts_with('"') && lit.ends_with('"'))
+            || (lit.starts_with('\'') && lit.ends_with('\'')));
+    if !quoted {
+        anyhow::bail!("line {line}: expected a quoted string, found '{lit}'");
+    }
+
+    Ok(lit[1..lit.len() - 1].to_string())
+}
+
+/// Returns whether `name` can be used as a variable name in templates.
+pub fn is_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn check_identifier(name: &str, line: usize) -> Result<()> {
+    if !is_identifier(name) {
+        anyhow::bail!("line {line}: '{name}' is not a valid variable name");
+    }
+
+    Ok(())
+}
+
+fn lookup<'a>(
+    vars: &'a HashMap<String, String>,
+    var: &str,
+    line: usize,
+) -> Result<&'a str> {
+    vars.get(var).map(String::as_str).ok_or_else(|| {
+        anyhow::anyhow!("line {line}: undefined variable '{var}'")
+    })
+}
+
+fn is_truthy(value: &str) -> bool {
+    !matches!(value, "" | "false" | "0")
+}
+
+impl Condition {
+    fn evaluate(&self, vars: &HashMap<String, String>) -> Result<bool> {
+        for all_of in &self.any_of {
+            let mut all_true = true;
+            for (negated, term) in all_of {
+                let value = match term {
+                    Term::Defined(var) => vars.contains_key(var),
+                    Term::Truthy(var) => {
+                        is_truthy(lookup(vars, var, self.line)?)
+                    }
+                    Term::Equals(var, lit) => {
+                        lookup(vars, var, self.line)? == lit
+                    }
+                    Term::NotEquals(var, lit) => {
+                        lookup(vars, var, self.line)? != lit
+                    }
+                };
+
+                if value == *negated {
+                    all_true = false;
+                    break;
+                }
+            }
+
+            if all_true {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    vars: &HashMap<String, String>,
+    out: &mut String,
+) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Expr { var, filters, line } => {
+                let default = filters.iter().find_map(|f| match f {
+                    Filter::Default(d) => Some(d.as_str()),
+                    _ => None,
+                });
+
+                let mut value = match (vars.get(var), default) {
+                    (Some(v), _) => v.clone(),
+                    (None, Some(d)) => d.to_string(),
+                    (None, None) => {
+                        anyhow::bail!("line {line}: undefined variable '{var}'")
+                    }
+                };
+
+                for filter in filters {
+                    value = match filter {
+                        Filter::Default(_) => value,
+                        Filter::Lower => value.to_lowercase(),
+                        Filter::Upper => value.to_uppercase(),
+                        Filter::XmlEscape => xml_escape(&value),
+                    };
+                }
+
+                out.push_str(&value);
+            }
+            Node::If { branches, otherwise } => {
+                let mut taken = false;
+                for (cond, body) in branches {
+                    if cond.evaluate(vars)? {
+                        render_nodes(body, vars, out)?;
+                        taken = true;
+                        break;
+                    }
+                }
+
+                if !taken {
+                    render_nodes(otherwise, vars, out)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Escapes the characters that may not appear literally in XML character data
+/// or attribute values.
+pub fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn substitutes_and_filters() {
+        let vars = vars(&[("name", "Server"), ("amp", "a&b")]);
+        let out = render(
+            "{{ name }} {{name|upper}} {{ missing | default('x') }} {{ amp | xml_escape }}",
+            &vars,
+        )
+        .unwrap();
+        assert_eq!(out, "Server SERVER x a&amp;b");
+    }
+
+    #[test]
+    fn undefined_variable_is_an_error() {
+        let err = render("a\nb {{ nope }}", &vars(&[])).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+        assert!(err.to_string().contains("nope"), "{err}");
+
+        assert!(render("{% if nope %}x{% endif %}", &vars(&[])).is_err());
+    }
+
+    #[test]
+    fn conditionals_trim_standalone_lines() {
+        let template = "\
+<a>
+  {% if rdp %}
+  <rdp/>
+  {% elif defined ssh and ssh == \"on\" %}
+  <ssh/>
+  {% else %}
+  <none/>
+  {% endif %}
+</a>
+";
+        let out = render(template, &vars(&[("rdp", "true")])).unwrap();
+        assert_eq!(out, "<a>\n  <rdp/>\n</a>\n");
+
+        let out = render(template, &vars(&[("rdp", "false"), ("ssh", "on")]))
+            .unwrap();
+        assert_eq!(out, "<a>\n  <ssh/>\n</a>\n");
+
+        let out = render(template, &vars(&[("rdp", "0")])).unwrap();
+        assert_eq!(out, "<a>\n  <none/>\n</a>\n");
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        assert!(render("{% if a %}x", &vars(&[("a", "1")])).is_err());
+        assert!(render("x{% endif %}", &vars(&[])).is_err());
+        assert!(render("{{ a ", &vars(&[("a", "1")])).is_err());
+    }
+}
@@ -0,0 +1,638 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Structured diagnostic traces for maintainers.
+//!
+//! The [`Ui`](crate::ui::Ui) is the curated channel for telling users what a
+//! build is doing. Traces are the firehose: each step and external command
+//! runs in a span, and code emits events at interesting decision points. The
+//! `RUST_LOG` environment variable selects which events to record using the
+//! same directive syntax as `env_logger` and `tracing-subscriber` (e.g.
+//! `debug` or `info,wimsy::monitor=trace`). Events are written as JSON lines
+//! to [`TRACE_FILE_NAME`] in the work directory's logs directory, and as
+//! plain text to [`BUILD_LOG_NAME`] beside it. `-v` (or `-vv`, or `-vvv`)
+//! also writes `info` (or `debug`, or `trace`) events to stderr as they
+//! happen, whatever `RUST_LOG` says.
+//!
+//! Events are emitted with the [`info!`], [`debug!`], and [`trace!`] macros
+//! (or [`event!`] with an explicit [`Level`]), which take a format string
+//! followed by any number of `key = value` fields, where each value converts
+//! into [`Json`]. Spans are entered with [`span!`] and last until the returned
+//! guard is dropped.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::Write,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::json::Json;
+
+/// The name of the file, in the work directory, to which traces are written.
+pub const TRACE_FILE_NAME: &str = "trace.jsonl";
+
+/// The name of the file, beside the trace file, to which traces are written
+/// as plain text.
+pub const BUILD_LOG_NAME: &str = "build.log";
+
+/// The environment variable that selects which events to record.
+pub const FILTER_ENV_VAR: &str = "RUST_LOG";
+
+/// The filter to use if [`FILTER_ENV_VAR`] isn't set.
+const DEFAULT_FILTER: &str = "info";
+
+/// The verbosity of an event or span, from least to most verbose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => anyhow::bail!("unknown level '{s}'"),
+        }
+    }
+}
+
+/// A single `RUST_LOG` directive. A `max_level` of `None` turns off all events
+/// for the target.
+#[derive(Debug, PartialEq, Eq)]
+struct Directive {
+    target: Option<String>,
+    max_level: Option<Level>,
+}
+
+impl Directive {
+    fn matches(&self, target: &str) -> bool {
+        match &self.target {
+            None => true,
+            Some(prefix) => match target.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Decides which events to record based on their levels and targets (the
+/// paths of the modules that emit them).
+#[derive(Debug)]
+pub struct Filter {
+    /// The directives to apply, most specific first.
+    directives: Vec<Directive>,
+}
+
+impl Filter {
+    /// Parses a comma-separated list of directives of the form `level`,
+    /// `target=level`, or `target` (which enables all events for the target).
+    /// Returns the filter and a message describing each invalid directive,
+    /// which are ignored.
+    pub fn parse(spec: &str) -> (Self, Vec<String>) {
+        let mut directives = Vec::new();
+        let mut invalid = Vec::new();
+        for directive in spec.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            let (target, level) = match directive.split_once('=') {
+                Some((target, level)) => (Some(target.trim()), level.trim()),
+                None if directive.parse::<Level>().is_ok()
+                    || directive.eq_ignore_ascii_case("off") =>
+                {
+                    (None, directive)
+                }
+                None => (Some(directive), "trace"),
+            };
+
+            let max_level = if level.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                match level.parse() {
+                    Ok(level) => Some(level),
+                    Err(e) => {
+                        invalid.push(format!("'{directive}': {e}"));
+                        continue;
+                    }
+                }
+            };
+
+            directives.push(Directive {
+                target: target.map(str::to_string),
+                max_level,
+            });
+        }
+
+        // Later directives for the same target override earlier ones, and
+        // longer targets are more specific than shorter ones.
+        directives.reverse();
+        directives.sort_by_key(|d| {
+            std::cmp::Reverse(d.target.as_ref().map_or(0, |t| t.len() + 1))
+        });
+        (Self { directives }, invalid)
+    }
+
+    /// Returns `true` if an event at `level` from `target` should be
+    /// recorded.
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        match self.directives.iter().find(|d| d.matches(target)) {
+            Some(directive) => {
+                directive.max_level.is_some_and(|max| level <= max)
+            }
+            None => false,
+        }
+    }
+}
+
+/// The destination for trace events.
+struct Tracer {
+    filter: Filter,
+    path: Utf8PathBuf,
+    file: Mutex<File>,
+    build_log: Mutex<File>,
+
+    /// The most verbose events to write to stderr, if any are.
+    console: Option<Level>,
+}
+
+/// Returns the most verbose events that passing `-v` `verbosity` times
+/// writes to stderr.
+fn console_level(verbosity: u8) -> Option<Level> {
+    match verbosity {
+        0 => None,
+        1 => Some(Level::Info),
+        2 => Some(Level::Debug),
+        _ => Some(Level::Trace),
+    }
+}
+
+/// The tracer of the build running in this process, if it's tracing.
+static TRACER: RwLock<Option<Arc<Tracer>>> = RwLock::new(None);
+
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Starts writing traces to [`TRACE_FILE_NAME`] and [`BUILD_LOG_NAME`] in
+/// `logs_dir`, using the filter in `RUST_LOG`, and to stderr at the given
+/// `-v` `verbosity`, until the returned guard is dropped. Events emitted
+/// while no build is tracing, or if this fails, are discarded. Only one build
+/// in a process can trace at a time.
+pub fn init(logs_dir: &Utf8Path, verbosity: u8) -> Result<TraceGuard> {
+    let spec = std::env::var(FILTER_ENV_VAR)
+        .unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let (filter, invalid) = Filter::parse(&spec);
+    for message in invalid {
+        eprintln!("Ignoring invalid {FILTER_ENV_VAR} directive {message}");
+    }
+
+    // Check for another build's tracer before truncating this build's files,
+    // and hold the lock until this one is installed.
+    let mut current = TRACER.write().unwrap();
+    if current.is_some() {
+        anyhow::bail!("another build in this process is already tracing");
+    }
+
+    let path = logs_dir.join(TRACE_FILE_NAME);
+    let file = File::create(&path)
+        .with_context(|| format!("creating trace file '{path}'"))?;
+    let build_log_path = logs_dir.join(BUILD_LOG_NAME);
+    let build_log = File::create(&build_log_path)
+        .with_context(|| format!("creating '{build_log_path}'"))?;
+    let tracer = Arc::new(Tracer {
+        filter,
+        path,
+        file: Mutex::new(file),
+        build_log: Mutex::new(build_log),
+        console: console_level(verbosity),
+    });
+    *current = Some(tracer.clone());
+    drop(current);
+
+    info!(
+        "tracing started",
+        filter = spec,
+        version = env!("CARGO_PKG_VERSION")
+    );
+    Ok(TraceGuard { tracer })
+}
+
+/// A guard representing a build's tracing, which stops when the guard is
+/// dropped.
+#[must_use = "tracing stops as soon as the guard is dropped"]
+pub struct TraceGuard {
+    tracer: Arc<Tracer>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let mut current = TRACER.write().unwrap();
+        if current.as_ref().is_some_and(|t| Arc::ptr_eq(t, &self.tracer)) {
+            *current = None;
+        }
+    }
+}
+
+/// Returns the tracer of the build that's tracing, if any is.
+fn tracer() -> Option<Arc<Tracer>> {
+    TRACER.read().unwrap().clone()
+}
+
+/// Returns the path to the trace file, if a build is tracing.
+pub fn path() -> Option<Utf8PathBuf> {
+    tracer().map(|t| t.path.clone())
+}
+
+/// Returns `true` if an event at `level` from `target` would be recorded.
+pub fn enabled(level: Level, target: &str) -> bool {
+    tracer().is_some_and(|t| {
+        t.console.is_some_and(|max| level <= max)
+            || t.filter.enabled(level, target)
+    })
+}
+
+/// Information about an entered span, shared by the threads that run in it.
+struct SpanInfo {
+    id: u64,
+    name: &'static str,
+    fields: Vec<(&'static str, Json)>,
+}
+
+impl SpanInfo {
+    fn to_json(&self) -> Json {
+        let mut json =
+            Json::object().with("id", self.id).with("name", self.name);
+        for (key, value) in &self.fields {
+            json.insert(key, value.clone());
+        }
+        json
+    }
+
+    /// Formats the span as plain text, like `step{step=install number=4}`.
+    fn to_text(&self) -> String {
+        if self.fields.is_empty() {
+            return self.name.to_string();
+        }
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{key}={}", text_value(value)))
+            .collect();
+        format!("{}{{{}}}", self.name, fields.join(" "))
+    }
+}
+
+/// Formats a field's value as plain text: strings without quotes unless they
+/// need them, and everything else as JSON.
+fn text_value(value: &Json) -> String {
+    match value.as_str() {
+        Some(s)
+            if !s.is_empty()
+                && !s.contains(|c: char| c.is_whitespace() || c == '"') =>
+        {
+            s.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Formats a Unix timestamp as a UTC time of day, like `13:04:05.250`.
+fn time_of_day(timestamp: f64) -> String {
+    let millis = (timestamp * 1000.0) as u64 % (24 * 60 * 60 * 1000);
+    let secs = millis / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        millis % 1000
+    )
+}
+
+/// Formats an event as a line of plain text, like `13:04:05.250  INFO
+/// step{step=install number=4}: running step attempt=1`.
+fn text_line(
+    timestamp: f64,
+    level: Level,
+    spans: &[Arc<SpanInfo>],
+    message: &str,
+    fields: &[(&'static str, Json)],
+) -> String {
+    let mut line = format!("{} {:>5} ", time_of_day(timestamp), level.as_str());
+    let spans: Vec<String> = spans.iter().map(|span| span.to_text()).collect();
+    if !spans.is_empty() {
+        line.push_str(&spans.join(":"));
+        line.push_str(": ");
+    }
+    line.push_str(message);
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={}", text_value(value)));
+    }
+    line.push('\n');
+    line
+}
+
+thread_local! {
+    /// The spans the current thread is running in, outermost first.
+    static SPANS: RefCell<Vec<Arc<SpanInfo>>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Writes an event to the trace file. Callers should use the event macros
+/// instead of calling this directly.
+pub fn record(
+    level: Level,
+    target: &str,
+    message: String,
+    fields: Vec<(&'static str, Json)>,
+) {
+    let Some(tracer) = tracer() else {
+        return;
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let spans = SPANS.with(|spans| spans.borrow().clone());
+    let text = text_line(timestamp, level, &spans, &message, &fields);
+    if tracer.console.is_some_and(|max| level <= max) {
+        eprint!("{text}");
+    }
+    if !tracer.filter.enabled(level, target) {
+        return;
+    }
+
+    let thread = std::thread::current();
+    let mut event_fields = Json::object().with("message", message);
+    for (key, value) in fields {
+        event_fields.insert(key, value);
+    }
+
+    let spans: Vec<Json> = spans.iter().map(|span| span.to_json()).collect();
+    let event = Json::object()
+        .with("timestamp", (timestamp * 1000.0).round() / 1000.0)
+        .with("level", level.as_str())
+        .with("target", target)
+        .with("thread", thread.name().unwrap_or("unnamed"))
+        .with("spans", spans)
+        .with("fields", event_fields);
+
+    // Tracing is best-effort: if the trace files can't be written, there's
+    // nowhere better to report that.
+    let line = format!("{event}\n");
+    let _ = tracer.file.lock().unwrap().write_all(line.as_bytes());
+    let _ = tracer.build_log.lock().unwrap().write_all(text.as_bytes());
+}
+
+/// A guard representing an entered span. The span is exited when the guard
+/// is dropped.
+#[must_use = "the span is exited as soon as the guard is dropped"]
+pub struct Span {
+    id: u64,
+    level: Level,
+    target: &'static str,
+    name: &'static str,
+    start: Instant,
+
+    /// Spans are tracked per thread, so the guard must be dropped on the
+    /// thread that entered it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Span {
+    /// Enters a span. Callers should use [`span!`] instead of calling this
+    /// directly.
+    pub fn enter(
+        level: Level,
+        target: &'static str,
+        name: &'static str,
+        fields: Vec<(&'static str, Json)>,
+    ) -> Self {
+        let id = NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed);
+        SPANS.with(|spans| {
+            spans.borrow_mut().push(Arc::new(SpanInfo { id, name, fields }))
+        });
+
+        if enabled(level, target) {
+            record(level, target, "enter".to_string(), Vec::new());
+        }
+
+        Self {
+            id,
+            level,
+            target,
+            name,
+            start: Instant::now(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if enabled(self.level, self.target) {
+            let elapsed_ms = self.start.elapsed().as_millis() as u64;
+            record(
+                self.level,
+                self.target,
+                "close".to_string(),
+                vec![("elapsed_ms", Json::from(elapsed_ms))],
+            );
+        }
+
+        SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            let top = spans.pop();
+            debug_assert!(
+                top.is_some_and(|span| span.id == self.id),
+                "span '{}' exited out of order",
+                self.name
+            );
+        });
+    }
+}
+
+/// Spawns a thread with the supplied `name` that runs `f` in the calling
+/// thread's current spans, so that events emitted by helper threads (serial
+/// watchers and the like) are attributed to the step that started them.
+pub fn spawn<F, T>(name: &str, f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let parents = SPANS.with(|spans| spans.borrow().clone());
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            SPANS.with(|spans| *spans.borrow_mut() = parents);
+            f()
+        })
+        .expect("failed to spawn thread")
+}
+
+/// Emits an event at the supplied level. See the module documentation.
+macro_rules! event {
+    ($level:expr, $message:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::trace::enabled($level, module_path!()) {
+            $crate::trace::record(
+                $level,
+                module_path!(),
+                format!($message),
+                vec![$((stringify!($key), $crate::json::Json::from($value))),*],
+            );
+        }
+    };
+}
+
+/// Enters a span at the supplied level, returning a guard that exits the
+/// span when dropped.
+macro_rules! span {
+    ($level:expr, $name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::trace::Span::enter(
+            $level,
+            module_path!(),
+            $name,
+            vec![$((stringify!($key), $crate::json::Json::from($value))),*],
+        )
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::trace::event!($crate::trace::Level::Info, $($arg)+)
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::trace::event!($crate::trace::Level::Debug, $($arg)+)
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::trace::event!($crate::trace::Level::Trace, $($arg)+)
+    };
+}
+
+pub(crate) use {debug, event, info, span, trace};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filters_by_level_and_target() {
+        let (filter, invalid) = Filter::parse(
+            "warn, wimsy::monitor=trace,wimsy::monitor::serial=off,wimsy::ui",
+        );
+        assert!(invalid.is_empty());
+        assert!(filter.enabled(Level::Warn, "wimsy::steps"));
+        assert!(!filter.enabled(Level::Info, "wimsy::steps"));
+        assert!(filter.enabled(Level::Trace, "wimsy::monitor"));
+        assert!(filter.enabled(Level::Trace, "wimsy::monitor::disk"));
+        assert!(!filter.enabled(Level::Error, "wimsy::monitor::serial"));
+        assert!(!filter.enabled(Level::Trace, "wimsy::monitorx"));
+        assert!(filter.enabled(Level::Trace, "wimsy::ui"));
+
+        let (filter, invalid) = Filter::parse("debug,info,wimsy=loud");
+        assert_eq!(invalid.len(), 1);
+        assert!(!filter.enabled(Level::Debug, "wimsy"));
+        assert!(filter.enabled(Level::Info, "wimsy"));
+
+        let (filter, _) = Filter::parse("");
+        assert!(!filter.enabled(Level::Error, "wimsy"));
+    }
+
+    #[test]
+    fn traces_each_build_to_its_own_files() {
+        let dir = Utf8PathBuf::try_from(
+            std::env::temp_dir().join("wimsy-trace-test"),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+
+        let guard = init(&first, 0).unwrap();
+        assert!(init(&second, 0).is_err());
+        assert_eq!(path(), Some(first.join(TRACE_FILE_NAME)));
+        info!("first build event");
+        drop(guard);
+        assert_eq!(path(), None);
+
+        let guard = init(&second, 0).unwrap();
+        info!("second build event");
+        drop(guard);
+
+        let read = |dir: &Utf8Path, name| {
+            std::fs::read_to_string(dir.join(name)).unwrap()
+        };
+        for name in [TRACE_FILE_NAME, BUILD_LOG_NAME] {
+            assert!(read(&first, name).contains("first build event"));
+            assert!(!read(&first, name).contains("second build event"));
+            assert!(read(&second, name).contains("second build event"));
+            assert!(!read(&second, name).contains("first build event"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn formats_events_as_text() {
+        let spans = [Arc::new(SpanInfo {
+            id: 1,
+            name: "step",
+            fields: vec![("step", "install".into()), ("number", 4u64.into())],
+        })];
+        let fields = [("program", "qemu-img".into()), ("args", "a b".into())];
+        assert_eq!(
+            text_line(
+                86400.0 * 3.0 + 3600.0 * 13.0 + 245.25,
+                Level::Info,
+                &spans,
+                "command started",
+                &fields
+            ),
+            "13:04:05.250  INFO step{step=install number=4}: command \
+            started program=qemu-img args=\"a b\"\n"
+        );
+        assert_eq!(
+            text_line(0.0, Level::Debug, &[], "hi", &[]),
+            "00:00:00.000 DEBUG hi\n"
+        );
+    }
+}
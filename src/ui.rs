@@ -2,17 +2,132 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::runner::{Context, Script, ScriptStep};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use camino::Utf8Path;
+use crate::{
+    checkpoint::Checkpoint,
+    command::{CommandRunner, HostRunner},
+    config::RetryPolicy,
+    json::Json,
+    manifest::Signer,
+    report::{BuildReport, Decision, Outcome},
+    runner::{Context, Step},
+    trace,
+};
+
+use anyhow::Context as _;
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 const PROGRESS_TICK_INTERVAL: std::time::Duration =
     std::time::Duration::from_millis(100);
 
+/// The number of lines of command output to show below the step list in
+/// interactive mode.
+const LOG_TAIL_LINES: usize = 6;
+
+/// How often to refresh the command output shown in interactive mode.
+const LOG_TAIL_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(500);
+
+/// How much of the end of a log file to read when looking for its last lines.
+const LOG_TAIL_READ_BYTES: u64 = 16 * 1024;
+
+/// The name of the file in the work directory to which the guest serial
+/// consoles of the build's VMs are copied.
+pub const SERIAL_LOG_NAME: &str = "serial.log";
+
 pub enum Mode {
-    Interactive,
+    Interactive {
+        pause_after: PauseAfter,
+    },
     NonInteractive,
+
+    /// Non-interactive, reporting progress as events (see [`Events`]).
+    Events(Events),
+}
+
+/// A function that receives a build's progress events. See "Progress for CI
+/// pipelines" in README.md for the events and their fields.
+pub type EventCallback = Arc<dyn Fn(&Json) + Send + Sync>;
+
+/// Where a build's progress events go.
+#[derive(Clone)]
+pub enum Events {
+    /// To stdout, one JSON object per line, for `--progress json`.
+    Stdout,
+
+    /// To a callback, for programs that run builds with the library.
+    Callback(EventCallback),
+}
+
+impl Events {
+    /// Reports `event`, adding the time at which it was emitted (in seconds
+    /// since the Unix epoch) as `time`.
+    pub fn emit(&self, event: Json) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let event = event.with("time", time);
+        match self {
+            Events::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{event}");
+                let _ = stdout.flush();
+            }
+            Events::Callback(callback) => callback(&event),
+        }
+    }
+}
+
+/// Starts a progress event of the kind `name`. Callers add the event's fields
+/// and pass it to [`Events::emit`].
+pub fn event(name: &str) -> Json {
+    Json::object().with("event", name)
+}
+
+/// The steps after which to pause in interactive mode.
+#[derive(Clone, Debug, Default)]
+pub enum PauseAfter {
+    #[default]
+    Never,
+    All,
+    Steps(BTreeSet<String>),
+}
+
+impl PauseAfter {
+    /// Converts the arguments to `--pause-after` into a `PauseAfter`.
+    pub fn from_args(args: &[String]) -> Self {
+        if args.is_empty() {
+            PauseAfter::Never
+        } else if args.iter().any(|a| a == "all") {
+            PauseAfter::All
+        } else {
+            PauseAfter::Steps(args.iter().cloned().collect())
+        }
+    }
+
+    pub fn is_never(&self) -> bool {
+        matches!(self, PauseAfter::Never)
+    }
+
+    fn matches(&self, step: &dyn Step) -> bool {
+        match self {
+            PauseAfter::Never => false,
+            PauseAfter::All => true,
+            PauseAfter::Steps(names) => names.contains(step.name()),
+        }
+    }
 }
 
 /// Describes UI-related functionality that's available to each step in a
@@ -31,6 +146,51 @@ pub trait Ui {
     /// of a process with the supplied name.
     fn child_stderr(&self, process_name: &str)
         -> anyhow::Result<std::fs::File>;
+
+    /// Obtains a handle to the build's serial console log, to which to copy
+    /// the serial output of the VM named `vm`. The log is appended to, with a
+    /// header naming the step and VM, so that it keeps the output of every VM
+    /// the build runs (or every boot of one).
+    fn serial_log(
+        &self,
+        vm: &str,
+    ) -> anyhow::Result<Box<dyn std::io::Write + Send>>;
+
+    /// Tells the UI that the current step is about to run `cmd`.
+    fn command_started(&self, cmd: &std::process::Command);
+
+    /// Displays a warning to the user without interrupting the current step.
+    fn warn(&self, message: &str);
+
+    /// Records a named measurement taken by the current step in the build
+    /// report.
+    fn record_metric(&self, name: &str, value: Json);
+
+    /// Displays `prompt` and reads a secret from the terminal without echoing
+    /// it.
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String>;
+
+    /// Returns the runner that runs the commands the current step runs to
+    /// completion. By default, they run on this host.
+    fn command_runner(&self) -> &dyn CommandRunner {
+        &HostRunner
+    }
+}
+
+/// Settings that apply to the [`Ui`] every step of a build gets.
+#[derive(Clone)]
+pub struct StepUiOptions {
+    /// Whether to copy the output VMs write to the serial log to stderr too.
+    pub follow_serial: bool,
+
+    /// Runs the commands steps run to completion.
+    pub command_runner: Arc<dyn CommandRunner>,
+
+    /// Which failed steps to run again, and how long to wait first.
+    pub retry: RetryPolicy,
+
+    /// Signs the image manifest, if set.
+    pub signer: Option<Signer>,
 }
 
 /// The handler used to display updates about the status of a particular step or
@@ -39,16 +199,24 @@ pub trait Ui {
 enum StepHandler<'a> {
     ProgressBar(&'a ProgressBar),
     Stdout,
+
+    /// Steps' starts and ends are emitted by the runner, since they carry
+    /// details the handler doesn't have.
+    Events(&'a Events),
 }
 
 impl StepHandler<'_> {
     /// Informs this handler that `step` completed with outcome `result`.
-    fn apply_result(&self, step: &ScriptStep, result: &anyhow::Result<()>) {
+    fn apply_result(&self, step: &dyn Step, result: &anyhow::Result<()>) {
         match self {
             StepHandler::ProgressBar(bar) => {
                 match result {
                     Ok(()) => {
-                        bar.set_message(step.label());
+                        bar.set_message(format!(
+                            "{} ({})",
+                            step.label(),
+                            format_elapsed(bar.elapsed())
+                        ));
                         bar.set_style(
                             ProgressStyle::with_template("✓ {msg:.green}")
                                 .unwrap(),
@@ -70,6 +238,49 @@ impl StepHandler<'_> {
                     println!("  {e:?}");
                 }
             },
+            StepHandler::Events(_) => {}
+        }
+    }
+}
+
+impl StepHandler<'_> {
+    /// Informs this handler that `step` completed in an earlier run.
+    fn apply_resumed(&self, step: &dyn Step) {
+        match self {
+            StepHandler::ProgressBar(bar) => {
+                bar.set_message(format!(
+                    "{} (completed earlier)",
+                    step.label()
+                ));
+                bar.set_style(
+                    ProgressStyle::with_template("✓ {msg:.dim}").unwrap(),
+                );
+                bar.finish();
+            }
+            StepHandler::Stdout => {
+                println!("Completed earlier: {}", step.label())
+            }
+            StepHandler::Events(events) => {
+                events.emit(event("step_resumed").with("step", step.name()))
+            }
+        }
+    }
+
+    /// Informs this handler that `step` was skipped, because the user chose
+    /// to skip it after it failed or didn't select it to run.
+    fn apply_skipped(&self, step: &dyn Step) {
+        match self {
+            StepHandler::ProgressBar(bar) => {
+                bar.set_message(format!("{} (skipped)", step.label()));
+                bar.set_style(
+                    ProgressStyle::with_template("↷ {msg:.yellow}").unwrap(),
+                );
+                bar.finish();
+            }
+            StepHandler::Stdout => println!("Skipped: {}", step.label()),
+            StepHandler::Events(events) => {
+                events.emit(event("step_skipped").with("step", step.name()))
+            }
         }
     }
 }
@@ -78,13 +289,21 @@ impl StepHandler<'_> {
 /// specific step in a script.
 struct PerStepUi<'a> {
     step_id: usize,
-    step: &'a ScriptStep,
+    step: &'a dyn Step,
     step_handler: StepHandler<'a>,
     log_dir: &'a Utf8Path,
+    log_tail: Option<&'a LogTail>,
+    metrics: RefCell<Vec<(String, Json)>>,
+
+    /// The step's current attempt, counting from 1.
+    attempt: Cell<usize>,
+
+    options: &'a StepUiOptions,
 }
 
 impl Ui for PerStepUi<'_> {
     fn set_substep(&self, substep: &str) {
+        trace::debug!("substep", substep = substep);
         match self.step_handler {
             StepHandler::ProgressBar(bar) => {
                 bar.set_message(format!("{}: {}", self.step.label(), substep));
@@ -92,6 +311,11 @@ impl Ui for PerStepUi<'_> {
             StepHandler::Stdout => {
                 println!("  {}", substep);
             }
+            StepHandler::Events(events) => events.emit(
+                event("substep")
+                    .with("step", self.step.name())
+                    .with("message", substep),
+            ),
         }
     }
 
@@ -108,16 +332,112 @@ impl Ui for PerStepUi<'_> {
     ) -> anyhow::Result<std::fs::File> {
         self.create_log_file_for_process(LogStream::Stderr, process_name)
     }
+
+    fn serial_log(
+        &self,
+        vm: &str,
+    ) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
+        let path = self.log_dir.join(SERIAL_LOG_NAME);
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening '{path}'"))?;
+        writeln!(file, "==> {}: {vm} <==", self.step.name())?;
+        if let Some(tail) = self.log_tail {
+            tail.watch(path);
+        }
+
+        if self.options.follow_serial {
+            Ok(Box::new(SerialEcho { file, line_started: false }))
+        } else {
+            Ok(Box::new(file))
+        }
+    }
+
+    fn command_started(&self, cmd: &std::process::Command) {
+        match self.step_handler {
+            StepHandler::Events(events) => {
+                let args: Vec<String> = cmd
+                    .get_args()
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .collect();
+                events.emit(
+                    event("command")
+                        .with("step", self.step.name())
+                        .with(
+                            "program",
+                            cmd.get_program().to_string_lossy().into_owned(),
+                        )
+                        .with("args", args),
+                );
+            }
+            _ => self.set_substep(&format!("{} {:?}", "executing: ", cmd)),
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        trace::event!(trace::Level::Warn, "{message}");
+        let formatted = format!(
+            "{} {}: {}",
+            "Warning:".bold().yellow(),
+            self.step.name(),
+            message
+        );
+        match self.step_handler {
+            StepHandler::ProgressBar(bar) => bar.println(formatted),
+            StepHandler::Stdout => println!("{formatted}"),
+            StepHandler::Events(events) => events.emit(
+                event("warning")
+                    .with("step", self.step.name())
+                    .with("message", message),
+            ),
+        }
+    }
+
+    fn record_metric(&self, name: &str, value: Json) {
+        trace::debug!("recorded metric", name = name, value = value.clone());
+        if let StepHandler::Events(events) = self.step_handler {
+            events.emit(
+                event("metric")
+                    .with("step", self.step.name())
+                    .with("name", name)
+                    .with("value", value.clone()),
+            );
+        }
+        self.metrics.borrow_mut().push((name.to_string(), value));
+    }
+
+    fn read_secret(&self, prompt: &str) -> anyhow::Result<String> {
+        let read = || {
+            print!("{prompt}");
+            std::io::stdout().flush()?;
+            crate::secrets::read_line_without_echo()
+        };
+
+        match self.step_handler {
+            StepHandler::ProgressBar(bar) => bar.suspend(read),
+            StepHandler::Stdout => read(),
+            // Keep stdout free of anything but events.
+            StepHandler::Events(_) => {
+                eprint!("{prompt}");
+                crate::secrets::read_line_without_echo()
+            }
+        }
+    }
+
+    fn command_runner(&self) -> &dyn CommandRunner {
+        self.options.command_runner.as_ref()
+    }
 }
 
 impl PerStepUi<'_> {
-    /// Creates a log file for the process named `process_name`.
+    /// Opens the log file for the process named `process_name`.
     ///
-    /// Note that the log file is always truncated, not appended to. The
-    /// framework adds a step ID to each log file name to disambiguate output
-    /// files generated by the same process name in different steps, but if the
-    /// same step needs to run a single process multiple times, it should tag
-    /// them with distinct `process_name`s.
+    /// The framework adds a step ID to each log file name to disambiguate
+    /// output files generated by the same process name in different steps.
+    /// Each run of a process in a step (including each attempt at the step)
+    /// appends to the same file, after a header saying which attempt it was.
     fn create_log_file_for_process(
         &self,
         stream: LogStream,
@@ -125,11 +445,237 @@ impl PerStepUi<'_> {
     ) -> anyhow::Result<std::fs::File> {
         let mut path = self.log_dir.to_path_buf();
         path.push(format!("{}.{}.{}.log", self.step_id, process_name, stream));
-        Ok(std::fs::File::create(&path)?)
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening '{path}'"))?;
+        writeln!(
+            file,
+            "==> {}: {process_name} (attempt {}) <==",
+            self.step.name(),
+            self.attempt.get()
+        )?;
+        if let Some(tail) = self.log_tail {
+            tail.watch(path);
+        }
+
+        Ok(file)
+    }
+}
+
+/// A UI for the commands that work on an image outside of a build, which
+/// have no step to report their commands under and throw their logs away.
+pub struct StandaloneUi;
+
+impl Ui for StandaloneUi {
+    fn set_substep(&self, _: &str) {}
+
+    fn child_stdout(&self, _: &str) -> anyhow::Result<std::fs::File> {
+        Ok(std::fs::File::create("/dev/null")?)
+    }
+
+    fn child_stderr(&self, _: &str) -> anyhow::Result<std::fs::File> {
+        Ok(std::fs::File::create("/dev/null")?)
+    }
+
+    fn serial_log(
+        &self,
+        _: &str,
+    ) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
+        Ok(Box::new(std::io::sink()))
+    }
+
+    fn command_started(&self, _: &std::process::Command) {}
+
+    fn warn(&self, message: &str) {
+        eprintln!("Warning: {message}");
+    }
+
+    fn record_metric(&self, _: &str, _: Json) {}
+
+    fn read_secret(&self, _: &str) -> anyhow::Result<String> {
+        anyhow::bail!("only builds read secrets")
+    }
+}
+
+/// Copies serial console output to the serial log and to stderr, marking
+/// each line on stderr as the guest's.
+struct SerialEcho {
+    file: std::fs::File,
+
+    /// Whether the last line written to stderr is unfinished.
+    line_started: bool,
+}
+
+impl Write for SerialEcho {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all(buf)?;
+        let mut stderr = std::io::stderr().lock();
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if !self.line_started {
+                write!(stderr, "{} ", "serial:".dimmed())?;
+            }
+            stderr.write_all(line)?;
+            self.line_started = !line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        std::io::stderr().flush()
+    }
+}
+
+/// Shows the last few lines written to the current step's command logs below
+/// the step list in interactive mode, refreshing them on a background thread.
+///
+/// Commands run with [`crate::util::run_command_check_status`] write their
+/// logs when they exit, but long-running processes such as the installation
+/// VM write to their logs as they run, so their output appears here live.
+struct LogTail {
+    logs: Arc<Mutex<Vec<Utf8PathBuf>>>,
+    bars: Arc<Vec<ProgressBar>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl LogTail {
+    /// Adds a header and `lines` lines of output to the bottom of `multi`.
+    fn new(multi: &MultiProgress, lines: usize) -> Self {
+        let bars: Arc<Vec<ProgressBar>> = Arc::new(
+            (0..=lines)
+                .map(|i| {
+                    let bar = multi.add(ProgressBar::new_spinner());
+                    let template = if i == 0 {
+                        "{msg:.bold}"
+                    } else {
+                        "  {wide_msg:.dim}"
+                    };
+                    bar.set_style(
+                        ProgressStyle::with_template(template).unwrap(),
+                    );
+                    bar
+                })
+                .collect(),
+        );
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (logs, bars, stop) = (logs.clone(), bars.clone(), stop.clone());
+            trace::spawn("log-tail", move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let latest = latest_log(&logs.lock().unwrap());
+                    show_tail(&bars, latest.as_deref());
+                    std::thread::sleep(LOG_TAIL_INTERVAL);
+                }
+            })
+        };
+
+        Self { logs, bars, stop, thread: Some(thread) }
+    }
+
+    /// Adds the log file at `path` to the logs being followed.
+    fn watch(&self, path: Utf8PathBuf) {
+        self.logs.lock().unwrap().push(path);
+    }
+
+    /// Stops following the previous step's logs.
+    fn clear(&self) {
+        self.logs.lock().unwrap().clear();
+        show_tail(&self.bars, None);
+    }
+}
+
+impl Drop for LogTail {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        for bar in self.bars.iter() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Returns the most recently modified non-empty log in `logs`.
+fn latest_log(logs: &[Utf8PathBuf]) -> Option<Utf8PathBuf> {
+    logs.iter()
+        .filter_map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            let modified = metadata.modified().ok()?;
+            (metadata.len() > 0).then_some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path.clone())
+}
+
+/// Shows the last lines of the log at `path` in `bars`, the first of which
+/// is a header. Clears the bars if there's no log to show.
+fn show_tail(bars: &[ProgressBar], path: Option<&Utf8Path>) {
+    let Some((header, lines)) = bars.split_first() else {
+        return;
+    };
+
+    let tail = path.and_then(|path| Some((path, read_tail(path).ok()?)));
+    let Some((path, (text, partial))) = tail else {
+        for bar in bars {
+            bar.set_message("");
+        }
+        return;
+    };
+
+    header.set_message(format!(
+        "Output of {}:",
+        path.file_name().unwrap_or(path.as_str())
+    ));
+    let last = last_lines(&text, partial, lines.len());
+    let blank = lines.len() - last.len();
+    for (i, bar) in lines.iter().enumerate() {
+        match i.checked_sub(blank) {
+            Some(index) => bar.set_message(last[index].clone()),
+            None => bar.set_message(""),
+        }
     }
 }
 
-fn format_elapsed(elapsed: std::time::Duration) -> String {
+/// Reads the end of the file at `path`. Also returns whether the text read
+/// starts in the middle of the file (and so probably mid-line).
+fn read_tail(path: &Utf8Path) -> std::io::Result<(String, bool)> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(LOG_TAIL_READ_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok((String::from_utf8_lossy(&data).into_owned(), start > 0))
+}
+
+/// Returns up to `count` of the last non-blank lines in `text`, dropping the
+/// first line if it's `partial`. Only the text after a line's last carriage
+/// return is kept, since that's what a terminal would show, and other control
+/// characters are removed.
+fn last_lines(text: &str, partial: bool, count: usize) -> Vec<String> {
+    let mut lines: Vec<String> = text
+        .split('\n')
+        .skip(usize::from(partial))
+        .map(|line| {
+            let line = line.trim_end_matches('\r');
+            let shown = line.rsplit('\r').next().unwrap_or(line);
+            shown.chars().filter(|c| !c.is_control() || *c == '\t').collect()
+        })
+        .filter(|line: &String| !line.trim().is_empty())
+        .collect();
+    let skip = lines.len().saturating_sub(count);
+    lines.drain(..skip);
+    lines
+}
+
+pub(crate) fn format_elapsed(elapsed: std::time::Duration) -> String {
     let total_secs = elapsed.as_secs();
     let hours = total_secs / 3600;
     let minutes = (total_secs % 3600) / 60;
@@ -143,23 +689,160 @@ fn format_elapsed(elapsed: std::time::Duration) -> String {
     }
 }
 
+/// Runs the supplied `steps` in order, then writes a [`BuildReport`] describing
+/// the run to `work_dir` and, if it succeeded, the image's manifest (see
+/// [`crate::manifest`]) next to the image. Command logs go to the work
+/// directory's logs directory (see [`crate::workspace`]).
+///
+/// Only the steps that are `selected` run. The steps `checkpoint` lists as
+/// completed are assumed to be the first steps in `steps` and aren't run
+/// again. Each step that completes is added to the checkpoint, which is
+/// rewritten to `work_dir` as it is; without one, no checkpoint is written.
+///
+/// In interactive mode, if a step fails, the user can choose to retry it, skip
+/// it, open a shell in the work directory before retrying it, or abort the
+/// script. In non-interactive mode, the script stops at the first failure.
 pub fn run_script(
-    script: Box<dyn Script>,
-    mut ctx: Context,
-    log_dir: &Utf8Path,
+    steps: &[&dyn Step],
+    selected: &[bool],
+    ctx: Context,
+    work_dir: &Utf8Path,
     mode: Mode,
+    checkpoint: Option<Checkpoint>,
+    options: StepUiOptions,
 ) -> anyhow::Result<()> {
     let start = std::time::Instant::now();
+    let events = match &mode {
+        Mode::Events(events) => Some(events.clone()),
+        _ => None,
+    };
+    let mut report = BuildReport::new();
+    let planned: Vec<(&dyn Step, bool)> =
+        steps.iter().copied().zip(selected.iter().copied()).collect();
+    let mut ctx = ctx;
+    let result = run_steps(
+        &planned,
+        &mut ctx,
+        work_dir,
+        mode,
+        checkpoint,
+        &options,
+        &mut report,
+    );
+    let written = report.write(work_dir, &result);
+
+    let manifest = if result.is_ok() {
+        if events.is_none() {
+            println!("\nWriting the image manifest...");
+        }
+        let ran: Vec<&dyn Step> = planned
+            .iter()
+            .filter(|(_, selected)| *selected)
+            .map(|(step, _)| *step)
+            .collect();
+        crate::manifest::write(
+            &ran,
+            &ctx,
+            &report,
+            options.command_runner.as_ref(),
+            options.signer.as_ref(),
+        )
+    } else {
+        Ok(None)
+    };
+
+    if let Some(events) = events {
+        let classified = result.as_ref().err().and_then(crate::error::classify);
+        events.emit(
+            event("build_finished")
+                .with("succeeded", result.is_ok())
+                .with("elapsed_secs", start.elapsed().as_secs_f64())
+                .with(
+                    "report",
+                    written.as_ref().ok().map(|path| path.to_string()),
+                )
+                .with(
+                    "manifest",
+                    manifest
+                        .as_ref()
+                        .ok()
+                        .and_then(Option::as_ref)
+                        .map(|path| path.as_str()),
+                )
+                .with("error", result.as_ref().err().map(|e| format!("{e:#}")))
+                .with("error_kind", classified.map(|e| e.kind().as_str()))
+                .with("hint", classified.map(|e| e.hint())),
+        );
+        if let Err(e) = &written {
+            eprintln!("Failed to write build report: {e:#}");
+        }
+        if let Err(e) = &manifest {
+            eprintln!("Failed to write image manifest: {e:#}");
+        }
+
+        return result;
+    }
+
+    match written {
+        Ok(path) => println!("\nBuild report written to {path}"),
+        Err(e) => println!("\nFailed to write build report: {e:#}"),
+    }
+    match manifest {
+        Ok(Some(path)) => println!("Image manifest written to {path}"),
+        Ok(None) => {}
+        Err(e) => println!("Failed to write image manifest: {e:#}"),
+    }
+
+    if result.is_ok() {
+        println!("\nTotal build time: {}.", format_elapsed(start.elapsed()));
+    }
+
+    result
+}
+
+/// Runs `steps`, skipping those that aren't paired with `true`. See
+/// [`run_script`].
+fn run_steps(
+    steps: &[(&dyn Step, bool)],
+    ctx: &mut Context,
+    work_dir: &Utf8Path,
+    mode: Mode,
+    mut checkpoint: Option<Checkpoint>,
+    options: &StepUiOptions,
+    report: &mut BuildReport,
+) -> anyhow::Result<()> {
+    let events = match &mode {
+        Mode::Events(events) => Some(events.clone()),
+        _ => None,
+    };
+    if let Some(events) = &events {
+        let names: Vec<&str> =
+            steps.iter().map(|(step, _)| step.name()).collect();
+        events.emit(event("build_started").with("steps", names));
+    }
+
+    // Replace any checkpoint left by a different build straight away, so
+    // that it can't be resumed by mistake if this one dies before finishing
+    // a step.
+    let resumed = checkpoint.as_ref().map_or(0, |c| c.completed().len());
+    if let Some(Err(e)) = checkpoint.as_ref().map(|c| c.write(work_dir)) {
+        eprintln!("Warning: not writing checkpoints: {e:#}");
+    }
+
+    // Start a new serial log unless this build continues an earlier one.
+    let log_dir = crate::workspace::logs_dir(work_dir);
+    if resumed == 0 {
+        let _ = std::fs::remove_file(log_dir.join(SERIAL_LOG_NAME));
+    }
 
-    let (_multi, bars) = match mode {
-        Mode::Interactive => {
+    let (multi, bars, pause_after) = match mode {
+        Mode::Interactive { pause_after } => {
             let multi = MultiProgress::new();
-            let bars: Vec<ProgressBar> = script
-                .steps()
+            let bars: Vec<ProgressBar> = steps
                 .iter()
-                .map(|step| {
+                .map(|(step, _)| {
                     let bar = multi.add(ProgressBar::new_spinner());
-                    bar.set_message(step.label());
+                    bar.set_message(step.label().to_string());
                     bar.set_style(
                         ProgressStyle::with_template("  {msg:.dim}").unwrap(),
                     );
@@ -168,40 +851,375 @@ pub fn run_script(
                 })
                 .collect();
 
-            (Some(multi), Some(bars))
+            (Some(multi), Some(bars), pause_after)
+        }
+        Mode::NonInteractive | Mode::Events(_) => {
+            (None, None, PauseAfter::Never)
         }
-        Mode::NonInteractive => (None, None),
     };
 
+    // Declared after the progress bars so that it's dropped (and its bars
+    // cleared) first.
+    let log_tail =
+        multi.as_ref().map(|multi| LogTail::new(multi, LOG_TAIL_LINES));
+
     let substep_handlers: Box<dyn Iterator<Item = StepHandler>> = match &bars {
         Some(bars) => Box::new(bars.iter().map(StepHandler::ProgressBar)),
-        None => Box::new(std::iter::repeat(StepHandler::Stdout)),
+        None => match &events {
+            Some(events) => {
+                Box::new(std::iter::repeat(StepHandler::Events(events)))
+            }
+            None => Box::new(std::iter::repeat(StepHandler::Stdout)),
+        },
     };
 
-    for (step_number, (step, handler)) in
-        script.steps().iter().zip(substep_handlers).enumerate()
+    for (step_number, ((step, selected), handler)) in
+        steps.iter().copied().zip(substep_handlers).enumerate()
     {
         let ui = PerStepUi {
             step_id: step_number,
             step,
             step_handler: handler,
-            log_dir,
+            log_dir: &log_dir,
+            log_tail: log_tail.as_ref(),
+            metrics: RefCell::new(Vec::new()),
+            attempt: Cell::new(0),
+            options,
         };
 
-        if let StepHandler::ProgressBar(bar) = ui.step_handler {
-            bar.set_style(ProgressStyle::default_spinner());
-            bar.enable_steady_tick(PROGRESS_TICK_INTERVAL);
+        let _span = trace::span!(
+            trace::Level::Info,
+            "step",
+            step = step.name(),
+            number = step_number
+        );
+        report.begin_step(step.name(), step.label());
+        if step_number < resumed {
+            trace::info!("step completed in an earlier run");
+            ui.step_handler.apply_resumed(step);
+            report.finish_step(Outcome::Resumed);
+            continue;
+        }
+
+        if !selected {
+            trace::info!("step not selected to run");
+            ui.step_handler.apply_skipped(step);
+            report.finish_step(Outcome::Skipped);
+            continue;
         }
 
-        let result = step.run(&mut ctx, &ui);
-        ui.step_handler.apply_result(step, &result);
-        result?;
+        let mut attempt = 0usize;
+        loop {
+            attempt += 1;
+            ui.attempt.set(attempt);
+            if let StepHandler::ProgressBar(bar) = ui.step_handler {
+                bar.set_message(step.label().to_string());
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "{spinner} {msg} {elapsed:.dim}",
+                    )
+                    .unwrap(),
+                );
+                bar.reset_elapsed();
+                bar.enable_steady_tick(PROGRESS_TICK_INTERVAL);
+            }
+            if let Some(tail) = &log_tail {
+                tail.clear();
+            }
+
+            let vars_before = ctx.vars().clone();
+            let attempt_start = std::time::Instant::now();
+            trace::info!(
+                "running step",
+                label = step.label(),
+                attempt = attempt
+            );
+            if let Some(events) = &events {
+                events.emit(
+                    event("step_started")
+                        .with("step", step.name())
+                        .with("label", step.label())
+                        .with("number", step_number)
+                        .with("total", steps.len())
+                        .with("attempt", attempt),
+                );
+            }
+            let result = step.execute(ctx, &ui);
+            match &result {
+                Ok(()) => trace::info!("step succeeded"),
+                Err(e) => trace::event!(
+                    trace::Level::Error,
+                    "step failed",
+                    error = format!("{e:#}")
+                ),
+            }
+            let elapsed = attempt_start.elapsed();
+            if let Some(events) = &events {
+                events.emit(
+                    event("step_finished")
+                        .with("step", step.name())
+                        .with(
+                            "outcome",
+                            if result.is_ok() { "succeeded" } else { "failed" },
+                        )
+                        .with("elapsed_secs", elapsed.as_secs_f64())
+                        .with(
+                            "error",
+                            result.as_ref().err().map(|e| format!("{e:#}")),
+                        ),
+                );
+            }
+            report.record_attempt(elapsed, &result, ui.metrics.take());
+            let error = match result {
+                Ok(()) => {
+                    ui.step_handler.apply_result(step, &Ok(()));
+                    report.finish_step(Outcome::Succeeded);
+                    let mut vars: Vec<(String, String)> = ctx
+                        .vars()
+                        .iter()
+                        .filter(|(var, value)| {
+                            vars_before.get(*var) != Some(value)
+                        })
+                        .map(|(var, value)| (var.clone(), value.clone()))
+                        .collect();
+                    vars.sort();
+                    if let Some(checkpoint) = &mut checkpoint {
+                        checkpoint.record(step.name(), vars);
+                        if let Err(e) = checkpoint.write(work_dir) {
+                            ui.warn(&format!(
+                                "couldn't update the checkpoint: {e:#}"
+                            ));
+                        }
+                    }
+                    if let (Some(multi), true) =
+                        (&multi, pause_after.matches(step))
+                    {
+                        let resume =
+                            multi.suspend(|| pause(step, &vars_before, ctx))?;
+                        if !resume {
+                            crate::interrupt::clean_up();
+                            return Err(crate::error::Error::new(
+                                crate::error::ErrorKind::Cancelled,
+                                format!(
+                                    "build stopped at user request after \
+                                    step '{}'",
+                                    step.name()
+                                ),
+                            )
+                            .into());
+                        }
+                    }
+                    break;
+                }
+                Err(e) => e,
+            };
+
+            let attempts =
+                options.retry.attempts_for(step.name(), step.retryable());
+            if attempt < attempts as usize {
+                let wait = options.retry.backoff(attempt as u32);
+                ui.warn(&format!(
+                    "attempt {attempt} of {attempts} failed: {error:#}; \
+                    retrying in {}s",
+                    wait.as_secs()
+                ));
+                trace::info!(
+                    "retrying step",
+                    attempt = attempt,
+                    wait_secs = wait.as_secs()
+                );
+                report.record_decision(Decision::AutoRetry);
+                ui.set_substep(&format!(
+                    "waiting to retry after attempt {attempt} failed"
+                ));
+                std::thread::sleep(wait);
+                continue;
+            }
+
+            // Failures are fatal unless there's someone around to decide
+            // what to do about them.
+            let Some(multi) = &multi else {
+                let result = Err(error);
+                ui.step_handler.apply_result(step, &result);
+                report.finish_step(Outcome::Failed);
+                return result;
+            };
+
+            if let StepHandler::ProgressBar(bar) = ui.step_handler {
+                bar.disable_steady_tick();
+                bar.set_style(
+                    ProgressStyle::with_template("⚠ {msg:.bold.red}").unwrap(),
+                );
+            }
+
+            let decision = multi.suspend(|| prompt_for_decision(step, &error));
+            let decision = match decision {
+                Ok(decision) => decision,
+                Err(e) => {
+                    println!("Failed to read a response: {e:#}");
+                    Decision::Abort
+                }
+            };
+
+            trace::info!(
+                "user chose how to proceed",
+                decision = decision.as_str()
+            );
+            report.record_decision(decision);
+            match decision {
+                Decision::Retry | Decision::AutoRetry => {}
+                Decision::Shell => {
+                    if let Err(e) = multi.suspend(|| open_shell(work_dir)) {
+                        multi.suspend(|| println!("{e:#}"));
+                    }
+                }
+                Decision::Skip => {
+                    multi.suspend(|| warn_about_skipped_step(step, ctx));
+                    ui.step_handler.apply_skipped(step);
+                    report.finish_step(Outcome::Skipped);
+                    break;
+                }
+                Decision::Abort => {
+                    let result = Err(error);
+                    ui.step_handler.apply_result(step, &result);
+                    report.finish_step(Outcome::Failed);
+                    return result;
+                }
+            }
+        }
     }
 
-    println!("\nTotal build time: {}.", format_elapsed(start.elapsed()));
     Ok(())
 }
 
+/// Pauses after `step` completes, printing the state of the build and the
+/// context variables the step changed. Returns `true` if the user chose to
+/// continue and `false` if they asked to stop the build.
+fn pause(
+    step: &dyn Step,
+    vars_before: &HashMap<String, String>,
+    ctx: &Context,
+) -> anyhow::Result<bool> {
+    println!("\n{} after step '{}'", "Paused".bold(), step.name());
+    for (label, var) in
+        [("Work directory", "work_dir"), ("Output image", "output_image")]
+    {
+        if let Some(value) = ctx.get_var(var) {
+            println!("  {}: {}", label.bold(), value);
+        }
+    }
+
+    let mut changed: Vec<(&String, &String)> = ctx
+        .vars()
+        .iter()
+        .filter(|(var, value)| vars_before.get(*var) != Some(*value))
+        .collect();
+    changed.sort();
+    if changed.is_empty() {
+        println!("  This step didn't change any context variables.");
+    } else {
+        println!("  Context variables set by this step:");
+        for (var, value) in changed {
+            println!("    {var} = {value}");
+        }
+    }
+
+    loop {
+        print!("\nPress Enter to continue or type 'q' to stop the build: ");
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+
+        match line.trim() {
+            "" => return Ok(true),
+            "q" | "Q" => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Asks the user what to do about a failed step.
+fn prompt_for_decision(
+    step: &dyn Step,
+    error: &anyhow::Error,
+) -> anyhow::Result<Decision> {
+    println!("\n{} {}", "Step failed:".bold().red(), step.label());
+    println!("  {error:?}\n");
+    loop {
+        print!(
+            "[r]etry, [s]kip, open a s[h]ell in the work directory and then \
+            retry, or [a]bort? "
+        );
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(Decision::Abort);
+        }
+
+        match line.trim().to_ascii_lowercase().as_str() {
+            "r" | "retry" => return Ok(Decision::Retry),
+            "s" | "skip" => return Ok(Decision::Skip),
+            "h" | "shell" => return Ok(Decision::Shell),
+            "a" | "abort" => return Ok(Decision::Abort),
+            _ => {}
+        }
+    }
+}
+
+/// Runs the user's shell in the work directory and waits for it to exit.
+fn open_shell(work_dir: &Utf8Path) -> anyhow::Result<()> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    println!(
+        "Starting {shell} in {work_dir}. Exit the shell to retry the step."
+    );
+    std::process::Command::new(&shell)
+        .current_dir(work_dir)
+        .status()
+        .with_context(|| format!("starting shell '{shell}'"))?;
+    Ok(())
+}
+
+/// Warns that steps after a skipped step may fail because they depend on the
+/// skipped step's outputs.
+fn warn_about_skipped_step(step: &dyn Step, ctx: &Context) {
+    let missing: Vec<&str> = step
+        .provided_vars()
+        .iter()
+        .copied()
+        .filter(|var| ctx.get_var(var).is_none())
+        .collect();
+    let replaced: Vec<&str> = step
+        .provided_vars()
+        .iter()
+        .copied()
+        .filter(|var| ctx.get_var(var).is_some())
+        .collect();
+
+    if !missing.is_empty() {
+        println!(
+            "{} skipping '{}' leaves these variables unset, so later steps \
+            that use them will fail: {}",
+            "Warning:".bold(),
+            step.name(),
+            missing.join(", ")
+        );
+    }
+
+    if !replaced.is_empty() {
+        println!(
+            "{} '{}' normally updates these variables, so later steps will \
+            see their previous values: {}",
+            "Warning:".bold(),
+            step.name(),
+            replaced.join(", ")
+        );
+    }
+}
+
 enum LogStream {
     Stdout,
     Stderr,
@@ -219,3 +1237,22 @@ impl std::fmt::Display for LogStream {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_last_lines() {
+        let text =
+            "tial line\nfirst\n\nsecond\r\n10%\r50%\r100%\n\x1b[0mdone\n";
+        assert_eq!(
+            last_lines(text, true, 10),
+            ["first", "second", "100%", "[0mdone"]
+        );
+        assert_eq!(last_lines(text, true, 2), ["100%", "[0mdone"]);
+        assert_eq!(last_lines(text, false, 10)[0], "tial line");
+        assert!(last_lines("", false, 3).is_empty());
+        assert!(last_lines("\n \n", false, 3).is_empty());
+    }
+}
@@ -0,0 +1,600 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Slipstreams Windows updates (the `.msu` and `.cab` packages passed with
+//! `--update` or listed in the configuration file's `[updates]` table) into
+//! the installation media, so that built images are patched when Windows is
+//! installed instead of spending hours installing updates on first boot.
+//!
+//! Adding packages to a Windows image takes DISM, which only runs on Windows,
+//! so wimsy services the media in a VM: a servicing VM boots the Windows ISO
+//! with an answer file whose windowsPE pass runs a generated script instead of
+//! letting Setup install Windows. The script exports the image the build
+//! installs to a scratch disk, adds the packages to it in the order they were
+//! given, splits it into `.swm` files that fit in an ISO 9660 file system, and
+//! powers the VM off. wimsy then copies the split image off the scratch
+//! disk's NTFS volume, extracts the rest of the ISO next to it, and has
+//! `xorriso` write a bootable ISO of the result, which the rest of the build
+//! installs from.
+//!
+//! Only the install image is serviced; `boot.wim` only runs Setup, and is
+//! left as it is.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    gpt::Guid, json::Json, media::InstallMedia, ntfs::Volume, runner::Context,
+    template::xml_escape, trace, ui::Ui, util::run_command_check_status,
+};
+
+/// The context variable that lists the packages to add, one per line.
+pub const UPDATES_VAR: &str = "update_packages";
+
+/// The extensions of the packages DISM can add to an offline image.
+const PACKAGE_EXTENSIONS: [&str; 2] = ["msu", "cab"];
+
+/// The name of the directory on the servicing VM's disc that holds the
+/// packages.
+const UPDATES_DIR: &str = "Updates";
+
+/// The name of the script the servicing VM runs.
+const SERVICE_SCRIPT: &str = "WimsyService.cmd";
+
+/// The name of the `diskpart` script that formats the scratch disk.
+const DISKPART_SCRIPT: &str = "WimsyScratch.txt";
+
+/// The drive letter the scratch disk's volume gets in the servicing VM.
+const SCRATCH_DRIVE: &str = "W:";
+
+/// The size of the scratch disk, which holds the exported image, the image
+/// mounted for servicing, DISM's scratch files, and the split image. It's
+/// sparse, so only the space these take up is allocated.
+const SCRATCH_DISK_BYTES: u64 = 64 * 1024 * 1024 * 1024;
+
+/// The largest part, in MiB, the serviced image is split into, which leaves
+/// room under ISO 9660's 4 GiB limit on file sizes.
+const SWM_PART_MIB: u32 = 3800;
+
+/// The most parts of a split image that are looked for on the scratch disk.
+const MAX_SWM_PARTS: u32 = 32;
+
+/// The name of the serviced ISO in the build's ISO directory.
+pub const SERVICED_ISO_NAME: &str = "windows-serviced.iso";
+
+/// The name of the servicing VM's disc in the build's ISO directory.
+pub const UPDATES_ISO_NAME: &str = "updates.iso";
+
+/// The scratch files a servicing run uses.
+const STAGING_DIR_NAME: &str = "updates";
+const SCRATCH_DISK_NAME: &str = "servicing-scratch.raw";
+const MEDIA_DIR_NAME: &str = "serviced-media";
+
+/// Checks that each of `packages` is an update package that exists, that the
+/// build names the image to service (`image_selected`), and that `xorriso`
+/// is installed to write the serviced ISO, returning a message for each
+/// problem.
+pub fn check_prerequisites(
+    packages: &[Utf8PathBuf],
+    image_selected: bool,
+) -> Vec<String> {
+    let mut errors = crate::util::check_file_prerequisites(packages);
+    for path in packages {
+        let is_package = path.extension().is_some_and(|ext| {
+            PACKAGE_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+        });
+        if !is_package {
+            errors.push(format!(
+                "update '{path}' should be a .msu or .cab package"
+            ));
+        }
+    }
+
+    if !packages.is_empty() && !image_selected {
+        errors.push(
+            "slipstreaming updates services only the image the build \
+            installs, so it needs --edition or --unattend-image-index (or \
+            the configuration file's media.edition)"
+                .to_string(),
+        );
+    }
+    if !packages.is_empty() && which::which("xorriso").is_err() {
+        errors.push(
+            "slipstreaming updates requires xorriso, which wasn't found (is \
+            it on your PATH?)"
+                .to_string(),
+        );
+    }
+
+    errors
+}
+
+/// Returns the name under which the `index`th package (counting from 0) is
+/// staged. The names sort in the order the packages were given, and any
+/// characters the servicing script's commands would trip over are replaced.
+fn staged_name(index: usize, path: &Utf8Path) -> String {
+    let name: String = path
+        .file_name()
+        .unwrap_or("update.msu")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{:02}-{name}", index + 1)
+}
+
+/// Returns the answer file that has the servicing VM's Windows PE run the
+/// servicing script from whichever drive its disc is in.
+fn answer_file(architecture: &str, locale: &str) -> String {
+    let locale = xml_escape(locale);
+    let component = |name: &str, body: &str| {
+        format!(
+            "        <component name=\"{name}\" processorArchitecture=\
+            \"{architecture}\" publicKeyToken=\"31bf3856ad364e35\" \
+            language=\"neutral\" versionScope=\"nonSxS\" \
+            xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\">\n\
+            {body}\n        </component>"
+        )
+    };
+    let international = component(
+        "Microsoft-Windows-International-Core-WinPE",
+        &format!(
+            "            <SetupUILanguage><UILanguage>{locale}</UILanguage>\
+            </SetupUILanguage>\n            <InputLocale>{locale}</InputLocale>\n\
+            \x20           <SystemLocale>{locale}</SystemLocale>\n\
+            \x20           <UILanguage>{locale}</UILanguage>\n\
+            \x20           <UserLocale>{locale}</UserLocale>"
+        ),
+    );
+    let setup = component(
+        "Microsoft-Windows-Setup",
+        &format!(
+            "            <RunSynchronous>\n\
+            \x20               <RunSynchronousCommand wcm:action=\"add\">\n\
+            \x20                   <Description>Slipstream updates</Description>\n\
+            \x20                   <Order>1</Order>\n\
+            \x20                   <Path>cmd /c \"FOR %i IN (C D E F G H I J K L M N \
+            O P Q R S T U V X Y Z) DO IF EXIST %i:\\{SERVICE_SCRIPT} cmd /c \
+            %i:\\{SERVICE_SCRIPT} %i\"</Path>\n\
+            \x20               </RunSynchronousCommand>\n\
+            \x20           </RunSynchronous>"
+        ),
+    );
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <!-- Generated by wimsy to service the Windows image instead of \
+        installing it. -->\n\
+        <unattend xmlns=\"urn:schemas-microsoft-com:unattend\">\n\
+        \x20   <settings pass=\"windowsPE\">\n{international}\n{setup}\n\
+        \x20   </settings>\n</unattend>\n"
+    )
+}
+
+/// Returns the `diskpart` script that gives the servicing VM's only disk a
+/// single NTFS volume.
+fn diskpart_script() -> String {
+    [
+        "select disk 0",
+        "clean",
+        "convert gpt",
+        "create partition primary",
+        "format quick fs=ntfs label=WimsyScratch",
+        &format!("assign letter={}", &SCRATCH_DRIVE[..1]),
+        "exit",
+        "",
+    ]
+    .join("\r\n")
+}
+
+/// Returns the commands that run `command` and stop the script, reporting
+/// `what` failed, if it fails. DISM's 3010 status means a restart is needed,
+/// which doesn't apply to offline images.
+fn checked(command: &str, what: &str) -> Vec<String> {
+    vec![
+        format!("{command} >%LOG%"),
+        "set STATUS=%ERRORLEVEL%".to_string(),
+        "if not %STATUS%==0 if not %STATUS%==3010 (".to_string(),
+        format!("    call :fail {what} failed with status %STATUS%"),
+        "    goto :done".to_string(),
+        ")".to_string(),
+    ]
+}
+
+/// Returns the script that services image `index` of the installation media
+/// with the packages staged as `packages`, reporting its progress on the
+/// serial port.
+fn service_script(index: u32, packages: &[String]) -> String {
+    let scratch = SCRATCH_DRIVE;
+    let dism = |args: &str| {
+        format!("dism /English {args} /ScratchDir:{scratch}\\Scratch")
+    };
+    let mut script = vec![
+        "@echo off".to_string(),
+        "rem Generated by wimsy to slipstream updates into the Windows image."
+            .to_string(),
+        "setlocal".to_string(),
+        "mode COM1 BAUD=115200 PARITY=n DATA=8 >NUL".to_string(),
+        "set LOG=\\\\.\\COM1".to_string(),
+        "set WIMSY=%1:".to_string(),
+        "call :log Preparing the scratch disk".to_string(),
+    ];
+    script.extend(checked(
+        &format!("diskpart /s %WIMSY%\\{DISKPART_SCRIPT}"),
+        "preparing the scratch disk",
+    ));
+    script.extend([
+        format!("mkdir {scratch}\\Mount {scratch}\\Scratch"),
+        "set SOURCE=".to_string(),
+        "for %%d in (C D E F G H I J K L M N O P Q R S T U V X Y Z) do ("
+            .to_string(),
+        "    if not defined SOURCE if exist %%d:\\sources\\install.wim set \
+        SOURCE=%%d:\\sources\\install.wim"
+            .to_string(),
+        "    if not defined SOURCE if exist %%d:\\sources\\install.esd set \
+        SOURCE=%%d:\\sources\\install.esd"
+            .to_string(),
+        ")".to_string(),
+        "if not defined SOURCE (".to_string(),
+        "    call :fail no drive has sources\\install.wim or \
+        sources\\install.esd"
+            .to_string(),
+        "    goto :done".to_string(),
+        ")".to_string(),
+        format!("call :log Exporting image {index} from %SOURCE%"),
+    ]);
+    script.extend(checked(
+        &dism(&format!(
+            "/Export-Image /SourceImageFile:%SOURCE% /SourceIndex:{index} \
+            /DestinationImageFile:{scratch}\\install.wim /Compress:max \
+            /CheckIntegrity"
+        )),
+        &format!("exporting image {index}"),
+    ));
+    script.extend(checked(
+        &dism(&format!(
+            "/Mount-Image /ImageFile:{scratch}\\install.wim /Index:1 \
+            /MountDir:{scratch}\\Mount"
+        )),
+        "mounting the image",
+    ));
+    for package in packages {
+        script.push(format!("call :log Adding {package}"));
+        script.extend(checked(
+            &dism(&format!(
+                "/Image:{scratch}\\Mount /Add-Package \
+                /PackagePath:\"%WIMSY%\\{UPDATES_DIR}\\{package}\""
+            )),
+            &format!("adding {package}"),
+        ));
+    }
+    script.push("call :log Saving the serviced image".to_string());
+    script.extend(checked(
+        &dism(&format!("/Unmount-Image /MountDir:{scratch}\\Mount /Commit")),
+        "saving the serviced image",
+    ));
+    script.extend(checked(
+        &dism(&format!(
+            "/Split-Image /ImageFile:{scratch}\\install.wim \
+            /SWMFile:{scratch}\\install.swm /FileSize:{SWM_PART_MIB}"
+        )),
+        "splitting the serviced image",
+    ));
+    script.extend([
+        "call :log Serviced the image".to_string(),
+        ":done".to_string(),
+        "wpeutil shutdown".to_string(),
+        "goto :eof".to_string(),
+        ":log".to_string(),
+        "echo %* >%LOG%".to_string(),
+        "goto :eof".to_string(),
+        ":fail".to_string(),
+        format!("echo {} %* >%LOG%", crate::monitor::GUEST_FAILURE_MARKER),
+        "goto :eof".to_string(),
+        String::new(),
+    ]);
+    script.join("\r\n")
+}
+
+/// The files a servicing run reads and writes.
+pub struct Servicing {
+    /// The disc the servicing VM reads the packages and its script from.
+    pub updates_iso: Utf8PathBuf,
+
+    /// The disk the servicing VM writes the serviced image to.
+    pub scratch_disk: Utf8PathBuf,
+}
+
+impl Servicing {
+    pub fn new(ctx: &Context) -> Self {
+        Self {
+            updates_iso: crate::workspace::iso_path(ctx, UPDATES_ISO_NAME),
+            scratch_disk: crate::workspace::scratch_path(
+                ctx,
+                SCRATCH_DISK_NAME,
+            ),
+        }
+    }
+}
+
+/// Returns the packages listed in the context's updates variable.
+fn packages(ctx: &Context) -> Vec<&Utf8Path> {
+    ctx.get_var(UPDATES_VAR)
+        .map(|var| var.lines().map(Utf8Path::new).collect())
+        .unwrap_or_default()
+}
+
+/// Writes the servicing VM's disc, holding the packages listed in the
+/// context's updates variable, its answer file, and its scripts, and creates
+/// its empty scratch disk. Returns `None` if there are no packages to add.
+pub fn prepare_servicing(
+    ctx: &Context,
+    ui: &dyn Ui,
+) -> Result<Option<Servicing>> {
+    let packages = packages(ctx);
+    if packages.is_empty() {
+        return Ok(None);
+    }
+
+    let index: u32 = ctx
+        .get_var("unattend_image_index")
+        .context("no image was selected to service")?
+        .parse()
+        .context("parsing unattend_image_index")?;
+    let staging_dir = crate::workspace::scratch_path(ctx, STAGING_DIR_NAME);
+    let updates_dir = staging_dir.join(UPDATES_DIR);
+    std::fs::create_dir_all(&updates_dir)
+        .with_context(|| format!("creating '{updates_dir}'"))?;
+
+    let mut staged = Vec::new();
+    for (i, path) in packages.iter().enumerate() {
+        let name = staged_name(i, path);
+        ui.set_substep(&format!("staging {path} as {name}"));
+        trace::debug!(
+            "staging update package",
+            source = path.as_str(),
+            name = name.as_str()
+        );
+        let dst = updates_dir.join(&name);
+        std::fs::copy(path, &dst)
+            .with_context(|| format!("copying '{path}' to '{dst}'"))?;
+        staged.push(name);
+    }
+
+    let architecture = crate::steps::architecture(ctx).windows_name();
+    let locale = ctx.get_var("locale").unwrap_or("en-US");
+    for (name, contents) in [
+        ("Autounattend.xml", answer_file(architecture, locale)),
+        (DISKPART_SCRIPT, diskpart_script()),
+        (SERVICE_SCRIPT, service_script(index, &staged)),
+    ] {
+        let path = staging_dir.join(name);
+        std::fs::write(&path, contents)
+            .with_context(|| format!("writing '{path}'"))?;
+    }
+
+    let servicing = Servicing::new(ctx);
+    ui.set_substep(&format!("writing {}", servicing.updates_iso));
+    crate::iso::create_iso(&staging_dir, &servicing.updates_iso, "UPDATES")?;
+
+    let disk = &servicing.scratch_disk;
+    let file = std::fs::File::create(disk)
+        .with_context(|| format!("creating '{disk}'"))?;
+    file.set_len(SCRATCH_DISK_BYTES)
+        .with_context(|| format!("resizing '{disk}'"))?;
+
+    ui.record_metric("update_packages", Json::from(staged));
+    Ok(Some(servicing))
+}
+
+/// Copies the parts of the split image the servicing VM wrote to the scratch
+/// disk `disk` into `dest_dir`, returning how many there were.
+fn copy_split_image(disk: &Utf8Path, dest_dir: &Utf8Path) -> Result<u32> {
+    let table = crate::gpt::read_image(disk)?;
+    let partition = table
+        .partitions
+        .iter()
+        .find(|p| p.type_guid == Guid::BASIC_DATA)
+        .with_context(|| {
+            format!("the servicing VM didn't create a volume on '{disk}'")
+        })?;
+    let file = std::fs::File::open(disk)
+        .with_context(|| format!("opening '{disk}'"))?;
+    let offset = partition.first_lba * table.sector_size;
+    let mut volume = Volume::open(std::io::BufReader::new(file), offset)
+        .with_context(|| format!("opening the volume on '{disk}'"))?;
+
+    let mut parts = 0;
+    for part in 1..=MAX_SWM_PARTS {
+        let name = match part {
+            1 => "install.swm".to_string(),
+            n => format!("install{n}.swm"),
+        };
+        if !volume.contains(&name)? {
+            break;
+        }
+
+        let dest = dest_dir.join(&name);
+        let mut out = std::io::BufWriter::new(
+            std::fs::File::create(&dest)
+                .with_context(|| format!("creating '{dest}'"))?,
+        );
+        volume
+            .copy_file(&name, &mut out)
+            .with_context(|| format!("copying {name} to '{dest}'"))?;
+        std::io::Write::flush(&mut out)
+            .with_context(|| format!("writing '{dest}'"))?;
+        parts = part;
+    }
+
+    if parts == 0 {
+        anyhow::bail!(
+            "the servicing VM powered off without writing a serviced image; \
+            see the serial log for what it did"
+        );
+    }
+    Ok(parts)
+}
+
+/// Returns the `xorriso` command that writes a bootable ISO of `media_dir` to
+/// `output`, booting from the same files that Windows installation media
+/// boot from.
+fn repack_command(media_dir: &Utf8Path, output: &Utf8Path) -> Command {
+    let mut cmd = Command::new("xorriso");
+    cmd.args([
+        "-as",
+        "mkisofs",
+        "-iso-level",
+        "3",
+        "-full-iso9660-filenames",
+        "-J",
+        "-joliet-long",
+        "-V",
+        "WIMSY_SERVICED",
+    ]);
+    if media_dir.join("boot/etfsboot.com").is_file() {
+        cmd.args([
+            "-b",
+            "boot/etfsboot.com",
+            "-no-emul-boot",
+            "-boot-load-size",
+            "8",
+            "-eltorito-alt-boot",
+        ]);
+    }
+    cmd.args(["-e", "efi/microsoft/boot/efisys.bin", "-no-emul-boot", "-o"]);
+    cmd.arg(output).arg(media_dir);
+    cmd
+}
+
+/// Builds the serviced ISO from the split image on the scratch disk and the
+/// rest of the original ISO's files, and points the `windows_iso` and
+/// `unattend_image_index` variables at it and the serviced image.
+pub fn finish_servicing(
+    ctx: &mut Context,
+    ui: &dyn Ui,
+    servicing: &Servicing,
+) -> Result<()> {
+    let iso = Utf8PathBuf::from(ctx.get_var("windows_iso").unwrap());
+    let media_dir = crate::workspace::scratch_path(ctx, MEDIA_DIR_NAME);
+    if media_dir.exists() {
+        std::fs::remove_dir_all(&media_dir)
+            .with_context(|| format!("removing '{media_dir}'"))?;
+    }
+
+    let mut media = InstallMedia::open(&iso)?;
+    let root = media.root().clone();
+    ui.set_substep(&format!("extracting {iso} ({})", media.filesystem()));
+    media.extract_tree(&root, &media_dir, &|path| {
+        crate::media::INSTALL_IMAGE_PATHS
+            .iter()
+            .any(|image| path.eq_ignore_ascii_case(image))
+    })?;
+    if !media_dir.join("efi/microsoft/boot/efisys.bin").is_file() {
+        anyhow::bail!(
+            "'{iso}' has no efi/microsoft/boot/efisys.bin to boot the \
+            serviced ISO from"
+        );
+    }
+
+    ui.set_substep(&format!(
+        "copying the serviced image from {}",
+        servicing.scratch_disk
+    ));
+    let parts =
+        copy_split_image(&servicing.scratch_disk, &media_dir.join("sources"))?;
+    std::fs::remove_file(&servicing.scratch_disk)
+        .with_context(|| format!("removing '{}'", servicing.scratch_disk))?;
+
+    let serviced = crate::workspace::iso_path(ctx, SERVICED_ISO_NAME);
+    ui.set_substep(&format!("writing {serviced}"));
+    run_command_check_status(&mut repack_command(&media_dir, &serviced), ui)?;
+    std::fs::remove_dir_all(&media_dir)
+        .with_context(|| format!("removing '{media_dir}'"))?;
+
+    ui.record_metric("serviced_image_parts", Json::from(parts));
+    ui.record_metric("serviced_iso", Json::from(serviced.as_str()));
+    ctx.set_var("windows_iso", serviced.to_string());
+    ctx.set_var("unattend_image_index", "1".to_string());
+    Ok(())
+}
+
+/// Describes a servicing run whose VM is launched by `vm_command`, for
+/// `--dry-run`.
+pub fn describe_servicing(
+    ctx: &mut Context,
+    vm_command: String,
+) -> Vec<String> {
+    let packages = packages(ctx);
+    let iso = ctx.get_var("windows_iso").unwrap();
+    let servicing = Servicing::new(ctx);
+    let serviced = crate::workspace::iso_path(ctx, SERVICED_ISO_NAME);
+    let media_dir = crate::workspace::scratch_path(ctx, MEDIA_DIR_NAME);
+    let mut descriptions: Vec<_> =
+        packages.iter().map(|path| format!("stage update {path}")).collect();
+    descriptions.extend([
+        format!("write the servicing VM's disc to {}", servicing.updates_iso),
+        format!(
+            "create a {SCRATCH_DISK_BYTES}-byte scratch disk at {}",
+            servicing.scratch_disk
+        ),
+        vm_command,
+        format!("extract {iso} without its install image"),
+        crate::util::format_command(&repack_command(&media_dir, &serviced)),
+    ]);
+    ctx.set_var("windows_iso", serviced.to_string());
+    ctx.set_var("unattend_image_index", "1".to_string());
+    descriptions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_servicing_scripts() {
+        assert_eq!(
+            staged_name(0, Utf8Path::new("/u/windows10.0-kb50 (1)&.msu")),
+            "01-windows10.0-kb50__1__.msu"
+        );
+
+        let staged = ["01-ssu.msu".to_string(), "02-lcu.msu".to_string()];
+        let script = service_script(4, &staged);
+        assert!(script.contains(
+            "dism /English /Export-Image /SourceImageFile:%SOURCE% \
+            /SourceIndex:4 /DestinationImageFile:W:\\install.wim"
+        ));
+        let ssu = script.find("call :log Adding 01-ssu.msu").unwrap();
+        let lcu = script.find("call :log Adding 02-lcu.msu").unwrap();
+        let commit = script.find("/Unmount-Image").unwrap();
+        assert!(ssu < lcu && lcu < commit);
+        assert!(script.contains(
+            "/PackagePath:\"%WIMSY%\\Updates\\02-lcu.msu\" \
+            /ScratchDir:W:\\Scratch >%LOG%"
+        ));
+        assert!(script.contains("echo WIMSY-FAILURE: %* >%LOG%"));
+        assert!(script.contains("\r\n"));
+
+        let xml = answer_file("amd64", "en-GB");
+        assert!(xml.contains("%i:\\WimsyService.cmd %i\"</Path>"));
+        assert!(xml.contains("<UILanguage>en-GB</UILanguage>"));
+        xml::reader::EventReader::new(xml.as_bytes()).into_iter().for_each(
+            |event| {
+                event.unwrap();
+            },
+        );
+
+        let packages = [Utf8PathBuf::from("/nonexistent/lcu.exe")];
+        let errors = check_prerequisites(&packages, false);
+        assert!(errors.len() >= 3, "{errors:?}");
+        assert!(errors.iter().any(|e| e.contains(".msu or .cab")));
+    }
+}
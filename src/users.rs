@@ -0,0 +1,218 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Local user accounts declared in the configuration file's `[[users]]`
+//! tables, which the repo's specialize-unattend.xml creates in the oobeSystem
+//! pass alongside (or instead of) the built-in Administrator account.
+//!
+//! As with `--admin-password`, only each password's source is kept in the
+//! script context. The passwords are read, and the `LocalAccounts` element
+//! built, when the unattend files are rendered.
+
+use anyhow::Result;
+
+use crate::{
+    secrets::{encode_unattend_password, SecretSource},
+    template::xml_escape,
+    ui::Ui,
+};
+
+/// The context variable listing the users to create.
+pub const USERS_VAR: &str = "local_users";
+
+/// The template variable holding the rendered `LocalAccounts` element.
+pub const LOCAL_ACCOUNTS_VAR: &str = "local_accounts";
+
+/// The group users join if their table doesn't list any.
+pub const DEFAULT_GROUP: &str = "Users";
+
+/// How long Windows lets local account names be.
+const MAX_NAME_LEN: usize = 20;
+
+/// Accounts every image already has, which setup can't create again.
+const BUILT_IN_ACCOUNTS: [&str; 4] =
+    ["Administrator", "Guest", "DefaultAccount", "WDAGUtilityAccount"];
+
+/// A local user account to create in built images.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalUser {
+    pub name: String,
+    pub password: SecretSource,
+
+    /// The local groups the account joins, e.g. `Administrators`.
+    pub groups: Vec<String>,
+}
+
+impl LocalUser {
+    pub fn new(
+        name: &str,
+        password: SecretSource,
+        groups: Vec<String>,
+    ) -> Result<Self, String> {
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(format!(
+                "'{name}' must be from 1 to {MAX_NAME_LEN} characters long"
+            ));
+        }
+        if name.contains(|c: char| "\"/\\[]:;|=,+*?<>@".contains(c))
+            || name.contains(char::is_control)
+            || name.chars().all(|c| c == '.' || c == ' ')
+        {
+            return Err(format!("'{name}' isn't a valid account name"));
+        }
+        if let Some(built_in) = BUILT_IN_ACCOUNTS
+            .iter()
+            .find(|built_in| built_in.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("'{name}' is the built-in {built_in} account"));
+        }
+
+        let groups = if groups.is_empty() {
+            vec![DEFAULT_GROUP.to_string()]
+        } else {
+            groups
+        };
+        if let Some(group) =
+            groups.iter().find(|g| g.is_empty() || g.contains([';', '\t']))
+        {
+            return Err(format!("'{group}' isn't a valid group name"));
+        }
+
+        Ok(Self { name: name.to_string(), password, groups })
+    }
+}
+
+/// Returns the encoding of `users` in the users variable.
+pub fn users_var(users: &[LocalUser]) -> String {
+    itertools::join(
+        users.iter().map(|user| {
+            format!(
+                "{}\t{}\t{}",
+                user.name,
+                user.password,
+                user.groups.join(";")
+            )
+        }),
+        "\n",
+    )
+}
+
+fn users_from_var(var: &str) -> Result<Vec<LocalUser>> {
+    var.lines()
+        .map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let (Some(name), Some(password), Some(groups)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("malformed local user '{line}'");
+            };
+            Ok(LocalUser {
+                name: name.to_string(),
+                password: password.parse()?,
+                groups: groups.split(';').map(str::to_string).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Checks that `users` have distinct names and that their passwords can be
+/// read, returning a message for each problem.
+pub fn check_prerequisites(users: &[LocalUser]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (i, user) in users.iter().enumerate() {
+        if users[..i]
+            .iter()
+            .any(|other| other.name.eq_ignore_ascii_case(&user.name))
+        {
+            errors.push(format!(
+                "the local user '{}' is declared twice",
+                user.name
+            ));
+        }
+        if let Err(e) = user
+            .password
+            .check(&format!("password for local user '{}'", user.name))
+        {
+            errors.push(format!("{e:#}"));
+        }
+    }
+
+    errors
+}
+
+/// Builds the `LocalAccounts` element that creates `users`, with passwords
+/// given by `password`.
+fn local_accounts_xml(
+    users: &[LocalUser],
+    mut password: impl FnMut(&LocalUser) -> Result<String>,
+) -> Result<String> {
+    let mut accounts = String::new();
+    for user in users {
+        accounts.push_str(&format!(
+            "<LocalAccount wcm:action=\"add\"><Password><Value>{}</Value>\
+            <PlainText>false</PlainText></Password><Group>{}</Group>\
+            <Name>{}</Name></LocalAccount>",
+            encode_unattend_password(&password(user)?, "Password"),
+            xml_escape(&user.groups.join(";")),
+            xml_escape(&user.name),
+        ));
+    }
+
+    Ok(format!("<LocalAccounts>{accounts}</LocalAccounts>"))
+}
+
+/// Returns the `LocalAccounts` element for the users listed in the users
+/// variable `var`, reading their passwords from their sources (prompting via
+/// `ui` if need be).
+pub fn local_accounts(var: &str, ui: &dyn Ui) -> Result<String> {
+    let users = users_from_var(var)?;
+    local_accounts_xml(&users, |user| {
+        user.password
+            .resolve(&format!("password for local user '{}'", user.name), ui)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_local_accounts() {
+        let password = || SecretSource::Env("DEPLOY_PASSWORD".to_string());
+        let deploy = LocalUser::new(
+            "deploy",
+            password(),
+            vec!["Administrators".to_string(), "Remote Desktop Users".into()],
+        )
+        .unwrap();
+        let ops = LocalUser::new("ops.team", password(), Vec::new()).unwrap();
+        assert_eq!(ops.groups, [DEFAULT_GROUP]);
+
+        for name in ["", "a-very-long-user-name", "ops/team", "...", "guest"] {
+            assert!(LocalUser::new(name, password(), Vec::new()).is_err());
+        }
+        assert!(LocalUser::new("ops", password(), vec!["a;b".into()]).is_err());
+
+        let users = [deploy, ops];
+        let var = users_var(&users);
+        assert_eq!(users_from_var(&var).unwrap(), users);
+
+        let xml = local_accounts_xml(&users, |_| Ok("hunter2".into())).unwrap();
+        assert!(xml.starts_with("<LocalAccounts><LocalAccount"));
+        assert!(xml.contains(&format!(
+            "<Value>{}</Value>",
+            encode_unattend_password("hunter2", "Password")
+        )));
+        assert!(xml.contains(
+            "<Group>Administrators;Remote Desktop Users</Group>\
+            <Name>deploy</Name>"
+        ));
+        assert!(xml.contains("<Group>Users</Group><Name>ops.team</Name>"));
+
+        let twice = [users[0].clone(), users[0].clone()];
+        assert!(check_prerequisites(&twice)
+            .iter()
+            .any(|e| e.contains("declared twice")));
+    }
+}
@@ -8,12 +8,13 @@
 use std::{
     collections::BTreeSet,
     io::Write,
-    process::{Command, Output},
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus, Output},
 };
 
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 
-use crate::{runner::ScriptStep, ui::Ui};
+use crate::{command::CommandRunner, runner::Step, trace, ui::Ui};
 
 /// Runs a `Command` and returns its output. Returns `Err` if the command's exit
 /// status indicates that it failed.
@@ -24,8 +25,23 @@ pub fn run_command_check_status(
     cmd: &mut Command,
     ui: &dyn Ui,
 ) -> anyhow::Result<Output> {
-    ui.set_substep(&format!("{} {:?}", "executing: ", cmd));
-    let output = cmd.output()?;
+    let _span = command_span(cmd);
+    ui.command_started(cmd);
+    let output = match ui.command_runner().output(cmd) {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let program = cmd.get_program().to_string_lossy();
+            return Err(anyhow::Error::new(e)
+                .context(crate::error::missing_tools(&[&program])));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    trace::debug!(
+        "command exited",
+        status = output.status.to_string(),
+        stdout_bytes = output.stdout.len(),
+        stderr_bytes = output.stderr.len()
+    );
 
     let process_name = cmd.get_program();
     ui.child_stdout(&process_name.to_string_lossy())?
@@ -44,38 +60,83 @@ pub fn run_command_check_status(
     Ok(output)
 }
 
-/// Runs the supplied `cmd` and searches its `stdout` for the first line
-/// containing `row_contains`, then splits it by whitespace and returns the
-/// `column`th zero-indexed word from that line.
-///
-/// Note that all searches are case-sensitive.
-pub fn grep_command_for_row_and_column(
-    cmd: &mut Command,
-    row_contains: &str,
-    column: usize,
-    ui: &dyn Ui,
-) -> anyhow::Result<String> {
-    let output = run_command_check_status(cmd, ui)?.stdout;
-    let output = String::from_utf8_lossy(&output);
-    for line in output.lines() {
-        if !line.contains(row_contains) {
-            continue;
+/// Formats `bytes` in the largest binary unit it fills at least one of.
+pub fn format_size(bytes: u64) -> String {
+    for (unit, size) in
+        [("GiB", 1u64 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)]
+    {
+        if bytes >= size {
+            return if bytes.is_multiple_of(size) {
+                format!("{} {unit}", bytes / size)
+            } else {
+                format!("{:.1} {unit}", bytes as f64 / size as f64)
+            };
         }
+    }
+    format!("{bytes} bytes")
+}
+
+/// Formats `cmd` as a shell command line, quoting the program and arguments
+/// that need it. Describes commands in `--dry-run` output.
+pub fn format_command(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|word| shell_quote(&word.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-        return Ok(line
-            .split_whitespace()
-            .nth(column)
-            .ok_or(anyhow::anyhow!(
-                "matching line '{line}' does not have column index {column}"
-            ))?
-            .to_owned());
+/// Quotes `word` for a POSIX shell if it contains anything but characters
+/// that are always safe. Placeholders like `<partition>` are left as they
+/// are so that they stand out.
+pub fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    let placeholder = word.starts_with('<')
+        && word.ends_with('>')
+        && word[1..word.len() - 1].chars().all(|c| safe(c) || c == ' ');
+    if (!word.is_empty() && word.chars().all(safe)) || placeholder {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
     }
+}
 
-    anyhow::bail!(
-        "'{row_contains}' not found in output of {} (output: {:?})",
-        cmd.get_program().to_string_lossy(),
-        output
+/// Enters a trace span describing the external command `cmd`. Callers that
+/// launch commands without [`run_command_check_status`] should hold one of
+/// these while the command runs.
+pub fn command_span(cmd: &Command) -> trace::Span {
+    let span = trace::span!(
+        trace::Level::Debug,
+        "command",
+        program = cmd.get_program().to_string_lossy().into_owned()
     );
+
+    // Record the arguments once here instead of as a span field so that they
+    // aren't repeated in every event the command's span contains.
+    let args: Vec<String> =
+        cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+    trace::debug!("launching command", args = args);
+    span
+}
+
+/// Describes how a virtual machine process with the supplied `name` exited.
+/// If the process was killed by a signal, names the signal and, for
+/// `SIGKILL`, suggests that the host may have run out of memory, since that
+/// is the usual reason a VMM dies that way.
+pub fn describe_vm_exit(name: &str, status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("{name} exited with status {code}"),
+        (None, Some(libc::SIGKILL)) => format!(
+            "{name} was killed by SIGKILL; if nothing else stopped it, the \
+            host probably ran out of memory and the kernel's out-of-memory \
+            killer ended it (check the system log or `dmesg` for OOM \
+            messages, and consider giving the VM less memory)"
+        ),
+        (None, Some(signal)) => {
+            format!("{name} was killed by signal {signal}")
+        }
+        (None, None) => format!("{name} exited abnormally: {status:?}"),
+    }
 }
 
 /// Checks each file in `files` to make sure that it exists and is a file.
@@ -94,23 +155,154 @@ pub fn check_file_prerequisites(files: &[Utf8PathBuf]) -> Vec<String> {
     errors
 }
 
-pub fn check_executable_prerequisites(steps: &[ScriptStep]) -> Vec<String> {
-    let mut errors = Vec::new();
-    let mut executables = BTreeSet::new();
+/// Returns the path from which the unattend file `filename` will be read
+/// from `unattend_dir`: the file's template if one is present, or the file
+/// itself otherwise.
+pub fn unattend_source_path(
+    unattend_dir: &Utf8Path,
+    filename: &str,
+) -> Utf8PathBuf {
+    let template = unattend_dir
+        .join(format!("{filename}{}", crate::template::TEMPLATE_SUFFIX));
+    if template.exists() {
+        trace::debug!("using unattend template", path = template.as_str());
+        template
+    } else {
+        unattend_dir.join(filename)
+    }
+}
+
+/// Checks that each command the supplied `steps` expect to run, other than
+/// those `runner` runs elsewhere, can be found on the `PATH`, along with the
+/// commands `runner` needs itself. Returns the names of any that can't.
+pub fn check_executable_prerequisites(
+    steps: &[&dyn Step],
+    runner: &dyn CommandRunner,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    let mut executables: BTreeSet<&str> =
+        runner.host_tools().into_iter().collect();
     for step in steps {
-        for dep in step.prereq_commands() {
-            executables.insert(dep);
+        for dep in step.required_tools() {
+            if !runner.runs_elsewhere(dep) {
+                executables.insert(dep);
+            }
         }
     }
 
     for dep in executables {
-        if let Err(e) = which::which(dep) {
-            errors.push(format!(
-                "binary or command '{}' not found (is it on your PATH?): {}",
-                dep, e
-            ));
+        if which::which(dep).is_err() {
+            missing.push(dep.to_string());
         }
     }
 
-    errors
+    missing
+}
+
+/// Decodes standard (RFC 4648) base64, ignoring whitespace.
+pub fn decode_base64(text: &str) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    let mut padding = 0;
+    for c in text.chars().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            '=' => {
+                padding += 1;
+                continue;
+            }
+            _ => anyhow::bail!("invalid base64 character '{c}'"),
+        };
+
+        if padding > 0 {
+            anyhow::bail!("base64 data continues after padding");
+        }
+
+        accumulator = (accumulator << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((accumulator >> bits) as u8);
+            accumulator &= (1 << bits) - 1;
+        }
+    }
+
+    if padding > 2 || bits >= 6 {
+        anyhow::bail!("base64 data has an invalid length");
+    }
+
+    Ok(out)
+}
+
+/// Encodes `data` as standard (RFC 4648) base64 with padding.
+pub fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                out.push(ALPHABET[index as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Quotes `s` as a single-quoted PowerShell string.
+pub fn powershell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_commands() {
+        let mut cmd = Command::new("qemu-img");
+        cmd.args(["resize", "-f", "raw", "/tmp/my image.img", "<new size>"]);
+        assert_eq!(
+            format_command(&cmd),
+            "qemu-img resize -f raw '/tmp/my image.img' <new size>"
+        );
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'hi' > $OUT", ""]);
+        assert_eq!(format_command(&cmd), "sh -c 'echo '\\''hi'\\'' > $OUT' ''");
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8h").unwrap(), b"hello!");
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert!(decode_base64("aGk*").is_err());
+        assert!(decode_base64("a").is_err());
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"hi"), "aGk=");
+        assert_eq!(encode_base64(b"hello"), "aGVsbG8=");
+        assert_eq!(encode_base64(b"hello!"), "aGVsbG8h");
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode_base64(&encode_base64(&data)).unwrap(), data);
+    }
 }
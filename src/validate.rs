@@ -0,0 +1,465 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks the final Autounattend.xml for mistakes that would make Windows
+//! Setup stop and wait for input.
+//!
+//! Setup doesn't report problems with an answer file anywhere the build can
+//! see: it shows a dialog on the VM's console and waits, and the build only
+//! notices when the installation times out an hour later. The checks here
+//! catch the common causes of that (a missing or misspelled pass, an answer
+//! file that doesn't accept the EULA or say where to install, and so on)
+//! before the installation VM starts. They don't attempt to validate the
+//! answer file against Microsoft's schema.
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{autounattend::PASSES, runner::Context, ui::Ui};
+
+const UNATTEND_NAMESPACE: &str = "urn:schemas-microsoft-com:unattend";
+
+/// An element of a parsed answer file.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    namespace: Option<String>,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Follows `path` through the first child with each name.
+    fn find(&self, path: &[&str]) -> Option<&Element> {
+        path.iter().try_fold(self, |element, name| element.child(name))
+    }
+
+    /// Yields this element and all of its descendants named `name`.
+    fn descendants<'a>(&'a self, name: &'a str) -> Vec<&'a Element> {
+        let mut found = Vec::new();
+        if self.name == name {
+            found.push(self);
+        }
+
+        for child in &self.children {
+            found.extend(child.descendants(name));
+        }
+
+        found
+    }
+
+    fn component(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| {
+            child.name == "component" && child.attribute("name") == Some(name)
+        })
+    }
+}
+
+fn parse(xml: &str) -> Result<Element> {
+    use xml::reader::XmlEvent;
+
+    let mut stack: Vec<Element> = Vec::new();
+    for event in xml::EventReader::new(xml.as_bytes()) {
+        match event? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    namespace: name.namespace,
+                    attributes: attributes
+                        .into_iter()
+                        .map(|attr| (attr.name.local_name, attr.value))
+                        .collect(),
+                    ..Default::default()
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    anyhow::bail!("the document has no root element")
+}
+
+/// The problems found in an answer file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Findings {
+    /// Problems that will make Setup stop or fail.
+    pub errors: Vec<String>,
+
+    /// Settings that are probably mistakes but might be intended.
+    pub warnings: Vec<String>,
+}
+
+/// Checks the answer file `xml`, which will be used to install `version`
+/// of Windows, if known, for `arch`.
+pub fn check_autounattend(
+    xml: &str,
+    version: Option<crate::autounattend::WindowsVersion>,
+    arch: crate::autounattend::Architecture,
+) -> Findings {
+    let mut findings = Findings::default();
+    let root = match parse(xml) {
+        Ok(root) => root,
+        Err(e) => {
+            findings.errors.push(format!("isn't well-formed XML: {e}"));
+            return findings;
+        }
+    };
+
+    if root.name != "unattend"
+        || root.namespace.as_deref() != Some(UNATTEND_NAMESPACE)
+    {
+        findings.errors.push(format!(
+            "the root element should be <unattend \
+            xmlns=\"{UNATTEND_NAMESPACE}\">"
+        ));
+        return findings;
+    }
+
+    let mut seen = Vec::new();
+    for settings in root.children.iter().filter(|c| c.name == "settings") {
+        let Some(pass) = settings.attribute("pass") else {
+            findings.errors.push("a <settings> element has no pass".into());
+            continue;
+        };
+
+        if !PASSES.contains(&pass) {
+            findings.errors.push(format!(
+                "'{pass}' isn't a configuration pass (expected one of {})",
+                PASSES.join(", ")
+            ));
+        } else if seen.contains(&pass) {
+            findings
+                .errors
+                .push(format!("the {pass} pass is configured more than once"));
+        }
+        seen.push(pass);
+
+        for component in settings.children.iter() {
+            check_component(pass, component, arch, &mut findings);
+        }
+    }
+
+    let Some(windows_pe) = root.children.iter().find(|c| {
+        c.name == "settings" && c.attribute("pass") == Some("windowsPE")
+    }) else {
+        findings.errors.push(
+            "there's no windowsPE pass, so Setup won't run unattended".into(),
+        );
+        return findings;
+    };
+
+    check_windows_pe(windows_pe, &mut findings);
+    if let Some(version) = version {
+        check_driver_paths(&root, version, &mut findings);
+    }
+
+    findings
+}
+
+fn check_component(
+    pass: &str,
+    component: &Element,
+    arch: crate::autounattend::Architecture,
+    findings: &mut Findings,
+) {
+    if component.name != "component" {
+        return;
+    }
+
+    let Some(name) = component.attribute("name") else {
+        findings
+            .errors
+            .push(format!("a component in the {pass} pass has no name"));
+        return;
+    };
+
+    match component.attribute("processorArchitecture") {
+        None => findings.errors.push(format!(
+            "component {name} in the {pass} pass has no \
+            processorArchitecture, so Setup will ignore it"
+        )),
+        Some(found) if found == arch.windows_name() => {}
+        Some(found) => findings.warnings.push(format!(
+            "component {name} in the {pass} pass is for {found}, not {}, so \
+            Setup will ignore it",
+            arch.windows_name()
+        )),
+    }
+}
+
+fn check_windows_pe(pass: &Element, findings: &mut Findings) {
+    let international =
+        pass.component("Microsoft-Windows-International-Core-WinPE");
+    if international
+        .and_then(|c| c.find(&["SetupUILanguage", "UILanguage"]))
+        .is_none()
+    {
+        findings.errors.push(
+            "the windowsPE pass doesn't set \
+            Microsoft-Windows-International-Core-WinPE's \
+            SetupUILanguage/UILanguage, so Setup will ask for a language"
+                .into(),
+        );
+    }
+
+    let Some(setup) = pass.component("Microsoft-Windows-Setup") else {
+        findings.errors.push(
+            "the windowsPE pass has no Microsoft-Windows-Setup component, so \
+            Setup will ask what to install and where"
+                .into(),
+        );
+        return;
+    };
+
+    let eula = setup.find(&["UserData", "AcceptEula"]);
+    if !eula.is_some_and(|e| e.text.trim().eq_ignore_ascii_case("true")) {
+        findings.errors.push(
+            "Microsoft-Windows-Setup doesn't set UserData/AcceptEula to true, \
+            so Setup will show the license terms"
+                .into(),
+        );
+    }
+
+    if setup
+        .find(&["UserData", "ProductKey", "WillShowUI"])
+        .is_some_and(|e| e.text.trim() == "Always")
+    {
+        findings.errors.push(
+            "Microsoft-Windows-Setup's ProductKey/WillShowUI is Always, so \
+            Setup will ask for a product key"
+                .into(),
+        );
+    }
+
+    let Some(image) = setup.find(&["ImageInstall", "OSImage"]) else {
+        findings.errors.push(
+            "Microsoft-Windows-Setup has no ImageInstall/OSImage, so Setup \
+            will ask where to install Windows"
+                .into(),
+        );
+        return;
+    };
+
+    let install_anywhere = image
+        .child("InstallToAvailablePartition")
+        .is_some_and(|e| e.text.trim().eq_ignore_ascii_case("true"));
+    if image.child("InstallTo").is_none() && !install_anywhere {
+        findings.errors.push(
+            "ImageInstall/OSImage has neither InstallTo nor \
+            InstallToAvailablePartition, so Setup will ask where to install \
+            Windows"
+                .into(),
+        );
+    }
+
+    for metadata in image.descendants("MetaData") {
+        let key = metadata.child("Key").map(|k| k.text.trim());
+        let value = metadata.child("Value").map(|v| v.text.trim());
+        if let (Some("/IMAGE/INDEX"), Some(value)) = (key, value) {
+            if !value.parse::<u32>().is_ok_and(|index| index > 0) {
+                findings.errors.push(format!(
+                    "InstallFrom's /IMAGE/INDEX is '{value}', which isn't an \
+                    image index"
+                ));
+            }
+        }
+    }
+}
+
+/// Warns about virtio driver paths for a different Windows version than the
+/// one being installed.
+fn check_driver_paths(
+    root: &Element,
+    version: crate::autounattend::WindowsVersion,
+    findings: &mut Findings,
+) {
+    let expected = version.as_driver_path_component();
+    for path in root.descendants("Path") {
+        let path = path.text.trim();
+        let other = path.split('\\').find(|segment| {
+            let digits = segment
+                .strip_prefix("2k")
+                .filter(|digits| digits.len() == 2)
+                .or_else(|| segment.strip_prefix('w').filter(|d| d.len() == 2));
+            digits.is_some_and(|d| d.chars().all(|c| c.is_ascii_digit()))
+                && *segment != expected
+        });
+        if let Some(other) = other {
+            findings.warnings.push(format!(
+                "driver path '{path}' is for {other}, but {version} \
+                ({expected}) is being installed"
+            ));
+        }
+    }
+}
+
+/// Checks the Autounattend.xml in the unattend directory, failing if it has
+/// problems that would stop Setup and warning about likely mistakes.
+pub fn validate_autounattend(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let path = Utf8Path::new(ctx.get_var("unattend_dir").unwrap())
+        .join("Autounattend.xml");
+    ui.set_substep(&format!("checking {path}"));
+    let xml = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading {path}"))?;
+    let findings = check_autounattend(
+        &xml,
+        crate::steps::windows_version(ctx),
+        crate::steps::architecture(ctx),
+    );
+    for warning in &findings.warnings {
+        ui.warn(&format!("Autounattend.xml: {warning}"));
+    }
+
+    if !findings.errors.is_empty() {
+        anyhow::bail!(
+            "Autounattend.xml would stop Windows Setup:\n  {}",
+            findings.errors.join("\n  ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::autounattend::{Architecture, WindowsVersion};
+
+    fn answer_file(windows_pe: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\
+            <unattend xmlns=\"{UNATTEND_NAMESPACE}\">\
+            <settings pass=\"windowsPE\">{windows_pe}</settings></unattend>"
+        )
+    }
+
+    const INTERNATIONAL: &str = "<component \
+        name=\"Microsoft-Windows-International-Core-WinPE\" \
+        processorArchitecture=\"amd64\"><SetupUILanguage>\
+        <UILanguage>en-US</UILanguage></SetupUILanguage></component>";
+
+    #[test]
+    fn accepts_the_repo_answer_files() {
+        let linux = crate::template::render(
+            include_str!("../unattend/Autounattend.xml.j2"),
+            &Default::default(),
+        )
+        .unwrap();
+        let illumos = include_str!("../illumos/Autounattend.xml");
+        for xml in [linux.as_str(), illumos] {
+            let version = Some(WindowsVersion::Server2022);
+            assert_eq!(
+                check_autounattend(xml, version, Architecture::X86_64),
+                Findings::default()
+            );
+        }
+
+        let findings = check_autounattend(
+            &linux,
+            Some(WindowsVersion::Server2019),
+            Architecture::X86_64,
+        );
+        assert!(findings.errors.is_empty());
+        assert_eq!(findings.warnings.len(), 6, "{:?}", findings.warnings);
+
+        for version in [WindowsVersion::Windows10, WindowsVersion::Windows11] {
+            let client = crate::template::render(
+                include_str!("../unattend/Autounattend.xml.j2"),
+                &[
+                    ("windows_client".to_string(), String::new()),
+                    ("windows_version".to_string(), format!("{version:?}")),
+                ]
+                .into(),
+            )
+            .unwrap();
+            assert_eq!(
+                client.contains("BypassTPMCheck"),
+                version == WindowsVersion::Windows11
+            );
+            let findings = check_autounattend(
+                &client,
+                Some(version),
+                Architecture::X86_64,
+            );
+            assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+            assert_eq!(findings.warnings.len(), 6, "{:?}", findings.warnings);
+        }
+
+        let arm64 = crate::template::render(
+            include_str!("../unattend/Autounattend.xml.j2"),
+            &[
+                ("processor_architecture".to_string(), "arm64".to_string()),
+                ("driver_arch".to_string(), "ARM64".to_string()),
+            ]
+            .into(),
+        )
+        .unwrap();
+        assert!(arm64.contains("D:\\NetKVM\\2k22\\ARM64"));
+        let version = Some(WindowsVersion::Server2022);
+        assert_eq!(
+            check_autounattend(&arm64, version, Architecture::Aarch64),
+            Findings::default()
+        );
+        let findings =
+            check_autounattend(&arm64, version, Architecture::X86_64);
+        assert!(findings.errors.is_empty(), "{:?}", findings.errors);
+        assert_eq!(findings.warnings.len(), 5, "{:?}", findings.warnings);
+    }
+
+    #[test]
+    fn finds_setup_blockers() {
+        let check = |xml: &str| {
+            check_autounattend(xml, None, Architecture::X86_64).errors
+        };
+        assert_eq!(check("<unattend>").len(), 1);
+        assert_eq!(check("<unattend/>").len(), 1);
+
+        let errors = check(&answer_file(""));
+        assert_eq!(errors.len(), 2, "{errors:?}");
+
+        let errors = check(&answer_file(&format!(
+            "{INTERNATIONAL}<component name=\"Microsoft-Windows-Setup\" \
+            processorArchitecture=\"amd64\"><UserData>\
+            <AcceptEula>false</AcceptEula></UserData><ImageInstall><OSImage>\
+            <InstallFrom><MetaData><Key>/IMAGE/INDEX</Key><Value>0</Value>\
+            </MetaData></InstallFrom></OSImage></ImageInstall></component>"
+        )));
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].contains("AcceptEula"), "{errors:?}");
+        assert!(errors[1].contains("neither InstallTo"), "{errors:?}");
+        assert!(errors[2].contains("/IMAGE/INDEX"), "{errors:?}");
+
+        let errors = check(&format!(
+            "<unattend xmlns=\"{UNATTEND_NAMESPACE}\">\
+            <settings pass=\"windowsPe\"/><settings pass=\"specialize\">\
+            <component name=\"A\"/></settings>\
+            <settings pass=\"specialize\"/></unattend>"
+        ));
+        assert_eq!(errors.len(), 4, "{errors:?}");
+    }
+}
@@ -0,0 +1,133 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Converts a finished output image into a VHDX image for Hyper-V.
+//!
+//! `qemu-img` writes the VHDX headers, region tables, and metadata itself and
+//! aligns the image's payload blocks to 1 MiB as the format requires. Azure
+//! doesn't accept VHDX images, but a fixed VHDX image is often converted to a
+//! VHD for it, and Azure requires a disk's virtual size to be a whole number of
+//! MiB. The step that shrinks the output image guarantees that; this step warns
+//! if it's handed an image of any other size. The converted image is checked
+//! with `qemu-img check` so that a damaged image fails the build instead of an
+//! import.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// The granularity Azure requires of the virtual size of a VHD converted from
+/// a VHDX image.
+const AZURE_SIZE_ALIGNMENT: u64 = 1024 * 1024;
+
+/// Returns the commands that convert `output_image` (in `format`) to a VHDX
+/// image at `vhdx_image` with `subformat` and then check the result.
+pub fn commands(
+    output_image: &str,
+    format: &str,
+    vhdx_image: &str,
+    subformat: &str,
+) -> [Command; 2] {
+    let mut convert = Command::new("qemu-img");
+    convert
+        .args(["convert", "-f", format, "-O", "vhdx", "-o"])
+        .arg(format!("subformat={subformat}"))
+        .args([output_image, vhdx_image]);
+    let mut check = Command::new("qemu-img");
+    check.args(["check", "-f", "vhdx", vhdx_image]);
+    [convert, check]
+}
+
+/// Returns a warning about converting `image`, which presents a disk of
+/// `virtual_size` bytes, to VHDX if Azure won't accept a VHD image converted
+/// from the result.
+pub fn size_warning(image: &str, virtual_size: u64) -> Option<String> {
+    (!virtual_size.is_multiple_of(AZURE_SIZE_ALIGNMENT)).then(|| {
+        format!(
+            "{image}'s size ({virtual_size} bytes) isn't a whole number of \
+            MiB, so Azure won't accept a VHD image converted from it"
+        )
+    })
+}
+
+/// Describes the commands [`convert_output_image`] would run.
+pub fn describe_conversion(ctx: &mut Context) -> Vec<String> {
+    let Some(vhdx_image) = ctx.get_var("vhdx_image") else {
+        return Vec::new();
+    };
+
+    let output_image = ctx.get_var("output_image").unwrap();
+    let format = crate::steps::output_format(ctx);
+    let mut lines = vec![crate::steps::describe_get_image_virtual_size(
+        output_image,
+        format,
+    )];
+    lines.extend(
+        commands(
+            output_image,
+            format,
+            vhdx_image,
+            ctx.get_var("vhdx_subformat").unwrap_or("dynamic"),
+        )
+        .iter()
+        .map(format_command),
+    );
+    lines
+}
+
+/// Converts the output image to the VHDX image named by the `vhdx_image`
+/// context variable, with the allocation policy in `vhdx_subformat`, then
+/// checks the result. Does nothing if no VHDX image was requested.
+pub fn convert_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(vhdx_image) = ctx.get_var("vhdx_image") else {
+        return Ok(());
+    };
+
+    let vhdx_image = Utf8PathBuf::from(vhdx_image);
+    let output_image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let format = crate::steps::output_format(ctx);
+    let subformat = ctx.get_var("vhdx_subformat").unwrap_or("dynamic");
+
+    let virtual_size = crate::steps::get_image_virtual_size(
+        output_image.as_str(),
+        format,
+        ui,
+    )?;
+    if let Some(warning) = size_warning("the output image", virtual_size) {
+        ui.warn(&warning);
+    }
+
+    let slot = crate::slots::take_conversion_slot(ctx, ui)?;
+    let [mut convert, mut check] =
+        commands(output_image.as_str(), format, vhdx_image.as_str(), subformat);
+    let cancel = crate::interrupt::remove_on_cancel(&vhdx_image);
+    run_command_check_status(&mut convert, ui)?;
+    drop(cancel);
+    run_command_check_status(&mut check, ui)
+        .with_context(|| format!("checking VHDX image '{vhdx_image}'"))?;
+    drop(slot);
+
+    let file_size = std::fs::metadata(&vhdx_image)
+        .with_context(|| format!("reading metadata for '{vhdx_image}'"))?
+        .len();
+    trace::debug!(
+        "converted output image to VHDX",
+        subformat = subformat,
+        virtual_size = virtual_size,
+        file_size = file_size
+    );
+
+    ui.record_metric("vhdx_subformat", Json::from(subformat));
+    ui.record_metric("vhdx_image_size_bytes", Json::from(file_size));
+    Ok(())
+}
@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Converts a finished output image into a VMDK image for vSphere and ESXi.
+//!
+//! vCenter imports disks (e.g. as part of an OVF package) in the
+//! stream-optimized VMDK subformat: a single compressed extent with an
+//! embedded descriptor, written so that it can be read front to back.
+//! `qemu-img` writes the descriptor and the extent's grain tables itself.
+
+use std::process::Command;
+
+use anyhow::{Context as _, Result};
+use camino::Utf8PathBuf;
+
+use crate::{
+    json::Json,
+    runner::Context,
+    trace,
+    ui::Ui,
+    util::{format_command, run_command_check_status},
+};
+
+/// Returns the command that converts `output_image` (in `format`) to a
+/// stream-optimized VMDK image at `vmdk_image`.
+pub fn command(output_image: &str, format: &str, vmdk_image: &str) -> Command {
+    let mut cmd = Command::new("qemu-img");
+    cmd.args([
+        "convert",
+        "-f",
+        format,
+        "-O",
+        "vmdk",
+        "-o",
+        "subformat=streamOptimized",
+        output_image,
+        vmdk_image,
+    ]);
+    cmd
+}
+
+/// Describes the command [`convert_output_image`] would run.
+pub fn describe_conversion(ctx: &mut Context) -> Vec<String> {
+    let Some(vmdk_image) = ctx.get_var("vmdk_image") else {
+        return Vec::new();
+    };
+
+    vec![format_command(&command(
+        ctx.get_var("output_image").unwrap(),
+        crate::steps::output_format(ctx),
+        vmdk_image,
+    ))]
+}
+
+/// Converts the output image to the stream-optimized VMDK image named by the
+/// `vmdk_image` context variable. Does nothing if no VMDK image was
+/// requested.
+pub fn convert_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(vmdk_image) = ctx.get_var("vmdk_image") else {
+        return Ok(());
+    };
+
+    let vmdk_image = Utf8PathBuf::from(vmdk_image);
+    let output_image = Utf8PathBuf::from(ctx.get_var("output_image").unwrap());
+    let format = crate::steps::output_format(ctx);
+
+    let slot = crate::slots::take_conversion_slot(ctx, ui)?;
+    let cancel = crate::interrupt::remove_on_cancel(&vmdk_image);
+    run_command_check_status(
+        &mut command(output_image.as_str(), format, vmdk_image.as_str()),
+        ui,
+    )?;
+    drop(cancel);
+    drop(slot);
+
+    let file_size = std::fs::metadata(&vmdk_image)
+        .with_context(|| format!("reading metadata for '{vmdk_image}'"))?
+        .len();
+    trace::debug!("converted output image to VMDK", file_size = file_size);
+    ui.record_metric("vmdk_image_size_bytes", Json::from(file_size));
+    Ok(())
+}
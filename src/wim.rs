@@ -0,0 +1,535 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads the metadata that describes the Windows images in a WIM (or ESD)
+//! file.
+//!
+//! A WIM file's header points to an uncompressed, UTF-16-encoded XML document
+//! with an `<IMAGE>` element for each image in the file. Each image's
+//! `<WINDOWS>` element gives the edition and build of Windows it contains,
+//! which is enough to tell which Windows release an ISO installs.
+
+use std::io::{Read, Seek};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+use crate::{
+    autounattend::WindowsVersion,
+    error::{Error, ErrorKind},
+    media::{Entry, InstallMedia, WIM_MAGIC},
+};
+
+/// The size of the part of the WIM header this module reads.
+const HEADER_SIZE: usize = 96;
+
+/// The offset of the XML data's resource header in the WIM header.
+const XML_RESOURCE_OFFSET: usize = 72;
+
+/// The resource header flag marking compressed resources.
+const RESOURCE_COMPRESSED: u8 = 0x04;
+
+/// The largest XML document this module will read. Installation media with
+/// a dozen images have documents of a few tens of kilobytes.
+const MAX_XML_BYTES: u64 = 16 * 1024 * 1024;
+
+/// An image in a WIM file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WimImage {
+    /// The image's 1-based index, which Autounattend.xml uses to select it.
+    pub index: u32,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+
+    /// The edition ID, e.g. "ServerDatacenter".
+    pub edition_id: Option<String>,
+
+    /// The installation type, e.g. "Server" (with the Desktop Experience),
+    /// "Server Core", or "Client" (a desktop edition of Windows).
+    pub installation_type: Option<String>,
+
+    /// The Windows build number, e.g. 20348 for Windows Server 2022.
+    pub build: Option<u32>,
+}
+
+impl WimImage {
+    /// The image's display name if it has one, and otherwise its name.
+    pub fn title(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Returns the Windows release this image contains, if its build number
+    /// is one of theirs.
+    pub fn windows_version(&self) -> Option<WindowsVersion> {
+        let client = self.installation_type.as_deref() == Some("Client");
+        WindowsVersion::from_build(self.build?, client)
+    }
+}
+
+/// Which of a Windows Server edition's installation options to install.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Experience {
+    /// Server Core, which has no desktop.
+    Core,
+
+    /// The Desktop Experience.
+    Desktop,
+}
+
+impl Experience {
+    /// The installation type images with this experience declare.
+    fn installation_type(&self) -> &'static str {
+        match self {
+            Experience::Core => "Server Core",
+            Experience::Desktop => "Server",
+        }
+    }
+}
+
+/// An edition to install, as passed to `--edition`: either an image index or
+/// an edition name (e.g. "datacenter") with an optional "-core" or
+/// "-desktop" suffix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edition {
+    Index(u32),
+    Named { family: String, experience: Option<Experience> },
+}
+
+impl std::str::FromStr for Edition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(index) = s.parse::<u32>() {
+            if index == 0 {
+                return Err("image indices start at 1".to_string());
+            }
+
+            return Ok(Self::Index(index));
+        }
+
+        let lower = s.to_ascii_lowercase();
+        let (family, experience) =
+            if let Some(family) = lower.strip_suffix("-core") {
+                (family, Some(Experience::Core))
+            } else if let Some(family) = lower.strip_suffix("-desktop") {
+                (family, Some(Experience::Desktop))
+            } else {
+                (lower.as_str(), None)
+            };
+
+        if family.is_empty()
+            || !family.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(format!(
+                "'{s}' isn't an image index or an edition like \
+                \"datacenter-core\""
+            ));
+        }
+
+        Ok(Self::Named { family: family.to_string(), experience })
+    }
+}
+
+impl std::fmt::Display for Edition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Edition::Index(index) => write!(f, "image {index}"),
+            Edition::Named { family, experience: None } => {
+                write!(f, "{family}")
+            }
+            Edition::Named { family, experience: Some(Experience::Core) } => {
+                write!(f, "{family}-core")
+            }
+            Edition::Named {
+                family,
+                experience: Some(Experience::Desktop),
+            } => {
+                write!(f, "{family}-desktop")
+            }
+        }
+    }
+}
+
+/// Returns the edition ID that `family`, a lowercase edition name without
+/// hyphens, is short for, if it's a common short name for a client edition
+/// (e.g. "pro" for "Professional" or "ltsc" for "EnterpriseS"), and
+/// otherwise `family` itself.
+pub fn expand_edition_alias(family: &str) -> String {
+    match family {
+        "pro" => "professional",
+        "proworkstation" => "professionalworkstation",
+        "ltsc" => "enterprises",
+        "iotltsc" => "iotenterprises",
+        family => family,
+    }
+    .to_string()
+}
+
+impl Edition {
+    /// Returns whether `image` is one of this edition's images. Named
+    /// editions match images whose edition ID is the name with or without a
+    /// "Server" prefix (so "datacenter" matches "ServerDatacenter"), ignoring
+    /// case and hyphens. Client editions can also be named by their short
+    /// names (see [`expand_edition_alias`]).
+    fn matches(&self, image: &WimImage) -> bool {
+        let (family, experience) = match self {
+            Edition::Index(index) => return image.index == *index,
+            Edition::Named { family, experience } => (family, experience),
+        };
+
+        let Some(edition_id) = &image.edition_id else {
+            return false;
+        };
+
+        let family = expand_edition_alias(&family.replace('-', ""));
+        let edition_id = edition_id.to_ascii_lowercase();
+        let family_matches = edition_id == family
+            || edition_id.strip_prefix("server") == Some(family.as_str());
+        family_matches
+            && experience.is_none_or(|experience| {
+                image.installation_type.as_deref()
+                    == Some(experience.installation_type())
+            })
+    }
+
+    /// Returns the image among `images` that this edition selects. A name
+    /// without an experience suffix selects the edition's Desktop Experience
+    /// image if it has more than one.
+    pub fn select<'a>(&self, images: &'a [WimImage]) -> Result<&'a WimImage> {
+        let matches: Vec<&WimImage> =
+            images.iter().filter(|image| self.matches(image)).collect();
+        let selected = match matches.as_slice() {
+            [] => None,
+            [image] => Some(*image),
+            _ => matches
+                .iter()
+                .copied()
+                .find(|image| {
+                    image.installation_type.as_deref()
+                        == Some(Experience::Desktop.installation_type())
+                })
+                .or(matches.first().copied()),
+        };
+
+        selected.ok_or_else(|| {
+            anyhow::anyhow!(
+                "no image matches edition {self}; the images are: {}",
+                images
+                    .iter()
+                    .map(|image| format!("{} ({})", image.index, image.title()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+}
+
+/// Reads the images listed in the WIM file `entry` on `media`.
+pub fn read_images<R: Read + Seek>(
+    media: &mut InstallMedia<R>,
+    entry: &Entry,
+) -> Result<Vec<WimImage>> {
+    let mut header = [0; HEADER_SIZE];
+    if media.read_at(entry, 0, &mut header)? != HEADER_SIZE
+        || &header[..8] != WIM_MAGIC
+    {
+        anyhow::bail!("'{}' isn't a WIM file", entry.name);
+    }
+
+    let resource = &header[XML_RESOURCE_OFFSET..XML_RESOURCE_OFFSET + 24];
+    let mut size_bytes = [0; 8];
+    size_bytes[..7].copy_from_slice(&resource[..7]);
+    let size = u64::from_le_bytes(size_bytes);
+    let flags = resource[7];
+    let offset = u64::from_le_bytes(resource[8..16].try_into().unwrap());
+    if flags & RESOURCE_COMPRESSED != 0 {
+        anyhow::bail!("'{}' has compressed image metadata", entry.name);
+    }
+
+    if size > MAX_XML_BYTES {
+        anyhow::bail!("'{}' has implausibly large image metadata", entry.name);
+    }
+
+    let mut xml = vec![0; size as usize];
+    if media.read_at(entry, offset, &mut xml)? != xml.len() {
+        anyhow::bail!("'{}' is truncated", entry.name);
+    }
+
+    let units: Vec<u16> = xml
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    let xml = String::from_utf16_lossy(&units);
+    parse_xml(xml.trim_start_matches('\u{feff}'))
+        .with_context(|| format!("reading the image list in '{}'", entry.name))
+}
+
+/// Reads the images in the Windows image on the installation media at
+/// `iso`.
+pub fn read_install_images(iso: &Utf8Path) -> Result<Vec<WimImage>> {
+    let bad_media =
+        |message: String| Error::new(ErrorKind::BadInstallMedia, message);
+    let mut media = InstallMedia::open(iso)
+        .context(bad_media(format!("'{iso}' isn't a readable ISO")))?;
+    let Some(entry) = crate::media::find_install_image(&mut media)
+        .context(bad_media(format!("reading '{iso}'")))?
+    else {
+        return Err(bad_media(format!("'{iso}' has no Windows image")).into());
+    };
+
+    read_images(&mut media, &entry)
+        .context(bad_media(format!("reading the Windows image in '{iso}'")))
+}
+
+/// Returns the image among `images` that Setup installs: the one with
+/// `index`, or the first image if no index was given. All the images on
+/// Microsoft's installation media contain the same build, so the first
+/// image's version is the ISO's.
+pub fn select_image(
+    images: &[WimImage],
+    index: Option<u32>,
+) -> Option<&WimImage> {
+    match index {
+        Some(index) => images.iter().find(|image| image.index == index),
+        None => images.first(),
+    }
+}
+
+/// Prints the images in the Windows image on the installation media at
+/// `iso`, for the `list-editions` command.
+pub fn list_editions(iso: &Utf8Path) -> Result<()> {
+    let images = read_install_images(iso)?;
+    if images.is_empty() {
+        anyhow::bail!("the Windows image in '{iso}' lists no images");
+    }
+
+    for line in edition_table(&images) {
+        println!("{line}");
+    }
+
+    println!();
+    println!(
+        "Pass --unattend-image-index with one of these indices to install \
+        that edition."
+    );
+    Ok(())
+}
+
+/// Lays out `images` as a table with a header row.
+fn edition_table(images: &[WimImage]) -> Vec<String> {
+    let header = ["INDEX", "EDITION", "TYPE", "BUILD", "NAME"];
+    let mut rows = vec![header.map(str::to_string)];
+    for image in images {
+        rows.push([
+            image.index.to_string(),
+            image.edition_id.clone().unwrap_or_else(|| "-".to_string()),
+            image.installation_type.clone().unwrap_or_else(|| "-".to_string()),
+            image.build.map_or("-".to_string(), |build| build.to_string()),
+            image.title().to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (width, cell) in widths.iter().zip(row) {
+                line.push_str(&format!("{cell:width$}  "));
+            }
+            line.push_str(&row[4]);
+            line
+        })
+        .collect()
+}
+
+/// Parses a WIM file's XML metadata.
+fn parse_xml(xml: &str) -> Result<Vec<WimImage>> {
+    use xml::reader::XmlEvent;
+
+    let mut images = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut image: Option<WimImage> = None;
+    for event in xml::EventReader::new(xml.as_bytes()) {
+        match event? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                if name.local_name == "IMAGE" && path == ["WIM"] {
+                    let index = attributes
+                        .iter()
+                        .find(|attr| attr.name.local_name == "INDEX")
+                        .and_then(|attr| attr.value.parse().ok())
+                        .context("image has no index")?;
+                    image = Some(WimImage { index, ..Default::default() });
+                }
+                path.push(name.local_name);
+            }
+            XmlEvent::EndElement { .. } => {
+                if path.len() == 2 && path[1] == "IMAGE" {
+                    images.extend(image.take());
+                }
+                path.pop();
+            }
+            XmlEvent::Characters(text) => {
+                let Some(image) = image.as_mut() else {
+                    continue;
+                };
+
+                let fields: Vec<&str> =
+                    path.iter().skip(2).map(String::as_str).collect();
+                match fields.as_slice() {
+                    ["NAME"] => image.name = text,
+                    ["DISPLAYNAME"] => image.display_name = Some(text),
+                    ["DESCRIPTION"] => image.description = Some(text),
+                    ["WINDOWS", "EDITIONID"] => image.edition_id = Some(text),
+                    ["WINDOWS", "INSTALLATIONTYPE"] => {
+                        image.installation_type = Some(text)
+                    }
+                    ["WINDOWS", "VERSION", "BUILD"] => {
+                        image.build = text.trim().parse().ok()
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(images)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const XML: &str = "<WIM><TOTALBYTES>1</TOTALBYTES>\
+        <IMAGE INDEX=\"1\"><NAME>Windows Server 2022 SERVERSTANDARDCORE</NAME>\
+        <DISPLAYNAME>Windows Server 2022 Standard</DISPLAYNAME>\
+        <WINDOWS><ARCH>9</ARCH><EDITIONID>ServerStandard</EDITIONID>\
+        <INSTALLATIONTYPE>Server Core</INSTALLATIONTYPE>\
+        <VERSION><MAJOR>10</MAJOR><BUILD>20348</BUILD></VERSION></WINDOWS>\
+        </IMAGE>\
+        <IMAGE INDEX=\"2\"><NAME>Custom</NAME><WINDOWS><VERSION>\
+        <BUILD>22000</BUILD></VERSION></WINDOWS></IMAGE>\
+        <IMAGE INDEX=\"3\"><NAME>Windows 11 Pro</NAME><WINDOWS>\
+        <EDITIONID>Professional</EDITIONID>\
+        <INSTALLATIONTYPE>Client</INSTALLATIONTYPE>\
+        <VERSION><BUILD>26100</BUILD></VERSION></WINDOWS></IMAGE></WIM>";
+
+    #[test]
+    fn parses_image_metadata() {
+        let images = parse_xml(XML).unwrap();
+        assert_eq!(images.len(), 3);
+        assert_eq!(images[0].index, 1);
+        assert_eq!(images[0].title(), "Windows Server 2022 Standard");
+        assert_eq!(images[0].edition_id.as_deref(), Some("ServerStandard"));
+        assert_eq!(images[0].installation_type.as_deref(), Some("Server Core"));
+        assert_eq!(
+            images[0].windows_version(),
+            Some(WindowsVersion::Server2022)
+        );
+        assert_eq!(images[1].title(), "Custom");
+        assert_eq!(images[1].windows_version(), None);
+        assert_eq!(
+            images[2].windows_version(),
+            Some(WindowsVersion::Windows11)
+        );
+        assert_eq!(select_image(&images, None), Some(&images[0]));
+        assert_eq!(select_image(&images, Some(2)), Some(&images[1]));
+        assert_eq!(select_image(&images, Some(4)), None);
+    }
+
+    #[test]
+    fn selects_editions() {
+        let image = |index, edition: &str, installation_type: &str| WimImage {
+            index,
+            name: format!("{edition} {installation_type}"),
+            edition_id: Some(edition.to_string()),
+            installation_type: Some(installation_type.to_string()),
+            ..Default::default()
+        };
+        let images = [
+            image(1, "ServerStandard", "Server Core"),
+            image(2, "ServerStandard", "Server"),
+            image(3, "ServerDatacenter", "Server Core"),
+            image(4, "ServerDatacenter", "Server"),
+            image(5, "ServerTurbine", "Server Core"),
+            image(6, "Professional", "Client"),
+            image(7, "ProfessionalWorkstation", "Client"),
+            image(8, "EnterpriseS", "Client"),
+        ];
+
+        let select = |edition: &str| {
+            edition.parse::<Edition>().unwrap().select(&images).map(|i| i.index)
+        };
+        assert_eq!(select("datacenter-core").unwrap(), 3);
+        assert_eq!(select("Datacenter").unwrap(), 4);
+        assert_eq!(select("ServerStandard-desktop").unwrap(), 2);
+        assert_eq!(select("turbine").unwrap(), 5);
+        assert_eq!(select("3").unwrap(), 3);
+        assert_eq!(select("pro").unwrap(), 6);
+        assert_eq!(select("professional").unwrap(), 6);
+        assert_eq!(select("pro-workstation").unwrap(), 7);
+        assert_eq!(select("LTSC").unwrap(), 8);
+        assert!(select("essentials").is_err());
+        assert!(select("9").is_err());
+
+        for bad in ["0", "", "-core", "data center"] {
+            assert!(bad.parse::<Edition>().is_err(), "{bad:?}");
+        }
+        assert_eq!(
+            "Datacenter-Core".parse::<Edition>().unwrap().to_string(),
+            "datacenter-core"
+        );
+    }
+
+    #[test]
+    fn lays_out_edition_tables() {
+        let table = edition_table(&parse_xml(XML).unwrap());
+        assert_eq!(
+            table,
+            [
+                "INDEX  EDITION         TYPE         BUILD  NAME",
+                "1      ServerStandard  Server Core  20348  \
+                Windows Server 2022 Standard",
+                "2      -               -            22000  Custom",
+                "3      Professional    Client       26100  Windows 11 Pro",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_metadata_from_wim_headers() {
+        let xml: Vec<u8> = std::iter::once('\u{feff}')
+            .chain(XML.chars())
+            .collect::<String>()
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let mut wim = vec![0; 208];
+        wim[..8].copy_from_slice(WIM_MAGIC);
+        wim[72..79].copy_from_slice(&(xml.len() as u64).to_le_bytes()[..7]);
+        wim[80..88].copy_from_slice(&208u64.to_le_bytes());
+        wim.extend(&xml);
+
+        let tmp = camino::Utf8PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("wimsy-wim-test-{}", std::process::id()));
+        let src = tmp.join("src");
+        std::fs::create_dir_all(src.join("sources")).unwrap();
+        std::fs::write(src.join("sources/install.wim"), &wim).unwrap();
+        let iso = tmp.join("test.iso");
+        crate::iso::create_iso(&src, &iso, "TEST").unwrap();
+
+        let images = read_install_images(&iso).unwrap();
+        assert_eq!(images, parse_xml(XML).unwrap());
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+}
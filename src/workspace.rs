@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The layout of the work directory, and what's kept of it when a build
+//! finishes.
+//!
+//! The work directory holds:
+//!
+//! - `logs/`: command logs, the serial log, traces, and screenshots;
+//! - `isos/`: the ISOs `wimsy` builds to attach to the installation VM;
+//! - `scratch/`: everything else steps write for later steps, like sockets,
+//!   varstores, and the customized copy of the unattend files;
+//! - the build report and checkpoint; and
+//! - `downloads/`, the cache of downloaded installation media, which later
+//!   builds reuse and which is never cleaned up.
+
+use std::fmt;
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{checkpoint::CHECKPOINT_FILE_NAME, runner::Context};
+
+/// The name of the directory in the work directory that holds logs.
+pub const LOGS_DIR_NAME: &str = "logs";
+
+/// The name of the directory in the work directory that holds built ISOs.
+pub const ISOS_DIR_NAME: &str = "isos";
+
+/// The name of the directory in the work directory that holds other
+/// intermediate files.
+pub const SCRATCH_DIR_NAME: &str = "scratch";
+
+/// When to keep the work directory's logs, ISOs, and scratch files after a
+/// build finishes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeepWorkDir {
+    /// Keep them after every build.
+    Always,
+
+    /// Keep them only if the build fails.
+    #[default]
+    OnFailure,
+
+    /// Remove them after every build.
+    Never,
+}
+
+impl KeepWorkDir {
+    /// Returns whether to keep the work directory after a build that
+    /// `succeeded` (or didn't).
+    pub fn keeps(self, succeeded: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::OnFailure => !succeeded,
+            Self::Never => false,
+        }
+    }
+}
+
+impl fmt::Display for KeepWorkDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::OnFailure => "on-failure",
+            Self::Never => "never",
+        })
+    }
+}
+
+pub fn logs_dir(work_dir: &Utf8Path) -> Utf8PathBuf {
+    work_dir.join(LOGS_DIR_NAME)
+}
+
+pub fn isos_dir(work_dir: &Utf8Path) -> Utf8PathBuf {
+    work_dir.join(ISOS_DIR_NAME)
+}
+
+pub fn scratch_dir(work_dir: &Utf8Path) -> Utf8PathBuf {
+    work_dir.join(SCRATCH_DIR_NAME)
+}
+
+fn work_dir(ctx: &Context) -> &Utf8Path {
+    Utf8Path::new(ctx.get_var("work_dir").unwrap())
+}
+
+/// Returns the path of the log file `name` for the build whose context is
+/// `ctx`.
+pub fn log_path(ctx: &Context, name: &str) -> Utf8PathBuf {
+    logs_dir(work_dir(ctx)).join(name)
+}
+
+/// Returns the path of the ISO `name` for the build whose context is `ctx`.
+pub fn iso_path(ctx: &Context, name: &str) -> Utf8PathBuf {
+    isos_dir(work_dir(ctx)).join(name)
+}
+
+/// Returns the path of the intermediate file `name` for the build whose
+/// context is `ctx`.
+pub fn scratch_path(ctx: &Context, name: &str) -> Utf8PathBuf {
+    scratch_dir(work_dir(ctx)).join(name)
+}
+
+/// Creates the work directory and its subdirectories, if they don't exist.
+pub fn create(work_dir: &Utf8Path) -> Result<()> {
+    for dir in [logs_dir(work_dir), isos_dir(work_dir), scratch_dir(work_dir)] {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating '{dir}'"))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the work directory's logs, ISOs, scratch files, and checkpoint,
+/// and then the work directory itself if nothing else is left in it. The
+/// build report and download cache are kept.
+pub fn clean_up(work_dir: &Utf8Path) -> Result<()> {
+    for dir in [logs_dir(work_dir), isos_dir(work_dir), scratch_dir(work_dir)] {
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing '{dir}'"));
+            }
+            _ => {}
+        }
+    }
+
+    let checkpoint = work_dir.join(CHECKPOINT_FILE_NAME);
+    match std::fs::remove_file(&checkpoint) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing '{checkpoint}'"));
+        }
+        _ => {}
+    }
+
+    // This only succeeds if the directory is empty.
+    let _ = std::fs::remove_dir(work_dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retention_policies() {
+        assert!(KeepWorkDir::Always.keeps(true));
+        assert!(KeepWorkDir::Always.keeps(false));
+        assert!(!KeepWorkDir::OnFailure.keeps(true));
+        assert!(KeepWorkDir::OnFailure.keeps(false));
+        assert!(!KeepWorkDir::Never.keeps(false));
+    }
+
+    #[test]
+    fn clean_up_keeps_report_and_downloads() {
+        let work_dir = Utf8PathBuf::try_from(
+            std::env::temp_dir().join("wimsy-workspace-test"),
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&work_dir);
+        create(&work_dir).unwrap();
+        std::fs::write(logs_dir(&work_dir).join("a.log"), "log").unwrap();
+        std::fs::write(work_dir.join(CHECKPOINT_FILE_NAME), "{}").unwrap();
+        std::fs::create_dir(work_dir.join("downloads")).unwrap();
+
+        clean_up(&work_dir).unwrap();
+        assert!(!logs_dir(&work_dir).exists());
+        assert!(!work_dir.join(CHECKPOINT_FILE_NAME).exists());
+        assert!(work_dir.join("downloads").exists());
+
+        std::fs::remove_dir(work_dir.join("downloads")).unwrap();
+        clean_up(&work_dir).unwrap();
+        assert!(!work_dir.exists());
+    }
+}
@@ -5,6 +5,41 @@ param (
 
 $ErrorActionPreference = 'stop'
 
+# wimsy writes the build options that affect this script (e.g.
+# --skip-generalize) to WimsySettings.ps1 in the configuration directory.
+$WimsySkipGeneralize = $false
+$WimsyClient = $false
+$WimsyVerifyDomainJoin = $false
+$WimsyInstallGuestAgent = $false
+$WimsySkipCloudbaseInit = $false
+$WimsySkipRdp = $false
+$WimsyEnableSsh = $false
+$WimsySshAuthorizedKeys = @()
+$WimsyWindowsFeatures = @()
+$WimsyRemoveFeatures = @()
+$WimsyZeroFreeSpace = $false
+$WimsyMinimalImage = $false
+$WimsyShrinkHeadroom = 3GB
+$WimsyExpandOnFirstBoot = $false
+$WimsyVerifySecureBoot = $false
+$WimsyCloudbaseInitUrl = "https://oxide-omicron-build.s3.amazonaws.com/CloudbaseInitSetup.msi"
+$settingsPath = Join-Path $ConfigDir "WimsySettings.ps1"
+if (Test-Path $settingsPath) {
+    . $settingsPath
+}
+
+# Reports a fatal error to wimsy, which watches the serial port (where this
+# script's output goes) for this marker and fails the build when it sees it.
+function ReportFailure {
+    param (
+        [Parameter(Mandatory=$True)]
+        [string]$Message
+    )
+
+    Write-Host "WIMSY-FAILURE: $Message"
+    exit 1
+}
+
 function RetryWithBackoff {
     param (
         [Parameter(Mandatory=$True)]
@@ -75,21 +110,110 @@ function InstallSshFromArchive {
     New-NetFirewallRule -Name sshd -DisplayName 'OpenSSH Server (sshd)' -Enabled True -Direction Inbound -Protocol TCP -Action Allow -LocalPort 22
 }
 
+#region Pause Store app updates on client editions
+# Keep the Store from installing app updates while setup runs, so that fewer
+# apps need removing before sysprep will generalize the image. The policy is
+# removed again before the image is generalized.
+if ($WimsyClient) {
+    Write-Host "Pausing Store app updates"
+    $storePolicy = "HKLM:\SOFTWARE\Policies\Microsoft\WindowsStore"
+    New-Item -Force -Path $storePolicy | Out-Null
+    Set-ItemProperty -Path $storePolicy -Name "AutoDownload" -Value 2 -Type DWord
+}
+#endregion
+
 #region Enable serial console
 Write-Host "Enabling Serial Console"
 bcdedit /ems on
 bcdedit /emssettings EMSPORT:1 EMSBAUDRATE:115200
 #endregion
 
+#region Verify Secure Boot
+# wimsy asks for Secure Boot by giving the VM a varstore with Microsoft's keys
+# enrolled. If the varstore doesn't actually have them (or the firmware isn't
+# a Secure Boot build), Windows boots with Secure Boot off, and the image
+# wouldn't boot with it enforced, so fail the build early.
+if ($WimsyVerifySecureBoot) {
+    $secureBoot = $false
+    try {
+        $secureBoot = Confirm-SecureBootUEFI
+    } catch {
+        Write-Host "Confirm-SecureBootUEFI failed:" $_.Exception.Message
+    }
+    if (-not $secureBoot) {
+        ReportFailure "Secure Boot isn't enabled in the setup VM; check that --ovmf-path is a Secure Boot OVMF build and that --ovmf-vars-template has Microsoft's keys enrolled"
+    }
+    Write-Host "Secure Boot is enabled"
+}
+#endregion
+
+#region Install trusted root and intermediate certificates
+# wimsy stages any certificates passed with --trusted-cert in the TrustedCerts
+# directory, and those passed with --intermediate-cert in IntermediateCerts.
+# Install them before anything below downloads files so that environments that
+# intercept TLS with an internal CA work.
+$trustedCertsDir = Join-Path $ConfigDir "TrustedCerts"
+if (Test-Path $trustedCertsDir) {
+    Get-ChildItem -Path $trustedCertsDir -Filter *.cer | ForEach-Object {
+        Write-Host "Trusting root certificate" $_.Name
+        Import-Certificate -FilePath $_.FullName -CertStoreLocation Cert:\LocalMachine\Root | Out-Null
+    }
+}
+$intermediateCertsDir = Join-Path $ConfigDir "IntermediateCerts"
+if (Test-Path $intermediateCertsDir) {
+    Get-ChildItem -Path $intermediateCertsDir -Filter *.cer | ForEach-Object {
+        Write-Host "Adding intermediate certificate" $_.Name
+        Import-Certificate -FilePath $_.FullName -CertStoreLocation Cert:\LocalMachine\CA | Out-Null
+    }
+}
+#endregion
+
+#region Verify domain join
+# Windows Setup doesn't fail if the UnattendedJoin component can't join the
+# domain, so check the result here.
+if ($WimsyVerifyDomainJoin) {
+    Write-Host "Verifying domain join"
+    $computer = Get-CimInstance -ClassName Win32_ComputerSystem
+    if (-not $computer.PartOfDomain) {
+        ReportFailure "the guest did not join a domain during setup; see C:\Windows\debug\NetSetup.LOG in the guest for details"
+    }
+    Write-Host "Joined domain" $computer.Domain
+}
+#endregion
+
 #region Enable Ping
 Write-Host "Enabling Ping"
 New-NetFirewallRule -DisplayName "Allow Inbound ICMPv4" -Direction Inbound -Protocol ICMPv4 -IcmpType 8 -RemoteAddress Any -Action Allow
 #endregion
 
 #region Enable RDP
-Write-Host "Enabling RDP"
-Set-ItemProperty "HKLM:\SYSTEM\CurrentControlSet\Control\Terminal Server\" -Name "fDenyTSConnections" -Value 0
-Enable-NetFirewallRule -DisplayGroup "Remote Desktop"
+# Generalized images get Remote Desktop in specialize-unattend.xml's specialize
+# pass, which images that aren't generalized never run.
+if ($WimsySkipGeneralize -and -not $WimsySkipRdp) {
+    Write-Host "Enabling RDP"
+    Set-ItemProperty "HKLM:\SYSTEM\CurrentControlSet\Control\Terminal Server\" -Name "fDenyTSConnections" -Value 0
+    Enable-NetFirewallRule -DisplayGroup "Remote Desktop"
+}
+#endregion
+
+#region Apply registry settings
+# wimsy compiles the configuration file's [[registry]] tables into
+# WimsyRegistry.reg. Generalizing can reset machine settings, so generalized
+# images import it in specialize-unattend.xml's specialize pass from the copy
+# kept here; images that aren't generalized import it now.
+$registryFile = Join-Path $ConfigDir "WimsyRegistry.reg"
+if (Test-Path $registryFile) {
+    $setupScripts = "C:\Windows\Setup\Scripts"
+    New-Item -ItemType Directory -Force -Path $setupScripts | Out-Null
+    Copy-Item -Path $registryFile -Destination "$setupScripts\WimsyRegistry.reg"
+    if ($WimsySkipGeneralize) {
+        Write-Host "Applying registry settings"
+        & reg.exe import "$setupScripts\WimsyRegistry.reg"
+        if ($LASTEXITCODE -ne 0) {
+            ReportFailure "importing WimsyRegistry.reg failed with status $LASTEXITCODE"
+        }
+    }
+}
 #endregion
 
 #region Wait for internet access
@@ -100,66 +224,200 @@ Write-Host "Skipping internet connectivity poll"
 
 
 #region Enable SSH
-Write-Host "Enabling SSH"
-
-# Windows Server 2025 ships with OpenSSH pre-installed (both client and
-# server) but the sshd service is disabled. Earlier versions require
-# installation. Check for the service first to avoid double-installing.
-$sshdService = Get-Service -Name sshd -ErrorAction SilentlyContinue
-if (-not $sshdService) {
-    # The easiest way to install OpenSSH is to install the relevant Windows
-    # capability, but this only exists in-box on Windows Server 2019 and later.
-    # Server 2016 recognizes the capability name, but enabling it doesn't
-    # actually install the sshd service. Try to detect both of these cases.
-    Add-WindowsCapability -Online -Name OpenSSH.Server~~~~0.0.1.0 -ErrorAction SilentlyContinue
-    if ($?) {
-        $sshCap = Get-Service -Name sshd -ErrorAction SilentlyContinue
-    }
-
-    # If either of the last two commands produced an error, fall back to trying
-    # to pull the latest release down from GitHub and install it manually.
-    if ($?) {
-        Write-Host "SSH service installed via Add-WindowsCapability"
+# cloudbase-init gives deployed instances the SSH keys in their metadata, so
+# images with it always get sshd; images without it only if wimsy asks.
+if ($WimsyEnableSsh -or -not $WimsySkipCloudbaseInit) {
+    Write-Host "Enabling SSH"
+
+    # Windows Server 2025 ships with OpenSSH pre-installed (both client and
+    # server) but the sshd service is disabled. Earlier versions require
+    # installation. Check for the service first to avoid double-installing.
+    $sshdService = Get-Service -Name sshd -ErrorAction SilentlyContinue
+    if (-not $sshdService) {
+        # The easiest way to install OpenSSH is to install the relevant Windows
+        # capability, but this only exists in-box on Windows Server 2019 and later.
+        # Server 2016 recognizes the capability name, but enabling it doesn't
+        # actually install the sshd service. Try to detect both of these cases.
+        Add-WindowsCapability -Online -Name OpenSSH.Server~~~~0.0.1.0 -ErrorAction SilentlyContinue
+        if ($?) {
+            $sshCap = Get-Service -Name sshd -ErrorAction SilentlyContinue
+        }
+
+        # If either of the last two commands produced an error, fall back to trying
+        # to pull the latest release down from GitHub and install it manually.
+        if ($?) {
+            Write-Host "SSH service installed via Add-WindowsCapability"
+        } else {
+            Write-Host "SSH capability not present in image, will download from GitHub"
+            $sshPath = "C:\Windows\Temp\OpenSSH-Win64.zip"
+            RetryWithBackoff -ScriptBlock { DownloadLatestSshArchive -ArchivePath $sshPath }
+            InstallSshFromArchive -ArchivePath $sshPath
+        }
     } else {
-        Write-Host "SSH capability not present in image, will download from GitHub"
-        $sshPath = "C:\Windows\Temp\OpenSSH-Win64.zip"
-        RetryWithBackoff -ScriptBlock { DownloadLatestSshArchive -ArchivePath $sshPath }
-        InstallSshFromArchive -ArchivePath $sshPath
+        Write-Host "SSH service already installed (Windows Server 2025+)"
     }
-} else {
-    Write-Host "SSH service already installed (Windows Server 2025+)"
-}
 
-# Configure and enable sshd (works on all versions).
-Set-Service -Name sshd -StartupType Automatic
-Start-Service sshd
+    # Configure and enable sshd (works on all versions).
+    Set-Service -Name sshd -StartupType Automatic
+    Start-Service sshd
 
-# Ensure the firewall rule exists (InstallSshFromArchive creates it for the
-# GitHub-download path, but not for the capability or pre-installed paths).
-if (-not (Get-NetFirewallRule -Name "OpenSSH-Server-In-TCP" -ErrorAction SilentlyContinue)) {
-    New-NetFirewallRule -Name "OpenSSH-Server-In-TCP" -DisplayName "OpenSSH Server (sshd)" `
-        -Direction Inbound -Protocol TCP -LocalPort 22 -Action Allow
-}
+    # Ensure the firewall rule exists (InstallSshFromArchive creates it for the
+    # GitHub-download path, but not for the capability or pre-installed paths).
+    if (-not (Get-NetFirewallRule -Name "OpenSSH-Server-In-TCP" -ErrorAction SilentlyContinue)) {
+        New-NetFirewallRule -Name "OpenSSH-Server-In-TCP" -DisplayName "OpenSSH Server (sshd)" `
+            -Direction Inbound -Protocol TCP -LocalPort 22 -Action Allow
+    }
+
+    $content = [System.IO.File]::ReadAllText("C:\ProgramData\ssh\sshd_config").Replace("Match Group administrators", "#Match Group administrators").Replace("AuthorizedKeysFile __PROGRAMDATA__", "#AuthorizedKeysFile __PROGRAMDATA__")
+    [System.IO.File]::WriteAllText("C:\ProgramData\ssh\sshd_config", $content)
 
-$content = [System.IO.File]::ReadAllText("C:\ProgramData\ssh\sshd_config").Replace("Match Group administrators", "#Match Group administrators").Replace("AuthorizedKeysFile __PROGRAMDATA__", "#AuthorizedKeysFile __PROGRAMDATA__")
-[System.IO.File]::WriteAllText("C:\ProgramData\ssh\sshd_config", $content)
+    # Keys passed with --ssh-authorized-keys let any administrator log on.
+    # sshd ignores this file unless only Administrators and SYSTEM can access
+    # it. The Match block commented out above only looks here, and
+    # cloudbase-init puts instance keys in the user's own authorized_keys, so
+    # look in both places.
+    if ($WimsySshAuthorizedKeys.Count -gt 0) {
+        Write-Host "Installing" $WimsySshAuthorizedKeys.Count "authorized SSH keys for administrators"
+        $keysPath = "C:\ProgramData\ssh\administrators_authorized_keys"
+        [System.IO.File]::WriteAllLines($keysPath, [string[]]$WimsySshAuthorizedKeys)
+        icacls.exe $keysPath /inheritance:r /grant "*S-1-5-32-544:F" /grant "*S-1-5-18:F" | Out-Null
+        Add-Content -Path "C:\ProgramData\ssh\sshd_config" -Value "`r`nMatch Group administrators`r`n       AuthorizedKeysFile .ssh/authorized_keys __PROGRAMDATA__/ssh/administrators_authorized_keys"
+    }
+}
 #endregion
 
 #region Install Cloudbase-init (built from https://github.com/luqmana/cloudbase-init/tree/oxide w/ https://github.com/luqmana/cloudbase-init-installer/tree/oxide)
-Write-Host "Installing cloudbase-init"
-RetryWithBackoff -ScriptBlock { Invoke-WebRequest -Uri https://oxide-omicron-build.s3.amazonaws.com/CloudbaseInitSetup.msi -OutFile C:\Windows\Temp\CloudbaseInitSetup.msi | Out-Null }
-Start-Process msiexec.exe -ArgumentList "/i C:\Windows\Temp\CloudbaseInitSetup.msi /qn /norestart RUN_SERVICE_AS_LOCAL_SYSTEM=1" -Wait
-del C:\Windows\Temp\CloudbaseInitSetup.msi
+# wimsy stages the installer passed with --cloudbase-init-msi as
+# CloudbaseInitSetup.msi in the configuration directory; otherwise download it.
+if (-not $WimsySkipCloudbaseInit) {
+    Write-Host "Installing cloudbase-init"
+    $cloudbaseInitMsi = Join-Path $ConfigDir "CloudbaseInitSetup.msi"
+    if (-not (Test-Path $cloudbaseInitMsi)) {
+        $cloudbaseInitMsi = "C:\Windows\Temp\CloudbaseInitSetup.msi"
+        RetryWithBackoff -ScriptBlock { Invoke-WebRequest -Uri $WimsyCloudbaseInitUrl -OutFile $cloudbaseInitMsi | Out-Null }
+    }
+    $install = Start-Process msiexec.exe -ArgumentList "/i `"$cloudbaseInitMsi`" /qn /norestart RUN_SERVICE_AS_LOCAL_SYSTEM=1" -Wait -PassThru
+    if ($install.ExitCode -ne 0) {
+        ReportFailure "installing cloudbase-init from $cloudbaseInitMsi failed with exit code $($install.ExitCode)"
+    }
+    del C:\Windows\Temp\CloudbaseInitSetup.msi -ErrorAction SilentlyContinue
+
+    # Copy cloudbase-init configuration appropriate for Oxide rack
+    $confPath = "C:\Program Files\Cloudbase Solutions\Cloudbase-Init\conf\"
+    Copy-Item "$ConfigDir\cloudbase-init.conf" -Destination "$confPath\cloudbase-init.conf"
+    Copy-Item "$ConfigDir\cloudbase-init-unattend.conf" -Destination "$confPath\cloudbase-init-unattend.conf"
+    Remove-Item "$confPath\Unattend.xml"
+
+    # Disable the service so it doesn't run on first boot and contend with the unattend first pass.
+    # We re-enable it during the specialize phase. See cloudbase-unattend.xml.
+    Set-Service -Name cloudbase-init -StartupType Disabled
+}
+#endregion
+
+#region Install QEMU guest agent
+# wimsy's image tests run commands in the guest through the QEMU guest agent,
+# which the virtio driver ISO includes.
+if ($WimsyInstallGuestAgent) {
+    Write-Host "Installing QEMU guest agent"
+    $agentMsi = Get-PSDrive -PSProvider FileSystem |
+        ForEach-Object { Join-Path $_.Root "guest-agent\qemu-ga-x86_64.msi" } |
+        Where-Object { Test-Path $_ } |
+        Select-Object -First 1
+    if (-not $agentMsi) {
+        ReportFailure "image tests need the QEMU guest agent, but no drive contains guest-agent\qemu-ga-x86_64.msi; use a virtio driver ISO that includes it"
+    }
+    $install = Start-Process msiexec.exe -ArgumentList "/i `"$agentMsi`" /qn /norestart" -Wait -PassThru
+    if ($install.ExitCode -ne 0) {
+        ReportFailure "installing the QEMU guest agent from $agentMsi failed with exit code $($install.ExitCode)"
+    }
+}
+#endregion
 
-# Copy cloudbase-init configuration appropriate for Oxide rack
-$confPath = "C:\Program Files\Cloudbase Solutions\Cloudbase-Init\conf\"
-Copy-Item "$ConfigDir\cloudbase-init.conf" -Destination "$confPath\cloudbase-init.conf"
-Copy-Item "$ConfigDir\cloudbase-init-unattend.conf" -Destination "$confPath\cloudbase-init-unattend.conf"
-Remove-Item "$confPath\Unattend.xml"
+#region Add and remove Windows features
+# Names passed with --windows-feature and --remove-feature may be server roles
+# and features (Install-WindowsFeature, Server editions only) or optional
+# features (DISM). Features whose payloads were removed from the image, like
+# NetFx3, install from the sources\sxs directory of the installation media if
+# a drive has one. Changes that need a restart finish the next time the image
+# boots.
+$serverManager = [bool](Get-Command Get-WindowsFeature -ErrorAction SilentlyContinue)
+$sxsSource = Get-PSDrive -PSProvider FileSystem |
+    ForEach-Object { Join-Path $_.Root "sources\sxs" } |
+    Where-Object { Test-Path $_ } |
+    Select-Object -First 1
+function FindServerFeature($name) {
+    if ($serverManager) {
+        Get-WindowsFeature -Name $name -ErrorAction SilentlyContinue
+    }
+}
+function FindOptionalFeature($name) {
+    Get-WindowsOptionalFeature -Online -FeatureName $name -ErrorAction SilentlyContinue
+}
+foreach ($feature in $WimsyWindowsFeatures) {
+    Write-Host "Adding Windows feature" $feature
+    $sourceArgs = @{}
+    if ($sxsSource) {
+        $sourceArgs.Source = $sxsSource
+    }
+    if (FindServerFeature $feature) {
+        $result = Install-WindowsFeature -Name $feature -IncludeManagementTools @sourceArgs
+        if (-not $result.Success) {
+            ReportFailure "installing the Windows feature $feature failed"
+        }
+        $restart = $result.RestartNeeded -eq 'Yes'
+    } elseif (FindOptionalFeature $feature) {
+        if ($sxsSource) {
+            $sourceArgs.LimitAccess = $true
+        }
+        $result = Enable-WindowsOptionalFeature -Online -FeatureName $feature -All -NoRestart @sourceArgs
+        $restart = $result.RestartNeeded
+    } else {
+        ReportFailure "this edition of Windows has no feature named $feature"
+    }
+    if ($restart) {
+        Write-Host "Adding $feature finishes after a restart"
+    }
+}
+foreach ($feature in $WimsyRemoveFeatures) {
+    Write-Host "Removing Windows feature" $feature
+    if (FindServerFeature $feature) {
+        $result = Uninstall-WindowsFeature -Name $feature
+        if (-not $result.Success) {
+            ReportFailure "removing the Windows feature $feature failed"
+        }
+        $restart = $result.RestartNeeded -eq 'Yes'
+    } elseif (FindOptionalFeature $feature) {
+        $result = Disable-WindowsOptionalFeature -Online -FeatureName $feature -NoRestart
+        $restart = $result.RestartNeeded
+    } else {
+        ReportFailure "this edition of Windows has no feature named $feature"
+    }
+    if ($restart) {
+        Write-Host "Removing $feature finishes after a restart"
+    }
+}
+#endregion
 
-# Disable the service so it doesn't run on first boot and contend with the unattend first pass.
-# We re-enable it during the specialize phase. See cloudbase-unattend.xml.
-Set-Service -Name cloudbase-init -StartupType Disabled
+#region Run provisioning scripts
+# wimsy stages the scripts passed with --provision-script in the Provision
+# directory, named so that they sort in the order they were given. It watches
+# for each script's result line on the serial port and records the exit
+# statuses in the build report.
+$provisionDir = Join-Path $ConfigDir "Provision"
+if (Test-Path $provisionDir) {
+    foreach ($script in Get-ChildItem -Path $provisionDir -Filter *.ps1 | Sort-Object Name) {
+        Write-Host "Running provisioning script" $script.Name
+        $ErrorActionPreference = 'continue'
+        & powershell.exe -NoProfile -ExecutionPolicy Bypass -File $script.FullName 2>&1 |
+            ForEach-Object { Write-Host "$_" }
+        $status = $LASTEXITCODE
+        $ErrorActionPreference = 'stop'
+        Write-Host "WIMSY-PROVISIONED: $($script.Name) $status"
+        if ($status -ne 0) {
+            ReportFailure "provisioning script $($script.Name) exited with status $status"
+        }
+    }
+}
 #endregion
 
 #region Cleanup and defrag/TRIM disk
@@ -169,13 +427,20 @@ Write-Host "Skipping DISM ResetBase and Optimize-Volume (handled by offline shri
 #endregion
 
 #region Shrink OS partition
+# Shrinking the partition shrinks its NTFS volume too. The volume can only
+# shrink as far as its last unmovable file, so minimal images first move as
+# much as they can toward the start of the volume.
+if ($WimsyMinimalImage) {
+    Write-Host "Consolidating files on C:"
+    Optimize-Volume -DriveLetter C -Defrag
+}
 Write-Host "Shrinking OS partition"
 $osPartition = Get-Partition -DriveLetter C
 $resizeInfo = Get-PartitionSupportedSize -DriveLetter C
 $minSz = $resizeInfo.SizeMin
 $maxSz = $resizeInfo.SizeMax
 $curSz = $osPartition.Size
-$newSz = $minSz + 3GB
+$newSz = $minSz + $WimsyShrinkHeadroom
 $diff = $curSz - $newSz
 if ($newSz -lt $maxSz) { Resize-Partition -DriveLetter C -Size $newSz; Write-Host "New Partition Size: $newSz"; Write-Host "Free'd $diff" }
 #endregion
@@ -192,7 +457,83 @@ Get-AppxPackage -AllUsers "Microsoft.Winget.Source" -ErrorAction SilentlyContinu
     Remove-AppxPackage -AllUsers -ErrorAction SilentlyContinue
 #endregion
 
+#region Clean up AppX packages that block sysprep on client editions
+# Windows 10 and 11 install and update Store apps for the audit-mode
+# Administrator in the background. Sysprep refuses to generalize an image with
+# an app that's installed for a user but not provisioned for new ones, so
+# remove those apps for everyone.
+if ($WimsyClient) {
+    Write-Host "Removing unprovisioned AppX packages"
+    $provisioned = (Get-AppxProvisionedPackage -Online).DisplayName
+    Get-AppxPackage -AllUsers |
+        Where-Object { -not $_.IsFramework -and -not $_.NonRemovable -and $_.Name -notin $provisioned } |
+        ForEach-Object {
+            Write-Host "Removing $($_.PackageFullName)"
+            Remove-AppxPackage -AllUsers -Package $_.PackageFullName -ErrorAction SilentlyContinue
+        }
+    Remove-ItemProperty -Path $storePolicy -Name "AutoDownload" -ErrorAction SilentlyContinue
+}
+#endregion
+
+#region Expand OS partition on first boot
+# Windows runs SetupComplete.cmd once setup finishes on a deployed image's
+# first boot, before anyone can log on. Have it grow the OS partition into
+# the rest of the disk, which is usually larger than the shrunken image.
+if ($WimsyExpandOnFirstBoot) {
+    Write-Host "Registering first-boot OS partition expansion"
+    $setupScripts = "C:\Windows\Setup\Scripts"
+    New-Item -ItemType Directory -Force -Path $setupScripts | Out-Null
+    Set-Content -Path "$setupScripts\WimsyExpandOSPartition.ps1" -Value @'
+Update-HostStorageCache
+$supported = Get-PartitionSupportedSize -DriveLetter C
+if ((Get-Partition -DriveLetter C).Size -lt $supported.SizeMax) {
+    Resize-Partition -DriveLetter C -Size $supported.SizeMax
+}
+'@
+    # Append rather than overwrite, in case a provisioning script has already
+    # added its own commands.
+    Add-Content -Path "$setupScripts\SetupComplete.cmd" -Value "powershell.exe -NoProfile -ExecutionPolicy Bypass -File %~dp0WimsyExpandOSPartition.ps1 > %~dp0WimsyExpandOSPartition.log 2>&1"
+}
+#endregion
+
+#region Zero free space
+# The data of files that setup deleted stays in the OS partition's free space,
+# where it takes up room in compressed and sparse copies of the image. Fill
+# the volume with a file of zeroes and delete it to overwrite it. This runs
+# after the partition is shrunk, so it only has a few GB to write.
+if ($WimsyZeroFreeSpace) {
+    Write-Host "Zeroing free space on C:"
+    $zeroFile = "C:\WimsyZeroFill.tmp"
+    # Leave a little space free for anything else that's writing to the disk.
+    $zeroBytes = (Get-Volume -DriveLetter C).SizeRemaining - 64MB
+    $buffer = New-Object byte[] (1MB)
+    $stream = [System.IO.File]::Open($zeroFile, [System.IO.FileMode]::Create, [System.IO.FileAccess]::Write, [System.IO.FileShare]::None)
+    try {
+        while ($stream.Length -lt $zeroBytes) {
+            $stream.Write($buffer, 0, $buffer.Length)
+        }
+        $stream.Flush($true)
+    } catch [System.IO.IOException] {
+        # Something else used up the remaining space first; everything up to
+        # that point is zeroed.
+    } finally {
+        $zeroed = $stream.Length
+        $stream.Dispose()
+        Remove-Item -Force $zeroFile
+    }
+    Write-Host "Zeroed $([math]::Round($zeroed / 1MB)) MiB of free space"
+}
+#endregion
+
 #region Generalize image
-Write-Host "Generalizing image"
-C:\Windows\System32\Sysprep\sysprep.exe /generalize /oobe /shutdown /unattend:"$ConfigDir\specialize-unattend.xml"
+if ($WimsySkipGeneralize) {
+    # The specialize pass in specialize-unattend.xml only runs after the image
+    # is generalized, so re-enable cloudbase-init here instead.
+    Write-Host "Leaving audit mode without generalizing image"
+    Set-Service -Name cloudbase-init -StartupType Automatic
+    C:\Windows\System32\Sysprep\sysprep.exe /oobe /shutdown /unattend:"$ConfigDir\specialize-unattend.xml"
+} else {
+    Write-Host "Generalizing image"
+    C:\Windows\System32\Sysprep\sysprep.exe /generalize /oobe /shutdown /unattend:"$ConfigDir\specialize-unattend.xml"
+}
 #endregion